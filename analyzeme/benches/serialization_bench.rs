@@ -2,7 +2,7 @@
 
 extern crate test;
 
-use analyzeme::testing_common;
+use analyzeme::{testing_common, ProfilingData};
 
 #[bench]
 fn bench_serialization_sink(bencher: &mut test::Bencher) {
@@ -17,3 +17,61 @@ fn bench_serialization_sink_8_threads(bencher: &mut test::Bencher) {
         testing_common::run_serialization_bench("serialization_sink_test_8_threads", 50_000, 8);
     });
 }
+
+#[bench]
+fn bench_serialization_sink_4kib_pages(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_serialization_bench_with_page_size(
+            "serialization_sink_test_4kib_pages",
+            500_000,
+            1,
+            4 * 1024,
+        );
+    });
+}
+
+#[bench]
+fn bench_serialization_sink_1mib_pages(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_serialization_bench_with_page_size(
+            "serialization_sink_test_1mib_pages",
+            500_000,
+            1,
+            1024 * 1024,
+        );
+    });
+}
+
+#[bench]
+fn bench_repeated_strings(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_string_deduplication_bench("repeated_strings", 500_000, false);
+    });
+}
+
+#[bench]
+fn bench_repeated_strings_deduplicated(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_string_deduplication_bench(
+            "repeated_strings_deduplicated",
+            500_000,
+            true,
+        );
+    });
+}
+
+#[bench]
+fn bench_load_profile(bencher: &mut test::Bencher) {
+    let filestem = testing_common::record_query_args_profile("load_profile", 100_000);
+    bencher.iter(|| ProfilingData::new(&filestem).unwrap().num_events());
+}
+
+#[bench]
+fn bench_decode_full_events(bencher: &mut test::Bencher) {
+    let data = testing_common::open_query_args_profile("decode_full_events", 100_000);
+    bencher.iter(|| {
+        data.iter_full()
+            .map(|event| event.label.len() + event.additional_data[0].len())
+            .sum::<usize>()
+    });
+}
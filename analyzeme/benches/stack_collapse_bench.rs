@@ -0,0 +1,34 @@
+#![feature(test)]
+
+extern crate test;
+
+use analyzeme::{collapse_stacks, ProfilingData, ProfilingDataBuilder};
+
+// A profile of `num_threads` threads that each record `num_events` nested
+// queries, interleaved like the threads of a parallel compiler would.
+fn nested_queries(num_events: u64, num_threads: u32) -> ProfilingData {
+    let mut b = ProfilingDataBuilder::new();
+
+    for i in 0..num_events {
+        let thread_id = (i % num_threads as u64) as u32;
+        let start = i * 100;
+        b.interval("Query", "outer", thread_id, start, start + 90, |b| {
+            b.interval("Query", "middle", thread_id, start + 10, start + 80, |b| {
+                b.interval("Query", "inner", thread_id, start + 20, start + 30, |_| {});
+                b.interval("Query", "inner", thread_id, start + 40, start + 50, |_| {});
+            });
+        });
+    }
+
+    b.into_profiling_data()
+}
+
+// Compare `cargo bench -p analyzeme --bench stack_collapse_bench` with the
+// same command with `--features rayon` on a machine with several cores. The
+// speedup is bounded by the number of threads in the profile, as the stacks of
+// each thread are collapsed on one core.
+#[bench]
+fn bench_collapse_stacks_8_threads(bencher: &mut test::Bencher) {
+    let profiling_data = nested_queries(100_000, 8);
+    bencher.iter(|| collapse_stacks(&profiling_data));
+}
@@ -1,3 +1,4 @@
+use crate::tdigest::TDigest;
 use crate::{Event, EventPayload, ProfilingData, Timestamp};
 use measureme::rustc::*;
 use rustc_hash::FxHashMap;
@@ -7,6 +8,15 @@ use std::collections::BTreeMap;
 use std::time::Duration;
 use std::time::SystemTime;
 
+/// Whether `event` records the size of an artifact, i.e. is an integer event
+/// with the `ARTIFACT_SIZE_EVENT_KIND` event kind or the
+/// `ARTIFACT_SIZE_CATEGORY` category.
+pub fn is_artifact_size(event: &Event<'_>) -> bool {
+    event.payload.is_integer()
+        && (event.event_kind == ARTIFACT_SIZE_EVENT_KIND
+            || event.category.as_deref() == Some(ARTIFACT_SIZE_CATEGORY))
+}
+
 impl ProfilingData {
     /// Collects accumulated summary data for the given ProfilingData.
     ///
@@ -113,169 +123,315 @@ impl ProfilingData {
     /// In this case when we encounter `e2`, the stack is `[e1, e3, e4]`, and both
     /// `e4` and `e3` need to be popped in the same step.
     pub fn perform_analysis(self) -> AnalysisResults {
-        struct PerThreadState<'a> {
-            stack: Vec<Event<'a>>,
-            start: SystemTime,
-            end: SystemTime,
+        self.perform_analysis_impl(false)
+    }
+
+    /// Like [`ProfilingData::perform_analysis()`], but additionally estimates
+    /// the distribution of the durations of the individual invocations of each
+    /// label, see [`QueryData::latency`].
+    pub fn perform_analysis_with_percentiles(self) -> AnalysisResults {
+        self.perform_analysis_impl(true)
+    }
+
+    fn perform_analysis_impl(self, compute_percentiles: bool) -> AnalysisResults {
+        // Events are only ever nested within events of the same thread, so
+        // the threads can be analyzed separately.
+        #[cfg(feature = "rayon")]
+        let analysis = {
+            use rayon::prelude::*;
+
+            self.par_events_by_thread()
+                .into_par_iter()
+                .map(|(_, event_indices)| {
+                    let events = event_indices.iter().rev().map(|&event_index| {
+                        (
+                            self.decode_full_event(event_index),
+                            self.decode_wall_time(event_index),
+                        )
+                    });
+                    analyze_events(events, compute_percentiles)
+                })
+                .reduce(PartialAnalysis::default, PartialAnalysis::merge)
+        };
+
+        #[cfg(not(feature = "rayon"))]
+        let analysis = analyze_events(
+            self.iter()
+                .rev()
+                .map(|event| (self.to_full_event(&event), self.wall_time(&event))),
+            compute_percentiles,
+        );
+
+        analysis.into_results()
+    }
+}
+
+/// The results of analyzing some of the threads of a profile.
+#[derive(Default)]
+pub(crate) struct PartialAnalysis<'a> {
+    query_data: FxHashMap<String, QueryData>,
+    artifact_sizes: BTreeMap<Cow<'a, str>, ArtifactSize>,
+    instant_values: BTreeMap<Cow<'a, str>, InstantValues>,
+    latencies: FxHashMap<Cow<'a, str>, TDigest>,
+    total_time: Duration,
+}
+
+impl<'a> PartialAnalysis<'a> {
+    /// Combines the results of analyzing two disjoint sets of threads.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn merge(mut self, other: PartialAnalysis<'a>) -> PartialAnalysis<'a> {
+        for (label, query_data) in other.query_data {
+            self.query_data
+                .entry(label)
+                .or_insert_with_key(|label| QueryData::new(label.clone()))
+                .merge(&query_data);
         }
+        for (label, artifact_size) in other.artifact_sizes {
+            self.artifact_sizes
+                .entry(label)
+                .or_insert_with(|| ArtifactSize::new(artifact_size.label.clone()))
+                .add_value(artifact_size.value);
+        }
+        for (label, instant_values) in other.instant_values {
+            self.instant_values
+                .entry(label)
+                .or_insert_with(|| InstantValues::new(instant_values.label.clone()))
+                .merge(&instant_values);
+        }
+        for (label, digest) in other.latencies {
+            self.latencies
+                .entry(label)
+                .or_insert_with(TDigest::new)
+                .merge(digest);
+        }
+        self.total_time += other.total_time;
 
-        let mut query_data = FxHashMap::<String, QueryData>::default();
-        let mut artifact_sizes = BTreeMap::<Cow<'_, str>, ArtifactSize>::default();
-        let mut threads = FxHashMap::<_, PerThreadState<'_>>::default();
+        self
+    }
 
-        let mut record_event_data = |label: &Cow<'_, str>, f: &dyn Fn(&mut QueryData)| {
+    pub(crate) fn into_results(self) -> AnalysisResults {
+        let PartialAnalysis {
+            mut query_data,
+            artifact_sizes,
+            instant_values,
+            latencies,
+            total_time,
+        } = self;
+
+        for (label, mut digest) in latencies {
             if let Some(data) = query_data.get_mut(&label[..]) {
-                f(data);
-            } else {
-                let mut data = QueryData::new(label.clone().into_owned());
-                f(&mut data);
-                query_data.insert(label.clone().into_owned(), data);
+                data.latency = Some(LatencyPercentiles::from_digest(&mut digest));
             }
-        };
+        }
+
+        AnalysisResults {
+            query_data: query_data.drain().map(|(_, value)| value).collect(),
+            artifact_sizes: artifact_sizes.into_values().collect(),
+            instant_values: instant_values.into_values().collect(),
+            total_time,
+        }
+    }
+}
+
+/// Analyzes `events`, each with its wall time (see `ProfilingData::wall_time`),
+/// which must be in the reverse order of how they have been recorded, see
+/// `ProfilingData::perform_analysis`. It doesn't matter which threads they
+/// are from, as long as all events of a thread are there.
+pub(crate) fn analyze_events<'a>(
+    events: impl Iterator<Item = (Event<'a>, Option<Duration>)>,
+    compute_percentiles: bool,
+) -> PartialAnalysis<'a> {
+    struct PerThreadState<'a> {
+        stack: Vec<Event<'a>>,
+        start: SystemTime,
+        end: SystemTime,
+    }
+
+    let mut query_data = FxHashMap::<String, QueryData>::default();
+    let mut artifact_sizes = BTreeMap::<Cow<'_, str>, ArtifactSize>::default();
+    let mut instant_values = BTreeMap::<Cow<'_, str>, InstantValues>::default();
+    let mut threads = FxHashMap::<_, PerThreadState<'_>>::default();
+    let mut latencies = FxHashMap::<Cow<'_, str>, TDigest>::default();
+
+    let mut record_event_data = |label: &Cow<'_, str>, f: &dyn Fn(&mut QueryData)| {
+        if let Some(data) = query_data.get_mut(&label[..]) {
+            f(data);
+        } else {
+            let mut data = QueryData::new(label.clone().into_owned());
+            f(&mut data);
+            query_data.insert(label.clone().into_owned(), data);
+        }
+    };
+
+    for (current_event, wall_time) in events {
+        match current_event.payload {
+            EventPayload::Timestamp(Timestamp::Instant(_)) => {
+                if &current_event.event_kind[..] == QUERY_CACHE_HIT_EVENT_KIND {
+                    record_event_data(&current_event.label, &|data| {
+                        data.number_of_cache_hits += 1;
+                        data.invocation_count += 1;
+                    });
+                }
+            }
+            EventPayload::Timestamp(Timestamp::Interval { start, end }) => {
+                // This is an interval event
+                let thread =
+                    threads
+                        .entry(current_event.thread_id)
+                        .or_insert_with(|| PerThreadState {
+                            stack: Vec::new(),
+                            start,
+                            end,
+                        });
 
-        for current_event in self.iter_full().rev() {
-            match current_event.payload {
-                EventPayload::Timestamp(Timestamp::Instant(_)) => {
-                    if &current_event.event_kind[..] == QUERY_CACHE_HIT_EVENT_KIND {
+                // Pop all events from the stack that are not parents of the
+                // current event.
+                while let Some(current_top) = thread.stack.last().cloned() {
+                    if current_top.contains(&current_event) {
+                        break;
+                    }
+
+                    thread.stack.pop();
+                }
+
+                let current_event_duration = current_event.duration().unwrap();
+
+                // If there is something on the stack, subtract the current
+                // interval from it.
+                if let Some(current_top) = thread.stack.last() {
+                    record_event_data(
+                        &current_top.label,
+                        &|data| match &current_top.event_kind[..] {
+                            QUERY_EVENT_KIND | GENERIC_ACTIVITY_EVENT_KIND => {
+                                data.self_time -= current_event_duration;
+                            }
+                            INCREMENTAL_RESULT_HASHING_EVENT_KIND => {
+                                // We are within hashing something. If we now encounter something
+                                // within that event (like the nested "intern-the-dep-node" event)
+                                // then we don't want to attribute that to the hashing time.
+                                data.self_time -= current_event_duration;
+                                data.incremental_hashing_time -= current_event_duration;
+                            }
+                            INCREMENTAL_LOAD_RESULT_EVENT_KIND => {
+                                data.self_time -= current_event_duration;
+                                data.incremental_load_time -= current_event_duration;
+                            }
+                            _ => {
+                                // Data sources other than rustc will use their own event kinds so
+                                // just treat this like a GENERIC_ACTIVITY except that we don't
+                                // track cache misses since those may not apply to all data sources.
+                                data.self_time -= current_event_duration;
+                            }
+                        },
+                    );
+                }
+
+                // Update counters for the current event
+                match &current_event.event_kind[..] {
+                    QUERY_EVENT_KIND | GENERIC_ACTIVITY_EVENT_KIND => {
                         record_event_data(&current_event.label, &|data| {
-                            data.number_of_cache_hits += 1;
+                            data.self_time += current_event_duration;
+                            data.time += current_event_duration;
+                            data.add_wall_time(wall_time);
+                            data.number_of_cache_misses += 1;
                             data.invocation_count += 1;
                         });
                     }
-                }
-                EventPayload::Timestamp(Timestamp::Interval { start, end }) => {
-                    // This is an interval event
-                    let thread =
-                        threads
-                            .entry(current_event.thread_id)
-                            .or_insert_with(|| PerThreadState {
-                                stack: Vec::new(),
-                                start,
-                                end,
-                            });
-
-                    // Pop all events from the stack that are not parents of the
-                    // current event.
-                    while let Some(current_top) = thread.stack.last().cloned() {
-                        if current_top.contains(&current_event) {
-                            break;
-                        }
-
-                        thread.stack.pop();
+
+                    QUERY_BLOCKED_EVENT_KIND => {
+                        record_event_data(&current_event.label, &|data| {
+                            data.self_time += current_event_duration;
+                            data.time += current_event_duration;
+                            data.add_wall_time(wall_time);
+                            data.blocked_time += current_event_duration;
+                            data.invocation_count += 1;
+                        });
                     }
 
-                    let current_event_duration = current_event.duration().unwrap();
-
-                    // If there is something on the stack, subtract the current
-                    // interval from it.
-                    if let Some(current_top) = thread.stack.last() {
-                        record_event_data(
-                            &current_top.label,
-                            &|data| match &current_top.event_kind[..] {
-                                QUERY_EVENT_KIND | GENERIC_ACTIVITY_EVENT_KIND => {
-                                    data.self_time -= current_event_duration;
-                                }
-                                INCREMENTAL_RESULT_HASHING_EVENT_KIND => {
-                                    // We are within hashing something. If we now encounter something
-                                    // within that event (like the nested "intern-the-dep-node" event)
-                                    // then we don't want to attribute that to the hashing time.
-                                    data.self_time -= current_event_duration;
-                                    data.incremental_hashing_time -= current_event_duration;
-                                }
-                                INCREMENTAL_LOAD_RESULT_EVENT_KIND => {
-                                    data.self_time -= current_event_duration;
-                                    data.incremental_load_time -= current_event_duration;
-                                }
-                                _ => {
-                                    // Data sources other than rustc will use their own event kinds so
-                                    // just treat this like a GENERIC_ACTIVITY except that we don't
-                                    // track cache misses since those may not apply to all data sources.
-                                    data.self_time -= current_event_duration;
-                                }
-                            },
-                        );
+                    INCREMENTAL_LOAD_RESULT_EVENT_KIND => {
+                        record_event_data(&current_event.label, &|data| {
+                            data.self_time += current_event_duration;
+                            data.time += current_event_duration;
+                            data.add_wall_time(wall_time);
+                            data.incremental_load_time += current_event_duration;
+                        });
                     }
 
-                    // Update counters for the current event
+                    INCREMENTAL_RESULT_HASHING_EVENT_KIND => {
+                        record_event_data(&current_event.label, &|data| {
+                            // Don't add to data.time since this event happens
+                            // within the query itself which is already contributing
+                            // to data.time
+                            data.self_time += current_event_duration;
+                            data.incremental_hashing_time += current_event_duration;
+                        });
+                    }
+
+                    _ => {
+                        // Data sources other than rustc will use their own event kinds so just
+                        // treat this like a GENERIC_ACTIVITY except that we don't track cache
+                        // misses since those may not apply to all data sources.
+                        record_event_data(&current_event.label, &|data| {
+                            data.self_time += current_event_duration;
+                            data.time += current_event_duration;
+                            data.add_wall_time(wall_time);
+                            data.invocation_count += 1;
+                        });
+                    }
+                };
+
+                // Only events that count as an invocation of their label
+                // contribute to its latency distribution.
+                if compute_percentiles {
                     match &current_event.event_kind[..] {
-                        QUERY_EVENT_KIND | GENERIC_ACTIVITY_EVENT_KIND => {
-                            record_event_data(&current_event.label, &|data| {
-                                data.self_time += current_event_duration;
-                                data.time += current_event_duration;
-                                data.number_of_cache_misses += 1;
-                                data.invocation_count += 1;
-                            });
-                        }
-
-                        QUERY_BLOCKED_EVENT_KIND => {
-                            record_event_data(&current_event.label, &|data| {
-                                data.self_time += current_event_duration;
-                                data.time += current_event_duration;
-                                data.blocked_time += current_event_duration;
-                                data.invocation_count += 1;
-                            });
-                        }
-
-                        INCREMENTAL_LOAD_RESULT_EVENT_KIND => {
-                            record_event_data(&current_event.label, &|data| {
-                                data.self_time += current_event_duration;
-                                data.time += current_event_duration;
-                                data.incremental_load_time += current_event_duration;
-                            });
-                        }
-
-                        INCREMENTAL_RESULT_HASHING_EVENT_KIND => {
-                            record_event_data(&current_event.label, &|data| {
-                                // Don't add to data.time since this event happens
-                                // within the query itself which is already contributing
-                                // to data.time
-                                data.self_time += current_event_duration;
-                                data.incremental_hashing_time += current_event_duration;
-                            });
-                        }
-
-                        _ => {
-                            // Data sources other than rustc will use their own event kinds so just
-                            // treat this like a GENERIC_ACTIVITY except that we don't track cache
-                            // misses since those may not apply to all data sources.
-                            record_event_data(&current_event.label, &|data| {
-                                data.self_time += current_event_duration;
-                                data.time += current_event_duration;
-                                data.invocation_count += 1;
-                            });
-                        }
-                    };
-
-                    // Update the start and end times for thread
-                    thread.start = std::cmp::min(thread.start, start);
-                    thread.end = std::cmp::max(thread.end, end);
-
-                    // Bring the stack up-to-date
-                    thread.stack.push(current_event)
-                }
-                EventPayload::Integer(value) => {
-                    if current_event.event_kind == ARTIFACT_SIZE_EVENT_KIND {
-                        // Dedup artifact size events according to their label
-                        artifact_sizes
+                        INCREMENTAL_LOAD_RESULT_EVENT_KIND
+                        | INCREMENTAL_RESULT_HASHING_EVENT_KIND => {}
+                        _ => latencies
                             .entry(current_event.label.clone())
-                            .or_insert_with(|| ArtifactSize::new(current_event.label.into_owned()))
-                            .add_value(value);
+                            .or_insert_with(TDigest::new)
+                            .add(current_event_duration.as_nanos() as f64),
                     }
                 }
-            }
-        }
 
-        let total_time = threads
-            .values()
-            .map(|t| t.end.duration_since(t.start).unwrap())
-            .sum();
+                // Update the start and end times for thread
+                thread.start = std::cmp::min(thread.start, start);
+                thread.end = std::cmp::max(thread.end, end);
 
-        AnalysisResults {
-            query_data: query_data.drain().map(|(_, value)| value).collect(),
-            artifact_sizes: artifact_sizes.into_values().collect(),
-            total_time,
+                // Bring the stack up-to-date
+                thread.stack.push(current_event)
+            }
+            EventPayload::InstantWithValue { value, .. } => {
+                // These mark points on the timeline, so they are kept
+                // apart from the query data, which aggregates intervals.
+                instant_values
+                    .entry(current_event.label.clone())
+                    .or_insert_with(|| InstantValues::new(current_event.label.into_owned()))
+                    .add_value(value);
+            }
+            EventPayload::Integer(value) => {
+                if is_artifact_size(&current_event) {
+                    // Dedup artifact size events according to their label
+                    artifact_sizes
+                        .entry(current_event.label.clone())
+                        .or_insert_with(|| ArtifactSize::new(current_event.label.into_owned()))
+                        .add_value(value);
+                }
+            }
         }
     }
+
+    let total_time = threads
+        .values()
+        .map(|t| t.end.duration_since(t.start).unwrap())
+        .sum();
+
+    PartialAnalysis {
+        query_data,
+        artifact_sizes,
+        instant_values,
+        latencies,
+        total_time,
+    }
 }
 
 /// A collection data for an entire rustc invocation
@@ -283,6 +439,9 @@ impl ProfilingData {
 pub struct AnalysisResults {
     pub query_data: Vec<QueryData>,
     pub artifact_sizes: Vec<ArtifactSize>,
+    /// The instant events that have been recorded with a value, by label.
+    #[serde(default)]
+    pub instant_values: Vec<InstantValues>,
     pub total_time: Duration,
 }
 
@@ -313,6 +472,15 @@ pub struct QueryData {
     pub blocked_time: Duration,
     pub incremental_load_time: Duration,
     pub incremental_hashing_time: Duration,
+    /// The wall-clock equivalent of `time`, for profiles recorded with
+    /// `measureme::ProfilerOptions::record_wall_time`, e.g. with an
+    /// instruction counter. `None` for other profiles.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wall_time: Option<Duration>,
+    /// The distribution of the durations of the individual invocations. This
+    /// is only computed by [`ProfilingData::perform_analysis_with_percentiles()`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyPercentiles>,
 }
 
 impl QueryData {
@@ -322,6 +490,51 @@ impl QueryData {
             ..Self::default()
         }
     }
+
+    /// Adds the numbers of `other`, which has the same label, except for the
+    /// latency.
+    #[cfg(feature = "rayon")]
+    fn merge(&mut self, other: &QueryData) {
+        self.time += other.time;
+        self.self_time += other.self_time;
+        self.number_of_cache_misses += other.number_of_cache_misses;
+        self.number_of_cache_hits += other.number_of_cache_hits;
+        self.invocation_count += other.invocation_count;
+        self.blocked_time += other.blocked_time;
+        self.incremental_load_time += other.incremental_load_time;
+        self.incremental_hashing_time += other.incremental_hashing_time;
+        self.add_wall_time(other.wall_time);
+    }
+
+    fn add_wall_time(&mut self, wall_time: Option<Duration>) {
+        if let Some(wall_time) = wall_time {
+            *self.wall_time.get_or_insert_with(Duration::default) += wall_time;
+        }
+    }
+}
+
+/// Percentiles of the durations of the invocations of a label. These include
+/// the time spent in nested events, like [`QueryData::time`], and are
+/// estimates, except for `max`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    fn from_digest(digest: &mut TDigest) -> LatencyPercentiles {
+        let mut nanos = |q| Duration::from_nanos(digest.quantile(q).unwrap().round() as u64);
+
+        LatencyPercentiles {
+            p50: nanos(0.5),
+            p90: nanos(0.9),
+            p99: nanos(0.99),
+            max: Duration::from_nanos(digest.max().unwrap() as u64),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -340,6 +553,40 @@ impl ArtifactSize {
     }
 }
 
+/// The instant events with a given label that have been recorded with a
+/// value, see `measureme::Profiler::record_instant_event`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstantValues {
+    pub label: String,
+    pub count: usize,
+    pub total: u64,
+    pub max: u64,
+}
+
+impl InstantValues {
+    pub fn new(label: String) -> Self {
+        Self {
+            label,
+            count: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    pub(crate) fn add_value(&mut self, value: u64) {
+        self.count += 1;
+        self.total = self.total.saturating_add(value);
+        self.max = std::cmp::max(self.max, value);
+    }
+
+    #[cfg(feature = "rayon")]
+    fn merge(&mut self, other: &InstantValues) {
+        self.count += other.count;
+        self.total = self.total.saturating_add(other.total);
+        self.max = std::cmp::max(self.max, other.max);
+    }
+}
+
 #[rustfmt::skip]
 #[cfg(test)]
 mod tests {
@@ -646,13 +893,111 @@ mod tests {
         b.integer(ARTIFACT_SIZE_EVENT_KIND, "artifact1", 2, 50);
         b.integer(ARTIFACT_SIZE_EVENT_KIND, "artifact2", 1, 50);
         b.integer("OTHER_EVENT", "other_id", 1, 50);
+        b.integer("OTHER_EVENT", "artifact3\x1E\x12ArtifactSize", 1, 25);
 
         let results = b.into_profiling_data().perform_analysis();
 
-        assert_eq!(results.artifact_sizes.len(), 2);
+        assert_eq!(results.artifact_sizes.len(), 3);
         assert_eq!(results.artifact_size_by_label("artifact1").value, 150);
         assert_eq!(results.artifact_size_by_label("artifact1").label, "artifact1");
         assert_eq!(results.artifact_size_by_label("artifact2").value, 50);
         assert_eq!(results.artifact_size_by_label("artifact2").label, "artifact2");
+        assert_eq!(results.artifact_size_by_label("artifact3").value, 25);
+    }
+
+    #[test]
+    fn instant_values() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval(QUERY_EVENT_KIND, "q1", 0, 0, 100, |b| {
+            b.instant_with_value("GC", "collect", 0, 10, 40);
+            b.instant_with_value("GC", "collect", 0, 20, 2);
+        });
+        b.instant_with_value("GC", "compact", 1, 50, 7);
+
+        let results = b.into_profiling_data().perform_analysis();
+
+        // The markers don't take any time away from the query they are
+        // nested in.
+        assert_eq!(results.query_data.len(), 1);
+        assert_eq!(results.query_data_by_label("q1").self_time, Duration::from_nanos(100));
+        assert_eq!(results.total_time, Duration::from_nanos(100));
+
+        assert_eq!(results.instant_values, vec![
+            InstantValues { label: "collect".to_string(), count: 2, total: 42, max: 40 },
+            InstantValues { label: "compact".to_string(), count: 1, total: 7, max: 7 },
+        ]);
+    }
+
+    #[test]
+    fn wall_times() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval_with_wall_time(QUERY_EVENT_KIND, "typeck", 0, 0, 1000, 400, |b| {
+            b.interval_with_wall_time(QUERY_EVENT_KIND, "type_of", 0, 100, 300, 50, |_| {});
+            b.interval_with_wall_time(INCREMENTAL_RESULT_HASHING_EVENT_KIND, "typeck", 0, 500, 600, 30, |_| {});
+            b.instant(QUERY_CACHE_HIT_EVENT_KIND, "type_of", 0, 700);
+        });
+        b.interval_with_wall_time(QUERY_EVENT_KIND, "type_of", 1, 0, 600, 150, |_| {});
+
+        let results = b.into_profiling_data().perform_analysis();
+
+        // Like the time, the wall time of a label includes its nested
+        // events, and hashing time is already part of the query's time.
+        assert_eq!(results.query_data_by_label("typeck").wall_time, Some(Duration::from_nanos(400)));
+        assert_eq!(results.query_data_by_label("type_of").wall_time, Some(Duration::from_nanos(200)));
+    }
+
+    #[test]
+    fn no_wall_times_without_flag() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval(QUERY_EVENT_KIND, "typeck", 0, 0, 100, |_| {});
+
+        let results = b.into_profiling_data().perform_analysis();
+        assert_eq!(results.query_data_by_label("typeck").wall_time, None);
+    }
+
+    #[test]
+    fn instant_values_total_saturates() {
+        let mut values = InstantValues::new("collect".to_string());
+        values.add_value(measureme::MAX_INSTANT_VALUE);
+        values.total = u64::MAX - 1;
+        values.add_value(measureme::MAX_INSTANT_VALUE);
+
+        assert_eq!(values.count, 2);
+        assert_eq!(values.total, u64::MAX);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let build = || {
+            let mut b = ProfilingDataBuilder::new();
+
+            // Invocations that take 1ns, 2ns, ..., 100ns.
+            for i in 0..100 {
+                b.interval(QUERY_EVENT_KIND, "q1", 0, i * 1000, i * 1000 + i + 1, |b| {
+                    b.interval(INCREMENTAL_RESULT_HASHING_EVENT_KIND, "q1", 0, i * 1000, i * 1000 + 1, |_| {});
+                });
+            }
+
+            b.into_profiling_data()
+        };
+
+        assert_eq!(build().perform_analysis().query_data_by_label("q1").latency, None);
+
+        let results = build().perform_analysis_with_percentiles();
+        let latency = results.query_data_by_label("q1").latency.unwrap();
+
+        // The hashing events do not count as separate invocations, otherwise
+        // the median would be much lower.
+        let assert_close = |actual: Duration, expected: u64| {
+            assert!(actual.as_nanos() as i128 - expected as i128 <= 2, "{:?} vs {}", actual, expected);
+            assert!(expected as i128 - actual.as_nanos() as i128 <= 2, "{:?} vs {}", actual, expected);
+        };
+
+        assert_close(latency.p50, 50);
+        assert_close(latency.p90, 90);
+        assert_close(latency.p99, 99);
+        assert_eq!(latency.max, Duration::from_nanos(100));
     }
 }
@@ -0,0 +1,466 @@
+use crate::{LightweightEvent, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// A node of the call tree of a thread, see [`ProfilingData::call_tree()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallTreeNode {
+    /// The index of the interval event this node has been created for, or
+    /// `None` for the root of the tree.
+    pub event_index: Option<usize>,
+    pub label: String,
+    pub category: Option<String>,
+    /// The duration of the event. For the root, the sum of the durations of
+    /// its children.
+    pub duration: Duration,
+    /// The duration of the event minus the durations of its children.
+    pub self_duration: Duration,
+    /// The events directly nested within this one, in the order in which
+    /// they have been recorded, i.e. ordered by their end.
+    pub children: Vec<CallTreeNode>,
+}
+
+impl CallTreeNode {
+    fn root() -> CallTreeNode {
+        CallTreeNode {
+            event_index: None,
+            label: String::new(),
+            category: None,
+            duration: Duration::from_secs(0),
+            self_duration: Duration::from_secs(0),
+            children: Vec::new(),
+        }
+    }
+
+    /// Returns the chain of nested events from this node down to a leaf
+    /// with the largest sum of self durations, which is often where
+    /// optimizing pays off. The chain starts with this node, except for the
+    /// root of a call tree, which isn't an event itself, so it starts with
+    /// one of its children instead. Of several equally long chains, the one
+    /// through the children that have been recorded first is returned.
+    ///
+    /// Adding up the self durations counts the time of every event on the
+    /// chain once, unlike adding up the durations, which include those of
+    /// the nested events. The longest chain doesn't necessarily start with
+    /// the longest child, as much of its time may be spent in events that
+    /// aren't on a single chain.
+    pub fn critical_path(&self) -> Vec<&CallTreeNode> {
+        // The length of the longest chain from each node down to a leaf, and
+        // the child that chain continues with, computed after those of the
+        // node's children. The nodes are visited with an explicit stack, as
+        // recursion could overflow the stack of deeply recursive programs.
+        let mut longest =
+            FxHashMap::<*const CallTreeNode, (Duration, Option<&CallTreeNode>)>::default();
+        let mut stack = vec![(self, false)];
+        while let Some((node, children_done)) = stack.pop() {
+            if !children_done {
+                stack.push((node, true));
+                stack.extend(node.children.iter().map(|child| (child, false)));
+                continue;
+            }
+
+            let longest_child = node
+                .children
+                .iter()
+                .map(|child| (longest[&(child as *const _)].0, child))
+                .reduce(|longest, next| if next.0 > longest.0 { next } else { longest });
+
+            let length = longest_child.map_or(Duration::ZERO, |(length, _)| length);
+            longest.insert(
+                node,
+                (
+                    node.self_duration.saturating_add(length),
+                    longest_child.map(|(_, child)| child),
+                ),
+            );
+        }
+
+        let mut path = Vec::new();
+        if self.event_index.is_some() {
+            path.push(self);
+        }
+
+        let mut node = self;
+        while let Some(child) = longest[&(node as *const _)].1 {
+            path.push(child);
+            node = child;
+        }
+
+        path
+    }
+}
+
+impl ProfilingData {
+    /// Reconstructs the call tree of the given thread from the nesting of its
+    /// interval events. The returned node is a synthetic root whose children
+    /// are the outermost events of the thread. Instant and integer events are
+    /// not part of the tree. Spans that have never been closed (see
+    /// [`ProfilingData::unclosed_intervals()`]) last until the end of the
+    /// profile.
+    ///
+    /// This never fails for malformed profiles (see
+    /// [`ProfilingData::validate()`]): an event that isn't contained in the
+    /// event it partially overlaps is attached to the innermost event that
+    /// does contain it, or to the root, and an event that ends before it
+    /// starts is attached to the root with a duration of zero.
+    ///
+    /// In profiles with explicit parents (see
+    /// [`ProfilingData::has_explicit_parents()`]), an event that names its
+    /// parent is attached to the innermost event with that event id that
+    /// contains it, regardless of the events that are nested in between, so
+    /// that the fragments of interleaved async tasks end up in the task they
+    /// belong to. If there is no such event, it is nested like the others.
+    pub fn call_tree(&self, thread_id: u32) -> CallTreeNode {
+        let mut builder = CallTreeBuilder::new(self);
+        for event in self.intervals_in_reverse() {
+            if event.thread_id == thread_id {
+                builder.add(self, event);
+            }
+        }
+        builder.finish()
+    }
+
+    /// The call trees of all threads with interval events, by thread id, see
+    /// [`ProfilingData::call_tree()`]. Unlike calling that for every thread,
+    /// this reads the events only once. With the `rayon` feature, the trees
+    /// are reconstructed on multiple threads.
+    pub fn call_trees(&self) -> BTreeMap<u32, CallTreeNode> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+
+            let trees: Vec<(u32, CallTreeNode)> = self
+                .par_events_by_thread()
+                .into_par_iter()
+                .map(|(thread_id, event_indices)| {
+                    // The unclosed intervals end last, like in
+                    // `intervals_in_reverse`.
+                    let unclosed_intervals = self
+                        .unclosed_intervals()
+                        .iter()
+                        .rev()
+                        .filter(|event| event.thread_id == thread_id)
+                        .cloned();
+                    let events = event_indices
+                        .iter()
+                        .rev()
+                        .map(|&event_index| self.decode_lightweight_event(event_index));
+
+                    let mut builder = CallTreeBuilder::new(self);
+                    for event in unclosed_intervals.chain(events) {
+                        if event.payload.is_interval() {
+                            builder.add(self, event);
+                        }
+                    }
+                    (thread_id, builder.finish())
+                })
+                .collect();
+
+            trees
+                .into_iter()
+                .filter(|(_, tree)| !tree.children.is_empty())
+                .collect()
+        }
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            let mut builders = BTreeMap::<u32, CallTreeBuilder<'_>>::new();
+            for event in self.intervals_in_reverse() {
+                builders
+                    .entry(event.thread_id)
+                    .or_insert_with(|| CallTreeBuilder::new(self))
+                    .add(self, event);
+            }
+
+            builders
+                .into_iter()
+                .map(|(thread_id, builder)| (thread_id, builder.finish()))
+                .collect()
+        }
+    }
+
+    /// The interval events of all threads, including the unclosed intervals,
+    /// which end last, in reverse order.
+    fn intervals_in_reverse(&self) -> impl Iterator<Item = LightweightEvent> + '_ {
+        let unclosed_intervals = self.unclosed_intervals().iter().cloned();
+        self.iter()
+            .chain(unclosed_intervals)
+            .rev()
+            .filter(|event| event.payload.is_interval())
+    }
+}
+
+/// Reconstructs the call tree of a single thread from its interval events,
+/// which have to be added in reverse order.
+struct CallTreeBuilder<'a> {
+    /// The nodes and the indices of their parents in `nodes`, with the root
+    /// at index 0. Walking the events in reverse order means that we
+    /// encounter parents before their children, i.e. parents always have a
+    /// smaller index.
+    nodes: Vec<(CallTreeNode, usize)>,
+    stack: Vec<(LightweightEvent, usize)>,
+    /// The interval events visited so far by their event id, the candidates
+    /// for explicit parents.
+    by_event_id: Option<EventsById<'a>>,
+}
+
+/// Events and the indices of their nodes, by event id.
+type EventsById<'a> = FxHashMap<Cow<'a, [u8]>, Vec<(LightweightEvent, usize)>>;
+
+impl<'a> CallTreeBuilder<'a> {
+    fn new(data: &ProfilingData) -> CallTreeBuilder<'a> {
+        CallTreeBuilder {
+            nodes: vec![(CallTreeNode::root(), 0)],
+            stack: Vec::new(),
+            by_event_id: if data.has_explicit_parents() {
+                Some(FxHashMap::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn add(&mut self, data: &'a ProfilingData, event: LightweightEvent) {
+        let duration = event.duration();
+        let parent = if duration.is_some() {
+            while let Some((top, _)) = self.stack.last() {
+                if top.contains(&event) {
+                    break;
+                }
+                self.stack.pop();
+            }
+
+            let explicit_parent = self.by_event_id.as_ref().and_then(|by_event_id| {
+                let candidates = by_event_id.get(&data.explicit_parent_id_bytes(&event)?)?;
+                candidates
+                    .iter()
+                    .rev()
+                    .find(|(candidate, _)| candidate.contains(&event))
+                    .map(|&(_, index)| index)
+            });
+
+            explicit_parent
+                .unwrap_or_else(|| self.stack.last().map(|&(_, index)| index).unwrap_or(0))
+        } else {
+            0
+        };
+
+        let duration = duration.unwrap_or_default();
+        if parent != 0 {
+            let parent = &mut self.nodes[parent].0;
+            parent.self_duration = parent.self_duration.saturating_sub(duration);
+        }
+
+        let full_event = data.to_full_event(&event);
+        let index = self.nodes.len();
+        self.nodes.push((
+            CallTreeNode {
+                event_index: Some(event.event_index),
+                label: full_event.label.into_owned(),
+                category: full_event.category.map(|c| c.into_owned()),
+                duration,
+                self_duration: duration,
+                children: Vec::new(),
+            },
+            parent,
+        ));
+
+        if event.duration().is_some() {
+            if let Some(ref mut by_event_id) = self.by_event_id {
+                by_event_id
+                    .entry(data.event_id_bytes(&event))
+                    .or_default()
+                    .push((event.clone(), index));
+            }
+            self.stack.push((event, index));
+        }
+    }
+
+    fn finish(mut self) -> CallTreeNode {
+        // Moving the nodes into their parents starting with the last one
+        // means that each node's children are complete by the time it is
+        // moved, and that the children end up ordered by their end.
+        while self.nodes.len() > 1 {
+            let (node, parent) = self.nodes.pop().unwrap();
+            if parent == 0 {
+                self.nodes[0].0.duration += node.duration;
+            }
+            self.nodes[parent].0.children.push(node);
+        }
+
+        self.nodes.pop().unwrap().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    // Renders the tree as `label(self time)[children]`.
+    fn render(node: &CallTreeNode) -> String {
+        let children: Vec<_> = node.children.iter().map(render).collect();
+        format!(
+            "{}({})[{}]",
+            node.label,
+            node.self_duration.as_nanos(),
+            children.join(",")
+        )
+    }
+
+    #[test]
+    fn nested_intervals() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q1", 0, 0, 100, |b| {
+            b.interval("Query", "q2", 0, 10, 30, |_| {});
+            b.instant("Query", "i1", 0, 35);
+            b.interval("Query", "q3", 0, 40, 90, |b| {
+                b.interval("Query", "q4", 0, 50, 60, |_| {});
+            });
+        });
+        b.interval("Query", "q5", 0, 110, 120, |_| {});
+        b.interval("Query", "other thread", 1, 0, 200, |_| {});
+
+        let data = b.into_profiling_data();
+        let tree = data.call_tree(0);
+
+        assert_eq!(
+            render(&tree),
+            "(0)[q1(30)[q2(20)[],q3(40)[q4(10)[]]],q5(10)[]]"
+        );
+        assert_eq!(tree.event_index, None);
+        assert_eq!(tree.duration, Duration::from_nanos(110));
+
+        let q3 = &tree.children[0].children[1];
+        assert_eq!(q3.duration, Duration::from_nanos(50));
+        assert_eq!(data.decode_full_event(q3.event_index.unwrap()).label, "q3");
+
+        assert_eq!(render(&data.call_tree(1)), "(0)[other thread(200)[]]");
+        assert_eq!(render(&data.call_tree(2)), "(0)[]");
+
+        let trees = data.call_trees();
+        assert_eq!(trees.keys().copied().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(trees[&0], tree);
+        assert_eq!(trees[&1], data.call_tree(1));
+    }
+
+    #[test]
+    fn critical_path() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "short root", 0, 0, 50, |b| {
+            b.interval("Query", "a", 0, 10, 40, |_| {});
+        });
+        b.interval("Query", "long root", 0, 100, 200, |b| {
+            // Together with its child, `c` takes longer than `b`, but the
+            // child's time is part of `c`'s already.
+            b.interval("Query", "b", 0, 105, 135, |_| {});
+            b.interval("Query", "c", 0, 135, 160, |b| {
+                b.interval("Query", "d", 0, 140, 155, |_| {});
+            });
+            // As long as `b`, which has been recorded first.
+            b.interval("Query", "e", 0, 160, 190, |b| {
+                b.interval("Query", "f", 0, 161, 189, |_| {});
+            });
+        });
+
+        let data = b.into_profiling_data();
+        let tree = data.call_tree(0);
+        let labels = |path: Vec<&CallTreeNode>| -> Vec<_> {
+            path.iter().map(|node| node.label.clone()).collect()
+        };
+
+        // `long root` spends 15ns in itself and 30ns in `b` or `e`, which
+        // is less than the 50ns on the chain through `short root`.
+        assert_eq!(labels(tree.critical_path()), ["short root", "a"]);
+        assert_eq!(labels(tree.children[1].critical_path()), ["long root", "b"]);
+        assert_eq!(
+            labels(tree.children[1].children[1].critical_path()),
+            ["c", "d"]
+        );
+        assert!(data.call_tree(1).critical_path().is_empty());
+    }
+
+    #[test]
+    fn critical_path_does_not_follow_the_longest_child() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "root", 0, 0, 200, |b| {
+            // `wide` is the longest child, but its time is split between two
+            // children, so its longest chain only takes 10 + 60 ns.
+            b.interval("Query", "wide", 0, 0, 120, |b| {
+                b.interval("Query", "x", 0, 0, 60, |_| {});
+                b.interval("Query", "y", 0, 60, 110, |_| {});
+            });
+            b.interval("Query", "narrow", 0, 120, 200, |b| {
+                b.interval("Query", "z", 0, 125, 195, |_| {});
+            });
+        });
+
+        let data = b.into_profiling_data();
+        let tree = data.call_tree(0);
+        let labels: Vec<_> = tree
+            .critical_path()
+            .iter()
+            .map(|node| node.label.clone())
+            .collect();
+        assert_eq!(labels, ["root", "narrow", "z"]);
+    }
+
+    #[test]
+    fn malformed_nesting() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "outer", 0, 0, 100, |b| {
+            // Overlaps `partial`, so it ends up next to it within `outer`.
+            b.interval("Query", "first", 0, 10, 50, |_| {});
+            b.interval("Query", "partial", 0, 40, 60, |_| {});
+            b.inverted_interval("Query", "inverted", 0, 80, 70);
+        });
+        b.interval("Query", "crossing", 0, 90, 150, |_| {});
+
+        assert_eq!(
+            render(&b.into_profiling_data().call_tree(0)),
+            "(0)[inverted(0)[],outer(40)[first(40)[],partial(20)[]],crossing(60)[]]"
+        );
+    }
+
+    #[test]
+    fn explicit_parents() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // Two tasks whose fragments are polled on the same thread. `task_b`
+        // happens to be nested in `task_a` by its timestamps, so without
+        // explicit parents all fragments would end up in `task_b`.
+        b.interval("Task", "task_a", 0, 0, 100, |b| {
+            b.interval("Task", "task_b", 0, 10, 90, |b| {
+                b.interval_with_parent("Poll", "poll_a", "task_a", 0, 20, 30, |_| {});
+                b.interval_with_parent("Poll", "poll_b", "task_b", 0, 30, 40, |b| {
+                    b.interval("Query", "inner", 0, 32, 35, |_| {});
+                });
+                b.interval_with_parent("Poll", "poll_a", "task_a", 0, 40, 50, |_| {});
+                // No event with this id contains it, so it is nested in the
+                // innermost event that does.
+                b.interval_with_parent("Poll", "orphan", "missing", 0, 60, 70, |_| {});
+            });
+        });
+
+        let mut data = b.into_profiling_data();
+        assert!(data.has_explicit_parents());
+        assert_eq!(
+            render(&data.call_tree(0)),
+            "(0)[task_a(0)[poll_a(10)[],poll_a(10)[],\
+             task_b(60)[poll_b(7)[inner(3)[]],orphan(10)[]]]]"
+        );
+
+        data.file_flags = 0;
+        assert_eq!(
+            render(&data.call_tree(0)),
+            "(0)[task_a(20)[task_b(40)[poll_a(10)[],poll_b(7)[inner(3)[]],\
+             poll_a(10)[],orphan(10)[]]]]"
+        );
+    }
+}
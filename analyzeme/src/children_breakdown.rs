@@ -0,0 +1,204 @@
+//! Finding out which labels the time of the events with a given label has
+//! been spent in.
+
+use crate::{CallTreeNode, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::time::Duration;
+
+/// The time spent in the instances of a label, i.e. the interval events with
+/// that label, and in the events directly nested within them, see
+/// [`ProfilingData::children_breakdown()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChildrenBreakdown {
+    /// The number of instances, not counting those nested within another
+    /// instance.
+    pub invocation_count: usize,
+    /// The sum of the durations of the instances.
+    pub duration: Duration,
+    /// `duration` minus the durations of the children, i.e. the time spent
+    /// in the instances themselves.
+    pub self_duration: Duration,
+    /// Ordered by descending duration, and by label for equal durations.
+    pub children: Vec<ChildDuration>,
+}
+
+/// The events with the label `label` that are directly nested within the
+/// instances of another label, see [`ChildrenBreakdown`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChildDuration {
+    pub label: String,
+    pub invocation_count: usize,
+    /// The sum of the durations of these events, including the time spent in
+    /// the events nested within them.
+    pub duration: Duration,
+}
+
+impl ProfilingData {
+    /// Sums up the durations of the events directly nested within the
+    /// instances of `label`, by their label, e.g. to find out what the time
+    /// of a query that dominates the profile has been spent on. The call
+    /// trees of all threads are reconstructed for this, see
+    /// [`ProfilingData::call_trees()`].
+    ///
+    /// Time is never counted twice if `label` is recursive: an instance that
+    /// is nested within another one is part of the outer one. An instance
+    /// directly nested within another one is treated like a single frame
+    /// with it, so its children count as children of the outer instance. An
+    /// instance nested deeper than that is part of the child it is nested in.
+    pub fn children_breakdown(&self, label: &str) -> ChildrenBreakdown {
+        let mut breakdown = ChildrenBreakdown::default();
+        let mut children = FxHashMap::<String, ChildDuration>::default();
+        for tree in self.call_trees().values() {
+            // The root isn't an event, so it can't be an instance.
+            add_instances(&tree.children, label, &mut breakdown, &mut children);
+        }
+
+        let children_duration: Duration = children.values().map(|child| child.duration).sum();
+        breakdown.self_duration = breakdown.duration.saturating_sub(children_duration);
+
+        breakdown.children = children.into_values().collect();
+        breakdown.children.sort_by(|a, b| {
+            b.duration
+                .cmp(&a.duration)
+                .then_with(|| a.label.cmp(&b.label))
+        });
+        breakdown
+    }
+}
+
+/// Adds the outermost instances of `label` in the trees of `nodes`.
+fn add_instances(
+    nodes: &[CallTreeNode],
+    label: &str,
+    breakdown: &mut ChildrenBreakdown,
+    children: &mut FxHashMap<String, ChildDuration>,
+) {
+    let mut stack: Vec<&CallTreeNode> = nodes.iter().collect();
+    while let Some(node) = stack.pop() {
+        if node.label != label {
+            stack.extend(&node.children);
+            continue;
+        }
+
+        breakdown.invocation_count += 1;
+        breakdown.duration += node.duration;
+        add_children(node, label, children);
+    }
+}
+
+/// Adds the children of the instance `instance`, and those of the instances
+/// directly nested within it.
+fn add_children(
+    instance: &CallTreeNode,
+    label: &str,
+    children: &mut FxHashMap<String, ChildDuration>,
+) {
+    let mut instances = vec![instance];
+    while let Some(node) = instances.pop() {
+        for child in &node.children {
+            if child.label == label {
+                instances.push(child);
+                continue;
+            }
+
+            let child_duration =
+                children
+                    .entry(child.label.clone())
+                    .or_insert_with(|| ChildDuration {
+                        label: child.label.clone(),
+                        invocation_count: 0,
+                        duration: Duration::from_secs(0),
+                    });
+            child_duration.invocation_count += 1;
+            child_duration.duration += child.duration;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    fn children(breakdown: &ChildrenBreakdown) -> Vec<(&str, usize, u64)> {
+        breakdown
+            .children
+            .iter()
+            .map(|child| {
+                (
+                    &child.label[..],
+                    child.invocation_count,
+                    child.duration.as_nanos() as u64,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn children_of_all_instances() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 100, |b| {
+            b.interval("Query", "type_of", 0, 10, 30, |b| {
+                b.interval("Query", "generics_of", 0, 15, 25, |_| {});
+            });
+            b.interval("Query", "generics_of", 0, 40, 50, |_| {});
+            b.interval("Query", "type_of", 0, 60, 70, |_| {});
+        });
+        b.interval("Query", "type_of", 0, 200, 300, |_| {});
+        b.interval("Query", "typeck", 1, 0, 50, |b| {
+            b.interval("Query", "generics_of", 1, 10, 30, |_| {});
+            b.interval("Query", "layout_of", 1, 30, 50, |_| {});
+        });
+        let data = b.into_profiling_data();
+
+        let breakdown = data.children_breakdown("typeck");
+        assert_eq!(breakdown.invocation_count, 2);
+        assert_eq!(breakdown.duration, Duration::from_nanos(150));
+        assert_eq!(breakdown.self_duration, Duration::from_nanos(70));
+        // `generics_of` within `type_of` is part of `type_of`, and the
+        // children with the same duration are ordered by label.
+        assert_eq!(
+            children(&breakdown),
+            vec![
+                ("generics_of", 2, 30),
+                ("type_of", 2, 30),
+                ("layout_of", 1, 20),
+            ]
+        );
+
+        assert_eq!(
+            data.children_breakdown("unknown"),
+            ChildrenBreakdown::default()
+        );
+    }
+
+    #[test]
+    fn recursion_is_not_counted_twice() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "eval", 0, 0, 100, |b| {
+            b.interval("Query", "eval", 0, 10, 60, |b| {
+                b.interval("Query", "normalize", 0, 20, 30, |_| {});
+                b.interval("Query", "eval", 0, 30, 50, |b| {
+                    b.interval("Query", "normalize", 0, 35, 45, |_| {});
+                });
+            });
+            b.interval("Query", "select", 0, 70, 90, |b| {
+                b.interval("Query", "eval", 0, 75, 85, |b| {
+                    b.interval("Query", "normalize", 0, 76, 84, |_| {});
+                });
+            });
+        });
+        let data = b.into_profiling_data();
+
+        // Only the outermost instance counts, the directly nested ones are
+        // part of it, and the one within `select` is part of `select`.
+        let breakdown = data.children_breakdown("eval");
+        assert_eq!(breakdown.invocation_count, 1);
+        assert_eq!(breakdown.duration, Duration::from_nanos(100));
+        assert_eq!(breakdown.self_duration, Duration::from_nanos(60));
+        assert_eq!(
+            children(&breakdown),
+            vec![("normalize", 2, 20), ("select", 1, 20)]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Demangling the Rust symbol names that rustc records in the strings of
+//! events, e.g. in the arguments of codegen events, when they are displayed.
+
+use crate::{Event, ProfilingData};
+use measureme::StringId;
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+/// Returns `s` demangled if it is a mangled Rust symbol name, e.g.
+/// `core::ptr::drop_in_place` for `_ZN4core3ptr13drop_in_place17h...E`, and
+/// `s` itself otherwise. The hash of legacy symbols is left out.
+pub fn demangle(s: &str) -> Cow<'_, str> {
+    match rustc_demangle::try_demangle(s) {
+        Ok(demangled) => Cow::Owned(format!("{:#}", demangled)),
+        Err(_) => Cow::Borrowed(s),
+    }
+}
+
+/// The number of buckets of `DemangleCache::strings`, which is enough for
+/// every possible string id.
+const NUM_BUCKETS: usize = 32;
+
+/// The demangled strings of events, by the string id of their event id, see
+/// `ProfilingData::demangle_symbols`.
+#[derive(Debug, Default)]
+pub(crate) struct DemangleCache {
+    /// The position in `strings` of the demangled strings of each event id.
+    /// `None` for event ids without mangled symbols, which are by far the
+    /// most common ones.
+    event_ids: RwLock<FxHashMap<StringId, Option<usize>>>,
+    /// The demangled strings, which are only ever added to, so that the
+    /// events of the profile can borrow them. Bucket `b` has `2^b` entries,
+    /// which are allocated when the first of them is added.
+    strings: [OnceLock<Box<[OnceLock<DemangledStrings>]>>; NUM_BUCKETS],
+    num_strings: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct DemangledStrings {
+    label: String,
+    additional_data: Vec<String>,
+    backtrace: Vec<String>,
+}
+
+impl DemangledStrings {
+    fn of(event: &Event<'_>) -> Option<DemangledStrings> {
+        let label = demangle(&event.label);
+        let additional_data: Vec<_> = event.additional_data.iter().map(|s| demangle(s)).collect();
+        let backtrace: Vec<_> = event.backtrace.iter().map(|s| demangle(s)).collect();
+
+        let is_owned = |s: &Cow<'_, str>| matches!(s, Cow::Owned(_));
+        if !is_owned(&label)
+            && !additional_data.iter().any(is_owned)
+            && !backtrace.iter().any(is_owned)
+        {
+            return None;
+        }
+
+        let into_strings = |strings: Vec<Cow<'_, str>>| {
+            strings.into_iter().map(Cow::into_owned).collect::<Vec<_>>()
+        };
+        Some(DemangledStrings {
+            label: label.into_owned(),
+            additional_data: into_strings(additional_data),
+            backtrace: into_strings(backtrace),
+        })
+    }
+
+    fn apply_to<'a>(&'a self, event: &mut Event<'a>) {
+        let to_cows = |strings: &'a [String]| {
+            strings
+                .iter()
+                .map(|s| Cow::Borrowed(&s[..]))
+                .collect::<Vec<_>>()
+        };
+        event.label = Cow::Borrowed(&self.label);
+        event.additional_data = to_cows(&self.additional_data);
+        event.backtrace = to_cows(&self.backtrace);
+    }
+
+    fn move_to(self, event: &mut Event<'_>) {
+        let to_cows = |strings: Vec<String>| strings.into_iter().map(Cow::Owned).collect();
+        event.label = Cow::Owned(self.label);
+        event.additional_data = to_cows(self.additional_data);
+        event.backtrace = to_cows(self.backtrace);
+    }
+}
+
+/// The bucket of `DemangleCache::strings` that the entry at `index` is in,
+/// and its position within the bucket.
+fn bucket_of(index: usize) -> (usize, usize) {
+    let bucket = (usize::BITS - 1 - (index + 1).leading_zeros()) as usize;
+    (bucket, index + 1 - (1 << bucket))
+}
+
+impl DemangleCache {
+    /// Demangles the label, arguments and backtrace of `event`, whose event
+    /// id has the string id `event_id`. Every event id is only demangled
+    /// once, unless the file format doesn't provide string ids, and the
+    /// event borrows the demangled strings from the cache.
+    pub(crate) fn demangle_event<'a>(&'a self, event_id: Option<StringId>, event: &mut Event<'a>) {
+        let event_id = match event_id {
+            Some(event_id) => event_id,
+            None => {
+                if let Some(demangled) = DemangledStrings::of(event) {
+                    demangled.move_to(event);
+                }
+                return;
+            }
+        };
+
+        let cached = self.event_ids.read().unwrap().get(&event_id).copied();
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let index = DemangledStrings::of(event).map(|demangled| self.add(demangled));
+                // Another thread may have demangled the same event id in
+                // the meantime, whose entry is kept.
+                *self
+                    .event_ids
+                    .write()
+                    .unwrap()
+                    .entry(event_id)
+                    .or_insert(index)
+            }
+        };
+
+        if let Some(index) = index {
+            self.get(index).apply_to(event);
+        }
+    }
+
+    /// Adds `demangled` to `strings`, returning its position.
+    fn add(&self, demangled: DemangledStrings) -> usize {
+        let index = self.num_strings.fetch_add(1, Ordering::Relaxed);
+        let (bucket, pos) = bucket_of(index);
+        let entries = self.strings[bucket]
+            .get_or_init(|| (0..1usize << bucket).map(|_| OnceLock::new()).collect());
+        entries[pos].set(demangled).unwrap();
+        index
+    }
+
+    fn get(&self, index: usize) -> &DemangledStrings {
+        let (bucket, pos) = bucket_of(index);
+        self.strings[bucket].get().unwrap()[pos].get().unwrap()
+    }
+}
+
+impl ProfilingData {
+    /// Makes the events of this profile show demangled names instead of the
+    /// mangled Rust symbol names that have been recorded, e.g.
+    /// `core::ptr::drop_in_place` instead of `_ZN4core3ptr13drop_in_place...`.
+    /// This affects the labels, arguments and backtraces of the events that
+    /// `to_full_event` and everything based on it returns, i.e. it only
+    /// changes how the events are displayed. Copies of the profile, e.g. the
+    /// result of `merge` or `strip_args`, keep the recorded strings, as do
+    /// `event_id_bytes` and `label_bytes`. Strings that aren't mangled
+    /// symbols are left as they are.
+    pub fn demangle_symbols(&mut self) {
+        if self.demangle_cache.is_none() {
+            self.demangle_cache = Some(DemangleCache::default());
+        }
+    }
+
+    /// Whether `demangle_symbols` has been called.
+    pub fn demangles_symbols(&self) -> bool {
+        self.demangle_cache.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    const MANGLED: &str = "_ZN4core3ptr13drop_in_place17h0123456789abcdefE";
+
+    #[test]
+    fn demangle_symbols() {
+        assert_eq!(demangle(MANGLED), "core::ptr::drop_in_place");
+        assert_eq!(demangle("typeck"), "typeck");
+        assert_eq!(demangle("_ZN"), "_ZN");
+        assert_eq!(demangle(""), "");
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket_of(0), (0, 0));
+        assert_eq!(bucket_of(1), (1, 0));
+        assert_eq!(bucket_of(2), (1, 1));
+        assert_eq!(bucket_of(3), (2, 0));
+        assert_eq!(bucket_of(6), (2, 3));
+        assert_eq!(bucket_of(7), (3, 0));
+        assert_eq!(bucket_of(u32::MAX as usize - 1), (31, (1 << 31) - 1));
+    }
+
+    #[test]
+    fn demangled_events() {
+        let mut b = ProfilingDataBuilder::new();
+        let mangled_label = format!("{}\x1E{}", MANGLED, MANGLED);
+        b.interval("Query", &mangled_label, 0, 10, 20, |_| {});
+        b.interval("Query", &mangled_label, 0, 30, 40, |_| {});
+        b.interval("Query", "typeck\x1Emain", 0, 50, 60, |_| {});
+
+        let mut data = b.into_profiling_data();
+        let strings = |data: &ProfilingData| {
+            data.iter_full()
+                .map(|event| {
+                    let mut strings = vec![event.label.into_owned()];
+                    strings.extend(event.additional_data.into_iter().map(Cow::into_owned));
+                    strings
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let recorded = strings(&data);
+        assert_eq!(recorded[0], vec![MANGLED, MANGLED]);
+
+        assert!(!data.demangles_symbols());
+        data.demangle_symbols();
+        assert!(data.demangles_symbols());
+
+        // Decoding the events twice exercises the cache.
+        for _ in 0..2 {
+            assert_eq!(
+                strings(&data),
+                vec![
+                    vec!["core::ptr::drop_in_place", "core::ptr::drop_in_place"],
+                    vec!["core::ptr::drop_in_place", "core::ptr::drop_in_place"],
+                    vec!["typeck", "main"],
+                ]
+            );
+        }
+
+        // The events borrow the demangled strings from the cache.
+        let event = data.iter_full().next().unwrap();
+        assert!(matches!(event.label, Cow::Borrowed(_)));
+
+        // The recorded strings are left untouched.
+        let first = data.iter().next().unwrap();
+        assert!(data.label_bytes(&first).starts_with(MANGLED.as_bytes()));
+        assert_eq!(strings(&data.strip_args())[0], vec![MANGLED]);
+    }
+}
@@ -0,0 +1,48 @@
+//! Helpers for the tools that convert profiles to the trace formats of other
+//! tools, so that all of them name processes and express timestamps alike.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the crate that rustc has compiled, or the whole command line
+/// for other processes, given the `Metadata::cmd` of a profile.
+pub fn process_name(cmd: &str) -> &str {
+    match cmd.find(" --crate-name ") {
+        Some(index) => {
+            let rest = &cmd[index + " --crate-name ".len()..];
+            rest.split(' ').next().unwrap()
+        }
+        None => cmd.trim(),
+    }
+}
+
+/// The number of nanoseconds between the Unix epoch and `timestamp`, `0` for
+/// timestamps before the epoch.
+pub fn nanos_since_epoch(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn process_names() {
+        assert_eq!(
+            process_name("rustc --crate-name serde --edition=2018 src/lib.rs"),
+            "serde"
+        );
+        assert_eq!(process_name("rustc --crate-name serde"), "serde");
+        assert_eq!(process_name("my-tool --verbose "), "my-tool --verbose");
+    }
+
+    #[test]
+    fn timestamps_before_the_epoch() {
+        let after = UNIX_EPOCH + Duration::from_nanos(1234);
+        assert_eq!(nanos_since_epoch(after), 1234);
+        assert_eq!(nanos_since_epoch(UNIX_EPOCH - Duration::from_secs(1)), 0);
+    }
+}
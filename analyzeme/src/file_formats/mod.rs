@@ -1,5 +1,13 @@
-use decodeme::{event::Event, lightweight_event::LightweightEvent, Metadata};
+use decodeme::{
+    event::Event, lightweight_event::LightweightEvent, stringtable::StringMap, Metadata,
+    TraceContext,
+};
+use measureme::StringId;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::fmt::Debug;
+use std::path::Path;
 
 pub mod v7;
 pub mod v8;
@@ -8,8 +16,49 @@ pub use v8 as current;
 
 /// The [EventDecoder] knows how to decode events for a specific file format.
 pub trait EventDecoder: Debug + Send + Sync {
+    fn file_format_version(&self) -> u32;
+    /// The `FILE_FLAG_*` bits of the top-level file header.
+    fn file_flags(&self) -> u8;
+    /// The number of bytes at the end of the file(s) that have been ignored
+    /// because they don't form a complete page.
+    fn truncated_bytes(&self) -> u64;
     fn num_events(&self) -> usize;
     fn metadata(&self) -> &Metadata;
+    fn process_metadata(&self) -> &BTreeMap<String, String>;
+    /// The trace context that a `TRACE_CONTEXT_EVENT_KIND` marker with the
+    /// value `index` refers to.
+    fn trace_context_entry(&self, index: u64) -> Option<TraceContext>;
+    /// The strings of the string table, if the file format supports looking
+    /// them up by id.
+    fn string_map(&self) -> Option<StringMap<'_>>;
     fn decode_full_event<'a>(&'a self, event_index: usize) -> Event<'a>;
+    /// The string id of the event's event id, if the file format supports
+    /// looking strings up by id.
+    fn decode_event_id(&self, event_index: usize) -> Option<StringId>;
+    /// The string id of the event's event kind, if the file format supports
+    /// looking strings up by id.
+    fn decode_event_kind(&self, event_index: usize) -> Option<StringId>;
+    /// The actual bytes of the event's event id, which may not be valid
+    /// UTF-8.
+    fn decode_event_id_bytes<'a>(&'a self, event_index: usize) -> Cow<'a, [u8]>;
+    /// The bytes of the event id of the explicit parent of the interval
+    /// event at `event_index`, if it has been recorded with one.
+    fn decode_explicit_parent_id_bytes<'a>(&'a self, event_index: usize) -> Option<Cow<'a, [u8]>>;
+    /// The wall-clock duration in nanoseconds of the interval event at
+    /// `event_index`, if it has been recorded with one.
+    fn decode_wall_time_nanos(&self, event_index: usize) -> Option<u64>;
+    /// Whether the counter couldn't be read at the start or end of the
+    /// interval event at `event_index`.
+    fn decode_counter_unavailable(&self, event_index: usize) -> bool;
+    /// The trace context of the interval event at `event_index`, if it has
+    /// been recorded with one.
+    fn decode_trace_context(&self, event_index: usize) -> Option<TraceContext>;
     fn decode_lightweight_event<'a>(&'a self, event_index: usize) -> LightweightEvent;
+    /// Decodes `pages`, the complete pages that have been appended to the
+    /// file, see `decodeme::EventDecoder::append_pages`.
+    fn append_pages(
+        &mut self,
+        pages: &[u8],
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
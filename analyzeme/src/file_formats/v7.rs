@@ -1,14 +1,20 @@
 //! This module implements file loading for the v7 file format used until
 //! crate version 9.2.0
 
+use measureme::event_id::SEPARATOR_BYTE;
+use measureme::StringId;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::path::Path;
 
 use analyzeme_9_2_0::ProfilingData;
 use decodeme::{
     event::Event,
     event_payload::{EventPayload, Timestamp},
     lightweight_event::LightweightEvent,
-    Metadata,
+    stringtable::StringMap,
+    Metadata, TraceContext,
 };
 
 pub const FILE_FORMAT: u32 = analyzeme_9_2_0::CURRENT_FILE_FORMAT_VERSION;
@@ -17,6 +23,9 @@ pub const FILE_FORMAT: u32 = analyzeme_9_2_0::CURRENT_FILE_FORMAT_VERSION;
 pub struct EventDecoder {
     legacy_profiling_data: ProfilingData,
     metadata: Metadata,
+    // The v7 file format can't store any process metadata, so this is
+    // always empty.
+    process_metadata: BTreeMap<String, String>,
 }
 
 impl EventDecoder {
@@ -27,16 +36,34 @@ impl EventDecoder {
             start_time: legacy_profiling_data.metadata.start_time,
             cmd: legacy_profiling_data.metadata.cmd.clone(),
             process_id: legacy_profiling_data.metadata.process_id,
+            counter: None,
+            page_size: None,
+            wall_clock_start: None,
         };
 
         Ok(EventDecoder {
             legacy_profiling_data,
             metadata,
+            process_metadata: BTreeMap::new(),
         })
     }
 }
 
 impl super::EventDecoder for EventDecoder {
+    fn file_format_version(&self) -> u32 {
+        FILE_FORMAT
+    }
+
+    fn file_flags(&self) -> u8 {
+        // The v7 file format doesn't have any flags.
+        0
+    }
+
+    fn truncated_bytes(&self) -> u64 {
+        // Truncated v7 files fail to load.
+        0
+    }
+
     fn num_events(&self) -> usize {
         self.legacy_profiling_data.num_events()
     }
@@ -45,6 +72,20 @@ impl super::EventDecoder for EventDecoder {
         &self.metadata
     }
 
+    fn process_metadata(&self) -> &BTreeMap<String, String> {
+        &self.process_metadata
+    }
+
+    fn trace_context_entry(&self, _index: u64) -> Option<TraceContext> {
+        // The v7 file format doesn't have a trace context stream.
+        None
+    }
+
+    fn string_map(&self) -> Option<StringMap<'_>> {
+        // The legacy decoder doesn't expose its string table.
+        None
+    }
+
     fn decode_full_event(&self, event_index: usize) -> Event<'_> {
         let legacy_event = self.legacy_profiling_data.decode_full_event(event_index);
         let timestamp = convert_timestamp(legacy_event.timestamp);
@@ -52,10 +93,56 @@ impl super::EventDecoder for EventDecoder {
         Event {
             event_kind: legacy_event.event_kind,
             label: legacy_event.label,
+            category: None,
             additional_data: legacy_event.additional_data,
+            integer_args: Vec::new(),
+            backtrace: Vec::new(),
             thread_id: legacy_event.thread_id,
             payload: EventPayload::Timestamp(timestamp),
+            depth: None,
+        }
+    }
+
+    fn decode_event_id(&self, _event_index: usize) -> Option<StringId> {
+        // Like the string table, the string ids aren't exposed.
+        None
+    }
+
+    fn decode_event_kind(&self, _event_index: usize) -> Option<StringId> {
+        None
+    }
+
+    fn decode_event_id_bytes(&self, event_index: usize) -> Cow<'_, [u8]> {
+        // The v7 file format only supports UTF-8 strings, so the event id can
+        // be reassembled from the decoded event.
+        let legacy_event = self.legacy_profiling_data.decode_full_event(event_index);
+
+        let mut event_id = legacy_event.label.into_owned();
+        for arg in &legacy_event.additional_data {
+            event_id.push_str(SEPARATOR_BYTE);
+            event_id.push_str(arg);
         }
+
+        Cow::Owned(event_id.into_bytes())
+    }
+
+    fn decode_explicit_parent_id_bytes(&self, _event_index: usize) -> Option<Cow<'_, [u8]>> {
+        // The v7 file format doesn't support explicit parents.
+        None
+    }
+
+    fn decode_wall_time_nanos(&self, _event_index: usize) -> Option<u64> {
+        // The v7 file format doesn't support wall times.
+        None
+    }
+
+    fn decode_counter_unavailable(&self, _event_index: usize) -> bool {
+        // The v7 file format doesn't flag such events.
+        false
+    }
+
+    fn decode_trace_context(&self, _event_index: usize) -> Option<TraceContext> {
+        None
     }
 
     fn decode_lightweight_event(&self, event_index: usize) -> LightweightEvent {
@@ -68,6 +155,16 @@ impl super::EventDecoder for EventDecoder {
             payload: EventPayload::Timestamp(convert_timestamp(legacy_event.timestamp)),
         }
     }
+
+    fn append_pages(
+        &mut self,
+        _pages: &[u8],
+        _diagnostic_file_path: Option<&Path>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Err(From::from(
+            "Profiles in the v7 file format cannot be read incrementally",
+        ))
+    }
 }
 
 fn convert_timestamp(legacy_timestamp: analyzeme_9_2_0::Timestamp) -> Timestamp {
@@ -3,11 +3,28 @@
 
 use crate::{Event, LightweightEvent};
 pub use decodeme::EventDecoder;
-use decodeme::Metadata;
+use decodeme::{stringtable::StringMap, Metadata, TraceContext};
+use measureme::StringId;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
 
 pub const FILE_FORMAT: u32 = decodeme::CURRENT_FILE_FORMAT_VERSION;
 
 impl super::EventDecoder for EventDecoder {
+    fn file_format_version(&self) -> u32 {
+        FILE_FORMAT
+    }
+
+    fn file_flags(&self) -> u8 {
+        self.file_flags()
+    }
+
+    fn truncated_bytes(&self) -> u64 {
+        self.truncated_bytes() as u64
+    }
+
     fn num_events(&self) -> usize {
         self.num_events()
     }
@@ -16,11 +33,59 @@ impl super::EventDecoder for EventDecoder {
         self.metadata()
     }
 
+    fn process_metadata(&self) -> &BTreeMap<String, String> {
+        self.process_metadata()
+    }
+
+    fn trace_context_entry(&self, index: u64) -> Option<TraceContext> {
+        self.trace_contexts().get(index)
+    }
+
+    fn string_map(&self) -> Option<StringMap<'_>> {
+        Some(self.string_map())
+    }
+
     fn decode_full_event(&self, event_index: usize) -> Event<'_> {
         self.decode_full_event(event_index)
     }
 
+    fn decode_event_id(&self, event_index: usize) -> Option<StringId> {
+        Some(self.decode_event_id(event_index))
+    }
+
+    fn decode_event_kind(&self, event_index: usize) -> Option<StringId> {
+        Some(self.decode_event_kind(event_index))
+    }
+
+    fn decode_event_id_bytes(&self, event_index: usize) -> Cow<'_, [u8]> {
+        self.decode_event_id_bytes(event_index)
+    }
+
+    fn decode_explicit_parent_id_bytes(&self, event_index: usize) -> Option<Cow<'_, [u8]>> {
+        self.decode_explicit_parent_id_bytes(event_index)
+    }
+
+    fn decode_wall_time_nanos(&self, event_index: usize) -> Option<u64> {
+        self.decode_wall_time_nanos(event_index)
+    }
+
+    fn decode_counter_unavailable(&self, event_index: usize) -> bool {
+        self.decode_counter_unavailable(event_index)
+    }
+
+    fn decode_trace_context(&self, event_index: usize) -> Option<TraceContext> {
+        self.decode_trace_context(event_index)
+    }
+
     fn decode_lightweight_event(&self, event_index: usize) -> LightweightEvent {
         self.decode_lightweight_event(event_index)
     }
+
+    fn append_pages(
+        &mut self,
+        pages: &[u8],
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.append_pages(pages, diagnostic_file_path)
+    }
 }
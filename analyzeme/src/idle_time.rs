@@ -0,0 +1,229 @@
+//! Finding the stretches of time in which a thread had no active event.
+
+use crate::ProfilingData;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// How much of the time of a profile its threads have been busy, see
+/// `ProfilingData::activity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Activity {
+    /// The time from the start of the first interval event of the profile
+    /// to the end of its last one, on any thread.
+    pub wall_time: Duration,
+    /// The sum of the time in which each thread has had an active interval
+    /// event, i.e. the union of its intervals. Unlike the sum of the self
+    /// times, this doesn't count the same time twice for events that
+    /// overlap on one thread.
+    pub active_time: Duration,
+}
+
+impl Activity {
+    /// How many threads have been busy on average, `active_time` divided by
+    /// `wall_time`. `None` if the profile has no interval events with a
+    /// duration.
+    pub fn parallelism(&self) -> Option<f64> {
+        if self.wall_time == Duration::from_secs(0) {
+            return None;
+        }
+
+        Some(self.active_time.as_nanos() as f64 / self.wall_time.as_nanos() as f64)
+    }
+}
+
+impl ProfilingData {
+    /// Returns the stretches of time, as their start and end and in order,
+    /// in which none of the interval events of the thread `thread_id` has
+    /// been active, between the start of its first one and the end of its
+    /// last one. This tells a thread that has been blocked on something that
+    /// hasn't been recorded apart from one that has been busy.
+    ///
+    /// The intervals of the events are merged first, so nested events and
+    /// events that overlap, e.g. ones recorded with
+    /// `Profiler::start_recording_interval_event_detached`, never leave a
+    /// gap. Instant and integer events don't count as activity. Empty if the
+    /// thread has no interval events, or if it has never been idle.
+    pub fn idle_intervals(&self, thread_id: u32) -> Vec<(SystemTime, SystemTime)> {
+        self.busy_intervals(|event_thread_id| event_thread_id == thread_id)
+            .remove(&thread_id)
+            .map(|busy| busy.gaps())
+            .unwrap_or_default()
+    }
+
+    /// Like `idle_intervals`, but for all threads with interval events at
+    /// once, in a single pass over the profile.
+    pub fn idle_intervals_by_thread(&self) -> BTreeMap<u32, Vec<(SystemTime, SystemTime)>> {
+        self.busy_intervals(|_| true)
+            .into_iter()
+            .map(|(thread_id, busy)| (thread_id, busy.gaps()))
+            .collect()
+    }
+
+    /// Merges the intervals of the interval events of the threads for which
+    /// `include_thread` returns true, in a single pass over the profile.
+    fn busy_intervals(
+        &self,
+        include_thread: impl Fn(u32) -> bool,
+    ) -> FxHashMap<u32, BusyIntervals> {
+        let mut busy_intervals = FxHashMap::<u32, BusyIntervals>::default();
+        for event in self.iter() {
+            if !event.payload.is_interval() || !include_thread(event.thread_id) {
+                continue;
+            }
+            if let Some(timestamp) = event.timestamp() {
+                busy_intervals
+                    .entry(event.thread_id)
+                    .or_default()
+                    .add(timestamp.start(), timestamp.end());
+            }
+        }
+
+        busy_intervals
+    }
+
+    /// Computes the wall time of the profile and how much of it its threads
+    /// have been busy, which tells how well a parallel build has made use of
+    /// its threads. The intervals of each thread are merged like for
+    /// `idle_intervals`.
+    pub fn activity(&self) -> Activity {
+        let mut first_start = None;
+        let mut last_end = None;
+        let mut active_time = Duration::from_secs(0);
+        for busy in self.busy_intervals(|_| true).into_values() {
+            if let (Some(&(start, _)), Some(&(_, end))) = (busy.0.first(), busy.0.last()) {
+                first_start = Some(first_start.map_or(start, |first: SystemTime| first.min(start)));
+                last_end = Some(last_end.map_or(end, |last: SystemTime| last.max(end)));
+            }
+            for (start, end) in busy.0 {
+                active_time += end.duration_since(start).unwrap();
+            }
+        }
+
+        let wall_time = match (first_start, last_end) {
+            (Some(start), Some(end)) => end.duration_since(start).unwrap(),
+            _ => Duration::from_secs(0),
+        };
+
+        Activity {
+            wall_time,
+            active_time,
+        }
+    }
+}
+
+/// The stretches of time in which at least one of the interval events of a
+/// thread has been active, as their start and end, merged from the events as
+/// they are read. The stretches are disjoint and in order.
+#[derive(Debug, Default)]
+struct BusyIntervals(Vec<(SystemTime, SystemTime)>);
+
+impl BusyIntervals {
+    /// Merges the interval of an event into the stretches. Events are
+    /// recorded once they end, so this usually only merges the stretches at
+    /// the end that the event is nested in, like the stack of a thread.
+    /// Events that end before others, e.g. detached ones, are merged into
+    /// the stretches in the middle.
+    fn add(&mut self, start: SystemTime, end: SystemTime) {
+        let first = self.0.partition_point(|&(_, busy_end)| busy_end < start);
+        let last = self.0.partition_point(|&(busy_start, _)| busy_start <= end);
+
+        if first == last {
+            self.0.insert(first, (start, end));
+        } else {
+            let merged = (start.min(self.0[first].0), end.max(self.0[last - 1].1));
+            self.0.splice(first..last, std::iter::once(merged));
+        }
+    }
+
+    fn gaps(&self) -> Vec<(SystemTime, SystemTime)> {
+        self.0
+            .windows(2)
+            .map(|busy| (busy[0].1, busy[1].0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Activity;
+    use crate::ProfilingDataBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn gaps_between_merged_intervals() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 10, 100, |b| {
+            b.interval("Query", "nested", 0, 20, 50, |_| {});
+        });
+        // Overlaps the end of `outer` without being nested in it.
+        b.interval("Query", "overlapping", 0, 90, 120, |_| {});
+        b.instant("QueryCacheHit", "hit", 0, 130);
+        b.interval("Query", "after a gap", 0, 150, 160, |_| {});
+        b.interval("Query", "adjacent", 0, 160, 170, |_| {});
+        b.interval("Query", "after another gap", 0, 200, 210, |_| {});
+        b.interval("Query", "other thread", 1, 0, 300, |_| {});
+        let data = b.into_profiling_data();
+
+        let start_time = data.metadata().start_time;
+        let nanos = |nanos| start_time + Duration::from_nanos(nanos);
+        assert_eq!(
+            data.idle_intervals(0),
+            vec![(nanos(120), nanos(150)), (nanos(170), nanos(200))]
+        );
+        assert!(data.idle_intervals(1).is_empty());
+        assert!(data.idle_intervals(2).is_empty());
+
+        let by_thread = data.idle_intervals_by_thread();
+        assert_eq!(by_thread.keys().copied().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(by_thread[&0], data.idle_intervals(0));
+        assert!(by_thread[&1].is_empty());
+    }
+
+    #[test]
+    fn events_that_end_before_earlier_ones() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "late", 0, 100, 110, |_| {});
+        // Recorded after `late`, like a detached event that has ended late.
+        b.interval("Query", "early", 0, 10, 20, |_| {});
+        b.interval("Query", "bridging", 0, 15, 60, |_| {});
+        b.interval("Query", "last", 0, 200, 210, |_| {});
+        let data = b.into_profiling_data();
+
+        let start_time = data.metadata().start_time;
+        let nanos = |nanos| start_time + Duration::from_nanos(nanos);
+        assert_eq!(
+            data.idle_intervals(0),
+            vec![(nanos(60), nanos(100)), (nanos(110), nanos(200))]
+        );
+    }
+
+    #[test]
+    fn activity_of_parallel_threads() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 100, 200, |b| {
+            b.interval("Query", "nested", 0, 110, 150, |_| {});
+        });
+        b.interval("Query", "overlapping", 0, 180, 220, |_| {});
+        b.interval("Query", "after a gap", 0, 300, 400, |_| {});
+        b.interval("Query", "concurrent", 1, 150, 350, |_| {});
+        b.instant("QueryCacheHit", "hit", 2, 0);
+        let data = b.into_profiling_data();
+
+        // Thread 0 is busy from 100 to 220 and from 300 to 400, thread 1
+        // from 150 to 350, and the instant event doesn't count.
+        let activity = data.activity();
+        assert_eq!(
+            activity,
+            Activity {
+                wall_time: Duration::from_nanos(300),
+                active_time: Duration::from_nanos(420),
+            }
+        );
+        assert_eq!(activity.parallelism(), Some(1.4));
+
+        let empty = ProfilingDataBuilder::new().into_profiling_data();
+        assert_eq!(empty.activity(), Activity::default());
+        assert_eq!(empty.activity().parallelism(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::file_formats;
+use crate::ProfilingData;
+use decodeme::read_file_header;
+use measureme::file_header::{
+    FILE_EXTENSION, FILE_FORMAT_VERSION_MASK, FILE_HEADER_SIZE, FILE_MAGIC_TOP_LEVEL,
+};
+use measureme::{complete_pages_len, iter_pages, PageTag};
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// A profile that is read while it is still being written, see
+/// [`ProfilingData::open_incremental()`].
+#[derive(Debug)]
+pub struct IncrementalProfilingData {
+    path: PathBuf,
+    file: fs::File,
+    // What has been read from `file` but not decoded yet. The first
+    // `complete_len` bytes, i.e. the top-level file header and the complete
+    // pages, are kept until there is enough data for decoding events, the
+    // rest belongs to a page that isn't complete yet.
+    bytes: Vec<u8>,
+    complete_len: usize,
+    has_events: bool,
+    has_string_data: bool,
+    has_string_index: bool,
+    data: Option<ProfilingData>,
+}
+
+impl ProfilingData {
+    /// Opens the profile at `path_stem` for reading it while it is still
+    /// being written, e.g. to show live statistics of a running process.
+    /// Initially it contains all pages that have been written completely, and
+    /// [`IncrementalProfilingData::refresh()`] picks up the pages that have
+    /// been written since.
+    ///
+    /// This relies on the profiler only ever appending complete pages to the
+    /// file, in one go and without modifying them afterwards, which is what
+    /// the measureme `SerializationSink` does. A partially written page at the
+    /// end of the file is ignored until it is complete. Since the profiler
+    /// buffers a whole page of each stream before writing it, events can only
+    /// be read once pages of the event stream and of both string table streams
+    /// have been written, and strings that haven't been written yet are
+    /// decoded as `<unknown>`. Profiles that are split into multiple files
+    /// (see `ProfilerOptions::max_file_bytes`) aren't supported.
+    pub fn open_incremental(
+        path_stem: &Path,
+    ) -> Result<IncrementalProfilingData, Box<dyn Error + Send + Sync>> {
+        let path = path_stem.with_extension(FILE_EXTENSION);
+        let file = fs::File::open(&path)?;
+
+        let mut data = IncrementalProfilingData {
+            path,
+            file,
+            bytes: Vec::new(),
+            complete_len: 0,
+            has_events: false,
+            has_string_data: false,
+            has_string_index: false,
+            data: None,
+        };
+
+        data.refresh()?;
+        Ok(data)
+    }
+}
+
+impl IncrementalProfilingData {
+    /// Reads the data that has been appended to the file since the last call
+    /// and returns whether it contained any new complete pages. Only the new
+    /// pages are decoded, and only their strings and events get indexed, so
+    /// this takes time proportional to the size of the new data.
+    pub fn refresh(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.file.read_to_end(&mut self.bytes)?;
+
+        if self.data.is_none() && self.complete_len == 0 {
+            if self.bytes.len() < FILE_HEADER_SIZE {
+                return Ok(false);
+            }
+
+            let file_format_version = read_file_header(
+                &self.bytes,
+                FILE_MAGIC_TOP_LEVEL,
+                Some(&self.path),
+                "top-level",
+            )? & FILE_FORMAT_VERSION_MASK;
+
+            if file_format_version != file_formats::current::FILE_FORMAT {
+                let msg = format!(
+                    "Cannot read `{}` incrementally: only file format version {} is supported, but the file has version {}.",
+                    self.path.display(),
+                    file_formats::current::FILE_FORMAT,
+                    file_format_version
+                );
+                return Err(From::from(msg));
+            }
+
+            self.complete_len = FILE_HEADER_SIZE;
+        }
+
+        let new_pages_len = complete_pages_len(&self.bytes[self.complete_len..]);
+        if new_pages_len == 0 {
+            return Ok(false);
+        }
+
+        let new_pages = &self.bytes[self.complete_len..self.complete_len + new_pages_len];
+        if let Some(ref mut data) = self.data {
+            data.append_pages(new_pages, Some(&self.path))?;
+            self.bytes.drain(..new_pages_len);
+            return Ok(true);
+        }
+
+        for (tag, _) in iter_pages(new_pages) {
+            match tag {
+                PageTag::Events => self.has_events = true,
+                PageTag::StringData => self.has_string_data = true,
+                PageTag::StringIndex => self.has_string_index = true,
+                PageTag::Metadata
+                | PageTag::TraceContext
+                | PageTag::TimestampEpochIndex
+                | PageTag::Segment => {}
+            }
+        }
+
+        self.complete_len += new_pages_len;
+
+        if self.has_events && self.has_string_data && self.has_string_index {
+            let incomplete = self.bytes.split_off(self.complete_len);
+            let bytes = mem::replace(&mut self.bytes, incomplete);
+            self.data = Some(ProfilingData::from_paged_buffer(bytes, Some(&self.path))?);
+            self.complete_len = 0;
+        }
+
+        Ok(true)
+    }
+
+    /// The events that have been read so far, or `None` if the file doesn't
+    /// contain enough data for reading any events yet.
+    pub fn profiling_data(&self) -> Option<&ProfilingData> {
+        self.data.as_ref()
+    }
+}
@@ -6,19 +6,73 @@
 //!
 //! To create a [`ProfilingData`], call the [`ProfilingData::new()`] function and
 //! provide a `Path` with the directory and file name for the trace files.
+//! Trace files that have been gzipped as a whole, e.g. to archive them, are
+//! decompressed transparently.
 //!
 //! To retrieve an `Iterator` of all of the events in the file,
-//! call the [`ProfilingData::iter()`] method.
+//! call the [`ProfilingData::iter()`] method. [`ProfilingData::events_in_range()`]
+//! only returns the events within a window of time,
+//! [`ProfilingData::events_sorted_by_time()`] merges the events of all
+//! threads into a single timeline, and [`ProfilingData::call_tree()`]
+//! reconstructs how the interval events of a thread are nested within each
+//! other.
+//!
+//! Profiles that are still being written can be read with
+//! [`ProfilingData::open_incremental()`], which picks up new events every
+//! time [`IncrementalProfilingData::refresh()`] is called.
+//!
+//! The results of [`ProfilingData::perform_analysis()`] can be stored in a
+//! compact binary summary file with [`AnalysisResults::write_summary()`] and
+//! read back with [`AnalysisResults::read_summary()`], see [`summary_file`].
 
 mod analysis;
+mod call_tree;
+mod children_breakdown;
+mod demangle;
+mod export;
 mod file_formats;
+mod idle_time;
+mod incremental;
+mod matching_events;
+mod open_ended;
+#[cfg(feature = "rayon")]
+mod parallel;
 mod profiling_data;
+mod self_time;
+mod sorted_events;
 mod stack_collapse;
+mod strip_args;
+pub mod summary_file;
+mod tdigest;
 pub mod testing_common;
+mod time_range;
+mod timeline;
+mod top_events;
+mod validation;
+mod warnings;
 
-pub use crate::profiling_data::{ProfilingData, ProfilingDataBuilder};
-pub use crate::stack_collapse::collapse_stacks;
-pub use analysis::{AnalysisResults, ArtifactSize, QueryData};
+pub use crate::call_tree::CallTreeNode;
+pub use crate::children_breakdown::{ChildDuration, ChildrenBreakdown};
+pub use crate::demangle::demangle;
+pub use crate::export::{nanos_since_epoch, process_name};
+pub use crate::idle_time::Activity;
+pub use crate::incremental::IncrementalProfilingData;
+pub use crate::profiling_data::{ProfilingData, ProfilingDataBuilder, SpansByTraceId};
+pub use crate::self_time::EventSelfTime;
+pub use crate::stack_collapse::{
+    collapse_stacks, collapse_stacks_folded, collapse_stacks_weighted,
+    collapse_stacks_with_categories, collapse_stacks_with_categories_weighted,
+};
+pub use crate::time_range::TimeRange;
+pub use crate::timeline::{BucketAssignment, TimelineBucket, MAX_TIMELINE_BUCKETS};
+pub use crate::validation::{ValidationError, ValidationErrorKind};
+pub use crate::warnings::{profile_warnings, warn_about_profile};
+pub use analysis::{
+    is_artifact_size, AnalysisResults, ArtifactSize, InstantValues, LatencyPercentiles, QueryData,
+};
 pub use decodeme::event::Event;
 pub use decodeme::event_payload::{EventPayload, Timestamp};
+pub use decodeme::event_stream::EventStream;
 pub use decodeme::lightweight_event::LightweightEvent;
+pub use decodeme::stringtable::StringMap;
+pub use decodeme::{CounterDescription, Metadata, TraceContext};
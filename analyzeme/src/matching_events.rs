@@ -0,0 +1,108 @@
+//! Finding the events of a thread, with a label, or both.
+
+use crate::{LightweightEvent, ProfilingData};
+
+impl ProfilingData {
+    /// Returns the events, in the order they have been recorded in, that
+    /// have been recorded by the thread `thread_id` and whose label is
+    /// `label`, e.g. all `codegen_module` events of thread 3. `None` matches
+    /// every thread or label, respectively.
+    ///
+    /// This is still a linear scan over all events of the profile, it just
+    /// saves decoding the full events: the label of an event is compared
+    /// with the bytes looked up in the string table, and only for the events
+    /// of `thread_id`. Labels have to match exactly, there is no support for
+    /// patterns, which would require decoding every label. To only get the
+    /// events within a window of time as well, filter the result of
+    /// `events_in_range` with `event_matches` instead.
+    pub fn events_matching<'a>(
+        &'a self,
+        thread_id: Option<u32>,
+        label: Option<&'a str>,
+    ) -> impl DoubleEndedIterator<Item = LightweightEvent> + 'a {
+        self.iter()
+            .filter(move |event| self.event_matches(event, thread_id, label))
+    }
+
+    /// Whether `event` would be returned by `events_matching` with the same
+    /// arguments.
+    pub fn event_matches(
+        &self,
+        event: &LightweightEvent,
+        thread_id: Option<u32>,
+        label: Option<&str>,
+    ) -> bool {
+        if let Some(thread_id) = thread_id {
+            if event.thread_id != thread_id {
+                return false;
+            }
+        }
+
+        match label {
+            Some(label) => &self.label_bytes(event)[..] == label.as_bytes(),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ProfilingDataBuilder;
+
+    #[test]
+    fn events_by_thread_and_label() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "codegen_module", 0, 0, 100, |b| {
+            b.interval("Query", "codegen_module\x1Ewith args", 0, 10, 20, |_| {});
+            b.instant("Query", "typeck", 0, 30);
+        });
+        b.interval("Query", "codegen_module", 3, 0, 50, |_| {});
+        b.interval("Query", "typeck", 3, 60, 70, |_| {});
+        b.interval("Query", "codegen_module", 3, 200, 300, |_| {});
+        let data = b.into_profiling_data();
+
+        let matching = |thread_id, label| {
+            data.events_matching(thread_id, label)
+                .map(|event| {
+                    let full_event = data.to_full_event(&event);
+                    (full_event.thread_id, full_event.label.into_owned())
+                })
+                .collect::<Vec<_>>()
+        };
+        let event = |thread_id, label: &str| (thread_id, label.to_string());
+
+        // The arguments aren't part of the label.
+        assert_eq!(
+            matching(None, Some("codegen_module")),
+            vec![
+                event(0, "codegen_module"),
+                event(0, "codegen_module"),
+                event(3, "codegen_module"),
+                event(3, "codegen_module"),
+            ]
+        );
+        assert_eq!(
+            matching(Some(3), None),
+            vec![
+                event(3, "codegen_module"),
+                event(3, "typeck"),
+                event(3, "codegen_module"),
+            ]
+        );
+        assert_eq!(
+            matching(Some(3), Some("codegen_module")),
+            vec![event(3, "codegen_module"), event(3, "codegen_module")]
+        );
+        assert_eq!(matching(None, None).len(), data.num_events());
+        assert!(matching(Some(1), None).is_empty());
+        assert!(matching(None, Some("codegen")).is_empty());
+
+        // Combined with a window of time.
+        let in_range: Vec<_> = data
+            .events_in_range(0, 100)
+            .filter(|event| data.event_matches(event, Some(3), Some("codegen_module")))
+            .map(|event| event.event_index)
+            .collect();
+        assert_eq!(in_range.len(), 1);
+    }
+}
@@ -0,0 +1,125 @@
+//! Spans recorded with `Profiler::open_ended_span` that have never been
+//! closed.
+
+use crate::{EventPayload, LightweightEvent, ProfilingData, Timestamp};
+use rustc_hash::FxHashMap;
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// The event id of an `OPEN_INTERVAL_EVENT_KIND` marker, and the marker.
+type OpenMarker = (Vec<u8>, LightweightEvent);
+
+impl ProfilingData {
+    /// The spans of the profile that have been opened with
+    /// `Profiler::open_ended_span` but never closed, e.g. because the
+    /// process has exited while still holding the lock they stand for. Each
+    /// is an interval event from the start of the span to the last timestamp
+    /// of the profile, whose `event_index` is the one of the span's
+    /// `OPEN_INTERVAL_EVENT_KIND` marker, so `to_full_event` gives its label
+    /// and arguments. They are ordered as if they had been closed at the end
+    /// of the profile, innermost first.
+    ///
+    /// `call_tree`, `validate` and the collapsed stacks include these, all
+    /// other analyses only see the intervals that have been recorded.
+    pub fn unclosed_intervals(&self) -> &[LightweightEvent] {
+        &self.unclosed_intervals
+    }
+}
+
+/// The spans whose `OPEN_INTERVAL_EVENT_KIND` marker has been found among the
+/// events that `ProfilingData::index_events` has indexed so far, but not
+/// their interval event, see `ProfilingData::unclosed_intervals`.
+#[derive(Debug, Default)]
+pub(crate) struct OpenSpans {
+    // A closed span is an interval event of the same thread with the same
+    // event id that starts when its marker has been recorded. The markers are
+    // looked up by thread and start first, so the event ids of the other
+    // intervals don't have to be read.
+    open: FxHashMap<(u32, SystemTime), Vec<OpenMarker>>,
+    last_timestamp: Option<SystemTime>,
+}
+
+impl OpenSpans {
+    /// Adds the span of `marker`. `indexed` are the events after the marker
+    /// that have already been passed to `record`, which happens if the event
+    /// kind of the marker has been written after them, so they are searched
+    /// for the interval event that closes the span.
+    pub(crate) fn open(
+        &mut self,
+        data: &ProfilingData,
+        marker: LightweightEvent,
+        indexed: Range<usize>,
+    ) {
+        let start = match marker.start() {
+            Some(start) => start,
+            None => return,
+        };
+
+        let event_id = data.event_id_bytes(&marker).into_owned();
+        let is_closed = indexed
+            .map(|i| data.decode_lightweight_event(i))
+            .any(|event| {
+                event.payload.is_interval()
+                    && event.thread_id == marker.thread_id
+                    && event.start() == Some(start)
+                    && data.event_id_bytes(&event)[..] == event_id[..]
+            });
+        if !is_closed {
+            self.open
+                .entry((marker.thread_id, start))
+                .or_default()
+                .push((event_id, marker));
+        }
+    }
+
+    /// Closes the span that `event`, the event after those that have been
+    /// recorded before, is the interval event of, if any.
+    pub(crate) fn record(&mut self, data: &ProfilingData, event: &LightweightEvent) {
+        let timestamp = match event.timestamp() {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+
+        self.last_timestamp = Some(match self.last_timestamp {
+            Some(last_timestamp) => last_timestamp.max(timestamp.end()),
+            None => timestamp.end(),
+        });
+        if self.open.is_empty() || !event.payload.is_interval() {
+            return;
+        }
+
+        let key = (event.thread_id, timestamp.start());
+        if let Some(markers) = self.open.get_mut(&key) {
+            let event_id = data.event_id_bytes(event);
+            if let Some(i) = markers.iter().position(|(id, _)| id[..] == event_id[..]) {
+                markers.swap_remove(i);
+                if markers.is_empty() {
+                    self.open.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// The spans that are still open, as interval events that end at the
+    /// last timestamp of the events that have been recorded.
+    pub(crate) fn unclosed_intervals(&self) -> Vec<LightweightEvent> {
+        let end = self.last_timestamp.unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut unclosed: Vec<_> = self
+            .open
+            .values()
+            .flatten()
+            .map(|(_, marker)| LightweightEvent {
+                payload: EventPayload::Timestamp(Timestamp::Interval {
+                    start: marker.start().unwrap(),
+                    end,
+                }),
+                ..marker.clone()
+            })
+            .collect();
+
+        // All of them end at the same time, so the one that has started last
+        // is the innermost one, which would have been closed first.
+        unclosed.sort_by_key(|event| (std::cmp::Reverse(event.start()), event.event_index));
+        unclosed
+    }
+}
@@ -0,0 +1,160 @@
+//! Decoding the events of a profile on multiple threads, with the `rayon`
+//! feature.
+
+use crate::ProfilingData;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+
+/// The number of events that are decoded as one unit of work. This is large
+/// enough for the overhead of distributing the work not to matter and small
+/// enough for large profiles to be spread evenly across all threads.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl ProfilingData {
+    /// Decodes the events in parallel and groups them by the thread that has
+    /// recorded them, ordered by thread id. The indices of the events of each
+    /// thread (see `decode_lightweight_event`) are in the order in which the
+    /// events have been recorded, so anything that reconstructs the nesting
+    /// of a thread's events, like `perform_analysis` or `call_tree`, can
+    /// process each thread on its own and merge the results afterwards.
+    pub fn par_events_by_thread(&self) -> Vec<(u32, Vec<usize>)> {
+        let num_events = self.num_events();
+
+        let chunks: Vec<FxHashMap<u32, Vec<usize>>> = (0..num_events.div_ceil(CHUNK_SIZE))
+            .into_par_iter()
+            .map(|chunk| {
+                let start = chunk * CHUNK_SIZE;
+                let end = std::cmp::min(start + CHUNK_SIZE, num_events);
+
+                let mut events_by_thread = FxHashMap::<u32, Vec<usize>>::default();
+                for event_index in start..end {
+                    let thread_id = self.decode_lightweight_event(event_index).thread_id;
+                    events_by_thread
+                        .entry(thread_id)
+                        .or_default()
+                        .push(event_index);
+                }
+                events_by_thread
+            })
+            .collect();
+
+        // The chunks are in the order of their events, so appending them to
+        // each other keeps the events of each thread in order.
+        let mut events_by_thread = FxHashMap::<u32, Vec<usize>>::default();
+        for chunk in chunks {
+            for (thread_id, event_indices) in chunk {
+                events_by_thread
+                    .entry(thread_id)
+                    .or_default()
+                    .extend(event_indices);
+            }
+        }
+
+        let mut events_by_thread: Vec<_> = events_by_thread.into_iter().collect();
+        events_by_thread.sort_by_key(|&(thread_id, _)| thread_id);
+        events_by_thread
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyze_events;
+    use crate::stack_collapse::ThreadStacks;
+    use crate::{collapse_stacks, CallTreeNode, LightweightEvent, ProfilingDataBuilder};
+    use std::borrow::Cow;
+
+    fn interleaved_threads(num_events: u64) -> ProfilingData {
+        let mut b = ProfilingDataBuilder::new();
+
+        for i in 0..num_events {
+            let thread_id = (i % 3) as u32;
+            let start = i * 10;
+            b.interval("Query", "outer", thread_id, start, start + 9, |b| {
+                b.interval("Query", "inner", thread_id, start + 1, start + 5, |_| {});
+                b.instant("QueryCacheHit", "inner", thread_id, start + 6);
+            });
+        }
+
+        b.into_profiling_data()
+    }
+
+    #[test]
+    fn events_are_grouped_by_thread_in_order() {
+        let data = interleaved_threads(CHUNK_SIZE as u64 / 2);
+
+        let events_by_thread = data.par_events_by_thread();
+        let thread_ids: Vec<_> = events_by_thread.iter().map(|&(id, _)| id).collect();
+        assert_eq!(thread_ids, vec![0, 1, 2]);
+
+        for (thread_id, event_indices) in events_by_thread {
+            let expected: Vec<_> = data
+                .iter()
+                .filter(|event| event.thread_id == thread_id)
+                .map(|event| event.event_index)
+                .collect();
+            assert_eq!(event_indices, expected);
+        }
+    }
+
+    #[test]
+    fn parallel_analysis_matches_sequential_analysis() {
+        let data = interleaved_threads(CHUNK_SIZE as u64 / 2);
+        let events = data.iter_full().rev().map(|event| (event, None));
+        let sequential = analyze_events(events, false).into_results();
+
+        let mut expected: Vec<_> = sequential
+            .query_data
+            .iter()
+            .map(|q| (q.label.clone(), q.self_time, q.time, q.invocation_count))
+            .collect();
+        expected.sort();
+
+        let parallel = data.perform_analysis();
+        let mut actual: Vec<_> = parallel
+            .query_data
+            .iter()
+            .map(|q| (q.label.clone(), q.self_time, q.time, q.invocation_count))
+            .collect();
+        actual.sort();
+
+        assert_eq!(actual, expected);
+        assert_eq!(parallel.total_time, sequential.total_time);
+    }
+
+    #[test]
+    fn parallel_call_trees_match_sequential_call_trees() {
+        let data = interleaved_threads(CHUNK_SIZE as u64 / 2);
+
+        let call_trees = data.call_trees();
+        assert_eq!(
+            call_trees.keys().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        for (&thread_id, call_tree) in &call_trees {
+            assert_eq!(*call_tree, data.call_tree(thread_id));
+        }
+    }
+
+    #[test]
+    fn parallel_stack_collapsing_matches_sequential_stack_collapsing() {
+        let data = interleaved_threads(CHUNK_SIZE as u64 / 2);
+        fn frame_name(node: &CallTreeNode) -> Cow<'_, str> {
+            Cow::Borrowed(&node.label[..])
+        }
+
+        let stacks = ThreadStacks::new(&data, frame_name, None::<fn(&LightweightEvent) -> u64>);
+        let mut sequential = FxHashMap::default();
+        let rustc_time: u64 = (0..3)
+            .map(|thread_id| stacks.collapse(&data.call_tree(thread_id), &mut sequential))
+            .sum();
+        sequential.insert("rustc".to_owned(), rustc_time);
+
+        let parallel = collapse_stacks(&data);
+        assert_eq!(parallel, sequential);
+
+        // The stacks are merged in the same order every time.
+        let stacks = |stacks: FxHashMap<String, u64>| stacks.into_iter().collect::<Vec<_>>();
+        assert_eq!(stacks(collapse_stacks(&data)), stacks(parallel));
+    }
+}
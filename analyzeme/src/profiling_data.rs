@@ -1,20 +1,77 @@
+use crate::demangle::DemangleCache;
 use crate::file_formats::EventDecoder;
-use crate::{file_formats, Event, LightweightEvent};
-use decodeme::{read_file_header, Metadata};
+use crate::open_ended::OpenSpans;
+use crate::{file_formats, Event, EventPayload, LightweightEvent, Timestamp};
+use decodeme::event_stream::EventStream;
+use decodeme::{
+    read_file_header, stringtable::StringMap, CounterDescription, Metadata, TraceContext,
+};
+use measureme::event_id::{
+    escape_text, BACKTRACE_FRAME_TAG_BYTE, CATEGORY_TAG_BYTE, INTEGER_ARG_TAG_BYTE,
+    OMITTED_ARGS_TAG_BYTE, SEPARATOR_BYTE,
+};
 use measureme::file_header::{
-    write_file_header, FILE_EXTENSION, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_TOP_LEVEL,
+    check_file_format_version, segment_file_path, write_file_header, write_top_level_file_header,
+    TopLevelFileHeader, FILE_CODEC_NONE, FILE_EXTENSION, FILE_FLAG_ARGS_BUDGET,
+    FILE_FLAG_EXPLICIT_PARENTS, FILE_FLAG_LABELS_ONLY, FILE_FLAG_RESUMED, FILE_FLAG_SAMPLED,
+    FILE_FLAG_TRACE_CONTEXT, FILE_FLAG_WALL_TIME, FILE_FORMAT_VERSION_MASK, FILE_HEADER_SIZE,
+    FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
+    FILE_MAGIC_TOP_LEVEL, FILE_MAGIC_TRACE_CONTEXT,
 };
+use measureme::stringtable::STRING_INDEX_ENTRY_SIZE;
 use measureme::{
-    EventId, PageTag, RawEvent, SerializationSink, SerializationSinkBuilder, StringTableBuilder,
+    complete_pages_len, decode_trace_contexts, iter_pages, split_streams, write_page_to, Addr,
+    EventId, InMemorySink, PageTag, ProcessMetadataWriter, RawEvent, SerializationSink,
+    SerializationSinkBuilder, StringId, StringTableBuilder, TimestampEpochIndexWriter,
+    TraceContextWriter, ARGS_DROPPED_EVENT_KIND, OPEN_INTERVAL_EVENT_KIND,
+    PARENT_EVENT_ID_EVENT_KIND, THREAD_NAME_EVENT_KIND, THREAD_TIMESTAMP_EPOCH_EVENT_KIND,
+    TIMESTAMP_EPOCH_EVENT_KIND, TIMESTAMP_EPOCH_LENGTH, TRACE_CONTEXT_ENTRY_SIZE,
+    TRACE_CONTEXT_EVENT_KIND, TRACE_CONTEXT_PRELUDE_SIZE, WALL_TIME_EVENT_KIND,
 };
-use std::fs;
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::mem;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 use std::{error::Error, path::PathBuf};
 
 #[derive(Debug)]
 pub struct ProfilingData {
     event_decoder: Box<dyn EventDecoder>,
+    pub(crate) markers: Markers,
+    /// Set by `demangle_symbols`.
+    pub(crate) demangle_cache: Option<DemangleCache>,
+    pub(crate) file_flags: u8,
+    pub(crate) process_metadata: BTreeMap<String, String>,
+    /// See `unclosed_intervals`.
+    pub(crate) unclosed_intervals: Vec<LightweightEvent>,
+    /// Built by the first call of `spans_by_trace_id`.
+    spans_by_trace_id: OnceLock<SpansByTraceId>,
+}
+
+/// The interval events that have a trace context, grouped by trace id, see
+/// `ProfilingData::spans_by_trace_id`.
+pub type SpansByTraceId = BTreeMap<u128, Vec<(TraceContext, LightweightEvent)>>;
+
+/// The markers that `ProfilingData::index_events` has found among the events
+/// that have been indexed so far.
+#[derive(Debug, Default)]
+pub(crate) struct Markers {
+    /// Whether the string of an event kind is one of the marker kinds.
+    kinds: FxHashMap<StringId, bool>,
+    /// The last name of each thread, and the index of the event recording it.
+    pub(crate) thread_names: FxHashMap<u32, (usize, String)>,
+    pub(crate) args_dropped_at: Option<SystemTime>,
+    open_spans: OpenSpans,
+    /// Instant events that may be markers, but whose event kind, or the
+    /// thread name they record, hasn't been written yet.
+    pending: Vec<usize>,
 }
 
 impl ProfilingData {
@@ -46,50 +103,537 @@ impl ProfilingData {
         }
     }
 
+    /// Opens a profile that has been split into multiple files because of
+    /// `ProfilerOptions::max_file_bytes` and presents it as a single stream
+    /// of events. All files in `path_stem`'s directory that belong to the
+    /// profile are read, in the order they were written. Segments that have
+    /// been deleted are skipped.
+    pub fn from_rotated_files(
+        path_stem: &Path,
+    ) -> Result<ProfilingData, Box<dyn Error + Send + Sync>> {
+        let segment_indices = find_segment_indices(path_stem)?;
+
+        if segment_indices.is_empty() {
+            let msg = format!(
+                "Could not find any profiling data files for `{}`.",
+                path_stem.display()
+            );
+            return Err(From::from(msg));
+        }
+
+        // The segments are combined into a single file. Their string tables
+        // are appended to each other, with the addresses in their string
+        // indices moved by where each one starts, and the events pages of
+        // the later segments continue the events stream of the first one.
+        // Their copies of the events stream header, which is all that their
+        // first events page contains, are left out. All segments have the
+        // whole metadata stream, so only the one of the first is kept.
+        let first_path = segment_file_path(path_stem, segment_indices[0]);
+        let mut header = Vec::new();
+        let mut string_data = Vec::new();
+        write_file_header(&mut string_data, FILE_MAGIC_STRINGTABLE_DATA)?;
+        let mut string_index = Vec::new();
+        write_file_header(&mut string_index, FILE_MAGIC_STRINGTABLE_INDEX)?;
+        let mut metadata = None;
+        let mut trace_contexts: Option<(u64, Vec<u8>)> = None;
+        let mut events_pages = Vec::new();
+        let mut incomplete = &[][..];
+
+        let segments = segment_indices
+            .iter()
+            .map(|&segment_index| {
+                let path = segment_file_path(path_stem, segment_index);
+                let segment = gunzip_if_needed(fs::read(&path)?, Some(&path))?;
+                read_file_header(&segment, FILE_MAGIC_TOP_LEVEL, Some(&path), "top-level")?;
+                Ok(segment)
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+
+        for (i, segment) in segments.iter().enumerate() {
+            if i == 0 {
+                header.extend_from_slice(&segment[..FILE_HEADER_SIZE]);
+            }
+
+            let pages = &segment[FILE_HEADER_SIZE..];
+            let complete_len = complete_pages_len(pages);
+            let mut streams = split_streams(&pages[..complete_len]);
+
+            let data_offset = (string_data.len() - FILE_HEADER_SIZE) as u32;
+            if let Some(data) = streams.remove(&PageTag::StringData) {
+                string_data.extend_from_slice(data.get(FILE_HEADER_SIZE..).unwrap_or_default());
+            }
+            if let Some(index) = streams.remove(&PageTag::StringIndex) {
+                let entries = index.get(FILE_HEADER_SIZE..).unwrap_or_default();
+                for entry in entries.chunks_exact(STRING_INDEX_ENTRY_SIZE) {
+                    let addr = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                    string_index.extend_from_slice(&entry[0..4]);
+                    string_index.extend_from_slice(&(addr + data_offset).to_le_bytes());
+                }
+            }
+
+            if metadata.is_none() {
+                metadata = streams.remove(&PageTag::Metadata);
+            }
+
+            // The trace context stream of each segment starts with the index
+            // of its first entry, which follows the entries of the segments
+            // before it, unless some of these have been deleted.
+            if let Some(stream) = streams.remove(&PageTag::TraceContext) {
+                let contexts = decode_trace_contexts(&stream, Some(&first_path))?;
+                let entries = &stream[TRACE_CONTEXT_PRELUDE_SIZE..];
+                match trace_contexts {
+                    None => trace_contexts = Some((contexts.first_index, entries.to_vec())),
+                    Some((first_index, ref mut all_entries)) => {
+                        let end_index =
+                            first_index + (all_entries.len() / TRACE_CONTEXT_ENTRY_SIZE) as u64;
+                        if contexts.first_index >= end_index {
+                            let gap = (contexts.first_index - end_index) as usize;
+                            all_entries
+                                .resize(all_entries.len() + gap * TRACE_CONTEXT_ENTRY_SIZE, 0);
+                            all_entries.extend_from_slice(entries);
+                        } else {
+                            let overlap = (end_index - contexts.first_index) as usize;
+                            all_entries.extend_from_slice(
+                                entries
+                                    .get(overlap * TRACE_CONTEXT_ENTRY_SIZE..)
+                                    .unwrap_or_default(),
+                            );
+                        }
+                    }
+                }
+            }
+
+            let mut has_stream_header = i > 0;
+            for (tag, page_contents) in iter_pages(&pages[..complete_len]) {
+                if tag == PageTag::Events {
+                    if has_stream_header {
+                        has_stream_header = false;
+                        continue;
+                    }
+                    write_page_to(&mut events_pages, PageTag::Events, page_contents)?;
+                }
+            }
+
+            // Only the last segment can be incomplete, if the profiler has
+            // been killed. Its incomplete page is kept, so that it is
+            // reported as truncated.
+            if i == segments.len() - 1 {
+                incomplete = &pages[complete_len..];
+            }
+        }
+
+        let mut data = header;
+        write_page_to(
+            &mut data,
+            PageTag::Segment,
+            &segment_indices[0].to_le_bytes(),
+        )?;
+        write_page_to(&mut data, PageTag::StringData, &string_data)?;
+        write_page_to(&mut data, PageTag::StringIndex, &string_index)?;
+        if let Some(metadata) = metadata {
+            write_page_to(&mut data, PageTag::Metadata, &metadata)?;
+        }
+        if let Some((first_index, entries)) = trace_contexts {
+            let mut stream = Vec::new();
+            write_file_header(&mut stream, FILE_MAGIC_TRACE_CONTEXT)?;
+            stream.extend_from_slice(&first_index.to_le_bytes());
+            stream.extend_from_slice(&entries);
+            write_page_to(&mut data, PageTag::TraceContext, &stream)?;
+        }
+        data.extend_from_slice(&events_pages);
+        data.extend_from_slice(incomplete);
+
+        ProfilingData::from_paged_buffer(data, Some(&first_path))
+    }
+
+    /// Reads a profile from `reader`, e.g. from stdin when the profile is
+    /// piped into a tool instead of being written to a file. The whole
+    /// profile is read into memory first, since decoding it requires random
+    /// access, so `reader` doesn't need to be seekable. Only profiles that
+    /// consist of a single file can be read this way.
+    pub fn from_reader(
+        mut reader: impl Read,
+    ) -> Result<ProfilingData, Box<dyn Error + Send + Sync>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        ProfilingData::from_paged_buffer(data, None)
+    }
+
     pub fn from_paged_buffer(
         data: Vec<u8>,
         diagnostic_file_path: Option<&Path>,
     ) -> Result<ProfilingData, Box<dyn Error + Send + Sync>> {
-        // let event_decoder = EventDecoder::new(data, diagnostic_file_path)?;
-        // Ok(ProfilingData { event_decoder })
+        let event_decoder = event_decoder_from_paged_buffer(data, diagnostic_file_path)?;
+        Ok(ProfilingData::from_event_decoder(event_decoder))
+    }
 
-        let file_format_version = read_file_header(
-            &data,
-            FILE_MAGIC_TOP_LEVEL,
-            diagnostic_file_path,
-            "top-level",
-        )?;
+    fn from_event_decoder(event_decoder: Box<dyn EventDecoder>) -> ProfilingData {
+        let file_flags = event_decoder.file_flags();
+        let process_metadata = event_decoder.process_metadata().clone();
+        let mut data = ProfilingData {
+            event_decoder,
+            markers: Markers::default(),
+            demangle_cache: None,
+            file_flags,
+            process_metadata,
+            unclosed_intervals: Vec::new(),
+            spans_by_trace_id: OnceLock::new(),
+        };
+
+        data.index_events(0..data.num_events());
+        data
+    }
+
+    /// Decodes `pages`, the complete pages that have been appended to the
+    /// file of the profile, see `IncrementalProfilingData::refresh`.
+    pub(crate) fn append_pages(
+        &mut self,
+        pages: &[u8],
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let num_events = self.num_events();
+        self.event_decoder
+            .append_pages(pages, diagnostic_file_path)?;
+
+        self.process_metadata = self.event_decoder.process_metadata().clone();
+        self.spans_by_trace_id = OnceLock::new();
+        self.index_events(num_events..self.num_events());
+        Ok(())
+    }
 
-        let event_decoder: Box<dyn file_formats::EventDecoder> = match file_format_version {
-            file_formats::v7::FILE_FORMAT => Box::new(file_formats::v7::EventDecoder::new(data)?),
-            file_formats::v8::FILE_FORMAT => Box::new(file_formats::v8::EventDecoder::new(
-                data,
-                diagnostic_file_path,
-            )?),
-            unsupported_version => {
-                let msg = if unsupported_version > file_formats::current::FILE_FORMAT {
-                    format!(
-                        "File version {} is too new for this version of measureme. Try upgrading your tools to the latest version.",
-                        unsupported_version
-                    )
-                } else {
-                    format!(
-                        "File version {} is too new for this version of the measureme tool suite. Try upgrading the tool suite to the latest version.",
-                        unsupported_version
-                    )
-                };
-
-                return Err(From::from(msg));
+    /// Finds the markers among `event_indices`, which are the events after
+    /// those that have been indexed before, and sets `unclosed_intervals`.
+    ///
+    /// Thread names are recorded as instant events, so only those need to
+    /// be looked at. Later events overwrite earlier ones, which makes the
+    /// most recent name stick when a thread has been renamed. The
+    /// `ARGS_DROPPED_EVENT_KIND` and `OPEN_INTERVAL_EVENT_KIND` markers
+    /// are instant events as well. The string of each event kind is only
+    /// looked up once, in `Markers::kinds`, and only the markers are fully
+    /// decoded. File formats without string ids have all their instant
+    /// events decoded.
+    fn index_events(&mut self, event_indices: Range<usize>) {
+        let mut markers = mem::take(&mut self.markers);
+
+        // The closing intervals of late open markers may have been indexed
+        // already.
+        for event_index in mem::take(&mut markers.pending) {
+            self.index_instant_event(&mut markers, event_index, event_indices.start);
+        }
+
+        for event_index in event_indices {
+            let event = self.decode_lightweight_event(event_index);
+            markers.open_spans.record(self, &event);
+            if event.payload.is_instant() {
+                self.index_instant_event(&mut markers, event_index, event_index + 1);
+            }
+        }
+
+        self.unclosed_intervals = markers.open_spans.unclosed_intervals();
+        self.markers = markers;
+    }
+
+    /// Adds the instant event at `event_index` to `markers` if it is a
+    /// marker, or to `markers.pending` if that can't be told yet. The events
+    /// before `indexed_end` have already been indexed.
+    fn index_instant_event(&self, markers: &mut Markers, event_index: usize, indexed_end: usize) {
+        let string_map = self.event_decoder.string_map();
+        if let Some(event_kind) = self.event_decoder.decode_event_kind(event_index) {
+            let is_marker = match markers.kinds.get(&event_kind) {
+                Some(&is_marker) => is_marker,
+                None => match string_map.and_then(|strings| strings.get(event_kind)) {
+                    Some(kind) => {
+                        let is_marker = matches!(
+                            kind,
+                            OPEN_INTERVAL_EVENT_KIND
+                                | THREAD_NAME_EVENT_KIND
+                                | ARGS_DROPPED_EVENT_KIND
+                        );
+                        markers.kinds.insert(event_kind, is_marker);
+                        is_marker
+                    }
+                    None => {
+                        markers.pending.push(event_index);
+                        return;
+                    }
+                },
+            };
+            if !is_marker {
+                return;
+            }
+        }
+
+        let lightweight_event = self.decode_lightweight_event(event_index);
+        let event = self.decode_full_event(event_index);
+        if event.event_kind == OPEN_INTERVAL_EVENT_KIND {
+            let indexed = event_index + 1..indexed_end;
+            markers.open_spans.open(self, lightweight_event, indexed);
+        } else if event.event_kind == THREAD_NAME_EVENT_KIND {
+            let label = self.event_decoder.decode_event_id(event_index);
+            if let (Some(strings), Some(label)) = (string_map, label) {
+                if strings.get(label).is_none() {
+                    markers.pending.push(event_index);
+                    return;
+                }
+            }
+
+            let thread_names = &mut markers.thread_names;
+            let is_latest = match thread_names.get(&event.thread_id) {
+                Some(&(named_at, _)) => named_at < event_index,
+                None => true,
+            };
+            if is_latest {
+                thread_names.insert(event.thread_id, (event_index, event.label.into_owned()));
+            }
+        } else if event.event_kind == ARGS_DROPPED_EVENT_KIND {
+            if let Some(timestamp) = event.payload.timestamp() {
+                let start = timestamp.start();
+                let args_dropped_at = markers.args_dropped_at.map_or(start, |at| at.min(start));
+                markers.args_dropped_at = Some(args_dropped_at);
             }
+        }
+    }
+
+    /// Merges the events of several profiles, e.g. of the different
+    /// processes taking part in a build, into a single profile.
+    ///
+    /// All timestamps are made relative to the earliest start time of the
+    /// sources, and the merged events are ordered by the time they were
+    /// recorded (i.e. the end of an interval event). Every thread of every
+    /// source gets its own thread id in the merged profile; ids are assigned
+    /// in the order of `sources` and, within a source, in the order of the
+    /// threads' first events. Strings are re-interned, so string ids of
+    /// different sources can't collide.
+    ///
+    /// The process metadata of all sources is combined. If several sources
+    /// have a value for the same key, the one that comes first in `sources`
+    /// is kept. The trace contexts of interval events are kept as well, so
+    /// `spans_by_trace_id` of the merged profile correlates the spans that
+    /// the different sources have recorded for the same trace.
+    ///
+    /// Returns an error if `sources` is empty or if the sources don't all
+    /// have the same file format version.
+    pub fn merge(sources: &[ProfilingData]) -> Result<ProfilingData, Box<dyn Error + Send + Sync>> {
+        let first = match sources.first() {
+            Some(first) => first,
+            None => return Err(From::from("Cannot merge an empty set of profiles")),
         };
 
-        Ok(ProfilingData { event_decoder })
+        let file_format_version = first.file_format_version();
+        if let Some(other) = sources
+            .iter()
+            .find(|source| source.file_format_version() != file_format_version)
+        {
+            let msg = format!(
+                "Cannot merge profiles with different file format versions ({} and {})",
+                file_format_version,
+                other.file_format_version()
+            );
+            return Err(From::from(msg));
+        }
+
+        let base = sources
+            .iter()
+            .min_by_key(|source| source.metadata().start_time)
+            .unwrap();
+        let base_time = base.metadata().start_time;
+
+        // Determine the position of every event in the merged stream and
+        // assign the new thread ids.
+        let mut thread_ids = FxHashMap::<(usize, u32), u32>::default();
+        let mut events = Vec::new();
+
+        for (source_index, source) in sources.iter().enumerate() {
+            // Integer events don't have a timestamp. They stay with the event
+            // recorded before them.
+            let mut last_recorded = source.metadata().start_time;
+
+            for event in source.iter() {
+                if let Some(timestamp) = event.timestamp() {
+                    last_recorded = timestamp.end();
+                }
+
+                let next_thread_id = thread_ids.len() as u32;
+                thread_ids
+                    .entry((source_index, event.thread_id))
+                    .or_insert(next_thread_id);
+
+                events.push((last_recorded, source_index, event.event_index));
+            }
+        }
+
+        // This is a stable sort, so events recorded at the same time keep
+        // their relative order.
+        events.sort_by_key(|&(recorded, _, _)| recorded);
+
+        let mut builder = ProfilingDataBuilder::with_metadata(
+            base_time
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            base.metadata().process_id,
+            &base.metadata().cmd,
+        );
+        let mut string_ids = FxHashMap::<String, StringId>::default();
+
+        for (_, source_index, event_index) in events {
+            let source = &sources[source_index];
+            let mut event = source.decode_recorded_event(event_index);
+            let omitted_args = source.omitted_args(&source.decode_lightweight_event(event_index));
+            let thread_id = thread_ids[&(source_index, event.thread_id)];
+
+            // The index of a trace context differs between the profiles.
+            if let Some(trace_context) = source.marked_trace_context(&event) {
+                event.payload = EventPayload::Integer(builder.record_trace_context(trace_context));
+            }
+
+            builder.copy_event(&mut string_ids, &event, omitted_args, thread_id, base_time);
+        }
+
+        let mut merged = builder.into_profiling_data();
+        merged.file_flags = sources
+            .iter()
+            .fold(0, |file_flags, source| file_flags | source.file_flags);
+        for source in sources {
+            for (key, value) in &source.process_metadata {
+                merged
+                    .process_metadata
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+
+        Ok(merged)
     }
 
     pub fn metadata(&self) -> &Metadata {
         self.event_decoder.metadata()
     }
 
+    /// The wall-clock time that the timestamps of the events are relative to,
+    /// if the profile has been recorded with a counter that measures wall
+    /// time, see `Metadata::wall_clock_start`.
+    pub fn wall_clock_start(&self) -> Option<SystemTime> {
+        self.metadata().wall_clock_start
+    }
+
+    /// Converts the time of an event, e.g. `Timestamp::start`, which is
+    /// relative to `metadata().start_time`, to the wall-clock time at which
+    /// it has been recorded, so that it can be correlated with e.g. the
+    /// timestamps of log messages. `None` if `wall_clock_start` is.
+    pub fn to_wall_clock(&self, time: SystemTime) -> Option<SystemTime> {
+        let since_start = time.duration_since(self.metadata().start_time).ok()?;
+        Some(self.wall_clock_start()? + since_start)
+    }
+
+    /// The number of e.g. instructions counted during the interval event
+    /// `event`, for profiles recorded with a counter that doesn't measure
+    /// time, see `CounterDescription::counter_value`. `None` for profiles
+    /// recorded with `wall-time` and for other kinds of events.
+    pub fn counter_value(&self, event: &LightweightEvent) -> Option<u64> {
+        self.metadata()
+            .counter
+            .as_ref()?
+            .counter_value(event.duration()?)
+    }
+
+    /// The key/value pairs describing the profiled process that have been
+    /// recorded with `Profiler::record_metadata`, e.g. the compiler version
+    /// (see the `measureme::rustc::METADATA_KEY_*` constants).
+    pub fn process_metadata(&self) -> &BTreeMap<String, String> {
+        &self.process_metadata
+    }
+
+    pub fn file_format_version(&self) -> u32 {
+        self.event_decoder.file_format_version()
+    }
+
+    /// The number of bytes at the end of the profile that have been ignored
+    /// because they don't form a complete page, e.g. because the profiled
+    /// process crashed while writing the profile. All events before that
+    /// page are available, but some of their strings may be `<unknown>`. `0`
+    /// for complete profiles.
+    pub fn truncated_bytes(&self) -> u64 {
+        self.event_decoder.truncated_bytes()
+    }
+
+    /// Whether the profile has been recorded with
+    /// `ProfilerOptions::min_duration_nanos`, i.e. whether short interval
+    /// events are missing from it. Self times computed from such a profile
+    /// are only approximate, which tools should point out to the user.
+    pub fn is_sampled(&self) -> bool {
+        self.file_flags & FILE_FLAG_SAMPLED != 0
+    }
+
+    /// Whether the profile has been recorded with
+    /// `ProfilerOptions::record_explicit_parents`, i.e. whether interval
+    /// events may name their parent (see `explicit_parent_id_bytes`).
+    pub fn has_explicit_parents(&self) -> bool {
+        self.file_flags & FILE_FLAG_EXPLICIT_PARENTS != 0
+    }
+
+    /// Whether the profile has been recorded with
+    /// `ProfilerOptions::record_trace_context`, i.e. whether interval events
+    /// may be tied to a span of a distributed trace (see `trace_context`).
+    pub fn has_trace_contexts(&self) -> bool {
+        self.file_flags & FILE_FLAG_TRACE_CONTEXT != 0
+    }
+
+    /// Whether the profile has been recorded with
+    /// `ProfilerOptions::record_wall_time`, i.e. whether interval events
+    /// have a wall-clock duration in addition to their counter value (see
+    /// `wall_time`).
+    pub fn has_wall_times(&self) -> bool {
+        self.file_flags & FILE_FLAG_WALL_TIME != 0
+    }
+
+    /// When the strings of the profile exceeded
+    /// `ProfilerOptions::max_string_bytes`, after which events have been
+    /// recorded without their arguments. Tools that show arguments should
+    /// point out that they are missing for the events after this. `None` if
+    /// the profile has been recorded without a budget, or if the budget has
+    /// never been exceeded.
+    pub fn args_dropped_since(&self) -> Option<SystemTime> {
+        if self.file_flags & FILE_FLAG_ARGS_BUDGET == 0 {
+            return None;
+        }
+
+        self.markers.args_dropped_at
+    }
+
+    /// Whether the profile has been recorded with
+    /// `ProfilerOptions::labels_only`, i.e. whether all of its events are
+    /// without a category and arguments. Tools that show arguments can point
+    /// out that they haven't been recorded, rather than that there are none.
+    pub fn is_labels_only(&self) -> bool {
+        self.file_flags & FILE_FLAG_LABELS_ONLY != 0
+    }
+
+    /// Whether events have been appended to the profile with
+    /// `Profiler::open` after it had been written, i.e. whether it contains
+    /// the events of several sessions. The time between the sessions, when
+    /// nothing has been recorded, shows up as a gap between their events.
+    pub fn is_resumed(&self) -> bool {
+        self.file_flags & FILE_FLAG_RESUMED != 0
+    }
+
+    /// The decoded strings of the profile's string table, for resolving many
+    /// `StringId`s, e.g. the event ids of raw events, without decoding each of
+    /// them. `None` for profiles in the legacy v7 file format.
+    pub fn string_map(&self) -> Option<StringMap<'_>> {
+        self.event_decoder.string_map()
+    }
+
+    /// The name that has been given to the thread with id `thread_id` via
+    /// `Profiler::set_thread_name`. If the thread has been renamed, this is
+    /// the name it was given last.
+    pub fn thread_name(&self, thread_id: u32) -> Option<&str> {
+        self.markers
+            .thread_names
+            .get(&thread_id)
+            .map(|(_, name)| &name[..])
+    }
+
     pub fn iter<'a>(&'a self) -> ProfilerEventIterator<'a> {
         ProfilerEventIterator::new(&self)
     }
@@ -100,6 +644,20 @@ impl ProfilingData {
         self.iter().map(move |e| self.to_full_event(&e))
     }
 
+    /// Reads the events of the profile at `path_stem` one page at a time, in
+    /// the order they have been recorded, without loading the profile into
+    /// memory. Only its strings are kept in memory, so this also works for
+    /// profiles that are larger than the available memory. The events have
+    /// the same indices as those of `ProfilingData::new(path_stem)`.
+    pub fn stream_events(
+        path_stem: &Path,
+    ) -> Result<EventStream<BufReader<File>>, Box<dyn Error + Send + Sync>> {
+        let paged_path = path_stem.with_extension(FILE_EXTENSION);
+        let file = File::open(&paged_path)
+            .map_err(|e| format!("Could not open `{}`: {}", paged_path.display(), e))?;
+        EventStream::new(BufReader::new(file), Some(&paged_path))
+    }
+
     pub fn num_events(&self) -> usize {
         self.event_decoder.num_events()
     }
@@ -109,10 +667,157 @@ impl ProfilingData {
     }
 
     pub(crate) fn decode_full_event<'a>(&'a self, event_index: usize) -> Event<'a> {
+        let mut event = self.event_decoder.decode_full_event(event_index);
+        if let Some(ref demangle_cache) = self.demangle_cache {
+            let event_id = self.event_decoder.decode_event_id(event_index);
+            demangle_cache.demangle_event(event_id, &mut event);
+        }
+        event
+    }
+
+    /// Like `decode_full_event`, but with the strings that have been
+    /// recorded even if `demangle_symbols` has been called, for copying the
+    /// event into another profile.
+    pub(crate) fn decode_recorded_event<'a>(&'a self, event_index: usize) -> Event<'a> {
         self.event_decoder.decode_full_event(event_index)
     }
 
-    fn decode_lightweight_event(&self, event_index: usize) -> LightweightEvent {
+    /// The actual bytes of the event id of `event`, i.e. of its label,
+    /// category and arguments separated by `SEPARATOR_BYTE`. The strings of
+    /// `Event` are decoded lossily if they aren't valid UTF-8, e.g. because
+    /// they have been created from paths (see `Profiler::alloc_string_bytes`),
+    /// these are the bytes that have been recorded.
+    pub fn event_id_bytes(&self, event: &LightweightEvent) -> Cow<'_, [u8]> {
+        self.event_decoder.decode_event_id_bytes(event.event_index)
+    }
+
+    /// The number of arguments that have been left out of the event id of
+    /// `event` because it has been created with more arguments than
+    /// `EventIdBuilder::with_max_args` allows. Usually 0.
+    pub fn omitted_args(&self, event: &LightweightEvent) -> u64 {
+        decodeme::event::omitted_args(&self.event_id_bytes(event))
+    }
+
+    /// The bytes of the event id of the event that the interval event
+    /// `event` has been recorded as nested in with
+    /// `Profiler::start_recording_interval_event_with_parent`. Always `None`
+    /// for profiles without explicit parents.
+    pub fn explicit_parent_id_bytes(&self, event: &LightweightEvent) -> Option<Cow<'_, [u8]>> {
+        if !self.has_explicit_parents() {
+            return None;
+        }
+
+        self.event_decoder
+            .decode_explicit_parent_id_bytes(event.event_index)
+    }
+
+    /// The wall-clock duration of the interval event `event`. For profiles
+    /// recorded with a counter other than `wall-time`, the duration of
+    /// `event.payload` is a counter value instead, e.g. a number of
+    /// instructions. Always `None` for profiles without wall times.
+    pub fn wall_time(&self, event: &LightweightEvent) -> Option<Duration> {
+        self.decode_wall_time(event.event_index)
+    }
+
+    pub(crate) fn decode_wall_time(&self, event_index: usize) -> Option<Duration> {
+        if !self.has_wall_times() {
+            return None;
+        }
+
+        self.event_decoder
+            .decode_wall_time_nanos(event_index)
+            .map(Duration::from_nanos)
+    }
+
+    /// Whether the counter couldn't be read at the start or end of the
+    /// interval event `event`, or went backwards in between, e.g. because a
+    /// hardware counter has been reset while the profile was recorded. The
+    /// duration of such events is unknown, they are recorded with a duration
+    /// of zero instead, so they can be excluded from the analysis.
+    pub fn counter_unavailable(&self, event: &LightweightEvent) -> bool {
+        self.event_decoder
+            .decode_counter_unavailable(event.event_index)
+    }
+
+    /// The span of a distributed trace that the interval event `event` has
+    /// been recorded for with
+    /// `Profiler::start_recording_interval_event_with_trace_context`. Always
+    /// `None` for profiles without trace contexts.
+    pub fn trace_context(&self, event: &LightweightEvent) -> Option<TraceContext> {
+        if !self.has_trace_contexts() {
+            return None;
+        }
+
+        self.event_decoder.decode_trace_context(event.event_index)
+    }
+
+    /// The interval events that have a trace context, grouped by trace id and
+    /// ordered by their start within each trace. For a merged profile (see
+    /// `merge`), this puts the spans that different processes recorded for
+    /// the same trace, e.g. for the same request, next to each other.
+    ///
+    /// The index is built by the first call, with a single pass over the
+    /// events, and shared by all later ones.
+    pub fn spans_by_trace_id(&self) -> &SpansByTraceId {
+        self.spans_by_trace_id
+            .get_or_init(|| self.build_spans_by_trace_id())
+    }
+
+    /// The spans of the trace `trace_id`, see `spans_by_trace_id`. Empty if
+    /// there is no such trace.
+    pub fn spans_of_trace(&self, trace_id: u128) -> &[(TraceContext, LightweightEvent)] {
+        self.spans_by_trace_id()
+            .get(&trace_id)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    fn build_spans_by_trace_id(&self) -> SpansByTraceId {
+        let mut traces = SpansByTraceId::new();
+        if !self.has_trace_contexts() {
+            return traces;
+        }
+
+        for event in self.iter() {
+            if let Some(trace_context) = self.trace_context(&event) {
+                traces
+                    .entry(trace_context.trace_id)
+                    .or_default()
+                    .push((trace_context, event));
+            }
+        }
+
+        for spans in traces.values_mut() {
+            // This is a stable sort, so spans that start at the same time
+            // stay in the order in which they have been recorded.
+            spans.sort_by_key(|(_, event)| event.start());
+        }
+
+        traces
+    }
+
+    /// The trace context that `event` refers to, if it is one of the
+    /// `TRACE_CONTEXT_EVENT_KIND` markers.
+    pub(crate) fn marked_trace_context(&self, event: &Event<'_>) -> Option<TraceContext> {
+        if !self.has_trace_contexts() || event.event_kind != TRACE_CONTEXT_EVENT_KIND {
+            return None;
+        }
+
+        match event.payload {
+            EventPayload::Integer(index) => self.event_decoder.trace_context_entry(index),
+            _ => None,
+        }
+    }
+
+    /// The actual bytes of the label of `event`, see `event_id_bytes`. Bytes
+    /// that have been escaped with `measureme::event_id::escape_text` are
+    /// unescaped.
+    pub fn label_bytes(&self, event: &LightweightEvent) -> Cow<'_, [u8]> {
+        decodeme::event::label_bytes(self.event_id_bytes(event))
+    }
+
+    /// Decodes the event at `event_index`, e.g. the one a
+    /// [`CallTreeNode`](crate::CallTreeNode) has been created for.
+    pub fn decode_lightweight_event(&self, event_index: usize) -> LightweightEvent {
         self.event_decoder.decode_lightweight_event(event_index)
     }
 }
@@ -178,16 +883,44 @@ impl<'a> DoubleEndedIterator for ProfilerEventIterator<'a> {
 /// `ProfilingDataBuilder` provides a convenient interface but its
 /// implementation might not be efficient, which why it should only be used for
 /// writing tests and other things that are not performance sensitive.
+///
+/// The events are encoded exactly like `measureme::Profiler` encodes them, so
+/// the result of `into_bytes` or `write_to_file` is a regular `.mm_profdata`
+/// file that can be passed to tools like `crox` and `summarize`.
 pub struct ProfilingDataBuilder {
+    sink: InMemorySink,
     event_sink: SerializationSink,
     string_table_data_sink: Arc<SerializationSink>,
     string_table_index_sink: Arc<SerializationSink>,
     string_table: StringTableBuilder,
+    metadata: ProcessMetadataWriter,
+    trace_contexts: TraceContextWriter,
+    file_flags: u8,
+    /// The epoch of the latest epoch marker that `copy_event` has recorded
+    /// for each thread.
+    timestamp_epochs: FxHashMap<u32, u64>,
+    timestamp_epoch_index: TimestampEpochIndexWriter,
 }
 
 impl ProfilingDataBuilder {
     pub fn new() -> ProfilingDataBuilder {
-        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        Self::with_metadata(0, 0, "test cmd")
+    }
+
+    fn with_metadata(start_time_nanos: u64, process_id: u32, cmd: &str) -> ProfilingDataBuilder {
+        Self::with_metadata_of(&Metadata {
+            start_time: std::time::UNIX_EPOCH + Duration::from_nanos(start_time_nanos),
+            process_id,
+            cmd: cmd.to_string(),
+            counter: None,
+            page_size: None,
+            wall_clock_start: None,
+        })
+    }
+
+    fn with_metadata_json(metadata: &str) -> ProfilingDataBuilder {
+        let sink = InMemorySink::new();
+        let sink_builder = SerializationSinkBuilder::new_from_in_memory_sink(&sink);
 
         let event_sink = sink_builder.new_sink(PageTag::Events);
         let string_table_data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
@@ -202,19 +935,44 @@ impl ProfilingDataBuilder {
         )
         .unwrap();
 
-        string_table.alloc_metadata(&*format!(
-            r#"{{ "start_time": {}, "process_id": {}, "cmd": "{}" }}"#,
-            0, 0, "test cmd",
-        ));
+        string_table.alloc_metadata(metadata);
 
         ProfilingDataBuilder {
+            sink,
             event_sink,
             string_table_data_sink,
             string_table_index_sink,
             string_table,
+            metadata: ProcessMetadataWriter::new(sink_builder.new_sink(PageTag::Metadata)),
+            trace_contexts: TraceContextWriter::new(sink_builder.new_sink(PageTag::TraceContext)),
+            file_flags: 0,
+            timestamp_epochs: FxHashMap::default(),
+            timestamp_epoch_index: TimestampEpochIndexWriter::new(
+                sink_builder.new_sink(PageTag::TimestampEpochIndex),
+                true,
+            ),
         }
     }
 
+    /// Creates a builder for a profile recorded with `counter`, i.e. whose
+    /// timestamps are values of that counter.
+    pub fn with_counter(counter: CounterDescription) -> ProfilingDataBuilder {
+        Self::with_metadata_of(&Metadata {
+            start_time: std::time::UNIX_EPOCH,
+            process_id: 0,
+            cmd: "test cmd".to_string(),
+            counter: Some(counter),
+            page_size: None,
+            wall_clock_start: None,
+        })
+    }
+
+    /// Creates a builder for a profile with the same metadata as a profile
+    /// with `metadata`, including the counter.
+    pub(crate) fn with_metadata_of(metadata: &Metadata) -> ProfilingDataBuilder {
+        Self::with_metadata_json(&serde_json::to_string(metadata).unwrap())
+    }
+
     /// Record an interval event. Provide an `inner` function for recording
     /// nested events.
     pub fn interval<F>(
@@ -242,6 +1000,155 @@ impl ProfilingDataBuilder {
         self
     }
 
+    /// Like `interval`, but the event is recorded with the explicit parent
+    /// `parent`, like `Profiler::start_recording_interval_event_with_parent`
+    /// does, and the profile is marked as having explicit parents.
+    #[allow(clippy::too_many_arguments)]
+    pub fn interval_with_parent<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        parent: &str,
+        thread_id: u32,
+        start_nanos: u64,
+        end_nanos: u64,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = EventId::from_label(self.string_table.alloc(event_id));
+        let parent_event_kind = self.string_table.alloc(PARENT_EVENT_ID_EVENT_KIND);
+        let parent = EventId::from_label(self.string_table.alloc(parent));
+
+        inner(self);
+
+        self.write_raw_event(&RawEvent::new_integer(
+            parent_event_kind,
+            parent,
+            thread_id,
+            0,
+        ));
+        self.write_raw_event(&RawEvent::new_interval(
+            event_kind,
+            event_id,
+            thread_id,
+            start_nanos,
+            end_nanos,
+        ));
+        self.file_flags |= FILE_FLAG_EXPLICIT_PARENTS;
+
+        self
+    }
+
+    /// Like `interval`, but the event is recorded with the wall-clock
+    /// duration `wall_nanos`, like `ProfilerOptions::record_wall_time` does,
+    /// and the profile is marked as having wall times.
+    #[allow(clippy::too_many_arguments)]
+    pub fn interval_with_wall_time<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u32,
+        start_nanos: u64,
+        end_nanos: u64,
+        wall_nanos: u64,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = EventId::from_label(self.string_table.alloc(event_id));
+        let wall_time_event_kind = self.string_table.alloc(WALL_TIME_EVENT_KIND);
+
+        inner(self);
+
+        self.write_raw_event(&RawEvent::new_interval(
+            event_kind,
+            event_id,
+            thread_id,
+            start_nanos,
+            end_nanos,
+        ));
+        self.write_raw_event(&RawEvent::new_integer(
+            wall_time_event_kind,
+            event_id,
+            thread_id,
+            wall_nanos,
+        ));
+        self.file_flags |= FILE_FLAG_WALL_TIME;
+
+        self
+    }
+
+    /// Like `interval`, but the event is recorded with `trace_context`, like
+    /// `Profiler::start_recording_interval_event_with_trace_context` does,
+    /// and the profile is marked as having trace contexts.
+    #[allow(clippy::too_many_arguments)]
+    pub fn interval_with_trace_context<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u32,
+        start_nanos: u64,
+        end_nanos: u64,
+        trace_context: TraceContext,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = EventId::from_label(self.string_table.alloc(event_id));
+        let trace_context_event_kind = self.string_table.alloc(TRACE_CONTEXT_EVENT_KIND);
+
+        inner(self);
+
+        let index = self.record_trace_context(trace_context);
+        self.write_raw_event(&RawEvent::new_interval(
+            event_kind,
+            event_id,
+            thread_id,
+            start_nanos,
+            end_nanos,
+        ));
+        self.write_raw_event(&RawEvent::new_integer(
+            trace_context_event_kind,
+            event_id,
+            thread_id,
+            index,
+        ));
+
+        self
+    }
+
+    /// Record an interval event that ends before it starts. The `Profiler`
+    /// never writes such events, but they can be found in corrupted files.
+    #[cfg(test)]
+    pub(crate) fn inverted_interval(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u32,
+        start_nanos: u32,
+        end_nanos: u32,
+    ) -> &mut Self {
+        assert!(end_nanos < start_nanos);
+
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = EventId::from_label(self.string_table.alloc(event_id));
+
+        let mut raw_event = RawEvent::new_interval(event_kind, event_id, thread_id, 0, 0);
+        raw_event.payload1_lower = start_nanos;
+        raw_event.payload2_lower = end_nanos;
+
+        self.write_raw_event(&raw_event);
+
+        self
+    }
+
     /// Record and instant event with the given data.
     pub fn instant(
         &mut self,
@@ -259,6 +1166,30 @@ impl ProfilingDataBuilder {
         self
     }
 
+    /// Record an instant event that carries a numeric payload.
+    pub fn instant_with_value(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u32,
+        timestamp_nanos: u64,
+        value: u64,
+    ) -> &mut Self {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = EventId::from_label(self.string_table.alloc(event_id));
+        let raw_event = RawEvent::new_wrapping_instant_with_value(
+            event_kind,
+            event_id,
+            thread_id,
+            timestamp_nanos,
+            value,
+        );
+
+        self.write_raw_event(&raw_event);
+
+        self
+    }
+
     /// Record and instant event with the given data.
     pub fn integer(
         &mut self,
@@ -276,44 +1207,379 @@ impl ProfilingDataBuilder {
         self
     }
 
+    /// Records the name of the thread with the given id, like
+    /// `measureme::Profiler::set_thread_name` does. This is an instant event
+    /// at `timestamp_nanos`, so that a later name replaces an earlier one.
+    pub fn thread_name(&mut self, thread_id: u32, name: &str, timestamp_nanos: u64) -> &mut Self {
+        self.instant(THREAD_NAME_EVENT_KIND, name, thread_id, timestamp_nanos)
+    }
+
+    /// Records a key/value pair of process metadata, like
+    /// `measureme::Profiler::record_metadata` does.
+    pub fn record_metadata(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata.record(key, value);
+        self
+    }
+
+    /// Appends `trace_context` to the trace context stream and returns the
+    /// value that a `TRACE_CONTEXT_EVENT_KIND` marker referring to it must
+    /// have. The profile is marked as having trace contexts.
+    pub(crate) fn record_trace_context(&mut self, trace_context: TraceContext) -> u64 {
+        self.file_flags |= FILE_FLAG_TRACE_CONTEXT;
+        self.trace_contexts.record(trace_context)
+    }
+
+    /// Sets the `FILE_FLAG_*` bits of the profile, e.g. for a copy of a
+    /// profile with the same flags.
+    pub(crate) fn set_file_flags(&mut self, file_flags: u8) -> &mut Self {
+        self.file_flags = file_flags;
+        self
+    }
+
     /// Convert this builder into a `ProfilingData` object that can be iterated.
     pub fn into_profiling_data(self) -> ProfilingData {
-        // Drop the string table, so that the `string_table_data_sink` and
-        // `string_table_index_sink` fields are the only event-sink references
-        // left. This enables us to unwrap the `Arc`s and get the byte data out.
-        drop(self.string_table);
+        ProfilingData::from_paged_buffer(self.into_bytes(), None).unwrap()
+    }
 
-        let event_data = self.event_sink.into_bytes();
-        let string_data = Arc::try_unwrap(self.string_table_data_sink)
-            .unwrap()
-            .into_bytes();
-        let index_data = Arc::try_unwrap(self.string_table_index_sink)
-            .unwrap()
-            .into_bytes();
-
-        ProfilingData {
-            event_decoder: Box::new(
-                file_formats::current::EventDecoder::from_separate_buffers(
-                    string_data,
-                    index_data,
-                    event_data,
-                    None,
+    /// Returns the contents of the `.mm_profdata` file that a `Profiler`
+    /// recording these events would have written.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let ProfilingDataBuilder {
+            sink,
+            event_sink,
+            string_table_data_sink,
+            string_table_index_sink,
+            string_table,
+            metadata,
+            trace_contexts,
+            file_flags,
+            timestamp_epochs: _,
+            timestamp_epoch_index,
+        } = self;
+
+        // Dropping the sinks writes their last pages to `sink`. The string
+        // table holds references to its sinks, so it has to go first.
+        drop(string_table);
+        drop(string_table_data_sink);
+        drop(string_table_index_sink);
+        drop(event_sink);
+        drop(metadata);
+        drop(trace_contexts);
+        drop(timestamp_epoch_index);
+
+        let mut data = Vec::new();
+        write_top_level_file_header(
+            &mut data,
+            TopLevelFileHeader {
+                codec: FILE_CODEC_NONE,
+                flags: file_flags,
+            },
+        )
+        .unwrap();
+        data.extend_from_slice(&sink.bytes());
+        data
+    }
+
+    /// Writes the profile to the file that `ProfilingData::new(path_stem)`
+    /// reads, creating its directory if necessary.
+    pub fn write_to_file(self, path_stem: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = segment_file_path(path_stem, 0);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(path, self.into_bytes())?;
+        Ok(())
+    }
+
+    /// Records a copy of `event` for `thread_id`, with timestamps relative to
+    /// `base_time`, which must be the start time of this builder's profile,
+    /// and with `omitted_args` arguments marked as omitted (see
+    /// `ProfilingData::omitted_args`). `string_ids` caches the ids of the
+    /// strings that have been allocated for previously copied events.
+    pub(crate) fn copy_event(
+        &mut self,
+        string_ids: &mut FxHashMap<String, StringId>,
+        event: &Event<'_>,
+        omitted_args: u64,
+        thread_id: u32,
+        base_time: SystemTime,
+    ) {
+        let string_table = &self.string_table;
+        let mut intern = |s: String| {
+            *string_ids
+                .entry(s)
+                .or_insert_with_key(|s| string_table.alloc(&s[..]))
+        };
+
+        // The epoch markers of the source hold the epochs of its counter
+        // values, which `check_timestamp_epoch` records anew for the copy.
+        if event.event_kind == TIMESTAMP_EPOCH_EVENT_KIND
+            || event.event_kind == THREAD_TIMESTAMP_EPOCH_EVENT_KIND
+        {
+            return;
+        }
+
+        let event_kind = intern(event.event_kind.clone().into_owned());
+        let event_id = EventId::from_label(intern(event_id_string(event, omitted_args)));
+        self.copy_payload(event_kind, event_id, thread_id, event.payload, base_time);
+    }
+
+    /// Records a copy of the event at `event_index` of `source`, like
+    /// `copy_event` does, but with its event id copied byte for byte, so that
+    /// nothing that the decoded `Event` leaves out gets lost, e.g. the number
+    /// of omitted arguments or bytes that aren't valid UTF-8. The event gets
+    /// `payload` instead of its own, e.g. a clipped one. `source` must have
+    /// the start time of this builder's profile.
+    pub(crate) fn copy_raw_event(
+        &mut self,
+        string_ids: &mut FxHashMap<Vec<u8>, StringId>,
+        source: &ProfilingData,
+        event_index: usize,
+        payload: EventPayload,
+    ) {
+        let event = source.decode_recorded_event(event_index);
+        if event.event_kind == TIMESTAMP_EPOCH_EVENT_KIND
+            || event.event_kind == THREAD_TIMESTAMP_EPOCH_EVENT_KIND
+        {
+            return;
+        }
+
+        let string_table = &self.string_table;
+        let mut intern = |bytes: &[u8]| {
+            if let Some(&id) = string_ids.get(bytes) {
+                return id;
+            }
+            let id = string_table.alloc_event_id_bytes(bytes);
+            string_ids.insert(bytes.to_vec(), id);
+            id
+        };
+
+        let event_kind = intern(event.event_kind.as_bytes());
+        let event_id = EventId::from_label(intern(
+            &source.event_decoder.decode_event_id_bytes(event_index),
+        ));
+        let base_time = source.metadata().start_time;
+        self.copy_payload(event_kind, event_id, event.thread_id, payload, base_time);
+    }
+
+    fn copy_payload(
+        &mut self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        payload: EventPayload,
+        base_time: SystemTime,
+    ) {
+        let nanos = |time: SystemTime| time.duration_since(base_time).unwrap().as_nanos() as u64;
+
+        let raw_event = match payload {
+            EventPayload::Timestamp(Timestamp::Interval { start, end }) => {
+                self.check_timestamp_epoch(nanos(end), thread_id);
+                RawEvent::new_wrapping_interval(
+                    event_kind,
+                    event_id,
+                    thread_id,
+                    nanos(start),
+                    nanos(end),
                 )
-                .unwrap(),
-            ),
+            }
+            EventPayload::Timestamp(Timestamp::Instant(time)) => {
+                self.check_timestamp_epoch(nanos(time), thread_id);
+                RawEvent::new_wrapping_instant(event_kind, event_id, thread_id, nanos(time))
+            }
+            EventPayload::Integer(value) => {
+                RawEvent::new_integer(event_kind, event_id, thread_id, value)
+            }
+            EventPayload::InstantWithValue { time, value } => {
+                self.check_timestamp_epoch(nanos(time), thread_id);
+                RawEvent::new_wrapping_instant_with_value(
+                    event_kind,
+                    event_id,
+                    thread_id,
+                    nanos(time),
+                    value,
+                )
+            }
+        };
+
+        self.write_raw_event(&raw_event);
+    }
+
+    /// Records an epoch marker before the first copied event of `thread_id`
+    /// whose timestamp falls into a later epoch than those before it, like
+    /// `Profiler::check_timestamp_epoch` does, so that copies of long
+    /// profiles decode like their source. The markers are per-thread ones,
+    /// which also suit sources whose counter differs between threads.
+    pub(crate) fn check_timestamp_epoch(&mut self, nanos: u64, thread_id: u32) {
+        let epoch = nanos / TIMESTAMP_EPOCH_LENGTH;
+        let latest = self.timestamp_epochs.entry(thread_id).or_insert(0);
+        if epoch <= *latest {
+            return;
+        }
+
+        // Readers take the events before the first marker of a thread to
+        // belong to the epoch before it, which is only right for epoch 1.
+        let epochs = if *latest == 0 && epoch > 1 {
+            vec![1, epoch]
+        } else {
+            vec![epoch]
+        };
+        *latest = epoch;
+
+        let event_kind = self.string_table.alloc(THREAD_TIMESTAMP_EPOCH_EVENT_KIND);
+        let event_id = EventId::from_label(event_kind);
+        for epoch in epochs {
+            let marker = RawEvent::new_integer(event_kind, event_id, thread_id, epoch);
+            let addr = self.write_raw_event(&marker);
+            self.timestamp_epoch_index.record(addr);
         }
     }
 
-    fn write_raw_event(&mut self, raw_event: &RawEvent) {
+    fn write_raw_event(&mut self, raw_event: &RawEvent) -> Addr {
         self.event_sink
             .write_atomic(std::mem::size_of::<RawEvent>(), |bytes| {
                 raw_event.serialize(bytes);
-            });
+            })
     }
 }
 
 impl<'a> ExactSizeIterator for ProfilerEventIterator<'a> {}
 
+/// The first two bytes of every gzip file, see RFC 1952.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Archived profiles are often gzipped as a whole. Since the magic of the
+/// top-level header can't be mistaken for that of gzip, they can be
+/// decompressed before anything else looks at the data.
+fn gunzip_if_needed(
+    data: Vec<u8>,
+    diagnostic_file_path: Option<&Path>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+
+    let mut decompressed = Vec::new();
+    if let Err(e) = flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut decompressed) {
+        let msg = match diagnostic_file_path {
+            Some(path) => format!("Could not decompress `{}`: {}", path.display(), e),
+            None => format!("Could not decompress the gzipped profile: {}", e),
+        };
+        return Err(From::from(msg));
+    }
+
+    Ok(decompressed)
+}
+
+fn event_decoder_from_paged_buffer(
+    data: Vec<u8>,
+    diagnostic_file_path: Option<&Path>,
+) -> Result<Box<dyn EventDecoder>, Box<dyn Error + Send + Sync>> {
+    let data = gunzip_if_needed(data, diagnostic_file_path)?;
+
+    // The codec flag byte is handled by the decoder of each file format.
+    let file_format_version = read_file_header(
+        &data,
+        FILE_MAGIC_TOP_LEVEL,
+        diagnostic_file_path,
+        "top-level",
+    )? & FILE_FORMAT_VERSION_MASK;
+
+    let event_decoder: Box<dyn file_formats::EventDecoder> = match file_format_version {
+        file_formats::v7::FILE_FORMAT => Box::new(file_formats::v7::EventDecoder::new(data)?),
+        file_formats::v8::FILE_FORMAT => Box::new(file_formats::v8::EventDecoder::new(
+            data,
+            diagnostic_file_path,
+        )?),
+        unsupported_version => {
+            let msg = check_file_format_version(
+                unsupported_version,
+                file_formats::v7::FILE_FORMAT,
+                file_formats::current::FILE_FORMAT,
+            )
+            .err()
+            .unwrap_or_else(|| format!("Unsupported file format version {}.", unsupported_version));
+
+            return Err(From::from(msg));
+        }
+    };
+
+    Ok(event_decoder)
+}
+
+/// Reassembles the event id string that `event`'s label, category and
+/// arguments have been parsed from, with the marker of `omitted_args` left
+/// out arguments.
+fn event_id_string(event: &Event<'_>, omitted_args: u64) -> String {
+    let mut event_id = escape_text(&event.label).into_owned();
+
+    if let Some(category) = &event.category {
+        event_id.push_str(SEPARATOR_BYTE);
+        event_id.push_str(CATEGORY_TAG_BYTE);
+        event_id.push_str(&escape_text(category));
+    }
+
+    for (index, arg) in event.additional_data.iter().enumerate() {
+        event_id.push_str(SEPARATOR_BYTE);
+        if event.integer_args.iter().any(|&(i, _)| i == index) {
+            event_id.push_str(INTEGER_ARG_TAG_BYTE);
+        }
+        event_id.push_str(&escape_text(arg));
+    }
+
+    for frame in &event.backtrace {
+        event_id.push_str(SEPARATOR_BYTE);
+        event_id.push_str(BACKTRACE_FRAME_TAG_BYTE);
+        event_id.push_str(&escape_text(frame));
+    }
+
+    // The marker is always last (see `measureme::event_id`).
+    if omitted_args > 0 {
+        event_id.push_str(SEPARATOR_BYTE);
+        event_id.push_str(OMITTED_ARGS_TAG_BYTE);
+        event_id.push_str(&omitted_args.to_string());
+    }
+
+    event_id
+}
+
+/// Returns the indices of all segments of the profile at `path_stem` that
+/// exist on disk, in ascending order.
+fn find_segment_indices(path_stem: &Path) -> Result<Vec<u32>, Box<dyn Error + Send + Sync>> {
+    let first_segment = segment_file_path(path_stem, 0);
+    let dir = match first_segment.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    // Segment files are named `<prefix><index>.mm_profdata`, with the prefix
+    // being the first segment's file name minus the extension.
+    let first_segment_name = first_segment.file_name().unwrap().to_string_lossy();
+    let prefix = &first_segment_name[..first_segment_name.len() - FILE_EXTENSION.len()];
+    let suffix = format!(".{}", FILE_EXTENSION);
+
+    let mut segment_indices = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        if file_name == first_segment_name {
+            segment_indices.push(0);
+        } else if let Some(index) = file_name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(&suffix[..]))
+            .and_then(|index| index.parse::<u32>().ok())
+        {
+            segment_indices.push(index);
+        }
+    }
+
+    segment_indices.sort_unstable();
+
+    Ok(segment_indices)
+}
+
 // This struct reflects what filenames were in old versions of measureme. It is
 // used only for giving helpful error messages now if a user tries to load old
 // data.
@@ -337,6 +1603,8 @@ impl ProfilerFiles {
 mod tests {
     use super::*;
     use crate::{EventPayload, Timestamp};
+    use measureme::Profiler;
+    use std::io::Write;
     use std::time::Duration;
     use std::{borrow::Cow, time::SystemTime};
 
@@ -350,12 +1618,16 @@ mod tests {
         Event {
             event_kind: Cow::from(event_kind),
             label: Cow::from(label),
+            category: None,
             additional_data: Vec::new(),
+            integer_args: Vec::new(),
+            backtrace: Vec::new(),
             payload: EventPayload::Timestamp(Timestamp::Interval {
                 start: SystemTime::UNIX_EPOCH + Duration::from_nanos(start_nanos),
                 end: SystemTime::UNIX_EPOCH + Duration::from_nanos(end_nanos),
             }),
             thread_id,
+            depth: None,
         }
     }
 
@@ -368,11 +1640,15 @@ mod tests {
         Event {
             event_kind: Cow::from(event_kind),
             label: Cow::from(label),
+            category: None,
             additional_data: Vec::new(),
+            integer_args: Vec::new(),
+            backtrace: Vec::new(),
             payload: EventPayload::Timestamp(Timestamp::Instant(
                 SystemTime::UNIX_EPOCH + Duration::from_nanos(timestamp_nanos),
             )),
             thread_id,
+            depth: None,
         }
     }
 
@@ -385,9 +1661,13 @@ mod tests {
         Event {
             event_kind: Cow::from(event_kind),
             label: Cow::from(label),
+            category: None,
             additional_data: Vec::new(),
+            integer_args: Vec::new(),
+            backtrace: Vec::new(),
             payload: EventPayload::Integer(value),
             thread_id,
+            depth: None,
         }
     }
 
@@ -429,6 +1709,205 @@ mod tests {
         }
     }
 
+    #[test]
+    fn metadata_with_special_characters() {
+        let cmd = "rustc \"a b\" \\ ü\n\u{1}";
+        let data = ProfilingDataBuilder::with_metadata(7, 3, cmd).into_profiling_data();
+
+        assert_eq!(data.metadata().cmd, cmd);
+        assert_eq!(data.metadata().process_id, 3);
+        assert_eq!(
+            data.metadata().start_time,
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(7)
+        );
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn merge_profiles() {
+        let mut a = ProfilingDataBuilder::new();
+        a.interval("k", "a1", 0, 10, 20, |_| {})
+         .instant("k", "a2\x1E\x12cat\x1Earg\x1E\x1342", 0, 30)
+         .interval("k", "a3", 0, 150, 200, |_| {});
+
+        // Starts 100ns after `a`.
+        let mut b = ProfilingDataBuilder::with_metadata(100, 2, "b");
+        b.interval("k", "b1", 0, 0, 5, |_| {})
+         .instant("k", "b2", 1, 3)
+         .integer("k", "b3", 1, 7);
+
+        let merged = ProfilingData::merge(&[a.into_profiling_data(), b.into_profiling_data()]).unwrap();
+
+        assert_eq!(merged.metadata().start_time, SystemTime::UNIX_EPOCH);
+
+        let events: Vec<Event<'_>> = merged.iter_full().collect();
+        assert_eq!(events.len(), 6);
+
+        assert_eq!(events[0], full_interval("k", "a1", 0, 10, 20));
+        assert_eq!(events[1].label, "a2");
+        assert_eq!(events[1].category.as_deref(), Some("cat"));
+        assert_eq!(events[1].additional_data, vec!["arg", "42"]);
+        assert_eq!(events[1].integer_args, vec![(1, 42)]);
+        assert_eq!(events[2], full_instant("k", "b2", 2, 103));
+        assert_eq!(events[3], full_integer("k", "b3", 2, 7));
+        assert_eq!(events[4], full_interval("k", "b1", 1, 100, 105));
+        assert_eq!(events[5], full_interval("k", "a3", 0, 150, 200));
+    }
+
+    #[test]
+    fn instants_with_values() {
+        let mut b = ProfilingDataBuilder::new();
+        b.instant_with_value("GC", "collect", 0, 10, 40 << 20)
+            .instant("GC", "collect", 0, 20)
+            .interval("GC", "collect", 0, 30, 30, |_| {});
+
+        let merged = ProfilingData::merge(&[b.into_profiling_data()]).unwrap();
+        let payloads: Vec<_> = merged.iter().map(|e| e.payload).collect();
+
+        assert_eq!(
+            payloads,
+            vec![
+                EventPayload::InstantWithValue {
+                    time: SystemTime::UNIX_EPOCH + Duration::from_nanos(10),
+                    value: 40 << 20,
+                },
+                full_instant("GC", "collect", 0, 20).payload,
+                full_interval("GC", "collect", 0, 30, 30).payload,
+            ]
+        );
+        assert!(payloads[0].is_instant() && payloads[1].is_instant());
+        assert_eq!(payloads[0].instant_value(), Some(40 << 20));
+        assert_eq!(payloads[1].instant_value(), None);
+    }
+
+    #[test]
+    fn merge_nothing() {
+        assert!(ProfilingData::merge(&[]).is_err());
+    }
+
+    #[test]
+    fn thread_names() {
+        let mut builder = ProfilingDataBuilder::new();
+
+        builder
+            .instant(THREAD_NAME_EVENT_KIND, "worker", 1, 10)
+            .interval("k1", "id1", 1, 20, 30, |_| {})
+            .instant(THREAD_NAME_EVENT_KIND, "main", 0, 40)
+            .instant(THREAD_NAME_EVENT_KIND, "renamed worker", 1, 50);
+
+        let profiling_data = builder.into_profiling_data();
+
+        assert_eq!(profiling_data.thread_name(0), Some("main"));
+        assert_eq!(profiling_data.thread_name(1), Some("renamed worker"));
+        assert_eq!(profiling_data.thread_name(2), None);
+    }
+
+    #[test]
+    fn thread_names_recorded_by_profiler() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiling_data")
+            .join("thread_names");
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.set_thread_name("main");
+        let thread_id = profiler.start_interval(event_kind, event_id).thread_id();
+        drop(profiler);
+
+        let profiling_data = ProfilingData::new(&path_stem).unwrap();
+        assert_eq!(profiling_data.thread_name(thread_id), Some("main"));
+        assert_eq!(profiling_data.thread_name(thread_id + 1), None);
+    }
+
+    #[test]
+    fn written_files_can_be_read_back() {
+        let build = || {
+            let mut b = ProfilingDataBuilder::with_metadata(100, 7, "rustc");
+            b.thread_name(1, "worker", 0)
+                .interval("Query", "typeck", 1, 10, 100, |b| {
+                    b.interval("Query", "type_of", 1, 20, 50, |_| {});
+                    b.instant("QueryCacheHit", "type_of", 1, 60);
+                })
+                .integer("ArtifactSize", "crate_metadata", 0, 1234);
+            b
+        };
+
+        let path_stem = Path::new("test-tmp")
+            .join("profiling_data")
+            .join("builder_round_trip");
+        build().write_to_file(&path_stem).unwrap();
+
+        let expected = build().into_profiling_data();
+        let data = ProfilingData::new(&path_stem).unwrap();
+
+        assert_eq!(
+            fs::read(segment_file_path(&path_stem, 0)).unwrap(),
+            build().into_bytes()
+        );
+        assert_eq!(data.metadata().cmd, "rustc");
+        assert_eq!(data.metadata().process_id, 7);
+        assert_eq!(data.thread_name(1), Some("worker"));
+        assert_eq!(
+            data.iter_full().collect::<Vec<_>>(),
+            expected.iter_full().collect::<Vec<_>>()
+        );
+
+        let self_times: Vec<_> = data
+            .self_times()
+            .into_iter()
+            .map(|self_time| {
+                let event = data.decode_full_event(self_time.event_index);
+                (event.label.into_owned(), self_time.self_duration)
+            })
+            .collect();
+        assert_eq!(
+            self_times,
+            vec![
+                ("type_of".to_string(), Duration::from_nanos(30)),
+                ("typeck".to_string(), Duration::from_nanos(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn counter_values() {
+        let counter_values = |builder: &mut ProfilingDataBuilder| {
+            builder
+                .interval("Query", "q1", 0, 10, 110, |b| {
+                    b.instant("Query", "i1", 0, 50);
+                })
+                .integer("ArtifactSize", "size", 0, 1234);
+        };
+
+        let mut builder = ProfilingDataBuilder::with_metadata_json(
+            r#"{ "start_time": 0, "process_id": 0, "cmd": "test cmd", "counter": { "name": "instructions:u", "units": [["instructions", 1]] } }"#,
+        );
+        counter_values(&mut builder);
+        let data = builder.into_profiling_data();
+
+        let values: Vec<_> = data
+            .iter()
+            .map(|event| data.counter_value(&event))
+            .collect();
+        assert_eq!(values, vec![None, Some(100), None]);
+
+        // Wall-time profiles don't have counter values, regardless of whether
+        // they name their counter.
+        for metadata in &[
+            r#"{ "start_time": 0, "process_id": 0, "cmd": "test cmd" }"#,
+            r#"{ "start_time": 0, "process_id": 0, "cmd": "test cmd", "counter": { "name": "wall-time", "units": [["ns", 1]] } }"#,
+        ] {
+            let mut builder = ProfilingDataBuilder::with_metadata_json(metadata);
+            counter_values(&mut builder);
+            let data = builder.into_profiling_data();
+
+            assert!(data
+                .iter()
+                .all(|event| data.counter_value(&event).is_none()));
+        }
+    }
+
     #[rustfmt::skip]
     #[test]
     fn build_interval_sequence() {
@@ -513,6 +1992,167 @@ mod tests {
         assert_eq!(profiling_data.to_full_event(&events[6]), full_interval("k1", "id1", 0, 10, 100));
     }
 
+    #[test]
+    fn future_file_format_versions() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 20, |_| {});
+        let bytes = b.into_bytes();
+
+        // A newer minor version with an optional section can be read.
+        let mut newer_minor = bytes.clone();
+        newer_minor[measureme::file_header::FILE_MINOR_VERSION_BYTE_INDEX] += 1;
+        newer_minor.extend_from_slice(&[measureme::LAST_OPTIONAL_PAGE_TAG, 3, 0, 0, 0, 1, 2, 3]);
+        let data = ProfilingData::from_paged_buffer(newer_minor, None).unwrap();
+        assert_eq!(data.truncated_bytes(), 0);
+        assert_eq!(data.iter_full().next().unwrap().label, "typeck");
+
+        // A newer major version can't.
+        let mut newer_major = bytes.clone();
+        newer_major[4] += 1;
+        let error = ProfilingData::from_paged_buffer(newer_major, None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains(&format!(
+            "format version {}, but this version of measureme only supports versions up to {}",
+            file_formats::current::FILE_FORMAT + 1,
+            file_formats::current::FILE_FORMAT
+        )));
+
+        let mut older_major = bytes;
+        older_major[4] = 6;
+        let error = ProfilingData::from_paged_buffer(older_major, None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("format version 6, which is older than version 7"));
+    }
+
+    #[test]
+    fn intervals_of_files_without_instant_values() {
+        // Before instant events could have a value, their encoding was that
+        // of intervals that start and end after `TIMESTAMP_PERIOD`.
+        let start = measureme::TIMESTAMP_PERIOD + 10;
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, start, start + 5, |_| {});
+        let mut bytes = b.into_bytes();
+        bytes[measureme::file_header::FILE_MINOR_VERSION_BYTE_INDEX] =
+            measureme::file_header::FIRST_MINOR_VERSION_WITH_INSTANT_VALUES - 1;
+
+        let expected = full_interval("Query", "typeck", 0, start, start + 5);
+        let data = ProfilingData::from_paged_buffer(bytes.clone(), None).unwrap();
+        assert_eq!(data.iter_full().collect::<Vec<_>>(), vec![expected.clone()]);
+
+        let streamed = EventStream::new(std::io::Cursor::new(&bytes[..]), None).unwrap();
+        let streamed: Vec<_> = streamed.map(|e| e.unwrap().payload).collect();
+        assert_eq!(streamed, vec![expected.payload]);
+    }
+
+    #[test]
+    fn timestamp_epoch_index_of_older_files_is_ignored() {
+        // An index stream that ends with a partial entry is corrupt, but
+        // before the index was added, its pages were unknown optional pages.
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 15, |_| {});
+        let mut bytes = b.into_bytes();
+        measureme::write_page_to(&mut bytes, PageTag::TimestampEpochIndex, &[0; 4]).unwrap();
+        assert!(ProfilingData::from_paged_buffer(bytes.clone(), None).is_err());
+
+        bytes[measureme::file_header::FILE_MINOR_VERSION_BYTE_INDEX] =
+            measureme::file_header::FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX - 1;
+
+        let expected = full_interval("Query", "typeck", 0, 10, 15);
+        let data = ProfilingData::from_paged_buffer(bytes.clone(), None).unwrap();
+        assert_eq!(data.iter_full().collect::<Vec<_>>(), vec![expected.clone()]);
+
+        let streamed = EventStream::new(std::io::Cursor::new(&bytes[..]), None).unwrap();
+        let streamed: Vec<_> = streamed.map(|e| e.unwrap().payload).collect();
+        assert_eq!(streamed, vec![expected.payload]);
+    }
+
+    #[test]
+    fn read_from_a_pipe() {
+        /// Like a pipe, hands out the bytes in small chunks and can't seek.
+        struct Chunked<'a>(&'a [u8]);
+
+        impl Read for Chunked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = std::cmp::min(std::cmp::min(buf.len(), 7), self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let mut b = ProfilingDataBuilder::new();
+        b.thread_name(0, "main", 0)
+            .record_metadata("key", "value")
+            .interval("Query", "typeck\x1eitem", 0, 10, 100, |b| {
+                b.instant("QueryCacheHit", "type_of", 0, 50);
+            });
+        let bytes = b.into_bytes();
+
+        let expected = ProfilingData::from_paged_buffer(bytes.clone(), None).unwrap();
+        let data = ProfilingData::from_reader(Chunked(&bytes)).unwrap();
+
+        assert_eq!(data.num_events(), 3);
+        assert!(data.iter_full().eq(expected.iter_full()));
+        assert_eq!(data.thread_name(0), Some("main"));
+        assert_eq!(data.process_metadata()["key"], "value");
+        assert_eq!(data.metadata().start_time, expected.metadata().start_time);
+
+        assert!(ProfilingData::from_reader(Chunked(&bytes[..10])).is_err());
+    }
+
+    #[test]
+    fn read_gzipped_profile() {
+        let mut b = ProfilingDataBuilder::new();
+        b.thread_name(0, "main", 0)
+            .interval("Query", "typeck\x1eitem", 0, 10, 100, |b| {
+                b.instant("QueryCacheHit", "type_of", 0, 50);
+            });
+        let bytes = b.into_bytes();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&bytes).unwrap();
+        let gzipped = gz.finish().unwrap();
+
+        let path_stem = Path::new("test-tmp").join("gzip").join("profile");
+        fs::create_dir_all(path_stem.parent().unwrap()).unwrap();
+        fs::write(path_stem.with_extension(FILE_EXTENSION), &gzipped).unwrap();
+
+        let expected = ProfilingData::from_paged_buffer(bytes, None).unwrap();
+        for data in [
+            ProfilingData::new(&path_stem).unwrap(),
+            ProfilingData::from_reader(&gzipped[..]).unwrap(),
+        ] {
+            assert_eq!(data.num_events(), 3);
+            assert!(data.iter_full().eq(expected.iter_full()));
+            assert_eq!(data.thread_name(0), Some("main"));
+        }
+
+        let error = ProfilingData::from_paged_buffer(gzipped[..10].to_vec(), None).unwrap_err();
+        assert!(error.to_string().starts_with("Could not decompress"));
+    }
+
+    #[test]
+    fn string_map() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck\x1eitem", 0, 10, 100, |b| {
+            b.interval("Query", "type_of", 0, 20, 30, |_| {});
+        });
+        let data = b.into_profiling_data();
+
+        let map = data.string_map().unwrap();
+        let strings: Vec<_> = map.iter().map(|(_, s)| s).collect();
+        for s in &["Query", "typeck\x1eitem", "type_of"] {
+            assert!(strings.contains(s), "missing {:?}", s);
+        }
+        for (id, s) in map.iter() {
+            assert_eq!(map.get(id), Some(s));
+        }
+    }
+
     /// Tests that `ProfilingData` can handle more than one file format.
     ///
     /// ## Adding new tests
@@ -0,0 +1,275 @@
+use crate::{Event, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// The self time of a single interval event, see [`ProfilingData::self_times()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventSelfTime {
+    pub event_index: usize,
+    /// The duration of the event minus the durations of its direct children.
+    pub self_duration: Duration,
+    /// Like `self_duration`, but an event that is nested within another one
+    /// with the same label, directly or through events with other labels, is
+    /// treated as part of the outermost of them: that event is attributed its
+    /// own self time and those of all nested events with its label, and the
+    /// nested events are attributed nothing.
+    pub self_duration_excluding_recursion: Duration,
+}
+
+impl ProfilingData {
+    /// Computes the self time of every interval event, both the naive value
+    /// that is also used by [`ProfilingData::perform_analysis()`] and one that
+    /// accounts for recursion (see [`EventSelfTime`]). For recursive queries,
+    /// the latter is much easier to interpret: it is attributed to the
+    /// invocation that started the recursion, and it is the wall time of
+    /// that invocation minus the time spent computing other things.
+    ///
+    /// The results are ordered by event index. Like the self-times computed
+    /// by `perform_analysis`, this relies on the events of each thread being
+    /// properly nested, see [`ProfilingData::validate()`].
+    pub fn self_times(&self) -> Vec<EventSelfTime> {
+        struct Frame<'a> {
+            event: Event<'a>,
+            // The index in `self_times` of this event and of the outermost
+            // event with its label on the stack, which it is attributed to.
+            index: usize,
+            root: usize,
+        }
+
+        #[derive(Default)]
+        struct Stack<'a> {
+            frames: Vec<Frame<'a>>,
+            // The index in `self_times` of the outermost event with each label
+            // on the stack.
+            roots: FxHashMap<Cow<'a, str>, usize>,
+        }
+
+        impl<'a> Stack<'a> {
+            fn pop(&mut self) {
+                let frame = self.frames.pop().unwrap();
+                if frame.root == frame.index {
+                    self.roots.remove(&frame.event.label);
+                }
+            }
+        }
+
+        let mut self_times = Vec::<EventSelfTime>::new();
+        let mut stacks = FxHashMap::<u32, Stack<'_>>::default();
+
+        // Like in `perform_analysis`, walking the events in reverse order
+        // means that we encounter parents before their children.
+        for lightweight_event in self.iter().rev() {
+            let duration = match lightweight_event.duration() {
+                Some(duration) => duration,
+                None => continue,
+            };
+
+            let event = self.to_full_event(&lightweight_event);
+            let stack = stacks.entry(event.thread_id).or_default();
+
+            while let Some(top) = stack.frames.last() {
+                if top.event.contains(&event) {
+                    break;
+                }
+                stack.pop();
+            }
+
+            let index = self_times.len();
+            let mut self_time = EventSelfTime {
+                event_index: lightweight_event.event_index,
+                self_duration: duration,
+                self_duration_excluding_recursion: duration,
+            };
+
+            // Clock skew or improperly nested events can make the children
+            // of an event take longer than the event itself.
+            let root = stack.roots.get(&event.label).copied().unwrap_or(index);
+            if let Some(parent) = stack.frames.last() {
+                let parent_self_time = &mut self_times[parent.index].self_duration;
+                *parent_self_time = parent_self_time.saturating_sub(duration);
+
+                // The duration moves from the root of the parent to the root
+                // of the event, unless they are the same.
+                if root != parent.root {
+                    let parent_root = &mut self_times[parent.root];
+                    parent_root.self_duration_excluding_recursion = parent_root
+                        .self_duration_excluding_recursion
+                        .saturating_sub(duration);
+                    if root != index {
+                        self_times[root].self_duration_excluding_recursion += duration;
+                    }
+                }
+            }
+            if root != index {
+                // The time is accounted for by the root.
+                self_time.self_duration_excluding_recursion = Duration::from_secs(0);
+            } else {
+                stack.roots.insert(event.label.clone(), index);
+            }
+
+            self_times.push(self_time);
+            stack.frames.push(Frame { event, index, root });
+        }
+
+        self_times.reverse();
+        self_times
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    fn self_times_by_label(data: &ProfilingData) -> Vec<(String, u64, u64)> {
+        data.self_times()
+            .into_iter()
+            .map(|t| {
+                let event = data.decode_full_event(t.event_index);
+                (
+                    event.label.into_owned(),
+                    t.self_duration.as_nanos() as u64,
+                    t.self_duration_excluding_recursion.as_nanos() as u64,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_recursion() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q1", 0, 0, 100, |b| {
+            b.interval("Query", "q2", 0, 10, 30, |_| {});
+            b.interval("Query", "q3", 0, 40, 90, |b| {
+                b.interval("Query", "q4", 0, 50, 60, |_| {});
+            });
+        });
+        b.instant("Query", "i1", 0, 110);
+
+        assert_eq!(
+            self_times_by_label(&b.into_profiling_data()),
+            vec![
+                ("q2".to_string(), 20, 20),
+                ("q4".to_string(), 10, 10),
+                ("q3".to_string(), 40, 40),
+                ("q1".to_string(), 30, 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn recursion_three_deep() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q", 0, 0, 100, |b| {
+            b.interval("Query", "q", 0, 10, 90, |b| {
+                b.interval("Query", "q", 0, 20, 80, |b| {
+                    b.interval("Query", "r", 0, 30, 50, |_| {});
+                });
+                b.interval("Query", "s", 0, 85, 88, |_| {});
+            });
+        });
+
+        // A recursive chain on another thread is independent.
+        b.interval("Query", "q", 1, 0, 10, |_| {});
+
+        let data = b.into_profiling_data();
+        let self_times = self_times_by_label(&data);
+
+        assert_eq!(
+            self_times,
+            vec![
+                ("r".to_string(), 20, 20),
+                ("q".to_string(), 40, 0),
+                ("s".to_string(), 3, 3),
+                ("q".to_string(), 17, 0),
+                ("q".to_string(), 20, 77),
+                ("q".to_string(), 10, 10),
+            ]
+        );
+
+        // The outermost frame gets the wall time of the recursion minus the
+        // time spent in the distinct children `r` and `s`.
+        assert_eq!(self_times[4].2, 100 - 20 - 3);
+
+        // Either way, the nested frames don't get counted more than once.
+        let total_q = |f: fn(&(String, u64, u64)) -> u64| -> u64 {
+            self_times.iter().filter(|t| t.0 == "q").map(f).sum()
+        };
+        assert_eq!(total_q(|t| t.1), total_q(|t| t.2));
+    }
+
+    #[test]
+    fn indirect_recursion() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q", 0, 0, 100, |b| {
+            b.interval("Query", "r", 0, 10, 90, |b| {
+                b.interval("Query", "q", 0, 20, 80, |_| {});
+            });
+        });
+
+        // The outer `q` is attributed the time of `r` spent in the inner one.
+        assert_eq!(
+            self_times_by_label(&b.into_profiling_data()),
+            vec![
+                ("q".to_string(), 60, 0),
+                ("r".to_string(), 20, 20),
+                ("q".to_string(), 20, 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn indirect_recursion_with_children() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q", 0, 0, 100, |b| {
+            b.interval("Query", "r", 0, 10, 90, |b| {
+                b.interval("Query", "q", 0, 20, 80, |b| {
+                    b.interval("Query", "s", 0, 30, 40, |_| {});
+                    b.interval("Query", "r", 0, 50, 70, |_| {});
+                });
+            });
+        });
+
+        let self_times = self_times_by_label(&b.into_profiling_data());
+        assert_eq!(
+            self_times,
+            vec![
+                ("s".to_string(), 10, 10),
+                ("r".to_string(), 20, 0),
+                ("q".to_string(), 30, 0),
+                ("r".to_string(), 20, 40),
+                ("q".to_string(), 20, 50),
+            ]
+        );
+
+        // Nothing is counted twice or lost.
+        let total = |f: fn(&(String, u64, u64)) -> u64| -> u64 { self_times.iter().map(f).sum() };
+        assert_eq!(total(|t| t.1), 100);
+        assert_eq!(total(|t| t.2), 100);
+    }
+
+    #[test]
+    fn overlapping_children() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // The children overlap, so together they take longer than `q`.
+        b.interval("Query", "q", 0, 0, 100, |b| {
+            b.interval("Query", "r", 0, 10, 90, |_| {});
+            b.interval("Query", "s", 0, 20, 95, |_| {});
+        });
+
+        assert_eq!(
+            self_times_by_label(&b.into_profiling_data()),
+            vec![
+                ("r".to_string(), 80, 80),
+                ("s".to_string(), 75, 75),
+                ("q".to_string(), 0, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Merging the events of all threads into a single timeline.
+
+use crate::{LightweightEvent, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::SystemTime;
+
+/// The events of one thread, by their start and index.
+struct ThreadEvents {
+    thread_id: u32,
+    /// The events that have been ordered already, the latest start first, so
+    /// that the next one is popped off the end.
+    events: Vec<(SystemTime, usize)>,
+    /// The reorder buffer of the events that may still be preceded by events
+    /// that have been recorded before them, see `EventsSortedByTime::new`.
+    pending: Vec<(SystemTime, usize)>,
+    /// Whether `events` has turned out not to be ordered, which only happens
+    /// for malformed profiles whose events overlap.
+    unordered: bool,
+}
+
+impl ThreadEvents {
+    fn push(&mut self, event: (SystemTime, usize)) {
+        if let Some(&last) = self.events.last() {
+            self.unordered |= event > last;
+        }
+        self.events.push(event);
+    }
+}
+
+/// A k-way merge over the events of all threads, see
+/// `ProfilingData::events_sorted_by_time`.
+struct EventsSortedByTime<'a> {
+    data: &'a ProfilingData,
+    threads: Vec<ThreadEvents>,
+    /// The next event of every thread that has events left, as its start,
+    /// thread id, event index and the index of the thread in `threads`.
+    heads: BinaryHeap<Reverse<(SystemTime, u32, usize, usize)>>,
+}
+
+impl<'a> EventsSortedByTime<'a> {
+    fn new(data: &'a ProfilingData) -> EventsSortedByTime<'a> {
+        let mut threads = Vec::<ThreadEvents>::new();
+        let mut thread_indices = FxHashMap::<u32, usize>::default();
+
+        // The events of a thread are recorded when they end, so read in
+        // reverse, an event that encloses others comes before them, and the
+        // events that aren't nested in each other come by their start, the
+        // latest first. An event can be ordered once an event that starts
+        // before it has been read, since all events that are read after that
+        // one start before it as well, unless they are nested in it. So the
+        // reorder buffer only holds the events that enclose each other, i.e.
+        // it is bounded by the nesting depth of the thread. The event index
+        // breaks ties, i.e. events starting at the same time stay in the
+        // order they have been recorded in.
+        for event in data.iter().rev() {
+            let start = match event.start() {
+                Some(start) => start,
+                None => continue,
+            };
+
+            let thread_index = *thread_indices.entry(event.thread_id).or_insert_with(|| {
+                threads.push(ThreadEvents {
+                    thread_id: event.thread_id,
+                    events: Vec::new(),
+                    pending: Vec::new(),
+                    unordered: false,
+                });
+                threads.len() - 1
+            });
+
+            let thread = &mut threads[thread_index];
+            let event = (start, event.event_index);
+            while let Some(&pending) = thread.pending.last() {
+                if pending < event {
+                    break;
+                }
+                thread.pending.pop();
+                thread.push(pending);
+            }
+            thread.pending.push(event);
+        }
+
+        for thread in &mut threads {
+            while let Some(pending) = thread.pending.pop() {
+                thread.push(pending);
+            }
+            if thread.unordered {
+                thread.events.sort_by(|l, r| r.cmp(l));
+            }
+        }
+
+        let mut sorted = EventsSortedByTime {
+            data,
+            threads,
+            heads: BinaryHeap::new(),
+        };
+        for thread_index in 0..sorted.threads.len() {
+            sorted.push_head(thread_index);
+        }
+        sorted
+    }
+
+    /// Moves the next event of the thread at `thread_index` into `heads`.
+    fn push_head(&mut self, thread_index: usize) {
+        let thread = &mut self.threads[thread_index];
+        if let Some((start, event_index)) = thread.events.pop() {
+            self.heads.push(Reverse((
+                start,
+                thread.thread_id,
+                event_index,
+                thread_index,
+            )));
+        }
+    }
+}
+
+impl<'a> Iterator for EventsSortedByTime<'a> {
+    type Item = LightweightEvent;
+
+    fn next(&mut self) -> Option<LightweightEvent> {
+        let Reverse((_, _, event_index, thread_index)) = self.heads.pop()?;
+        self.push_head(thread_index);
+        Some(self.data.decode_lightweight_event(event_index))
+    }
+}
+
+impl ProfilingData {
+    /// Returns the interval and instant events of all threads ordered by the
+    /// time they start, as a single timeline. Events that start at the same
+    /// time are ordered by thread id, and the events of the same thread in
+    /// the order they have been recorded in. Integer events don't have a
+    /// timestamp and are left out.
+    ///
+    /// The events of each thread are ordered with a reorder buffer that is
+    /// bounded by how deeply they are nested, and the resulting per-thread
+    /// streams are merged while iterating, so just the start and index of
+    /// every event are kept in memory, not the decoded events.
+    pub fn events_sorted_by_time(&self) -> impl Iterator<Item = LightweightEvent> + '_ {
+        EventsSortedByTime::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ProfilingDataBuilder;
+
+    #[test]
+    fn events_of_all_threads_by_start() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 1, 10, 100, |b| {
+            b.interval("Query", "inner", 1, 20, 30, |_| {});
+            b.integer("ArtifactSize", "object_file", 1, 1024);
+            b.instant("QueryCacheHit", "hit", 1, 40);
+        });
+        b.interval("Query", "other thread", 0, 20, 50, |b| {
+            b.interval("Query", "same start", 0, 20, 25, |_| {});
+        });
+        b.instant("QueryCacheHit", "first", 2, 0);
+        b.interval("Query", "late", 0, 60, 70, |_| {});
+
+        let data = b.into_profiling_data();
+        let start_time = data.metadata().start_time;
+
+        let sorted: Vec<_> = data
+            .events_sorted_by_time()
+            .map(|event| {
+                let full_event = data.to_full_event(&event);
+                let start = event.start().unwrap().duration_since(start_time).unwrap();
+                (
+                    start.as_nanos() as u64,
+                    event.thread_id,
+                    full_event.label.into_owned(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            sorted,
+            vec![
+                (0, 2, "first".to_string()),
+                (10, 1, "outer".to_string()),
+                // Ties are broken by thread id, and then by the order in which
+                // the events have been recorded, i.e. inner events first.
+                (20, 0, "same start".to_string()),
+                (20, 0, "other thread".to_string()),
+                (20, 1, "inner".to_string()),
+                (40, 1, "hit".to_string()),
+                (60, 0, "late".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_events_by_start() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 0, 100, |b| {
+            b.interval("Query", "inner", 0, 60, 70, |_| {});
+        });
+        // Starts within `outer` but ends after it, and before `inner`.
+        b.interval("Query", "crossing", 0, 50, 150, |_| {});
+
+        let data = b.into_profiling_data();
+        let labels: Vec<_> = data
+            .events_sorted_by_time()
+            .map(|event| data.to_full_event(&event).label.into_owned())
+            .collect();
+        assert_eq!(labels, ["outer", "crossing", "inner"]);
+    }
+
+    #[test]
+    fn no_events() {
+        let data = ProfilingDataBuilder::new().into_profiling_data();
+        assert_eq!(data.events_sorted_by_time().count(), 0);
+    }
+}
@@ -1,102 +1,241 @@
 use rustc_hash::FxHashMap;
-use std::cmp;
-use std::time::SystemTime;
-
-use crate::{LightweightEvent, ProfilingData};
-
-// This state is kept up-to-date while iteration over events.
-struct PerThreadState {
-    stack: Vec<LightweightEvent>,
-    stack_id: String,
-    start: SystemTime,
-    end: SystemTime,
-    total_event_time_nanos: u64,
-}
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::{CallTreeNode, LightweightEvent, ProfilingData};
 
 /// Collect a map of all stacks and how many nanoseconds are spent in each.
-/// Uses a variation of the algorithm in `summarize`.
+/// The stacks are those of the call trees of the threads, see
+/// [`ProfilingData::call_trees()`].
 // Original implementation provided by @andjo403 in
 // https://github.com/michaelwoerister/measureme/pull/1
 pub fn collapse_stacks<'a>(profiling_data: &ProfilingData) -> FxHashMap<String, u64> {
-    let mut counters = FxHashMap::default();
-    let mut threads = FxHashMap::<_, PerThreadState>::default();
-
-    for current_event in profiling_data
-        .iter()
-        .rev()
-        .filter(|e| e.payload.is_interval())
-    {
-        let start = current_event.start().unwrap();
-        let end = current_event.end().unwrap();
-        let thread = threads
-            .entry(current_event.thread_id)
-            .or_insert(PerThreadState {
-                stack: Vec::new(),
-                stack_id: "rustc".to_owned(),
-                start,
-                end,
-                total_event_time_nanos: 0,
-            });
+    collapse_stacks_impl(
+        profiling_data,
+        |node| Cow::Borrowed(&node.label[..]),
+        None::<fn(&LightweightEvent) -> u64>,
+    )
+}
 
-        thread.start = cmp::min(thread.start, start);
+/// Like `collapse_stacks` but each interval event weighs `weight(event)`
+/// instead of its duration, e.g. its wall time in a profile that has been
+/// recorded with an instruction counter. As the gaps between the events
+/// can't be weighed, the `rustc` stack is always 0.
+pub fn collapse_stacks_weighted(
+    profiling_data: &ProfilingData,
+    weight: impl Fn(&LightweightEvent) -> u64 + Sync,
+) -> FxHashMap<String, u64> {
+    collapse_stacks_impl(
+        profiling_data,
+        |node| Cow::Borrowed(&node.label[..]),
+        Some(weight),
+    )
+}
 
-        // Pop all events from the stack that are not parents of the
-        // current event.
-        while let Some(current_top) = thread.stack.last().cloned() {
-            if current_top.contains(&current_event) {
-                break;
-            }
+/// Like `collapse_stacks` but frames of events that have a category are named
+/// `<label> (<category>)`, so that events with the same label but different
+/// categories end up in different stacks.
+pub fn collapse_stacks_with_categories(profiling_data: &ProfilingData) -> FxHashMap<String, u64> {
+    collapse_stacks_impl(
+        profiling_data,
+        category_frame_name,
+        None::<fn(&LightweightEvent) -> u64>,
+    )
+}
 
-            let popped = thread.stack.pop().unwrap();
-            let popped = profiling_data.to_full_event(&popped);
-            let new_stack_id_len = thread.stack_id.len() - (popped.label.len() + 1);
-            thread.stack_id.truncate(new_stack_id_len);
-        }
+/// Like `collapse_stacks_with_categories` but each interval event weighs
+/// `weight(event)`, see `collapse_stacks_weighted`.
+pub fn collapse_stacks_with_categories_weighted(
+    profiling_data: &ProfilingData,
+    weight: impl Fn(&LightweightEvent) -> u64 + Sync,
+) -> FxHashMap<String, u64> {
+    collapse_stacks_impl(profiling_data, category_frame_name, Some(weight))
+}
 
-        if !thread.stack.is_empty() {
-            // If there is something on the stack, subtract the current
-            // interval from it.
-            counters
-                .entry(thread.stack_id.clone())
-                .and_modify(|self_time| {
-                    *self_time -= current_event.duration().unwrap().as_nanos() as u64;
-                });
-        } else {
-            // Update the total_event_time_nanos counter as the current event
-            // is on top level
-            thread.total_event_time_nanos += current_event.duration().unwrap().as_nanos() as u64;
-        }
+fn category_frame_name(node: &CallTreeNode) -> Cow<'_, str> {
+    match node.category {
+        Some(ref category) => Cow::Owned(format!("{} ({})", node.label, category)),
+        None => Cow::Borrowed(&node.label[..]),
+    }
+}
 
-        // Add this event to the stack_id
-        thread.stack_id.push(';');
-        thread
-            .stack_id
-            .push_str(&profiling_data.to_full_event(&current_event).label[..]);
+/// Like `collapse_stacks` but in the folded stacks format that inferno
+/// expects: frames of events that have a category are named
+/// `<label>|<category>`. The stacks are sorted, so that the output for
+/// different profiles can be diffed. The counts are in the unit of the counter
+/// the profile has been recorded with, i.e. usually in nanoseconds.
+pub fn collapse_stacks_folded(profiling_data: &ProfilingData) -> BTreeMap<String, u64> {
+    collapse_stacks_impl(
+        profiling_data,
+        |node| match node.category {
+            Some(ref category) => Cow::Owned(format!("{}|{}", node.label, category)),
+            None => Cow::Borrowed(&node.label[..]),
+        },
+        None::<fn(&LightweightEvent) -> u64>,
+    )
+    .into_iter()
+    .collect()
+}
 
-        // Update current events self time
-        let self_time = counters.entry(thread.stack_id.clone()).or_default();
-        *self_time += current_event.duration().unwrap().as_nanos() as u64;
+/// `weight` is the weight of each interval event, or `None` for its duration.
+fn collapse_stacks_impl(
+    profiling_data: &ProfilingData,
+    frame_name: impl Fn(&CallTreeNode) -> Cow<'_, str> + Sync,
+    weight: Option<impl Fn(&LightweightEvent) -> u64 + Sync>,
+) -> FxHashMap<String, u64> {
+    let weighs_gaps = weight.is_none();
+    let stacks = ThreadStacks::new(profiling_data, frame_name, weight);
+    let trees = profiling_data.call_trees();
 
-        // Bring the stack up-to-date
-        thread.stack.push(current_event)
-    }
+    // Events are only ever nested within events of the same thread, so the
+    // stacks of each thread can be collapsed separately.
+    #[cfg(feature = "rayon")]
+    let threads: Vec<(FxHashMap<String, u64>, u64)> = {
+        use rayon::prelude::*;
+
+        trees
+            .values()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|tree| {
+                let mut counters = FxHashMap::default();
+                let rustc_time = stacks.collapse(tree, &mut counters);
+                (counters, rustc_time)
+            })
+            .collect()
+    };
 
-    // Finally add a stack that accounts for the gaps between any recorded
-    // events.
+    #[cfg(not(feature = "rayon"))]
+    let threads = trees.values().map(|tree| {
+        let mut counters = FxHashMap::default();
+        let rustc_time = stacks.collapse(tree, &mut counters);
+        (counters, rustc_time)
+    });
+
+    // The threads are merged in the order of their ids, so that the result
+    // doesn't depend on how the work has been scheduled.
+    let mut counters = FxHashMap::<String, u64>::default();
     let mut rustc_time = 0;
-    for thread in threads.values() {
-        // For each thread we take the time between the start of the first and
-        // the end of the last event, and subtract the duration of all top-level
-        // events of that thread. That leaves us with the duration of all gaps
-        // on the threads timeline.
-        rustc_time += thread.end.duration_since(thread.start).unwrap().as_nanos() as u64
-            - thread.total_event_time_nanos;
+    for (thread_counters, thread_rustc_time) in threads {
+        for (stack_id, self_time) in thread_counters {
+            *counters.entry(stack_id).or_default() += self_time;
+        }
+        rustc_time += thread_rustc_time;
     }
+
+    let rustc_time = if weighs_gaps { rustc_time } else { 0 };
     counters.insert("rustc".to_owned(), rustc_time);
 
     counters
 }
 
+/// Collapses the stacks of the call trees of a profile.
+pub(crate) struct ThreadStacks<'a, N, W> {
+    profiling_data: &'a ProfilingData,
+    frame_name: N,
+    weight: Option<W>,
+    /// The unclosed intervals of the profile (see
+    /// `ProfilingData::unclosed_intervals`) by their event index, which is
+    /// that of their marker.
+    unclosed_intervals: FxHashMap<usize, &'a LightweightEvent>,
+}
+
+impl<'a, N, W> ThreadStacks<'a, N, W>
+where
+    N: Fn(&CallTreeNode) -> Cow<'_, str>,
+    W: Fn(&LightweightEvent) -> u64,
+{
+    pub(crate) fn new(
+        profiling_data: &'a ProfilingData,
+        frame_name: N,
+        weight: Option<W>,
+    ) -> ThreadStacks<'a, N, W> {
+        let unclosed_intervals = profiling_data
+            .unclosed_intervals()
+            .iter()
+            .map(|event| (event.event_index, event))
+            .collect();
+
+        ThreadStacks {
+            profiling_data,
+            frame_name,
+            weight,
+            unclosed_intervals,
+        }
+    }
+
+    /// The interval event that `node` has been created for.
+    fn event(&self, node: &CallTreeNode) -> LightweightEvent {
+        let event_index = node.event_index.unwrap();
+        match self.unclosed_intervals.get(&event_index) {
+            Some(&event) => event.clone(),
+            None => self.profiling_data.decode_lightweight_event(event_index),
+        }
+    }
+
+    fn weight(&self, node: &CallTreeNode) -> u64 {
+        match self.weight {
+            Some(ref weight) => weight(&self.event(node)),
+            None => node.duration.as_nanos() as u64,
+        }
+    }
+
+    /// Adds the self weight of the stacks of the call tree `root` of a thread
+    /// to `counters`, and returns the total time of the gaps between the
+    /// top-level events of the thread, in the unit of the counter. The tree
+    /// is walked iteratively, so deep recursion can't overflow the stack.
+    pub(crate) fn collapse(
+        &self,
+        root: &CallTreeNode,
+        counters: &mut FxHashMap<String, u64>,
+    ) -> u64 {
+        let mut stack_id = "rustc".to_owned();
+        // The nodes whose frames make up `stack_id`, with the length of
+        // `stack_id` without their frame and the index of their next child.
+        let mut path = vec![(root, stack_id.len(), 0)];
+        while let Some((node, stack_id_len, next_child)) = path.last_mut() {
+            let child = match node.children.get(*next_child) {
+                Some(child) => child,
+                None => {
+                    stack_id.truncate(*stack_id_len);
+                    path.pop();
+                    continue;
+                }
+            };
+            *next_child += 1;
+
+            let stack_id_len = stack_id.len();
+            stack_id.push(';');
+            stack_id.push_str(&(self.frame_name)(child));
+
+            let children_weight: u64 = child.children.iter().map(|c| self.weight(c)).sum();
+            let self_weight = self.weight(child).saturating_sub(children_weight);
+            *counters.entry(stack_id.clone()).or_default() += self_weight;
+
+            path.push((child, stack_id_len, 0));
+        }
+
+        // The time of the `rustc` stack accounts for the gaps between the
+        // top-level events: the time between the start of the first one and
+        // the end of the last one, minus their durations.
+        let mut span = None;
+        for child in &root.children {
+            let event = self.event(child);
+            let (start, end) = (event.start().unwrap(), event.end().unwrap());
+            span = Some(match span {
+                Some((first, last)) => (std::cmp::min(first, start), std::cmp::max(last, end)),
+                None => (start, end),
+            });
+        }
+
+        match span {
+            Some((start, end)) => (end.duration_since(start).unwrap_or_default().as_nanos() as u64)
+                .saturating_sub(root.duration.as_nanos() as u64),
+            None => 0,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::ProfilingDataBuilder;
@@ -179,4 +318,77 @@ mod test {
 
         assert_eq!(expected_stacks, recorded_stacks);
     }
+
+    #[test]
+    fn weighted_test() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval_with_wall_time("Query", "e1", 0, 0, 1000, 30, |b| {
+            b.interval_with_wall_time("Query", "e2", 0, 100, 900, 20, |_| {});
+        });
+        b.interval_with_wall_time("Query", "e1", 0, 2000, 3000, 5, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        let recorded_stacks = super::collapse_stacks_weighted(&profiling_data, |event| {
+            profiling_data.wall_time(event).unwrap().as_nanos() as u64
+        });
+
+        let mut expected_stacks = FxHashMap::<String, u64>::default();
+        expected_stacks.insert("rustc;e1;e2".into(), 20);
+        expected_stacks.insert("rustc;e1".into(), 15);
+        expected_stacks.insert("rustc".into(), 0);
+
+        assert_eq!(expected_stacks, recorded_stacks);
+    }
+
+    #[test]
+    fn categories_test() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "e1", 0, 1, 2, |_| {});
+        b.interval("Query", "e1\x1E\x12cat", 0, 3, 9, |b| {
+            b.interval("Query", "e2", 0, 4, 8, |_| {});
+        });
+
+        let profiling_data = b.into_profiling_data();
+
+        let recorded_stacks = super::collapse_stacks_with_categories(&profiling_data);
+
+        let mut expected_stacks = FxHashMap::<String, u64>::default();
+        expected_stacks.insert("rustc;e1 (cat);e2".into(), 4);
+        expected_stacks.insert("rustc;e1 (cat)".into(), 2);
+        expected_stacks.insert("rustc;e1".into(), 1);
+        expected_stacks.insert("rustc".into(), 1);
+
+        assert_eq!(expected_stacks, recorded_stacks);
+    }
+
+    #[test]
+    fn folded_test() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "e2", 0, 1, 2, |_| {});
+        b.interval("Query", "e1\x1E\x12cat", 0, 3, 9, |b| {
+            b.interval("Query", "e3", 0, 4, 5, |_| {});
+            b.interval("Query", "e2", 0, 6, 8, |_| {});
+        });
+
+        let profiling_data = b.into_profiling_data();
+
+        let recorded_stacks: Vec<_> = super::collapse_stacks_folded(&profiling_data)
+            .into_iter()
+            .collect();
+
+        assert_eq!(
+            recorded_stacks,
+            vec![
+                ("rustc".to_string(), 1),
+                ("rustc;e1|cat".to_string(), 3),
+                ("rustc;e1|cat;e2".to_string(), 2),
+                ("rustc;e1|cat;e3".to_string(), 1),
+                ("rustc;e2".to_string(), 1),
+            ]
+        );
+    }
 }
@@ -0,0 +1,192 @@
+use crate::{EventPayload, ProfilingData, ProfilingDataBuilder};
+use measureme::file_header::FILE_FLAG_NESTING_DEPTH;
+use rustc_hash::FxHashMap;
+use std::error::Error;
+use std::path::Path;
+
+impl ProfilingData {
+    /// Creates a copy of the profile whose event ids only consist of their
+    /// label and category, without the arguments and captured stacks, e.g.
+    /// to share a profile whose arguments contain file paths or symbol
+    /// names. The string table
+    /// is rebuilt from scratch, so the arguments aren't left behind in it.
+    ///
+    /// Everything else is kept: the timestamps of the events, the start time,
+    /// the counter, the names of the threads and the process metadata. Since
+    /// the nesting depths of the events are derived from their timestamps
+    /// anyway, they aren't recorded in the copy.
+    pub fn strip_args(&self) -> ProfilingData {
+        self.strip_args_builder().into_profiling_data()
+    }
+
+    /// Writes the result of `strip_args` to the file that
+    /// `ProfilingData::new(path_stem)` reads.
+    pub fn write_with_stripped_args(
+        &self,
+        path_stem: &Path,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.strip_args_builder().write_to_file(path_stem)
+    }
+
+    fn strip_args_builder(&self) -> ProfilingDataBuilder {
+        let mut builder = ProfilingDataBuilder::with_metadata_of(self.metadata());
+        let mut string_ids = FxHashMap::default();
+
+        // The markers of explicit parents are copied like any other event,
+        // so they still name the (stripped) event id of the parent. Those of
+        // trace contexts refer to a copy of the trace context.
+        for event in self.iter() {
+            let mut full_event = self.decode_recorded_event(event.event_index);
+            full_event.additional_data.clear();
+            full_event.integer_args.clear();
+            full_event.backtrace.clear();
+            if let Some(trace_context) = self.marked_trace_context(&full_event) {
+                full_event.payload =
+                    EventPayload::Integer(builder.record_trace_context(trace_context));
+            }
+
+            builder.copy_event(
+                &mut string_ids,
+                &full_event,
+                self.omitted_args(&event),
+                event.thread_id,
+                self.metadata().start_time,
+            );
+        }
+
+        for (key, value) in &self.process_metadata {
+            builder.record_metadata(key, value);
+        }
+
+        builder.set_file_flags(self.file_flags & !FILE_FLAG_NESTING_DEPTH);
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::file_header::segment_file_path;
+    use measureme::testing_clocks::ManualClock;
+    use measureme::{EventId, Profiler, TIMESTAMP_EPOCH_LENGTH, TIMESTAMP_PERIOD};
+    use std::fs;
+
+    fn profile_with_args() -> ProfilingData {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(0, "main", 0)
+            .record_metadata("rustc-version", "1.70.0")
+            .interval("Query", "typeck\x1e/home/me/secret.rs", 0, 10, 100, |b| {
+                b.interval("Query", "type_of\x1esecret_symbol", 0, 20, 50, |_| {});
+                b.instant("QueryCacheHit", "type_of\x1esecret_symbol", 0, 60);
+                b.interval("Query", "type_of\x1eother_symbol", 0, 70, 80, |_| {});
+                b.instant("Backtrace", "typeck\x1e\x14secret_crate::frame", 0, 90);
+            })
+            .interval(
+                "Query",
+                "codegen\x1e\x12backend\x1e\x13123",
+                1,
+                0,
+                40,
+                |_| {},
+            );
+
+        b.into_profiling_data()
+    }
+
+    #[test]
+    fn args_are_stripped() {
+        let data = profile_with_args();
+        let stripped = data.strip_args();
+
+        assert_eq!(stripped.num_events(), data.num_events());
+        for (event, original) in stripped.iter_full().zip(data.iter_full()) {
+            assert!(event.additional_data.is_empty());
+            assert!(event.backtrace.is_empty());
+            assert_eq!(event.label, original.label);
+            assert_eq!(event.category, original.category);
+            assert_eq!(event.payload, original.payload);
+        }
+        assert!(data.iter_full().any(|event| event.category.is_some()));
+        assert!(data
+            .iter_full()
+            .any(|event| event.backtrace == vec!["secret_crate::frame"]));
+
+        assert_eq!(stripped.thread_name(0), Some("main"));
+        assert_eq!(stripped.process_metadata()["rustc-version"], "1.70.0");
+    }
+
+    #[test]
+    fn aggregation_by_label_is_unchanged() {
+        let data = profile_with_args();
+
+        let summarize = |data: ProfilingData| {
+            let mut query_data: Vec<_> = data
+                .perform_analysis()
+                .query_data
+                .into_iter()
+                .map(|q| (q.label, q.self_time, q.time, q.invocation_count))
+                .collect();
+            query_data.sort();
+            query_data
+        };
+
+        assert_eq!(summarize(data.strip_args()), summarize(data));
+    }
+
+    #[test]
+    fn timestamps_of_long_profiles_are_kept() {
+        let path_stem = Path::new("test-tmp").join("strip_args").join("long");
+        let clock = ManualClock::default();
+        let profiler = Profiler::with_clock(&path_stem, Box::new(clock.clone())).unwrap();
+        let event_kind = profiler.alloc_string("Query");
+        let event_id = EventId::from_label(profiler.alloc_string("typeck\x1esecret"));
+
+        for &(thread_id, t) in &[
+            (0, 10),
+            (0, TIMESTAMP_EPOCH_LENGTH + 1),
+            (0, TIMESTAMP_PERIOD + 5),
+            (1, 5 * TIMESTAMP_PERIOD + 3),
+        ] {
+            clock.set(t);
+            profiler.record_instant_event(event_kind, event_id, thread_id, None);
+        }
+        clock.set(6 * TIMESTAMP_PERIOD - 20);
+        let guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+        clock.set(6 * TIMESTAMP_PERIOD + 20);
+        drop(guard);
+        drop(profiler);
+
+        let payloads = |data: &ProfilingData| -> Vec<_> {
+            data.iter_full()
+                .filter(|event| event.event_kind == "Query")
+                .map(|event| (event.thread_id, event.payload))
+                .collect()
+        };
+        let data = ProfilingData::new(&path_stem).unwrap();
+        assert_eq!(payloads(&data).len(), 5);
+        assert_eq!(payloads(&data.strip_args()), payloads(&data));
+
+        let merged = ProfilingData::merge(&[data]).unwrap();
+        let data = ProfilingData::new(&path_stem).unwrap();
+        assert_eq!(payloads(&merged), payloads(&data));
+    }
+
+    #[test]
+    fn stripped_args_are_not_written() {
+        let path_stem = Path::new("test-tmp").join("strip_args").join("stripped");
+        profile_with_args()
+            .write_with_stripped_args(&path_stem)
+            .unwrap();
+
+        let bytes = fs::read(segment_file_path(&path_stem, 0)).unwrap();
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"secret"));
+        assert!(!contains(b"other_symbol"));
+        assert!(contains(b"type_of"));
+
+        let data = ProfilingData::new(&path_stem).unwrap();
+        assert_eq!(data.num_events(), profile_with_args().num_events());
+        assert_eq!(data.process_metadata()["rustc-version"], "1.70.0");
+    }
+}
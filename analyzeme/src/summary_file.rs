@@ -0,0 +1,287 @@
+//! A compact binary encoding of [`AnalysisResults`], e.g. for CI jobs that
+//! keep the numbers of every build to compare them later, without keeping
+//! the profiles they have been computed from.
+//!
+//! A summary file starts with the 4 byte file magic `MMSU` and a 4 byte
+//! little-endian format version, like the files written by `measureme` (see
+//! `measureme::file_header`), but summaries are versioned separately from
+//! profiles. The rest of the file is, with all integers little-endian:
+//!
+//! - the total time as a `u64` of nanoseconds,
+//! - the number of items as a `u32`, followed by each item as its label, its
+//!   time, self time, cache misses, cache hits, invocation count, blocked
+//!   time, incremental load time and incremental hashing time, each as a
+//!   `u64` of nanoseconds or a count,
+//! - the number of artifact sizes as a `u32`, followed by each as its label
+//!   and its size as a `u64`,
+//! - the number of instant values as a `u32`, followed by each as its label
+//!   and its count, total and maximum as `u64`s.
+//!
+//! Labels are stored as their length in bytes as a `u32`, followed by their
+//! UTF-8 bytes. The wall times and latency percentiles of the items are not
+//! stored.
+
+use crate::{AnalysisResults, ArtifactSize, InstantValues, QueryData};
+use std::convert::TryInto;
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+
+pub const SUMMARY_FILE_EXTENSION: &str = "mm_summary";
+pub const SUMMARY_FILE_MAGIC: &[u8; 4] = b"MMSU";
+pub const CURRENT_SUMMARY_FORMAT_VERSION: u32 = 1;
+
+/// The size of the header of a summary file in bytes.
+const SUMMARY_HEADER_SIZE: usize = 8;
+
+impl AnalysisResults {
+    /// Writes the results in the format of summary files, see the
+    /// `summary_file` module. Wall times and latency percentiles are left out.
+    pub fn write_summary(&self, w: &mut dyn Write) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(SUMMARY_FILE_MAGIC);
+        bytes.extend_from_slice(&CURRENT_SUMMARY_FORMAT_VERSION.to_le_bytes());
+
+        let mut encoder = Encoder { bytes };
+        encoder.duration(self.total_time);
+
+        encoder.len(self.query_data.len())?;
+        for query_data in &self.query_data {
+            encoder.string(&query_data.label)?;
+            encoder.duration(query_data.time);
+            encoder.duration(query_data.self_time);
+            encoder.u64(query_data.number_of_cache_misses as u64);
+            encoder.u64(query_data.number_of_cache_hits as u64);
+            encoder.u64(query_data.invocation_count as u64);
+            encoder.duration(query_data.blocked_time);
+            encoder.duration(query_data.incremental_load_time);
+            encoder.duration(query_data.incremental_hashing_time);
+        }
+
+        encoder.len(self.artifact_sizes.len())?;
+        for artifact_size in &self.artifact_sizes {
+            encoder.string(&artifact_size.label)?;
+            encoder.u64(artifact_size.value);
+        }
+
+        encoder.len(self.instant_values.len())?;
+        for instant_values in &self.instant_values {
+            encoder.string(&instant_values.label)?;
+            encoder.u64(instant_values.count as u64);
+            encoder.u64(instant_values.total);
+            encoder.u64(instant_values.max);
+        }
+
+        w.write_all(&encoder.bytes)?;
+        Ok(())
+    }
+
+    /// Reads results written by [`AnalysisResults::write_summary()`]. Fails
+    /// if `bytes` isn't a summary file, if it has been written in a newer
+    /// version of the format, or if it is truncated.
+    pub fn read_summary(bytes: &[u8]) -> Result<AnalysisResults, Box<dyn Error + Send + Sync>> {
+        if bytes.len() < SUMMARY_HEADER_SIZE || &bytes[..4] != SUMMARY_FILE_MAGIC {
+            return Err(From::from(
+                "not a summary file, it doesn't start with `MMSU`",
+            ));
+        }
+
+        let version = u32::from_le_bytes(bytes[4..SUMMARY_HEADER_SIZE].try_into().unwrap());
+        if version != CURRENT_SUMMARY_FORMAT_VERSION {
+            let msg = format!(
+                "unsupported summary format version {}, expected {}",
+                version, CURRENT_SUMMARY_FORMAT_VERSION
+            );
+            return Err(From::from(msg));
+        }
+
+        let mut decoder = Decoder {
+            bytes: &bytes[SUMMARY_HEADER_SIZE..],
+        };
+        let total_time = decoder.duration()?;
+
+        let query_data = (0..decoder.u32()?)
+            .map(|_| {
+                Ok(QueryData {
+                    label: decoder.string()?,
+                    time: decoder.duration()?,
+                    self_time: decoder.duration()?,
+                    number_of_cache_misses: decoder.u64()? as usize,
+                    number_of_cache_hits: decoder.u64()? as usize,
+                    invocation_count: decoder.u64()? as usize,
+                    blocked_time: decoder.duration()?,
+                    incremental_load_time: decoder.duration()?,
+                    incremental_hashing_time: decoder.duration()?,
+                    wall_time: None,
+                    latency: None,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+
+        let artifact_sizes = (0..decoder.u32()?)
+            .map(|_| {
+                Ok(ArtifactSize {
+                    label: decoder.string()?,
+                    value: decoder.u64()?,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+
+        let instant_values = (0..decoder.u32()?)
+            .map(|_| {
+                Ok(InstantValues {
+                    label: decoder.string()?,
+                    count: decoder.u64()? as usize,
+                    total: decoder.u64()?,
+                    max: decoder.u64()?,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+
+        if !decoder.bytes.is_empty() {
+            let msg = format!(
+                "the summary file has {} unexpected bytes at its end",
+                decoder.bytes.len()
+            );
+            return Err(From::from(msg));
+        }
+
+        Ok(AnalysisResults {
+            query_data,
+            artifact_sizes,
+            instant_values,
+            total_time,
+        })
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn duration(&mut self, duration: Duration) {
+        self.u64(duration.as_nanos() as u64);
+    }
+
+    fn len(&mut self, len: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let len: u32 = len
+            .try_into()
+            .map_err(|_| format!("{} entries don't fit into a summary file", len))?;
+        self.bytes.extend_from_slice(&len.to_le_bytes());
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.len(s.len())?;
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+        if self.bytes.len() < len {
+            return Err(From::from("the summary file is truncated"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error + Send + Sync>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn duration(&mut self) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+        Ok(Duration::from_nanos(self.u64()?))
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| From::from("the summary file contains a label that isn't valid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    fn summary_bytes(results: &AnalysisResults) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        results.write_summary(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 100, |b| {
+            b.interval("Query", "type_of", 0, 10, 30, |_| {});
+            b.integer("ArtifactSize", "object_file", 0, 1024);
+        });
+        b.interval("Query", "typeck", 1, 0, 50, |_| {});
+        b.instant_with_value("Allocation", "bytes", 0, 40, 64);
+        let results = b.into_profiling_data().perform_analysis_with_percentiles();
+
+        let bytes = summary_bytes(&results);
+        assert_eq!(&bytes[..4], SUMMARY_FILE_MAGIC);
+        let read = AnalysisResults::read_summary(&bytes).unwrap();
+
+        assert_eq!(read.total_time, results.total_time);
+        assert_eq!(read.query_data.len(), results.query_data.len());
+        for (read, written) in read.query_data.iter().zip(&results.query_data) {
+            assert!(written.latency.is_some());
+            let written = QueryData {
+                latency: None,
+                ..written.clone()
+            };
+            assert_eq!(format!("{:?}", read), format!("{:?}", written));
+        }
+        assert_eq!(
+            format!("{:?}", read.artifact_sizes),
+            format!("{:?}", results.artifact_sizes)
+        );
+        assert_eq!(read.instant_values, results.instant_values);
+    }
+
+    #[test]
+    fn invalid_summaries() {
+        let bytes = summary_bytes(
+            &ProfilingDataBuilder::new()
+                .into_profiling_data()
+                .perform_analysis(),
+        );
+        assert!(AnalysisResults::read_summary(&bytes).is_ok());
+
+        let error = |bytes: &[u8]| {
+            AnalysisResults::read_summary(bytes)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(error(b"MMPD\x08\0\0\0").contains("not a summary file"));
+        assert!(error(&bytes[..bytes.len() - 1]).contains("truncated"));
+        assert!(error(&[&bytes[..], &[0]].concat()).contains("unexpected bytes"));
+
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(error(&newer).contains("unsupported summary format version 2"));
+    }
+}
@@ -0,0 +1,254 @@
+//! A streaming quantile estimator (a "merging t-digest", see Dunning & Ertl,
+//! "Computing Extremely Accurate Quantiles Using t-Digests").
+//!
+//! A `TDigest` summarizes any number of values in a bounded amount of memory
+//! by clustering them into weighted centroids. Centroids near the tails of the
+//! distribution are kept small, so that high percentiles like p99 stay
+//! accurate even for very skewed distributions.
+
+use std::cmp::Ordering;
+
+/// Controls the trade-off between accuracy and size. The digest retains at
+/// most a small multiple of this many centroids.
+const COMPRESSION: f64 = 100.0;
+
+/// The number of values that are buffered before they are merged into the
+/// centroids.
+const BUFFER_SIZE: usize = 5 * COMPRESSION as usize;
+
+#[derive(Clone, Copy, Debug)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TDigest {
+    // Sorted by mean.
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub(crate) fn new() -> TDigest {
+        TDigest {
+            centroids: Vec::new(),
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub(crate) fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.merge_buffer();
+        }
+    }
+
+    /// Adds all values summarized by `other`, e.g. to combine the digests of
+    /// parts of the data that have been processed separately.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn merge(&mut self, mut other: TDigest) {
+        other.merge_buffer();
+
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.merge_centroids(other.centroids);
+    }
+
+    /// The largest value added so far. This is exact.
+    pub(crate) fn max(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    /// Estimates the value below which the fraction `q` of all values lies.
+    pub(crate) fn quantile(&mut self, q: f64) -> Option<f64> {
+        assert!((0.0..=1.0).contains(&q));
+
+        self.merge_buffer();
+
+        let (first, last) = match (self.centroids.first(), self.centroids.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return None,
+        };
+
+        let total_weight = self.count as f64;
+        let target = q * total_weight;
+
+        // Each centroid is assumed to be centered at its mean, with half of
+        // its weight on either side. Between the centers of two adjacent
+        // centroids, and between the outermost centroids and the extremes,
+        // values are interpolated linearly.
+        if target < first.weight / 2.0 {
+            return Some(lerp(self.min, first.mean, target / (first.weight / 2.0)));
+        }
+
+        let mut weight_so_far = 0.0;
+        for pair in self.centroids.windows(2) {
+            let left_center = weight_so_far + pair[0].weight / 2.0;
+            let right_center = weight_so_far + pair[0].weight + pair[1].weight / 2.0;
+
+            if target < right_center {
+                let t = (target - left_center) / (right_center - left_center);
+                return Some(lerp(pair[0].mean, pair[1].mean, t));
+            }
+
+            weight_so_far += pair[0].weight;
+        }
+
+        let last_center = total_weight - last.weight / 2.0;
+        let t = (target - last_center) / (last.weight / 2.0);
+        Some(lerp(last.mean, self.max, t.min(1.0)))
+    }
+
+    fn merge_buffer(&mut self) {
+        self.merge_centroids(Vec::new());
+    }
+
+    /// Merges the buffered values and `additional` centroids into the
+    /// centroids. `count` must already include their weight.
+    fn merge_centroids(&mut self, additional: Vec<Centroid>) {
+        if self.buffer.is_empty() && additional.is_empty() {
+            return;
+        }
+
+        let mut centroids = std::mem::take(&mut self.centroids);
+        centroids.extend(additional);
+        centroids.extend(self.buffer.drain(..).map(|value| Centroid {
+            mean: value,
+            weight: 1.0,
+        }));
+        centroids.sort_by(|l, r| l.mean.partial_cmp(&r.mean).unwrap_or(Ordering::Equal));
+
+        let total_weight = self.count as f64;
+        let mut merged = Vec::with_capacity(centroids.len());
+        let mut weight_so_far = 0.0;
+        let mut current = centroids[0];
+
+        for next in centroids.into_iter().skip(1) {
+            let q_left = weight_so_far / total_weight;
+            let q_right = (weight_so_far + current.weight + next.weight) / total_weight;
+
+            // A centroid may only cover a range of quantiles that amounts to
+            // at most 1 on the k-scale, which is very flat in the middle of
+            // the distribution and steep at the tails.
+            if k_scale(q_right) - k_scale(q_left) <= 1.0 {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                weight_so_far += current.weight;
+                merged.push(current);
+                current = next;
+            }
+        }
+
+        merged.push(current);
+        self.centroids = merged;
+    }
+}
+
+fn k_scale(q: f64) -> f64 {
+    COMPRESSION / (2.0 * std::f64::consts::PI) * (2.0 * q.min(1.0) - 1.0).asin()
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let mut digest = TDigest::new();
+        assert_eq!(digest.quantile(0.5), None);
+        assert_eq!(digest.max(), None);
+    }
+
+    #[test]
+    fn single_value() {
+        let mut digest = TDigest::new();
+        digest.add(42.0);
+
+        assert_eq!(digest.quantile(0.0), Some(42.0));
+        assert_eq!(digest.quantile(0.5), Some(42.0));
+        assert_eq!(digest.quantile(1.0), Some(42.0));
+        assert_eq!(digest.max(), Some(42.0));
+    }
+
+    #[test]
+    fn uniform_distribution() {
+        let mut digest = TDigest::new();
+
+        // Add the values in a scrambled order.
+        for i in 0..100_000u64 {
+            digest.add(((i * 7_919) % 100_000) as f64);
+        }
+
+        assert!(digest.centroids.len() + digest.buffer.len() < 10 * COMPRESSION as usize);
+
+        let assert_close = |actual: f64, expected: f64| {
+            assert!(
+                (actual - expected).abs() < 500.0,
+                "expected {} but got {}",
+                expected,
+                actual
+            );
+        };
+
+        assert_close(digest.quantile(0.5).unwrap(), 50_000.0);
+        assert_close(digest.quantile(0.9).unwrap(), 90_000.0);
+        assert_close(digest.quantile(0.99).unwrap(), 99_000.0);
+        assert_eq!(digest.max(), Some(99_999.0));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn merged_digests() {
+        let mut digests: Vec<_> = (0..4).map(|_| TDigest::new()).collect();
+        for i in 0..100_000u64 {
+            digests[(i % 4) as usize].add(((i * 7_919) % 100_000) as f64);
+        }
+
+        let mut merged = TDigest::new();
+        for digest in digests {
+            merged.merge(digest);
+        }
+        merged.merge(TDigest::new());
+
+        assert_eq!(merged.count, 100_000);
+        assert!((merged.quantile(0.5).unwrap() - 50_000.0).abs() < 500.0);
+        assert!((merged.quantile(0.99).unwrap() - 99_000.0).abs() < 500.0);
+        assert_eq!(merged.max(), Some(99_999.0));
+    }
+
+    #[test]
+    fn outlier_shows_up_in_max_only() {
+        let mut digest = TDigest::new();
+
+        for _ in 0..10_000 {
+            digest.add(1.0);
+        }
+        digest.add(5_000.0);
+
+        assert_eq!(digest.quantile(0.5), Some(1.0));
+        assert_eq!(digest.quantile(0.99), Some(1.0));
+        assert_eq!(digest.max(), Some(5_000.0));
+    }
+}
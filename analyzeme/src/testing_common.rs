@@ -1,11 +1,31 @@
-use crate::{Event, EventPayload, ProfilingData, Timestamp};
-use measureme::{EventId, EventIdBuilder, Profiler, StringId};
+use crate::{
+    CallTreeNode, Event, EventPayload, LightweightEvent, ProfilingData, Timestamp,
+    ValidationErrorKind,
+};
+use decodeme::event_stream::EventStream;
+use measureme::counters::{Counter, WallTime};
+use measureme::event_id::{escape_bytes, DEFAULT_MAX_ARGS};
+use measureme::file_header::{
+    segment_file_path, write_file_header, FILE_EXTENSION, FILE_HEADER_SIZE,
+    FILE_MAGIC_TIMESTAMP_EPOCH_INDEX,
+};
+use measureme::rustc::{METADATA_KEY_OPT_LEVEL, METADATA_KEY_RUSTC_VERSION};
+use measureme::testing_clocks::{FailingClock, ManualClock, SteppingClock};
+use measureme::{
+    decode_timestamp_epoch_index, iter_pages, split_streams, write_page_to, EventId,
+    EventIdBuilder, InMemorySink, PageTag, Profiler, ProfilerOptions, RingBufferSink, SpillingSink,
+    StringId, TraceContext, MAX_INTERVAL_VALUE, TIMESTAMP_EPOCH_EVENT_KIND, TIMESTAMP_EPOCH_LENGTH,
+    TIMESTAMP_PERIOD, TRACE_CONTEXT_PRELUDE_SIZE,
+};
 use rustc_hash::FxHashMap;
 use std::borrow::Cow;
 use std::default::Default;
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 fn mk_filestem(file_name_stem: &str) -> PathBuf {
     let mut path = PathBuf::new();
@@ -21,7 +41,9 @@ fn mk_filestem(file_name_stem: &str) -> PathBuf {
 struct ExpectedEvent {
     kind: Cow<'static, str>,
     label: Cow<'static, str>,
+    category: Option<Cow<'static, str>>,
     args: Vec<Cow<'static, str>>,
+    integer_args: Vec<(usize, u64)>,
 }
 
 impl ExpectedEvent {
@@ -29,9 +51,22 @@ impl ExpectedEvent {
         ExpectedEvent {
             kind: Cow::from(kind),
             label: Cow::from(label),
+            category: None,
             args: args.iter().map(|&x| Cow::from(x)).collect(),
+            integer_args: Vec::new(),
         }
     }
+
+    fn with_integer_arg(mut self, value: u64) -> ExpectedEvent {
+        self.integer_args.push((self.args.len(), value));
+        self.args.push(Cow::from(value.to_string()));
+        self
+    }
+
+    fn with_category(mut self, category: &'static str) -> ExpectedEvent {
+        self.category = Some(Cow::from(category));
+        self
+    }
 }
 
 // Generate some profiling data. This is the part that would run in rustc.
@@ -39,8 +74,22 @@ fn generate_profiling_data(
     filestem: &Path,
     num_stacks: usize,
     num_threads: usize,
+    options: ProfilerOptions,
 ) -> Vec<Event<'static>> {
-    let profiler = Arc::new(Profiler::new(Path::new(filestem)).unwrap());
+    let profiler = Profiler::with_options(
+        Path::new(filestem),
+        Counter::WallTime(WallTime::new()),
+        options,
+    )
+    .unwrap();
+
+    record_events(profiler, num_stacks, num_threads)
+}
+
+// Records the events of `generate_profiling_data` with `profiler` and drops it
+// afterwards.
+fn record_events(profiler: Profiler, num_stacks: usize, num_threads: usize) -> Vec<Event<'static>> {
+    let profiler = Arc::new(profiler);
 
     let event_id_virtual = EventId::from_label(StringId::new_virtual(42));
     let event_id_builder = EventIdBuilder::new(&profiler);
@@ -58,6 +107,19 @@ fn generate_profiling_data(
                 profiler.alloc_string("some_arg"),
             ),
         ),
+        (
+            profiler.alloc_string("QueryWithCategory"),
+            event_id_builder.from_label_category_and_args(
+                profiler.alloc_string("AQueryWithCategory"),
+                profiler.alloc_string("some_category"),
+                &[profiler.alloc_string("some_arg")],
+            ),
+        ),
+        (
+            profiler.alloc_string("QueryWithIntArg"),
+            event_id_builder
+                .from_label_and_int_arg(profiler.alloc_string("AQueryWithIntArg"), 1234567890),
+        ),
     ];
 
     // This and event_ids have to match!
@@ -65,6 +127,9 @@ fn generate_profiling_data(
         ExpectedEvent::new("Generic", "SomeGenericActivity", &[]),
         ExpectedEvent::new("Query", "SomeQuery", &[]),
         ExpectedEvent::new("QueryWithArg", "AQueryWithArg", &["some_arg"]),
+        ExpectedEvent::new("QueryWithCategory", "AQueryWithCategory", &["some_arg"])
+            .with_category("some_category"),
+        ExpectedEvent::new("QueryWithIntArg", "AQueryWithIntArg", &[]).with_integer_arg(1234567890),
     ];
 
     let threads: Vec<_> = (0..num_threads)
@@ -115,7 +180,13 @@ fn generate_profiling_data(
 // Process some profiling data. This is the part that would run in a
 // post processing tool.
 fn process_profiling_data(filestem: &Path, expected_events: &[Event<'static>]) {
-    let profiling_data = ProfilingData::new(filestem).unwrap();
+    check_recorded_events(&ProfilingData::new(filestem).unwrap(), expected_events);
+}
+
+fn check_recorded_events(profiling_data: &ProfilingData, expected_events: &[Event<'static>]) {
+    let counter = profiling_data.metadata().counter.as_ref().unwrap();
+    assert_eq!(counter.name, "wall-time");
+    assert!(counter.measures_time());
 
     // Check iterating forward over the events
     check_profiling_data(
@@ -162,7 +233,9 @@ fn check_profiling_data(
         for (actual_event, expected_event) in actual_events.iter().zip(expected_events.iter()) {
             assert_eq!(actual_event.event_kind, expected_event.event_kind);
             assert_eq!(actual_event.label, expected_event.label);
+            assert_eq!(actual_event.category, expected_event.category);
             assert_eq!(actual_event.additional_data, expected_event.additional_data);
+            assert_eq!(actual_event.integer_args, expected_event.integer_args);
             assert_eq!(
                 actual_event.payload.is_interval(),
                 expected_event.payload.is_interval()
@@ -200,120 +273,2160 @@ fn collect_events_per_thread<'a>(
 
 pub fn run_serialization_bench(file_name_stem: &str, num_events: usize, num_threads: usize) {
     let filestem = mk_filestem(file_name_stem);
-    generate_profiling_data(&filestem, num_events, num_threads);
+    generate_profiling_data(&filestem, num_events, num_threads, Default::default());
 }
 
-pub fn run_end_to_end_serialization_test(file_name_stem: &str, num_threads: usize) {
+pub fn run_serialization_bench_with_page_size(
+    file_name_stem: &str,
+    num_events: usize,
+    num_threads: usize,
+    page_size: usize,
+) {
     let filestem = mk_filestem(file_name_stem);
-    let expected_events = generate_profiling_data(&filestem, 10_000, num_threads);
-    process_profiling_data(&filestem, &expected_events);
+    let options = ProfilerOptions {
+        page_size: Some(page_size),
+        ..Default::default()
+    };
+    generate_profiling_data(&filestem, num_events, num_threads, options);
 }
 
-fn pseudo_invocation(
-    profiler: &Profiler,
-    random: usize,
-    thread_id: u32,
-    recursions_left: usize,
-    event_ids: &[(StringId, EventId)],
-    expected_events_templates: &[ExpectedEvent],
-    expected_events: &mut Vec<Event<'static>>,
-) {
-    if recursions_left == 0 {
-        return;
+// Records one query event per iteration, with an argument drawn from a set of
+// `num_distinct_args` strings, like rustc does for the arguments of its
+// queries. Returns the size of the resulting profile.
+fn record_repeated_query_args(
+    filestem: &Path,
+    num_events: usize,
+    num_distinct_args: usize,
+    deduplicate_strings: bool,
+) -> u64 {
+    let options = ProfilerOptions {
+        deduplicate_strings,
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+
+    let event_kind = profiler.alloc_string("Query");
+    let label = profiler.alloc_string("resolve_instance");
+    let event_id_builder = EventIdBuilder::new(&profiler);
+
+    for i in 0..num_events {
+        let arg = format!(
+            "rustc_middle::ty::Instance::<T{}>::resolve",
+            i % num_distinct_args
+        );
+        let event_id = event_id_builder.from_label_and_arg(label, profiler.alloc_string(&arg[..]));
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
     }
 
-    let random_event_index = random % event_ids.len();
+    drop(profiler);
+    fs::metadata(filestem.with_extension(FILE_EXTENSION))
+        .unwrap()
+        .len()
+}
 
-    let (event_kind, event_id) = event_ids[random_event_index];
+pub fn run_string_deduplication_bench(
+    file_name_stem: &str,
+    num_events: usize,
+    deduplicate_strings: bool,
+) {
+    let filestem = mk_filestem(file_name_stem);
+    record_repeated_query_args(&filestem, num_events, 500, deduplicate_strings);
+}
 
-    let _prof_guard = profiler.start_recording_interval_event(event_kind, event_id, thread_id);
+/// Records a profile of `num_events` query events with distinct arguments,
+/// for benchmarking how fast profiles are loaded and their events decoded,
+/// e.g. by `summarize`. Returns the path stem of the profile.
+pub fn record_query_args_profile(file_name_stem: &str, num_events: usize) -> PathBuf {
+    let filestem = mk_filestem(file_name_stem);
+    record_repeated_query_args(&filestem, num_events, num_events, false);
+    filestem
+}
 
-    pseudo_integer_event(
-        profiler,
-        random * 7,
-        thread_id,
-        event_ids,
-        expected_events_templates,
-        expected_events,
+/// Opens a profile recorded by `record_query_args_profile`.
+pub fn open_query_args_profile(file_name_stem: &str, num_events: usize) -> ProfilingData {
+    ProfilingData::new(&record_query_args_profile(file_name_stem, num_events)).unwrap()
+}
+
+/// Checks that deduplicating strings shrinks profiles in which the same
+/// strings are allocated over and over, without changing their events.
+pub fn run_string_deduplication_test(file_name_stem: &str) {
+    let labels_and_args = |filestem: &Path| -> Vec<(String, Vec<String>)> {
+        let data = ProfilingData::new(filestem).unwrap();
+        data.iter_full()
+            .map(|event| {
+                let args = event.additional_data.iter().map(|a| a.to_string());
+                (event.label.into_owned(), args.collect())
+            })
+            .collect()
+    };
+
+    let filestem = mk_filestem(file_name_stem);
+    let size = record_repeated_query_args(&filestem, 10_000, 100, false);
+
+    let deduplicated_filestem = mk_filestem(&format!("{}_deduplicated", file_name_stem));
+    let deduplicated_size = record_repeated_query_args(&deduplicated_filestem, 10_000, 100, true);
+
+    assert!(
+        deduplicated_size * 2 < size,
+        "deduplicated: {} bytes, not deduplicated: {} bytes",
+        deduplicated_size,
+        size
+    );
+    assert_eq!(
+        labels_and_args(&deduplicated_filestem),
+        labels_and_args(&filestem)
     );
+}
 
-    pseudo_invocation(
-        profiler,
-        random * 17,
-        thread_id,
-        recursions_left - 1,
-        event_ids,
-        expected_events_templates,
-        expected_events,
+/// Checks that labels and arguments that aren't valid UTF-8, such as paths
+/// on Unix systems can be, are decoded lossily and that their actual bytes
+/// can be read back.
+pub fn run_non_utf8_label_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let paths: &[&[u8]] = &[b"src/caf\xE9.rs", b"\xFF\xFE/lib.rs", b"plain.rs", b"\xC3"];
+
+    {
+        let profiler = Profiler::new(&filestem).unwrap();
+        let event_kind = profiler.alloc_string("OpenFile");
+        let builder = EventIdBuilder::new(&profiler);
+        let dir = profiler.alloc_string_bytes(&[b"dir/\xFE\xFF"]).unwrap();
+
+        for (thread_id, path) in paths.iter().enumerate() {
+            let label = profiler.alloc_string_bytes(&[b"open ", path]).unwrap();
+            let event_id = builder.from_label_and_arg(label, dir);
+            profiler.record_instant_event(event_kind, event_id, thread_id as u32, None);
+        }
+
+        // Separators have to be escaped.
+        assert!(profiler.alloc_string_bytes(&[b"a", b"\x1Eb"]).is_err());
+        let label = profiler
+            .alloc_string_bytes(&[&escape_bytes(b"a\x1E\xFEb")])
+            .unwrap();
+        let event_id = EventId::from_label(label);
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let mut events: Vec<_> = data.iter().collect();
+    let escaped = events.pop().unwrap();
+    assert_eq!(&data.label_bytes(&escaped)[..], b"a\x1E\xFEb");
+    assert_eq!(events.len(), paths.len());
+
+    for (event, path) in events.iter().zip(paths) {
+        let label = [&b"open "[..], path].concat();
+        assert_eq!(&data.label_bytes(event)[..], &label[..]);
+
+        let event_id = [&label[..], b"\x1Edir/\xFE\xFF"].concat();
+        assert_eq!(&data.event_id_bytes(event)[..], &event_id[..]);
+
+        let full_event = data.to_full_event(event);
+        assert_eq!(full_event.label, String::from_utf8_lossy(&label));
+        assert_eq!(
+            full_event.additional_data,
+            vec![Cow::from("dir/\u{FFFD}\u{FFFD}")]
+        );
+    }
+}
+
+/// Checks that event ids created with more arguments than
+/// `EventIdBuilder::with_max_args` allows keep the first arguments and that
+/// readers can tell how many have been left out.
+pub fn run_omitted_args_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let profiler = Profiler::new(&filestem).unwrap();
+
+    let event_kind = profiler.alloc_string("Query");
+    let label = profiler.alloc_string("typeck");
+    let args: Vec<_> = (0..1000)
+        .map(|i| profiler.alloc_string(&format!("arg{}", i)[..]))
+        .collect();
+
+    let builder = EventIdBuilder::new(&profiler);
+    let capped_builder = EventIdBuilder::new(&profiler).with_max_args(2);
+    for &event_id in &[
+        builder.from_label_and_args(label, &args[..3]),
+        builder.from_label_and_args(label, &args),
+        capped_builder.from_label_and_args(label, &args[..3]),
+    ] {
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+    }
+    drop(profiler);
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let events: Vec<_> = data
+        .iter()
+        .map(|event| {
+            let full_event = data.to_full_event(&event);
+            let last_arg = full_event.additional_data.last().unwrap().to_string();
+            (
+                full_event.additional_data.len(),
+                last_arg,
+                data.omitted_args(&event),
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        events,
+        vec![
+            (3, "arg2".to_string(), 0),
+            (
+                DEFAULT_MAX_ARGS,
+                format!("arg{}", DEFAULT_MAX_ARGS - 1),
+                744
+            ),
+            (2, "arg1".to_string(), 1),
+        ]
     );
 
-    pseudo_instant_event(
-        profiler,
-        random * 23,
-        thread_id,
-        event_ids,
-        expected_events_templates,
-        expected_events,
+    // Copies of the profile keep the number of omitted arguments.
+    let omitted_args =
+        |data: &ProfilingData| -> Vec<_> { data.iter().map(|e| data.omitted_args(&e)).collect() };
+    let merged = ProfilingData::merge(std::slice::from_ref(&data)).unwrap();
+    assert_eq!(omitted_args(&merged), [0, 744, 1]);
+    assert_eq!(omitted_args(&data.strip_args()), [0, 744, 1]);
+}
+
+/// Checks that labels, categories and arguments allocated with
+/// `EventIdBuilder::alloc_text` and `alloc_arg` are read back unchanged, even
+/// if they contain the separator or tag bytes of event ids.
+pub fn run_escaped_text_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let label = "label\x1Ewith separator";
+    let category = "\x12category";
+    let args = ["\x13123", "arg\x1E\x12", "\x1B", "plain"];
+
+    {
+        let profiler = Profiler::new(&filestem).unwrap();
+        let event_kind = profiler.alloc_string("Query");
+        let builder = EventIdBuilder::new(&profiler);
+
+        let arg_ids: Vec<_> = args.iter().map(|arg| builder.alloc_arg(arg)).collect();
+        let event_id = builder.from_label_category_and_args(
+            builder.alloc_text(label),
+            builder.alloc_text(category),
+            &arg_ids,
+        );
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+
+        let event_id = builder.from_label_and_int_arg(builder.alloc_text(label), 42);
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+
+        let event_id = builder.from_text_and_args(label, &args);
+        profiler.record_instant_event(event_kind, event_id, 2, None);
+
+        let event_id = builder.from_text_and_arg(label, args[1]);
+        profiler.record_instant_event(event_kind, event_id, 3, None);
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let events: Vec<_> = data.iter().collect();
+    assert_eq!(events.len(), 4);
+
+    let full_event = data.to_full_event(&events[0]);
+    assert_eq!(full_event.label, label);
+    assert_eq!(full_event.category, Some(Cow::from(category)));
+    assert_eq!(full_event.additional_data, args);
+    assert!(full_event.integer_args.is_empty());
+    assert_eq!(&data.label_bytes(&events[0])[..], label.as_bytes());
+
+    let full_event = data.to_full_event(&events[1]);
+    assert_eq!(full_event.label, label);
+    assert_eq!(full_event.additional_data, vec![Cow::from("42")]);
+    assert_eq!(full_event.integer_arg(0), Some(42));
+
+    let full_event = data.to_full_event(&events[2]);
+    assert_eq!(full_event.label, label);
+    assert_eq!(full_event.additional_data, args);
+
+    let full_event = data.to_full_event(&events[3]);
+    assert_eq!(full_event.label, label);
+    assert_eq!(full_event.additional_data, vec![Cow::from(args[1])]);
+
+    // Merging reassembles the event ids from the decoded events.
+    let merged = ProfilingData::merge(&[data]).unwrap();
+    assert_eq!(
+        merged.iter_full().collect::<Vec<_>>()[0].additional_data,
+        args
     );
+}
 
-    expected_events.push(Event {
-        event_kind: expected_events_templates[random_event_index].kind.clone(),
-        label: expected_events_templates[random_event_index].label.clone(),
-        additional_data: expected_events_templates[random_event_index].args.clone(),
-        thread_id,
-        // We can't test the actual timestamp value, so we just assign
-        // SystemTime::UNIX_EPOCH to everything.
-        payload: EventPayload::Timestamp(Timestamp::Interval {
-            start: SystemTime::UNIX_EPOCH,
-            end: SystemTime::UNIX_EPOCH,
-        }),
-    });
+/// Checks that events of kinds other than those that rustc uses are read back
+/// with their kind, and that the analysis treats them like generic
+/// activities.
+pub fn run_custom_event_kind_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    {
+        let profiler = Profiler::new(&filestem).unwrap();
+        let render = profiler.alloc_event_kind("Render");
+        assert_eq!(profiler.alloc_event_kind("Render"), render);
+        let layout = profiler.alloc_event_kind("Layout");
+        assert_ne!(layout, render);
+
+        let frame = EventId::from_label(profiler.alloc_string("frame"));
+        let paragraph = EventId::from_label(profiler.alloc_string("paragraph"));
+
+        for _ in 0..3 {
+            let _frame = profiler.start_recording_interval_event(render, frame, 0);
+            let layout = profiler.alloc_event_kind("Layout");
+            let _paragraph = profiler.start_recording_interval_event(layout, paragraph, 0);
+        }
+        profiler.record_instant_event(profiler.alloc_event_kind("Vsync"), frame, 0, None);
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let kinds: Vec<_> = data
+        .iter_full()
+        .map(|event| (event.event_kind.into_owned(), event.label.into_owned()))
+        .collect();
+    assert_eq!(kinds.len(), 7);
+    assert_eq!(kinds[0], ("Layout".to_string(), "paragraph".to_string()));
+    assert_eq!(kinds[1], ("Render".to_string(), "frame".to_string()));
+    assert_eq!(kinds[6], ("Vsync".to_string(), "frame".to_string()));
+
+    let results = data.perform_analysis();
+    let counts = |label: &str| {
+        let query_data = results.query_data.iter().find(|q| q.label == label);
+        let query_data = query_data.unwrap();
+        (
+            query_data.invocation_count,
+            query_data.number_of_cache_misses,
+        )
+    };
+    assert_eq!(counts("frame"), (3, 0));
+    assert_eq!(counts("paragraph"), (3, 0));
 }
 
-fn pseudo_integer_event(
+fn record_nested_intervals(
     profiler: &Profiler,
-    random: usize,
+    event_kind: StringId,
+    event_id: EventId,
     thread_id: u32,
-    event_ids: &[(StringId, EventId)],
-    expected_events_templates: &[ExpectedEvent],
-    expected_events: &mut Vec<Event<'static>>,
+    depth: u32,
 ) {
-    let random_event_index = random % event_ids.len();
+    let _guard = profiler.start_recording_interval_event(event_kind, event_id, thread_id);
 
-    let payload_value = random as u64 * 33;
+    if depth < 6 {
+        for _ in 0..(depth + thread_id) % 3 + 1 {
+            record_nested_intervals(profiler, event_kind, event_id, thread_id, depth + 1);
+        }
+    }
 
-    let (event_kind, event_id) = event_ids[random_event_index];
-    profiler.record_integer_event(event_kind, event_id, thread_id, payload_value);
+    profiler.record_instant_event(event_kind, event_id, thread_id, None);
+}
 
-    expected_events.push(Event {
-        event_kind: expected_events_templates[random_event_index].kind.clone(),
-        label: expected_events_templates[random_event_index].label.clone(),
-        additional_data: expected_events_templates[random_event_index].args.clone(),
-        thread_id,
-        payload: EventPayload::Integer(payload_value),
-    });
+/// Checks that the nesting depths recorded with
+/// `ProfilerOptions::record_nesting_depth` match the nesting that can be
+/// reconstructed from the timestamps.
+pub fn run_nesting_depth_test(file_name_stem: &str, num_threads: u32) {
+    fn collect_depths(node: &CallTreeNode, depth: u32, depths: &mut FxHashMap<usize, u32>) {
+        for child in &node.children {
+            depths.insert(child.event_index.unwrap(), depth);
+            collect_depths(child, depth + 1, depths);
+        }
+    }
+
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        record_nesting_depth: true,
+        ..Default::default()
+    };
+    // Every read of this clock returns a new timestamp, so that the nesting
+    // can be reconstructed unambiguously.
+    let clock = Counter::Clock(Box::new(SteppingClock::default()));
+    let profiler = Arc::new(Profiler::with_options(&filestem, clock, options).unwrap());
+
+    let threads: Vec<_> = (0..num_threads)
+        .map(|thread_id| {
+            let profiler = profiler.clone();
+            std::thread::spawn(move || {
+                let event_kind = profiler.alloc_string("Query");
+                let event_id = EventId::from_label(profiler.alloc_string("nested"));
+                for _ in 0..10 {
+                    record_nested_intervals(&profiler, event_kind, event_id, thread_id, 0);
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+
+    let mut expected_depths = FxHashMap::default();
+    for thread_id in 0..num_threads {
+        collect_depths(
+            &profiling_data.call_tree(thread_id),
+            0,
+            &mut expected_depths,
+        );
+    }
+
+    let mut max_depth = 0;
+    for event in profiling_data.iter() {
+        let full_event = profiling_data.to_full_event(&event);
+        assert!(event.thread_id < num_threads);
+
+        if event.payload.is_interval() {
+            let depth = full_event.depth.unwrap();
+            assert_eq!(Some(&depth), expected_depths.get(&event.event_index));
+            max_depth = std::cmp::max(max_depth, depth);
+        } else {
+            assert_eq!(full_event.depth, None);
+        }
+    }
+
+    assert_eq!(max_depth, 6);
+    assert_eq!(
+        expected_depths.len(),
+        profiling_data
+            .iter()
+            .filter(|e| e.payload.is_interval())
+            .count()
+    );
 }
 
-fn pseudo_instant_event(
-    profiler: &Profiler,
-    random: usize,
-    thread_id: u32,
-    event_ids: &[(StringId, EventId)],
-    expected_events_templates: &[ExpectedEvent],
-    expected_events: &mut Vec<Event<'static>>,
-) {
-    let random_event_index = random % event_ids.len();
+/// Checks that the call tree of interleaved async tasks is reconstructed from
+/// the explicit parents recorded with
+/// `Profiler::start_recording_interval_event_with_parent`, also together with
+/// nesting depths and a stable event order.
+pub fn run_explicit_parents_test(file_name_stem: &str, stable_event_order: bool) {
+    fn render(node: &CallTreeNode) -> String {
+        let children: Vec<_> = node.children.iter().map(render).collect();
+        format!("{}[{}]", node.label, children.join(","))
+    }
 
-    let (event_kind, event_id) = event_ids[random_event_index];
-    profiler.record_instant_event(event_kind, event_id, thread_id);
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        record_explicit_parents: true,
+        record_nesting_depth: true,
+        stable_event_order,
+        ..Default::default()
+    };
+    let clock = Counter::Clock(Box::new(SteppingClock::default()));
 
-    expected_events.push(Event {
-        event_kind: expected_events_templates[random_event_index].kind.clone(),
-        label: expected_events_templates[random_event_index].label.clone(),
-        additional_data: expected_events_templates[random_event_index].args.clone(),
-        thread_id,
-        // We can't test the actual timestamp value, so we just assign
-        // SystemTime::UNIX_EPOCH to everything.
-        payload: EventPayload::Timestamp(Timestamp::Instant(SystemTime::UNIX_EPOCH)),
-    });
+    {
+        let profiler = Profiler::with_options(&filestem, clock, options).unwrap();
+        let task = profiler.alloc_event_kind("Task");
+        let poll = profiler.alloc_event_kind("Poll");
+        let task_a = EventId::from_label(profiler.alloc_string("task_a"));
+        let task_b = EventId::from_label(profiler.alloc_string("task_b"));
+        let poll_a = EventId::from_label(profiler.alloc_string("poll_a"));
+        let poll_b = EventId::from_label(profiler.alloc_string("poll_b"));
+
+        let a = profiler.start_recording_interval_event_detached(task, task_a, 1);
+        let b = profiler.start_recording_interval_event_detached(task, task_b, 1);
+        for _ in 0..2 {
+            for &(event_id, parent) in &[(poll_a, task_a), (poll_b, task_b)] {
+                let _poll =
+                    profiler.start_recording_interval_event_with_parent(poll, event_id, parent, 1);
+                // Events nested in a fragment are still nested by their
+                // timestamps.
+                profiler.record_instant_event(poll, event_id, 1, None);
+                let _inner = profiler.start_recording_interval_event(poll, event_id, 1);
+            }
+        }
+        profiler.finish_recording_interval_event(b);
+        profiler.finish_recording_interval_event(a);
+
+        // A parent that isn't among the events of the thread is ignored.
+        let _other = profiler.start_recording_interval_event_with_parent(task, task_b, task_a, 2);
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    assert!(data.has_explicit_parents());
+
+    let parents: Vec<_> = data
+        .iter()
+        .filter(|event| event.thread_id == 1)
+        .filter_map(|event| {
+            data.explicit_parent_id_bytes(&event)
+                .map(|p| p.into_owned())
+        })
+        .collect();
+    assert_eq!(
+        parents,
+        vec![&b"task_a"[..], b"task_b", b"task_a", b"task_b"]
+    );
+
+    assert_eq!(
+        render(&data.call_tree(1)),
+        "[task_a[poll_a[poll_a[]],poll_a[poll_a[]],task_b[poll_b[poll_b[]],poll_b[poll_b[]]]]]"
+    );
+    assert_eq!(render(&data.call_tree(2)), "[task_b[]]");
+}
+
+/// Checks that the complete pages of a profile whose last page is incomplete
+/// or corrupt, e.g. because the profiled process crashed while writing it, can
+/// still be read.
+pub fn run_truncated_file_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        page_size: Some(1024),
+        ..Default::default()
+    };
+    let clock = Counter::Clock(Box::new(SteppingClock::default()));
+    let profiler = Profiler::with_options(&filestem, clock, options).unwrap();
+
+    // Like rustc, use virtual event ids, so that the index of the string table
+    // is written along with the other streams.
+    let event_kind = profiler.alloc_string("Query");
+    for i in 0..1_000 {
+        let virtual_id = StringId::new_virtual(i);
+        let label = profiler.alloc_string(&format!("query_{}", i)[..]);
+        profiler.map_virtual_to_concrete_string(virtual_id, label);
+
+        let event_id = EventId::from_virtual(virtual_id);
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+    }
+    drop(profiler);
+
+    let data = fs::read(filestem.with_extension(FILE_EXTENSION)).unwrap();
+    let complete = ProfilingData::from_paged_buffer(data.clone(), None).unwrap();
+    assert_eq!(complete.truncated_bytes(), 0);
+    assert_eq!(complete.num_events(), 1_000);
+
+    // The events are numbered in the order in which they have been read, the
+    // same way on every read.
+    let indices: Vec<_> = complete.iter().map(|event| event.index()).collect();
+    assert_eq!(indices, (0..1_000).collect::<Vec<_>>());
+    let reread = ProfilingData::from_paged_buffer(data.clone(), None).unwrap();
+    assert!(reread
+        .iter()
+        .map(|event| event.index())
+        .eq(indices.iter().cloned()));
+
+    let check_prefix = |truncated: &ProfilingData| {
+        assert!(truncated.num_events() < complete.num_events());
+
+        let mut num_unknown = 0;
+        for (event, complete_event) in truncated.iter().zip(complete.iter()) {
+            assert_eq!(event, complete_event);
+            assert_eq!(event.index(), complete_event.index());
+
+            // The pages with the strings of the last events may be missing.
+            let label = truncated.to_full_event(&event).label;
+            if label == "<unknown>" {
+                num_unknown += 1;
+            } else {
+                assert_eq!(label, complete.to_full_event(&complete_event).label);
+            }
+        }
+        assert!(num_unknown < truncated.num_events());
+    };
+
+    let half = data.len() / 2;
+    let complete_len =
+        FILE_HEADER_SIZE + measureme::complete_pages_len(&data[FILE_HEADER_SIZE..half]);
+    assert!(complete_len < half);
+
+    // An incomplete page.
+    let truncated = ProfilingData::from_paged_buffer(data[..half].to_vec(), None).unwrap();
+    assert_eq!(truncated.truncated_bytes(), (half - complete_len) as u64);
+    check_prefix(&truncated);
+    let warnings = crate::profile_warnings(&truncated, &filestem);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("is truncated"));
+
+    // A page with a corrupt header.
+    let mut corrupt = data[..complete_len].to_vec();
+    corrupt.extend_from_slice(&[0xAB, 0xCD, 0xEF, 0x01, 0x23, 0x45]);
+    let corrupt = ProfilingData::from_paged_buffer(corrupt, None).unwrap();
+    assert_eq!(corrupt.truncated_bytes(), 6);
+    assert_eq!(corrupt.num_events(), truncated.num_events());
+    check_prefix(&corrupt);
+}
+
+/// Checks that `decodeme::verify::verify_file` accepts a regular profile with
+/// several threads and reports where a truncated copy of it ends.
+pub fn run_verify_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    generate_profiling_data(&filestem, 1_000, 4, Default::default());
+
+    let data = fs::read(filestem.with_extension(FILE_EXTENSION)).unwrap();
+    let verified = decodeme::verify::verify_file(&data).unwrap();
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert_eq!(verified.num_events, profiling_data.num_events());
+
+    let half = data.len() / 2;
+    let complete_len =
+        FILE_HEADER_SIZE + measureme::complete_pages_len(&data[FILE_HEADER_SIZE..half]);
+    let e = decodeme::verify::verify_file(&data[..half]).unwrap_err();
+    assert_eq!(e.offset, complete_len);
+}
+
+/// Checks that a profile written with a non-default page size can be read and
+/// that none of its pages exceeds that size.
+pub fn run_page_size_test(file_name_stem: &str, page_size: usize) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        page_size: Some(page_size),
+        ..Default::default()
+    };
+    let expected_events = generate_profiling_data(&filestem, 1_000, 2, options);
+    process_profiling_data(&filestem, &expected_events);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert_eq!(profiling_data.metadata().page_size, Some(page_size as u64));
+
+    let file = fs::read(filestem.with_extension(FILE_EXTENSION)).unwrap();
+    for (_, page_contents) in
+        measureme::iter_pages(&file[measureme::file_header::FILE_HEADER_SIZE..])
+    {
+        assert!(page_contents.len() <= page_size);
+    }
+}
+
+pub fn run_end_to_end_serialization_test(file_name_stem: &str, num_threads: usize) {
+    let filestem = mk_filestem(file_name_stem);
+    let expected_events =
+        generate_profiling_data(&filestem, 10_000, num_threads, Default::default());
+    process_profiling_data(&filestem, &expected_events);
+}
+
+#[cfg(feature = "zstd")]
+pub fn run_compressed_end_to_end_serialization_test(file_name_stem: &str, num_threads: usize) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        compression: measureme::Compression::Zstd { level: 3 },
+        ..Default::default()
+    };
+    let expected_events = generate_profiling_data(&filestem, 10_000, num_threads, options);
+    process_profiling_data(&filestem, &expected_events);
+}
+
+/// Like `run_end_to_end_serialization_test`, but records the profile with an
+/// `InMemorySink`, and checks that the recorded bytes are a valid profile
+/// file by writing them to a file and reading that.
+pub fn run_in_memory_end_to_end_test(file_name_stem: &str, num_threads: usize) {
+    let sink = InMemorySink::new();
+    let expected_events = record_events(
+        Profiler::with_sink(sink.clone()).unwrap(),
+        2_000,
+        num_threads,
+    );
+
+    let profiling_data = ProfilingData::from_paged_buffer(sink.bytes(), None).unwrap();
+    check_recorded_events(&profiling_data, &expected_events);
+
+    let filestem = mk_filestem(file_name_stem);
+    fs::create_dir_all(filestem.parent().unwrap()).unwrap();
+    fs::write(filestem.with_extension(FILE_EXTENSION), sink.bytes()).unwrap();
+    process_profiling_data(&filestem, &expected_events);
+}
+
+/// Like `run_end_to_end_serialization_test`, but writes the profile with a
+/// `SpillingSink` that keeps up to `max_buffered_bytes` bytes in memory.
+pub fn run_spilling_sink_test(file_name_stem: &str, max_buffered_bytes: usize) {
+    let filestem = mk_filestem(file_name_stem);
+    fs::create_dir_all(filestem.parent().unwrap()).unwrap();
+    let file = fs::File::create(filestem.with_extension(FILE_EXTENSION)).unwrap();
+    let sink = SpillingSink::new(file, max_buffered_bytes);
+
+    let expected_events = record_events(Profiler::with_sink(sink).unwrap(), 2_000, 4);
+    process_profiling_data(&filestem, &expected_events);
+}
+
+/// Checks that the snapshot of a `RingBufferSink` that has overwritten some
+/// of its pages is a valid profile with the first and the most recent events.
+pub fn run_ring_buffer_sink_test(page_size: usize) {
+    let ring = RingBufferSink::new(4 * page_size);
+    let options = ProfilerOptions {
+        page_size: Some(page_size),
+        ..Default::default()
+    };
+    let num_events = 100 * page_size as u64 / std::mem::size_of::<measureme::RawEvent>() as u64;
+
+    {
+        let profiler = Profiler::with_sink_and_options(
+            ring.clone(),
+            Counter::WallTime(WallTime::new()),
+            options,
+        )
+        .unwrap();
+        let event_kind = profiler.alloc_string("Value");
+        let event_id = EventId::from_label(profiler.alloc_string("value"));
+
+        for value in 0..num_events {
+            profiler.record_integer_event(event_kind, event_id, 0, value);
+        }
+    }
+
+    assert!(ring.overwritten_pages() > 0);
+
+    let data = ProfilingData::from_paged_buffer(ring.snapshot(), None).unwrap();
+    let values: Vec<_> = data
+        .iter_full()
+        .map(|event| {
+            assert_eq!(event.label, "value");
+            event.integer().unwrap()
+        })
+        .collect();
+    assert!(values.len() < num_events as usize / 10);
+
+    // The events of the first page, followed by the most recent ones.
+    let gap = values.windows(2).position(|w| w[1] != w[0] + 1).unwrap();
+    assert_eq!(values[0], 0);
+    assert!(values[gap + 1] > values[gap] + 1);
+    assert!(values[gap + 1..].windows(2).all(|w| w[1] == w[0] + 1));
+    assert_eq!(*values.last().unwrap(), num_events - 1);
+}
+
+/// Checks that `RingBufferSink` also overwrites the oldest trace contexts,
+/// without losing those of the interval events that it keeps.
+pub fn run_ring_buffer_trace_context_test(page_size: usize) {
+    let ring = RingBufferSink::new(4 * page_size);
+    let options = ProfilerOptions {
+        page_size: Some(page_size),
+        record_trace_context: true,
+        ..Default::default()
+    };
+    let num_events = 100 * page_size as u64 / std::mem::size_of::<measureme::RawEvent>() as u64;
+
+    {
+        let profiler = Profiler::with_sink_and_options(
+            ring.clone(),
+            Counter::WallTime(WallTime::new()),
+            options,
+        )
+        .unwrap();
+        let event_kind = profiler.alloc_string("Query");
+        let event_id = EventId::from_label(profiler.alloc_string("traced"));
+
+        for span_id in 0..num_events {
+            let trace_context = TraceContext {
+                trace_id: 1,
+                span_id,
+            };
+            drop(profiler.start_recording_interval_event_with_trace_context(
+                event_kind,
+                event_id,
+                trace_context,
+                0,
+            ));
+        }
+    }
+
+    let snapshot = ring.snapshot();
+    let trace_context_bytes = split_streams(&snapshot[FILE_HEADER_SIZE..])
+        .remove(&PageTag::TraceContext)
+        .unwrap()
+        .len();
+    assert!(trace_context_bytes <= 5 * page_size + TRACE_CONTEXT_PRELUDE_SIZE);
+
+    let data = ProfilingData::from_paged_buffer(snapshot, None).unwrap();
+    let intervals: Vec<_> = data
+        .iter()
+        .filter(|event| event.payload.is_interval())
+        .collect();
+    assert!(intervals.len() < num_events as usize / 10);
+
+    // The events of the first page have lost their trace contexts, the most
+    // recent ones still have them.
+    let last = intervals.last().unwrap();
+    assert_eq!(data.trace_context(last).unwrap().span_id, num_events - 1);
+    let traced = intervals
+        .iter()
+        .filter(|event| data.trace_context(event).is_some())
+        .count();
+    assert!(traced > intervals.len() / 2);
+    assert!(traced < intervals.len());
+    for (event, span_id) in intervals
+        .iter()
+        .rev()
+        .zip((0..num_events).rev())
+        .take(traced)
+    {
+        assert_eq!(data.trace_context(event).unwrap().span_id, span_id);
+    }
+}
+
+/// Checks that the string table pages a `RingBufferSink` keeps grow with the
+/// arguments of the events, and that `ProfilerOptions::max_string_bytes`
+/// bounds them while the events pages are overwritten.
+pub fn run_ring_buffer_string_budget_test(page_size: usize) {
+    let num_events = 100 * page_size as u64 / std::mem::size_of::<measureme::RawEvent>() as u64;
+    let max_string_bytes = 4 * page_size;
+
+    let record = |max_string_bytes: Option<usize>| {
+        let ring = RingBufferSink::new(4 * page_size);
+        let options = ProfilerOptions {
+            page_size: Some(page_size),
+            max_string_bytes: max_string_bytes.map(|bytes| bytes as u64),
+            ..Default::default()
+        };
+
+        {
+            let profiler = Profiler::with_sink_and_options(
+                ring.clone(),
+                Counter::WallTime(WallTime::new()),
+                options,
+            )
+            .unwrap();
+            let event_id_builder = EventIdBuilder::new(&profiler);
+            let event_kind = profiler.alloc_string("Query");
+            let label = profiler.alloc_string("query");
+
+            for arg in 0..num_events {
+                let event_id = event_id_builder.from_label_and_int_arg(label, arg);
+                profiler.record_instant_event(event_kind, event_id, 0, None);
+            }
+        }
+
+        assert!(ring.overwritten_pages() > 0);
+        ring
+    };
+
+    // Every event allocates a string of its own, which is never overwritten.
+    let unbounded = record(None);
+    assert!(unbounded.string_bytes() > 10 * max_string_bytes);
+
+    // The budget is exceeded by at most the string that exceeds it, and the
+    // string table and metadata pages are less than a page on top of it.
+    let bounded = record(Some(max_string_bytes));
+    assert!(bounded.string_bytes() <= max_string_bytes + page_size);
+
+    // The `ARGS_DROPPED_EVENT_KIND` marker has been overwritten along with
+    // the other early events, but the recent events lack their arguments.
+    let data = ProfilingData::from_paged_buffer(bounded.snapshot(), None).unwrap();
+    let last = data.iter_full().last().unwrap();
+    assert_eq!(last.label, "query");
+    assert!(last.additional_data.is_empty());
+}
+
+/// Checks that the "end" event of an `IntervalGuard` is recorded, with the id
+/// of the thread that created the guard, when the thread panics while the
+/// guard is alive.
+pub fn run_interval_guard_unwind_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let profiler = Arc::new(Profiler::new(&filestem).unwrap());
+    let guard_thread_id = Arc::new(AtomicU32::new(u32::MAX));
+
+    let thread = {
+        let profiler = profiler.clone();
+        let guard_thread_id = guard_thread_id.clone();
+
+        std::thread::spawn(move || {
+            let event_kind = profiler.alloc_string("Generic");
+            let event_id = EventId::from_label(profiler.alloc_string("PanickingActivity"));
+
+            let guard = profiler.start_interval(event_kind, event_id);
+            guard_thread_id.store(guard.thread_id(), Ordering::SeqCst);
+
+            panic!("panicking while an IntervalGuard is alive");
+        })
+    };
+
+    assert!(thread.join().is_err());
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    let events: Vec<_> = profiling_data.iter_full().collect();
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event_kind, "Generic");
+    assert_eq!(events[0].label, "PanickingActivity");
+    assert!(events[0].payload.is_interval());
+    assert_eq!(events[0].thread_id, guard_thread_id.load(Ordering::SeqCst));
+}
+
+/// Checks that a profile split into multiple files by
+/// `ProfilerOptions::max_file_bytes` can be read back as a single stream, with
+/// strings allocated in the first segment still being valid in later ones,
+/// also once the earlier ones have been deleted.
+pub fn run_rotating_files_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    // Remove segments left over from previous runs.
+    for segment_index in 0..10 {
+        let _ = std::fs::remove_file(segment_file_path(&filestem, segment_index));
+    }
+
+    let options = ProfilerOptions {
+        max_file_bytes: Some(1),
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+
+    let event_kind = profiler.alloc_string("Generic");
+    let event_id = EventId::from_label(profiler.alloc_string("EarlyLabel"));
+
+    // Fill a bit more than two pages with events, so that they end up in three
+    // segments.
+    let num_early_events = 25_000;
+    for _ in 0..num_early_events {
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+    }
+
+    // This string is written only after the profile has been split.
+    let late_event_id = EventId::from_label(profiler.alloc_string("LateLabel"));
+    profiler.record_instant_event(event_kind, late_event_id, 2, None);
+    drop(profiler);
+
+    assert!(segment_file_path(&filestem, 2).exists());
+    assert!(!segment_file_path(&filestem, 3).exists());
+
+    // Every segment can be read on its own.
+    let first_segment = ProfilingData::new(&filestem).unwrap();
+    assert!(first_segment.num_events() > 0);
+    assert!(first_segment.num_events() < num_early_events);
+    for segment_index in 1..3 {
+        let path = segment_file_path(&filestem, segment_index);
+        let data = fs::read(&path).unwrap();
+        decodeme::verify::verify_file(&data).unwrap();
+        let segment = ProfilingData::from_paged_buffer(data, Some(&path)).unwrap();
+        let labels: Vec<_> = segment.iter_full().map(|e| e.label).collect();
+        assert!(!labels.is_empty());
+        assert!(labels
+            .iter()
+            .all(|label| label == "EarlyLabel" || label == "LateLabel"));
+    }
+
+    let profiling_data = ProfilingData::from_rotated_files(&filestem).unwrap();
+    assert_eq!(profiling_data.num_events(), num_early_events + 1);
+
+    for (index, event) in profiling_data.iter().enumerate() {
+        assert_eq!(event.event_index, index);
+    }
+
+    let events: Vec<_> = profiling_data.iter_full().collect();
+    for event in &events[..num_early_events] {
+        assert_eq!(event.event_kind, "Generic");
+        assert_eq!(event.label, "EarlyLabel");
+        assert_eq!(event.thread_id, 1);
+    }
+    assert_eq!(events[num_early_events].label, "LateLabel");
+    assert_eq!(events[num_early_events].thread_id, 2);
+
+    // Each segment has a copy of the strings that its events use, and only
+    // of these.
+    let count_labels = |path: &Path, label: &[u8]| {
+        let data = fs::read(path).unwrap();
+        data.windows(label.len())
+            .filter(|window| window == &label)
+            .count()
+    };
+    for segment_index in 0..3 {
+        let path = segment_file_path(&filestem, segment_index);
+        assert_eq!(count_labels(&path, b"EarlyLabel"), 1);
+        let has_late_label = segment_index == 2;
+        assert_eq!(count_labels(&path, b"LateLabel"), has_late_label as usize);
+    }
+
+    // Deleting old segments doesn't lose any strings.
+    fs::remove_file(segment_file_path(&filestem, 0)).unwrap();
+    let remaining = ProfilingData::from_rotated_files(&filestem).unwrap();
+    assert_eq!(
+        remaining.num_events(),
+        num_early_events + 1 - first_segment.num_events()
+    );
+    let labels: Vec<_> = remaining.iter_full().map(|event| event.label).collect();
+    assert!(labels[..labels.len() - 1]
+        .iter()
+        .all(|label| label == "EarlyLabel"));
+    assert_eq!(labels.last().unwrap(), "LateLabel");
+}
+
+/// Checks that the segments of a rotated profile whose events use a virtual
+/// string id that is only mapped after they have been written get the string,
+/// and that a segment that doesn't use it doesn't.
+pub fn run_rotating_files_virtual_ids_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    // Remove segments left over from previous runs.
+    for segment_index in 0..10 {
+        let _ = std::fs::remove_file(segment_file_path(&filestem, segment_index));
+    }
+
+    let options = ProfilerOptions {
+        max_file_bytes: Some(1),
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+
+    let event_kind = profiler.alloc_string("Generic");
+    let virtual_id = StringId::new_virtual(7);
+    let early_event_id = EventId::from_label(profiler.alloc_string("EarlyLabel"));
+
+    // The first segment uses the virtual id, the second one doesn't.
+    profiler.record_instant_event(event_kind, EventId::from_virtual(virtual_id), 1, None);
+    for _ in 0..25_000 {
+        profiler.record_instant_event(event_kind, early_event_id, 1, None);
+    }
+
+    profiler.map_virtual_to_concrete_string(virtual_id, profiler.alloc_string("VirtualLabel"));
+    drop(profiler);
+
+    assert!(segment_file_path(&filestem, 1).exists());
+
+    let first_segment = ProfilingData::new(&filestem).unwrap();
+    let labels: Vec<_> = first_segment.iter_full().map(|e| e.label).collect();
+    assert_eq!(labels[0], "VirtualLabel");
+    assert!(labels[1..].iter().all(|label| label == "EarlyLabel"));
+
+    let last_path = segment_file_path(&filestem, find_last_segment_index(&filestem));
+    let last_segment = fs::read(&last_path).unwrap();
+    assert!(!last_segment
+        .windows(b"VirtualLabel".len())
+        .any(|window| window == b"VirtualLabel"));
+
+    let profiling_data = ProfilingData::from_rotated_files(&filestem).unwrap();
+    let events: Vec<_> = profiling_data.iter_full().collect();
+    assert_eq!(events.len(), 25_001);
+    assert_eq!(events[0].label, "VirtualLabel");
+}
+
+/// The index of the last segment of the rotated profile at `filestem`.
+fn find_last_segment_index(filestem: &Path) -> u32 {
+    (0..)
+        .take_while(|&i| segment_file_path(filestem, i).exists())
+        .last()
+        .unwrap()
+}
+
+/// Checks that the metadata recorded with `Profiler::record_metadata` can be
+/// read back, and that every segment of a rotated profile contains all of it.
+pub fn run_process_metadata_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    // Remove segments left over from previous runs.
+    for segment_index in 0..10 {
+        let _ = std::fs::remove_file(segment_file_path(&filestem, segment_index));
+    }
+
+    let options = ProfilerOptions {
+        max_file_bytes: Some(1),
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+
+    profiler.record_metadata(METADATA_KEY_RUSTC_VERSION, "rustc 1.0.0");
+
+    // Fill more than a page with events, so that the profile gets split.
+    let event_kind = profiler.alloc_string("Generic");
+    let event_id = EventId::from_label(profiler.alloc_string("Label"));
+    for _ in 0..20_000 {
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+    }
+
+    profiler.record_metadata(METADATA_KEY_OPT_LEVEL, "3");
+    drop(profiler);
+
+    assert!(segment_file_path(&filestem, 1).exists());
+
+    for profiling_data in &[
+        ProfilingData::new(&filestem).unwrap(),
+        ProfilingData::from_rotated_files(&filestem).unwrap(),
+    ] {
+        let metadata = profiling_data.process_metadata();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[METADATA_KEY_RUSTC_VERSION], "rustc 1.0.0");
+        assert_eq!(metadata[METADATA_KEY_OPT_LEVEL], "3");
+    }
+}
+
+/// Checks that `ProfilingData::open_incremental` picks up the pages of a
+/// profile as they are written, by appending the file of a finished profile to
+/// another file in pieces that don't line up with the page boundaries.
+pub fn run_incremental_reading_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let profiler = Profiler::new(&filestem).unwrap();
+
+    // Write enough strings and string ids that pages of both string table
+    // streams get written before the last events page.
+    let labels: Vec<StringId> = (0..20_000)
+        .map(|i| {
+            profiler.alloc_string(&format!("a label with a rather long name, number {}", i)[..])
+        })
+        .collect();
+    let event_kind = profiler.alloc_string("Generic");
+    profiler.bulk_map_virtual_to_single_concrete_string(
+        (0..40_000).map(StringId::new_virtual),
+        labels[0],
+    );
+
+    // The markers are in earlier pages than some of the events that they
+    // are looked up with, and than some of their strings.
+    profiler.set_thread_name("main");
+    profiler.record_metadata("phase", "start");
+    let closed = profiler.open_ended_span(event_kind, EventId::from_label(labels[1]));
+    let held = profiler.open_ended_span(event_kind, EventId::from_label(labels[2]));
+    let thread_id = held.thread_id();
+
+    for i in 0..30_000 {
+        let event_id = EventId::from_label(labels[i % labels.len()]);
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+    }
+    profiler.close_span(closed);
+    profiler.set_thread_name("renamed");
+    profiler.record_metadata("phase", "end");
+    drop(held);
+    drop(profiler);
+
+    let expected = ProfilingData::new(&filestem).unwrap();
+    let complete_file = fs::read(filestem.with_extension(FILE_EXTENSION)).unwrap();
+
+    let live_filestem = mk_filestem(&format!("{}_live", file_name_stem));
+    let live_path = live_filestem.with_extension(FILE_EXTENSION);
+    fs::write(&live_path, &complete_file[..3]).unwrap();
+
+    let mut live = ProfilingData::open_incremental(&live_filestem).unwrap();
+    assert!(live.profiling_data().is_none());
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&live_path)
+        .unwrap();
+    let mut num_events_seen = vec![];
+
+    for chunk in complete_file[3..].chunks(10_007) {
+        file.write_all(chunk).unwrap();
+        live.refresh().unwrap();
+
+        num_events_seen.push(live.profiling_data().map_or(0, |data| data.num_events()));
+    }
+
+    // Nothing has been written since the last refresh.
+    assert!(!live.refresh().unwrap());
+
+    // The events have become visible gradually.
+    assert!(num_events_seen.windows(2).all(|w| w[0] <= w[1]));
+    assert!(num_events_seen
+        .iter()
+        .any(|&n| n > 0 && n < expected.num_events()));
+
+    let live = live.profiling_data().unwrap();
+    assert_eq!(live.num_events(), expected.num_events());
+    assert_eq!(
+        live.iter_full().collect::<Vec<_>>(),
+        expected.iter_full().collect::<Vec<_>>()
+    );
+
+    assert_eq!(live.thread_name(thread_id), Some("renamed"));
+    assert_eq!(live.process_metadata(), expected.process_metadata());
+    assert_eq!(live.process_metadata()["phase"], "end");
+    assert_eq!(expected.unclosed_intervals().len(), 1);
+    assert_eq!(live.unclosed_intervals(), expected.unclosed_intervals());
+}
+
+/// Checks that the wall-clock times of the events of a profile recorded with
+/// the `wall-time` counter are those at which they have been recorded, and
+/// that other counters don't claim to measure wall time.
+pub fn run_wall_clock_start_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let before_start = SystemTime::now();
+    let profiler = Profiler::new(&filestem).unwrap();
+    let after_start = SystemTime::now();
+
+    let event_kind = profiler.alloc_string("Generic");
+    let event_id = EventId::from_label(profiler.alloc_string("Event"));
+    std::thread::sleep(Duration::from_millis(10));
+    let before_event = SystemTime::now();
+    profiler.record_instant_event(event_kind, event_id, 0, None);
+    let after_event = SystemTime::now();
+    drop(profiler);
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let wall_clock_start = data.wall_clock_start().unwrap();
+    assert!(before_start <= wall_clock_start && wall_clock_start <= after_start);
+
+    let event = data.iter().next().unwrap();
+    let recorded_at = data.to_wall_clock(event.start().unwrap()).unwrap();
+    assert!(before_event <= recorded_at && recorded_at <= after_event);
+
+    let sliced = data.slice_time_range(0, u64::MAX);
+    assert_eq!(sliced.wall_clock_start(), Some(wall_clock_start));
+
+    let clock_filestem = mk_filestem(&format!("{}_clock", file_name_stem));
+    drop(Profiler::with_clock(&clock_filestem, Box::new(SteppingClock::default())).unwrap());
+    let data = ProfilingData::new(&clock_filestem).unwrap();
+    assert_eq!(data.wall_clock_start(), None);
+}
+
+/// Checks that the trace contexts recorded with
+/// `ProfilerOptions::record_trace_context` by two "processes" survive merging
+/// their profiles, so that the spans of the same trace can be correlated.
+pub fn run_trace_context_test(file_name_stem: &str) {
+    let request = TraceContext {
+        trace_id: 0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736,
+        span_id: 1,
+    };
+    let other_request = TraceContext {
+        trace_id: 7,
+        span_id: 2,
+    };
+
+    let record = |file_name_stem: String, spans: &[(&'static str, TraceContext)]| {
+        let filestem = mk_filestem(&file_name_stem);
+        let options = ProfilerOptions {
+            record_trace_context: true,
+            record_wall_time: true,
+            ..Default::default()
+        };
+        let clock = Counter::Clock(Box::new(SteppingClock::default()));
+
+        {
+            let profiler = Profiler::with_options(&filestem, clock, options)
+                .unwrap()
+                .into_ref();
+            let event_kind = profiler.alloc_string("Request");
+            let untraced = EventId::from_label(profiler.alloc_string("untraced"));
+            for &(label, trace_context) in spans {
+                let event_id = EventId::from_label(profiler.alloc_string(label));
+                let _span = profiler.start_recording_interval_event_with_trace_context(
+                    event_kind,
+                    event_id,
+                    trace_context,
+                    0,
+                );
+                drop(profiler.start_recording_interval_event(event_kind, untraced, 0));
+            }
+        }
+
+        let bytes = fs::read(filestem.with_extension(FILE_EXTENSION)).unwrap();
+        decodeme::verify::verify_file(&bytes).unwrap();
+        ProfilingData::new(&filestem).unwrap()
+    };
+
+    let frontend = record(
+        format!("{}_frontend", file_name_stem),
+        &[("frontend", request), ("frontend_other", other_request)],
+    );
+    let backend = record(
+        format!("{}_backend", file_name_stem),
+        &[(
+            "backend",
+            TraceContext {
+                span_id: 3,
+                ..request
+            },
+        )],
+    );
+
+    assert!(frontend.has_trace_contexts());
+    let labels = |data: &ProfilingData| -> Vec<(String, Option<TraceContext>)> {
+        data.iter()
+            .filter(|event| event.payload.is_interval())
+            .map(|event| {
+                (
+                    data.to_full_event(&event).label.into_owned(),
+                    data.trace_context(&event),
+                )
+            })
+            .collect()
+    };
+    assert_eq!(
+        labels(&frontend),
+        vec![
+            ("untraced".to_string(), None),
+            ("frontend".to_string(), Some(request)),
+            ("untraced".to_string(), None),
+            ("frontend_other".to_string(), Some(other_request)),
+        ]
+    );
+    let merged = ProfilingData::merge(&[frontend, backend]).unwrap();
+    assert!(merged.has_trace_contexts());
+
+    let traces = merged.spans_by_trace_id();
+    assert_eq!(traces.len(), 2);
+    let spans: Vec<_> = traces[&request.trace_id]
+        .iter()
+        .map(|(trace_context, event)| {
+            (
+                trace_context.span_id,
+                merged.to_full_event(event).label.into_owned(),
+                event.thread_id,
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            (1, "frontend".to_string(), 0),
+            (3, "backend".to_string(), 1)
+        ]
+    );
+    assert_eq!(traces[&other_request.trace_id].len(), 1);
+    assert!(merged
+        .wall_time(&traces[&other_request.trace_id][0].1)
+        .is_some());
+
+    // The index is only built once.
+    assert!(std::ptr::eq(merged.spans_by_trace_id(), traces));
+    assert_eq!(merged.spans_of_trace(request.trace_id).len(), 2);
+    assert!(merged.spans_of_trace(u128::MAX).is_empty());
+
+    // A slice of the profile keeps the trace contexts of its spans.
+    let end = merged.metadata().start_time;
+    let end = merged
+        .iter()
+        .filter_map(|event| event.timestamp())
+        .map(|timestamp| timestamp.end())
+        .max()
+        .unwrap()
+        .duration_since(end)
+        .unwrap();
+    let sliced = merged.slice_time_range(0, end.as_nanos() as u64 + 1);
+    let labeled_spans = |data: &ProfilingData| -> Vec<(u128, u64, String)> {
+        data.spans_by_trace_id()
+            .values()
+            .flatten()
+            .map(|(trace_context, event)| {
+                (
+                    trace_context.trace_id,
+                    trace_context.span_id,
+                    data.to_full_event(event).label.into_owned(),
+                )
+            })
+            .collect()
+    };
+    assert!(sliced.has_trace_contexts());
+    assert_eq!(labeled_spans(&sliced), labeled_spans(&merged));
+
+    // The markers don't count as queries.
+    assert_eq!(merged.perform_analysis().query_data.len(), 4);
+}
+
+/// Checks that the wall-clock durations recorded with
+/// `ProfilerOptions::record_wall_time` are available next to the counter
+/// values of the interval events, on all threads, and that the markers
+/// holding them don't show up in the analysis.
+pub fn run_wall_time_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        record_wall_time: true,
+        ..Default::default()
+    };
+    let clock = Counter::Clock(Box::new(SteppingClock::default()));
+
+    {
+        let profiler = Arc::new(Profiler::with_options(&filestem, clock, options).unwrap());
+        let event_kind = profiler.alloc_string("Generic");
+        let outer = EventId::from_label(profiler.alloc_string("outer"));
+        let inner = EventId::from_label(profiler.alloc_string("inner"));
+
+        let threads: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let profiler = profiler.clone();
+                std::thread::spawn(move || {
+                    let _outer =
+                        profiler.start_recording_interval_event(event_kind, outer, thread_id);
+                    std::thread::sleep(Duration::from_millis(2));
+                    drop(profiler.start_recording_interval_event(event_kind, inner, thread_id));
+                    profiler.record_instant_event(event_kind, inner, thread_id, None);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    assert!(data.has_wall_times());
+
+    let mut intervals = 0;
+    for event in data.iter() {
+        let full_event = data.to_full_event(&event);
+        match full_event.payload {
+            EventPayload::Timestamp(Timestamp::Interval { .. }) => {
+                intervals += 1;
+                let wall_time = data.wall_time(&event).unwrap();
+                if full_event.label == "outer" {
+                    assert!(wall_time >= Duration::from_millis(2));
+                    // The counter value is unaffected by the sleep.
+                    assert!(event.duration().unwrap() < Duration::from_millis(2));
+                }
+            }
+            _ => assert_eq!(data.wall_time(&event), None),
+        }
+    }
+    assert_eq!(intervals, 8);
+
+    let results = data.perform_analysis();
+    let mut labels: Vec<_> = results
+        .query_data
+        .iter()
+        .map(|q| (&q.label[..], q.invocation_count))
+        .collect();
+    labels.sort();
+    assert_eq!(labels, vec![("inner", 4), ("outer", 4)]);
+
+    let without_wall_times = mk_filestem(&format!("{}_without", file_name_stem));
+    drop(Profiler::with_clock(&without_wall_times, Box::new(SteppingClock::default())).unwrap());
+    assert!(!ProfilingData::new(&without_wall_times)
+        .unwrap()
+        .has_wall_times());
+}
+
+/// Checks that an interval event at whose end the counter couldn't be read
+/// is flagged instead of being recorded with a bogus duration, also when its
+/// wall time is recorded as well.
+pub fn run_counter_unavailable_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        record_wall_time: true,
+        ..Default::default()
+    };
+    let clock = Counter::Clock(Box::new(FailingClock::failing_at_read(2)));
+
+    {
+        let profiler = Profiler::with_options(&filestem, clock, options).unwrap();
+        let event_kind = profiler.alloc_string("Generic");
+        let outer = EventId::from_label(profiler.alloc_string("outer"));
+        let failed = EventId::from_label(profiler.alloc_string("failed"));
+        let inner = EventId::from_label(profiler.alloc_string("inner"));
+
+        let _outer = profiler.start_recording_interval_event(event_kind, outer, 0);
+        drop(profiler.start_recording_interval_event(event_kind, failed, 0));
+        drop(profiler.start_recording_interval_event(event_kind, inner, 0));
+    }
+
+    let data = ProfilingData::new(&filestem).unwrap();
+
+    let mut intervals = Vec::new();
+    for event in data.iter() {
+        if event.duration().is_none() {
+            continue;
+        }
+
+        let full_event = data.to_full_event(&event);
+        assert!(data.wall_time(&event).is_some());
+        intervals.push((
+            full_event.label.into_owned(),
+            event.duration().unwrap().as_nanos() as u64,
+            data.counter_unavailable(&event),
+        ));
+    }
+
+    assert_eq!(
+        intervals,
+        vec![
+            ("failed".to_string(), 0, true),
+            ("inner".to_string(), 10, false),
+            ("outer".to_string(), 50, false),
+        ]
+    );
+}
+
+/// Checks that intervals shorter than `ProfilerOptions::min_duration_nanos`
+/// are missing from the profile and that the profile is marked as sampled.
+pub fn run_sampled_profile_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let options = ProfilerOptions {
+        min_duration_nanos: 25,
+        ..Default::default()
+    };
+    let profiler = Profiler::with_options(
+        &filestem,
+        Counter::Clock(Box::new(SteppingClock::default())),
+        options,
+    )
+    .unwrap();
+
+    let event_kind = profiler.alloc_string("Generic");
+    let long_event_id = EventId::from_label(profiler.alloc_string("Long"));
+    let short_event_id = EventId::from_label(profiler.alloc_string("Short"));
+
+    {
+        let _long = profiler.start_interval(event_kind, long_event_id);
+        drop(profiler.start_interval(event_kind, short_event_id));
+    }
+    drop(profiler.start_interval(event_kind, short_event_id));
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert!(profiling_data.is_sampled());
+
+    let events: Vec<_> = profiling_data.iter_full().collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].label, "Long");
+    assert_eq!(events[0].duration(), Some(Duration::from_nanos(30)));
+}
+
+/// Checks that timestamps beyond the range that fits into a `RawEvent` are
+/// decoded correctly, by recording events with a clock that is moved right up
+/// to and past the points where the stored values wrap around.
+pub fn run_timestamp_overflow_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let clock = ManualClock::default();
+    let profiler = Profiler::with_clock(&filestem, Box::new(clock.clone())).unwrap();
+    let set_now = |t: u64| clock.set(t);
+
+    let event_kind = profiler.alloc_string("Generic");
+    let event_id = |label: &str| EventId::from_label(profiler.alloc_string(label));
+
+    // The expected label, thread id, start and end of each event.
+    let mut expected = Vec::new();
+    let mut instant = |label: &'static str, thread_id: u32, t: u64| {
+        set_now(t);
+        profiler.record_instant_event(event_kind, event_id(label), thread_id, None);
+        expected.push((label, thread_id, t, t));
+    };
+
+    instant("start", 0, 100);
+    instant("before first epoch", 0, TIMESTAMP_EPOCH_LENGTH - 1);
+    instant("first epoch", 0, TIMESTAMP_EPOCH_LENGTH);
+    instant("before wrap-around", 1, TIMESTAMP_PERIOD - 1);
+    instant("wrap-around", 1, TIMESTAMP_PERIOD);
+    instant("old limit", 0, MAX_INTERVAL_VALUE + 10);
+    // No events at all for several epochs.
+    instant("after gap", 0, 5 * TIMESTAMP_PERIOD + 3);
+
+    let interval = |label: &'static str, thread_id: u32, start: u64, end: u64| {
+        set_now(start);
+        let guard = profiler.start_recording_interval_event(event_kind, event_id(label), thread_id);
+        set_now(end);
+        drop(guard);
+        (label, thread_id, start, end)
+    };
+
+    let spanning_wrap_around = interval(
+        "spanning wrap-around",
+        0,
+        6 * TIMESTAMP_PERIOD - 20,
+        6 * TIMESTAMP_PERIOD + 20,
+    );
+    let spanning_epochs = interval(
+        "spanning epochs",
+        1,
+        6 * TIMESTAMP_PERIOD + 100,
+        7 * TIMESTAMP_PERIOD - 100,
+    );
+    // Intervals longer than the period get truncated.
+    let too_long = interval("too long", 1, 8 * TIMESTAMP_PERIOD, 10 * TIMESTAMP_PERIOD);
+    drop(profiler);
+    expected.extend_from_slice(&[
+        spanning_wrap_around,
+        spanning_epochs,
+        (too_long.0, too_long.1, 9 * TIMESTAMP_PERIOD + 1, too_long.3),
+    ]);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    let start_time = profiling_data.metadata().start_time;
+    let time = |nanos: u64| start_time + Duration::from_nanos(nanos);
+
+    let actual: Vec<_> = profiling_data
+        .iter_full()
+        .filter(|event| event.event_kind != TIMESTAMP_EPOCH_EVENT_KIND)
+        .map(|event| {
+            let timestamp = event.payload.timestamp().unwrap();
+            (
+                event.label.into_owned(),
+                event.thread_id,
+                timestamp.start(),
+                timestamp.end(),
+            )
+        })
+        .collect();
+
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(label, thread_id, start, end)| {
+            (label.to_string(), thread_id, time(start), time(end))
+        })
+        .collect();
+
+    assert_eq!(actual, expected);
+    assert_eq!(profiling_data.validate(), vec![]);
+
+    let epochs: Vec<_> = profiling_data
+        .iter_full()
+        .filter(|event| event.event_kind == TIMESTAMP_EPOCH_EVENT_KIND)
+        .map(|event| event.payload.integer().unwrap())
+        .collect();
+    assert_eq!(epochs, vec![1, 2, 4, 10, 12, 13, 20]);
+
+    // The markers are found through the timestamp epoch index. Without it,
+    // or with one that doesn't match the events, all events are scanned for
+    // them instead, with the same result.
+    let data = fs::read(segment_file_path(&filestem, 0)).unwrap();
+    let index = split_streams(&data[FILE_HEADER_SIZE..])
+        .remove(&PageTag::TimestampEpochIndex)
+        .unwrap();
+    let index = decode_timestamp_epoch_index(&index, None).unwrap().unwrap();
+    let marker_indices: Vec<_> = profiling_data
+        .iter()
+        .filter(|event| {
+            profiling_data.to_full_event(event).event_kind == TIMESTAMP_EPOCH_EVENT_KIND
+        })
+        .map(|event| event.event_index as u64)
+        .collect();
+    assert_eq!(index, marker_indices);
+
+    let with_index = |index: Option<&[u64]>| {
+        let mut rewritten = data[..FILE_HEADER_SIZE].to_vec();
+        for (tag, page_contents) in iter_pages(&data[FILE_HEADER_SIZE..]) {
+            if tag != PageTag::TimestampEpochIndex {
+                write_page_to(&mut rewritten, tag, page_contents).unwrap();
+            }
+        }
+        if let Some(index) = index {
+            let mut stream = Vec::new();
+            write_file_header(&mut stream, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX).unwrap();
+            for event_index in index {
+                stream.extend_from_slice(&event_index.to_le_bytes());
+            }
+            write_page_to(&mut rewritten, PageTag::TimestampEpochIndex, &stream).unwrap();
+        }
+        rewritten
+    };
+    let events = |data: Vec<u8>| {
+        let streamed: Vec<_> = EventStream::new(std::io::Cursor::new(&data), None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let loaded: Vec<_> = ProfilingData::from_paged_buffer(data, None)
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(streamed, loaded);
+        loaded
+    };
+
+    let expected: Vec<_> = profiling_data.iter().collect();
+    assert_eq!(events(data.clone()), expected);
+    assert_eq!(events(with_index(None)), expected);
+    let shifted: Vec<_> = marker_indices
+        .iter()
+        .map(|&event_index| event_index + 1)
+        .collect();
+    assert_eq!(events(with_index(Some(&shifted))), expected);
+    // Entries past the last event belong to pages that have been lost.
+    let mut past_the_end = marker_indices.clone();
+    past_the_end.push(profiling_data.num_events() as u64);
+    assert_eq!(events(with_index(Some(&past_the_end))), expected);
+}
+
+fn pseudo_invocation(
+    profiler: &Profiler,
+    random: usize,
+    thread_id: u32,
+    recursions_left: usize,
+    event_ids: &[(StringId, EventId)],
+    expected_events_templates: &[ExpectedEvent],
+    expected_events: &mut Vec<Event<'static>>,
+) {
+    if recursions_left == 0 {
+        return;
+    }
+
+    let random_event_index = random % event_ids.len();
+
+    let (event_kind, event_id) = event_ids[random_event_index];
+
+    let _prof_guard = profiler.start_recording_interval_event(event_kind, event_id, thread_id);
+
+    pseudo_integer_event(
+        profiler,
+        random * 7,
+        thread_id,
+        event_ids,
+        expected_events_templates,
+        expected_events,
+    );
+
+    pseudo_invocation(
+        profiler,
+        random * 17,
+        thread_id,
+        recursions_left - 1,
+        event_ids,
+        expected_events_templates,
+        expected_events,
+    );
+
+    pseudo_instant_event(
+        profiler,
+        random * 23,
+        thread_id,
+        event_ids,
+        expected_events_templates,
+        expected_events,
+    );
+
+    expected_events.push(Event {
+        event_kind: expected_events_templates[random_event_index].kind.clone(),
+        label: expected_events_templates[random_event_index].label.clone(),
+        category: expected_events_templates[random_event_index]
+            .category
+            .clone(),
+        additional_data: expected_events_templates[random_event_index].args.clone(),
+        integer_args: expected_events_templates[random_event_index]
+            .integer_args
+            .clone(),
+        backtrace: Vec::new(),
+        thread_id,
+        depth: None,
+        // We can't test the actual timestamp value, so we just assign
+        // SystemTime::UNIX_EPOCH to everything.
+        payload: EventPayload::Timestamp(Timestamp::Interval {
+            start: SystemTime::UNIX_EPOCH,
+            end: SystemTime::UNIX_EPOCH,
+        }),
+    });
+}
+
+fn pseudo_integer_event(
+    profiler: &Profiler,
+    random: usize,
+    thread_id: u32,
+    event_ids: &[(StringId, EventId)],
+    expected_events_templates: &[ExpectedEvent],
+    expected_events: &mut Vec<Event<'static>>,
+) {
+    let random_event_index = random % event_ids.len();
+
+    let payload_value = random as u64 * 33;
+
+    let (event_kind, event_id) = event_ids[random_event_index];
+    profiler.record_integer_event(event_kind, event_id, thread_id, payload_value);
+
+    expected_events.push(Event {
+        event_kind: expected_events_templates[random_event_index].kind.clone(),
+        label: expected_events_templates[random_event_index].label.clone(),
+        category: expected_events_templates[random_event_index]
+            .category
+            .clone(),
+        additional_data: expected_events_templates[random_event_index].args.clone(),
+        integer_args: expected_events_templates[random_event_index]
+            .integer_args
+            .clone(),
+        backtrace: Vec::new(),
+        thread_id,
+        depth: None,
+        payload: EventPayload::Integer(payload_value),
+    });
+}
+
+fn pseudo_instant_event(
+    profiler: &Profiler,
+    random: usize,
+    thread_id: u32,
+    event_ids: &[(StringId, EventId)],
+    expected_events_templates: &[ExpectedEvent],
+    expected_events: &mut Vec<Event<'static>>,
+) {
+    let random_event_index = random % event_ids.len();
+
+    let (event_kind, event_id) = event_ids[random_event_index];
+    profiler.record_instant_event(event_kind, event_id, thread_id, None);
+
+    expected_events.push(Event {
+        event_kind: expected_events_templates[random_event_index].kind.clone(),
+        label: expected_events_templates[random_event_index].label.clone(),
+        category: expected_events_templates[random_event_index]
+            .category
+            .clone(),
+        additional_data: expected_events_templates[random_event_index].args.clone(),
+        integer_args: expected_events_templates[random_event_index]
+            .integer_args
+            .clone(),
+        backtrace: Vec::new(),
+        thread_id,
+        depth: None,
+        // We can't test the actual timestamp value, so we just assign
+        // SystemTime::UNIX_EPOCH to everything.
+        payload: EventPayload::Timestamp(Timestamp::Instant(SystemTime::UNIX_EPOCH)),
+    });
+}
+
+/// Checks that events recorded with explicit timestamps keep them, including
+/// timestamps that need epoch markers and events that are recorded slightly
+/// out of order, and that the timestamps that can't be recorded are rejected.
+pub fn run_explicit_timestamps_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    // The clock is never read for the events below.
+    let profiler = Profiler::with_clock(&filestem, Box::new(ManualClock::default())).unwrap();
+
+    let event_kind = profiler.alloc_string("Replayed");
+    let event_id = |label: &str| EventId::from_label(profiler.alloc_string(label));
+
+    // The expected label, thread id, start and end of each event.
+    let mut expected = vec![
+        ("first", 0, 100, 200),
+        ("out of order", 0, 50, 150),
+        (
+            "after wrap-around",
+            1,
+            TIMESTAMP_PERIOD + 10,
+            TIMESTAMP_PERIOD + 50,
+        ),
+        (
+            "before wrap-around",
+            1,
+            TIMESTAMP_PERIOD - 100,
+            TIMESTAMP_PERIOD - 10,
+        ),
+        ("long", 0, TIMESTAMP_PERIOD, 2 * TIMESTAMP_PERIOD - 1),
+    ];
+    for &(label, thread_id, start, end) in &expected {
+        profiler
+            .record_event_with_timestamps(event_kind, event_id(label), thread_id, start, end)
+            .unwrap();
+    }
+    let instant = ("instant", 1, 2 * TIMESTAMP_PERIOD, 2 * TIMESTAMP_PERIOD);
+    profiler
+        .record_instant_event_with_timestamp(event_kind, event_id(instant.0), 1, instant.2)
+        .unwrap();
+    expected.push(instant);
+
+    let invalid = event_id("invalid");
+    for &(start, end) in &[
+        // Ends before it starts.
+        (3 * TIMESTAMP_PERIOD, 3 * TIMESTAMP_PERIOD - 1),
+        // Too long.
+        (2 * TIMESTAMP_PERIOD, 3 * TIMESTAMP_PERIOD),
+        // Too far before the events that have already been recorded.
+        (100, 200),
+    ] {
+        assert!(profiler
+            .record_event_with_timestamps(event_kind, invalid, 0, start, end)
+            .is_err());
+    }
+    assert!(profiler
+        .record_instant_event_with_timestamp(event_kind, invalid, 0, 100)
+        .is_err());
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    let start_time = profiling_data.metadata().start_time;
+    let time = |nanos: u64| start_time + Duration::from_nanos(nanos);
+
+    let actual: Vec<_> = profiling_data
+        .iter_full()
+        .filter(|event| event.event_kind != TIMESTAMP_EPOCH_EVENT_KIND)
+        .map(|event| {
+            let timestamp = event.payload.timestamp().unwrap();
+            (
+                event.label.into_owned(),
+                event.thread_id,
+                timestamp.start(),
+                timestamp.end(),
+            )
+        })
+        .collect();
+
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|&(label, thread_id, start, end)| {
+            (label.to_string(), thread_id, time(start), time(end))
+        })
+        .collect();
+
+    assert_eq!(actual, expected);
+}
+
+pub fn run_args_budget_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let options = ProfilerOptions {
+        // The metadata that the profiler writes on its own counts as well.
+        max_string_bytes: Some(16 * 1024),
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+    let builder = EventIdBuilder::new(&profiler);
+    let event_kind = profiler.alloc_string("Query");
+    let label = profiler.alloc_string("typeck");
+
+    // The last of these events only gets its argument if the budget is
+    // exceeded by the event id that the builder allocates for it, not by the
+    // argument itself, which the builder checks right after.
+    let mut num_events = 0;
+    let mut num_with_args = 0;
+    while profiler.records_args() {
+        let arg = profiler.alloc_string(&format!("argument {}", num_events)[..]);
+        if profiler.records_args() {
+            num_with_args += 1;
+        }
+        let event_id = builder.from_label_and_arg(label, arg);
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        num_events += 1;
+    }
+    assert!(num_with_args > 0);
+
+    for _ in 0..3 {
+        let event_id = builder.from_label_and_arg(label, StringId::INVALID);
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+    }
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    let args_dropped_since = profiling_data.args_dropped_since().unwrap();
+
+    let events: Vec<_> = profiling_data
+        .iter_full()
+        .filter(|event| event.event_kind == "Query")
+        .collect();
+    assert_eq!(events.len(), num_events + 3);
+    for (i, event) in events.iter().enumerate() {
+        assert_eq!(event.label, "typeck");
+        if i < num_with_args {
+            assert_eq!(event.additional_data, vec![format!("argument {}", i)]);
+        } else {
+            assert!(event.additional_data.is_empty());
+            assert!(event.payload.timestamp().unwrap().start() >= args_dropped_since);
+        }
+    }
+
+    // Without a budget, arguments are never dropped.
+    let filestem = mk_filestem(&format!("{}_unlimited", file_name_stem));
+    let profiler = Profiler::new(&filestem).unwrap();
+    let builder = EventIdBuilder::new(&profiler);
+    let event_kind = profiler.alloc_string("Query");
+    for i in 0..100 {
+        let arg = profiler.alloc_string(&format!("argument {}", i)[..]);
+        let event_id = builder.from_label_and_arg(profiler.alloc_string("typeck"), arg);
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+    }
+    assert!(profiler.records_args());
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert_eq!(profiling_data.args_dropped_since(), None);
+    assert!(profiling_data
+        .iter_full()
+        .all(|event| event.additional_data.len() == 1));
+}
+
+pub fn run_open_ended_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        min_duration_nanos: 15,
+        ..Default::default()
+    };
+    let clock = Counter::Clock(Box::new(SteppingClock::default()));
+    let profiler = Profiler::with_options(&filestem, clock, options).unwrap();
+    let event_kind = profiler.alloc_string("Resource");
+    let event_id = |label| EventId::from_label(profiler.alloc_string(label));
+
+    // Each read of the clock advances it by 10ns.
+    let lock = profiler.open_ended_span(event_kind, event_id("lock"));
+    let thread_id = lock.thread_id();
+    let connection = profiler.open_ended_span(event_kind, event_id("connection"));
+    // Closed spans are kept even if they are shorter than the minimum.
+    profiler.close_span(connection);
+    drop(profiler.start_recording_interval_event(event_kind, event_id("short"), thread_id));
+    let outer = profiler.start_recording_interval_event(event_kind, event_id("outer"), thread_id);
+    let held = profiler.open_ended_span(event_kind, event_id("held"));
+    drop(outer);
+    profiler.record_instant_event(event_kind, event_id("last"), thread_id, None);
+    // Spans that aren't open-ended are lost if they are never closed.
+    drop(profiler.open_span(event_kind, event_id("lost")));
+    drop((lock, held));
+    drop(profiler);
+
+    let data = ProfilingData::new(&filestem).unwrap();
+    let start_time = data.metadata().start_time;
+    let interval = |event: &LightweightEvent| {
+        let timestamp = event.timestamp().unwrap();
+        (
+            data.to_full_event(event).label.into_owned(),
+            timestamp
+                .start()
+                .duration_since(start_time)
+                .unwrap()
+                .as_nanos(),
+            timestamp
+                .end()
+                .duration_since(start_time)
+                .unwrap()
+                .as_nanos(),
+        )
+    };
+    let unclosed: Vec<_> = data.unclosed_intervals().iter().map(interval).collect();
+    assert_eq!(
+        unclosed,
+        vec![("held".to_string(), 60, 80), ("lock".to_string(), 0, 80)]
+    );
+    let recorded: Vec<_> = data
+        .iter()
+        .filter(|event| event.payload.is_interval())
+        .map(|event| interval(&event))
+        .collect();
+    assert_eq!(
+        recorded,
+        vec![
+            ("connection".to_string(), 10, 20),
+            ("outer".to_string(), 50, 70)
+        ]
+    );
+
+    // The unclosed spans last until the end of the profile.
+    let call_tree = data.call_tree(thread_id);
+    assert_eq!(call_tree.children.len(), 1);
+    let lock = &call_tree.children[0];
+    assert_eq!(lock.label, "lock");
+    assert_eq!(lock.duration, Duration::from_nanos(80));
+    let children: Vec<_> = lock.children.iter().map(|c| &c.label[..]).collect();
+    assert_eq!(children, vec!["connection", "outer", "held"]);
+    assert_eq!(lock.self_duration, Duration::from_nanos(80 - 10 - 20 - 20));
+    let stacks = crate::collapse_stacks(&data);
+    assert_eq!(stacks["rustc;lock"], 80 - 10 - 20 - 20);
+    assert_eq!(stacks["rustc;lock;held"], 20);
+
+    // Leaving a span open isn't a problem in itself, but outliving the span
+    // it has been opened in is.
+    let errors = data.validate();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].label, "held");
+    assert!(matches!(
+        errors[0].kind,
+        ValidationErrorKind::Overlap { ref other_label, .. } if other_label == "outer"
+    ));
+}
+
+pub fn run_append_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+    let _ = fs::remove_file(segment_file_path(&filestem, 0));
+    let options = || ProfilerOptions {
+        record_nesting_depth: true,
+        ..Default::default()
+    };
+
+    // The first session creates the profile.
+    let profiler = Profiler::open(&filestem, options()).unwrap();
+    let event_kind = profiler.alloc_string("Query");
+    let first = profiler.alloc_string("first session");
+    let first_id = EventId::from_label(first);
+    profiler.record_instant_event(event_kind, first_id, 0, None);
+    let _guard = profiler.start_recording_interval_event(event_kind, first_id, 0);
+    drop(_guard);
+    drop(profiler);
+    assert!(!ProfilingData::new(&filestem).unwrap().is_resumed());
+
+    // The second session reuses the strings of the first one by their ids
+    // and adds new ones, which must not collide with them.
+    let profiler = Profiler::open(&filestem, options()).unwrap();
+    let second = profiler.alloc_string("second session");
+    assert!(second.as_u32() > first.as_u32());
+    profiler.record_instant_event(event_kind, first_id, 1, None);
+    let builder = EventIdBuilder::new(&profiler);
+    let arg = builder.alloc_arg("some arg");
+    let _guard = profiler.start_recording_interval_event(
+        event_kind,
+        builder.from_label_and_arg(second, arg),
+        1,
+    );
+    drop(_guard);
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert!(profiling_data.is_resumed());
+    let events: Vec<_> = profiling_data.iter_full().collect();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| {
+            (
+                &event.event_kind[..],
+                &event.label[..],
+                event.thread_id,
+                event.payload.is_interval(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("Query", "first session", 0, false),
+            ("Query", "first session", 0, true),
+            ("Query", "first session", 1, false),
+            ("Query", "second session", 1, true),
+        ]
+    );
+    assert_eq!(events[3].additional_data, vec!["some arg"]);
+
+    // The timestamps of the second session continue after the first one.
+    let end_of_first_session = events[1].payload.timestamp().unwrap().end();
+    assert!(events[2].payload.timestamp().unwrap().start() >= end_of_first_session);
+
+    // Options that change the file format can't be mixed.
+    assert!(Profiler::open(&filestem, ProfilerOptions::default()).is_err());
+}
+
+pub fn run_labels_only_test(file_name_stem: &str) {
+    fn record_events(profiler: &Profiler) {
+        let builder = EventIdBuilder::new(profiler);
+        let event_kind = profiler.alloc_string("Query");
+        let label = profiler.alloc_string("typeck");
+        let category = profiler.alloc_string("rustc");
+
+        for i in 0..100 {
+            let arg = builder.alloc_arg(&format!("argument {}", i));
+            profiler.record_instant_event(
+                event_kind,
+                builder.from_label_and_arg(label, arg),
+                0,
+                None,
+            );
+            profiler.record_instant_event(
+                event_kind,
+                builder.from_label_and_int_arg(label, i),
+                0,
+                None,
+            );
+            profiler.record_instant_event(
+                event_kind,
+                builder.from_label_category_and_args(label, category, &[arg, arg]),
+                0,
+                None,
+            );
+        }
+    }
+
+    let filestem = mk_filestem(file_name_stem);
+    let options = ProfilerOptions {
+        labels_only: true,
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&filestem, Counter::WallTime(WallTime::new()), options).unwrap();
+    assert!(!profiler.records_args());
+    let initial_strings = profiler.stats().strings;
+    record_events(&profiler);
+    let labels_only_strings = profiler.stats().strings;
+    // Only the event kind, the label and the category are allocated, none of
+    // the arguments.
+    assert_eq!(labels_only_strings, initial_strings + 3);
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert!(profiling_data.is_labels_only());
+    // No marker is recorded, unlike when a string budget is exceeded.
+    assert_eq!(profiling_data.args_dropped_since(), None);
+    let events: Vec<_> = profiling_data.iter_full().collect();
+    assert_eq!(events.len(), 300);
+    for event in &events {
+        assert_eq!(event.label, "typeck");
+        assert!(event.category.is_none());
+        assert!(event.additional_data.is_empty());
+    }
+
+    // The same events with their arguments take up far more strings.
+    let filestem = mk_filestem(&format!("{}_full", file_name_stem));
+    let profiler = Profiler::new(&filestem).unwrap();
+    record_events(&profiler);
+    assert!(profiler.stats().strings > labels_only_strings + 200);
+    drop(profiler);
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert!(!profiling_data.is_labels_only());
+    assert!(profiling_data
+        .iter_full()
+        .all(|event| !event.additional_data.is_empty()));
 }
@@ -0,0 +1,397 @@
+use crate::{EventPayload, LightweightEvent, ProfilingData, ProfilingDataBuilder, Timestamp};
+use measureme::file_header::{FILE_FLAG_NESTING_DEPTH, FILE_FLAG_TRACE_CONTEXT};
+use measureme::{
+    COUNTER_UNAVAILABLE_EVENT_KIND, PARENT_EVENT_ID_EVENT_KIND, TRACE_CONTEXT_EVENT_KIND,
+    WALL_TIME_EVENT_KIND,
+};
+use rustc_hash::FxHashMap;
+use std::cmp;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// A window of time within a profile, in nanoseconds since the start of the
+/// profile. Parsed from `<start>:<end>` with both given in (fractional)
+/// seconds, e.g. `120:122.5`. Either may be omitted, e.g. `:10` for the first
+/// ten seconds of the profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start_nanos: u64,
+    pub end_nanos: u64,
+}
+
+impl FromStr for TimeRange {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<TimeRange, Self::Err> {
+        let invalid = |reason: &str| -> Self::Err {
+            From::from(format!("Invalid time range `{}`: {}", s, reason))
+        };
+
+        let (start, end) = match s.find(':') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => return Err(invalid("expected `<start>:<end>`")),
+        };
+
+        let nanos = |seconds: &str, default: u64| -> Result<u64, Self::Err> {
+            if seconds.is_empty() {
+                return Ok(default);
+            }
+
+            match seconds.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => Ok((seconds * 1e9).round() as u64),
+                _ => Err(invalid("expected a non-negative number of seconds")),
+            }
+        };
+
+        let range = TimeRange {
+            start_nanos: nanos(start, 0)?,
+            end_nanos: nanos(end, u64::MAX)?,
+        };
+
+        if range.start_nanos >= range.end_nanos {
+            return Err(invalid("the start must be before the end"));
+        }
+
+        Ok(range)
+    }
+}
+
+/// The window of time of `events_in_range`, as points in time.
+struct Window {
+    start: SystemTime,
+    /// `None` if the end of the window is too far off to be represented,
+    /// i.e. if the window extends to the end of the profile.
+    end: Option<SystemTime>,
+}
+
+impl Window {
+    fn new(start_time: SystemTime, start_nanos: u64, end_nanos: u64) -> Window {
+        let add = |nanos| start_time.checked_add(Duration::from_nanos(nanos));
+        Window {
+            start: add(start_nanos).unwrap_or(start_time),
+            end: add(end_nanos),
+        }
+    }
+
+    fn contains(&self, time: SystemTime) -> bool {
+        match self.end {
+            Some(end) => self.start <= time && time < end,
+            None => self.start <= time,
+        }
+    }
+
+    /// The part of `payload` that lies within the window, `None` if there is
+    /// none. Integer events don't have a timestamp, so they never do.
+    fn clip(&self, payload: EventPayload) -> Option<EventPayload> {
+        match payload {
+            EventPayload::Timestamp(Timestamp::Interval { start, end }) => {
+                let end = match self.end {
+                    Some(window_end) if start >= window_end => return None,
+                    Some(window_end) => cmp::min(end, window_end),
+                    None => end,
+                };
+                if end <= self.start {
+                    return None;
+                }
+
+                Some(EventPayload::Timestamp(Timestamp::Interval {
+                    start: cmp::max(start, self.start),
+                    end,
+                }))
+            }
+            EventPayload::Timestamp(Timestamp::Instant(time))
+            | EventPayload::InstantWithValue { time, .. } => {
+                if self.contains(time) {
+                    Some(payload)
+                } else {
+                    None
+                }
+            }
+            EventPayload::Integer(_) => None,
+        }
+    }
+}
+
+impl ProfilingData {
+    /// Returns the events that overlap the window from `start_nanos`
+    /// (inclusive) to `end_nanos` (exclusive), in nanoseconds since the start
+    /// of the profile. Interval events are clipped to the window, so that
+    /// nested events stay nested and self times computed from them only
+    /// account for the time within the window. Integer events don't have a
+    /// timestamp and are never returned.
+    ///
+    /// Only the timestamps of the events are decoded, so skipping the events
+    /// outside of the window is cheap.
+    pub fn events_in_range<'a>(
+        &'a self,
+        start_nanos: u64,
+        end_nanos: u64,
+    ) -> impl DoubleEndedIterator<Item = LightweightEvent> + 'a {
+        let window = Window::new(self.metadata().start_time, start_nanos, end_nanos);
+
+        self.iter().filter_map(move |mut event| {
+            event.payload = window.clip(event.payload)?;
+            Some(event)
+        })
+    }
+
+    /// Creates a profile that only contains the events of the window from
+    /// `start_nanos` to `end_nanos`, clipped like `events_in_range` clips
+    /// them, so that tools can analyze a window of time in the same way as a
+    /// whole profile. The events are copied as they have been recorded, with
+    /// all of their arguments.
+    ///
+    /// Integer events are kept along with the event they belong to: the
+    /// markers that the profiler records next to an interval event, e.g. for
+    /// its explicit parent, wall time or trace context, are kept if the
+    /// interval event is, and other integer events, e.g. artifact sizes, if
+    /// the latest event of their thread before them has been recorded within
+    /// the window.
+    ///
+    /// The start time, the counter, the names of the threads and the process
+    /// metadata are kept, and so is whether symbols are demangled (see
+    /// `demangle_symbols`).
+    pub fn slice_time_range(&self, start_nanos: u64, end_nanos: u64) -> ProfilingData {
+        let start_time = self.metadata().start_time;
+        let window = Window::new(start_time, start_nanos, end_nanos);
+        let nanos = |time: SystemTime| time.duration_since(start_time).unwrap().as_nanos() as u64;
+
+        let mut builder = ProfilingDataBuilder::with_metadata_of(self.metadata());
+        // The copies don't have nesting depths, and `record_trace_context`
+        // sets the flag for trace contexts if any are kept.
+        builder
+            .set_file_flags(self.file_flags & !(FILE_FLAG_NESTING_DEPTH | FILE_FLAG_TRACE_CONTEXT));
+
+        let mut string_ids = FxHashMap::default();
+        // For each thread, whether its latest interval event has been kept,
+        // and the time its latest timestamped event has been recorded at.
+        let mut threads: FxHashMap<u32, (bool, SystemTime)> = FxHashMap::default();
+
+        for event in self.iter() {
+            let (interval_kept, latest_time) = threads
+                .entry(event.thread_id)
+                .or_insert((false, start_time));
+
+            let payload = match event.payload {
+                EventPayload::Integer(_) => {
+                    let full_event = self.decode_recorded_event(event.event_index);
+                    let event_kind = &full_event.event_kind[..];
+                    if event_kind == PARENT_EVENT_ID_EVENT_KIND {
+                        // The marker comes right before its interval event,
+                        // which the epoch marker that the interval event may
+                        // need must not come between.
+                        let next = event.event_index + 1;
+                        if next == self.num_events() {
+                            continue;
+                        }
+                        let interval = self.decode_lightweight_event(next);
+                        match window.clip(interval.payload) {
+                            Some(payload)
+                                if interval.thread_id == event.thread_id
+                                    && payload.is_interval() =>
+                            {
+                                let end = payload.timestamp().unwrap().end();
+                                builder.check_timestamp_epoch(nanos(end), event.thread_id);
+                                event.payload
+                            }
+                            _ => continue,
+                        }
+                    } else if event_kind == TRACE_CONTEXT_EVENT_KIND {
+                        match self.marked_trace_context(&full_event) {
+                            Some(trace_context) if *interval_kept => {
+                                EventPayload::Integer(builder.record_trace_context(trace_context))
+                            }
+                            _ => continue,
+                        }
+                    } else if event_kind == WALL_TIME_EVENT_KIND
+                        || event_kind == COUNTER_UNAVAILABLE_EVENT_KIND
+                    {
+                        if !*interval_kept {
+                            continue;
+                        }
+                        event.payload
+                    } else {
+                        if !window.contains(*latest_time) {
+                            continue;
+                        }
+                        event.payload
+                    }
+                }
+                payload => {
+                    let timestamp = payload.timestamp().unwrap();
+                    *latest_time = timestamp.end();
+                    let clipped = window.clip(payload);
+                    if payload.is_interval() {
+                        *interval_kept = clipped.is_some();
+                    }
+                    match clipped {
+                        Some(clipped) => clipped,
+                        None => continue,
+                    }
+                }
+            };
+
+            builder.copy_raw_event(&mut string_ids, self, event.event_index, payload);
+        }
+
+        let mut sliced = builder.into_profiling_data();
+        sliced.markers.thread_names = self.markers.thread_names.clone();
+        sliced.markers.args_dropped_at = self.markers.args_dropped_at;
+        sliced.process_metadata = self.process_metadata.clone();
+        if self.demangles_symbols() {
+            sliced.demangle_symbols();
+        }
+        sliced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(nanos: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn events_are_clipped_to_the_range() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "before", 0, 0, 40, |_| {});
+        b.interval("Query", "outer", 0, 30, 200, |b| {
+            b.interval("Query", "inner", 0, 60, 80, |_| {});
+            b.instant("Query", "instant", 0, 90);
+        });
+        b.integer("ArtifactSize", "size", 0, 1234);
+        b.interval("Query", "after", 0, 150, 160, |_| {});
+
+        let data = b.into_profiling_data();
+        let events: Vec<_> = data
+            .events_in_range(50, 100)
+            .map(|e| {
+                (
+                    data.to_full_event(&e).label.into_owned(),
+                    e.timestamp().unwrap(),
+                )
+            })
+            .collect();
+
+        let interval = |start, end| Timestamp::Interval {
+            start: timestamp(start),
+            end: timestamp(end),
+        };
+        assert_eq!(
+            events,
+            vec![
+                ("inner".to_string(), interval(60, 80)),
+                ("instant".to_string(), Timestamp::Instant(timestamp(90))),
+                ("outer".to_string(), interval(50, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn self_times_of_slice() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "parent", 0, 0, 100, |b| {
+            b.interval("Query", "child", 0, 40, 60, |_| {});
+        });
+
+        let results = b
+            .into_profiling_data()
+            .slice_time_range(50, 80)
+            .perform_analysis();
+
+        assert_eq!(results.total_time, Duration::from_nanos(30));
+        assert_eq!(
+            results.query_data_by_label("parent").self_time,
+            Duration::from_nanos(20)
+        );
+        assert_eq!(
+            results.query_data_by_label("child").self_time,
+            Duration::from_nanos(10)
+        );
+    }
+
+    #[test]
+    fn slice_keeps_integer_events_and_markers() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval_with_parent("Query", "early", "parent", 0, 0, 10, |_| {});
+        b.integer("ArtifactSize", "early_size", 0, 1);
+        b.interval_with_parent("Query", "child", "parent", 0, 55, 70, |_| {});
+        b.interval_with_wall_time("Query", "timed", 0, 60, 80, 500, |_| {});
+        b.integer("ArtifactSize", "size", 0, 1234);
+        b.interval("Query", "args\x1Ea\x1E\x153", 0, 85, 90, |_| {});
+        b.interval("Query", "late", 0, 150, 160, |_| {});
+        b.integer("ArtifactSize", "late_size", 0, 2);
+
+        let data = b.into_profiling_data();
+        let sliced = data.slice_time_range(50, 100);
+
+        // Leaves out the markers, which are integer events themselves.
+        let recorded = |data: &ProfilingData, event: &LightweightEvent| {
+            let event = data.to_full_event(event);
+            event.event_kind == "Query" || event.event_kind == "ArtifactSize"
+        };
+
+        let events: Vec<_> = sliced.iter().filter(|e| recorded(&sliced, e)).collect();
+        let labels: Vec<_> = events
+            .iter()
+            .map(|e| sliced.to_full_event(e).label.into_owned())
+            .collect();
+        assert_eq!(labels, ["child", "timed", "size", "args"]);
+
+        assert!(sliced.has_explicit_parents());
+        assert_eq!(
+            sliced.explicit_parent_id_bytes(&events[0]).as_deref(),
+            Some(&b"parent"[..])
+        );
+        assert_eq!(
+            sliced.wall_time(&events[1]),
+            Some(Duration::from_nanos(500))
+        );
+        assert_eq!(events[2].payload, EventPayload::Integer(1234));
+
+        let args = sliced.to_full_event(&events[3]);
+        assert_eq!(args.additional_data, ["a"]);
+        assert_eq!(sliced.omitted_args(&events[3]), 3);
+
+        // A window without an end keeps everything after its start.
+        let sliced = data.slice_time_range(100, u64::MAX);
+        let labels: Vec<_> = sliced
+            .iter()
+            .filter(|e| recorded(&sliced, e))
+            .map(|e| sliced.to_full_event(&e).label.into_owned())
+            .collect();
+        assert_eq!(labels, ["late", "late_size"]);
+    }
+
+    #[test]
+    fn parse_time_range() {
+        let range = |s: &str| s.parse::<TimeRange>().map_err(|e| e.to_string());
+
+        assert_eq!(
+            range("1.5:2"),
+            Ok(TimeRange {
+                start_nanos: 1_500_000_000,
+                end_nanos: 2_000_000_000
+            })
+        );
+        assert_eq!(
+            range(":10"),
+            Ok(TimeRange {
+                start_nanos: 0,
+                end_nanos: 10_000_000_000
+            })
+        );
+        assert_eq!(range("3:").unwrap().end_nanos, u64::MAX);
+
+        assert!(range("2").is_err());
+        assert!(range("2:1").is_err());
+        assert!(range("-1:1").is_err());
+        assert!(range("a:b").is_err());
+    }
+}
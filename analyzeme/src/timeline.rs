@@ -0,0 +1,242 @@
+use crate::{EventSelfTime, ProfilingData, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+
+/// The largest number of buckets that [`ProfilingData::timeline()`] creates,
+/// so that a bucket width that is tiny compared to the length of the profile
+/// is reported instead of exhausting memory.
+pub const MAX_TIMELINE_BUCKETS: usize = 1_000_000;
+
+/// How [`ProfilingData::timeline()`] attributes an interval event that spans
+/// more than one bucket.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BucketAssignment {
+    /// The event is counted in full in the bucket it starts in.
+    Start,
+    /// The event is distributed over the buckets it overlaps, in proportion
+    /// to how much of its duration falls into each of them.
+    Proportional,
+}
+
+/// A fixed-width window of time, see [`ProfilingData::timeline()`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TimelineBucket {
+    /// The start of the bucket, in nanoseconds since the start of the profile.
+    pub start_nanos: u64,
+    /// The number of events in the bucket. Only fractional with
+    /// `BucketAssignment::Proportional`.
+    pub events: f64,
+    /// The summed self time of the interval events in the bucket, if it has
+    /// been requested.
+    pub self_time: Option<Duration>,
+}
+
+impl ProfilingData {
+    /// Sorts all interval and instant events into consecutive buckets that
+    /// are `bucket_nanos` wide, starting at the start of the profile, e.g. to
+    /// plot how many events per 100ms a build processes and spot where it
+    /// stalls. Integer events don't have a timestamp and are left out.
+    ///
+    /// The buckets are returned in order, up to the last one that contains an
+    /// event, including those that are empty. If `self_times` are given, i.e.
+    /// the result of [`ProfilingData::self_times()`], they are summed up per
+    /// bucket as well. Self times are attributed to buckets like the events
+    /// themselves, so with `BucketAssignment::Proportional` they are assumed
+    /// to be spread evenly over the duration of the event.
+    ///
+    /// Returns an error if the events span more than `MAX_TIMELINE_BUCKETS`
+    /// buckets.
+    pub fn timeline(
+        &self,
+        bucket_nanos: u64,
+        assignment: BucketAssignment,
+        self_times: Option<&[EventSelfTime]>,
+    ) -> Result<Vec<TimelineBucket>, Box<dyn Error + Send + Sync>> {
+        assert!(bucket_nanos > 0, "the buckets of a timeline can't be empty");
+
+        let start_time = self.metadata().start_time;
+        let nanos = |time: SystemTime| {
+            time.duration_since(start_time)
+                .unwrap_or(Duration::from_secs(0))
+                .as_nanos() as u64
+        };
+
+        // Both are ordered by event index, so the self times can be consumed
+        // while walking the events.
+        let with_self_time = self_times.is_some();
+        let mut self_times = self_times.unwrap_or_default().iter().peekable();
+
+        let mut events = Vec::<f64>::new();
+        let mut self_nanos = Vec::<f64>::new();
+        let add = |buckets: &mut Vec<f64>, bucket: usize, amount: f64| {
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0.0);
+            }
+            buckets[bucket] += amount;
+        };
+
+        for event in self.iter() {
+            let (start, end) = match event.timestamp() {
+                Some(Timestamp::Interval { start, end }) => (nanos(start), nanos(end)),
+                Some(Timestamp::Instant(time)) => (nanos(time), nanos(time)),
+                None => continue,
+            };
+
+            let mut self_time = 0.0;
+            while let Some(t) = self_times.peek() {
+                if t.event_index > event.event_index {
+                    break;
+                }
+                if t.event_index == event.event_index {
+                    self_time = t.self_duration.as_nanos() as f64;
+                }
+                self_times.next();
+            }
+
+            // The end is exclusive, so an event that ends exactly at the
+            // boundary of a bucket doesn't overlap the next one.
+            let first = start / bucket_nanos;
+            let last = if assignment == BucketAssignment::Start || end == start {
+                first
+            } else {
+                (end - 1) / bucket_nanos
+            };
+            if last >= MAX_TIMELINE_BUCKETS as u64 {
+                let msg = format!(
+                    "A timeline with buckets of {}ns would have more than {} buckets",
+                    bucket_nanos, MAX_TIMELINE_BUCKETS
+                );
+                return Err(From::from(msg));
+            }
+
+            let (first, last) = (first as usize, last as usize);
+            if first == last {
+                add(&mut events, first, 1.0);
+                add(&mut self_nanos, first, self_time);
+                continue;
+            }
+
+            let duration = (end - start) as f64;
+            for bucket in first..=last {
+                let bucket_start = bucket as u64 * bucket_nanos;
+                let overlap = end.min(bucket_start + bucket_nanos) - start.max(bucket_start);
+                let share = overlap as f64 / duration;
+                add(&mut events, bucket, share);
+                add(&mut self_nanos, bucket, self_time * share);
+            }
+        }
+
+        self_nanos.resize(events.len(), 0.0);
+        Ok(events
+            .into_iter()
+            .zip(self_nanos)
+            .enumerate()
+            .map(|(bucket, (events, self_nanos))| TimelineBucket {
+                start_nanos: bucket as u64 * bucket_nanos,
+                events,
+                self_time: if with_self_time {
+                    Some(Duration::from_nanos(self_nanos.round() as u64))
+                } else {
+                    None
+                },
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    fn profile() -> ProfilingData {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "q1", 0, 0, 200, |b| {
+            b.interval("Query", "q2", 0, 50, 150, |_| {});
+        });
+        b.instant("Query", "i1", 1, 120);
+        b.integer("ArtifactSize", "a1", 1, 1000);
+        b.interval("Query", "q3", 1, 300, 400, |_| {});
+
+        b.into_profiling_data()
+    }
+
+    fn summary(buckets: &[TimelineBucket]) -> Vec<(u64, f64, Option<u64>)> {
+        buckets
+            .iter()
+            .map(|b| {
+                (
+                    b.start_nanos,
+                    b.events,
+                    b.self_time.map(|t| t.as_nanos() as u64),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_are_counted_at_their_start() {
+        let timeline = profile()
+            .timeline(100, BucketAssignment::Start, None)
+            .unwrap();
+
+        assert_eq!(
+            summary(&timeline),
+            vec![
+                (0, 2.0, None),
+                (100, 1.0, None),
+                (200, 0.0, None),
+                (300, 1.0, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn events_are_distributed_proportionally() {
+        let data = profile();
+        let timeline = data
+            .timeline(
+                100,
+                BucketAssignment::Proportional,
+                Some(&data.self_times()),
+            )
+            .unwrap();
+
+        // `q1` and `q2` are half in the first and half in the second bucket,
+        // and so are their self times. `q3` ends exactly at the end of the
+        // last bucket, so it doesn't spill over into the next one.
+        assert_eq!(
+            summary(&timeline),
+            vec![
+                (0, 1.0, Some(100)),
+                (100, 2.0, Some(100)),
+                (200, 0.0, Some(0)),
+                (300, 1.0, Some(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_buckets() {
+        let end_nanos = MAX_TIMELINE_BUCKETS as u64 * 10;
+        let mut b = ProfilingDataBuilder::new();
+        b.instant("Query", "i1", 0, 0);
+        b.interval("Query", "q1", 0, 0, end_nanos + 1, |_| {});
+        let data = b.into_profiling_data();
+
+        // The interval starts in the first bucket, but ends in the one after
+        // the last allowed bucket.
+        assert!(data.timeline(10, BucketAssignment::Start, None).is_ok());
+        assert!(data
+            .timeline(10, BucketAssignment::Proportional, None)
+            .is_err());
+        assert_eq!(
+            data.timeline(20, BucketAssignment::Proportional, None)
+                .unwrap()
+                .len(),
+            MAX_TIMELINE_BUCKETS / 2 + 1
+        );
+    }
+}
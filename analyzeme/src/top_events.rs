@@ -0,0 +1,108 @@
+//! Finding the longest individual interval events of a profile.
+
+use crate::{Event, ProfilingData};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{Duration, SystemTime};
+
+/// Orders events by their duration, and events with the same duration by
+/// their start, thread id and index in reverse, so that the greatest key is
+/// the longest, earliest event.
+type TopEventKey = (Duration, Reverse<SystemTime>, Reverse<u32>, Reverse<usize>);
+
+impl ProfilingData {
+    /// Returns the `n` interval events with the longest durations, longest
+    /// first, e.g. for a quick look at what has taken the most time without
+    /// aggregating the events by label. Events with the same duration are
+    /// ordered by their start, then by thread id and then in the order they
+    /// have been recorded in, so the result is the same for the same profile.
+    ///
+    /// The events are found in a single pass over the profile that only
+    /// keeps the `n` longest events seen so far, and only those are decoded
+    /// into full events.
+    pub fn top_events_by_duration(&self, n: usize) -> Vec<Event<'_>> {
+        if n == 0 {
+            return Vec::new();
+        }
+
+        // A min-heap of the longest events seen so far, whose top is the one
+        // to drop once a longer one comes along.
+        let mut top =
+            BinaryHeap::<Reverse<TopEventKey>>::with_capacity(n.min(self.num_events()) + 1);
+
+        for event in self.iter() {
+            let timestamp = match event.timestamp() {
+                Some(timestamp) if event.payload.is_interval() => timestamp,
+                _ => continue,
+            };
+
+            top.push(Reverse((
+                timestamp.duration().unwrap_or_default(),
+                Reverse(timestamp.start()),
+                Reverse(event.thread_id),
+                Reverse(event.event_index),
+            )));
+            if top.len() > n {
+                top.pop();
+            }
+        }
+
+        // The ascending order of the reversed keys is the descending order of
+        // the keys.
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((_, _, _, Reverse(event_index)))| {
+                self.to_full_event(&self.decode_lightweight_event(event_index))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ProfilingDataBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn longest_interval_events() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 0, 100, |b| {
+            b.interval("Query", "first tie", 0, 10, 40, |_| {});
+            b.instant("QueryCacheHit", "hit", 0, 45);
+            b.interval("Query", "later tie", 0, 50, 80, |_| {});
+        });
+        b.interval("Query", "other thread", 1, 10, 40, |_| {});
+        b.interval("Query", "short", 1, 40, 45, |_| {});
+        b.instant_with_value("Allocation", "bytes", 1, 50, 1000);
+        b.interval("Query", "long", 2, 0, 60, |_| {});
+        let data = b.into_profiling_data();
+
+        let top = |n| {
+            data.top_events_by_duration(n)
+                .into_iter()
+                .map(|event| {
+                    let duration = event.duration().unwrap();
+                    (event.label.into_owned(), event.thread_id, duration)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The events that take 30ns are ordered by their start and then by
+        // their thread id.
+        assert_eq!(
+            top(4),
+            vec![
+                ("outer".to_string(), 0, Duration::from_nanos(100)),
+                ("long".to_string(), 2, Duration::from_nanos(60)),
+                ("first tie".to_string(), 0, Duration::from_nanos(30)),
+                ("other thread".to_string(), 1, Duration::from_nanos(30)),
+            ]
+        );
+
+        // Instant events are never included.
+        assert_eq!(top(10).len(), 6);
+        assert_eq!(top(10)[4].0, "later tie");
+        assert!(top(0).is_empty());
+        assert_eq!(top(usize::MAX).len(), 6);
+    }
+}
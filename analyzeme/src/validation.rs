@@ -0,0 +1,206 @@
+use crate::{ProfilingData, Timestamp};
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::SystemTime;
+
+/// A problem found by `ProfilingData::validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    pub thread_id: u32,
+    /// The label of the offending event.
+    pub label: String,
+    /// The point in time at which the problem occurs: the start of an
+    /// overlapping event, or the end of an event that ends before it starts.
+    pub timestamp: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    /// The event starts within another event on the same thread but ends after
+    /// it, i.e. the two are neither nested nor disjoint.
+    Overlap {
+        other_label: String,
+        other_end: SystemTime,
+    },
+    /// The event's end lies before its start, i.e. it has been stopped without
+    /// having been started first.
+    EndBeforeStart { start: SystemTime },
+}
+
+impl fmt::Display for ValidationErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationErrorKind::Overlap { other_label, .. } => write!(
+                f,
+                "overlaps `{}` without being nested within it",
+                other_label
+            ),
+            ValidationErrorKind::EndBeforeStart { .. } => write!(f, "ends before it starts"),
+        }
+    }
+}
+
+/// An interval event that is still open while the events of its thread are
+/// walked in order of their start.
+struct OpenInterval {
+    event_index: usize,
+    end: SystemTime,
+}
+
+impl ProfilingData {
+    /// Checks that the interval events of each thread form a proper call tree,
+    /// i.e. that any two of them are either disjoint or nested, and that no
+    /// interval ends before it starts. Tools that reconstruct call stacks
+    /// (e.g. `summarize` or `flamegraph`) silently produce nonsensical
+    /// results for profiles where this isn't the case, which usually means
+    /// that start and stop of some event have been mis-paired when recording.
+    ///
+    /// Since the start and the end of an interval are recorded together, a
+    /// mis-paired start or stop always shows up as one of these two problems.
+    /// Spans that have intentionally been left open (see
+    /// [`ProfilingData::unclosed_intervals()`]) aren't a problem, they are
+    /// checked as lasting until the end of the profile. The errors are
+    /// ordered by thread id and then by time.
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut threads = FxHashMap::<u32, Vec<(SystemTime, SystemTime, usize)>>::default();
+        let mut errors = Vec::new();
+
+        let unclosed_intervals = self.unclosed_intervals().iter().cloned();
+        for event in self.iter().chain(unclosed_intervals) {
+            if let Some(Timestamp::Interval { start, end }) = event.payload.timestamp() {
+                if end < start {
+                    errors.push(ValidationError {
+                        kind: ValidationErrorKind::EndBeforeStart { start },
+                        thread_id: event.thread_id,
+                        label: self.to_full_event(&event).label.into_owned(),
+                        timestamp: end,
+                    });
+                } else {
+                    threads.entry(event.thread_id).or_default().push((
+                        start,
+                        end,
+                        event.event_index,
+                    ));
+                }
+            }
+        }
+
+        for (thread_id, mut intervals) in threads.into_iter().collect::<BTreeMap<_, _>>() {
+            // Parents come before their children if they start at the same time.
+            intervals.sort_by(|l, r| l.0.cmp(&r.0).then(r.1.cmp(&l.1)));
+
+            let mut stack: Vec<OpenInterval> = Vec::new();
+
+            for (start, end, event_index) in intervals {
+                while let Some(top) = stack.last() {
+                    if top.end > start {
+                        break;
+                    }
+                    stack.pop();
+                }
+
+                if let Some(top) = stack.last() {
+                    if top.end < end {
+                        errors.push(ValidationError {
+                            kind: ValidationErrorKind::Overlap {
+                                other_label: self
+                                    .decode_full_event(top.event_index)
+                                    .label
+                                    .into_owned(),
+                                other_end: top.end,
+                            },
+                            thread_id,
+                            label: self.decode_full_event(event_index).label.into_owned(),
+                            timestamp: start,
+                        });
+                    }
+                }
+
+                stack.push(OpenInterval { event_index, end });
+            }
+        }
+
+        errors.sort_by(|l, r| {
+            l.thread_id
+                .cmp(&r.thread_id)
+                .then(l.timestamp.cmp(&r.timestamp))
+        });
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+    use std::time::Duration;
+
+    fn timestamp(nanos: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn proper_nesting() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k", "e1", 0, 100, 200, |b| {
+            b.interval("k", "e2", 0, 100, 150, |b| {
+                b.interval("k", "e3", 0, 120, 150, |_| {});
+            });
+            b.interval("k", "e4", 0, 150, 200, |_| {});
+        });
+        b.interval("k", "e5", 0, 200, 300, |_| {});
+        b.instant("k", "i1", 0, 250);
+
+        // Intervals on different threads may overlap.
+        b.interval("k", "e6", 1, 150, 250, |_| {});
+
+        assert_eq!(b.into_profiling_data().validate(), vec![]);
+    }
+
+    #[test]
+    fn overlap() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k", "e1", 0, 100, 200, |_| {});
+        b.interval("k", "e2", 0, 150, 250, |b| {
+            b.interval("k", "e3", 0, 160, 170, |_| {});
+        });
+
+        assert_eq!(
+            b.into_profiling_data().validate(),
+            vec![ValidationError {
+                kind: ValidationErrorKind::Overlap {
+                    other_label: "e1".to_string(),
+                    other_end: timestamp(200),
+                },
+                thread_id: 0,
+                label: "e2".to_string(),
+                timestamp: timestamp(150),
+            }]
+        );
+    }
+
+    #[test]
+    fn end_before_start() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k", "e1", 0, 100, 200, |_| {});
+        b.inverted_interval("k", "e2", 0, 150, 120);
+
+        assert_eq!(
+            b.into_profiling_data().validate(),
+            vec![ValidationError {
+                kind: ValidationErrorKind::EndBeforeStart {
+                    start: timestamp(150)
+                },
+                thread_id: 0,
+                label: "e2".to_string(),
+                timestamp: timestamp(120),
+            }]
+        );
+    }
+}
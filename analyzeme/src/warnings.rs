@@ -0,0 +1,62 @@
+//! The warnings that tools print about the profiles they read, so that all of
+//! them point out the same shortcomings in the same words.
+
+use crate::ProfilingData;
+use std::path::Path;
+
+/// The shortcomings of `data`, read from `path`, that make the results of
+/// tools incomplete or approximate, e.g. that it has been recorded in
+/// sampling mode or that its tail is missing. Empty for a complete profile.
+pub fn profile_warnings(data: &ProfilingData, path: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if data.is_sampled() {
+        warnings.push(format!(
+            "`{}` has been recorded in sampling mode. Short events are missing, so self times are approximate.",
+            path.display()
+        ));
+    }
+
+    if data.truncated_bytes() > 0 {
+        warnings.push(format!(
+            "`{}` is truncated. The last {} bytes are incomplete and have been ignored, so some events are missing.",
+            path.display(),
+            data.truncated_bytes()
+        ));
+    }
+
+    if let Some(dropped_since) = data.args_dropped_since() {
+        let offset = dropped_since
+            .duration_since(data.metadata().start_time)
+            .unwrap_or_default();
+        warnings.push(format!(
+            "`{}` exceeded its string budget. Events recorded after {:.3}s have no arguments.",
+            path.display(),
+            offset.as_secs_f64()
+        ));
+    }
+
+    warnings
+}
+
+/// Prints the `profile_warnings` of `data` to stderr.
+pub fn warn_about_profile(data: &ProfilingData, path: &Path) {
+    for warning in profile_warnings(data, path) {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProfilingDataBuilder;
+
+    #[test]
+    fn complete_profile_has_no_warnings() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 20, |_| {});
+        let data = b.into_profiling_data();
+
+        assert!(profile_warnings(&data, Path::new("profile")).is_empty());
+    }
+}
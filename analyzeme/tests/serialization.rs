@@ -1,4 +1,16 @@
-use analyzeme::testing_common::run_end_to_end_serialization_test;
+use analyzeme::testing_common::{
+    run_append_test, run_args_budget_test, run_counter_unavailable_test,
+    run_custom_event_kind_test, run_end_to_end_serialization_test, run_escaped_text_test,
+    run_explicit_parents_test, run_explicit_timestamps_test, run_in_memory_end_to_end_test,
+    run_incremental_reading_test, run_interval_guard_unwind_test, run_labels_only_test,
+    run_nesting_depth_test, run_non_utf8_label_test, run_omitted_args_test, run_open_ended_test,
+    run_page_size_test, run_process_metadata_test, run_ring_buffer_sink_test,
+    run_ring_buffer_string_budget_test, run_ring_buffer_trace_context_test,
+    run_rotating_files_test, run_rotating_files_virtual_ids_test, run_sampled_profile_test,
+    run_spilling_sink_test, run_string_deduplication_test, run_timestamp_overflow_test,
+    run_trace_context_test, run_truncated_file_test, run_verify_test, run_wall_clock_start_test,
+    run_wall_time_test,
+};
 
 #[test]
 fn test_serialization_sink_1_thread() {
@@ -9,3 +21,177 @@ fn test_serialization_sink_1_thread() {
 fn test_serialization_sink_8_threads() {
     run_end_to_end_serialization_test("serialization_sink_test_8_threads", 8);
 }
+
+#[test]
+fn test_in_memory_sink_8_threads() {
+    run_in_memory_end_to_end_test("in_memory_sink_test_8_threads", 8);
+}
+
+#[test]
+fn test_ring_buffer_sink() {
+    run_ring_buffer_sink_test(1024);
+}
+
+#[test]
+fn test_ring_buffer_sink_trace_contexts() {
+    run_ring_buffer_trace_context_test(1024);
+}
+
+#[test]
+fn test_ring_buffer_sink_string_budget() {
+    run_ring_buffer_string_budget_test(1024);
+}
+
+#[test]
+fn test_spilling_sink_spills_early() {
+    run_spilling_sink_test("spilling_sink_test_early", 64 * 1024);
+}
+
+#[test]
+fn test_spilling_sink_writes_at_drop() {
+    run_spilling_sink_test("spilling_sink_test_at_drop", usize::MAX);
+}
+
+#[test]
+fn test_interval_guard_records_end_event_on_unwind() {
+    run_interval_guard_unwind_test("interval_guard_unwind_test");
+}
+
+#[test]
+fn test_rotating_files() {
+    run_rotating_files_test("rotating_files_test");
+}
+
+#[test]
+fn test_rotating_files_virtual_ids() {
+    run_rotating_files_virtual_ids_test("rotating_files_virtual_ids_test");
+}
+
+#[test]
+fn test_process_metadata() {
+    run_process_metadata_test("process_metadata_test");
+}
+
+#[test]
+fn test_incremental_reading() {
+    run_incremental_reading_test("incremental_reading_test");
+}
+
+#[test]
+fn test_small_pages() {
+    run_page_size_test("small_pages_test", 4096);
+}
+
+#[test]
+fn test_sampled_profile() {
+    run_sampled_profile_test("sampled_profile_test");
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compressed_serialization_sink_8_threads() {
+    analyzeme::testing_common::run_compressed_end_to_end_serialization_test(
+        "compressed_serialization_sink_test_8_threads",
+        8,
+    );
+}
+
+#[test]
+fn test_string_deduplication() {
+    run_string_deduplication_test("string_deduplication_test");
+}
+
+#[test]
+fn test_custom_event_kinds() {
+    run_custom_event_kind_test("custom_event_kind_test");
+}
+
+#[test]
+fn test_non_utf8_labels() {
+    run_non_utf8_label_test("non_utf8_label_test");
+}
+
+#[test]
+fn test_escaped_text() {
+    run_escaped_text_test("escaped_text_test");
+}
+
+#[test]
+fn test_omitted_args() {
+    run_omitted_args_test("omitted_args_test");
+}
+
+#[test]
+fn test_nesting_depth() {
+    run_nesting_depth_test("nesting_depth_test", 4);
+}
+
+#[test]
+fn test_explicit_parents() {
+    run_explicit_parents_test("explicit_parents_test", false);
+}
+
+#[test]
+fn test_explicit_parents_stable_event_order() {
+    run_explicit_parents_test("explicit_parents_stable_event_order_test", true);
+}
+
+#[test]
+fn test_truncated_file() {
+    run_truncated_file_test("truncated_file_test");
+}
+
+#[test]
+fn test_verify() {
+    run_verify_test("verify_test");
+}
+
+#[test]
+fn test_wall_time() {
+    run_wall_time_test("wall_time_test");
+}
+
+#[test]
+fn test_trace_context() {
+    run_trace_context_test("trace_context_test");
+}
+
+#[test]
+fn test_counter_unavailable() {
+    run_counter_unavailable_test("counter_unavailable_test");
+}
+
+#[test]
+fn test_wall_clock_start() {
+    run_wall_clock_start_test("wall_clock_start_test");
+}
+
+#[test]
+fn test_timestamp_overflow() {
+    run_timestamp_overflow_test("timestamp_overflow_test");
+}
+
+#[test]
+fn test_explicit_timestamps() {
+    run_explicit_timestamps_test("explicit_timestamps_test");
+}
+
+#[test]
+fn test_args_budget() {
+    run_args_budget_test("args_budget_test");
+}
+
+#[test]
+fn test_append() {
+    run_append_test("append_test");
+}
+
+#[test]
+fn test_open_ended() {
+    run_open_ended_test("open_ended_test");
+}
+
+#[test]
+fn test_labels_only() {
+    run_labels_only_test("labels_only_test");
+}
@@ -0,0 +1,88 @@
+use analyzeme::ProfilingData;
+use measureme::{EventId, Profiler};
+use std::path::PathBuf;
+
+/// Returns the resident set size of the current process in kilobytes.
+#[cfg(target_os = "linux")]
+fn resident_set_size_kb() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let line = status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .unwrap();
+
+    line["VmRSS:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+// This test lives in its own test binary so that no other tests allocate
+// memory concurrently while the resident set size is being measured.
+#[cfg(target_os = "linux")]
+#[test]
+fn stream_events_has_bounded_memory_usage() {
+    const NUM_EVENTS: u32 = 1_000_000;
+
+    let mut path_stem = PathBuf::from("test-tmp");
+    path_stem.push("stream_events");
+    path_stem.push("large_profile");
+
+    // The profiler writes its pages to the file as it goes, so recording the
+    // profile doesn't allocate memory in proportion to its size either.
+    {
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("Query");
+        let event_id = EventId::from_label(profiler.alloc_string("SomeQuery"));
+        for i in 0..NUM_EVENTS {
+            profiler.record_instant_event(event_kind, event_id, i % 4, None);
+        }
+    }
+
+    let file_size_kb = std::fs::metadata(path_stem.with_extension("mm_profdata"))
+        .unwrap()
+        .len()
+        / 1024;
+
+    let baseline_kb = resident_set_size_kb();
+    let mut peak_kb = baseline_kb;
+    let mut num_events = 0;
+
+    for event in ProfilingData::stream_events(&path_stem).unwrap() {
+        let event = event.unwrap();
+        assert_eq!(event.event_index, num_events as usize);
+        assert_eq!(event.thread_id, num_events % 4);
+
+        num_events += 1;
+        if num_events % 10_000 == 0 {
+            peak_kb = peak_kb.max(resident_set_size_kb());
+        }
+    }
+
+    assert_eq!(num_events, NUM_EVENTS);
+    assert!(
+        peak_kb - baseline_kb < file_size_kb / 8,
+        "resident set size grew by {} kB while streaming a {} kB profile",
+        peak_kb - baseline_kb,
+        file_size_kb
+    );
+
+    // Loading the same profile takes memory in proportion to its size, which
+    // makes sure that the measurement above would have noticed.
+    let profiling_data = ProfilingData::new(&path_stem).unwrap();
+    let loaded_kb = resident_set_size_kb();
+    assert_eq!(profiling_data.num_events(), NUM_EVENTS as usize);
+    assert!(
+        loaded_kb.saturating_sub(baseline_kb) > file_size_kb / 2,
+        "resident set size grew by only {} kB while loading a {} kB profile",
+        loaded_kb.saturating_sub(baseline_kb),
+        file_size_kb
+    );
+
+    let streamed = ProfilingData::stream_events(&path_stem).unwrap();
+    for (streamed, loaded) in streamed.zip(profiling_data.iter()) {
+        assert_eq!(streamed.unwrap(), loaded);
+    }
+}
@@ -0,0 +1,515 @@
+//! The inverse of `crox`: turns a Chrome trace (in the JSON array or object
+//! format of the Trace Event Format) into a `measureme` profile, so that
+//! traces recorded by other tools can be analyzed with `summarize`,
+//! `flamegraph` and the other tools from this repository.
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use measureme::file_header::FILE_EXTENSION;
+use measureme::{EventId, EventIdBuilder, Profiler, StringId, THREAD_NAME_EVENT_KIND};
+
+use clap::Parser;
+use serde_json::Value;
+
+/// The event kind of events without a category.
+const DEFAULT_EVENT_KIND: &str = "Generic";
+
+#[derive(Parser, Debug)]
+struct Opt {
+    /// the Chrome trace to import, e.g. `chrome_profiler.json`
+    trace: PathBuf,
+    /// the path stem of the profile to write, i.e. the path without the
+    /// `.mm_profdata` extension. Defaults to the path of the trace without
+    /// its extension
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// An event of the trace that is recorded, with its timestamps in
+/// nanoseconds since the earliest timestamp of the trace.
+struct ImportedEvent<'a> {
+    event_kind: &'a str,
+    label: &'a str,
+    args: Vec<String>,
+    thread_id: u32,
+    start: u64,
+    /// `None` for instant events.
+    end: Option<u64>,
+}
+
+/// A `B` event that is waiting for its `E` event.
+struct OpenEvent<'a> {
+    event: &'a Value,
+    start: u64,
+}
+
+/// Which events have been left out of the profile.
+#[derive(Default)]
+struct Dropped {
+    unmatched_begins: usize,
+    unmatched_ends: usize,
+    unsupported: FxHashMap<String, usize>,
+}
+
+/// Reads the events from a JSON `[...]` array or from the `traceEvents` of
+/// a `{...}` object. Array traces may lack the closing `]`, since tools that
+/// stream their events often don't write it.
+fn trace_events(text: &str) -> Result<Vec<Value>, Box<dyn Error + Send + Sync>> {
+    let trace = match serde_json::from_str::<Value>(text) {
+        Ok(trace) => trace,
+        Err(e) => {
+            let text = text.trim_end();
+            if !text.starts_with('[') || text.ends_with(']') {
+                return Err(From::from(e));
+            }
+            serde_json::from_str(&format!("{}]", text.trim_end_matches(',')))?
+        }
+    };
+
+    let events = match trace {
+        Value::Array(events) => Some(events),
+        Value::Object(mut trace) => match trace.remove("traceEvents") {
+            Some(Value::Array(events)) => Some(events),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    events.ok_or_else(|| From::from("expected an array of events or an object with `traceEvents`"))
+}
+
+/// The timestamps of the trace are in microseconds, the only unit that
+/// Chrome supports for `ts` and `dur`.
+fn micros_field(event: &Value, field: &str) -> Option<f64> {
+    event.get(field).and_then(Value::as_f64)
+}
+
+fn str_field<'a>(event: &'a Value, field: &str) -> Option<&'a str> {
+    event.get(field).and_then(Value::as_str)
+}
+
+/// Assigns thread ids to the `(pid, tid)` pairs of the trace. If all events
+/// belong to the same process, its thread ids are kept, and threads whose
+/// tid isn't a number are numbered in order of appearance after the largest
+/// one. Otherwise, or if the unnumbered threads don't fit after the largest
+/// tid, the threads of all processes are numbered in order of appearance.
+fn thread_ids(events: &[Value]) -> FxHashMap<(String, String), u32> {
+    let key = |event: &Value| {
+        let field = |field| event.get(field).map(Value::to_string).unwrap_or_default();
+        (field("pid"), field("tid"))
+    };
+
+    let mut threads = Vec::new();
+    let mut thread_ids = FxHashMap::default();
+    let mut pids = FxHashSet::default();
+    for event in events {
+        let key = key(event);
+        if !thread_ids.contains_key(&key) {
+            pids.insert(key.0.clone());
+            thread_ids.insert(key.clone(), threads.len() as u32);
+            threads.push(key);
+        }
+    }
+
+    if pids.len() == 1 {
+        let tids: Vec<Option<u32>> = threads.iter().map(|key| key.1.parse().ok()).collect();
+        let mut next_id = tids
+            .iter()
+            .flatten()
+            .max()
+            .map_or(Some(0), |&max_tid| max_tid.checked_add(1));
+        let numbered: Option<Vec<u32>> = tids
+            .iter()
+            .map(|&tid| match tid {
+                Some(tid) => Some(tid),
+                None => {
+                    let thread_id = next_id?;
+                    next_id = thread_id.checked_add(1);
+                    Some(thread_id)
+                }
+            })
+            .collect();
+        if let Some(numbered) = numbered {
+            for (key, thread_id) in threads.iter().zip(numbered) {
+                thread_ids.insert(key.clone(), thread_id);
+            }
+        }
+    }
+
+    thread_ids
+}
+
+/// The arguments of `event`, in the order of the `arg0`, `arg1`, ... keys
+/// that `crox` writes. Other keys are kept as `<key>=<value>` arguments after
+/// them, ordered by key.
+fn args(event: &Value) -> Vec<String> {
+    let args = match event.get("args") {
+        Some(Value::Object(args)) => args,
+        _ => return Vec::new(),
+    };
+
+    let mut args: Vec<_> = args
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            match key
+                .strip_prefix("arg")
+                .and_then(|i| i.parse::<usize>().ok())
+            {
+                Some(index) => ((0, index, String::new()), value),
+                None => ((1, 0, key.clone()), format!("{}={}", key, value)),
+            }
+        })
+        .collect();
+    args.sort_by(|l, r| l.0.cmp(&r.0));
+
+    args.into_iter().map(|(_, arg)| arg).collect()
+}
+
+/// Converts `events` into the events to record, pairing up `B` and `E`
+/// events by thread. Chrome closes the latest open `B` event of a thread at
+/// an `E` event, regardless of its name.
+fn import_events<'a>(
+    events: &'a [Value],
+) -> (Vec<ImportedEvent<'a>>, Vec<(u32, &'a str)>, Dropped) {
+    let thread_ids = thread_ids(events);
+    let thread_id = |event: &Value| {
+        let field = |field| event.get(field).map(Value::to_string).unwrap_or_default();
+        thread_ids[&(field("pid"), field("tid"))]
+    };
+
+    let origin = events
+        .iter()
+        .filter(|event| str_field(event, "ph") != Some("M"))
+        .filter_map(|event| micros_field(event, "ts"))
+        .fold(f64::INFINITY, f64::min);
+    let nanos = |micros: f64| ((micros - origin) * 1000.0).round().max(0.0) as u64;
+
+    let mut imported = Vec::new();
+    let mut thread_names = Vec::new();
+    let mut dropped = Dropped::default();
+    let mut open_events = FxHashMap::<u32, Vec<OpenEvent<'a>>>::default();
+
+    let imported_event = |event: &'a Value, start: u64, end: Option<u64>| ImportedEvent {
+        event_kind: match str_field(event, "cat") {
+            Some(category) if !category.is_empty() => category,
+            _ => DEFAULT_EVENT_KIND,
+        },
+        label: str_field(event, "name").unwrap_or_default(),
+        args: args(event),
+        thread_id: thread_id(event),
+        start,
+        end,
+    };
+
+    for event in events {
+        let phase = str_field(event, "ph").unwrap_or_default();
+        let ts = micros_field(event, "ts");
+
+        match (phase, ts) {
+            ("X", Some(ts)) => {
+                let start = nanos(ts);
+                let end = nanos(ts + micros_field(event, "dur").unwrap_or(0.0));
+                imported.push(imported_event(event, start, Some(end)));
+            }
+            ("B", Some(ts)) => open_events
+                .entry(thread_id(event))
+                .or_default()
+                .push(OpenEvent {
+                    event,
+                    start: nanos(ts),
+                }),
+            ("E", Some(ts)) => match open_events
+                .get_mut(&thread_id(event))
+                .and_then(|open| open.pop())
+            {
+                Some(open) => {
+                    let mut begin = imported_event(open.event, open.start, Some(nanos(ts)));
+                    begin.args.extend(args(event));
+                    imported.push(begin);
+                }
+                None => dropped.unmatched_ends += 1,
+            },
+            ("i", Some(ts)) | ("I", Some(ts)) => {
+                imported.push(imported_event(event, nanos(ts), None));
+            }
+            ("M", _) if str_field(event, "name") == Some("thread_name") => {
+                let name = event.get("args").and_then(|args| str_field(args, "name"));
+                if let Some(name) = name {
+                    thread_names.push((thread_id(event), name));
+                }
+            }
+            // Process names and sort indices don't have a counterpart.
+            ("M", _) => {}
+            (phase, _) => {
+                *dropped.unsupported.entry(phase.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    dropped.unmatched_begins = open_events.values().map(Vec::len).sum();
+
+    (imported, thread_names, dropped)
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let opt = Opt::parse();
+
+    let events = trace_events(&fs::read_to_string(&opt.trace)?)?;
+    let (mut imported, thread_names, dropped) = import_events(&events);
+
+    if dropped.unmatched_ends > 0 {
+        eprintln!(
+            "Warning: dropped {} `E` events without a matching `B` event.",
+            dropped.unmatched_ends
+        );
+    }
+    if dropped.unmatched_begins > 0 {
+        eprintln!(
+            "Warning: dropped {} `B` events without a matching `E` event.",
+            dropped.unmatched_begins
+        );
+    }
+    let mut unsupported: Vec<_> = dropped.unsupported.into_iter().collect();
+    unsupported.sort();
+    for (phase, count) in unsupported {
+        eprintln!(
+            "Warning: dropped {} events with the unsupported phase `{}`.",
+            count, phase
+        );
+    }
+
+    let output = match opt.output {
+        Some(output) => output,
+        None => opt.trace.with_extension(""),
+    };
+    let profiler = Profiler::new(&output)?;
+    record_events(&profiler, &mut imported, &thread_names)?;
+    drop(profiler);
+
+    eprintln!(
+        "Imported {} events into `{}`",
+        imported.len(),
+        output.with_extension(FILE_EXTENSION).display()
+    );
+
+    Ok(())
+}
+
+fn record_events(
+    profiler: &Profiler,
+    imported: &mut [ImportedEvent<'_>],
+    thread_names: &[(u32, &str)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let builder = EventIdBuilder::new(profiler);
+
+    // Labels and arguments repeat a lot, so each is only stored once. They
+    // are escaped, so that separators in them don't change the event ids.
+    let mut strings = FxHashMap::<&str, StringId>::default();
+    let mut alloc_text = |s| *strings.entry(s).or_insert_with(|| builder.alloc_text(s));
+
+    let thread_name_kind = profiler.alloc_event_kind(THREAD_NAME_EVENT_KIND);
+    for &(thread_id, name) in thread_names {
+        let name = EventId::from_label(profiler.alloc_string(name));
+        profiler.record_instant_event_with_timestamp(thread_name_kind, name, thread_id, 0)?;
+    }
+
+    // The profiler expects events in roughly chronological order, by the
+    // time they end.
+    imported.sort_by_key(|event| event.end.unwrap_or(event.start));
+
+    for event in imported.iter() {
+        let event_kind = profiler.alloc_event_kind(event.event_kind);
+        let label = alloc_text(event.label);
+        let event_id = if event.args.is_empty() {
+            EventId::from_label(label)
+        } else {
+            let args: Vec<_> = event.args.iter().map(|arg| alloc_text(arg)).collect();
+            builder.from_label_and_args(label, &args)
+        };
+
+        match event.end {
+            Some(end) => profiler.record_event_with_timestamps(
+                event_kind,
+                event_id,
+                event.thread_id,
+                event.start,
+                end,
+            )?,
+            None => profiler.record_instant_event_with_timestamp(
+                event_kind,
+                event_id,
+                event.thread_id,
+                event.start,
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary<'a>(imported: &[ImportedEvent<'a>]) -> Vec<(&'a str, u32, u64, Option<u64>)> {
+        imported
+            .iter()
+            .map(|event| (event.label, event.thread_id, event.start, event.end))
+            .collect()
+    }
+
+    #[test]
+    fn phases_are_mapped_to_events() {
+        let events = trace_events(
+            &json!([
+                { "ph": "X", "name": "complete", "cat": "Query", "pid": 1, "tid": 1, "ts": 10.0, "dur": 5.0 },
+                { "ph": "B", "name": "outer", "pid": 1, "tid": 1, "ts": 20.0 },
+                { "ph": "B", "name": "inner", "pid": 1, "tid": 1, "ts": 21.0 },
+                { "ph": "E", "pid": 1, "tid": 1, "ts": 22.5 },
+                { "ph": "E", "pid": 1, "tid": 1, "ts": 30.0 },
+                { "ph": "i", "name": "instant", "pid": 1, "tid": 1, "ts": 31.0 },
+                { "ph": "M", "name": "thread_name", "pid": 1, "tid": 1, "args": { "name": "main" } },
+            ])
+            .to_string(),
+        )
+        .unwrap();
+
+        let (imported, thread_names, dropped) = import_events(&events);
+        assert_eq!(
+            summary(&imported),
+            vec![
+                ("complete", 1, 0, Some(5000)),
+                ("inner", 1, 11000, Some(12500)),
+                ("outer", 1, 10000, Some(20000)),
+                ("instant", 1, 21000, None),
+            ]
+        );
+        assert_eq!(imported[0].event_kind, "Query");
+        assert_eq!(imported[1].event_kind, DEFAULT_EVENT_KIND);
+        assert_eq!(thread_names, vec![(1, "main")]);
+        assert_eq!(dropped.unmatched_begins + dropped.unmatched_ends, 0);
+        assert!(dropped.unsupported.is_empty());
+    }
+
+    #[test]
+    fn unmatched_events_are_dropped() {
+        let events = trace_events(
+            &json!([
+                { "ph": "E", "pid": 1, "tid": 1, "ts": 1.0 },
+                { "ph": "B", "name": "unclosed", "pid": 1, "tid": 1, "ts": 2.0 },
+                // An `E` event only closes a `B` event of the same thread.
+                { "ph": "E", "pid": 1, "tid": 2, "ts": 3.0 },
+                { "ph": "C", "name": "counter", "pid": 1, "tid": 1, "ts": 4.0 },
+            ])
+            .to_string(),
+        )
+        .unwrap();
+
+        let (imported, _, dropped) = import_events(&events);
+        assert!(imported.is_empty());
+        assert_eq!(dropped.unmatched_begins, 1);
+        assert_eq!(dropped.unmatched_ends, 2);
+        assert_eq!(dropped.unsupported["C"], 1);
+    }
+
+    #[test]
+    fn args_are_ordered_like_crox_writes_them() {
+        let event = json!({
+            "args": { "zeta": 1, "arg10": "c", "arg2": "b", "alpha": "x", "arg0": "a" }
+        });
+        assert_eq!(args(&event), vec!["a", "b", "c", "alpha=x", "zeta=1"]);
+
+        // The args of an `E` event come after the ones of its `B` event.
+        let events = trace_events(
+            &json!([
+                { "ph": "B", "name": "e", "pid": 1, "tid": 1, "ts": 1.0, "args": { "arg0": "begin" } },
+                { "ph": "E", "pid": 1, "tid": 1, "ts": 2.0, "args": { "arg0": "end" } },
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let (imported, _, _) = import_events(&events);
+        assert_eq!(imported[0].args, vec!["begin", "end"]);
+    }
+
+    #[test]
+    fn thread_ids_of_unnumbered_threads_do_not_collide() {
+        let events = vec![
+            json!({ "pid": 1, "tid": "worker" }),
+            json!({ "pid": 1, "tid": 7 }),
+            json!({ "pid": 1, "tid": 3 }),
+            json!({ "pid": 1, "tid": "io" }),
+        ];
+        let ids = thread_ids(&events);
+        let thread_id = |tid: &str| ids[&("1".to_string(), tid.to_string())];
+        assert_eq!(thread_id("7"), 7);
+        assert_eq!(thread_id("3"), 3);
+        assert_eq!(thread_id("\"worker\""), 8);
+        assert_eq!(thread_id("\"io\""), 9);
+
+        // The threads of several processes are numbered in order.
+        let events = vec![
+            json!({ "pid": 1, "tid": 7 }),
+            json!({ "pid": 2, "tid": 7 }),
+            json!({ "pid": 1, "tid": 7 }),
+        ];
+        let ids = thread_ids(&events);
+        assert_eq!(ids[&("1".to_string(), "7".to_string())], 0);
+        assert_eq!(ids[&("2".to_string(), "7".to_string())], 1);
+    }
+
+    #[test]
+    fn thread_ids_after_the_largest_tid_do_not_overflow() {
+        let events = vec![
+            json!({ "pid": 1, "tid": u32::MAX - 1 }),
+            json!({ "pid": 1, "tid": "worker" }),
+        ];
+        let ids = thread_ids(&events);
+        assert_eq!(ids[&("1".to_string(), "\"worker\"".to_string())], u32::MAX);
+
+        // If the unnumbered threads don't fit, all threads are numbered in
+        // order.
+        let events = vec![
+            json!({ "pid": 1, "tid": u32::MAX }),
+            json!({ "pid": 1, "tid": "worker" }),
+        ];
+        let ids = thread_ids(&events);
+        assert_eq!(ids[&("1".to_string(), u32::MAX.to_string())], 0);
+        assert_eq!(ids[&("1".to_string(), "\"worker\"".to_string())], 1);
+    }
+
+    #[test]
+    fn labels_and_args_with_separators_round_trip() {
+        let label = "label\x1Ewith\x12separator\x1B";
+        let events = trace_events(
+            &json!([
+                { "ph": "X", "name": label, "pid": 1, "tid": 1, "ts": 1.0, "dur": 2.0,
+                  "args": { "arg0": "a\x1Eb", "arg1": "\x13c" } },
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let (mut imported, thread_names, _) = import_events(&events);
+
+        let path = std::path::Path::new("test-tmp")
+            .join("crox-import")
+            .join("separators");
+        let profiler = Profiler::new(&path).unwrap();
+        record_events(&profiler, &mut imported, &thread_names).unwrap();
+        drop(profiler);
+
+        let data = analyzeme::ProfilingData::new(&path).unwrap();
+        let events: Vec<_> = data.iter_full().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].label, label);
+        assert_eq!(events[0].additional_data, vec!["a\x1Eb", "\x13c"]);
+    }
+}
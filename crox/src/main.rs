@@ -1,10 +1,13 @@
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::fs;
 use std::io::BufWriter;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use analyzeme::{ProfilingData, Timestamp};
+use analyzeme::{warn_about_profile, LightweightEvent, ProfilingData, TimeRange, Timestamp};
 use measureme::file_header::FILE_EXTENSION;
 
 use clap::Parser;
@@ -42,6 +45,133 @@ struct Event {
     args: Option<FxHashMap<String, String>>,
 }
 
+/// An instant event that has been recorded with a value, e.g. a garbage
+/// collection run along with the number of bytes it freed.
+#[derive(Serialize)]
+struct InstantEvent {
+    name: String,
+    #[serde(rename = "cat")]
+    category: String,
+    #[serde(rename = "ph")]
+    event_type: &'static str,
+    #[serde(rename = "ts", serialize_with = "as_micros")]
+    timestamp: Duration,
+    /// The scope of the event, `t` draws it on its thread's track.
+    #[serde(rename = "s")]
+    scope: &'static str,
+    #[serde(rename = "pid")]
+    process_id: u32,
+    #[serde(rename = "tid")]
+    thread_id: u32,
+    args: FxHashMap<String, String>,
+}
+
+/// The values of one counter track at a point in time. Chrome draws each
+/// track as a stacked area graph with one series per key of `args`.
+#[derive(Serialize)]
+struct CounterEvent<'a> {
+    name: &'a str,
+    #[serde(rename = "ph")]
+    event_type: &'static str,
+    #[serde(rename = "ts", serialize_with = "as_micros")]
+    timestamp: Duration,
+    #[serde(rename = "pid")]
+    process_id: u32,
+    args: &'a BTreeMap<&'a str, u64>,
+}
+
+/// Selects the instant events that are drawn as a counter track instead of
+/// as markers, see `Opt::counters`.
+#[derive(Clone, Debug)]
+struct CounterMapping {
+    track: String,
+    label: String,
+}
+
+impl FromStr for CounterMapping {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<CounterMapping, Self::Err> {
+        let (track, label) = match s.find('=') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => (s, s),
+        };
+
+        if track.is_empty() || label.is_empty() {
+            let msg = format!(
+                "Invalid counter `{}`: expected `<label>` or `<track>=<label>`",
+                s
+            );
+            return Err(From::from(msg));
+        }
+
+        Ok(CounterMapping {
+            track: track.to_string(),
+            label: label.to_string(),
+        })
+    }
+}
+
+/// The thread ids given to `--threads`, e.g. `0,3,4`.
+#[derive(Clone, Debug)]
+struct ThreadIds(Vec<u32>);
+
+impl FromStr for ThreadIds {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<ThreadIds, Self::Err> {
+        s.split(',')
+            .map(|thread_id| {
+                thread_id.trim().parse().map_err(|_| {
+                    let msg = format!(
+                        "Invalid thread id `{}` in `{}`: expected a comma-separated list of thread ids",
+                        thread_id, s
+                    );
+                    From::from(msg)
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(ThreadIds)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Serialize)]
+enum FlowEventType {
+    #[serde(rename = "s")]
+    Start,
+    #[serde(rename = "f")]
+    Finish,
+}
+
+/// One end of an arrow from a parent event to one of its children.
+#[derive(Serialize)]
+struct FlowEvent<'a> {
+    name: &'a str,
+    #[serde(rename = "cat")]
+    category: &'a str,
+    #[serde(rename = "ph")]
+    event_type: FlowEventType,
+    id: u64,
+    #[serde(rename = "ts", serialize_with = "as_micros")]
+    timestamp: Duration,
+    #[serde(rename = "pid")]
+    process_id: u32,
+    #[serde(rename = "tid")]
+    thread_id: u32,
+    /// Binds the flow end to the slice enclosing `ts` rather than the next
+    /// slice starting after it.
+    #[serde(rename = "bp", skip_serializing_if = "Option::is_none")]
+    binding_point: Option<&'static str>,
+}
+
+/// An event that has been emitted but whose parent hasn't been seen yet.
+struct PendingChild {
+    event: LightweightEvent,
+    name: String,
+    category: String,
+    thread_id: u32,
+}
+
 #[derive(Parser, Debug)]
 struct Opt {
     #[clap(required_unless = "dir")]
@@ -55,12 +185,83 @@ struct Opt {
     /// filter out events with shorter duration (in microseconds)
     #[clap(long = "minimum-duration")]
     minimum_duration: Option<u128>,
+    /// don't draw arrows from events to the events nested within them
+    #[clap(long = "no-flows")]
+    no_flows: bool,
+    /// only export the events within the given window of time, in seconds since
+    /// the start of each profile, e.g. `120:122.5`
+    #[clap(long = "time-range")]
+    time_range: Option<TimeRange>,
+    /// draw the values of the instant events with the given label as a counter
+    /// track instead of as markers. With `<track>=<label>`, the values become
+    /// a series of the named track, so that several labels can share a track.
+    /// Can be given multiple times
+    #[clap(long = "counter")]
+    counters: Vec<CounterMapping>,
+    /// only export the events of the threads with the given ids, e.g. `0,3,4`.
+    /// The ids are those of the profile, before `--collapse-threads`
+    #[clap(long = "threads")]
+    threads: Option<ThreadIds>,
+    /// only export the events of the given number of threads that have been
+    /// busy for the longest time. Combined with `--threads`, the busiest of
+    /// the given threads are kept
+    #[clap(long = "top-threads")]
+    top_threads: Option<usize>,
+    /// add the index of each event in its profile to its arguments, as
+    /// `event_index`, to join the trace with the output of other tools
+    #[clap(long = "event-index")]
+    event_index: bool,
+}
+
+/// Returns the ids of the threads selected with `--threads` and
+/// `--top-threads`, or `None` if the events of all threads are exported. A
+/// thread is as busy as the sum of the self times of its interval events,
+/// i.e. the time during which any of its events has been running.
+fn selected_threads(opt: &Opt, data: &ProfilingData) -> Option<FxHashSet<u32>> {
+    if opt.threads.is_none() && opt.top_threads.is_none() {
+        return None;
+    }
+
+    let mut busy_times = FxHashMap::<u32, Duration>::default();
+    for event in data.iter() {
+        busy_times.entry(event.thread_id).or_default();
+    }
+    if opt.top_threads.is_some() {
+        for self_time in data.self_times() {
+            let thread_id = data
+                .decode_lightweight_event(self_time.event_index)
+                .thread_id;
+            *busy_times.get_mut(&thread_id).unwrap() += self_time.self_duration;
+        }
+    }
+
+    let mut threads: Vec<_> = busy_times
+        .into_iter()
+        .filter(|(thread_id, _)| match opt.threads {
+            Some(ThreadIds(ref thread_ids)) => thread_ids.contains(thread_id),
+            None => true,
+        })
+        .collect();
+
+    if let Some(top_threads) = opt.top_threads {
+        // Break ties by thread id, so that the selection is deterministic.
+        threads.sort_by(|l, r| r.1.cmp(&l.1).then_with(|| l.0.cmp(&r.0)));
+        threads.truncate(top_threads);
+    }
+
+    Some(
+        threads
+            .into_iter()
+            .map(|(thread_id, _)| thread_id)
+            .collect(),
+    )
 }
 
 // generate mapping from thread_id to collapsed thread_id or an empty map
 fn generate_thread_to_collapsed_thread_mapping(
     opt: &Opt,
     data: &ProfilingData,
+    is_selected: impl Fn(u32) -> bool,
 ) -> FxHashMap<u32, u32> {
     let mut thread_to_collapsed_thread: FxHashMap<u32, u32> = FxHashMap::default();
 
@@ -70,6 +271,7 @@ fn generate_thread_to_collapsed_thread_mapping(
             FxHashMap::default();
         for (thread_id, timestamp) in data
             .iter()
+            .filter(|e| is_selected(e.thread_id))
             .filter_map(|e| e.timestamp().map(|t| (e.thread_id, t)))
         {
             thread_start_and_end
@@ -144,74 +346,265 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let dir_paths = file_prefixes_in_dir(&opt)?;
 
+    let mut next_flow_id = 0;
+
     for file_prefix in opt.file_prefix.iter().chain(dir_paths.iter()) {
-        let data = ProfilingData::new(&file_prefix)?;
-
-        let thread_to_collapsed_thread = generate_thread_to_collapsed_thread_mapping(&opt, &data);
-
-        // Chrome does not seem to like how many QueryCacheHit events we generate
-        // only handle Interval events for now
-        for event in data.iter().filter(|e| e.payload.is_interval()) {
-            let duration = event.duration().unwrap();
-            if let Some(minimum_duration) = opt.minimum_duration {
-                if duration.as_micros() < minimum_duration {
-                    continue;
-                }
-            }
-            let full_event = data.to_full_event(&event);
-            let crox_event = Event {
-                name: full_event.label.clone().into_owned(),
-                category: full_event.event_kind.clone().into_owned(),
-                event_type: EventType::Complete,
-                timestamp: event.start().unwrap().duration_since(UNIX_EPOCH).unwrap(),
-                duration,
-                process_id: data.metadata().process_id,
-                thread_id: *thread_to_collapsed_thread
-                    .get(&event.thread_id)
-                    .unwrap_or(&event.thread_id),
-                args: get_args(&full_event),
-            };
-            seq.serialize_element(&crox_event)?;
-        }
-        // add crate name for the process_id
-        let index_of_crate_name = data
-            .metadata()
-            .cmd
-            .find(" --crate-name ")
-            .map(|index| index + 14);
-        if let Some(index) = index_of_crate_name {
-            let (_, last) = data.metadata().cmd.split_at(index);
-            let (crate_name, _) = last.split_at(last.find(" ").unwrap_or(last.len()));
-
-            let process_name = json!({
-                "name": "process_name",
-                "ph" : "M",
-                "ts" : 0,
-                "tid" : 0,
-                "cat" : "",
-                "pid" : data.metadata().process_id,
-                "args": {
-                    "name" : crate_name
-                }
-            });
-            seq.serialize_element(&process_name)?;
+        let mut data = ProfilingData::new(&file_prefix)?;
+        warn_about_profile(&data, file_prefix);
+
+        if let Some(range) = opt.time_range {
+            data = data.slice_time_range(range.start_nanos, range.end_nanos);
         }
-        // sort the processes after start time
+
+        write_profile_events(&opt, &data, &mut next_flow_id, &mut seq)?;
+    }
+
+    seq.end()?;
+
+    Ok(())
+}
+
+/// Writes the trace events of the profile `data` to `seq`. The ids of the
+/// flow events continue from `next_flow_id`, so that they stay unique when
+/// several profiles are merged into one trace.
+fn write_profile_events<S: SerializeSeq>(
+    opt: &Opt,
+    data: &ProfilingData,
+    next_flow_id: &mut u64,
+    seq: &mut S,
+) -> Result<(), S::Error> {
+    // Dropped threads don't get any events, including the metadata events
+    // that name them.
+    let selected_threads = selected_threads(opt, data);
+    let is_selected = |thread_id: u32| match selected_threads {
+        Some(ref selected_threads) => selected_threads.contains(&thread_id),
+        None => true,
+    };
+
+    let thread_to_collapsed_thread =
+        generate_thread_to_collapsed_thread_mapping(opt, data, is_selected);
+
+    // add crate name for the process_id
+    let index_of_crate_name = data
+        .metadata()
+        .cmd
+        .find(" --crate-name ")
+        .map(|index| index + 14);
+    if let Some(index) = index_of_crate_name {
+        let (_, last) = data.metadata().cmd.split_at(index);
+        let (crate_name, _) = last.split_at(last.find(" ").unwrap_or(last.len()));
+
         let process_name = json!({
-            "name": "process_sort_index",
+            "name": "process_name",
             "ph" : "M",
             "ts" : 0,
             "tid" : 0,
             "cat" : "",
             "pid" : data.metadata().process_id,
             "args": {
-                "sort_index" : data.metadata().start_time.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+                "name" : crate_name
             }
         });
         seq.serialize_element(&process_name)?;
     }
+    // sort the processes after start time
+    let process_name = json!({
+        "name": "process_sort_index",
+        "ph" : "M",
+        "ts" : 0,
+        "tid" : 0,
+        "cat" : "",
+        "pid" : data.metadata().process_id,
+        "args": {
+            "sort_index" : data.metadata().start_time.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+        }
+    });
+    seq.serialize_element(&process_name)?;
 
-    seq.end()?;
+    // name the threads, so that they are not just anonymous numbers. If
+    // several threads have been collapsed into one, the first recorded
+    // name among them is used.
+    let mut thread_names = BTreeMap::<u32, Option<&str>>::new();
+    for event in data
+        .iter()
+        .filter(|e| e.payload.is_interval() && is_selected(e.thread_id))
+    {
+        let thread_id = *thread_to_collapsed_thread
+            .get(&event.thread_id)
+            .unwrap_or(&event.thread_id);
+        let thread_name = thread_names.entry(thread_id).or_insert(None);
+        if thread_name.is_none() {
+            *thread_name = data.thread_name(event.thread_id);
+        }
+    }
+    for (thread_id, thread_name) in thread_names {
+        let thread_name = json!({
+            "name": "thread_name",
+            "ph" : "M",
+            "ts" : 0,
+            "tid" : thread_id,
+            "cat" : "",
+            "pid" : data.metadata().process_id,
+            "args": {
+                "name" : thread_name
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("Thread {}", thread_id))
+            }
+        });
+        seq.serialize_element(&thread_name)?;
+    }
+
+    // Unlike the instant events without a value, such as QueryCacheHit,
+    // these are rare enough to show them as markers on the timeline, or
+    // as counter tracks if selected with `--counter`.
+    let mut counter_samples = Vec::new();
+    for event in data.iter().filter(|e| is_selected(e.thread_id)) {
+        let value = match event.payload.instant_value() {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let full_event = data.to_full_event(&event);
+        let timestamp = trace_timestamp(data, event.start().unwrap());
+
+        if let Some(mapping) = opt
+            .counters
+            .iter()
+            .find(|mapping| mapping.label == full_event.label)
+        {
+            counter_samples.push((&mapping.track[..], timestamp, &mapping.label[..], value));
+            continue;
+        }
+
+        let mut args = get_args(&full_event).unwrap_or_default();
+        args.insert("value".to_string(), value.to_string());
+        if opt.event_index {
+            args.insert("event_index".to_string(), event.index().to_string());
+        }
+
+        seq.serialize_element(&InstantEvent {
+            name: full_event.label.into_owned(),
+            category: full_event.event_kind.into_owned(),
+            event_type: "i",
+            timestamp,
+            scope: "t",
+            process_id: data.metadata().process_id,
+            thread_id: *thread_to_collapsed_thread
+                .get(&event.thread_id)
+                .unwrap_or(&event.thread_id),
+            args,
+        })?;
+    }
+
+    // Each counter event has to contain the current value of every series
+    // of its track, and the values of a track that have been recorded at
+    // the same time go into a single event.
+    counter_samples.sort_by_key(|&(track, timestamp, _, _)| (track, timestamp));
+    let mut series = BTreeMap::<&str, u64>::new();
+    for (i, &(track, timestamp, label, value)) in counter_samples.iter().enumerate() {
+        if i > 0 && counter_samples[i - 1].0 != track {
+            series.clear();
+        }
+        series.insert(label, value);
+
+        let is_last_of_group = match counter_samples.get(i + 1) {
+            Some(&(next_track, next_timestamp, _, _)) => {
+                next_track != track || next_timestamp != timestamp
+            }
+            None => true,
+        };
+        if is_last_of_group {
+            seq.serialize_element(&CounterEvent {
+                name: track,
+                event_type: "C",
+                timestamp,
+                process_id: data.metadata().process_id,
+                args: &series,
+            })?;
+        }
+    }
+
+    // Events are recorded when they end, so children always come before
+    // their parent. For each thread, this holds the events that haven't
+    // been claimed by a parent yet.
+    let mut pending_children = FxHashMap::<u32, Vec<PendingChild>>::default();
+
+    // Chrome does not seem to like how many QueryCacheHit events we generate
+    // only handle Interval events for now
+    for event in data
+        .iter()
+        .filter(|e| e.payload.is_interval() && is_selected(e.thread_id))
+    {
+        let duration = event.duration().unwrap();
+        if let Some(minimum_duration) = opt.minimum_duration {
+            if duration.as_micros() < minimum_duration {
+                continue;
+            }
+        }
+        let full_event = data.to_full_event(&event);
+        let mut args = get_args(&full_event);
+        if opt.event_index {
+            args.get_or_insert_with(FxHashMap::default)
+                .insert("event_index".to_string(), event.index().to_string());
+        }
+        let crox_event = Event {
+            name: full_event.label.clone().into_owned(),
+            category: full_event.event_kind.clone().into_owned(),
+            event_type: EventType::Complete,
+            timestamp: trace_timestamp(data, event.start().unwrap()),
+            duration,
+            process_id: data.metadata().process_id,
+            thread_id: *thread_to_collapsed_thread
+                .get(&event.thread_id)
+                .unwrap_or(&event.thread_id),
+            args,
+        };
+        seq.serialize_element(&crox_event)?;
+
+        if opt.no_flows {
+            continue;
+        }
+
+        let pending = pending_children.entry(event.thread_id).or_default();
+        while let Some(child) = pending.last() {
+            if !event.contains(&child.event) {
+                break;
+            }
+
+            let flow_id = *next_flow_id;
+            *next_flow_id += 1;
+
+            seq.serialize_element(&FlowEvent {
+                name: &child.name,
+                category: &child.category,
+                event_type: FlowEventType::Start,
+                id: flow_id,
+                timestamp: crox_event.timestamp,
+                process_id: crox_event.process_id,
+                thread_id: crox_event.thread_id,
+                binding_point: None,
+            })?;
+            seq.serialize_element(&FlowEvent {
+                name: &child.name,
+                category: &child.category,
+                event_type: FlowEventType::Finish,
+                id: flow_id,
+                timestamp: trace_timestamp(data, child.event.start().unwrap()),
+                process_id: crox_event.process_id,
+                thread_id: child.thread_id,
+                binding_point: Some("e"),
+            })?;
+
+            pending.pop();
+        }
+
+        pending.push(PendingChild {
+            event,
+            name: crox_event.name,
+            category: crox_event.category,
+            thread_id: crox_event.thread_id,
+        });
+    }
 
     Ok(())
 }
@@ -230,6 +623,17 @@ fn file_prefixes_in_dir(opt: &Opt) -> Result<Vec<PathBuf>, std::io::Error> {
     Ok(result)
 }
 
+/// Converts the time of an event to the time since the UNIX epoch that the
+/// trace shows it at. This is the wall-clock time at which it has been
+/// recorded if the profile allows computing it, so that the trace lines up
+/// with e.g. log messages.
+fn trace_timestamp(data: &ProfilingData, time: SystemTime) -> Duration {
+    data.to_wall_clock(time)
+        .unwrap_or(time)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+}
+
 fn timestamp_to_min_max(timestamp: Timestamp) -> (SystemTime, SystemTime) {
     match timestamp {
         Timestamp::Instant(t) => (t, t),
@@ -240,3 +644,282 @@ fn timestamp_to_min_max(timestamp: Timestamp) -> (SystemTime, SystemTime) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+    use serde_json::Value;
+
+    fn opt() -> Opt {
+        Opt {
+            file_prefix: Vec::new(),
+            dir: None,
+            collapse_threads: false,
+            minimum_duration: None,
+            no_flows: false,
+            time_range: None,
+            counters: Vec::new(),
+            threads: None,
+            top_threads: None,
+            event_index: false,
+        }
+    }
+
+    fn trace_events(opt: &Opt, data: &ProfilingData, next_flow_id: &mut u64) -> Vec<Value> {
+        let mut bytes = Vec::new();
+        let mut serializer = serde_json::Serializer::new(&mut bytes);
+        let mut seq = serializer.serialize_seq(None).unwrap();
+        write_profile_events(opt, data, next_flow_id, &mut seq).unwrap();
+        seq.end().unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn with_phase<'a>(events: &'a [Value], phase: &'a str) -> impl Iterator<Item = &'a Value> {
+        events.iter().filter(move |event| event["ph"] == phase)
+    }
+
+    /// The flow events as (phase, id, name of the child, timestamp).
+    fn flows(events: &[Value]) -> Vec<(String, u64, String, u64)> {
+        events
+            .iter()
+            .filter(|event| event["ph"] == "s" || event["ph"] == "f")
+            .map(|event| {
+                (
+                    event["ph"].as_str().unwrap().to_string(),
+                    event["id"].as_u64().unwrap(),
+                    event["name"].as_str().unwrap().to_string(),
+                    event["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn flow(phase: &str, id: u64, name: &str, ts: u64) -> (String, u64, String, u64) {
+        (phase.to_string(), id, name.to_string(), ts)
+    }
+
+    #[test]
+    fn flows_connect_each_event_to_its_direct_children() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 0, 100_000, |b| {
+            b.interval("Query", "mid", 0, 10_000, 90_000, |b| {
+                b.interval("Query", "inner", 0, 20_000, 30_000, |_| {});
+            });
+        });
+        let data = b.into_profiling_data();
+
+        let events = trace_events(&opt(), &data, &mut 0);
+        assert_eq!(
+            flows(&events),
+            vec![
+                flow("s", 0, "inner", 10),
+                flow("f", 0, "inner", 20),
+                flow("s", 1, "mid", 0),
+                flow("f", 1, "mid", 10),
+            ]
+        );
+
+        // The end of each arrow is bound to the child that encloses it.
+        let finish = with_phase(&events, "f").next().unwrap();
+        assert_eq!(finish["bp"], "e");
+        assert!(with_phase(&events, "s").all(|event| event.get("bp").is_none()));
+    }
+
+    #[test]
+    fn flows_connect_siblings_to_their_parent() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "parent", 0, 0, 100_000, |b| {
+            b.interval("Query", "first", 0, 10_000, 20_000, |_| {});
+            b.interval("Query", "second", 0, 30_000, 40_000, |_| {});
+        });
+        b.interval("Query", "other", 1, 0, 50_000, |_| {});
+        let data = b.into_profiling_data();
+
+        // The children are claimed from the last one, and events on other
+        // threads are never claimed.
+        let events = trace_events(&opt(), &data, &mut 0);
+        assert_eq!(
+            flows(&events),
+            vec![
+                flow("s", 0, "second", 0),
+                flow("f", 0, "second", 30),
+                flow("s", 1, "first", 0),
+                flow("f", 1, "first", 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn flow_ids_continue_across_profiles() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "parent", 0, 0, 100_000, |b| {
+            b.interval("Query", "child", 0, 10_000, 20_000, |_| {});
+        });
+        let data = b.into_profiling_data();
+
+        let mut next_flow_id = 0;
+        let first = trace_events(&opt(), &data, &mut next_flow_id);
+        let second = trace_events(&opt(), &data, &mut next_flow_id);
+        assert_eq!(flows(&first)[0].1, 0);
+        assert_eq!(flows(&second)[0].1, 1);
+        assert_eq!(next_flow_id, 2);
+    }
+
+    #[test]
+    fn no_flows_and_minimum_duration() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "parent", 0, 0, 100_000, |b| {
+            b.interval("Query", "short", 0, 10_000, 11_000, |_| {});
+            b.interval("Query", "long", 0, 20_000, 60_000, |_| {});
+        });
+        let data = b.into_profiling_data();
+        let names = |events: &[Value]| -> Vec<String> {
+            with_phase(events, "X")
+                .map(|event| event["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Events that are too short get neither a slice nor an arrow.
+        let opt = Opt {
+            minimum_duration: Some(5),
+            ..opt()
+        };
+        let events = trace_events(&opt, &data, &mut 0);
+        assert_eq!(names(&events), vec!["long", "parent"]);
+        assert_eq!(
+            flows(&events),
+            vec![flow("s", 0, "long", 0), flow("f", 0, "long", 20)]
+        );
+
+        // `--no-flows` only drops the arrows.
+        let opt = Opt {
+            no_flows: true,
+            ..opt
+        };
+        let mut next_flow_id = 0;
+        let events = trace_events(&opt, &data, &mut next_flow_id);
+        assert_eq!(names(&events), vec!["long", "parent"]);
+        assert!(flows(&events).is_empty());
+        assert_eq!(next_flow_id, 0);
+    }
+
+    #[test]
+    fn parse_counter_mappings() {
+        let mapping: CounterMapping = "rss".parse().unwrap();
+        assert_eq!((&mapping.track[..], &mapping.label[..]), ("rss", "rss"));
+        let mapping: CounterMapping = "memory=rss".parse().unwrap();
+        assert_eq!((&mapping.track[..], &mapping.label[..]), ("memory", "rss"));
+
+        assert!("=rss".parse::<CounterMapping>().is_err());
+        assert!("memory=".parse::<CounterMapping>().is_err());
+    }
+
+    #[test]
+    fn counters_share_tracks() {
+        let mut b = ProfilingDataBuilder::new();
+        b.instant_with_value("Memory", "rss", 0, 10_000, 5);
+        b.instant_with_value("Memory", "heap", 0, 10_000, 3);
+        b.instant_with_value("Memory", "rss", 0, 20_000, 7);
+        b.instant_with_value("Memory", "gc", 0, 30_000, 1);
+        let data = b.into_profiling_data();
+
+        let opt = Opt {
+            counters: vec![
+                "memory=rss".parse().unwrap(),
+                "memory=heap".parse().unwrap(),
+            ],
+            ..opt()
+        };
+        let events = trace_events(&opt, &data, &mut 0);
+
+        // The values recorded at the same time go into a single event, and
+        // each event has the latest value of every series of the track.
+        let counters: Vec<_> = with_phase(&events, "C")
+            .map(|event| {
+                (
+                    event["name"].as_str().unwrap(),
+                    event["ts"].as_u64().unwrap(),
+                    event["args"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counters,
+            vec![
+                ("memory", 10, serde_json::json!({ "heap": 3, "rss": 5 })),
+                ("memory", 20, serde_json::json!({ "heap": 3, "rss": 7 })),
+            ]
+        );
+
+        // Values that aren't mapped to a track are markers.
+        let markers: Vec<_> = with_phase(&events, "i").collect();
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0]["name"], "gc");
+        assert_eq!(markers[0]["args"]["value"], "1");
+    }
+
+    #[test]
+    fn parse_thread_ids() {
+        let ThreadIds(thread_ids) = "0, 3,4".parse().unwrap();
+        assert_eq!(thread_ids, vec![0, 3, 4]);
+        assert!("1,x".parse::<ThreadIds>().is_err());
+        assert!("".parse::<ThreadIds>().is_err());
+    }
+
+    fn busy_threads() -> ProfilingData {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "a", 1, 0, 10_000, |_| {});
+        b.interval("Query", "b", 2, 0, 30_000, |_| {});
+        b.interval("Query", "c", 3, 0, 20_000, |_| {});
+        b.thread_name(2, "busiest", 0);
+        b.into_profiling_data()
+    }
+
+    #[test]
+    fn select_threads() {
+        let data = busy_threads();
+        let selected = |threads: Option<&str>, top_threads: Option<usize>| {
+            let opt = Opt {
+                threads: threads.map(|threads| threads.parse().unwrap()),
+                top_threads,
+                ..opt()
+            };
+            let mut selected: Option<Vec<_>> =
+                selected_threads(&opt, &data).map(|threads| threads.into_iter().collect());
+            if let Some(ref mut selected) = selected {
+                selected.sort_unstable();
+            }
+            selected
+        };
+
+        assert_eq!(selected(None, None), None);
+        assert_eq!(selected(Some("1,3"), None), Some(vec![1, 3]));
+        assert_eq!(selected(None, Some(2)), Some(vec![2, 3]));
+        assert_eq!(selected(Some("1,3"), Some(1)), Some(vec![3]));
+        assert_eq!(selected(Some("7"), None), Some(vec![]));
+    }
+
+    #[test]
+    fn dropped_threads_get_no_events() {
+        let data = busy_threads();
+        let opt = Opt {
+            top_threads: Some(1),
+            ..opt()
+        };
+        let events = trace_events(&opt, &data, &mut 0);
+
+        let thread_events: Vec<_> = events
+            .iter()
+            .filter(|event| event["name"] != "process_sort_index")
+            .map(|event| {
+                (
+                    event["ph"].as_str().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(thread_events, vec![("M", 2), ("X", 2)]);
+        assert_eq!(events[1]["args"]["name"], "busiest");
+    }
+}
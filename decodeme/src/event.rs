@@ -1,5 +1,5 @@
 use crate::event_payload::EventPayload;
-use memchr::memchr;
+use memchr::memchr2;
 use std::borrow::Cow;
 use std::time::Duration;
 
@@ -7,12 +7,55 @@ use std::time::Duration;
 pub struct Event<'a> {
     pub event_kind: Cow<'a, str>,
     pub label: Cow<'a, str>,
+    pub category: Option<Cow<'a, str>>,
     pub additional_data: Vec<Cow<'a, str>>,
+    /// The arguments in `additional_data` that were recorded as integers,
+    /// given as `(index into additional_data, value)` pairs.
+    pub integer_args: Vec<(usize, u64)>,
+    /// The frames of the stack captured with
+    /// `measureme::Profiler::record_event_with_backtrace`, innermost first.
+    /// They are not part of `additional_data`.
+    pub backtrace: Vec<Cow<'a, str>>,
     pub payload: EventPayload,
     pub thread_id: u32,
+    /// The number of interval events of the same thread that this interval
+    /// event is nested in, if the profiler has recorded it (see
+    /// `measureme::ProfilerOptions::record_nesting_depth`).
+    pub depth: Option<u32>,
+}
+
+/// The components of an `event_id` string (see `measureme::event_id` for the
+/// grammar).
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct ParsedEventId<'a> {
+    pub label: Cow<'a, str>,
+    pub category: Option<Cow<'a, str>>,
+    pub args: Vec<Cow<'a, str>>,
+    pub integer_args: Vec<(usize, u64)>,
+    pub backtrace: Vec<Cow<'a, str>>,
 }
 
 impl<'a> Event<'a> {
+    /// The label of the event, i.e. its event id without the category and
+    /// the arguments, unescaped (see `measureme::event_id`).
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// The category of the event, if it has been recorded with one (e.g. via
+    /// `EventIdBuilder::from_label_and_category`).
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    /// The arguments of the event, in the order they have been recorded in
+    /// and unescaped, so that separators within an argument don't split it.
+    /// These are the strings in `additional_data`, including the ones that
+    /// have been recorded as integers (see `integer_arg`).
+    pub fn arguments(&self) -> impl ExactSizeIterator<Item = &str> + '_ {
+        self.additional_data.iter().map(|arg| &arg[..])
+    }
+
     /// Returns true if the time interval of `self` completely contains the
     /// time interval of `other`.
     pub fn contains(&self, other: &Event<'_>) -> bool {
@@ -27,7 +70,16 @@ impl<'a> Event<'a> {
         self.payload.integer()
     }
 
-    pub(crate) fn parse_event_id(event_id: Cow<'a, str>) -> (Cow<'a, str>, Vec<Cow<'a, str>>) {
+    /// Returns the value of the argument at `index` if it was recorded as an
+    /// integer (e.g. via `EventIdBuilder::from_label_and_int_arg`).
+    pub fn integer_arg(&self, index: usize) -> Option<u64> {
+        self.integer_args
+            .iter()
+            .find(|&&(arg_index, _)| arg_index == index)
+            .map(|&(_, value)| value)
+    }
+
+    pub(crate) fn parse_event_id(event_id: Cow<'a, str>) -> ParsedEventId<'a> {
         let event_id = match event_id {
             Cow::Owned(s) => Cow::Owned(s.into_bytes()),
             Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
@@ -39,15 +91,49 @@ impl<'a> Event<'a> {
             Ok(label) => label,
             Err(message) => {
                 eprintln!("{}", message);
-                return (Cow::from("<parse error>"), Vec::new());
+                return ParsedEventId::new(Cow::from("<parse error>"));
             }
         };
 
-        let mut args = Vec::new();
+        let mut parsed = ParsedEventId::new(label);
+
+        if parser.at_category() {
+            match parser.parse_category() {
+                Ok(category) => parsed.category = Some(category),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    return parsed;
+                }
+            }
+        }
 
         while parser.pos != parser.full_text.len() {
+            // The marker is always last, and read by `omitted_args`.
+            if parser.at_omitted_args() {
+                if let Err(message) = parser.parse_omitted_args() {
+                    eprintln!("{}", message);
+                }
+                break;
+            }
+
+            if parser.at_backtrace_frame() {
+                match parser.parse_backtrace_frame() {
+                    Ok(frame) => parsed.backtrace.push(frame),
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        break;
+                    }
+                }
+                continue;
+            }
+
             match parser.parse_arg() {
-                Ok(arg) => args.push(arg),
+                Ok((arg, integer_value)) => {
+                    if let Some(value) = integer_value {
+                        parsed.integer_args.push((parsed.args.len(), value));
+                    }
+                    parsed.args.push(arg);
+                }
                 Err(message) => {
                     eprintln!("{}", message);
                     break;
@@ -55,7 +141,19 @@ impl<'a> Event<'a> {
             }
         }
 
-        (label, args)
+        parsed
+    }
+}
+
+impl<'a> ParsedEventId<'a> {
+    fn new(label: Cow<'a, str>) -> ParsedEventId<'a> {
+        ParsedEventId {
+            label,
+            category: None,
+            args: Vec::new(),
+            integer_args: Vec::new(),
+            backtrace: Vec::new(),
+        }
     }
 }
 
@@ -65,6 +163,84 @@ struct Parser<'a> {
 }
 
 const SEPARATOR_BYTE: u8 = measureme::event_id::SEPARATOR_BYTE.as_bytes()[0];
+const CATEGORY_TAG_BYTE: u8 = measureme::event_id::CATEGORY_TAG_BYTE.as_bytes()[0];
+const INTEGER_ARG_TAG_BYTE: u8 = measureme::event_id::INTEGER_ARG_TAG_BYTE.as_bytes()[0];
+const BACKTRACE_FRAME_TAG_BYTE: u8 = measureme::event_id::BACKTRACE_FRAME_TAG_BYTE.as_bytes()[0];
+const OMITTED_ARGS_TAG_BYTE: u8 = measureme::event_id::OMITTED_ARGS_TAG_BYTE.as_bytes()[0];
+const ESCAPE_BYTE: u8 = measureme::event_id::ESCAPE_BYTE.as_bytes()[0];
+
+/// Returns the position of the first `SEPARATOR_BYTE` in `text` that hasn't
+/// been escaped, or `text.len()` if there is none.
+fn find_separator(text: &[u8]) -> usize {
+    let mut pos = 0;
+
+    while let Some(offset) = memchr2(SEPARATOR_BYTE, ESCAPE_BYTE, &text[pos..]) {
+        pos += offset;
+        if text[pos] == SEPARATOR_BYTE {
+            return pos;
+        }
+
+        // Skip the escaped byte.
+        pos = std::cmp::min(pos + 2, text.len());
+    }
+
+    text.len()
+}
+
+/// Removes the `ESCAPE_BYTE`s that `measureme::event_id::escape_text` has
+/// inserted into `text`.
+pub fn unescape_text(text: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    if !text.contains(&ESCAPE_BYTE) {
+        return text;
+    }
+
+    let mut unescaped = Vec::with_capacity(text.len());
+    let mut bytes = text.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            ESCAPE_BYTE => unescaped.extend(bytes.next()),
+            _ => unescaped.push(byte),
+        }
+    }
+
+    Cow::Owned(unescaped)
+}
+
+/// Returns the number of arguments that have been left out of the event id
+/// `event_id` because it has been created with more arguments than
+/// `measureme::EventIdBuilder::with_max_args` allows, without parsing the
+/// rest of the event id. Usually 0.
+pub fn omitted_args(event_id: &[u8]) -> u64 {
+    let mut pos = find_separator(event_id);
+    while pos < event_id.len() {
+        let component = &event_id[pos + 1..];
+        let len = find_separator(component);
+        if component.first() == Some(&OMITTED_ARGS_TAG_BYTE) {
+            return std::str::from_utf8(&component[1..len])
+                .ok()
+                .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|digits| digits.parse().ok())
+                .unwrap_or(0);
+        }
+        pos += 1 + len;
+    }
+
+    0
+}
+
+/// Returns the label of the event id `event_id`, with escaped bytes
+/// unescaped, without parsing the rest of the event id.
+pub fn label_bytes(event_id: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    let label = match event_id {
+        Cow::Borrowed(bytes) => Cow::Borrowed(&bytes[..find_separator(bytes)]),
+        Cow::Owned(mut bytes) => {
+            bytes.truncate(find_separator(&bytes));
+            Cow::Owned(bytes)
+        }
+    };
+
+    unescape_text(label)
+}
 
 impl<'a> Parser<'a> {
     fn new(full_text: Cow<'a, [u8]>) -> Parser<'a> {
@@ -82,10 +258,7 @@ impl<'a> Parser<'a> {
 
     fn parse_separator_terminated_text(&mut self) -> Result<Cow<'a, str>, String> {
         let start = self.pos;
-
-        let end = memchr(SEPARATOR_BYTE, &self.full_text[start..])
-            .map(|pos| pos + start)
-            .unwrap_or(self.full_text.len());
+        let end = start + find_separator(&self.full_text[start..]);
 
         if start == end {
             return self.err("Zero-length <text>");
@@ -93,18 +266,73 @@ impl<'a> Parser<'a> {
 
         self.pos = end;
 
-        if self.full_text[start..end]
-            .iter()
-            .filter(|x| !x.is_ascii_whitespace())
-            .any(u8::is_ascii_control)
-        {
-            return self.err("Found ASCII control character in <text>");
+        let mut bytes = self.full_text[start..end].iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == ESCAPE_BYTE {
+                match bytes.next() {
+                    Some(&escaped) if escaped.is_ascii_control() => {}
+                    _ => return self.err("Invalid escape sequence in <text>"),
+                }
+            } else if byte.is_ascii_control() && !byte.is_ascii_whitespace() {
+                return self.err("Found ASCII control character in <text>");
+            }
         }
 
         Ok(self.substring(start, end))
     }
 
-    fn parse_arg(&mut self) -> Result<Cow<'a, str>, String> {
+    fn at_category(&self) -> bool {
+        self.full_text[self.pos..].starts_with(&[SEPARATOR_BYTE, CATEGORY_TAG_BYTE])
+    }
+
+    fn parse_category(&mut self) -> Result<Cow<'a, str>, String> {
+        if !self.at_category() {
+            return self.err(&format!(
+                "Expected '\\x{:x}\\x{:x}' chars at start of <category>",
+                SEPARATOR_BYTE, CATEGORY_TAG_BYTE
+            ));
+        }
+
+        self.pos += 2;
+        self.parse_separator_terminated_text()
+    }
+
+    fn at_backtrace_frame(&self) -> bool {
+        self.full_text[self.pos..].starts_with(&[SEPARATOR_BYTE, BACKTRACE_FRAME_TAG_BYTE])
+    }
+
+    /// Parses an `<argument>` that is a `<backtrace_frame>`, returning the
+    /// name of the frame.
+    fn parse_backtrace_frame(&mut self) -> Result<Cow<'a, str>, String> {
+        assert!(self.at_backtrace_frame());
+        self.pos += 2;
+        self.parse_separator_terminated_text()
+    }
+
+    fn at_omitted_args(&self) -> bool {
+        self.full_text[self.pos..].starts_with(&[SEPARATOR_BYTE, OMITTED_ARGS_TAG_BYTE])
+    }
+
+    /// Parses an `<argument>` that is an `<omitted_args>` marker, returning the
+    /// number of arguments that have been left out.
+    fn parse_omitted_args(&mut self) -> Result<u64, String> {
+        assert!(self.at_omitted_args());
+        self.pos += 2;
+        let text = self.parse_separator_terminated_text()?;
+
+        if !text.bytes().all(|b| b.is_ascii_digit()) {
+            return self.err("Found non-digit character in <omitted_args>");
+        }
+
+        match text.parse::<u64>() {
+            Ok(value) => Ok(value),
+            Err(_) => self.err("Integer overflow in <omitted_args>"),
+        }
+    }
+
+    /// Parses an `<argument>`, returning its text and, for integer arguments,
+    /// the decoded value.
+    fn parse_arg(&mut self) -> Result<(Cow<'a, str>, Option<u64>), String> {
         if self.peek() != SEPARATOR_BYTE {
             return self.err(&format!(
                 "Expected '\\x{:x}' char at start of <argument>",
@@ -113,7 +341,22 @@ impl<'a> Parser<'a> {
         }
 
         self.pos += 1;
-        self.parse_separator_terminated_text()
+
+        if self.pos < self.full_text.len() && self.peek() == INTEGER_ARG_TAG_BYTE {
+            self.pos += 1;
+            let text = self.parse_separator_terminated_text()?;
+
+            if !text.bytes().all(|b| b.is_ascii_digit()) {
+                return self.err("Found non-digit character in <integer_argument>");
+            }
+
+            return match text.parse::<u64>() {
+                Ok(value) => Ok((text, Some(value))),
+                Err(_) => self.err("Integer overflow in <integer_argument>"),
+            };
+        }
+
+        Ok((self.parse_separator_terminated_text()?, None))
     }
 
     fn err<T>(&self, message: &str) -> Result<T, String> {
@@ -125,7 +368,14 @@ impl<'a> Parser<'a> {
         ))
     }
 
+    /// The unescaped text between `start` and `end`.
     fn substring(&self, start: usize, end: usize) -> Cow<'a, str> {
+        if self.full_text[start..end].contains(&ESCAPE_BYTE) {
+            let text = unescape_text(Cow::Borrowed(&self.full_text[start..end])).into_owned();
+            // Only ASCII characters are escaped, so this is still valid UTF-8.
+            return Cow::Owned(String::from_utf8(text).unwrap());
+        }
+
         match self.full_text {
             Cow::Owned(ref s) => {
                 let bytes = s[start..end].to_owned();
@@ -141,17 +391,56 @@ mod tests {
     use super::*;
     use std::borrow::Cow;
 
+    fn event(event_id: &str) -> Event<'_> {
+        let parsed = Event::parse_event_id(Cow::from(event_id));
+        Event {
+            event_kind: Cow::from("Query"),
+            label: parsed.label,
+            category: parsed.category,
+            additional_data: parsed.args,
+            integer_args: parsed.integer_args,
+            backtrace: parsed.backtrace,
+            payload: EventPayload::Integer(0),
+            thread_id: 0,
+            depth: None,
+        }
+    }
+
+    #[test]
+    fn structured_access_to_args() {
+        let no_args = event("foo");
+        assert_eq!(no_args.label(), "foo");
+        assert_eq!(no_args.category(), None);
+        assert_eq!(no_args.arguments().len(), 0);
+
+        // Escaped separators are part of the label and the arguments.
+        let escaped = event("foo\x1b\x1ebar\x1e\x12cat\x1ea\x1b\x1eb\x1e\x1342");
+        assert_eq!(escaped.label(), "foo\x1ebar");
+        assert_eq!(escaped.category(), Some("cat"));
+        assert_eq!(
+            escaped.arguments().collect::<Vec<_>>(),
+            vec!["a\x1eb", "42"]
+        );
+        assert_eq!(escaped.integer_arg(1), Some(42));
+    }
+
     #[test]
     fn parse_event_id_no_args() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo"));
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo"));
 
         assert_eq!(label, "foo");
+        assert_eq!(category, None);
         assert!(args.is_empty());
     }
 
     #[test]
     fn parse_event_id_with_control_char() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo\x1b"));
+        let ParsedEventId { label, args, .. } = Event::parse_event_id(Cow::from("foo\x1b"));
 
         assert_eq!(label, "<parse error>");
         assert!(args.is_empty());
@@ -159,17 +448,29 @@ mod tests {
 
     #[test]
     fn parse_event_id_one_arg() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo\x1emy_arg"));
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1emy_arg"));
 
         assert_eq!(label, "foo");
+        assert_eq!(category, None);
         assert_eq!(args, vec![Cow::from("my_arg")]);
     }
 
     #[test]
     fn parse_event_id_n_args() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo\x1earg1\x1earg2\x1earg3"));
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1earg1\x1earg2\x1earg3"));
 
         assert_eq!(label, "foo");
+        assert_eq!(category, None);
         assert_eq!(
             args,
             vec![Cow::from("arg1"), Cow::from("arg2"), Cow::from("arg3")]
@@ -178,9 +479,15 @@ mod tests {
 
     #[test]
     fn parse_event_id_args_with_whitespace() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo\x1earg\n1\x1earg\t2\x1earg 3"));
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1earg\n1\x1earg\t2\x1earg 3"));
 
         assert_eq!(label, "foo");
+        assert_eq!(category, None);
         assert_eq!(
             args,
             vec![Cow::from("arg\n1"), Cow::from("arg\t2"), Cow::from("arg 3")]
@@ -189,8 +496,172 @@ mod tests {
 
     #[test]
     fn parse_event_id_args_with_control_char() {
-        let (label, args) = Event::parse_event_id(Cow::from("foo\x1earg\x1b1"));
+        let ParsedEventId { label, args, .. } = Event::parse_event_id(Cow::from("foo\x1earg\x1b1"));
+        assert_eq!(label, "foo");
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn parse_event_id_with_escaped_bytes() {
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            integer_args,
+            backtrace,
+        } = Event::parse_event_id(Cow::from(
+            "foo\x1b\x1ebar\x1e\x12\x1b\x12cat\x1e\x1b\x1342\x1ea\x1b\x1bb\x1b\x1e",
+        ));
+
+        assert_eq!(label, "foo\x1ebar");
+        assert_eq!(category, Some(Cow::from("\x12cat")));
+        assert_eq!(args, vec![Cow::from("\x1342"), Cow::from("a\x1bb\x1e")]);
+        assert!(integer_args.is_empty());
+        assert!(backtrace.is_empty());
+
+        let label = label_bytes(Cow::Borrowed(b"a\x1b\x1e\x1b\x1bb\x1ec"));
+        assert_eq!(&label[..], b"a\x1e\x1bb");
+    }
+
+    #[test]
+    fn parse_event_id_with_category() {
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x12my_category"));
+
+        assert_eq!(label, "foo");
+        assert_eq!(category, Some(Cow::from("my_category")));
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn parse_event_id_with_category_and_args() {
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x12my_category\x1earg1\x1earg2"));
+
         assert_eq!(label, "foo");
+        assert_eq!(category, Some(Cow::from("my_category")));
+        assert_eq!(args, vec![Cow::from("arg1"), Cow::from("arg2")]);
+    }
+
+    #[test]
+    fn parse_event_id_empty_category() {
+        let ParsedEventId {
+            label,
+            category,
+            args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x12"));
+
+        assert_eq!(label, "foo");
+        assert_eq!(category, None);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn parse_event_id_integer_arg() {
+        let ParsedEventId {
+            label,
+            args,
+            integer_args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x1312345"));
+
+        assert_eq!(label, "foo");
+        assert_eq!(args, vec![Cow::from("12345")]);
+        assert_eq!(integer_args, vec![(0, 12345)]);
+    }
+
+    #[test]
+    fn parse_event_id_mixed_args() {
+        let ParsedEventId {
+            label,
+            args,
+            integer_args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1earg1\x1e\x13018446744073709551615"));
+
+        assert_eq!(label, "foo");
+        assert_eq!(
+            args,
+            vec![Cow::from("arg1"), Cow::from("018446744073709551615")]
+        );
+        assert_eq!(integer_args, vec![(1, u64::MAX)]);
+    }
+
+    #[test]
+    fn parse_event_id_integer_arg_not_a_number() {
+        let ParsedEventId {
+            label,
+            args,
+            integer_args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x13abc"));
+
+        assert_eq!(label, "foo");
+        assert!(args.is_empty());
+        assert!(integer_args.is_empty());
+    }
+
+    #[test]
+    fn parse_event_id_integer_arg_overflow() {
+        let ParsedEventId {
+            label,
+            args,
+            integer_args,
+            ..
+        } = Event::parse_event_id(Cow::from("foo\x1e\x1318446744073709551616"));
+
+        assert_eq!(label, "foo");
+        assert!(args.is_empty());
+        assert!(integer_args.is_empty());
+    }
+
+    #[test]
+    fn parse_event_id_backtrace() {
+        let ParsedEventId {
+            label,
+            args,
+            integer_args,
+            backtrace,
+            ..
+        } = Event::parse_event_id(Cow::from(
+            "foo\x1earg1\x1e\x1342\x1e\x14my_crate::inner\x1e\x14<T as my_crate::Outer>::outer",
+        ));
+
+        assert_eq!(label, "foo");
+        assert_eq!(args, vec![Cow::from("arg1"), Cow::from("42")]);
+        assert_eq!(integer_args, vec![(1, 42)]);
+        assert_eq!(
+            backtrace,
+            vec![
+                Cow::from("my_crate::inner"),
+                Cow::from("<T as my_crate::Outer>::outer")
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_event_id_omitted_args() {
+        let event_id = "foo\x1earg1\x1earg2\x1e\x15998";
+        let ParsedEventId { label, args, .. } = Event::parse_event_id(Cow::from(event_id));
+
+        assert_eq!(label, "foo");
+        assert_eq!(args, vec![Cow::from("arg1"), Cow::from("arg2")]);
+        assert_eq!(omitted_args(event_id.as_bytes()), 998);
+
+        let ParsedEventId { args, .. } = Event::parse_event_id(Cow::from("foo\x1e\x15x"));
+
         assert!(args.is_empty());
+        assert_eq!(omitted_args(b"foo\x1e\x15x"), 0);
+        assert_eq!(omitted_args(b"foo\x1b\x1e\x15998\x1ea"), 0);
+        assert_eq!(omitted_args(b"foo\x1e\x12cat\x1e\x1342\x1e\x157"), 7);
     }
 }
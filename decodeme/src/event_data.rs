@@ -0,0 +1,149 @@
+//! Access to the raw bytes of the events stream, which may be stored as is or
+//! as a sequence of individually compressed pages.
+
+use measureme::{decompress_page, decompressed_page_size};
+use std::cell::RefCell;
+use std::error::Error;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub(crate) enum EventData {
+    Uncompressed(Vec<u8>),
+    Compressed(CompressedPages),
+}
+
+impl EventData {
+    /// The size of the (uncompressed) events stream in bytes.
+    pub(crate) fn len(&self) -> usize {
+        match *self {
+            EventData::Uncompressed(ref data) => data.len(),
+            EventData::Compressed(ref pages) => pages.len,
+        }
+    }
+
+    /// Adds the contents of an events page that has been appended to the
+    /// profile, see `EventDecoder::append_pages`.
+    pub(crate) fn push_page(
+        &mut self,
+        page_contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match *self {
+            EventData::Uncompressed(ref mut data) => {
+                data.extend_from_slice(page_contents);
+                Ok(())
+            }
+            EventData::Compressed(ref mut pages) => pages.push_page(page_contents),
+        }
+    }
+
+    /// Calls `f` with the bytes at `range` of the (uncompressed) events
+    /// stream. The range must not cross page boundaries, which is the case for
+    /// the stream header and for single events since these are always written
+    /// atomically.
+    pub(crate) fn with_bytes<R>(&self, range: Range<usize>, f: impl FnOnce(&[u8]) -> R) -> R {
+        match *self {
+            EventData::Uncompressed(ref data) => f(&data[range]),
+            EventData::Compressed(ref pages) => pages.with_bytes(range, f),
+        }
+    }
+}
+
+thread_local! {
+    /// The page that the calling thread has decompressed most recently. Threads
+    /// that decode the events of the same profile, e.g. different ranges of
+    /// them, thus don't have to wait for each other. The page is kept until
+    /// the thread decompresses another one.
+    static PAGE_CACHE: RefCell<Option<CachedPage>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    /// The id of the `CompressedPages` the page belongs to.
+    pages_id: u64,
+    page_index: usize,
+    page: Rc<Vec<u8>>,
+}
+
+/// The compressed pages of an events stream, which are only decompressed
+/// when they are accessed. The most recently used page of each thread is
+/// cached since events are usually read in order.
+#[derive(Debug)]
+pub(crate) struct CompressedPages {
+    /// Tells the pages of different profiles apart in `PAGE_CACHE`.
+    id: u64,
+    codec: u8,
+    pages: Vec<Vec<u8>>,
+    // The address of the first byte of each page within the uncompressed
+    // stream.
+    page_start_addrs: Vec<usize>,
+    len: usize,
+}
+
+impl CompressedPages {
+    pub(crate) fn new(codec: u8) -> CompressedPages {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        CompressedPages {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            codec,
+            pages: Vec::new(),
+            page_start_addrs: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push_page(
+        &mut self,
+        page_contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let page_size = decompressed_page_size(page_contents)?;
+
+        self.pages.push(page_contents.to_vec());
+        self.page_start_addrs.push(self.len);
+        self.len += page_size;
+
+        Ok(())
+    }
+
+    fn with_bytes<R>(&self, range: Range<usize>, f: impl FnOnce(&[u8]) -> R) -> R {
+        if range.is_empty() {
+            return f(&[]);
+        }
+
+        let page_index = self
+            .page_start_addrs
+            .partition_point(|&start_addr| start_addr <= range.start)
+            - 1;
+
+        let page = self.page(page_index);
+        let page_start_addr = self.page_start_addrs[page_index];
+
+        f(&page[range.start - page_start_addr..range.end - page_start_addr])
+    }
+
+    fn page(&self, page_index: usize) -> Rc<Vec<u8>> {
+        PAGE_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+
+            if let Some(ref cached) = *cache {
+                if cached.pages_id == self.id && cached.page_index == page_index {
+                    return cached.page.clone();
+                }
+            }
+
+            let page = Rc::new(
+                decompress_page(self.codec, &self.pages[page_index])
+                    .expect("Invalid file: Corrupt events page"),
+            );
+
+            *cache = Some(CachedPage {
+                pages_id: self.id,
+                page_index,
+                page: page.clone(),
+            });
+            page
+        })
+    }
+}
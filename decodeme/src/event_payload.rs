@@ -1,18 +1,59 @@
-use measureme::RawEvent;
+use crate::timestamp_epochs::unwrap_timestamp;
+use measureme::{RawEvent, TIMESTAMP_PERIOD};
 use std::time::{Duration, SystemTime};
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum EventPayload {
     Timestamp(Timestamp),
     Integer(u64),
+    /// An instant event with a numeric payload, recorded with
+    /// `Profiler::record_instant_event`.
+    InstantWithValue {
+        time: SystemTime,
+        value: u64,
+    },
 }
 
 impl EventPayload {
     pub fn from_raw_event(raw_event: &RawEvent, start_time: SystemTime) -> Self {
+        Self::from_raw_event_in_epoch(raw_event, start_time, 0)
+    }
+
+    /// Like `from_raw_event`, for an event that belongs to the given epoch,
+    /// see `measureme::TIMESTAMP_EPOCH_LENGTH`.
+    pub fn from_raw_event_in_epoch(
+        raw_event: &RawEvent,
+        start_time: SystemTime,
+        epoch: u64,
+    ) -> Self {
+        Self::from_raw_event_in_file(raw_event, start_time, epoch, true)
+    }
+
+    /// Like `from_raw_event_in_epoch`, for an event of a file that predates
+    /// instant events with a value if `!instant_values`, see
+    /// `measureme::file_header::has_instant_values`.
+    pub(crate) fn from_raw_event_in_file(
+        raw_event: &RawEvent,
+        start_time: SystemTime,
+        epoch: u64,
+        instant_values: bool,
+    ) -> Self {
         if raw_event.is_integer() {
             Self::Integer(raw_event.value())
+        } else if raw_event.is_interval_in(instant_values) {
+            Self::Timestamp(Timestamp::from_raw_interval_in_epoch(
+                raw_event, start_time, epoch,
+            ))
+        } else if let Some(value) = raw_event.instant_payload() {
+            let t = unwrap_timestamp(raw_event.instant_timestamp(), epoch);
+            Self::InstantWithValue {
+                time: start_time + Duration::from_nanos(t),
+                value,
+            }
         } else {
-            Self::Timestamp(Timestamp::from_raw_event(raw_event, start_time))
+            Self::Timestamp(Timestamp::from_raw_event_in_epoch(
+                raw_event, start_time, epoch,
+            ))
         }
     }
 
@@ -28,12 +69,15 @@ impl EventPayload {
                     start: other_start,
                     end: other_end,
                 }) => self_start <= other_start && other_end <= self_end,
-                EventPayload::Timestamp(Timestamp::Instant(other_t)) => {
+                EventPayload::Timestamp(Timestamp::Instant(other_t))
+                | EventPayload::InstantWithValue { time: other_t, .. } => {
                     self_start <= other_t && other_t <= self_end
                 }
                 EventPayload::Integer(_) => false,
             },
-            EventPayload::Timestamp(Timestamp::Instant(_)) | EventPayload::Integer(_) => false,
+            EventPayload::Timestamp(Timestamp::Instant(_))
+            | EventPayload::Integer(_)
+            | EventPayload::InstantWithValue { .. } => false,
         }
     }
 
@@ -49,8 +93,12 @@ impl EventPayload {
         matches!(self, &Self::Timestamp(Timestamp::Interval { .. }))
     }
 
+    /// True for instant events, with or without a value.
     pub fn is_instant(&self) -> bool {
-        matches!(self, &Self::Timestamp(Timestamp::Instant(_)))
+        matches!(
+            self,
+            &Self::Timestamp(Timestamp::Instant(_)) | &Self::InstantWithValue { .. }
+        )
     }
 
     pub fn is_integer(&self) -> bool {
@@ -61,15 +109,24 @@ impl EventPayload {
         match self {
             Self::Timestamp(t) => Some(*t),
             Self::Integer(_) => None,
+            Self::InstantWithValue { time, .. } => Some(Timestamp::Instant(*time)),
         }
     }
 
     pub fn integer(&self) -> Option<u64> {
         match self {
-            Self::Timestamp(_) => None,
+            Self::Timestamp(_) | Self::InstantWithValue { .. } => None,
             Self::Integer(i) => Some(*i),
         }
     }
+
+    /// The value of an instant event that has been recorded with one.
+    pub fn instant_value(&self) -> Option<u64> {
+        match self {
+            Self::InstantWithValue { value, .. } => Some(*value),
+            Self::Timestamp(_) | Self::Integer(_) => None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -80,14 +137,49 @@ pub enum Timestamp {
 
 impl Timestamp {
     pub fn from_raw_event(raw_event: &RawEvent, start_time: SystemTime) -> Self {
+        Self::from_raw_event_in_epoch(raw_event, start_time, 0)
+    }
+
+    /// Like `from_raw_event`, for an event that belongs to the given epoch,
+    /// see `measureme::TIMESTAMP_EPOCH_LENGTH`.
+    pub fn from_raw_event_in_epoch(
+        raw_event: &RawEvent,
+        start_time: SystemTime,
+        epoch: u64,
+    ) -> Self {
         debug_assert!(!raw_event.is_integer());
         if raw_event.is_instant() {
-            let t = start_time + Duration::from_nanos(raw_event.start_value());
-            Self::Instant(t)
+            let t = unwrap_timestamp(raw_event.instant_timestamp(), epoch);
+            Self::Instant(start_time + Duration::from_nanos(t))
+        } else {
+            Self::from_raw_interval_in_epoch(raw_event, start_time, epoch)
+        }
+    }
+
+    /// `from_raw_event_in_epoch` for an interval event.
+    fn from_raw_interval_in_epoch(
+        raw_event: &RawEvent,
+        start_time: SystemTime,
+        epoch: u64,
+    ) -> Self {
+        let end = unwrap_timestamp(raw_event.end_value(), epoch);
+        let start = if epoch == 0 {
+            // The values are stored as they are, so an interval that ends
+            // before it starts stays that way, see `ProfilingData::validate`.
+            raw_event.start_value()
         } else {
-            let start = start_time + Duration::from_nanos(raw_event.start_value());
-            let end = start_time + Duration::from_nanos(raw_event.end_value());
-            Timestamp::Interval { start, end }
+            // Intervals are shorter than `TIMESTAMP_PERIOD`, so a start
+            // value greater than the end value means that the counter has
+            // wrapped around in between.
+            let duration = (raw_event.end_value() % TIMESTAMP_PERIOD + TIMESTAMP_PERIOD
+                - raw_event.start_value() % TIMESTAMP_PERIOD)
+                % TIMESTAMP_PERIOD;
+            end.saturating_sub(duration)
+        };
+
+        Timestamp::Interval {
+            start: start_time + Duration::from_nanos(start),
+            end: start_time + Duration::from_nanos(end),
         }
     }
 
@@ -0,0 +1,312 @@
+//! Reading the events of a profile straight from its file, one page at a
+//! time, for profiles that are too large to be loaded into memory as a whole.
+
+use crate::event_payload::EventPayload;
+use crate::lightweight_event::LightweightEvent;
+use crate::stringtable::StringTable;
+use crate::timestamp_epochs::TimestampEpochs;
+use crate::{epoch_marker_kind, find_marker_kinds, Metadata, RAW_EVENT_SIZE};
+use measureme::file_header::{
+    has_instant_values, has_timestamp_epoch_index, verify_file_header,
+    verify_top_level_file_header, FILE_CODEC_NONE, FILE_FLAG_NESTING_DEPTH, FILE_HEADER_SIZE,
+    FILE_MAGIC_EVENT_STREAM,
+};
+use measureme::{
+    decode_timestamp_epoch_index, decompress_page, decompressed_page_size, is_optional_page_tag,
+    PageTag, RawEvent, PAGE_HEADER_SIZE,
+};
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// The events of a profile, decoded as they are read from `reader`. Only the
+/// strings of the profile are kept in memory, and the events page that is
+/// being decoded, so memory usage doesn't grow with the number of events.
+/// The events are the same as those of an `EventDecoder` for the same file,
+/// with the same indices, also if the file has a truncated tail.
+///
+/// Opening the stream reads the page headers of the whole file, and the
+/// timestamp epoch markers of long profiles at the positions the file's
+/// timestamp epoch index gives. Only files without the index, e.g. those of
+/// older versions, have all their events pages read once to find them.
+pub struct EventStream<R> {
+    reader: R,
+    diagnostic_file_path: Option<PathBuf>,
+    codec: u8,
+    file_flags: u8,
+    /// See `measureme::file_header::has_instant_values`.
+    instant_values: bool,
+    metadata: Metadata,
+    timestamp_epochs: TimestampEpochs,
+    /// The position and size of the contents of each events page within the
+    /// file, and the address of its first byte within the (uncompressed)
+    /// events stream.
+    event_pages: Vec<(u64, usize, usize)>,
+    /// The size of the (uncompressed) events stream in bytes.
+    events_len: usize,
+    next_page: usize,
+    page: Vec<u8>,
+    pos_in_page: usize,
+    next_event_index: usize,
+}
+
+impl<R: Read + Seek> EventStream<R> {
+    pub fn new(
+        mut reader: R,
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<EventStream<R>, Box<dyn Error + Send + Sync>> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let instant_values = has_instant_values(&header);
+        let epoch_index = has_timestamp_epoch_index(&header);
+        let header = verify_top_level_file_header(&header, diagnostic_file_path)?;
+
+        // The strings are read right away, the events pages only found. The
+        // pages after the first incomplete or corrupt one are ignored, like
+        // `EventDecoder` does.
+        let mut string_data = Vec::new();
+        let mut index_data = Vec::new();
+        let mut epoch_index_data = Vec::new();
+        let mut event_pages = Vec::new();
+        let mut events_len = 0;
+        let mut relocated = false;
+        let mut pos = FILE_HEADER_SIZE as u64;
+        while pos + PAGE_HEADER_SIZE as u64 <= file_len {
+            let mut page_header = [0u8; PAGE_HEADER_SIZE];
+            reader.read_exact(&mut page_header)?;
+            let page_size = u32::from_le_bytes(page_header[1..].try_into().unwrap()) as usize;
+            let contents_pos = pos + PAGE_HEADER_SIZE as u64;
+            if page_size == 0 || contents_pos + page_size as u64 > file_len {
+                break;
+            }
+
+            match PageTag::try_from(page_header[0]) {
+                Ok(PageTag::StringData) => read_contents(&mut reader, page_size, &mut string_data)?,
+                Ok(PageTag::StringIndex) => read_contents(&mut reader, page_size, &mut index_data)?,
+                Ok(PageTag::TimestampEpochIndex) if epoch_index => {
+                    read_contents(&mut reader, page_size, &mut epoch_index_data)?
+                }
+                Ok(PageTag::Events) => {
+                    // Compressed pages start with their decompressed size.
+                    let mut prefix = Vec::new();
+                    if header.codec != FILE_CODEC_NONE {
+                        read_contents(&mut reader, std::cmp::min(page_size, 4), &mut prefix)?;
+                    }
+                    reader.seek(SeekFrom::Current((page_size - prefix.len()) as i64))?;
+
+                    event_pages.push((contents_pos, page_size, events_len));
+                    events_len += if header.codec != FILE_CODEC_NONE {
+                        decompressed_page_size(&prefix)?
+                    } else {
+                        page_size
+                    };
+                }
+                Ok(tag) => {
+                    relocated |= tag == PageTag::Segment;
+                    reader.seek(SeekFrom::Current(page_size as i64))?;
+                }
+                Err(_) if is_optional_page_tag(page_header[0]) => {
+                    reader.seek(SeekFrom::Current(page_size as i64))?;
+                }
+                Err(_) => break,
+            }
+            pos = contents_pos + page_size as u64;
+        }
+
+        if event_pages.is_empty() {
+            return Err(From::from("Invalid file: No event data found"));
+        }
+
+        let mut stringtable = StringTable::new(string_data, index_data, diagnostic_file_path)?;
+        if relocated {
+            stringtable = stringtable.with_relocated_ids();
+        }
+        let metadata: Metadata = serde_json::from_str(&stringtable.get_metadata().to_string())?;
+
+        let mut stream = EventStream {
+            reader,
+            diagnostic_file_path: diagnostic_file_path.map(Path::to_path_buf),
+            codec: header.codec,
+            file_flags: header.flags,
+            instant_values,
+            metadata,
+            timestamp_epochs: TimestampEpochs::default(),
+            event_pages,
+            events_len,
+            next_page: 0,
+            page: Vec::new(),
+            pos_in_page: 0,
+            next_event_index: 0,
+        };
+
+        let epoch_index = decode_timestamp_epoch_index(&epoch_index_data, diagnostic_file_path)?;
+        let marker_kinds = find_marker_kinds(&stringtable);
+        let mut is_marker = |raw_event: &RawEvent| epoch_marker_kind(&marker_kinds, raw_event);
+        let num_events = stream.events_len.saturating_sub(FILE_HEADER_SIZE) / RAW_EVENT_SIZE;
+        let indexed_epochs = match epoch_index {
+            Some(ref index) => TimestampEpochs::from_index(
+                index,
+                num_events,
+                |event_index| stream.raw_event_at(event_index),
+                &mut is_marker,
+            )?,
+            None => None,
+        };
+
+        let timestamp_epochs = match indexed_epochs {
+            Some(epochs) => epochs,
+            None => {
+                stream.rewind();
+                let mut read_error = None;
+                let timestamp_epochs = TimestampEpochs::from_events(
+                    std::iter::from_fn(|| match stream.next_raw_event() {
+                        Ok(raw_event) => raw_event,
+                        Err(e) => {
+                            read_error = Some(e);
+                            None
+                        }
+                    })
+                    .enumerate(),
+                    is_marker,
+                );
+                if let Some(e) = read_error {
+                    return Err(e);
+                }
+                timestamp_epochs
+            }
+        };
+
+        stream.timestamp_epochs = timestamp_epochs;
+        stream.rewind();
+        Ok(stream)
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The `FILE_FLAG_*` bits of the top-level file header.
+    pub fn file_flags(&self) -> u8 {
+        self.file_flags
+    }
+
+    fn rewind(&mut self) {
+        self.next_page = 0;
+        self.page.clear();
+        self.pos_in_page = 0;
+        self.next_event_index = 0;
+    }
+
+    /// Reads the next raw event, and the next events page first if the
+    /// current one has been decoded completely. `None` after the last event.
+    fn next_raw_event(&mut self) -> Result<Option<RawEvent>, Box<dyn Error + Send + Sync>> {
+        while self.pos_in_page + RAW_EVENT_SIZE > self.page.len() {
+            if self.next_page == self.event_pages.len() {
+                return Ok(None);
+            }
+            self.read_page()?;
+        }
+
+        let bytes = &self.page[self.pos_in_page..self.pos_in_page + RAW_EVENT_SIZE];
+        let mut raw_event = RawEvent::deserialize(bytes);
+        if self.file_flags & FILE_FLAG_NESTING_DEPTH != 0
+            && raw_event.is_interval_in(self.instant_values)
+        {
+            raw_event.take_nesting_depth();
+        }
+
+        self.pos_in_page += RAW_EVENT_SIZE;
+        Ok(Some(raw_event))
+    }
+
+    /// Reads the raw event at `event_index`, by reading the events page that
+    /// contains it. The stream has to be rewound afterwards.
+    fn raw_event_at(
+        &mut self,
+        event_index: usize,
+    ) -> Result<RawEvent, Box<dyn Error + Send + Sync>> {
+        let addr = FILE_HEADER_SIZE + event_index * RAW_EVENT_SIZE;
+        let page_index = self
+            .event_pages
+            .partition_point(|&(_, _, start_addr)| start_addr <= addr)
+            - 1;
+
+        self.next_page = page_index;
+        self.read_page()?;
+        self.pos_in_page = addr - self.event_pages[page_index].2;
+        match self.next_raw_event()? {
+            Some(raw_event) => Ok(raw_event),
+            None => Err(From::from("Invalid file: Truncated events page")),
+        }
+    }
+
+    fn read_page(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (pos, size, _) = self.event_pages[self.next_page];
+        self.reader.seek(SeekFrom::Start(pos))?;
+        self.page.clear();
+        read_contents(&mut self.reader, size, &mut self.page)?;
+        if self.codec != FILE_CODEC_NONE {
+            self.page = decompress_page(self.codec, &self.page)?;
+        }
+
+        // The events stream starts with a header of its own.
+        self.pos_in_page = 0;
+        if self.next_page == 0 {
+            let header_len = std::cmp::min(self.page.len(), FILE_HEADER_SIZE);
+            verify_file_header(
+                &self.page[..header_len],
+                FILE_MAGIC_EVENT_STREAM,
+                self.diagnostic_file_path.as_deref(),
+                "event",
+            )?;
+            self.pos_in_page = FILE_HEADER_SIZE;
+        }
+
+        self.next_page += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Iterator for EventStream<R> {
+    type Item = Result<LightweightEvent, Box<dyn Error + Send + Sync>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw_event = match self.next_raw_event() {
+            Ok(raw_event) => raw_event?,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let event_index = self.next_event_index;
+        self.next_event_index += 1;
+
+        let epoch = self
+            .timestamp_epochs
+            .epoch(event_index, raw_event.thread_id);
+        Some(Ok(LightweightEvent {
+            event_index,
+            thread_id: raw_event.thread_id,
+            payload: EventPayload::from_raw_event_in_file(
+                &raw_event,
+                self.metadata.start_time,
+                epoch,
+                self.instant_values,
+            ),
+        }))
+    }
+}
+
+/// Appends the next `size` bytes of `reader` to `buffer`.
+fn read_contents(
+    reader: &mut impl Read,
+    size: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let start = buffer.len();
+    buffer.resize(start + size, 0);
+    reader.read_exact(&mut buffer[start..])?;
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use std::convert::TryInto;
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     error::Error,
     mem,
     path::Path,
@@ -7,26 +9,48 @@ use std::{
 };
 
 use event::Event;
+use event_data::{CompressedPages, EventData};
 use event_payload::EventPayload;
 use lightweight_event::LightweightEvent;
-use measureme::file_header::{verify_file_header, FILE_MAGIC_EVENT_STREAM};
+use measureme::decode_timestamp_epoch_index;
+use measureme::file_header::{
+    has_instant_values, has_timestamp_epoch_index, verify_file_header,
+    verify_top_level_file_header, FILE_CODEC_NONE, FILE_MAGIC_EVENT_STREAM,
+};
+use measureme::{
+    StringId, COUNTER_UNAVAILABLE_EVENT_KIND, PARENT_EVENT_ID_EVENT_KIND,
+    THREAD_TIMESTAMP_EPOCH_EVENT_KIND, TIMESTAMP_EPOCH_EVENT_KIND, TRACE_CONTEXT_EVENT_KIND,
+    WALL_TIME_EVENT_KIND,
+};
 
 pub mod event;
+mod event_data;
 pub mod event_payload;
+pub mod event_stream;
 pub mod lightweight_event;
 pub mod stringtable;
+mod timestamp_epochs;
+pub mod verify;
 
 // These re-exports allow us to use some types from the measureme version tied to this
 // version of decodeme, with explicitly mentioning that measureme version in downstream
 // Cargo.tomls.
 pub use measureme::file_header::CURRENT_FILE_FORMAT_VERSION;
+pub use measureme::file_header::FILE_FLAG_EXPLICIT_PARENTS;
+pub use measureme::file_header::FILE_FLAG_NESTING_DEPTH;
+pub use measureme::file_header::FILE_FLAG_SAMPLED;
+pub use measureme::file_header::FILE_FLAG_TRACE_CONTEXT;
+pub use measureme::file_header::FILE_FLAG_WALL_TIME;
 pub use measureme::file_header::FILE_HEADER_SIZE;
 pub use measureme::file_header::FILE_MAGIC_TOP_LEVEL;
 pub use measureme::PageTag;
 pub use measureme::RawEvent;
+pub use measureme::{TraceContext, TraceContexts};
 
-use serde::{Deserialize, Deserializer};
-use stringtable::StringTable;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stringtable::{StringMap, StringTable};
+use timestamp_epochs::TimestampEpochs;
 
 fn system_time_from_nanos<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
 where
@@ -38,12 +62,110 @@ where
         .expect("a time that can be represented as SystemTime"))
 }
 
-#[derive(Debug, Deserialize)]
+fn optional_system_time_from_nanos<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    system_time_from_nanos(deserializer).map(Some)
+}
+
+fn system_time_to_nanos<S: Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    serializer.serialize_u64(nanos as u64)
+}
+
+fn optional_system_time_to_nanos<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    system_time_to_nanos(time.as_ref().unwrap(), serializer)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Metadata {
-    #[serde(deserialize_with = "system_time_from_nanos")]
+    #[serde(
+        deserialize_with = "system_time_from_nanos",
+        serialize_with = "system_time_to_nanos"
+    )]
     pub start_time: SystemTime,
     pub process_id: u32,
     pub cmd: String,
+    /// The counter that the event timestamps have been measured with. This is
+    /// missing in profiles written by older versions of measureme, which
+    /// always measured wall time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<CounterDescription>,
+    /// The maximum size of the file's pages. This is informational only,
+    /// since every page header contains the size of the page. Missing in
+    /// profiles written by older versions of measureme.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u64>,
+    /// The wall-clock time at which the counter read zero, i.e. the time the
+    /// timestamps of the events are relative to. `start_time` is only
+    /// roughly that, since it is taken a little later. Missing for counters
+    /// that don't measure wall time and in profiles written by older
+    /// versions of measureme.
+    #[serde(
+        default,
+        deserialize_with = "optional_system_time_from_nanos",
+        serialize_with = "optional_system_time_to_nanos",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub wall_clock_start: Option<SystemTime>,
+}
+
+/// Describes a `measureme::counters::Counter`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CounterDescription {
+    /// The name under which the counter can be selected, e.g. `wall-time` or
+    /// `cache-misses:u`.
+    pub name: String,
+    /// The units of the counter's values, each with the number of base units
+    /// it corresponds to, from smallest to largest, e.g. `[("ns", 1), ("μs", 1000)]`.
+    pub units: Vec<(String, u64)>,
+}
+
+impl CounterDescription {
+    /// Whether the counter's values are nanoseconds, as opposed to counts of
+    /// e.g. instructions.
+    pub fn measures_time(&self) -> bool {
+        match self.units.first() {
+            Some((unit, _)) => unit == "ns",
+            None => false,
+        }
+    }
+
+    /// The name of the counter's smallest unit, e.g. `instructions`.
+    pub fn base_unit(&self) -> &str {
+        match self.units.first() {
+            Some((unit, _)) => unit,
+            None => "",
+        }
+    }
+
+    /// Whether the counter counts retired instructions, e.g. `instructions:u`.
+    pub fn counts_instructions(&self) -> bool {
+        self.base_unit() == "instructions"
+    }
+
+    /// The value of the counter that `duration`, the duration of an event or
+    /// e.g. the sum of the self times of several, stands for in profiles
+    /// recorded with this counter. The durations in profiles recorded with a
+    /// counter that doesn't measure time are counter values taken as
+    /// nanoseconds. `None` for counters that measure time.
+    pub fn counter_value(&self, duration: Duration) -> Option<u64> {
+        if self.measures_time() {
+            return None;
+        }
+
+        Some(duration.as_nanos() as u64)
+    }
 }
 
 #[must_use]
@@ -94,30 +216,142 @@ const RAW_EVENT_SIZE: usize = std::mem::size_of::<RawEvent>();
 
 #[derive(Debug)]
 pub struct EventDecoder {
-    event_data: Vec<u8>,
+    event_data: EventData,
     stringtable: StringTable,
     metadata: Metadata,
+    file_flags: u8,
+    /// See `measureme::file_header::has_instant_values`.
+    instant_values: bool,
+    truncated_bytes: usize,
+    process_metadata: BTreeMap<String, String>,
+    trace_contexts: TraceContexts,
+    timestamp_epochs: TimestampEpochs,
+    marker_kinds: FxHashMap<StringId, MarkerKind>,
+    /// The contents of the metadata stream, see `append_pages`.
+    metadata_data: Vec<u8>,
+    /// The size of the trace context stream, see `append_pages`.
+    trace_context_len: usize,
+    /// See `scan_timestamp_epochs`.
+    pending_epoch_markers: Vec<usize>,
 }
 
 impl EventDecoder {
+    /// Decodes the contents of a `.mm_profdata` file. If the file ends with
+    /// an incomplete or corrupt page, e.g. because the profiled process
+    /// crashed while writing it, that page is ignored and only the events in
+    /// the pages before it are decoded, see `truncated_bytes`.
     pub fn new(
-        entire_file_data: Vec<u8>,
+        mut entire_file_data: Vec<u8>,
         diagnostic_file_path: Option<&Path>,
     ) -> Result<EventDecoder, Box<dyn Error + Send + Sync>> {
-        verify_file_header(
-            &entire_file_data,
-            FILE_MAGIC_TOP_LEVEL,
-            diagnostic_file_path,
-            "top-level",
-        )?;
+        let header = verify_top_level_file_header(&entire_file_data, diagnostic_file_path)?;
+
+        let complete_len =
+            FILE_HEADER_SIZE + measureme::complete_pages_len(&entire_file_data[FILE_HEADER_SIZE..]);
+        let truncated_bytes = entire_file_data.len() - complete_len;
+        entire_file_data.truncate(complete_len);
+
+        if header.codec != FILE_CODEC_NONE {
+            let mut decoder = Self::from_compressed_file_data(
+                &entire_file_data,
+                header.codec,
+                diagnostic_file_path,
+            )?;
+            decoder.file_flags = header.flags;
+            decoder.instant_values = has_instant_values(&entire_file_data);
+            decoder.truncated_bytes = truncated_bytes;
+            return Ok(decoder);
+        }
 
         let mut split_data = measureme::split_streams(&entire_file_data[FILE_HEADER_SIZE..]);
 
-        let string_data = split_data.remove(&PageTag::StringData).expect("Invalid file: No string data found");
-        let index_data = split_data.remove(&PageTag::StringIndex).expect("Invalid file: No string index data found");
-        let event_data = split_data.remove(&PageTag::Events).expect("Invalid file: No event data found");
+        // These are missing if e.g. a profile that is being read from a pipe
+        // has been cut off before its first complete pages.
+        let string_data = split_data
+            .remove(&PageTag::StringData)
+            .ok_or("Invalid file: No string data found")?;
+        let index_data = split_data
+            .remove(&PageTag::StringIndex)
+            .ok_or("Invalid file: No string index data found")?;
+        let event_data = split_data
+            .remove(&PageTag::Events)
+            .ok_or("Invalid file: No event data found")?;
+        let metadata_data = split_data.remove(&PageTag::Metadata).unwrap_or_default();
+        let trace_context_data = split_data
+            .remove(&PageTag::TraceContext)
+            .unwrap_or_default();
+        let epoch_index_data = split_data
+            .remove(&PageTag::TimestampEpochIndex)
+            .filter(|_| has_timestamp_epoch_index(&entire_file_data))
+            .unwrap_or_default();
+        let relocated = split_data.contains_key(&PageTag::Segment);
+
+        let mut decoder = Self::from_event_data(
+            string_data,
+            index_data,
+            EventData::Uncompressed(event_data),
+            &epoch_index_data,
+            relocated,
+            diagnostic_file_path,
+        )?;
+        decoder.file_flags = header.flags;
+        decoder.instant_values = has_instant_values(&entire_file_data);
+        decoder.truncated_bytes = truncated_bytes;
+        decoder.process_metadata =
+            measureme::decode_process_metadata(&metadata_data, diagnostic_file_path)?;
+        decoder.trace_contexts =
+            measureme::decode_trace_contexts(&trace_context_data, diagnostic_file_path)?;
+        decoder.metadata_data = metadata_data;
+        decoder.trace_context_len = trace_context_data.len();
+        Ok(decoder)
+    }
+
+    /// Reads a file whose events pages have been compressed with `codec`. The
+    /// pages are kept compressed and only decompressed on demand.
+    fn from_compressed_file_data(
+        entire_file_data: &[u8],
+        codec: u8,
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<EventDecoder, Box<dyn Error + Send + Sync>> {
+        let mut string_data = Vec::new();
+        let mut index_data = Vec::new();
+        let mut metadata_data = Vec::new();
+        let mut trace_context_data = Vec::new();
+        let mut epoch_index_data = Vec::new();
+        let mut event_pages = CompressedPages::new(codec);
+        let mut relocated = false;
+
+        for (tag, page_contents) in measureme::iter_pages(&entire_file_data[FILE_HEADER_SIZE..]) {
+            match tag {
+                PageTag::StringData => string_data.extend_from_slice(page_contents),
+                PageTag::StringIndex => index_data.extend_from_slice(page_contents),
+                PageTag::Events => event_pages.push_page(page_contents)?,
+                PageTag::Metadata => metadata_data.extend_from_slice(page_contents),
+                PageTag::TraceContext => trace_context_data.extend_from_slice(page_contents),
+                PageTag::TimestampEpochIndex => {
+                    if has_timestamp_epoch_index(entire_file_data) {
+                        epoch_index_data.extend_from_slice(page_contents)
+                    }
+                }
+                PageTag::Segment => relocated = true,
+            }
+        }
 
-        Self::from_separate_buffers(string_data, index_data, event_data, diagnostic_file_path)
+        let mut decoder = Self::from_event_data(
+            string_data,
+            index_data,
+            EventData::Compressed(event_pages),
+            &epoch_index_data,
+            relocated,
+            diagnostic_file_path,
+        )?;
+        decoder.process_metadata =
+            measureme::decode_process_metadata(&metadata_data, diagnostic_file_path)?;
+        decoder.trace_contexts =
+            measureme::decode_trace_contexts(&trace_context_data, diagnostic_file_path)?;
+        decoder.metadata_data = metadata_data;
+        decoder.trace_context_len = trace_context_data.len();
+        Ok(decoder)
     }
 
     pub fn from_separate_buffers(
@@ -126,23 +360,168 @@ impl EventDecoder {
         event_data: Vec<u8>,
         diagnostic_file_path: Option<&Path>,
     ) -> Result<EventDecoder, Box<dyn Error + Send + Sync>> {
-        verify_file_header(
-            &event_data,
-            FILE_MAGIC_EVENT_STREAM,
+        Self::from_event_data(
+            string_data,
+            index_data,
+            EventData::Uncompressed(event_data),
+            &[],
+            false,
             diagnostic_file_path,
-            "event",
-        )?;
+        )
+    }
 
-        let stringtable = StringTable::new(string_data, index_data, diagnostic_file_path)?;
+    /// `epoch_index_data` is the timestamp epoch index stream, see
+    /// `measureme::decode_timestamp_epoch_index`. Without it, all events are
+    /// scanned for the epoch markers. `relocated` is set for the segments of
+    /// rotated profiles, see `StringTable::with_relocated_ids`.
+    fn from_event_data(
+        string_data: Vec<u8>,
+        index_data: Vec<u8>,
+        event_data: EventData,
+        epoch_index_data: &[u8],
+        relocated: bool,
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<EventDecoder, Box<dyn Error + Send + Sync>> {
+        let header_len = std::cmp::min(event_data.len(), FILE_HEADER_SIZE);
+        event_data.with_bytes(0..header_len, |header| {
+            verify_file_header(
+                header,
+                FILE_MAGIC_EVENT_STREAM,
+                diagnostic_file_path,
+                "event",
+            )
+        })?;
+
+        let mut stringtable = StringTable::new(string_data, index_data, diagnostic_file_path)?;
+        if relocated {
+            stringtable = stringtable.with_relocated_ids();
+        }
 
         let metadata = stringtable.get_metadata().to_string();
         let metadata: Metadata = serde_json::from_str(&metadata)?;
+        let marker_kinds = find_marker_kinds(&stringtable);
 
-        Ok(EventDecoder {
+        let mut decoder = EventDecoder {
             event_data,
             stringtable,
             metadata,
-        })
+            file_flags: 0,
+            instant_values: true,
+            truncated_bytes: 0,
+            process_metadata: BTreeMap::new(),
+            trace_contexts: TraceContexts::default(),
+            timestamp_epochs: TimestampEpochs::default(),
+            marker_kinds,
+            metadata_data: Vec::new(),
+            trace_context_len: 0,
+            pending_epoch_markers: Vec::new(),
+        };
+
+        let epoch_index = decode_timestamp_epoch_index(epoch_index_data, diagnostic_file_path)?;
+        let indexed_epochs = match epoch_index {
+            Some(ref index) => TimestampEpochs::from_index(
+                index,
+                decoder.num_events(),
+                |event_index| Ok(decoder.raw_event(event_index).0),
+                |raw_event| epoch_marker_kind(&decoder.marker_kinds, raw_event),
+            )?,
+            None => None,
+        };
+        match indexed_epochs {
+            Some(epochs) => decoder.timestamp_epochs = epochs,
+            None => decoder.scan_timestamp_epochs(0..decoder.num_events()),
+        }
+
+        Ok(decoder)
+    }
+
+    /// Adds the events and strings of `pages`, the complete pages that have
+    /// been appended to the file since it has been decoded, e.g. because it
+    /// is still being written, see `ProfilingData::open_incremental`. Only
+    /// these pages are decoded, and only the new strings and events are
+    /// indexed. The metadata stream is small, so it is decoded anew if it has
+    /// grown.
+    pub fn append_pages(
+        &mut self,
+        pages: &[u8],
+        diagnostic_file_path: Option<&Path>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let num_events = self.num_events();
+        let metadata_len = self.metadata_data.len();
+
+        let mut string_data = Vec::new();
+        let mut index_data = Vec::new();
+        for (tag, page_contents) in measureme::iter_pages(pages) {
+            match tag {
+                PageTag::StringData => string_data.extend_from_slice(page_contents),
+                PageTag::StringIndex => index_data.extend_from_slice(page_contents),
+                PageTag::Events => self.event_data.push_page(page_contents)?,
+                PageTag::Metadata => self.metadata_data.extend_from_slice(page_contents),
+                PageTag::TraceContext => {
+                    // The first page of the stream starts with its prelude.
+                    if self.trace_context_len == 0 {
+                        self.trace_contexts =
+                            measureme::decode_trace_contexts(page_contents, diagnostic_file_path)?;
+                    } else {
+                        let entries = measureme::decode_trace_context_entries(page_contents)?;
+                        self.trace_contexts.entries.extend(entries);
+                    }
+                    self.trace_context_len += page_contents.len();
+                }
+                // The timestamp epoch index only saves scanning all events of
+                // a profile when it is loaded, the new events are scanned.
+                // The segment page comes first, before the table is created.
+                PageTag::TimestampEpochIndex | PageTag::Segment => {}
+            }
+        }
+
+        if self.metadata_data.len() > metadata_len {
+            self.process_metadata =
+                measureme::decode_process_metadata(&self.metadata_data, diagnostic_file_path)?;
+        }
+
+        let new_strings = self.stringtable.extend(&string_data, &index_data);
+        let names = MARKER_KINDS.map(|(name, _)| name);
+        let found = self.stringtable.find_strings_among(new_strings, &names);
+        self.marker_kinds.extend(marker_kinds(found));
+
+        self.scan_timestamp_epochs(num_events..self.num_events());
+        Ok(())
+    }
+
+    /// Adds the timestamp epoch markers among the events at `event_indices`,
+    /// which come after the events that have been scanned before, to
+    /// `timestamp_epochs`. Integer events whose event kind hasn't been
+    /// written yet, which happens while the profile is still being written,
+    /// are looked at again by the next call.
+    fn scan_timestamp_epochs(&mut self, event_indices: impl Iterator<Item = usize>) {
+        let mut timestamp_epochs = mem::take(&mut self.timestamp_epochs);
+        let candidates = mem::take(&mut self.pending_epoch_markers)
+            .into_iter()
+            .chain(event_indices);
+
+        let strings = self.stringtable.map();
+        let mut known_kinds = FxHashMap::default();
+        let mut pending = Vec::new();
+        let events = candidates
+            .map(|event_index| (event_index, self.raw_event(event_index).0))
+            .filter(|&(event_index, ref raw_event)| {
+                let kind = raw_event.event_kind;
+                let known = *known_kinds
+                    .entry(kind)
+                    .or_insert_with(|| strings.get(kind).is_some());
+                if raw_event.is_integer() && !known {
+                    pending.push(event_index);
+                    return false;
+                }
+                true
+            });
+        timestamp_epochs.extend(events, |raw_event| {
+            epoch_marker_kind(&self.marker_kinds, raw_event)
+        });
+
+        self.timestamp_epochs = timestamp_epochs;
+        self.pending_epoch_markers = pending;
     }
 
     pub fn num_events(&self) -> usize {
@@ -155,41 +534,249 @@ impl EventDecoder {
         &self.metadata
     }
 
-    pub fn decode_full_event<'a>(&'a self, event_index: usize) -> Event<'a> {
+    /// The `FILE_FLAG_*` bits of the top-level file header. Always `0` for
+    /// decoders created from separate buffers, which lack such a header.
+    pub fn file_flags(&self) -> u8 {
+        self.file_flags
+    }
+
+    /// The number of bytes at the end of the file that have been ignored
+    /// because they don't form a complete page, see `new`. Events and strings
+    /// in these bytes are lost, so strings may decode as `<unknown>`. `0` for
+    /// complete files.
+    pub fn truncated_bytes(&self) -> usize {
+        self.truncated_bytes
+    }
+
+    /// The key/value pairs recorded with `Profiler::record_metadata`. Always
+    /// empty for decoders created from separate buffers.
+    pub fn process_metadata(&self) -> &BTreeMap<String, String> {
+        &self.process_metadata
+    }
+
+    /// The trace contexts recorded with
+    /// `Profiler::start_recording_interval_event_with_trace_context`, looked
+    /// up by the value of the `TRACE_CONTEXT_EVENT_KIND` markers that refer to
+    /// them. Always empty for decoders created from separate buffers.
+    pub fn trace_contexts(&self) -> &TraceContexts {
+        &self.trace_contexts
+    }
+
+    /// The strings of the string table, see `StringMap`.
+    pub fn string_map(&self) -> StringMap<'_> {
+        self.stringtable.map()
+    }
+
+    /// The event at `event_index`. In profiles with `FILE_FLAG_NESTING_DEPTH`,
+    /// the nesting depth of interval events is removed from their thread id
+    /// and returned separately.
+    fn raw_event(&self, event_index: usize) -> (RawEvent, Option<u32>) {
         let event_start_addr = event_index_to_addr(event_index);
         let event_end_addr = event_start_addr.checked_add(RAW_EVENT_SIZE).unwrap();
 
-        let raw_event_bytes = &self.event_data[event_start_addr..event_end_addr];
-        let raw_event = RawEvent::deserialize(raw_event_bytes);
+        let mut raw_event = self
+            .event_data
+            .with_bytes(event_start_addr..event_end_addr, RawEvent::deserialize);
+
+        let depth = if self.file_flags & FILE_FLAG_NESTING_DEPTH != 0
+            && raw_event.is_interval_in(self.instant_values)
+        {
+            Some(raw_event.take_nesting_depth())
+        } else {
+            None
+        };
+
+        (raw_event, depth)
+    }
+
+    fn payload(&self, event_index: usize, raw_event: &RawEvent) -> EventPayload {
+        let epoch = self
+            .timestamp_epochs
+            .epoch(event_index, raw_event.thread_id);
+        EventPayload::from_raw_event_in_file(
+            raw_event,
+            self.metadata.start_time,
+            epoch,
+            self.instant_values,
+        )
+    }
+
+    pub fn decode_full_event<'a>(&'a self, event_index: usize) -> Event<'a> {
+        let (raw_event, depth) = self.raw_event(event_index);
 
         let stringtable = &self.stringtable;
 
-        let payload = EventPayload::from_raw_event(&raw_event, self.metadata.start_time);
+        let payload = self.payload(event_index, &raw_event);
 
         let event_id = stringtable
             .get(raw_event.event_id.to_string_id())
             .to_string();
 
-        // Parse out the label and arguments from the `event_id`.
-        let (label, additional_data) = Event::parse_event_id(event_id);
+        // Parse out the label, category and arguments from the `event_id`.
+        let parsed_event_id = Event::parse_event_id(event_id);
 
         Event {
             event_kind: stringtable.get(raw_event.event_kind).to_string(),
-            label,
-            additional_data,
+            label: parsed_event_id.label,
+            category: parsed_event_id.category,
+            additional_data: parsed_event_id.args,
+            integer_args: parsed_event_id.integer_args,
+            backtrace: parsed_event_id.backtrace,
             payload,
             thread_id: raw_event.thread_id,
+            depth,
         }
     }
 
-    pub fn decode_lightweight_event<'a>(&'a self, event_index: usize) -> LightweightEvent {
-        let event_start_addr = event_index_to_addr(event_index);
-        let event_end_addr = event_start_addr.checked_add(RAW_EVENT_SIZE).unwrap();
+    /// The id of the string that is the event id of the event at
+    /// `event_index`. Events with equal event ids usually share it, so it can
+    /// be used as a key for what has been derived from their strings.
+    pub fn decode_event_id(&self, event_index: usize) -> StringId {
+        let (raw_event, _) = self.raw_event(event_index);
+        raw_event.event_id.to_string_id()
+    }
+
+    /// The id of the string that is the event kind of the event at
+    /// `event_index`, for telling the kinds of many events apart without
+    /// decoding their strings.
+    pub fn decode_event_kind(&self, event_index: usize) -> StringId {
+        let (raw_event, _) = self.raw_event(event_index);
+        raw_event.event_kind
+    }
+
+    /// The actual bytes of the event id of the event at `event_index`, of
+    /// which `decode_full_event` only returns a lossily decoded version if
+    /// they aren't valid UTF-8.
+    pub fn decode_event_id_bytes<'a>(&'a self, event_index: usize) -> Cow<'a, [u8]> {
+        let (raw_event, _) = self.raw_event(event_index);
+
+        self.stringtable
+            .get(raw_event.event_id.to_string_id())
+            .to_bytes()
+    }
+
+    /// The event id of the explicit parent of the interval event at
+    /// `event_index`, i.e. of the `PARENT_EVENT_ID_EVENT_KIND` marker that
+    /// has been recorded by the same thread right before it, if there is one.
+    /// Only profiles with `FILE_FLAG_EXPLICIT_PARENTS` contain such markers.
+    pub fn decode_explicit_parent_id_bytes<'a>(
+        &'a self,
+        event_index: usize,
+    ) -> Option<Cow<'a, [u8]>> {
+        if event_index == 0 {
+            return None;
+        }
+
+        let (raw_event, _) = self.raw_event(event_index);
+        let (marker, _) = self.raw_event(event_index - 1);
+        if !raw_event.is_interval()
+            || !marker.is_integer()
+            || marker.thread_id != raw_event.thread_id
+        {
+            return None;
+        }
+
+        if self.marker_kind(&marker) != Some(MarkerKind::ParentEventId) {
+            return None;
+        }
+
+        Some(
+            self.stringtable
+                .get(marker.event_id.to_string_id())
+                .to_bytes(),
+        )
+    }
 
-        let raw_event_bytes = &self.event_data[event_start_addr..event_end_addr];
-        let raw_event = RawEvent::deserialize(raw_event_bytes);
+    /// The wall-clock duration in nanoseconds of the interval event at
+    /// `event_index`, i.e. the value of the `WALL_TIME_EVENT_KIND` marker
+    /// that has been recorded by the same thread right after it, if there is
+    /// one. Only profiles with `FILE_FLAG_WALL_TIME` contain such markers.
+    pub fn decode_wall_time_nanos(&self, event_index: usize) -> Option<u64> {
+        if event_index + 1 >= self.num_events() {
+            return None;
+        }
+
+        let (raw_event, _) = self.raw_event(event_index);
+        let (marker, _) = self.raw_event(event_index + 1);
+        if !raw_event.is_interval()
+            || !marker.is_integer()
+            || marker.thread_id != raw_event.thread_id
+        {
+            return None;
+        }
+
+        if self.marker_kind(&marker) != Some(MarkerKind::WallTime) {
+            return None;
+        }
+
+        Some(marker.value())
+    }
+
+    /// Whether the counter couldn't be read at the start or end of the
+    /// interval event at `event_index`, i.e. whether a
+    /// `COUNTER_UNAVAILABLE_EVENT_KIND` marker has been recorded by the same
+    /// thread right after it, or after its `WALL_TIME_EVENT_KIND` marker.
+    pub fn decode_counter_unavailable(&self, event_index: usize) -> bool {
+        let (raw_event, _) = self.raw_event(event_index);
+        if !raw_event.is_interval() {
+            return false;
+        }
+
+        let markers_end = std::cmp::min(event_index + 3, self.num_events());
+        for marker_index in event_index + 1..markers_end {
+            let (marker, _) = self.raw_event(marker_index);
+            if !marker.is_integer() || marker.thread_id != raw_event.thread_id {
+                return false;
+            }
+
+            match self.marker_kind(&marker) {
+                Some(MarkerKind::CounterUnavailable) => return true,
+                Some(MarkerKind::WallTime) => continue,
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    /// The trace context of the interval event at `event_index`, i.e. the
+    /// one the `TRACE_CONTEXT_EVENT_KIND` marker that has been recorded by
+    /// the same thread right after it, or after its other markers, refers
+    /// to. Only profiles with `FILE_FLAG_TRACE_CONTEXT` contain such markers.
+    /// `None` if the marker refers to a trace context that is missing from the
+    /// file, e.g. because it hasn't been written yet.
+    pub fn decode_trace_context(&self, event_index: usize) -> Option<TraceContext> {
+        let (raw_event, _) = self.raw_event(event_index);
+        if !raw_event.is_interval() {
+            return None;
+        }
 
-        let payload = EventPayload::from_raw_event(&raw_event, self.metadata.start_time);
+        let markers_end = std::cmp::min(event_index + 4, self.num_events());
+        for marker_index in event_index + 1..markers_end {
+            let (marker, _) = self.raw_event(marker_index);
+            if !marker.is_integer() || marker.thread_id != raw_event.thread_id {
+                return None;
+            }
+
+            match self.marker_kind(&marker) {
+                Some(MarkerKind::TraceContext) => {
+                    return self.trace_contexts.get(marker.value());
+                }
+                Some(MarkerKind::WallTime | MarkerKind::CounterUnavailable) => continue,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    fn marker_kind(&self, raw_event: &RawEvent) -> Option<MarkerKind> {
+        self.marker_kinds.get(&raw_event.event_kind).copied()
+    }
+
+    pub fn decode_lightweight_event<'a>(&'a self, event_index: usize) -> LightweightEvent {
+        let (raw_event, _) = self.raw_event(event_index);
+        let payload = self.payload(event_index, &raw_event);
 
         LightweightEvent {
             event_index,
@@ -199,6 +786,59 @@ impl EventDecoder {
     }
 }
 
+/// The event kinds of the markers that are recorded alongside other events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MarkerKind {
+    ParentEventId,
+    WallTime,
+    CounterUnavailable,
+    TraceContext,
+    TimestampEpoch,
+    ThreadTimestampEpoch,
+}
+
+const MARKER_KINDS: [(&str, MarkerKind); 6] = [
+    (PARENT_EVENT_ID_EVENT_KIND, MarkerKind::ParentEventId),
+    (WALL_TIME_EVENT_KIND, MarkerKind::WallTime),
+    (
+        COUNTER_UNAVAILABLE_EVENT_KIND,
+        MarkerKind::CounterUnavailable,
+    ),
+    (TRACE_CONTEXT_EVENT_KIND, MarkerKind::TraceContext),
+    (TIMESTAMP_EPOCH_EVENT_KIND, MarkerKind::TimestampEpoch),
+    (
+        THREAD_TIMESTAMP_EPOCH_EVENT_KIND,
+        MarkerKind::ThreadTimestampEpoch,
+    ),
+];
+
+/// The ids of the strings of `stringtable` that are the event kind of a
+/// marker. They are found once when a profile is loaded, so that telling
+/// markers apart from other events doesn't need to decode their event kinds.
+fn find_marker_kinds(stringtable: &StringTable) -> FxHashMap<StringId, MarkerKind> {
+    let names = MARKER_KINDS.map(|(name, _)| name);
+    marker_kinds(stringtable.find_strings(&names)).collect()
+}
+
+/// The marker kinds of the strings that `StringTable::find_strings` has found
+/// for the names of `MARKER_KINDS`.
+fn marker_kinds(found: FxHashMap<StringId, usize>) -> impl Iterator<Item = (StringId, MarkerKind)> {
+    found.into_iter().map(|(id, i)| (id, MARKER_KINDS[i].1))
+}
+
+/// Whether `raw_event` is a global (`Some(false)`) or a per-thread
+/// (`Some(true)`) timestamp epoch marker, see `TimestampEpochs::from_events`.
+fn epoch_marker_kind(
+    marker_kinds: &FxHashMap<StringId, MarkerKind>,
+    raw_event: &RawEvent,
+) -> Option<bool> {
+    match marker_kinds.get(&raw_event.event_kind) {
+        Some(MarkerKind::TimestampEpoch) => Some(false),
+        Some(MarkerKind::ThreadTimestampEpoch) => Some(true),
+        _ => None,
+    }
+}
+
 fn event_index_to_addr(event_index: usize) -> usize {
     FILE_HEADER_SIZE + event_index * mem::size_of::<RawEvent>()
 }
@@ -9,6 +9,18 @@ pub struct LightweightEvent {
 }
 
 impl LightweightEvent {
+    /// The position of the event in the events stream of its file, counting
+    /// from 0 in the order in which the events have been recorded, i.e. what
+    /// `event_index` stands for. It isn't stored anywhere but follows from
+    /// where the event is in the file, so it is the same every time the file
+    /// is read, also if its truncated tail has been dropped (see
+    /// `ProfilingData::truncated_bytes`), and tools can use it to refer to
+    /// the same event in their outputs, e.g. in the `event_index` argument
+    /// that `crox --event-index` adds.
+    pub fn index(&self) -> usize {
+        self.event_index
+    }
+
     /// Returns true if the time interval of `self` completely contains the
     /// time interval of `other`.
     pub fn contains(&self, other: &LightweightEvent) -> bool {
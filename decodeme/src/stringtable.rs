@@ -1,11 +1,12 @@
 //! See module-level documentation `measureme::stringtable`.
 
-use measureme::stringtable::{METADATA_STRING_ID, TERMINATOR};
+use measureme::stringtable::{METADATA_STRING_ID, STRING_INDEX_ENTRY_SIZE, TERMINATOR};
 use measureme::{
     file_header::{
-        strip_file_header, verify_file_header, FILE_MAGIC_STRINGTABLE_DATA,
+        strip_file_header, verify_file_header, FILE_HEADER_SIZE, FILE_MAGIC_STRINGTABLE_DATA,
         FILE_MAGIC_STRINGTABLE_INDEX,
     },
+    stringtable::ESCAPED_BYTE_ENCODED_SIZE,
     stringtable::STRING_REF_ENCODED_SIZE,
     stringtable::STRING_REF_TAG,
 };
@@ -16,6 +17,8 @@ use std::borrow::Cow;
 use std::convert::TryInto;
 use std::error::Error;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 
 fn deserialize_index_entry(bytes: &[u8]) -> (StringId, Addr) {
     (
@@ -41,8 +44,19 @@ const INVALID_STRING: &str = "<invalid>";
 impl<'st> StringRef<'st> {
     /// Expands the StringRef into an actual string. This method will
     /// avoid allocating a `String` if it can instead return a `&str` pointing
-    /// into the raw string table data.
+    /// into the raw string table data. Strings that aren't valid UTF-8 are
+    /// decoded lossily, see `to_bytes` for getting their actual contents.
     pub fn to_string(&self) -> Cow<'st, str> {
+        if let Some(s) = self.table.map().get(self.id) {
+            return Cow::from(s);
+        }
+
+        self.decode_string()
+    }
+
+    /// Like `to_string`, but decodes the string from the string data without
+    /// looking it up in the `StringMap`, which uses this to resolve it.
+    fn decode_string(&self) -> Cow<'st, str> {
         let addr = match self.get_addr() {
             Ok(addr) => addr,
             Err(_) => return Cow::from(UNKNOWN_STRING),
@@ -59,17 +73,15 @@ impl<'st> StringRef<'st> {
         // Find the first 0xFF byte which which is either the sequence
         // terminator or a byte in the middle of string id. Use `memchr` which
         // is super fast.
-        let terminator_pos = memchr(TERMINATOR, slice_to_search).unwrap();
+        let terminator_pos = match memchr(TERMINATOR, slice_to_search) {
+            Some(terminator_pos) => terminator_pos,
+            // The rest of the string hasn't been written yet, see `get_addr`.
+            None => return Cow::from(INVALID_STRING),
+        };
 
         // Check if this is a string containing a single StringId component
-        let first_byte = self.table.string_data[pos];
-        if first_byte == STRING_REF_TAG && terminator_pos == pos + STRING_REF_ENCODED_SIZE {
-            let id = decode_string_ref_from_data(&self.table.string_data[pos..]);
-            return StringRef {
-                id,
-                table: self.table,
-            }
-            .to_string();
+        if let Some(string_ref) = self.single_string_ref(pos, terminator_pos) {
+            return string_ref.to_string();
         }
 
         // Decode the bytes until the terminator. If there is a string id in
@@ -88,11 +100,55 @@ impl<'st> StringRef<'st> {
         }
     }
 
+    /// Like `to_string`, but returns the actual bytes of the string, which
+    /// may not be valid UTF-8, see `StringTableBuilder::alloc_bytes`.
+    pub fn to_bytes(&self) -> Cow<'st, [u8]> {
+        let addr = match self.get_addr() {
+            Ok(addr) => addr,
+            Err(_) => return Cow::from(UNKNOWN_STRING.as_bytes()),
+        };
+
+        let pos = addr.as_usize();
+        let slice_to_search = &self.table.string_data[pos..];
+
+        match memchr2(TERMINATOR, STRING_REF_TAG, slice_to_search) {
+            // A string with a single value component can be borrowed.
+            Some(len) if slice_to_search[len] == TERMINATOR => Cow::from(&slice_to_search[..len]),
+            Some(_) => {
+                let terminator_pos = memchr(TERMINATOR, slice_to_search);
+                if let Some(string_ref) =
+                    terminator_pos.and_then(|end| self.single_string_ref(pos, end))
+                {
+                    return string_ref.to_bytes();
+                }
+
+                let mut output = Vec::new();
+                self.write_to_bytes(&mut output);
+                Cow::from(output)
+            }
+            None => Cow::from(INVALID_STRING.as_bytes()),
+        }
+    }
+
     pub fn write_to_string(&self, output: &mut String) {
+        // The values are split at the bytes that can't be part of UTF-8, so
+        // decoding the pieces one by one only differs from decoding the
+        // whole string if a component ends in the middle of a character.
+        self.for_each_piece(&mut |piece| output.push_str(&String::from_utf8_lossy(piece)));
+    }
+
+    /// Like `write_to_string`, but appends the actual bytes of the string.
+    pub fn write_to_bytes(&self, output: &mut Vec<u8>) {
+        self.for_each_piece(&mut |piece| output.extend_from_slice(piece));
+    }
+
+    /// Calls `f` with the pieces of the string in order: its values, its
+    /// escaped bytes and the placeholders of the parts that can't be decoded.
+    fn for_each_piece(&self, f: &mut impl FnMut(&[u8])) {
         let addr = match self.get_addr() {
             Ok(addr) => addr,
             Err(_) => {
-                output.push_str(UNKNOWN_STRING);
+                f(UNKNOWN_STRING.as_bytes());
                 return;
             }
         };
@@ -100,47 +156,104 @@ impl<'st> StringRef<'st> {
         let mut pos = addr.as_usize();
 
         loop {
-            let byte = self.table.string_data[pos];
+            let byte = match self.table.string_data.get(pos) {
+                Some(&byte) => byte,
+                None => {
+                    f(INVALID_STRING.as_bytes());
+                    return;
+                }
+            };
 
             if byte == TERMINATOR {
                 return;
             } else if byte == STRING_REF_TAG {
+                // The rest of the string hasn't been written, see `get_addr`.
+                if self.table.string_data.len() < pos + STRING_REF_ENCODED_SIZE {
+                    f(INVALID_STRING.as_bytes());
+                    return;
+                }
+
+                let id = decode_string_ref_from_data(&self.table.string_data[pos..]);
+                if id == StringId::INVALID {
+                    // An escaped byte, see `measureme::stringtable`.
+                    let escaped_pos = pos + STRING_REF_ENCODED_SIZE;
+                    match self.table.string_data.get(escaped_pos..escaped_pos + 1) {
+                        Some(escaped) => f(escaped),
+                        None => {
+                            f(INVALID_STRING.as_bytes());
+                            return;
+                        }
+                    }
+
+                    pos += ESCAPED_BYTE_ENCODED_SIZE;
+                    continue;
+                }
+
                 let string_ref = StringRef {
-                    id: decode_string_ref_from_data(&self.table.string_data[pos..]),
+                    id,
                     table: self.table,
                 };
 
-                string_ref.write_to_string(output);
+                string_ref.for_each_piece(f);
 
                 pos += STRING_REF_ENCODED_SIZE;
             } else {
-                // This is a literal UTF-8 string value. Find its end by looking
+                // This is a literal string value. Find its end by looking
                 // for either of the two possible terminator bytes.
                 let remaining_data = &self.table.string_data[pos..];
-                if let Some(len) = memchr2(0xFF, 0xFE, remaining_data) {
-                    let value = String::from_utf8_lossy(&remaining_data[..len]);
-                    output.push_str(&value);
+                if let Some(len) = memchr2(TERMINATOR, STRING_REF_TAG, remaining_data) {
+                    f(&remaining_data[..len]);
                     pos += len;
                 } else {
                     // The grammar does not allow unterminated raw strings. We
                     // have to stop decoding.
-                    output.push_str(INVALID_STRING);
+                    f(INVALID_STRING.as_bytes());
                     return;
                 }
             }
         }
     }
 
+    /// Returns the string referenced by the string at `pos`, if that
+    /// consists of just a single StringId component, given the offset of
+    /// the first `TERMINATOR` byte from `pos`.
+    fn single_string_ref(&self, pos: usize, terminator_pos: usize) -> Option<StringRef<'st>> {
+        let first_byte = self.table.string_data[pos];
+        if first_byte != STRING_REF_TAG || terminator_pos != STRING_REF_ENCODED_SIZE {
+            return None;
+        }
+
+        let id = decode_string_ref_from_data(&self.table.string_data[pos..]);
+        // The `TERMINATOR` might be an escaped byte instead.
+        if id == StringId::INVALID {
+            return None;
+        }
+
+        Some(StringRef {
+            id,
+            table: self.table,
+        })
+    }
+
     fn get_addr(&self) -> Result<Addr, ()> {
-        if self.id.is_virtual() {
+        let addr = if self.id.is_virtual() || (self.table.relocated && self.id != StringId::INVALID)
+        {
             match self.table.index.get(&self.id) {
-                Some(&addr) => Ok(addr),
-                None => Err(()),
+                Some(&addr) => addr,
+                None => return Err(()),
             }
         } else if self.id == StringId::INVALID {
-            Err(())
+            return Err(());
         } else {
-            Ok(self.id.to_addr())
+            self.id.to_addr()
+        };
+
+        // The string might not have been written yet if the profile is still
+        // being written, see `ProfilingData::open_incremental`.
+        if addr.as_usize() < self.table.string_data.len() {
+            Ok(addr)
+        } else {
+            Err(())
         }
     }
 }
@@ -158,12 +271,45 @@ fn decode_string_ref_from_data(bytes: &[u8]) -> StringId {
     StringId::new(id)
 }
 
+/// A complete string of `StringTable::string_data`, which is decoded the
+/// first time it is looked up in the `StringMap`.
+#[derive(Debug)]
+struct IndexedString {
+    id: StringId,
+    resolved: OnceLock<ResolvedString>,
+}
+
+/// A string decoded like `StringRef::to_string` does. Most strings don't need
+/// to be copied, so they are kept as their location in the string data.
+#[derive(Debug)]
+enum ResolvedString {
+    Borrowed { start: usize, end: usize },
+    Owned(Box<str>),
+}
+
 /// Read-only version of the string table
 #[derive(Debug)]
 pub struct StringTable {
-    // TODO: Replace with something lazy
     string_data: Vec<u8>,
     index: FxHashMap<StringId, Addr>,
+    /// The complete strings of `string_data`, ordered by their id, see `map`.
+    /// Found when the table is loaded, without decoding them.
+    strings: Vec<IndexedString>,
+    /// The ids that have been mapped to one of `strings` by the index,
+    /// ordered by id, with the position of that string in `strings`. These
+    /// are the virtual ids, and all other ids as well if `relocated` is set.
+    mapped_ids: Vec<(StringId, usize)>,
+    /// The position in `string_data` of the first string that isn't
+    /// complete, i.e. up to where `strings` have been found.
+    indexed_len: usize,
+    /// The entries of `index` whose string isn't complete yet.
+    pending_mapped_ids: Vec<(StringId, Addr)>,
+    /// Whether the index maps the concrete ids as well, because the strings
+    /// aren't at the address of their id, see `with_relocated_ids`.
+    relocated: bool,
+    /// Whether one of `strings` has been decoded with a placeholder for a
+    /// string that it references, see `extend`.
+    has_placeholders: AtomicBool,
 }
 
 impl StringTable {
@@ -185,13 +331,169 @@ impl StringTable {
             "StringTable Index",
         )?;
 
-        assert!(index_data.len() % 8 == 0);
+        // A truncated file may end with a partial entry, which is ignored like
+        // the entries that are missing altogether.
         let index: FxHashMap<_, _> = strip_file_header(&index_data)
-            .chunks(8)
+            .chunks_exact(STRING_INDEX_ENTRY_SIZE)
             .map(deserialize_index_entry)
             .collect();
+        let mapped_ids = index.iter().map(|(&id, &addr)| (id, addr)).collect();
+
+        let mut string_table = StringTable {
+            string_data,
+            index,
+            strings: Vec::new(),
+            mapped_ids: Vec::new(),
+            indexed_len: FILE_HEADER_SIZE,
+            pending_mapped_ids: Vec::new(),
+            relocated: false,
+            has_placeholders: AtomicBool::new(false),
+        };
+        string_table.index_strings(mapped_ids);
 
-        Ok(StringTable { string_data, index })
+        Ok(string_table)
+    }
+
+    /// Makes the table look up the address of every string id in the index,
+    /// instead of only the virtual ones. The string table of a segment of a
+    /// rotated profile only holds some of the strings of the profile, which
+    /// aren't at the address of their id, see `PageTag::Segment`.
+    pub fn with_relocated_ids(mut self) -> StringTable {
+        self.relocated = true;
+        self
+    }
+
+    /// Finds the complete strings that have been added to `string_data`
+    /// since the last call, and the strings that the ids of `mapped_ids`
+    /// and of the entries that have been left pending before
+    /// have been mapped to. Strings that haven't been written completely,
+    /// e.g. at the end of a truncated file, are left out and decoded on demand
+    /// by `StringRef` as before. Returns the ids of the strings that have been
+    /// found, so that they can be looked up in the `StringMap` now.
+    fn index_strings(&mut self, mapped_ids: Vec<(StringId, Addr)>) -> Vec<StringId> {
+        let first_new_string = self.strings.len();
+
+        let data = &self.string_data;
+        let strings = &mut self.strings;
+        let mut start = self.indexed_len;
+        let mut pos = start;
+        while pos < data.len() {
+            match data[pos] {
+                TERMINATOR => {
+                    strings.push(IndexedString {
+                        id: StringId::from_addr(Addr(start as u32)),
+                        resolved: OnceLock::new(),
+                    });
+
+                    pos += 1;
+                    start = pos;
+                }
+                STRING_REF_TAG => {
+                    if data.len() < pos + STRING_REF_ENCODED_SIZE {
+                        break;
+                    }
+
+                    pos += if decode_string_ref_from_data(&data[pos..]) == StringId::INVALID {
+                        ESCAPED_BYTE_ENCODED_SIZE
+                    } else {
+                        STRING_REF_ENCODED_SIZE
+                    };
+                }
+                _ => match memchr2(TERMINATOR, STRING_REF_TAG, &data[pos..]) {
+                    Some(len) => pos += len,
+                    None => break,
+                },
+            }
+        }
+        self.indexed_len = start;
+
+        let mut found: Vec<_> = self.strings[first_new_string..]
+            .iter()
+            .map(|s| s.id)
+            .collect();
+
+        let mut new_mapped_ids = Vec::new();
+        let candidates = std::mem::take(&mut self.pending_mapped_ids)
+            .into_iter()
+            .chain(mapped_ids);
+        for (id, addr) in candidates {
+            let concrete_id = StringId::from_addr(addr);
+            match self
+                .strings
+                .binary_search_by_key(&concrete_id.as_u32(), |s| s.id.as_u32())
+            {
+                Ok(i) => new_mapped_ids.push((id, i)),
+                Err(_) => self.pending_mapped_ids.push((id, addr)),
+            }
+        }
+        new_mapped_ids.sort_unstable_by_key(|&(id, _)| id.as_u32());
+        found.extend(new_mapped_ids.iter().map(|&(id, _)| id));
+
+        // Both parts are ordered already, which the stable sort makes use of.
+        self.mapped_ids.extend(new_mapped_ids);
+        self.mapped_ids.sort_by_key(|&(id, _)| id.as_u32());
+
+        found
+    }
+
+    /// Adds the contents of the string data and string index pages that
+    /// have been appended to the profile since the table has been created,
+    /// see `ProfilingData::open_incremental`. Only the new strings are
+    /// indexed. Returns the ids of the strings that can be looked up in the
+    /// `StringMap` now, but couldn't before.
+    pub fn extend(&mut self, string_data: &[u8], index_data: &[u8]) -> Vec<StringId> {
+        self.string_data.extend_from_slice(string_data);
+
+        let mapped_ids: Vec<_> = index_data
+            .chunks_exact(STRING_INDEX_ENTRY_SIZE)
+            .map(deserialize_index_entry)
+            .collect();
+        self.index.extend(mapped_ids.iter().copied());
+
+        // Strings that reference strings that hadn't been written yet have
+        // been decoded with placeholders, which may be resolved now.
+        if std::mem::take(self.has_placeholders.get_mut()) {
+            for string in &mut self.strings {
+                if let Some(ResolvedString::Owned(_)) = string.resolved.get() {
+                    string.resolved = OnceLock::new();
+                }
+            }
+        }
+
+        self.index_strings(mapped_ids)
+    }
+
+    /// The string at position `i` of `strings`, which `id` has been mapped
+    /// to, decoded on first use.
+    fn resolved(&self, id: StringId, i: usize) -> &str {
+        let string = &self.strings[i];
+        let resolved = string.resolved.get_or_init(|| {
+            let s = self.get(id).decode_string();
+            // Placeholders for strings that are referenced but missing
+            // are borrowed as well, but not from the string data.
+            let data = self.string_data.as_ptr() as usize;
+            let start = (s.as_ptr() as usize).wrapping_sub(data);
+            match s {
+                Cow::Borrowed(s) if start < self.string_data.len() => ResolvedString::Borrowed {
+                    start,
+                    end: start + s.len(),
+                },
+                s => {
+                    if s.contains(UNKNOWN_STRING) || s.contains(INVALID_STRING) {
+                        self.has_placeholders.store(true, Ordering::Relaxed);
+                    }
+                    ResolvedString::Owned(s.into_owned().into_boxed_str())
+                }
+            }
+        });
+
+        match resolved {
+            // The bytes have been checked to be UTF-8 when borrowing them.
+            ResolvedString::Borrowed { start, end } => {
+                std::str::from_utf8(&self.string_data[*start..*end]).unwrap()
+            }
+            ResolvedString::Owned(s) => s,
+        }
     }
 
     #[inline]
@@ -203,6 +505,118 @@ impl StringTable {
         let id = StringId::new(METADATA_STRING_ID);
         self.get(id)
     }
+
+    /// The ids of the complete strings that consist of exactly the bytes of
+    /// one of `strings`, with the position of that string in `strings`, and
+    /// the virtual ids that have been mapped to them. Only the string data
+    /// is compared, so this is much cheaper than decoding all strings, for
+    /// finding the ids of a few well-known strings like the event kinds of
+    /// markers. Strings that have been written with string references
+    /// aren't found.
+    pub fn find_strings(&self, strings: &[&str]) -> FxHashMap<StringId, usize> {
+        let mapped_ids = self.mapped_ids.iter().map(|&(id, _)| id);
+        let concrete_ids = self.concrete_strings().iter().map(|s| s.id);
+        self.find_strings_among(mapped_ids.chain(concrete_ids), strings)
+    }
+
+    /// Like `find_strings`, but only looks at the strings with the given ids,
+    /// e.g. the ones that `extend` returns.
+    pub fn find_strings_among(
+        &self,
+        ids: impl IntoIterator<Item = StringId>,
+        strings: &[&str],
+    ) -> FxHashMap<StringId, usize> {
+        ids.into_iter()
+            .filter_map(|id| {
+                let i = self.position(id)?;
+                let bytes = &self.string_data[self.strings[i].id.to_addr().as_usize()..];
+                let position = strings.iter().position(|s| {
+                    bytes.get(s.len()) == Some(&TERMINATOR) && bytes.starts_with(s.as_bytes())
+                })?;
+                Some((id, position))
+            })
+            .collect()
+    }
+
+    /// The position in `strings` of the string with id `id`, if it is
+    /// complete.
+    fn position(&self, id: StringId) -> Option<usize> {
+        if id.is_virtual() || self.relocated {
+            let i = self
+                .mapped_ids
+                .binary_search_by_key(&id.as_u32(), |&(id, _)| id.as_u32())
+                .ok()?;
+            Some(self.mapped_ids[i].1)
+        } else {
+            self.strings
+                .binary_search_by_key(&id.as_u32(), |s| s.id.as_u32())
+                .ok()
+        }
+    }
+
+    /// The strings that can be looked up by their own id, i.e. all of
+    /// `strings` unless the table is relocated.
+    fn concrete_strings(&self) -> &[IndexedString] {
+        if self.relocated {
+            &[]
+        } else {
+            &self.strings
+        }
+    }
+
+    /// All strings of the table, for looking up many string ids at once.
+    #[inline]
+    pub fn map(&self) -> StringMap<'_> {
+        StringMap { table: self }
+    }
+}
+
+/// The strings of a `StringTable`, for looking up many string ids. Each string
+/// is decoded like `StringRef::to_string` does when it is first looked up,
+/// and kept, so that looking it up again neither allocates nor walks the
+/// string data.
+#[derive(Copy, Clone)]
+pub struct StringMap<'st> {
+    table: &'st StringTable,
+}
+
+impl<'st> StringMap<'st> {
+    /// The string with id `id`, or `None` if there is no such string or it
+    /// hasn't been written completely. `StringTable::get` returns a
+    /// placeholder for these.
+    #[inline]
+    pub fn get(&self, id: StringId) -> Option<&'st str> {
+        let i = self.table.position(id)?;
+        Some(self.table.resolved(id, i))
+    }
+
+    /// The number of string ids that can be looked up, including virtual
+    /// ones.
+    pub fn len(&self) -> usize {
+        self.table.mapped_ids.len() + self.table.concrete_strings().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All string ids and their strings, ordered by id. A string that
+    /// virtual ids have been mapped to is listed under each of the virtual
+    /// ids, as well as under its own id.
+    pub fn iter(&self) -> impl Iterator<Item = (StringId, &'st str)> + 'st {
+        // Virtual ids are smaller than all concrete ids, so listing them first
+        // keeps the ids ordered. Relocated tables only have mapped ids.
+        let table = self.table;
+        let mapped_ids = table.mapped_ids.iter().map(move |&(id, i)| (id, i));
+        let concrete_ids = table
+            .concrete_strings()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.id, i));
+        mapped_ids
+            .chain(concrete_ids)
+            .map(move |(id, i)| (id, table.resolved(id, i)))
+    }
 }
 
 #[cfg(test)]
@@ -253,6 +667,271 @@ mod tests {
         }
     }
 
+    #[test]
+    fn strings_not_written_yet() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let (complete, partial, missing) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+            (
+                builder.alloc("abc"),
+                builder.alloc("def"),
+                builder.alloc("ghi"),
+            )
+        };
+
+        let mut data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // Cut off the data in the middle of the second string, like in a
+        // profile that is still being written.
+        data_bytes.truncate(missing.to_addr().as_usize() - 2);
+
+        let string_table = StringTable::new(data_bytes, index_bytes, None).unwrap();
+
+        for &(id, expected_string) in &[
+            (complete, "abc"),
+            (partial, INVALID_STRING),
+            (missing, UNKNOWN_STRING),
+        ] {
+            let str_ref = string_table.get(id);
+            if expected_string == "abc" {
+                assert_eq!(string_table.map().get(id), Some(expected_string));
+            } else {
+                assert_eq!(string_table.map().get(id), None);
+            }
+
+            assert_eq!(str_ref.to_string(), expected_string);
+
+            let mut write_to = String::new();
+            str_ref.write_to_string(&mut write_to);
+            assert_eq!(write_to, expected_string);
+        }
+    }
+
+    #[test]
+    fn string_map() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let virtual_id = StringId::new_virtual(7);
+        let (abc, composite, non_utf8, dangling) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+            let abc = builder.alloc("abc");
+            let composite =
+                builder.alloc(&[StringComponent::Value("x"), StringComponent::Ref(abc)]);
+            let non_utf8 = builder.alloc_bytes(&[b"caf\xE9"]).unwrap();
+            builder.map_virtual_to_concrete_string(virtual_id, composite);
+            builder.alloc_metadata("meta");
+            let dangling = builder.alloc(&[StringComponent::Ref(StringId::new_virtual(9))]);
+            (abc, composite, non_utf8, dangling)
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        let string_table = StringTable::new(data_bytes, index_bytes, None).unwrap();
+        let map = string_table.map();
+
+        assert_eq!(map.get(abc), Some("abc"));
+        assert_eq!(map.get(composite), Some("xabc"));
+        assert_eq!(map.get(non_utf8), Some("caf\u{FFFD}"));
+        assert_eq!(map.get(virtual_id), Some("xabc"));
+        assert_eq!(map.get(StringId::new(METADATA_STRING_ID)), Some("meta"));
+        assert_eq!(map.get(StringId::new_virtual(8)), None);
+        assert_eq!(map.get(dangling), Some(UNKNOWN_STRING));
+        assert_eq!(map.get(StringId::INVALID), None);
+
+        let entries: Vec<_> = map.iter().collect();
+        assert_eq!(entries.len(), map.len());
+        assert_eq!(entries[0], (virtual_id, "xabc"));
+        assert_eq!(entries[1], (StringId::new(METADATA_STRING_ID), "meta"));
+        assert!(entries.contains(&(composite, "xabc")));
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].0.as_u32() < pair[1].0.as_u32()));
+    }
+
+    #[test]
+    fn find_strings() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let virtual_id = StringId::new_virtual(7);
+        let (wall_time, longer, composite, parent) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+            let wall_time = builder.alloc("WallTime");
+            let longer = builder.alloc("WallTimes");
+            let time = builder.alloc("Time");
+            let composite =
+                builder.alloc(&[StringComponent::Value("Wall"), StringComponent::Ref(time)]);
+            let parent = builder.alloc("Parent");
+            builder.map_virtual_to_concrete_string(virtual_id, parent);
+            (wall_time, longer, composite, parent)
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+        let string_table = StringTable::new(data_bytes, index_bytes, None).unwrap();
+
+        let found = string_table.find_strings(&["Parent", "WallTime"]);
+        assert_eq!(found.get(&wall_time), Some(&1));
+        assert_eq!(found.get(&parent), Some(&0));
+        assert_eq!(found.get(&virtual_id), Some(&0));
+        assert_eq!(found.get(&longer), None);
+        assert_eq!(found.get(&composite), None);
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn extend() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let virtual_id = StringId::new_virtual(7);
+        let (abc, composite, def) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+            let abc = builder.alloc("abc");
+            let composite = builder.alloc(&[
+                StringComponent::Value("x"),
+                StringComponent::Ref(virtual_id),
+            ]);
+            let def = builder.alloc("def");
+            builder.map_virtual_to_concrete_string(virtual_id, abc);
+            (abc, composite, def)
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // The table starts out with the strings up to the middle of `def`, and
+        // without the index entry of the virtual id.
+        let split = def.to_addr().as_usize() + 1;
+        let mut string_table = StringTable::new(
+            data_bytes[..split].to_vec(),
+            index_bytes[..FILE_HEADER_SIZE].to_vec(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(string_table.map().get(abc), Some("abc"));
+        assert_eq!(string_table.map().get(composite), Some("x<unknown>"));
+        assert_eq!(string_table.map().get(def), None);
+        assert_eq!(string_table.map().get(virtual_id), None);
+
+        let found = string_table.extend(&data_bytes[split..], &index_bytes[FILE_HEADER_SIZE..]);
+        assert_eq!(found, vec![def, virtual_id]);
+        assert_eq!(string_table.map().get(composite), Some("xabc"));
+        assert_eq!(string_table.map().get(def), Some("def"));
+        assert_eq!(string_table.map().get(virtual_id), Some("abc"));
+        assert_eq!(string_table.map().len(), 4);
+    }
+
+    #[test]
+    fn truncated_string_table() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let composite = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+            let abc = builder.alloc("abc");
+            builder.alloc(&[StringComponent::Value("x"), StringComponent::Ref(abc)])
+        };
+
+        let mut data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let mut index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // Cut off the data in the middle of the reference to "abc", and end
+        // the index with a partial entry, like in a profile whose last pages
+        // have been written incompletely.
+        data_bytes.truncate(composite.to_addr().as_usize() + 3);
+        index_bytes.extend_from_slice(&[1, 2, 3]);
+
+        let string_table = StringTable::new(data_bytes, index_bytes, None).unwrap();
+
+        let mut write_to = String::new();
+        string_table.get(composite).write_to_string(&mut write_to);
+        assert_eq!(write_to, format!("x{}", INVALID_STRING));
+        assert_eq!(string_table.get(composite).to_string(), INVALID_STRING);
+    }
+
+    #[test]
+    fn non_utf8_strings() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let data_sink = Arc::new(sink_builder.new_sink(PageTag::StringData));
+        let index_sink = Arc::new(sink_builder.new_sink(PageTag::StringIndex));
+
+        let expected_bytes: &[&[u8]] = &[
+            b"plain",
+            b"latin-1: caf\xE9",
+            b"\xFF",
+            b"tags: \xFE\xFF\xFE",
+            b"",
+            b"\xFEsrc/\xFFmain.rs",
+        ];
+
+        let (ids, composite, separator, event_id) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone()).unwrap();
+
+            let ids: Vec<_> = expected_bytes
+                .iter()
+                .map(|&bytes| builder.alloc_bytes(&[bytes]).unwrap())
+                .collect();
+
+            let composite = builder.alloc(&[
+                StringComponent::Ref(ids[3]),
+                StringComponent::Value("|"),
+                StringComponent::Ref(ids[2]),
+            ]);
+
+            let separator = builder.alloc_bytes(&[b"a\x1B", b"\x1E\xFEb"]).unwrap();
+            let event_id = builder.alloc_event_id_bytes(b"a\x1E\xFEb");
+            (ids, composite, separator, event_id)
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        let string_table = StringTable::new(data_bytes, index_bytes, None).unwrap();
+        assert_eq!(&string_table.get(event_id).to_bytes()[..], b"a\x1E\xFEb");
+        assert_eq!(
+            &string_table.get(separator).to_bytes()[..],
+            b"a\x1B\x1E\xFEb"
+        );
+
+        for (&id, &expected) in ids.iter().zip(expected_bytes.iter()) {
+            let str_ref = string_table.get(id);
+
+            assert_eq!(&str_ref.to_bytes()[..], expected);
+
+            let mut write_to = Vec::new();
+            str_ref.write_to_bytes(&mut write_to);
+            assert_eq!(write_to, expected);
+
+            assert_eq!(str_ref.to_string(), String::from_utf8_lossy(expected));
+        }
+
+        // Strings without escapes are borrowed from the string table.
+        assert!(matches!(
+            string_table.get(ids[1]).to_bytes(),
+            Cow::Borrowed(_)
+        ));
+
+        assert_eq!(
+            &string_table.get(composite).to_bytes()[..],
+            &b"tags: \xFE\xFF\xFE|\xFF"[..]
+        );
+        assert_eq!(
+            string_table.get(composite).to_string(),
+            "tags: \u{FFFD}\u{FFFD}\u{FFFD}|\u{FFFD}"
+        );
+    }
+
     #[test]
     fn composite_string() {
         let sink_builder = SerializationSinkBuilder::new_in_memory();
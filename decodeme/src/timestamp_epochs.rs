@@ -0,0 +1,203 @@
+//! Recovers the counter values of long-running profiles, whose timestamps are
+//! stored modulo `measureme::TIMESTAMP_PERIOD`, from the epoch markers that the
+//! profiler records (see `measureme::TIMESTAMP_EPOCH_EVENT_KIND`).
+
+use measureme::{RawEvent, TIMESTAMP_EPOCH_LENGTH, TIMESTAMP_PERIOD};
+use rustc_hash::FxHashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+/// The epoch that each event belongs to, stored as the indices at which the
+/// epoch changes. Empty for profiles that don't need any markers, which are
+/// all profiles that are shorter than `TIMESTAMP_EPOCH_LENGTH`.
+#[derive(Debug, Default)]
+pub(crate) struct TimestampEpochs {
+    global: Vec<(usize, u64)>,
+    per_thread: FxHashMap<u32, Vec<(usize, u64)>>,
+}
+
+impl TimestampEpochs {
+    /// Finds the markers among `events`, which are in order and come with
+    /// their event index. `is_marker` reports whether the event with the
+    /// given event kind is a global marker (`Some(false)`), a per-thread
+    /// marker (`Some(true)`) or not a marker at all.
+    pub(crate) fn from_events(
+        events: impl Iterator<Item = (usize, RawEvent)>,
+        is_marker: impl FnMut(&RawEvent) -> Option<bool>,
+    ) -> TimestampEpochs {
+        let mut epochs = TimestampEpochs::default();
+        epochs.extend(events, is_marker);
+        epochs
+    }
+
+    /// Like `from_events`, for events that have been appended to the profile
+    /// since, see `EventDecoder::append_pages`. The markers may come before
+    /// those found before, if their event kind hasn't been known until now.
+    pub(crate) fn extend(
+        &mut self,
+        events: impl Iterator<Item = (usize, RawEvent)>,
+        mut is_marker: impl FnMut(&RawEvent) -> Option<bool>,
+    ) {
+        for (event_index, raw_event) in events {
+            if !raw_event.is_integer() {
+                continue;
+            }
+
+            let transitions = match is_marker(&raw_event) {
+                Some(false) => &mut self.global,
+                Some(true) => self.per_thread.entry(raw_event.thread_id).or_default(),
+                None => continue,
+            };
+
+            // If the profile has been split into several files, the events
+            // before the first marker in this file belong to the epoch before
+            // that marker's epoch.
+            if transitions.is_empty() && raw_event.value() > 1 {
+                transitions.push((0, raw_event.value() - 1));
+            }
+
+            if transitions
+                .last()
+                .is_some_and(|&(index, _)| index > event_index)
+            {
+                let i = transitions.partition_point(|&(index, _)| index < event_index);
+                transitions.insert(i, (event_index, raw_event.value()));
+            } else {
+                transitions.push((event_index, raw_event.value()));
+            }
+        }
+    }
+
+    /// Like `from_events`, but only reads the events at the positions that
+    /// the timestamp epoch index of the profile gives (see
+    /// `measureme::decode_timestamp_epoch_index`), with `raw_event`. Positions
+    /// from `num_events` on are ignored, as they belong to markers in the
+    /// pages of a truncated tail. `None` if one of the other events isn't a
+    /// marker, i.e. if the index doesn't belong to these events, in which
+    /// case all events have to be scanned for the markers.
+    pub(crate) fn from_index(
+        index: &[u64],
+        num_events: usize,
+        mut raw_event: impl FnMut(usize) -> Result<RawEvent, Box<dyn Error + Send + Sync>>,
+        mut is_marker: impl FnMut(&RawEvent) -> Option<bool>,
+    ) -> Result<Option<TimestampEpochs>, Box<dyn Error + Send + Sync>> {
+        // Markers that have been written concurrently may have been indexed
+        // in a different order.
+        let mut positions: Vec<usize> = index
+            .iter()
+            .filter_map(|&event_index| usize::try_from(event_index).ok())
+            .filter(|&event_index| event_index < num_events)
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+
+        let mut markers = Vec::with_capacity(positions.len());
+        for event_index in positions {
+            let raw_event = raw_event(event_index)?;
+            if !raw_event.is_integer() || is_marker(&raw_event).is_none() {
+                return Ok(None);
+            }
+            markers.push((event_index, raw_event));
+        }
+
+        Ok(Some(Self::from_events(markers.into_iter(), is_marker)))
+    }
+
+    /// The epoch of the event at `event_index`, which has been recorded by
+    /// `thread_id`.
+    pub(crate) fn epoch(&self, event_index: usize, thread_id: u32) -> u64 {
+        let transitions = if self.per_thread.is_empty() {
+            &self.global
+        } else {
+            match self.per_thread.get(&thread_id) {
+                Some(transitions) => transitions,
+                None => return 0,
+            }
+        };
+
+        match transitions.partition_point(|&(index, _)| index <= event_index) {
+            0 => 0,
+            i => transitions[i - 1].1,
+        }
+    }
+}
+
+/// Recovers the counter value that has been stored as `value` (modulo
+/// `TIMESTAMP_PERIOD`) in an event that belongs to `epoch`.
+///
+/// Events are recorded in roughly chronological order, so a value in the
+/// window of `TIMESTAMP_PERIOD` length that starts half an epoch before
+/// `epoch` is the right one, even for events that have been written a bit
+/// before or after the marker. In epoch `0`, and in profiles without markers,
+/// the stored value is the counter value.
+pub(crate) fn unwrap_timestamp(value: u64, epoch: u64) -> u64 {
+    if epoch == 0 {
+        return value;
+    }
+
+    let window_start = epoch * TIMESTAMP_EPOCH_LENGTH - TIMESTAMP_EPOCH_LENGTH / 2;
+    let offset = (value % TIMESTAMP_PERIOD + TIMESTAMP_PERIOD - window_start % TIMESTAMP_PERIOD)
+        % TIMESTAMP_PERIOD;
+
+    window_start + offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::{EventId, StringId};
+
+    #[test]
+    fn markers_at_the_indexed_positions() {
+        let marker_kind = StringId::new_virtual(1);
+        let other_kind = StringId::new_virtual(2);
+        let event = |event_kind, thread_id, value| {
+            RawEvent::new_integer(
+                event_kind,
+                EventId::from_virtual(event_kind),
+                thread_id,
+                value,
+            )
+        };
+        let events = [
+            event(other_kind, 0, 1),
+            event(marker_kind, 1, 3),
+            event(other_kind, 1, 2),
+            event(marker_kind, 0, 4),
+        ];
+        let is_marker = |raw_event: &RawEvent| match raw_event.event_kind {
+            kind if kind == marker_kind => Some(true),
+            _ => None,
+        };
+        let from_index = |index: &[u64]| {
+            TimestampEpochs::from_index(index, events.len(), |i| Ok(events[i]), is_marker).unwrap()
+        };
+
+        // Entries past the last event are ignored, and entries may come in
+        // any order.
+        let epochs = from_index(&[3, 1, 4]).unwrap();
+        assert_eq!(epochs.epoch(0, 0), 3);
+        assert_eq!(epochs.epoch(2, 1), 3);
+        assert_eq!(epochs.epoch(3, 0), 4);
+
+        // The index doesn't belong to these events.
+        assert!(from_index(&[1, 2]).is_none());
+    }
+
+    #[test]
+    fn unwrap_around_epoch_boundaries() {
+        assert_eq!(unwrap_timestamp(12345, 0), 12345);
+
+        for epoch in 1..10 {
+            let epoch_start = epoch * TIMESTAMP_EPOCH_LENGTH;
+            for &t in &[
+                epoch_start - 1000,
+                epoch_start,
+                epoch_start + 1000,
+                epoch_start + TIMESTAMP_EPOCH_LENGTH - 1,
+            ] {
+                assert_eq!(unwrap_timestamp(t % TIMESTAMP_PERIOD, epoch), t);
+            }
+        }
+    }
+}
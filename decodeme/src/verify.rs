@@ -0,0 +1,748 @@
+//! Checks that the contents of a `.mm_profdata` file are well-formed, without
+//! decoding its events into high-level structures, see `verify_file`.
+
+use crate::event_data::EventData;
+use crate::event_payload::Timestamp;
+use crate::{EventDecoder, RAW_EVENT_SIZE};
+use measureme::file_header::{
+    has_instant_values, has_timestamp_epoch_index, verify_file_header, CURRENT_FILE_FORMAT_VERSION,
+    FILE_CODEC_BYTE_INDEX, FILE_CODEC_NONE, FILE_CODEC_ZSTD, FILE_FLAGS_BYTE_INDEX,
+    FILE_FORMAT_VERSION_MASK, FILE_HEADER_SIZE, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_METADATA,
+    FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX,
+    FILE_MAGIC_TOP_LEVEL, FILE_MAGIC_TRACE_CONTEXT,
+};
+use measureme::stringtable::{
+    ESCAPED_BYTE_ENCODED_SIZE, METADATA_STRING_ID, STRING_INDEX_ENTRY_SIZE,
+    STRING_REF_ENCODED_SIZE, STRING_REF_TAG, TERMINATOR,
+};
+use measureme::{
+    decompress_page, is_optional_page_tag, PageTag, RawEvent, StringId, PAGE_HEADER_SIZE,
+    TRACE_CONTEXT_ENTRY_SIZE, TRACE_CONTEXT_PRELUDE_SIZE,
+};
+use memchr::memchr2;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::time::SystemTime;
+
+/// The first problem found by `verify_file`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The position of the problem within the file, in bytes. Problems within
+    /// a compressed events page are reported at the start of that page.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for VerifyError {}
+
+/// What `verify_file` has found in a well-formed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifiedFile {
+    pub num_pages: usize,
+    /// The number of entries in the string index.
+    pub num_indexed_strings: usize,
+    pub num_events: usize,
+}
+
+fn error<T>(offset: usize, message: String) -> Result<T, VerifyError> {
+    Err(VerifyError { offset, message })
+}
+
+/// Checks the binary format of the contents of a `.mm_profdata` file:
+///
+/// - the top-level file header has the right magic bytes, the current file
+///   format version and a known codec,
+/// - the file consists of complete pages with valid tags, and the streams
+///   they make up start with their own file headers,
+/// - the events stream consists of complete events, and the trace context
+///   and timestamp epoch index streams of complete entries,
+/// - the entries of the string index point into the string data, and each
+///   string the index or an event refers to is complete and only refers to
+///   strings that exist,
+/// - the events of each thread are recorded in order, i.e. the end of each
+///   interval and the time of each instant event is not before those of the
+///   thread's previous events, and no interval ends before it starts.
+///
+/// Unlike `EventDecoder::new`, this doesn't ignore an incomplete last page.
+/// Only the first problem is reported. Events recorded for the same thread id
+/// from several OS threads may be reported as out of order.
+pub fn verify_file(data: &[u8]) -> Result<VerifiedFile, VerifyError> {
+    let codec = verify_top_level_header(data)?;
+    let (mut streams, num_pages) = split_pages(data, codec)?;
+    let relocated = streams.remove(&PageTag::Segment).is_some();
+
+    let mut take_stream = |tag: PageTag, magic: &[u8; 4], name: &str| {
+        let stream = match streams.remove(&tag) {
+            Some(stream) => stream,
+            None if tag == PageTag::Metadata
+                || tag == PageTag::TraceContext
+                || tag == PageTag::TimestampEpochIndex =>
+            {
+                return Ok(None)
+            }
+            None => return error(data.len(), format!("the file has no {} pages", name)),
+        };
+        if let Err(e) = verify_file_header(&stream.bytes, magic, None, name) {
+            return error(stream.file_offset(0), e.to_string());
+        }
+        Ok(Some(stream))
+    };
+
+    let string_data = take_stream(
+        PageTag::StringData,
+        FILE_MAGIC_STRINGTABLE_DATA,
+        "string data",
+    )?;
+    let string_index = take_stream(
+        PageTag::StringIndex,
+        FILE_MAGIC_STRINGTABLE_INDEX,
+        "string index",
+    )?;
+    let mut events = take_stream(PageTag::Events, FILE_MAGIC_EVENT_STREAM, "event")?.unwrap();
+    take_stream(PageTag::Metadata, FILE_MAGIC_METADATA, "metadata")?;
+    let trace_contexts = take_stream(
+        PageTag::TraceContext,
+        FILE_MAGIC_TRACE_CONTEXT,
+        "trace context",
+    )?;
+    if let Some(trace_contexts) = trace_contexts {
+        if trace_contexts.bytes.len() < TRACE_CONTEXT_PRELUDE_SIZE {
+            return error(
+                trace_contexts.file_offset(trace_contexts.bytes.len()),
+                "the trace context stream has no first entry index".to_string(),
+            );
+        }
+        let partial_len =
+            (trace_contexts.bytes.len() - TRACE_CONTEXT_PRELUDE_SIZE) % TRACE_CONTEXT_ENTRY_SIZE;
+        if partial_len != 0 {
+            return error(
+                trace_contexts.file_offset(trace_contexts.bytes.len() - partial_len),
+                format!(
+                    "the trace context stream ends with a partial entry of {} bytes",
+                    partial_len
+                ),
+            );
+        }
+    }
+    // The pages of the index are unknown optional pages in older files.
+    let epoch_index = take_stream(
+        PageTag::TimestampEpochIndex,
+        FILE_MAGIC_TIMESTAMP_EPOCH_INDEX,
+        "timestamp epoch index",
+    )?
+    .filter(|_| has_timestamp_epoch_index(data));
+    if let Some(ref epoch_index) = epoch_index {
+        let partial_len = (epoch_index.bytes.len() - FILE_HEADER_SIZE) % 8;
+        if partial_len != 0 {
+            return error(
+                epoch_index.file_offset(epoch_index.bytes.len() - partial_len),
+                format!(
+                    "the timestamp epoch index stream ends with a partial entry of {} bytes",
+                    partial_len
+                ),
+            );
+        }
+    }
+
+    let string_index = string_index.unwrap();
+    let mut strings = Strings::new(string_data.unwrap(), &string_index, relocated)?;
+    let num_indexed_strings = strings.index.len();
+    let num_events = verify_events(&events, &mut strings)?;
+
+    // The decoder takes over the streams, which are known to be well-formed
+    // by now, instead of splitting the file into them again.
+    let decoder = EventDecoder::from_event_data(
+        mem::take(&mut strings.data.bytes),
+        string_index.bytes,
+        EventData::Uncompressed(mem::take(&mut events.bytes)),
+        epoch_index
+            .as_ref()
+            .map_or(&[][..], |epoch_index| &epoch_index.bytes),
+        relocated,
+        None,
+    );
+    let mut decoder = match decoder {
+        Ok(decoder) => decoder,
+        Err(e) => return error(0, e.to_string()),
+    };
+    decoder.file_flags = data[FILE_FLAGS_BYTE_INDEX];
+    decoder.instant_values = has_instant_values(data);
+    verify_timestamps(&decoder, &events)?;
+
+    Ok(VerifiedFile {
+        num_pages,
+        num_indexed_strings,
+        num_events,
+    })
+}
+
+/// Returns the codec of the events pages.
+fn verify_top_level_header(data: &[u8]) -> Result<u8, VerifyError> {
+    if data.len() < FILE_HEADER_SIZE {
+        return error(
+            0,
+            format!(
+                "expected at least {} bytes of file header but the file has {} bytes",
+                FILE_HEADER_SIZE,
+                data.len()
+            ),
+        );
+    }
+
+    if &data[0..4] != FILE_MAGIC_TOP_LEVEL {
+        return error(
+            0,
+            format!(
+                "expected file magic `{:?}` but found `{:?}`",
+                FILE_MAGIC_TOP_LEVEL,
+                &data[0..4]
+            ),
+        );
+    }
+
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap()) & FILE_FORMAT_VERSION_MASK;
+    if version != CURRENT_FILE_FORMAT_VERSION {
+        return error(
+            4,
+            format!(
+                "expected file format version {} but found {}",
+                CURRENT_FILE_FORMAT_VERSION, version
+            ),
+        );
+    }
+
+    let codec = data[FILE_CODEC_BYTE_INDEX];
+    if codec != FILE_CODEC_NONE && codec != FILE_CODEC_ZSTD {
+        return error(FILE_CODEC_BYTE_INDEX, format!("unknown codec {}", codec));
+    }
+
+    Ok(codec)
+}
+
+/// The concatenated contents of the pages with the same tag.
+#[derive(Default)]
+struct Stream {
+    bytes: Vec<u8>,
+    // The address within the stream of the first byte of each page, and the
+    // file offset of that byte. For compressed pages, the file offset is that
+    // of the page header since the bytes can't be located any more precisely.
+    pages: Vec<(usize, usize, bool)>,
+}
+
+impl Stream {
+    /// The file offset of the byte at `addr` within the stream.
+    fn file_offset(&self, addr: usize) -> usize {
+        let page = self.pages.partition_point(|&(start, _, _)| start <= addr);
+        let (start, offset, compressed) = self.pages[page.saturating_sub(1)];
+        if compressed {
+            offset
+        } else {
+            offset + (addr - start)
+        }
+    }
+}
+
+/// Splits the file into the streams of each page tag, and counts the pages.
+fn split_pages(data: &[u8], codec: u8) -> Result<(FxHashMap<PageTag, Stream>, usize), VerifyError> {
+    let mut streams = FxHashMap::<PageTag, Stream>::default();
+    let mut num_pages = 0;
+    let mut pos = FILE_HEADER_SIZE;
+
+    while pos < data.len() {
+        let header = match data.get(pos..pos + PAGE_HEADER_SIZE) {
+            Some(header) => header,
+            None => {
+                return error(
+                    pos,
+                    format!(
+                        "the file ends within a page header, after {} of its {} bytes",
+                        data.len() - pos,
+                        PAGE_HEADER_SIZE
+                    ),
+                )
+            }
+        };
+
+        // Pages of optional sections that aren't known are skipped.
+        let tag = match PageTag::try_from(header[0]) {
+            Ok(tag) => Some(tag),
+            Err(_) if is_optional_page_tag(header[0]) => None,
+            Err(_) => return error(pos, format!("invalid page tag {}", header[0])),
+        };
+
+        let page_size = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+        if page_size == 0 {
+            return error(pos, "empty page".to_string());
+        }
+
+        let contents_start = pos + PAGE_HEADER_SIZE;
+        let contents = match data.get(contents_start..contents_start + page_size) {
+            Some(contents) => contents,
+            None => {
+                return error(
+                    pos,
+                    format!(
+                        "the file ends within a page, after {} of its {} bytes",
+                        data.len() - contents_start,
+                        page_size
+                    ),
+                )
+            }
+        };
+
+        let tag = match tag {
+            Some(tag) => tag,
+            None => {
+                num_pages += 1;
+                pos = contents_start + page_size;
+                continue;
+            }
+        };
+
+        let stream = streams.entry(tag).or_default();
+        if tag == PageTag::Events && codec != FILE_CODEC_NONE {
+            let contents = match decompress_page(codec, contents) {
+                Ok(contents) => contents,
+                Err(e) => return error(pos, e.to_string()),
+            };
+            stream.pages.push((stream.bytes.len(), pos, true));
+            stream.bytes.extend_from_slice(&contents);
+        } else {
+            stream
+                .pages
+                .push((stream.bytes.len(), contents_start, false));
+            stream.bytes.extend_from_slice(contents);
+        }
+
+        num_pages += 1;
+        pos = contents_start + page_size;
+    }
+
+    Ok((streams, num_pages))
+}
+
+/// The string table, with the strings that have been verified so far.
+struct Strings {
+    data: Stream,
+    index: FxHashMap<StringId, usize>,
+    verified_addrs: FxHashSet<usize>,
+    /// Whether all ids are looked up in the index, see `PageTag::Segment`.
+    relocated: bool,
+}
+
+impl Strings {
+    fn new(data: Stream, index: &Stream, relocated: bool) -> Result<Strings, VerifyError> {
+        let entries = &index.bytes[FILE_HEADER_SIZE..];
+        let partial_len = entries.len() % STRING_INDEX_ENTRY_SIZE;
+        if partial_len != 0 {
+            return error(
+                index.file_offset(index.bytes.len() - partial_len),
+                "the string index ends with a partial entry".to_string(),
+            );
+        }
+
+        let mut strings = Strings {
+            data,
+            index: FxHashMap::default(),
+            verified_addrs: FxHashSet::default(),
+            relocated,
+        };
+
+        // The strings are verified once all entries are known, as they may
+        // refer to ids whose entries come later.
+        let mut addrs = Vec::new();
+
+        for (i, entry) in entries.chunks_exact(STRING_INDEX_ENTRY_SIZE).enumerate() {
+            let id = StringId::new(u32::from_le_bytes(entry[0..4].try_into().unwrap()));
+            let addr = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize;
+            let entry_offset = index.file_offset(FILE_HEADER_SIZE + i * STRING_INDEX_ENTRY_SIZE);
+
+            if addr < FILE_HEADER_SIZE || addr >= strings.data.bytes.len() {
+                return error(
+                    entry_offset,
+                    format!(
+                        "the string index entry of string id {} points to address {}, \
+                         outside of the {} bytes of string data",
+                        id.as_u32(),
+                        addr,
+                        strings.data.bytes.len()
+                    ),
+                );
+            }
+
+            strings.index.insert(id, addr);
+            addrs.push(addr);
+        }
+
+        for addr in addrs {
+            strings.verify_string(addr)?;
+        }
+
+        if !strings
+            .index
+            .contains_key(&StringId::new(METADATA_STRING_ID))
+        {
+            return error(
+                index.file_offset(index.bytes.len() - 1) + 1,
+                "the string index has no entry for the metadata string".to_string(),
+            );
+        }
+
+        Ok(strings)
+    }
+
+    /// The address of the string with the given id, or a description of why
+    /// the id doesn't refer to any string.
+    fn resolve(&self, id: StringId) -> Result<usize, String> {
+        if id == StringId::INVALID {
+            Err("refers to the invalid string id".to_string())
+        } else if id.is_virtual() || self.relocated {
+            match self.index.get(&id) {
+                Some(&addr) => Ok(addr),
+                None => Err(format!(
+                    "refers to {} string id {}, which is not in the string index",
+                    if id.is_virtual() {
+                        "virtual"
+                    } else {
+                        "relocated"
+                    },
+                    id.as_u32()
+                )),
+            }
+        } else {
+            let addr = id.to_addr().as_usize();
+            if addr >= FILE_HEADER_SIZE && addr < self.data.bytes.len() {
+                Ok(addr)
+            } else {
+                Err(format!(
+                    "refers to string id {} at address {}, outside of the {} bytes of string data",
+                    id.as_u32(),
+                    addr,
+                    self.data.bytes.len()
+                ))
+            }
+        }
+    }
+
+    /// Checks that the components of the string at `addr` are complete, up
+    /// to the terminator, and that the ids it contains can be resolved. The
+    /// strings these refer to are verified on their own, when they are
+    /// referred to by the index or by an event.
+    fn verify_string(&mut self, addr: usize) -> Result<(), VerifyError> {
+        if self.verified_addrs.contains(&addr) {
+            return Ok(());
+        }
+
+        let bytes = &self.data.bytes;
+        let mut pos = addr;
+
+        loop {
+            pos = match memchr2(TERMINATOR, STRING_REF_TAG, &bytes[pos..]) {
+                Some(len) => pos + len,
+                None => break,
+            };
+
+            if bytes[pos] == TERMINATOR {
+                self.verified_addrs.insert(addr);
+                return Ok(());
+            }
+
+            let id = match bytes.get(pos + 1..pos + STRING_REF_ENCODED_SIZE) {
+                Some(id) => StringId::new(u32::from_le_bytes(id.try_into().unwrap())),
+                None => break,
+            };
+
+            if id == StringId::INVALID {
+                // An escaped byte, see `measureme::stringtable`.
+                pos += ESCAPED_BYTE_ENCODED_SIZE;
+            } else {
+                if let Err(message) = self.resolve(id) {
+                    return error(
+                        self.data.file_offset(pos),
+                        format!("a component of the string at address {} {}", addr, message),
+                    );
+                }
+                pos += STRING_REF_ENCODED_SIZE;
+            }
+
+            if pos >= bytes.len() {
+                break;
+            }
+        }
+
+        error(
+            self.data.file_offset(addr),
+            format!("the string at address {} ends without a terminator", addr),
+        )
+    }
+}
+
+/// Checks that the events stream consists of complete events whose event kinds
+/// and ids refer to existing strings, and returns the number of events.
+fn verify_events(events: &Stream, strings: &mut Strings) -> Result<usize, VerifyError> {
+    let event_bytes = &events.bytes[FILE_HEADER_SIZE..];
+    let partial_len = event_bytes.len() % RAW_EVENT_SIZE;
+    if partial_len != 0 {
+        return error(
+            events.file_offset(events.bytes.len() - partial_len),
+            format!(
+                "the events stream ends with a partial event of {} bytes",
+                partial_len
+            ),
+        );
+    }
+
+    for (event_index, bytes) in event_bytes.chunks_exact(RAW_EVENT_SIZE).enumerate() {
+        let raw_event = RawEvent::deserialize(bytes);
+        let offset = events.file_offset(FILE_HEADER_SIZE + event_index * RAW_EVENT_SIZE);
+
+        for (what, id) in [
+            ("event kind", raw_event.event_kind),
+            ("event id", raw_event.event_id.to_string_id()),
+        ] {
+            match strings.resolve(id) {
+                Ok(addr) => strings.verify_string(addr)?,
+                Err(message) => {
+                    return error(
+                        offset,
+                        format!("the {} of event {} {}", what, event_index, message),
+                    )
+                }
+            }
+        }
+    }
+
+    Ok(event_bytes.len() / RAW_EVENT_SIZE)
+}
+
+/// Checks that the events of each thread have been recorded in order.
+fn verify_timestamps(decoder: &EventDecoder, events: &Stream) -> Result<(), VerifyError> {
+    let start_time = decoder.metadata().start_time;
+    let nanos = |t: SystemTime| t.duration_since(start_time).unwrap_or_default().as_nanos();
+
+    let mut latest = FxHashMap::<u32, SystemTime>::default();
+
+    for event_index in 0..decoder.num_events() {
+        let event = decoder.decode_lightweight_event(event_index);
+        let offset = events.file_offset(FILE_HEADER_SIZE + event_index * RAW_EVENT_SIZE);
+
+        let recorded_at = match event.payload.timestamp() {
+            Some(Timestamp::Interval { start, end }) => {
+                if end < start {
+                    return error(
+                        offset,
+                        format!(
+                            "event {} on thread {} ends at {} ns, before it starts at {} ns",
+                            event_index,
+                            event.thread_id,
+                            nanos(end),
+                            nanos(start)
+                        ),
+                    );
+                }
+                end
+            }
+            Some(Timestamp::Instant(t)) => t,
+            None => continue,
+        };
+
+        let previous = latest.entry(event.thread_id).or_insert(recorded_at);
+        if recorded_at < *previous {
+            return error(
+                offset,
+                format!(
+                    "event {} on thread {} is recorded at {} ns, before the previous event \
+                     of the thread at {} ns",
+                    event_index,
+                    event.thread_id,
+                    nanos(recorded_at),
+                    nanos(*previous)
+                ),
+            );
+        }
+        *previous = recorded_at;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::counters::Counter;
+    use measureme::testing_clocks::SteppingClock;
+    use measureme::{EventId, InMemorySink, Profiler, ProfilerOptions};
+
+    fn record_profile() -> Vec<u8> {
+        let sink = InMemorySink::new();
+        let profiler = Profiler::with_sink_and_options(
+            sink.clone(),
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions::default(),
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        for thread_id in 0..2 {
+            let _guard = profiler.start_recording_interval_event(event_kind, event_id, thread_id);
+            profiler.record_instant_event(event_kind, event_id, thread_id, None);
+        }
+
+        drop(profiler);
+        sink.bytes()
+    }
+
+    /// The file offset of the contents of the first page with the given tag.
+    fn page_contents_offset(data: &[u8], tag: PageTag) -> usize {
+        let mut pos = FILE_HEADER_SIZE;
+        while data[pos] != tag as u8 {
+            let page_size = u32::from_le_bytes(data[pos + 1..pos + 5].try_into().unwrap());
+            pos += PAGE_HEADER_SIZE + page_size as usize;
+        }
+        pos + PAGE_HEADER_SIZE
+    }
+
+    /// Applies `f` to the `event_index`-th event of the file and returns the
+    /// offset of the event.
+    fn modify_event(data: &mut [u8], event_index: usize, f: impl FnOnce(&mut RawEvent)) -> usize {
+        let offset = page_contents_offset(data, PageTag::Events)
+            + FILE_HEADER_SIZE
+            + event_index * RAW_EVENT_SIZE;
+        let bytes = &mut data[offset..offset + RAW_EVENT_SIZE];
+
+        let mut raw_event = RawEvent::deserialize(bytes);
+        f(&mut raw_event);
+        raw_event.serialize(bytes);
+        offset
+    }
+
+    fn verify_error(data: &[u8]) -> VerifyError {
+        verify_file(data).unwrap_err()
+    }
+
+    #[test]
+    fn well_formed_profile() {
+        let verified = verify_file(&record_profile()).unwrap();
+
+        assert_eq!(verified.num_events, 4);
+        assert!(verified.num_pages >= 3);
+        // Only the metadata string is virtual.
+        assert_eq!(verified.num_indexed_strings, 1);
+    }
+
+    #[test]
+    fn invalid_top_level_header() {
+        let data = record_profile();
+
+        let mut bad_magic = data.clone();
+        bad_magic[1] = b'X';
+        assert_eq!(verify_error(&bad_magic).offset, 0);
+
+        let mut bad_version = data.clone();
+        bad_version[4] = 7;
+        let e = verify_error(&bad_version);
+        assert_eq!(e.offset, 4);
+        assert_eq!(
+            e.message,
+            format!(
+                "expected file format version {} but found 7",
+                CURRENT_FILE_FORMAT_VERSION
+            )
+        );
+
+        let mut bad_codec = data.clone();
+        bad_codec[FILE_CODEC_BYTE_INDEX] = 9;
+        assert_eq!(verify_error(&bad_codec).offset, FILE_CODEC_BYTE_INDEX);
+
+        assert_eq!(verify_error(&data[..5]).offset, 0);
+    }
+
+    #[test]
+    fn truncated_and_corrupt_pages() {
+        let data = record_profile();
+
+        let mut last_page = FILE_HEADER_SIZE;
+        for (_, page_contents) in measureme::iter_pages(&data[FILE_HEADER_SIZE..]) {
+            let page_end = last_page + PAGE_HEADER_SIZE + page_contents.len();
+            if page_end == data.len() {
+                break;
+            }
+            last_page = page_end;
+        }
+
+        let e = verify_error(&data[..data.len() - 1]);
+        assert_eq!(e.offset, last_page);
+        assert!(e.message.starts_with("the file ends within a page,"));
+
+        assert_eq!(verify_error(&data[..last_page + 2]).offset, last_page);
+
+        let mut bad_tag = data.clone();
+        bad_tag[last_page] = 42;
+        let e = verify_error(&bad_tag);
+        assert_eq!(e.offset, last_page);
+        assert_eq!(e.message, "invalid page tag 42");
+    }
+
+    #[test]
+    fn string_index_entry_out_of_bounds() {
+        let mut data = record_profile();
+
+        let entry = page_contents_offset(&data, PageTag::StringIndex) + FILE_HEADER_SIZE;
+        data[entry + 4..entry + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let e = verify_error(&data);
+        assert_eq!(e.offset, entry);
+        assert!(e.message.contains("points to address 4294967295"));
+    }
+
+    #[test]
+    fn event_with_unknown_string() {
+        let mut data = record_profile();
+
+        let offset = modify_event(&mut data, 2, |raw_event| {
+            raw_event.event_kind = StringId::new_virtual(12345);
+        });
+
+        let e = verify_error(&data);
+        assert_eq!(e.offset, offset);
+        assert_eq!(
+            e.message,
+            "the event kind of event 2 refers to virtual string id 12345, \
+             which is not in the string index"
+        );
+    }
+
+    #[test]
+    fn events_out_of_order() {
+        let mut data = record_profile();
+
+        // The instant event of the first thread, recorded before the end of
+        // its interval.
+        let mut instant = None;
+        modify_event(&mut data, 0, |raw_event| instant = Some(*raw_event));
+        let mut interval = None;
+        modify_event(&mut data, 1, |raw_event| {
+            interval = Some(*raw_event);
+            *raw_event = instant.unwrap();
+        });
+        modify_event(&mut data, 0, |raw_event| *raw_event = interval.unwrap());
+
+        let e = verify_error(&data);
+        assert_eq!(
+            e.offset,
+            page_contents_offset(&data, PageTag::Events) + FILE_HEADER_SIZE + RAW_EVENT_SIZE
+        );
+        assert!(e.message.starts_with("event 1 on thread 0 is recorded at"));
+    }
+}
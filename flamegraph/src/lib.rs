@@ -0,0 +1,159 @@
+//! Support for differential flamegraphs, which show how the self time of each
+//! stack changed between a baseline profile and the current one, for pruning
+//! frames that are too narrow to be seen, and for coloring frames by the
+//! semantic group they belong to, and for making sure that the widths of the
+//! frames stand for what they are meant to.
+
+use analyzeme::ProfilingData;
+use std::collections::BTreeMap;
+
+mod palette;
+mod prune;
+mod weight;
+
+pub use palette::FramePalette;
+pub use prune::{prune_narrow_frames, DEFAULT_IMAGE_WIDTH, OTHER_FRAME};
+pub use weight::{EventWeight, WeightBy};
+
+/// Frame name suffix for stacks that only exist in the current profile.
+pub const ADDED_TAG: &str = " [added]";
+/// Frame name suffix for stacks that only exist in the baseline profile.
+pub const REMOVED_TAG: &str = " [removed]";
+
+/// The self time of a single stack in both profiles.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackDiff {
+    pub stack: String,
+    pub baseline_nanos: u64,
+    pub current_nanos: u64,
+}
+
+impl StackDiff {
+    /// Positive values mean that the stack got slower.
+    pub fn delta_nanos(&self) -> i128 {
+        self.current_nanos as i128 - self.baseline_nanos as i128
+    }
+
+    pub fn is_added(&self) -> bool {
+        self.baseline_nanos == 0 && self.current_nanos > 0
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.current_nanos == 0 && self.baseline_nanos > 0
+    }
+
+    /// Renders the stack as a line of inferno's differential input format,
+    /// `<stack> <before> <after>`, where the frame width is taken from
+    /// `<after>` and the color from the difference of the two.
+    ///
+    /// The leaf frame of stacks that exist in only one of the profiles gets
+    /// tagged with `ADDED_TAG` or `REMOVED_TAG`. Removed stacks would have no
+    /// width at all, so they are drawn with their baseline width instead and
+    /// colored like a stack that improved by that amount.
+    pub fn to_differential_line(&self) -> String {
+        if self.is_added() {
+            format!("{}{} 0 {}", self.stack, ADDED_TAG, self.current_nanos)
+        } else if self.is_removed() {
+            format!(
+                "{}{} {} {}",
+                self.stack,
+                REMOVED_TAG,
+                2 * self.baseline_nanos,
+                self.baseline_nanos
+            )
+        } else {
+            format!(
+                "{} {} {}",
+                self.stack, self.baseline_nanos, self.current_nanos
+            )
+        }
+    }
+}
+
+/// Collapses the stacks of both profiles, keyed by label and category of
+/// each frame, and pairs up the self times of equal stacks. The result is
+/// sorted by stack.
+pub fn diff_stacks(baseline: &ProfilingData, current: &ProfilingData) -> Vec<StackDiff> {
+    diff_stacks_weighted(
+        baseline,
+        EventWeight::CounterValue,
+        current,
+        EventWeight::CounterValue,
+    )
+}
+
+/// Like `diff_stacks`, with the events of each profile weighed by its
+/// `EventWeight`.
+pub fn diff_stacks_weighted(
+    baseline: &ProfilingData,
+    baseline_weight: EventWeight,
+    current: &ProfilingData,
+    current_weight: EventWeight,
+) -> Vec<StackDiff> {
+    let mut stacks = BTreeMap::<String, (u64, u64)>::new();
+
+    for (stack, nanos) in baseline_weight.collapse_stacks_with_categories(baseline) {
+        stacks.entry(stack).or_default().0 += nanos;
+    }
+
+    for (stack, nanos) in current_weight.collapse_stacks_with_categories(current) {
+        stacks.entry(stack).or_default().1 += nanos;
+    }
+
+    stacks
+        .into_iter()
+        .map(|(stack, (baseline_nanos, current_nanos))| StackDiff {
+            stack,
+            baseline_nanos,
+            current_nanos,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+
+    #[test]
+    fn diff() {
+        let mut baseline = ProfilingDataBuilder::new();
+        baseline.interval("Query", "e1", 0, 0, 10, |b| {
+            b.interval("Query", "e2", 0, 2, 4, |_| {});
+        });
+
+        let mut current = ProfilingDataBuilder::new();
+        current.interval("Query", "e1", 0, 0, 6, |b| {
+            b.interval("Query", "e3", 0, 2, 5, |_| {});
+        });
+
+        let diffs = diff_stacks(
+            &baseline.into_profiling_data(),
+            &current.into_profiling_data(),
+        );
+
+        let stack_diff = |stack: &str, baseline_nanos, current_nanos| StackDiff {
+            stack: stack.to_string(),
+            baseline_nanos,
+            current_nanos,
+        };
+
+        assert_eq!(
+            diffs,
+            vec![
+                stack_diff("rustc", 0, 0),
+                stack_diff("rustc;e1", 8, 3),
+                stack_diff("rustc;e1;e2", 2, 0),
+                stack_diff("rustc;e1;e3", 0, 3),
+            ]
+        );
+
+        assert_eq!(diffs[1].delta_nanos(), -5);
+        assert!(diffs[2].is_removed());
+        assert!(diffs[3].is_added());
+
+        assert_eq!(diffs[1].to_differential_line(), "rustc;e1 8 3");
+        assert_eq!(diffs[2].to_differential_line(), "rustc;e1;e2 [removed] 4 2");
+        assert_eq!(diffs[3].to_differential_line(), "rustc;e1;e3 [added] 0 3");
+    }
+}
@@ -1,29 +1,130 @@
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use analyzeme::{collapse_stacks, ProfilingData};
+use analyzeme::{warn_about_profile, ProfilingData};
 use clap::Parser;
+use flamegraph::{
+    diff_stacks_weighted, prune_narrow_frames, EventWeight, FramePalette, WeightBy,
+    DEFAULT_IMAGE_WIDTH,
+};
 use inferno::flamegraph::{from_lines, Options as FlamegraphOptions};
 
 #[derive(Parser, Debug)]
 struct Opt {
     file_prefix: PathBuf,
+
+    /// Generate a differential flamegraph, coloring stacks by how much their
+    /// self time changed compared to this profile
+    #[clap(long = "baseline")]
+    baseline: Option<PathBuf>,
+
+    /// The width of the generated image, in pixels [default: 1200]
+    #[clap(long = "image-width")]
+    image_width: Option<usize>,
+
+    /// Fold frames narrower than this many pixels into an "(other)" frame
+    /// next to them, which keeps the image small for large profiles
+    #[clap(long = "min-width-pixels", conflicts_with = "baseline")]
+    min_width_pixels: Option<f64>,
+
+    /// Color frames according to the rules in this file, which map the
+    /// category or a prefix of the label of an event to a color, e.g.
+    /// `category codegen #ff8800` or `prefix typeck #3366cc`. Other frames are
+    /// colored as usual
+    #[clap(long = "palette", conflicts_with = "baseline")]
+    palette: Option<PathBuf>,
+
+    /// Show demangled names instead of mangled Rust symbol names, e.g.
+    /// `core::ptr::drop_in_place` instead of `_ZN4core3ptr13drop_in_place..`
+    #[clap(long = "demangle")]
+    demangle: bool,
+
+    /// What the widths of the frames stand for. The profile has to be
+    /// recorded with a counter that measures it, e.g. `instructions:u` for
+    /// `instructions`, or with wall times for `time`. By default, the widths
+    /// are in the unit of whatever counter the profile has been recorded with
+    #[clap(long = "weight-by", value_enum)]
+    weight_by: Option<WeightBy>,
+}
+
+fn event_weight(
+    weight_by: Option<WeightBy>,
+    profiling_data: &ProfilingData,
+    path: &Path,
+) -> Result<EventWeight, Box<dyn Error + Send + Sync>> {
+    match weight_by {
+        Some(weight_by) => Ok(weight_by
+            .event_weight(profiling_data)
+            .map_err(|e| format!("Cannot weight the frames of `{}`: {}", path.display(), e))?),
+        None => Ok(EventWeight::CounterValue),
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let opt = Opt::from_args();
 
-    let profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    let mut profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    if opt.demangle {
+        profiling_data.demangle_symbols();
+    }
+
+    let weight = event_weight(opt.weight_by, &profiling_data, &opt.file_prefix)?;
+
+    warn_about_profile(&profiling_data, &opt.file_prefix);
+
+    let image_width = opt.image_width.unwrap_or(DEFAULT_IMAGE_WIDTH);
+
+    let recorded_stacks = match opt.baseline {
+        Some(ref baseline_path) => {
+            let mut baseline = ProfilingData::new(baseline_path)?;
+            if opt.demangle {
+                baseline.demangle_symbols();
+            }
+            let baseline_weight = event_weight(opt.weight_by, &baseline, baseline_path)?;
+
+            warn_about_profile(&baseline, baseline_path);
+
+            diff_stacks_weighted(&baseline, baseline_weight, &profiling_data, weight)
+                .iter()
+                .map(|stack_diff| stack_diff.to_differential_line())
+                .collect::<Vec<_>>()
+        }
+        None => {
+            let stacks = weight.collapse_stacks(&profiling_data);
+            let stacks = match opt.min_width_pixels {
+                Some(min_width_pixels) => {
+                    prune_narrow_frames(stacks, image_width, min_width_pixels)
+                }
+                None => stacks.into_iter().collect(),
+            };
+
+            stacks
+                .iter()
+                .map(|(unique_stack, count)| format!("{} {}", unique_stack, count))
+                .collect::<Vec<_>>()
+        }
+    };
 
-    let recorded_stacks = collapse_stacks(&profiling_data)
-        .iter()
-        .map(|(unique_stack, count)| format!("{} {}", unique_stack, count))
-        .collect::<Vec<_>>();
+    let mut palette_map = match opt.palette {
+        Some(ref palette_path) => {
+            let palette = FramePalette::parse(&fs::read_to_string(palette_path)?)
+                .map_err(|e| format!("Invalid palette `{}`: {}", palette_path.display(), e))?;
+            Some(palette.palette_map(&profiling_data))
+        }
+        None => None,
+    };
 
     let file = BufWriter::new(File::create("rustc.svg")?);
-    let mut flamegraph_options = FlamegraphOptions::default();
+    let mut flamegraph_options = FlamegraphOptions {
+        image_width: Some(image_width),
+        palette_map: palette_map.as_mut(),
+        ..Default::default()
+    };
+    if let Some(weight_by) = opt.weight_by {
+        flamegraph_options.count_name = weight_by.count_name().to_owned();
+    }
 
     from_lines(
         &mut flamegraph_options,
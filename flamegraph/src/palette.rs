@@ -0,0 +1,205 @@
+//! Coloring frames by semantic group, e.g. all codegen frames in one hue and
+//! all typeck frames in another, instead of by a hash of their name.
+//!
+//! A palette file has one rule per line, either
+//!
+//! ```text
+//! category <category> <color>
+//! prefix <label prefix> <color>
+//! ```
+//!
+//! where `<color>` is given as `#rrggbb`. Empty lines and lines starting with
+//! `#` are ignored. A frame gets the color of the category of its event, if
+//! there is a rule for it, and otherwise the color of the longest prefix of
+//! its label that there is a rule for.
+
+use analyzeme::ProfilingData;
+use inferno::flamegraph::color::{Color, PaletteMap};
+use std::collections::HashMap;
+
+/// The color rules of a palette file.
+#[derive(Clone, Debug, Default)]
+pub struct FramePalette {
+    categories: HashMap<String, Color>,
+    /// Ordered by decreasing length, so that the first match is the longest.
+    label_prefixes: Vec<(String, Color)>,
+}
+
+impl FramePalette {
+    /// Parses the contents of a palette file, see the module documentation.
+    pub fn parse(text: &str) -> Result<FramePalette, String> {
+        let mut palette = FramePalette::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: &str| format!("line {}: {}: `{}`", index + 1, message, line);
+
+            let (kind, rest) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected `category` or `prefix`, a name and a color"))?;
+            let (name, color) = rest
+                .trim()
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected a name and a color"))?;
+            let name = name.trim().to_string();
+            let color = parse_color(color).ok_or_else(|| invalid("expected a `#rrggbb` color"))?;
+
+            match kind {
+                "category" => {
+                    palette.categories.insert(name, color);
+                }
+                "prefix" => palette.label_prefixes.push((name, color)),
+                _ => return Err(invalid("expected `category` or `prefix`")),
+            }
+        }
+
+        // The sort is stable, so for duplicate prefixes the first rule wins.
+        palette
+            .label_prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(palette)
+    }
+
+    /// The color of the frames of events with `label` and `category`, or
+    /// `None` if no rule applies.
+    pub fn color(&self, label: &str, category: Option<&str>) -> Option<Color> {
+        if let Some(&color) = category.and_then(|category| self.categories.get(category)) {
+            return Some(color);
+        }
+
+        self.label_prefixes
+            .iter()
+            .find(|(prefix, _)| label.starts_with(&prefix[..]))
+            .map(|&(_, color)| color)
+    }
+
+    /// The colors of the frames of `profiling_data` that a rule applies to,
+    /// keyed by the frame names that `analyzeme::collapse_stacks` creates,
+    /// i.e. by label. If events with the same label have different
+    /// categories, the category of the first of them decides.
+    pub fn palette_map(&self, profiling_data: &ProfilingData) -> PaletteMap {
+        let mut categories = HashMap::new();
+        for event in profiling_data.iter_full() {
+            if event.payload.is_interval() {
+                let category = event.category.map(|c| c.into_owned());
+                categories
+                    .entry(event.label.into_owned())
+                    .or_insert(category);
+            }
+        }
+
+        let mut palette_map = PaletteMap::default();
+        for (label, category) in categories {
+            if let Some(color) = self.color(&label, category.as_deref()) {
+                palette_map.insert(label, color);
+            }
+        }
+
+        palette_map
+    }
+}
+
+fn parse_color(text: &str) -> Option<Color> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Some(Color {
+        r: component(0),
+        g: component(2),
+        b: component(4),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+
+    const CODEGEN: Color = Color {
+        r: 0xff,
+        g: 0x88,
+        b: 0x00,
+    };
+    const TYPECK: Color = Color {
+        r: 0x33,
+        g: 0x66,
+        b: 0xcc,
+    };
+    const TYPECK_ITEM: Color = Color {
+        r: 0x00,
+        g: 0x00,
+        b: 0x80,
+    };
+
+    fn palette() -> FramePalette {
+        FramePalette::parse(
+            "# Semantic groups\n\
+             category codegen #ff8800\n\
+             \n\
+             prefix typeck #3366CC\n\
+             prefix typeck_item #000080\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let palette = palette();
+
+        assert_eq!(
+            palette.color("codegen_crate", Some("codegen")),
+            Some(CODEGEN)
+        );
+        // Categories take precedence over prefixes.
+        assert_eq!(palette.color("typeck", Some("codegen")), Some(CODEGEN));
+        assert_eq!(palette.color("typeck", None), Some(TYPECK));
+        assert_eq!(palette.color("typeck_body", Some("other")), Some(TYPECK));
+        // The longest prefix wins, regardless of the order of the rules.
+        assert_eq!(palette.color("typeck_item_bodies", None), Some(TYPECK_ITEM));
+        assert_eq!(palette.color("borrowck", None), None);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for &(text, message) in &[
+            (
+                "category",
+                "line 1: expected `category` or `prefix`, a name and a color",
+            ),
+            ("category codegen", "line 1: expected a name and a color"),
+            ("\nprefix typeck red", "line 2: expected a `#rrggbb` color"),
+            ("prefix typeck #12345", "line 1: expected a `#rrggbb` color"),
+            (
+                "label typeck #123456",
+                "line 1: expected `category` or `prefix`",
+            ),
+        ] {
+            let error = FramePalette::parse(text).unwrap_err();
+            assert!(error.starts_with(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn palette_map() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "codegen_crate\x1E\x12codegen", 0, 0, 100, |b| {
+            b.interval("Query", "typeck", 0, 10, 20, |_| {});
+            b.interval("Query", "borrowck", 0, 30, 40, |_| {});
+        });
+
+        let palette_map = palette().palette_map(&b.into_profiling_data());
+
+        assert_eq!(palette_map.get("codegen_crate"), Some(CODEGEN));
+        assert_eq!(palette_map.get("typeck"), Some(TYPECK));
+        assert_eq!(palette_map.get("borrowck"), None);
+        assert_eq!(palette_map.get("rustc"), None);
+    }
+}
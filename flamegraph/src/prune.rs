@@ -0,0 +1,169 @@
+//! Pruning of frames that would be too narrow to see in the rendered image.
+
+use std::collections::BTreeMap;
+
+/// The frame that takes the place of the children of a frame that have been
+/// pruned by `prune_narrow_frames`.
+pub const OTHER_FRAME: &str = "(other)";
+
+/// The width of the image if none is given, which is also inferno's default.
+pub const DEFAULT_IMAGE_WIDTH: usize = 1200;
+
+/// The space that inferno leaves to the left and to the right of the frames,
+/// in pixels.
+const IMAGE_PADDING: usize = 10;
+
+#[derive(Default)]
+struct Frame {
+    self_count: u64,
+    total_count: u64,
+    children: BTreeMap<String, Frame>,
+}
+
+impl Frame {
+    fn insert(&mut self, stack: &str, count: u64) {
+        self.total_count += count;
+
+        let mut frame = self;
+        for name in stack.split(';') {
+            frame = frame.children.entry(name.to_string()).or_default();
+            frame.total_count += count;
+        }
+        frame.self_count += count;
+    }
+
+    fn prune(&mut self, min_count: u64) {
+        let mut pruned_count = 0;
+        self.children.retain(|_, child| {
+            let keep = child.total_count >= min_count;
+            if !keep {
+                pruned_count += child.total_count;
+            }
+            keep
+        });
+
+        for child in self.children.values_mut() {
+            child.prune(min_count);
+        }
+
+        if pruned_count > 0 {
+            let other = self.children.entry(OTHER_FRAME.to_string()).or_default();
+            other.self_count += pruned_count;
+            other.total_count += pruned_count;
+        }
+    }
+
+    fn write_stacks(&self, stack: &mut String, out: &mut Vec<(String, u64)>) {
+        for (name, child) in &self.children {
+            let len = stack.len();
+            if len > 0 {
+                stack.push(';');
+            }
+            stack.push_str(name);
+
+            if child.self_count > 0 {
+                out.push((stack.clone(), child.self_count));
+            }
+            child.write_stacks(stack, out);
+
+            stack.truncate(len);
+        }
+    }
+}
+
+/// Removes the frames that would be narrower than `min_width_pixels` in a
+/// flamegraph that is `image_width` pixels wide, together with all frames
+/// above them. The count of the removed frames is attributed to an
+/// `OTHER_FRAME` frame next to them instead, so that each remaining frame
+/// keeps its width. That frame may be narrower than `min_width_pixels`
+/// itself.
+///
+/// `stacks` and the result are in the folded stacks format, i.e. pairs of
+/// `;`-separated frames and the self count of the last frame. The result is
+/// sorted by stack.
+pub fn prune_narrow_frames(
+    stacks: impl IntoIterator<Item = (String, u64)>,
+    image_width: usize,
+    min_width_pixels: f64,
+) -> Vec<(String, u64)> {
+    let mut root = Frame::default();
+    for (stack, count) in stacks {
+        root.insert(&stack, count);
+    }
+
+    let drawable_width = image_width.saturating_sub(2 * IMAGE_PADDING).max(1);
+    let min_count = (min_width_pixels * root.total_count as f64 / drawable_width as f64).ceil();
+    root.prune(min_count as u64);
+
+    let mut out = Vec::new();
+    root.write_stacks(&mut String::new(), &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stacks(stacks: &[(&str, u64)]) -> Vec<(String, u64)> {
+        stacks
+            .iter()
+            .map(|&(stack, count)| (stack.to_string(), count))
+            .collect()
+    }
+
+    // The total count of all frames at each depth.
+    fn row_widths(stacks: &[(String, u64)]) -> Vec<u64> {
+        let mut widths = Vec::new();
+        for (stack, count) in stacks {
+            let depth = stack.split(';').count();
+            if widths.len() < depth {
+                widths.resize(depth, 0);
+            }
+            for width in &mut widths[..depth] {
+                *width += count;
+            }
+        }
+        widths
+    }
+
+    #[test]
+    fn narrow_frames_are_folded_into_other() {
+        let input = stacks(&[
+            ("rustc", 100),
+            ("rustc;typeck", 500),
+            ("rustc;typeck;tiny", 5),
+            ("rustc;typeck;small", 10),
+            ("rustc;typeck;small;tiny", 10),
+            ("rustc;borrowck", 370),
+            ("rustc;a", 3),
+            ("rustc;b", 2),
+        ]);
+
+        // 1000 counts over 100 drawable pixels, so frames of less than 20
+        // counts are removed.
+        let pruned = prune_narrow_frames(input.clone(), 120, 2.0);
+
+        assert_eq!(
+            pruned,
+            stacks(&[
+                ("rustc", 100),
+                ("rustc;(other)", 5),
+                ("rustc;borrowck", 370),
+                ("rustc;typeck", 500),
+                ("rustc;typeck;(other)", 5),
+                ("rustc;typeck;small", 10),
+                ("rustc;typeck;small;(other)", 10),
+            ])
+        );
+
+        // Folding the time into the `(other)` frames keeps the width of all
+        // rows.
+        assert_eq!(row_widths(&input), vec![1000, 900, 25, 10]);
+        assert_eq!(row_widths(&pruned), vec![1000, 900, 25, 10]);
+
+        // Nothing gets pruned at a larger width.
+        let unpruned = prune_narrow_frames(input.clone(), 2020, 2.0);
+        assert_eq!(unpruned.len(), input.len());
+        assert_eq!(row_widths(&unpruned), row_widths(&input));
+    }
+}
@@ -0,0 +1,193 @@
+//! What the widths of the frames stand for.
+//!
+//! The widths are the counter values of the events, i.e. their durations in
+//! the unit of the counter that the profile has been recorded with, unless
+//! the profile has been recorded with an instruction counter and the events
+//! are weighed by time, in which case their wall times are used. Pruning and
+//! diffing stacks treat them as plain numbers either way.
+
+use analyzeme::{
+    collapse_stacks, collapse_stacks_weighted, collapse_stacks_with_categories,
+    collapse_stacks_with_categories_weighted, CounterDescription, LightweightEvent, ProfilingData,
+};
+use clap::ValueEnum;
+use rustc_hash::FxHashMap;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightBy {
+    /// Nanoseconds, for profiles recorded with a counter that measures time,
+    /// e.g. `wall-time`.
+    Time,
+    /// Retired instructions, which are far less noisy than times, for
+    /// profiles recorded with an instruction counter, e.g. `instructions:u`.
+    Instructions,
+}
+
+impl WeightBy {
+    /// Fails if the events of a profile recorded with `counter` aren't
+    /// weighed in this unit. `None` stands for profiles of older versions of
+    /// measureme, which have always been recorded with `wall-time`.
+    pub fn check_counter(self, counter: Option<&CounterDescription>) -> Result<(), String> {
+        let counter_name = counter
+            .map(|counter| &counter.name[..])
+            .unwrap_or("wall-time");
+
+        match self {
+            WeightBy::Time => {
+                let measures_time = match counter {
+                    Some(counter) => counter.measures_time(),
+                    None => true,
+                };
+                if measures_time {
+                    return Ok(());
+                }
+
+                Err(format!(
+                    "it has been recorded with the `{}` counter, whose values aren't times",
+                    counter_name
+                ))
+            }
+            WeightBy::Instructions => {
+                if counter.is_some_and(CounterDescription::counts_instructions) {
+                    return Ok(());
+                }
+
+                Err(format!(
+                    "it has been recorded with the `{}` counter instead of an instruction counter like `instructions:u`",
+                    counter_name
+                ))
+            }
+        }
+    }
+
+    /// How the events of `profiling_data` are weighed in this unit. Fails if
+    /// they can't be, see `check_counter`, except for weighing by time a
+    /// profile that has the wall times of its events (see
+    /// `ProfilingData::has_wall_times`).
+    pub fn event_weight(self, profiling_data: &ProfilingData) -> Result<EventWeight, String> {
+        match self.check_counter(profiling_data.metadata().counter.as_ref()) {
+            Ok(()) => Ok(EventWeight::CounterValue),
+            Err(_) if self == WeightBy::Time && profiling_data.has_wall_times() => {
+                Ok(EventWeight::WallTime)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// What the width of a frame is given in, as shown when hovering over it.
+    pub fn count_name(self) -> &'static str {
+        match self {
+            WeightBy::Time => "ns",
+            WeightBy::Instructions => "instructions",
+        }
+    }
+}
+
+/// What the width of the frame of an event is taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventWeight {
+    /// The duration of the event in the unit of the profile's counter.
+    CounterValue,
+    /// The wall time of the event, see `ProfilingData::wall_time`.
+    WallTime,
+}
+
+impl EventWeight {
+    /// `analyzeme::collapse_stacks`, with the events weighed like this.
+    pub fn collapse_stacks(self, profiling_data: &ProfilingData) -> FxHashMap<String, u64> {
+        match self {
+            EventWeight::CounterValue => collapse_stacks(profiling_data),
+            EventWeight::WallTime => {
+                collapse_stacks_weighted(profiling_data, |event| wall_nanos(profiling_data, event))
+            }
+        }
+    }
+
+    /// `analyzeme::collapse_stacks_with_categories`, with the events weighed
+    /// like this.
+    pub fn collapse_stacks_with_categories(
+        self,
+        profiling_data: &ProfilingData,
+    ) -> FxHashMap<String, u64> {
+        match self {
+            EventWeight::CounterValue => collapse_stacks_with_categories(profiling_data),
+            EventWeight::WallTime => {
+                collapse_stacks_with_categories_weighted(profiling_data, |event| {
+                    wall_nanos(profiling_data, event)
+                })
+            }
+        }
+    }
+}
+
+fn wall_nanos(profiling_data: &ProfilingData, event: &LightweightEvent) -> u64 {
+    profiling_data
+        .wall_time(event)
+        .map_or(0, |wall_time| wall_time.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+
+    fn counter(name: &str, unit: &str) -> CounterDescription {
+        CounterDescription {
+            name: name.to_string(),
+            units: vec![(unit.to_string(), 1)],
+        }
+    }
+
+    #[test]
+    fn counters_of_each_weight() {
+        let wall_time = counter("wall-time", "ns");
+        let instructions = counter("instructions:u", "instructions");
+        let cache_misses = counter("cache-misses:u", "misses");
+
+        assert_eq!(WeightBy::Time.check_counter(None), Ok(()));
+        assert_eq!(WeightBy::Time.check_counter(Some(&wall_time)), Ok(()));
+        assert!(WeightBy::Time
+            .check_counter(Some(&instructions))
+            .unwrap_err()
+            .contains("`instructions:u` counter"));
+
+        assert_eq!(
+            WeightBy::Instructions.check_counter(Some(&instructions)),
+            Ok(())
+        );
+        assert!(WeightBy::Instructions
+            .check_counter(None)
+            .unwrap_err()
+            .contains("`wall-time` counter"));
+        assert!(WeightBy::Instructions
+            .check_counter(Some(&cache_misses))
+            .is_err());
+    }
+
+    #[test]
+    fn time_of_instruction_profiles_with_wall_times() {
+        let mut b = ProfilingDataBuilder::with_counter(counter("instructions:u", "instructions"));
+        b.interval_with_wall_time("Query", "e1", 0, 0, 1000, 30, |b| {
+            b.interval_with_wall_time("Query", "e2", 0, 100, 900, 20, |_| {});
+        });
+        let with_wall_times = b.into_profiling_data();
+
+        assert_eq!(
+            WeightBy::Time.event_weight(&with_wall_times),
+            Ok(EventWeight::WallTime)
+        );
+        assert_eq!(
+            WeightBy::Instructions.event_weight(&with_wall_times),
+            Ok(EventWeight::CounterValue)
+        );
+
+        let stacks = EventWeight::WallTime.collapse_stacks(&with_wall_times);
+        assert_eq!(stacks["rustc;e1"], 10);
+        assert_eq!(stacks["rustc;e1;e2"], 20);
+
+        let mut b = ProfilingDataBuilder::with_counter(counter("instructions:u", "instructions"));
+        b.interval("Query", "e1", 0, 0, 1000, |_| {});
+        let without_wall_times = b.into_profiling_data();
+        assert!(WeightBy::Time.event_weight(&without_wall_times).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Measures the cost of recording events with a profiler created by
+//! `Profiler::disabled`, compared to not calling the profiler at all and to
+//! an enabled profiler.
+//!
+//! Run with `cargo bench -p measureme --bench disabled_profiler`.
+
+use measureme::{EventId, Profiler, StringId};
+use std::hint::black_box;
+use std::time::Instant;
+
+const ITERATIONS: usize = 10_000_000;
+
+fn run(name: &str, mut f: impl FnMut(usize)) {
+    let start = Instant::now();
+
+    for i in 0..ITERATIONS {
+        f(black_box(i));
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>8.2} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn record(profiler: &Profiler, event_kind: StringId, event_id: EventId, i: usize) {
+    let _guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+    profiler.record_instant_event(event_kind, event_id, 0, None);
+    black_box(i);
+}
+
+fn main() {
+    run("no profiler", |i| {
+        black_box(i);
+    });
+
+    let disabled = Profiler::disabled();
+    let event_kind = disabled.alloc_string("kind");
+    let event_id = EventId::from_label(disabled.alloc_string("label"));
+    run("disabled profiler", |i| {
+        record(black_box(&disabled), event_kind, event_id, i)
+    });
+
+    let path_stem = std::env::temp_dir().join(format!("disabled_profiler-{}", std::process::id()));
+    let enabled = Profiler::new(&path_stem).unwrap();
+    let event_kind = enabled.alloc_string("kind");
+    let event_id = EventId::from_label(enabled.alloc_string("label"));
+    run("enabled profiler", |i| {
+        record(&enabled, event_kind, event_id, i)
+    });
+
+    drop(enabled);
+    let _ = std::fs::remove_file(path_stem.with_extension(measureme::file_header::FILE_EXTENSION));
+}
@@ -0,0 +1,92 @@
+//! Measures the throughput of recording instant events directly on the
+//! profiler and through an `EventBatch`, on one thread and on several threads
+//! that record at the same time. The `late` variants use a clock that starts
+//! after the first timestamp epoch, so that every event has to be checked
+//! against the latest epoch marker (see `TIMESTAMP_EPOCH_LENGTH`), which the
+//! other variants measure the baseline for.
+//!
+//! Run with `cargo bench -p measureme --bench event_batch`.
+
+use measureme::counters::Clock;
+use measureme::{EventId, Profiler, StringId, TIMESTAMP_EPOCH_LENGTH};
+use std::time::Instant;
+
+const EVENTS_PER_THREAD: usize = 1_000_000;
+
+/// The monotonic clock, offset into the second timestamp epoch.
+struct LateClock(Instant);
+
+impl Clock for LateClock {
+    fn now_nanos(&self) -> u64 {
+        TIMESTAMP_EPOCH_LENGTH + self.0.elapsed().as_nanos() as u64
+    }
+}
+
+fn run(
+    name: &str,
+    profiler: &Profiler,
+    num_threads: u32,
+    record: impl Fn(&Profiler, StringId, EventId, u32) + Sync,
+) {
+    let event_kind = profiler.alloc_string("kind");
+    let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread_id in 0..num_threads {
+            let record = &record;
+            scope.spawn(move || record(profiler, event_kind, event_id, thread_id));
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let num_events = EVENTS_PER_THREAD * num_threads as usize;
+    println!(
+        "{:<24} {:>2} threads {:>8.1} ns/event {:>8.1} M events/s",
+        name,
+        num_threads,
+        elapsed.as_nanos() as f64 / num_events as f64,
+        num_events as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let path_stem = std::env::temp_dir().join(format!("event_batch-{}", std::process::id()));
+    let profilers = [
+        ("", Profiler::new(&path_stem).unwrap()),
+        (
+            ", late",
+            Profiler::with_clock(&path_stem, Box::new(LateClock(Instant::now()))).unwrap(),
+        ),
+    ];
+
+    for (suffix, profiler) in &profilers {
+        for &num_threads in &[1, 4] {
+            run(
+                &format!("unbatched{}", suffix),
+                profiler,
+                num_threads,
+                |p, kind, id, thread_id| {
+                    for _ in 0..EVENTS_PER_THREAD {
+                        p.record_instant_event(kind, id, thread_id, None);
+                    }
+                },
+            );
+
+            run(
+                &format!("batched{}", suffix),
+                profiler,
+                num_threads,
+                |p, kind, id, thread_id| {
+                    let mut batch = p.batch();
+                    for _ in 0..EVENTS_PER_THREAD {
+                        batch.record_instant_event(kind, id, thread_id, None);
+                    }
+                },
+            );
+        }
+    }
+
+    drop(profilers);
+    let _ = std::fs::remove_file(path_stem.with_extension(measureme::file_header::FILE_EXTENSION));
+}
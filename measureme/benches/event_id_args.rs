@@ -0,0 +1,74 @@
+//! Measures the cost of creating event ids with six arguments, with the
+//! default `EventIdBuilder` and with one whose inline capacity is large
+//! enough for all arguments.
+//!
+//! Run with `cargo bench -p measureme --bench event_id_args`.
+
+use measureme::{EventId, EventIdBuilder, Profiler, StringId};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const ITERATIONS: usize = 1_000_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn run(name: &str, mut create_event_id: impl FnMut() -> EventId) {
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        std::hint::black_box(create_event_id());
+    }
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    println!(
+        "{:<24} {:>8.1} ns/iter {:>10} allocations",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        allocations
+    );
+}
+
+fn main() {
+    // Writing to a file, unlike to an `InMemorySink`, doesn't allocate when
+    // a page is flushed, so all allocations are made by the builder.
+    let path_stem = std::env::temp_dir().join(format!("event_id_args-{}", std::process::id()));
+    let profiler = Profiler::new(&path_stem).unwrap();
+
+    let label = profiler.alloc_string("label");
+    let args: Vec<StringId> = (0..6)
+        .map(|i| profiler.alloc_string(&format!("arg{}", i)[..]))
+        .collect();
+
+    let default_builder = EventIdBuilder::new(&profiler);
+    run("default (3 inline args)", || {
+        default_builder.from_label_and_args(label, &args)
+    });
+
+    let tuned_builder = EventIdBuilder::<6>::with_inline_args(&profiler);
+    run("6 inline args", || {
+        tuned_builder.from_label_and_args(label, &args)
+    });
+
+    drop(profiler);
+    let _ = std::fs::remove_file(path_stem.with_extension(measureme::file_header::FILE_EXTENSION));
+}
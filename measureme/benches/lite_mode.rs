@@ -0,0 +1,58 @@
+//! Measures the cost per event, and the size of the resulting profile, of
+//! recording interval events whose ids have a category and a unique
+//! argument, with and without `ProfilerOptions::labels_only`.
+//!
+//! Run with `cargo bench -p measureme --bench lite_mode`.
+
+use measureme::counters::{Counter, WallTime};
+use measureme::file_header::FILE_EXTENSION;
+use measureme::{EventIdBuilder, Profiler, ProfilerOptions};
+use std::time::Instant;
+
+const ITERATIONS: u64 = 1_000_000;
+
+fn run(name: &str, labels_only: bool) {
+    let path_stem = std::env::temp_dir().join(format!("lite_mode-{}-{}", name, std::process::id()));
+    let options = ProfilerOptions {
+        labels_only,
+        ..Default::default()
+    };
+    let profiler =
+        Profiler::with_options(&path_stem, Counter::WallTime(WallTime::new()), options).unwrap();
+
+    let builder = EventIdBuilder::new(&profiler);
+    let event_kind = profiler.alloc_string("Query");
+    let label = profiler.alloc_string("typeck");
+    let category = profiler.alloc_string("rustc");
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        // Like well-behaved instrumentation, only format and allocate the
+        // argument if it is going to be recorded.
+        let event_id = if profiler.records_args() {
+            let arg = profiler.alloc_string(&format!("item {}", i)[..]);
+            builder.from_label_category_and_args(label, category, &[arg])
+        } else {
+            builder.from_label(label)
+        };
+        let _guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+    }
+    let elapsed = start.elapsed();
+    drop(profiler);
+
+    let path = path_stem.with_extension(FILE_EXTENSION);
+    let file_size = std::fs::metadata(&path).unwrap().len();
+    let _ = std::fs::remove_file(&path);
+
+    println!(
+        "{:<12} {:>8.1} ns/event {:>8.1} bytes/event",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        file_size as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    run("full", false);
+    run("labels-only", true);
+}
@@ -0,0 +1,543 @@
+//! Appending the events of a new profiling session to an existing profile,
+//! see `Profiler::open`.
+//!
+//! The new session continues each stream of the profile where it has left
+//! off: new pages are appended to the file, the stream headers and the
+//! metadata string of the profile are kept, and new strings get ids after the
+//! ones already in the string table, so that the existing events and strings
+//! don't have to be touched. The only byte that is rewritten is the
+//! `FILE_FLAG_*` byte of the top-level file header, to set
+//! `FILE_FLAG_RESUMED`.
+
+use crate::counters::{Counter, WallTime};
+use crate::file_header::{
+    has_instant_values, segment_file_path, verify_top_level_file_header, FILE_CODEC_NONE,
+    FILE_FLAGS_BYTE_INDEX, FILE_FLAG_NESTING_DEPTH, FILE_FLAG_RESUMED, FILE_HEADER_SIZE,
+};
+use crate::profiler::{top_level_file_header, Profiler, ProfilerOptions};
+use crate::raw_event::{RawEvent, TIMESTAMP_PERIOD};
+use crate::serialization::{
+    decompress_page, decompressed_page_size, is_optional_page_tag, PageTag,
+    SerializationSinkBuilder, PAGE_HEADER_SIZE,
+};
+use crate::stringtable::{METADATA_STRING_ID, STRING_INDEX_ENTRY_SIZE, TERMINATOR};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl Profiler {
+    /// Like `with_options` with the `wall-time` counter, but if there is a
+    /// profile at `path_stem` already, e.g. one written by an earlier run of
+    /// a long-running service that has been restarted, the events are
+    /// appended to it instead of replacing it. The profile then contains the
+    /// events of all sessions, with the timestamps of the new events counted
+    /// from the start of the first session, and its file header has
+    /// `FILE_FLAG_RESUMED` set. The new session doesn't start before the
+    /// last event that has been recorded, even if the system clock has been
+    /// set back in between.
+    ///
+    /// Only the page headers of the profile are read, apart from the pages
+    /// that hold its metadata string and its last events page.
+    ///
+    /// Fails if the existing profile has been written by an incompatible
+    /// version of measureme, with other `options` (apart from
+    /// `deduplicate_strings` and the like, which don't show up in the file),
+    /// with another counter, or split across several files with
+    /// `max_file_bytes`, which isn't supported for appending either. It also
+    /// fails if the profile ends with an incomplete page, e.g. because the
+    /// process writing it has crashed, or if the profile is still being
+    /// written to, which this can't detect: only one profiler may write to a
+    /// profile at a time.
+    pub fn open<P: AsRef<Path>>(
+        path_stem: P,
+        options: ProfilerOptions,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        let path_stem = path_stem.as_ref();
+        let path = segment_file_path(path_stem, 0);
+
+        if !path.exists() {
+            return Self::with_options(path_stem, Counter::WallTime(WallTime::new()), options);
+        }
+
+        if options.max_file_bytes.is_some() {
+            return Err(From::from(
+                "`max_file_bytes` is not supported when appending to a profile",
+            ));
+        }
+
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut file_header = [0u8; FILE_HEADER_SIZE];
+        file.read_exact(&mut file_header)?;
+        let header = verify_top_level_file_header(&file_header, Some(&path))?;
+        let expected_header = top_level_file_header(&options);
+        if header.codec != expected_header.codec {
+            let msg = format!(
+                "Cannot append to `{}`: it has been written with codec {}, not {}",
+                path.display(),
+                header.codec,
+                expected_header.codec
+            );
+            return Err(From::from(msg));
+        }
+        if header.flags & !FILE_FLAG_RESUMED != expected_header.flags {
+            let msg = format!(
+                "Cannot append to `{}`: it has been written with other `ProfilerOptions` \
+                 (flags {:#010b} instead of {:#010b})",
+                path.display(),
+                header.flags & !FILE_FLAG_RESUMED,
+                expected_header.flags
+            );
+            return Err(From::from(msg));
+        }
+        // The new session may record instant events with a value, which
+        // would be mistaken for intervals in such a file.
+        if !has_instant_values(&file_header) {
+            let msg = format!(
+                "Cannot append to `{}`: it has been written by an older version of measureme",
+                path.display()
+            );
+            return Err(From::from(msg));
+        }
+
+        let pages = Pages::read(&mut file, header.codec)?.ok_or_else(|| {
+            format!(
+                "Cannot append to `{}`: it ends with an incomplete page",
+                path.display()
+            )
+        })?;
+        let metadata = pages
+            .metadata(&mut file)
+            .map_err(|msg| format!("Cannot append to `{}`: {}", path.display(), msg))?;
+        if let Some(ref counter) = metadata.counter {
+            if counter.name != WallTime::NAME {
+                let msg = format!(
+                    "Cannot append to `{}`: it has been recorded with another counter than `{}`",
+                    path.display(),
+                    WallTime::NAME
+                );
+                return Err(From::from(msg));
+            }
+        }
+
+        // Timestamps are relative to the time the counter of the first
+        // session read zero, which the monotonic clock of this process
+        // doesn't know about, so the time since then is taken from the system
+        // clock once. That clock may have been set back since, so the time
+        // also has to be past the last event of the profile.
+        let counter_start = metadata.wall_clock_start.unwrap_or(metadata.start_time);
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_nanos(counter_start))
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let last_timestamp = pages.last_timestamp(&mut file, header.flags, elapsed)?;
+        let offset_nanos = std::cmp::max(elapsed, last_timestamp);
+
+        let mut stream_lengths = FxHashMap::default();
+        for (&page_tag, &len) in &pages.stream_lengths {
+            let len = u32::try_from(len).map_err(|_| {
+                format!(
+                    "Cannot append to `{}`: its {:?} stream is too long",
+                    path.display(),
+                    page_tag
+                )
+            })?;
+            stream_lengths.insert(page_tag, len);
+        }
+
+        file.seek(SeekFrom::Start(FILE_FLAGS_BYTE_INDEX as u64))?;
+        file.write_all(&[header.flags | FILE_FLAG_RESUMED])?;
+        file.seek(SeekFrom::End(0))?;
+
+        let sink_builder =
+            SerializationSinkBuilder::new_from_file(file)?.continuing_streams(stream_lengths);
+        let counter = Counter::WallTime(WallTime::continuing_at(offset_nanos));
+        let start_time = UNIX_EPOCH + Duration::from_nanos(metadata.start_time);
+
+        Self::with_sink_builder_since(sink_builder, counter, options, Some(start_time))
+    }
+}
+
+/// The parts of the metadata string of a profile that appending to it needs.
+#[derive(Deserialize)]
+struct ProfileMetadata {
+    start_time: u64,
+    /// Missing in profiles of older versions of measureme, which always
+    /// measured wall time.
+    #[serde(default)]
+    counter: Option<CounterName>,
+    #[serde(default)]
+    wall_clock_start: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CounterName {
+    name: String,
+}
+
+/// A page of a profile: where its contents are in the file, how long they
+/// are, and where they are in the stream of the page, which may differ for
+/// compressed pages.
+#[derive(Clone, Copy)]
+struct Page {
+    contents_pos: u64,
+    size: usize,
+    stream_pos: u64,
+}
+
+/// The pages of a profile, read from their headers.
+struct Pages {
+    codec: u8,
+    by_tag: FxHashMap<PageTag, Vec<Page>>,
+    /// The uncompressed length of each stream, which is where a sink
+    /// continuing the stream starts.
+    stream_lengths: FxHashMap<PageTag, u64>,
+}
+
+impl Pages {
+    /// Reads the page headers of `file`, skipping the contents of the pages
+    /// apart from the size of compressed ones. `None` if the file ends with
+    /// an incomplete or corrupt page.
+    fn read(file: &mut fs::File, codec: u8) -> Result<Option<Pages>, Box<dyn Error + Send + Sync>> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let mut pages = Pages {
+            codec,
+            by_tag: FxHashMap::default(),
+            stream_lengths: FxHashMap::default(),
+        };
+
+        let mut pos = FILE_HEADER_SIZE as u64;
+        while pos < file_len {
+            if pos + PAGE_HEADER_SIZE as u64 > file_len {
+                return Ok(None);
+            }
+
+            let mut page_header = [0u8; PAGE_HEADER_SIZE];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut page_header)?;
+            let size = u32::from_le_bytes(page_header[1..].try_into().unwrap()) as usize;
+            let contents_pos = pos + PAGE_HEADER_SIZE as u64;
+            if size == 0 || contents_pos + size as u64 > file_len {
+                return Ok(None);
+            }
+            pos = contents_pos + size as u64;
+
+            let page_tag = match PageTag::try_from(page_header[0]) {
+                Ok(page_tag) => page_tag,
+                Err(_) if is_optional_page_tag(page_header[0]) => continue,
+                Err(_) => return Ok(None),
+            };
+
+            // Only the events stream is compressed.
+            let len = if page_tag == PageTag::Events && codec != FILE_CODEC_NONE {
+                let mut decompressed_size = [0u8; 4];
+                file.read_exact(&mut decompressed_size)?;
+                decompressed_page_size(&decompressed_size)? as u64
+            } else {
+                size as u64
+            };
+
+            let stream_len = pages.stream_lengths.entry(page_tag).or_insert(0);
+            pages.by_tag.entry(page_tag).or_default().push(Page {
+                contents_pos,
+                size,
+                stream_pos: *stream_len,
+            });
+            *stream_len += len;
+        }
+
+        Ok(Some(pages))
+    }
+
+    fn pages(&self, page_tag: PageTag) -> &[Page] {
+        self.by_tag.get(&page_tag).map_or(&[], |pages| &pages[..])
+    }
+
+    /// Reads the metadata string of the profile, from the pages that hold
+    /// its string index entry and its bytes.
+    fn metadata(&self, file: &mut fs::File) -> Result<ProfileMetadata, String> {
+        let io_error = |e: std::io::Error| e.to_string();
+
+        // Like readers, take the last entry for the metadata string. Each
+        // entry is written to a single page.
+        let mut metadata_addr = None;
+        for page in self.pages(PageTag::StringIndex).iter().rev() {
+            let contents = read_contents(file, page).map_err(io_error)?;
+            let entries_start =
+                entry_start(page.stream_pos, FILE_HEADER_SIZE, STRING_INDEX_ENTRY_SIZE);
+            metadata_addr = contents
+                .get(entries_start..)
+                .unwrap_or_default()
+                .chunks_exact(STRING_INDEX_ENTRY_SIZE)
+                .rev()
+                .find(|entry| {
+                    u32::from_le_bytes(entry[0..4].try_into().unwrap()) == METADATA_STRING_ID
+                })
+                .map(|entry| u32::from_le_bytes(entry[4..8].try_into().unwrap()) as u64);
+            if metadata_addr.is_some() {
+                break;
+            }
+        }
+        let metadata_addr = metadata_addr.ok_or("it has no metadata string")?;
+
+        // The string may continue on the following pages.
+        let mut metadata = Vec::new();
+        for page in self.pages(PageTag::StringData) {
+            if page.stream_pos + page.size as u64 <= metadata_addr {
+                continue;
+            }
+
+            let contents = read_contents(file, page).map_err(io_error)?;
+            let start = metadata_addr.saturating_sub(page.stream_pos) as usize;
+            match contents[start..].iter().position(|&b| b == TERMINATOR) {
+                Some(end) => {
+                    metadata.extend_from_slice(&contents[start..start + end]);
+                    return serde_json::from_slice(&metadata)
+                        .map_err(|e| format!("its metadata string is invalid: {}", e));
+                }
+                None => metadata.extend_from_slice(&contents[start..]),
+            }
+        }
+
+        Err(String::from("its metadata string is invalid"))
+    }
+
+    /// The latest timestamp of the events of the last events page, which
+    /// `now`, the approximate time since the start of the profile, tells the
+    /// timestamp period of. 0 if there are no events.
+    fn last_timestamp(
+        &self,
+        file: &mut fs::File,
+        file_flags: u8,
+        now: u64,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        const RAW_EVENT_SIZE: usize = std::mem::size_of::<RawEvent>();
+
+        let page = match self.pages(PageTag::Events).last() {
+            Some(page) => page,
+            None => return Ok(0),
+        };
+        let mut contents = read_contents(file, page)?;
+        if self.codec != FILE_CODEC_NONE {
+            contents = decompress_page(self.codec, &contents)?;
+        }
+
+        // Events may be split across pages, so the page may start in the
+        // middle of one.
+        let events_start = entry_start(page.stream_pos, FILE_HEADER_SIZE, RAW_EVENT_SIZE);
+        let last_timestamp = contents
+            .get(events_start..)
+            .unwrap_or_default()
+            .chunks_exact(RAW_EVENT_SIZE)
+            .filter_map(|bytes| {
+                let mut raw_event = RawEvent::deserialize(bytes);
+                if raw_event.is_integer() {
+                    return None;
+                }
+
+                let timestamp = if raw_event.is_instant() {
+                    raw_event.instant_timestamp()
+                } else {
+                    if file_flags & FILE_FLAG_NESTING_DEPTH != 0 {
+                        raw_event.take_nesting_depth();
+                    }
+                    raw_event.end_value()
+                };
+                Some(unwrap_timestamp(timestamp, now))
+            })
+            .max();
+
+        Ok(last_timestamp.unwrap_or(0))
+    }
+}
+
+/// The offset within the contents of a page at `stream_pos` of the first
+/// entry of a stream whose entries are `entry_size` bytes long and start
+/// after `header_size` bytes.
+fn entry_start(stream_pos: u64, header_size: usize, entry_size: usize) -> usize {
+    let header_size = header_size as u64;
+    let entry_size = entry_size as u64;
+    if stream_pos <= header_size {
+        return (header_size - stream_pos) as usize;
+    }
+
+    ((entry_size - (stream_pos - header_size) % entry_size) % entry_size) as usize
+}
+
+/// The timestamp closest to `now` that is stored as `timestamp`, i.e. is
+/// the same modulo `TIMESTAMP_PERIOD`.
+fn unwrap_timestamp(timestamp: u64, now: u64) -> u64 {
+    let candidate = now - now % TIMESTAMP_PERIOD + timestamp % TIMESTAMP_PERIOD;
+    [
+        candidate.checked_sub(TIMESTAMP_PERIOD),
+        Some(candidate),
+        candidate.checked_add(TIMESTAMP_PERIOD),
+    ]
+    .iter()
+    .flatten()
+    .copied()
+    .min_by_key(|candidate| candidate.abs_diff(now))
+    .unwrap()
+}
+
+fn read_contents(file: &mut fs::File, page: &Page) -> std::io::Result<Vec<u8>> {
+    let mut contents = vec![0u8; page.size];
+    file.seek(SeekFrom::Start(page.contents_pos))?;
+    file.read_exact(&mut contents)?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_id::EventId;
+    use crate::file_header::{
+        CURRENT_FILE_FORMAT_MINOR_VERSION, FILE_MINOR_VERSION_BYTE_INDEX,
+        FIRST_MINOR_VERSION_WITH_INSTANT_VALUES,
+    };
+    use crate::serialization::split_streams;
+
+    fn fresh_path_stem(name: &str) -> std::path::PathBuf {
+        let path_stem = Path::new("test-tmp").join("append").join(name);
+        let _ = fs::remove_file(segment_file_path(&path_stem, 0));
+        path_stem
+    }
+
+    #[test]
+    fn incompatible_profiles_are_not_appended_to() {
+        let path_stem = fresh_path_stem("incompatible");
+        let profiler = Profiler::open(&path_stem, ProfilerOptions::default()).unwrap();
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.record_instant_event(profiler.alloc_string("kind"), event_id, 0, None);
+        drop(profiler);
+
+        let error = |options| {
+            Profiler::open(&path_stem, options)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(error(ProfilerOptions {
+            record_wall_time: true,
+            ..Default::default()
+        })
+        .contains("other `ProfilerOptions`"));
+        assert!(error(ProfilerOptions {
+            max_file_bytes: Some(1 << 20),
+            ..Default::default()
+        })
+        .contains("`max_file_bytes`"));
+
+        // A newer format version.
+        let path = segment_file_path(&path_stem, 0);
+        let mut data = fs::read(&path).unwrap();
+        data[4] += 1;
+        fs::write(&path, &data).unwrap();
+        assert!(error(ProfilerOptions::default()).contains("version"));
+
+        // An older minor version, without instant events with a value.
+        data[4] -= 1;
+        data[FILE_MINOR_VERSION_BYTE_INDEX] = FIRST_MINOR_VERSION_WITH_INSTANT_VALUES - 1;
+        fs::write(&path, &data).unwrap();
+        assert!(error(ProfilerOptions::default()).contains("older version of measureme"));
+
+        // A truncated page.
+        data[FILE_MINOR_VERSION_BYTE_INDEX] = CURRENT_FILE_FORMAT_MINOR_VERSION;
+        data.pop();
+        fs::write(&path, &data).unwrap();
+        assert!(error(ProfilerOptions::default()).contains("incomplete page"));
+
+        let path_stem = fresh_path_stem("other_counter");
+        let clock = Counter::Clock(Box::new(WallTime::new()));
+        drop(Profiler::with_options(&path_stem, clock, ProfilerOptions::default()).unwrap());
+        assert!(Profiler::open(&path_stem, ProfilerOptions::default())
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("another counter"));
+    }
+
+    #[test]
+    fn resumed_sessions_start_after_the_last_event() {
+        let path_stem = fresh_path_stem("clock_set_back");
+        let path = segment_file_path(&path_stem, 0);
+        let profiler = Profiler::open(&path_stem, ProfilerOptions::default()).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        drop(profiler);
+
+        let mut file = fs::File::open(&path).unwrap();
+        let pages = Pages::read(&mut file, FILE_CODEC_NONE).unwrap().unwrap();
+        let first_metadata = pages.metadata(&mut file).unwrap();
+        drop(file);
+
+        // Move the start of the profile into the future, as if the system
+        // clock had been set back since.
+        let mut data = fs::read(&path).unwrap();
+        let key = br#""wall_clock_start":"#;
+        let pos = data.windows(key.len()).position(|w| w == key).unwrap() + key.len();
+        assert!(data[pos].is_ascii_digit() && data[pos] < b'9');
+        data[pos] = b'9';
+        fs::write(&path, &data).unwrap();
+
+        let profiler = Profiler::open(&path_stem, ProfilerOptions::default()).unwrap();
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        drop(profiler);
+
+        let data = fs::read(&path).unwrap();
+        let mut streams = split_streams(&data[FILE_HEADER_SIZE..]);
+        let events: Vec<_> = streams.remove(&PageTag::Events).unwrap()[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[1].instant_timestamp() >= events[0].end_value());
+
+        // The second session has recorded its own metadata string, with the
+        // start time of the first one.
+        let metadata_entries = streams.remove(&PageTag::StringIndex).unwrap()[FILE_HEADER_SIZE..]
+            .chunks_exact(STRING_INDEX_ENTRY_SIZE)
+            .filter(|entry| {
+                u32::from_le_bytes(entry[0..4].try_into().unwrap()) == METADATA_STRING_ID
+            })
+            .count();
+        assert_eq!(metadata_entries, 2);
+
+        let mut file = fs::File::open(&path).unwrap();
+        let pages = Pages::read(&mut file, FILE_CODEC_NONE).unwrap().unwrap();
+        let metadata = pages.metadata(&mut file).unwrap();
+        assert_eq!(metadata.start_time, first_metadata.start_time);
+        assert_eq!(metadata.counter.unwrap().name, WallTime::NAME);
+    }
+
+    #[test]
+    fn entries_split_across_pages() {
+        // The stream header takes up the first 8 bytes.
+        assert_eq!(entry_start(0, 8, 24), 8);
+        assert_eq!(entry_start(8, 8, 24), 0);
+        assert_eq!(entry_start(32, 8, 24), 0);
+        assert_eq!(entry_start(40, 8, 24), 16);
+    }
+
+    #[test]
+    fn timestamps_are_unwrapped_to_the_closest_period() {
+        assert_eq!(unwrap_timestamp(5, 10), 5);
+        assert_eq!(
+            unwrap_timestamp(TIMESTAMP_PERIOD - 5, 10),
+            TIMESTAMP_PERIOD - 5
+        );
+        assert_eq!(
+            unwrap_timestamp(5, TIMESTAMP_PERIOD - 10),
+            TIMESTAMP_PERIOD + 5
+        );
+        assert_eq!(
+            unwrap_timestamp(TIMESTAMP_PERIOD - 5, TIMESTAMP_PERIOD + 10),
+            TIMESTAMP_PERIOD - 5
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! The events that are held back for `ProfilerOptions::stable_event_order`
+//! until the profiler is dropped.
+
+use crate::raw_event::RawEvent;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+/// The events that a thread has recorded, each with the counter value that
+/// it is ordered by.
+type ThreadBuffer = Arc<Mutex<Vec<(u64, RawEvent)>>>;
+
+/// The number of profilers whose buffers a thread remembers. A thread that
+/// records events on more profilers gets a new buffer for the ones it has
+/// forgotten.
+const MAX_PROFILERS: usize = 8;
+
+thread_local! {
+    /// The buffers of the calling thread, by the id of their profiler.
+    static THREAD_BUFFERS: RefCell<Vec<(u64, ThreadBuffer)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The events recorded on a profiler, in a buffer per thread, so that
+/// recording an event only takes the lock of the buffer of the calling
+/// thread, which no other thread contends for.
+pub(crate) struct BufferedEvents {
+    profiler_id: u64,
+    /// The buffers of all threads, in the order in which they have been
+    /// created.
+    buffers: Mutex<Vec<ThreadBuffer>>,
+}
+
+impl BufferedEvents {
+    pub(crate) fn new(profiler_id: u64) -> BufferedEvents {
+        BufferedEvents {
+            profiler_id,
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds `raw_events`, which the calling thread has recorded, with the
+    /// counter values they are ordered by. `None` orders an event after the
+    /// one that the thread has recorded before it, e.g. for integer events,
+    /// which don't have a timestamp.
+    pub(crate) fn push(&self, raw_events: impl IntoIterator<Item = (Option<u64>, RawEvent)>) {
+        let mut raw_events = Some(raw_events);
+        let mut push = |buffer: &ThreadBuffer| {
+            let mut buffer = buffer.lock();
+            for (timestamp, raw_event) in raw_events.take().unwrap() {
+                let timestamp = timestamp.unwrap_or_else(|| buffer.last().map_or(0, |&(t, _)| t));
+                buffer.push((timestamp, raw_event));
+            }
+        };
+
+        let pushed = THREAD_BUFFERS.try_with(|buffers| {
+            let mut buffers = match buffers.try_borrow_mut() {
+                Ok(buffers) => buffers,
+                Err(_) => return false,
+            };
+
+            match buffers.iter().find(|(id, _)| *id == self.profiler_id) {
+                Some((_, buffer)) => push(buffer),
+                None => {
+                    if buffers.len() == MAX_PROFILERS {
+                        buffers.remove(0);
+                    }
+                    let buffer = self.new_buffer();
+                    push(&buffer);
+                    buffers.push((self.profiler_id, buffer));
+                }
+            }
+            true
+        });
+
+        // The thread is being torn down.
+        if !pushed.unwrap_or(false) {
+            push(&self.new_buffer());
+        }
+    }
+
+    fn new_buffer(&self) -> ThreadBuffer {
+        let buffer = ThreadBuffer::default();
+        self.buffers.lock().push(buffer.clone());
+        buffer
+    }
+
+    /// Takes the events of all threads, those of each thread in the order in
+    /// which it has recorded them.
+    pub(crate) fn take(&mut self) -> Vec<(u64, RawEvent)> {
+        let buffers = self.buffers.get_mut();
+        let mut raw_events = Vec::with_capacity(buffers.iter().map(|b| b.lock().len()).sum());
+        for buffer in buffers.drain(..) {
+            raw_events.append(&mut buffer.lock());
+        }
+        raw_events
+    }
+}
@@ -0,0 +1,351 @@
+use crate::event_id::EventId;
+use crate::profiler::{DetachedTiming, Profiler};
+use crate::raw_event::{RawEvent, TIMESTAMP_EPOCH_LENGTH};
+use crate::stringtable::StringId;
+
+/// The number of raw events that an `EventBatch` created by `Profiler::batch`
+/// collects before writing them.
+pub const DEFAULT_BATCH_CAPACITY: usize = 1024;
+
+/// The most raw events a single event is recorded as: an interval event with
+/// all of its markers.
+const MAX_RAW_EVENTS_PER_EVENT: usize = 5;
+
+/// Collects the events that a thread records, e.g. in a tight loop, and
+/// writes them to the profile in one contiguous write once the batch is
+/// full, when `flush` is called and when it is dropped, which includes being
+/// dropped while unwinding from a panic. Every write to the event sink takes
+/// the lock that it shares with all other threads, a batch only takes it
+/// once per write instead of once per event.
+///
+/// The events of a batch are written in the order in which they have been
+/// recorded, together with the markers of their interval events. Events that
+/// are recorded directly on the profiler in the meantime, e.g. with a
+/// `TimingGuard`, are written before the events that the batch still holds,
+/// so a thread should either record all of its events through the batch or
+/// `flush` it before recording elsewhere.
+///
+/// Created by `Profiler::batch` and `Profiler::batch_with_capacity`.
+pub struct EventBatch<'a> {
+    profiler: &'a Profiler,
+    raw_events: Vec<RawEvent>,
+    /// The counter values that the events are ordered by, which are only
+    /// needed for `ProfilerOptions::stable_event_order`.
+    timestamps: Vec<Option<u64>>,
+    capacity: usize,
+    /// The thread of the event recorded last and the timestamp epoch that the
+    /// profiler is known to have recorded a marker for, so that the events
+    /// of the same epoch don't have to ask the profiler, which takes a lock
+    /// for counters that are read per thread.
+    known_epoch: Option<(u32, u64)>,
+}
+
+impl Profiler {
+    /// Creates a batch that collects up to `DEFAULT_BATCH_CAPACITY` raw
+    /// events before writing them, see `EventBatch`.
+    pub fn batch(&self) -> EventBatch<'_> {
+        self.batch_with_capacity(DEFAULT_BATCH_CAPACITY)
+    }
+
+    /// Like `batch`, but the batch collects up to `capacity` raw events.
+    /// Every event takes up one of them, and every marker of an interval
+    /// event, e.g. for its wall time, another one. Capacities too small for
+    /// an interval event with all of its markers are raised to fit one.
+    pub fn batch_with_capacity(&self, capacity: usize) -> EventBatch<'_> {
+        let capacity = capacity.max(MAX_RAW_EVENTS_PER_EVENT);
+
+        // Disabled profilers never record anything, so the batch doesn't
+        // need room for events either.
+        let raw_events = if self.is_enabled() {
+            Vec::with_capacity(capacity)
+        } else {
+            Vec::new()
+        };
+
+        EventBatch {
+            profiler: self,
+            raw_events,
+            timestamps: Vec::new(),
+            capacity,
+            known_epoch: None,
+        }
+    }
+}
+
+impl<'a> EventBatch<'a> {
+    /// The profiler that the batch writes to.
+    #[inline]
+    pub fn profiler(&self) -> &'a Profiler {
+        self.profiler
+    }
+
+    /// The number of raw events that haven't been written yet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.raw_events.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.raw_events.is_empty()
+    }
+
+    /// See `Profiler::record_instant_event`.
+    #[inline]
+    pub fn record_instant_event(
+        &mut self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: Option<u64>,
+    ) {
+        if !self.profiler.is_enabled() || !self.profiler.records_event_kind(event_kind) {
+            return;
+        }
+
+        let count = self.profiler.read_counter();
+        self.check_timestamp_epoch(count, thread_id);
+
+        let raw_event = match value {
+            None => RawEvent::new_wrapping_instant(event_kind, event_id, thread_id, count),
+            Some(value) => RawEvent::new_wrapping_instant_with_value(
+                event_kind, event_id, thread_id, count, value,
+            ),
+        };
+        self.push(&[raw_event], Some(count));
+    }
+
+    /// See `Profiler::record_integer_event`.
+    #[inline]
+    pub fn record_integer_event(
+        &mut self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: u64,
+    ) {
+        if !self.profiler.is_enabled() || !self.profiler.records_event_kind(event_kind) {
+            return;
+        }
+
+        let raw_event = RawEvent::new_integer(event_kind, event_id, thread_id, value);
+        self.push(&[raw_event], None);
+    }
+
+    /// Records the "end" of an interval event that has been started with
+    /// `Profiler::start_recording_interval_event_detached` (or one of its
+    /// variants) on the profiler of the batch, like
+    /// `Profiler::finish_recording_interval_event` does.
+    pub fn finish_recording_interval_event(&mut self, timing: DetachedTiming) {
+        if !self.profiler.is_enabled() {
+            return;
+        }
+
+        if let Some(end) = timing.end(self.profiler) {
+            self.check_timestamp_epoch(end.end_count, timing.thread_id);
+            self.push(end.raw_events(), Some(end.end_count));
+        }
+    }
+
+    /// Writes the events collected so far.
+    pub fn flush(&mut self) {
+        if self.raw_events.is_empty() {
+            return;
+        }
+
+        self.profiler
+            .record_batched_events(&self.raw_events, &self.timestamps);
+        self.raw_events.clear();
+        self.timestamps.clear();
+    }
+
+    #[inline]
+    fn push(&mut self, raw_events: &[RawEvent], timestamp: Option<u64>) {
+        // The raw events of an interval event are written together.
+        if self.raw_events.len() + raw_events.len() > self.capacity {
+            self.flush();
+        }
+
+        if self.profiler.has_stable_event_order() {
+            self.timestamps.extend(raw_events.iter().map(|_| timestamp));
+        }
+
+        self.raw_events.extend_from_slice(raw_events);
+    }
+
+    #[inline]
+    fn check_timestamp_epoch(&mut self, count: u64, thread_id: u32) {
+        if count < TIMESTAMP_EPOCH_LENGTH {
+            return;
+        }
+
+        let epoch = count / TIMESTAMP_EPOCH_LENGTH;
+        match self.known_epoch {
+            Some((known_thread_id, known_epoch))
+                if known_thread_id == thread_id && epoch <= known_epoch => {}
+            _ => {
+                // The profiler writes epoch markers right away, so the events
+                // that belong to the previous epoch have to be written before
+                // them.
+                if epoch > self.profiler.latest_timestamp_epoch(thread_id) {
+                    self.flush();
+                    self.profiler.check_timestamp_epoch(count, thread_id);
+                }
+                self.known_epoch = Some((thread_id, epoch));
+            }
+        }
+    }
+}
+
+impl<'a> Drop for EventBatch<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counters::{Counter, WallTime};
+    use crate::file_header::{segment_file_path, FILE_HEADER_SIZE};
+    use crate::profiler::ProfilerOptions;
+    use crate::serialization::{split_streams, PageTag};
+    use crate::testing_clocks::ManualClock;
+    use std::fs;
+    use std::path::Path;
+
+    fn read_raw_events(path_stem: &Path) -> Vec<RawEvent> {
+        let data = fs::read(segment_file_path(path_stem, 0)).unwrap();
+        let event_data = split_streams(&data[FILE_HEADER_SIZE..])
+            .remove(&PageTag::Events)
+            .unwrap();
+
+        event_data[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect()
+    }
+
+    #[test]
+    fn events_are_written_once_the_batch_is_full() {
+        let path_stem = Path::new("test-tmp").join("event_batch").join("full");
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(WallTime::new()),
+            ProfilerOptions {
+                record_wall_time: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = |label: &str| EventId::from_label(profiler.alloc_string(label));
+
+        let mut batch = profiler.batch_with_capacity(5);
+        batch.record_integer_event(event_kind, event_id("first"), 0, 1);
+        batch.record_instant_event(event_kind, event_id("second"), 0, None);
+        let timing =
+            profiler.start_recording_interval_event_detached(event_kind, event_id("third"), 0);
+        // The interval event and its wall-time marker.
+        batch.finish_recording_interval_event(timing);
+        assert_eq!(batch.len(), 4);
+        assert_eq!(profiler.stats().events, 0);
+
+        // There is no room for the next interval event and its marker, so
+        // the batch is written first.
+        let timing =
+            profiler.start_recording_interval_event_detached(event_kind, event_id("fourth"), 0);
+        batch.finish_recording_interval_event(timing);
+        batch.record_instant_event(event_kind, event_id("fifth"), 0, Some(42));
+        assert_eq!(profiler.stats().events, 4);
+        assert_eq!(batch.len(), 3);
+
+        drop(batch);
+        assert_eq!(profiler.stats().events, 7);
+        drop(profiler);
+
+        let raw_events = read_raw_events(&path_stem);
+        let kinds: Vec<_> = raw_events
+            .iter()
+            .map(|e| (e.is_integer(), e.is_instant(), e.is_interval()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (true, false, false),
+                (false, true, false),
+                (false, false, true),
+                (true, false, false),
+                (false, false, true),
+                (true, false, false),
+                (false, true, false),
+            ]
+        );
+        assert_eq!(raw_events[0].value(), 1);
+        assert_eq!(raw_events[6].instant_payload(), Some(42));
+        assert_eq!(raw_events[2].event_id, raw_events[3].event_id);
+        assert_eq!(raw_events[4].event_id, raw_events[5].event_id);
+    }
+
+    #[test]
+    fn panic_while_batching_writes_the_batch() {
+        let path_stem = Path::new("test-tmp").join("event_batch").join("panic");
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut batch = profiler.batch();
+            for i in 0..10 {
+                batch.record_integer_event(event_kind, event_id, 0, i);
+            }
+            panic!("while batching");
+        }));
+        assert!(result.is_err());
+        assert_eq!(profiler.stats().events, 10);
+        drop(profiler);
+
+        let values: Vec<_> = read_raw_events(&path_stem)
+            .iter()
+            .map(|e| e.value())
+            .collect();
+        assert_eq!(values, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn events_are_written_before_the_next_epoch_marker() {
+        let path_stem = Path::new("test-tmp").join("event_batch").join("epochs");
+        let clock = ManualClock::default();
+        let profiler = Profiler::with_clock(&path_stem, Box::new(clock.clone())).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        let mut batch = profiler.batch();
+        for &(epoch, thread_id) in &[(1, 0), (1, 0), (1, 1), (2, 0), (2, 0)] {
+            clock.set(epoch * TIMESTAMP_EPOCH_LENGTH + 5);
+            batch.record_instant_event(event_kind, event_id, thread_id, None);
+        }
+        drop(batch);
+        drop(profiler);
+
+        // The epoch is the same for all threads with the clock, so there is
+        // one marker per epoch.
+        let summary: Vec<_> = read_raw_events(&path_stem)
+            .iter()
+            .map(|e| if e.is_integer() { e.value() } else { u64::MAX })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![1, u64::MAX, u64::MAX, u64::MAX, 2, u64::MAX, u64::MAX]
+        );
+    }
+
+    #[test]
+    fn disabled_profiler_batches_nothing() {
+        let profiler = Profiler::disabled();
+        let mut batch = profiler.batch();
+        batch.record_instant_event(StringId::INVALID, EventId::INVALID, 0, None);
+        batch.record_integer_event(StringId::INVALID, EventId::INVALID, 0, 1);
+        assert!(batch.is_empty());
+    }
+}
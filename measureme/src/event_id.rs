@@ -1,23 +1,75 @@
 use smallvec::SmallVec;
+use std::borrow::Cow;
 
+use crate::stringtable::{SerializableString, TERMINATOR};
 use crate::{Profiler, StringComponent, StringId};
 
 /// Event IDs are strings conforming to the following grammar:
 ///
 /// ```ignore
-///   <event_id> = <label> {<argument>}
+///   <event_id> = <label> [<category>] {<argument>}
 ///   <label> = <text>
-///   <argument> = '\x1E' <text>
-///   <text> = regex([[[:^cntrl:]][[:space:]]]+) // Anything but ASCII control characters except for whitespace.
+///   <category> = '\x1E' '\x12' <text>
+///   <argument> = '\x1E' (<text> | <integer_argument> | <backtrace_frame> | <omitted_args>)
+///   <integer_argument> = '\x13' regex([0-9]+) // A u64 in decimal notation.
+///   <backtrace_frame> = '\x14' <text> // The name of a function on the stack.
+///   <omitted_args> = '\x15' regex([0-9]+) // The number of arguments left out, always last.
+///   <text> = {regex([[:^cntrl:][:space:]]) | <escaped_byte>}+ // Anything but ASCII control characters except for whitespace.
+///   <escaped_byte> = '\x1B' regex([[:cntrl:]])
 ///  ```
 ///
-/// This means there's always a "label", followed by an optional list of
-/// arguments. Future versions may support other optional suffixes (with a tag
-/// other than '\x11' after the '\x1E' separator), such as a "category".
+/// This means there's always a "label", followed by an optional "category"
+/// and an optional list of arguments. Future versions may support other
+/// optional suffixes (with a tag other than '\x11', '\x12', '\x13', '\x14'
+/// or '\x15' after the '\x1E' separator).
+///
+/// The grammar is defined on bytes: `<text>` may contain bytes that aren't
+/// valid UTF-8, see `Profiler::alloc_string_bytes`.
+///
+/// Control characters can be part of a `<text>` by prefixing them with
+/// `ESCAPE_BYTE`, see `escape_text` and `escape_bytes`. This way labels and
+/// arguments that contain e.g. the separator are read back as they have been
+/// recorded.
 
 /// The byte used to separate arguments from the label and each other.
 pub const SEPARATOR_BYTE: &str = "\x1E";
 
+/// The tag byte following a `SEPARATOR_BYTE` that marks the suffix as the
+/// event's category instead of a regular argument.
+pub const CATEGORY_TAG_BYTE: &str = "\x12";
+
+/// The tag byte following a `SEPARATOR_BYTE` that marks the argument as an
+/// integer stored inline in decimal notation.
+pub const INTEGER_ARG_TAG_BYTE: &str = "\x13";
+
+/// The tag byte following a `SEPARATOR_BYTE` that marks the argument as a
+/// frame of the stack captured with `Profiler::record_event_with_backtrace`.
+pub const BACKTRACE_FRAME_TAG_BYTE: &str = "\x14";
+
+/// The tag byte following a `SEPARATOR_BYTE` that marks the end of an event
+/// id whose arguments have been cut off at `EventIdBuilder::with_max_args`,
+/// along with the number of arguments that have been left out.
+pub const OMITTED_ARGS_TAG_BYTE: &str = "\x15";
+
+/// `SEPARATOR_BYTE` followed by `OMITTED_ARGS_TAG_BYTE`, as a single
+/// component.
+const OMITTED_ARGS_PREFIX: &str = "\x1E\x15";
+
+/// The byte that makes the control character following it part of a
+/// `<text>`, instead of e.g. separating arguments.
+pub const ESCAPE_BYTE: &str = "\x1B";
+
+/// The maximum number of decimal digits needed to represent a `u64`.
+const MAX_U64_DECIMAL_DIGITS: usize = 20;
+
+/// The number of arguments that an [`EventIdBuilder`] can add to an event id
+/// without allocating, unless configured otherwise.
+pub const DEFAULT_INLINE_ARGS: usize = 3;
+
+/// The number of arguments that an [`EventIdBuilder`] adds to an event id at
+/// most, unless configured otherwise with [`EventIdBuilder::with_max_args`].
+pub const DEFAULT_MAX_ARGS: usize = 256;
+
 /// An `EventId` is a `StringId` with the additional guarantee that the
 /// corresponding string conforms to the event_id grammar.
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
@@ -55,13 +107,115 @@ impl EventId {
     }
 }
 
-pub struct EventIdBuilder<'p> {
+fn needs_escaping(byte: u8) -> bool {
+    byte.is_ascii_control() && !byte.is_ascii_whitespace()
+}
+
+/// Returns `text` with `ESCAPE_BYTE` inserted before every ASCII control
+/// character other than whitespace, which includes `SEPARATOR_BYTE`, the tag
+/// bytes and `ESCAPE_BYTE` itself. The result can be used as the label,
+/// category or an argument of an event id without changing its structure.
+pub fn escape_text(text: &str) -> Cow<'_, str> {
+    match escape_bytes(text.as_bytes()) {
+        Cow::Borrowed(_) => Cow::Borrowed(text),
+        // Only ASCII bytes are escaped, which are never part of a multi-byte
+        // character.
+        Cow::Owned(escaped) => Cow::Owned(String::from_utf8(escaped).unwrap()),
+    }
+}
+
+/// Like `escape_text`, but for the bytes of a string that may not be valid
+/// UTF-8, see `Profiler::alloc_string_bytes`.
+pub fn escape_bytes(bytes: &[u8]) -> Cow<'_, [u8]> {
+    if !bytes.iter().copied().any(needs_escaping) {
+        return Cow::Borrowed(bytes);
+    }
+
+    let mut escaped = Vec::with_capacity(bytes.len() + 2);
+    for &byte in bytes {
+        if needs_escaping(byte) {
+            escaped.extend_from_slice(ESCAPE_BYTE.as_bytes());
+        }
+        escaped.push(byte);
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Creates event ids that conform to the event_id grammar.
+///
+/// `from_label_and_args` and `from_label_category_and_args` collect the
+/// components of the event id on the stack for up to `INLINE_ARGS` arguments
+/// and on the heap beyond that. Callers that routinely pass more arguments can
+/// avoid the allocation by creating the builder with a larger capacity, e.g.
+/// `EventIdBuilder::<6>::with_inline_args(&profiler)`.
+///
+/// Both keep at most `max_args` arguments (see `with_max_args`), so that
+/// instrumentation that passes far too many arguments by mistake doesn't
+/// blow up the size of the profile. The arguments beyond that are replaced by
+/// an `<omitted_args>` marker with their number.
+///
+/// Once the profiler's string budget is exhausted (see
+/// `ProfilerOptions::max_string_bytes`), all methods that take arguments
+/// leave them out, and `from_label_category_and_args` keeps only the
+/// category. With `ProfilerOptions::labels_only`, all methods return just the
+/// label, without allocating anything.
+///
+/// The methods that take `StringId`s put the strings into the event id as
+/// they are, so a separator or tag byte in one of them changes the structure
+/// of the event id. The strings should thus come from `alloc_text` and
+/// `alloc_arg`, or the `from_text_*` methods that allocate them should be
+/// used instead.
+pub struct EventIdBuilder<'p, const INLINE_ARGS: usize = DEFAULT_INLINE_ARGS> {
     profiler: &'p Profiler,
+    max_args: usize,
 }
 
 impl<'p> EventIdBuilder<'p> {
     pub fn new(profiler: &Profiler) -> EventIdBuilder<'_> {
-        EventIdBuilder { profiler }
+        EventIdBuilder {
+            profiler,
+            max_args: DEFAULT_MAX_ARGS,
+        }
+    }
+}
+
+impl<'p, const INLINE_ARGS: usize> EventIdBuilder<'p, INLINE_ARGS> {
+    /// Creates a builder that stores the components of up to `INLINE_ARGS`
+    /// arguments on the stack.
+    pub fn with_inline_args(profiler: &'p Profiler) -> EventIdBuilder<'p, INLINE_ARGS> {
+        EventIdBuilder {
+            profiler,
+            max_args: DEFAULT_MAX_ARGS,
+        }
+    }
+
+    /// Keeps at most `max_args` arguments in the event ids created by
+    /// `from_label_and_args` and `from_label_category_and_args`, instead of
+    /// `DEFAULT_MAX_ARGS`.
+    pub fn with_max_args(mut self, max_args: usize) -> EventIdBuilder<'p, INLINE_ARGS> {
+        self.max_args = max_args;
+        self
+    }
+
+    /// Allocates `text` for use as the label or category of the event ids
+    /// created by this builder. Unlike `Profiler::alloc_string`, this escapes
+    /// the separator and the tag bytes (see `escape_text`), so readers get
+    /// back exactly `text` instead of splitting it up.
+    pub fn alloc_text(&self, text: &str) -> StringId {
+        self.profiler.alloc_string(&escape_text(text)[..])
+    }
+
+    /// Like `alloc_text`, for an argument of the event ids created by this
+    /// builder. Returns `StringId::INVALID` without allocating anything if
+    /// the profiler doesn't record arguments (see `Profiler::records_args`),
+    /// since the event ids would leave the argument out anyway.
+    pub fn alloc_arg(&self, text: &str) -> StringId {
+        if !self.profiler.records_args() {
+            return StringId::INVALID;
+        }
+
+        self.alloc_text(text)
     }
 
     #[inline]
@@ -70,7 +224,13 @@ impl<'p> EventIdBuilder<'p> {
         EventId::from_label(label)
     }
 
+    /// `label` and `arg` should come from `alloc_text` and `alloc_arg`, see
+    /// the type documentation.
     pub fn from_label_and_arg(&self, label: StringId, arg: StringId) -> EventId {
+        if !self.profiler.records_args() {
+            return EventId::from_label(label);
+        }
+
         EventId(self.profiler.alloc_string(&[
             // Label
             StringComponent::Ref(label),
@@ -81,17 +241,294 @@ impl<'p> EventIdBuilder<'p> {
         ]))
     }
 
+    /// Creates an event id with a single integer argument. The integer is
+    /// encoded inline into the event id, so unlike `from_label_and_arg` no
+    /// separate string has to be allocated (or formatted) for it.
+    pub fn from_label_and_int_arg(&self, label: StringId, arg: u64) -> EventId {
+        if !self.profiler.records_args() {
+            return EventId::from_label(label);
+        }
+
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+
+        EventId(self.profiler.alloc_string(&[
+            // Label
+            StringComponent::Ref(label),
+            // Seperator and tag for the integer arg
+            StringComponent::Value(SEPARATOR_BYTE),
+            StringComponent::Value(INTEGER_ARG_TAG_BYTE),
+            // The arg itself, in decimal notation
+            StringComponent::Value(format_u64(arg, &mut buffer)),
+        ]))
+    }
+
+    /// `label` and `args` should come from `alloc_text` and `alloc_arg`, see
+    /// the type documentation.
     pub fn from_label_and_args(&self, label: StringId, args: &[StringId]) -> EventId {
-        // Store up to 7 components on the stack: 1 label + 3 arguments + 3 argument separators
-        let mut parts = SmallVec::<[StringComponent<'_>; 7]>::with_capacity(1 + args.len() * 2);
+        if !self.profiler.records_args() {
+            return EventId::from_label(label);
+        }
+
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+
+        EventId(self.profiler.alloc_string(&EventIdWithArgs {
+            prefix: &[StringComponent::Ref(label)],
+            args: arg_components::<INLINE_ARGS>(args, self.max_args, &mut buffer),
+        }))
+    }
+
+    /// Like `from_label_and_arg`, but allocates `label` and `arg` with
+    /// `alloc_text` and `alloc_arg`, so that they are read back unchanged
+    /// whatever bytes they contain.
+    pub fn from_text_and_arg(&self, label: &str, arg: &str) -> EventId {
+        self.from_label_and_arg(self.alloc_text(label), self.alloc_arg(arg))
+    }
+
+    /// Like `from_label_and_args`, but allocates `label` and `args` with
+    /// `alloc_text` and `alloc_arg`, see `from_text_and_arg`.
+    pub fn from_text_and_args(&self, label: &str, args: &[&str]) -> EventId {
+        let args: SmallVec<[StringId; INLINE_ARGS]> =
+            args.iter().map(|arg| self.alloc_arg(arg)).collect();
+        self.from_label_and_args(self.alloc_text(label), &args)
+    }
 
-        parts.push(StringComponent::Ref(label));
+    pub fn from_label_and_category(&self, label: StringId, category: StringId) -> EventId {
+        if self.profiler.records_labels_only() {
+            return EventId::from_label(label);
+        }
+
+        EventId(self.profiler.alloc_string(&[
+            // Label
+            StringComponent::Ref(label),
+            // Seperator and tag for the category
+            StringComponent::Value(SEPARATOR_BYTE),
+            StringComponent::Value(CATEGORY_TAG_BYTE),
+            // Category string id
+            StringComponent::Ref(category),
+        ]))
+    }
+
+    pub fn from_label_category_and_args(
+        &self,
+        label: StringId,
+        category: StringId,
+        args: &[StringId],
+    ) -> EventId {
+        // With `labels_only`, `from_label_and_category` drops the category
+        // as well.
+        if !self.profiler.records_args() {
+            return self.from_label_and_category(label, category);
+        }
+
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+
+        EventId(self.profiler.alloc_string(&EventIdWithArgs {
+            prefix: &[
+                StringComponent::Ref(label),
+                StringComponent::Value(SEPARATOR_BYTE),
+                StringComponent::Value(CATEGORY_TAG_BYTE),
+                StringComponent::Ref(category),
+            ],
+            args: arg_components::<INLINE_ARGS>(args, self.max_args, &mut buffer),
+        }))
+    }
+}
+
+/// The separator and the reference for each of the first `max_args`
+/// arguments, stored inline for up to `INLINE_ARGS` arguments. If there are
+/// more arguments, they are followed by the `<omitted_args>` marker, whose
+/// number is formatted into `buffer`.
+fn arg_components<'a, const INLINE_ARGS: usize>(
+    args: &[StringId],
+    max_args: usize,
+    buffer: &'a mut [u8; MAX_U64_DECIMAL_DIGITS],
+) -> SmallVec<[[StringComponent<'a>; 2]; INLINE_ARGS]> {
+    let kept_args = std::cmp::min(args.len(), max_args);
+
+    let mut components: SmallVec<_> = args[..kept_args]
+        .iter()
+        .map(|&arg| {
+            [
+                StringComponent::Value(SEPARATOR_BYTE),
+                StringComponent::Ref(arg),
+            ]
+        })
+        .collect();
+
+    if kept_args < args.len() {
+        let omitted_args = (args.len() - kept_args) as u64;
+        components.push([
+            StringComponent::Value(OMITTED_ARGS_PREFIX),
+            StringComponent::Value(format_u64(omitted_args, buffer)),
+        ]);
+    }
+
+    components
+}
+
+/// An event id made up of the components in `prefix` followed by the ones in
+/// `args`. Serializing it directly saves copying both into a single slice.
+struct EventIdWithArgs<'a, const INLINE_ARGS: usize> {
+    prefix: &'a [StringComponent<'a>],
+    args: SmallVec<[[StringComponent<'a>; 2]; INLINE_ARGS]>,
+}
+
+impl<'a, const INLINE_ARGS: usize> EventIdWithArgs<'a, INLINE_ARGS> {
+    fn components(&self) -> impl Iterator<Item = &StringComponent<'a>> {
+        self.prefix.iter().chain(self.args.iter().flatten())
+    }
+}
+
+impl<'a, const INLINE_ARGS: usize> SerializableString for EventIdWithArgs<'a, INLINE_ARGS> {
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.components().map(|c| c.serialized_size()).sum::<usize>() + // size of components
+        1 // terminator
+    }
+
+    #[inline]
+    fn serialize(&self, mut bytes: &mut [u8]) {
+        assert!(bytes.len() == self.serialized_size());
+        for component in self.components() {
+            bytes = component.serialize(bytes);
+        }
+
+        // Assert that we used the exact number of bytes we anticipated.
+        assert!(bytes.len() == 1);
+        bytes[0] = TERMINATOR;
+    }
+}
+
+/// Writes the decimal representation of `value` into the end of `buffer`
+/// without going through `std::fmt`, which would require a heap allocation.
+fn format_u64(mut value: u64, buffer: &mut [u8; MAX_U64_DECIMAL_DIGITS]) -> &str {
+    let mut pos = buffer.len();
+
+    loop {
+        pos -= 1;
+        buffer[pos] = b'0' + (value % 10) as u8;
+        value /= 10;
+
+        if value == 0 {
+            break;
+        }
+    }
+
+    // The buffer only contains ASCII digits at this point.
+    std::str::from_utf8(&buffer[pos..]).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_u64_matches_std() {
+        for &value in &[
+            0,
+            1,
+            9,
+            10,
+            99,
+            100,
+            12345,
+            u32::MAX as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ] {
+            let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+            assert_eq!(format_u64(value, &mut buffer), value.to_string());
+        }
+    }
 
-        for arg in args {
-            parts.push(StringComponent::Value(SEPARATOR_BYTE));
-            parts.push(StringComponent::Ref(*arg));
+    #[test]
+    fn escape_text_prefixes_control_characters() {
+        assert_eq!(
+            escape_text("plain text\tand\n"),
+            Cow::Borrowed("plain text\tand\n")
+        );
+        assert_eq!(escape_text("a\x1Eb"), "a\x1B\x1Eb");
+        assert_eq!(escape_text("\x12cat\x13"), "\x1B\x12cat\x1B\x13");
+        assert_eq!(escape_text("\x1B\u{e9}\x00"), "\x1B\x1B\u{e9}\x1B\x00");
+    }
+
+    #[test]
+    fn escape_bytes_keeps_non_utf8_bytes() {
+        assert_eq!(escape_bytes(b"caf\xE9"), Cow::Borrowed(&b"caf\xE9"[..]));
+        assert_eq!(&escape_bytes(b"\xFF\x1E\xFE")[..], b"\xFF\x1B\x1E\xFE");
+    }
+
+    fn serialize(s: &(impl SerializableString + ?Sized)) -> Vec<u8> {
+        let mut bytes = vec![0; s.serialized_size()];
+        s.serialize(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn event_id_with_args_matches_components() {
+        let args: Vec<_> = (0..6).map(|i| StringId::new(200 + i)).collect();
+
+        let mut expected = vec![StringComponent::Ref(StringId::new(100))];
+        for &arg in &args {
+            expected.push(StringComponent::Value(SEPARATOR_BYTE));
+            expected.push(StringComponent::Ref(arg));
         }
 
-        EventId(self.profiler.alloc_string(&parts[..]))
+        // The same bytes regardless of whether the arguments fit inline.
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+        let inline = EventIdWithArgs::<6> {
+            prefix: &[StringComponent::Ref(StringId::new(100))],
+            args: arg_components(&args, DEFAULT_MAX_ARGS, &mut buffer),
+        };
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+        let spilled = EventIdWithArgs::<1> {
+            prefix: &[StringComponent::Ref(StringId::new(100))],
+            args: arg_components(&args, DEFAULT_MAX_ARGS, &mut buffer),
+        };
+
+        assert_eq!(serialize(&inline), serialize(&expected[..]));
+        assert_eq!(serialize(&spilled), serialize(&expected[..]));
+    }
+
+    #[test]
+    fn too_many_args_are_omitted() {
+        let args: Vec<_> = (0..1000).map(|i| StringId::new(200 + i)).collect();
+
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+        let event_id = EventIdWithArgs::<DEFAULT_INLINE_ARGS> {
+            prefix: &[StringComponent::Ref(StringId::new(100))],
+            args: arg_components(&args, DEFAULT_MAX_ARGS, &mut buffer),
+        };
+
+        let mut expected = vec![StringComponent::Ref(StringId::new(100))];
+        for &arg in &args[..DEFAULT_MAX_ARGS] {
+            expected.push(StringComponent::Value(SEPARATOR_BYTE));
+            expected.push(StringComponent::Ref(arg));
+        }
+        expected.push(StringComponent::Value(SEPARATOR_BYTE));
+        expected.push(StringComponent::Value(OMITTED_ARGS_TAG_BYTE));
+        expected.push(StringComponent::Value("744"));
+
+        let bytes = serialize(&event_id);
+        assert_eq!(bytes, serialize(&expected[..]));
+        // The label and each argument take a separator and a string ref.
+        assert!(bytes.len() <= (DEFAULT_MAX_ARGS + 2) * 6);
+        assert!(bytes.ends_with(b"\x1E\x15744\xFF"));
+
+        let mut buffer = [0u8; MAX_U64_DECIMAL_DIGITS];
+        let no_args = EventIdWithArgs::<DEFAULT_INLINE_ARGS> {
+            prefix: &[StringComponent::Ref(StringId::new(100))],
+            args: arg_components(&args, 0, &mut buffer),
+        };
+        assert_eq!(
+            serialize(&no_args),
+            serialize(
+                &[
+                    StringComponent::Ref(StringId::new(100)),
+                    StringComponent::Value(OMITTED_ARGS_PREFIX),
+                    StringComponent::Value("1000"),
+                ][..]
+            )
+        );
     }
 }
@@ -1,34 +1,146 @@
 //! All binary files generated by measureme have a simple file header that
 //! consists of a 4 byte file magic string and a 4 byte little-endian version
 //! number.
+//!
+//! In the top-level file header, the most significant byte of the version
+//! number is a flag byte that specifies the codec used for the pages of the
+//! events stream (see `serialization::Compression`), and the byte below it
+//! holds the `FILE_FLAG_*` bits. Readers that predate these bytes thus see an
+//! unknown file format version for such files and refuse to read them.
+//!
+//! The two bytes below those hold the major and the minor file format
+//! version. A new major version changes the format in ways that older readers
+//! don't understand, so they refuse to read files with a newer major version
+//! and say so. A new minor version only adds optional sections, in pages
+//! whose tags older readers skip (see `serialization::FIRST_OPTIONAL_PAGE_TAG`),
+//! or gives a meaning to event encodings that older writers could produce in
+//! rare cases, which readers then only interpret that way in files of that
+//! minor version (see `FIRST_MINOR_VERSION_WITH_INSTANT_VALUES`). Readers thus
+//! accept files of any minor version of the major version they support, but
+//! only read an optional section from files of the minor version that added
+//! it or a later one (see `FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX`).
 use std::convert::TryInto;
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+/// The current major file format version.
 pub const CURRENT_FILE_FORMAT_VERSION: u32 = 8;
+/// The current minor file format version, see the module documentation.
+pub const CURRENT_FILE_FORMAT_MINOR_VERSION: u8 = 3;
+
+/// The first minor file format version whose files may contain instant events
+/// with a value (see `Profiler::record_instant_event`). In older files, the
+/// interval events whose start and end are both at least
+/// `raw_event::INSTANT_VALUE_FLAG` look like such events, see
+/// `RawEvent::is_interval_in`.
+pub const FIRST_MINOR_VERSION_WITH_INSTANT_VALUES: u8 = 2;
+
+/// The first minor file format version whose files may contain a timestamp
+/// epoch index (see `timestamp_epoch_index`). Readers ignore the pages of the
+/// index in older files and scan the events for the markers instead.
+pub const FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX: u8 = 3;
 
 pub const FILE_MAGIC_TOP_LEVEL: &[u8; 4] = b"MMPD";
 pub const FILE_MAGIC_EVENT_STREAM: &[u8; 4] = b"MMES";
 pub const FILE_MAGIC_STRINGTABLE_DATA: &[u8; 4] = b"MMSD";
 pub const FILE_MAGIC_STRINGTABLE_INDEX: &[u8; 4] = b"MMSI";
+pub const FILE_MAGIC_METADATA: &[u8; 4] = b"MMMD";
+pub const FILE_MAGIC_TRACE_CONTEXT: &[u8; 4] = b"MMTC";
+pub const FILE_MAGIC_TIMESTAMP_EPOCH_INDEX: &[u8; 4] = b"MMEI";
 
 pub const FILE_EXTENSION: &str = "mm_profdata";
 
+/// The events stream is stored as is.
+pub const FILE_CODEC_NONE: u8 = 0;
+/// Each page of the events stream is compressed with zstd.
+pub const FILE_CODEC_ZSTD: u8 = 1;
+
+/// Interval events that were shorter than
+/// `ProfilerOptions::min_duration_nanos` have not been recorded.
+pub const FILE_FLAG_SAMPLED: u8 = 1 << 0;
+/// Interval events store their nesting depth in the upper bits of their
+/// thread id, see `ProfilerOptions::record_nesting_depth`.
+pub const FILE_FLAG_NESTING_DEPTH: u8 = 1 << 1;
+/// Interval events may be preceded by a marker that names their parent, see
+/// `ProfilerOptions::record_explicit_parents`.
+pub const FILE_FLAG_EXPLICIT_PARENTS: u8 = 1 << 2;
+/// Interval events are followed by a marker that holds their wall-clock
+/// duration, see `ProfilerOptions::record_wall_time`.
+pub const FILE_FLAG_WALL_TIME: u8 = 1 << 3;
+/// Interval events may be followed by a marker that refers to their trace
+/// and span ids in the trace context stream, see
+/// `ProfilerOptions::record_trace_context`.
+pub const FILE_FLAG_TRACE_CONTEXT: u8 = 1 << 4;
+/// The profile has been recorded with a byte budget for its strings, see
+/// `ProfilerOptions::max_string_bytes`. This doesn't mean that arguments
+/// have been dropped: that only happened if the budget has been exceeded, to
+/// the events recorded after the `ARGS_DROPPED_EVENT_KIND` marker.
+pub const FILE_FLAG_ARGS_BUDGET: u8 = 1 << 5;
+/// Event ids are only labels, without categories or arguments, see
+/// `ProfilerOptions::labels_only`.
+pub const FILE_FLAG_LABELS_ONLY: u8 = 1 << 6;
+/// Events have been appended to the profile after the profiler that created
+/// it was done, possibly by another process, see `Profiler::open`.
+pub const FILE_FLAG_RESUMED: u8 = 1 << 7;
+
+/// The position of the codec flag byte within the top-level file header.
+pub const FILE_CODEC_BYTE_INDEX: usize = 7;
+/// The position of the `FILE_FLAG_*` byte within the top-level file header.
+pub const FILE_FLAGS_BYTE_INDEX: usize = 6;
+/// The position of the minor file format version within the file header.
+pub const FILE_MINOR_VERSION_BYTE_INDEX: usize = 5;
+
+/// Masks out the codec and flags bytes and the minor version from the version
+/// number stored in the top-level file header, which leaves the major
+/// version.
+pub const FILE_FORMAT_VERSION_MASK: u32 = 0x0000_00FF;
+
 /// The size of the file header in bytes. Note that functions in this module
 /// rely on this size to be `8`.
 pub const FILE_HEADER_SIZE: usize = 8;
 
+/// The information stored in the top-level file header in addition to the
+/// file format version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TopLevelFileHeader {
+    /// One of the `FILE_CODEC_*` constants.
+    pub codec: u8,
+    /// A combination of the `FILE_FLAG_*` constants.
+    pub flags: u8,
+}
+
 pub fn write_file_header(
     s: &mut dyn std::io::Write,
     file_magic: &[u8; 4],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_file_header_impl(s, file_magic, TopLevelFileHeader::default())
+}
+
+/// Like `write_file_header` for the top-level file header, but also sets the
+/// codec and flags bytes.
+pub fn write_top_level_file_header(
+    s: &mut dyn std::io::Write,
+    header: TopLevelFileHeader,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_file_header_impl(s, FILE_MAGIC_TOP_LEVEL, header)
+}
+
+fn write_file_header_impl(
+    s: &mut dyn std::io::Write,
+    file_magic: &[u8; 4],
+    header: TopLevelFileHeader,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // The implementation here relies on FILE_HEADER_SIZE to have the value 8.
     // Let's make sure this assumption cannot be violated without being noticed.
     assert_eq!(FILE_HEADER_SIZE, 8);
 
+    let mut version = CURRENT_FILE_FORMAT_VERSION.to_le_bytes();
+    version[FILE_CODEC_BYTE_INDEX - 4] = header.codec;
+    version[FILE_FLAGS_BYTE_INDEX - 4] = header.flags;
+    version[FILE_MINOR_VERSION_BYTE_INDEX - 4] = CURRENT_FILE_FORMAT_MINOR_VERSION;
+
     s.write_all(file_magic).map_err(Box::new)?;
-    s.write_all(&CURRENT_FILE_FORMAT_VERSION.to_le_bytes())
-        .map_err(Box::new)?;
+    s.write_all(&version).map_err(Box::new)?;
 
     Ok(())
 }
@@ -74,7 +186,9 @@ pub fn verify_file_header(
 
     let file_format_version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
 
-    if file_format_version != CURRENT_FILE_FORMAT_VERSION {
+    // Only the top-level file header has a codec and flags byte, see
+    // `verify_top_level_file_header`.
+    if file_format_version > 0x0000_FFFF {
         let msg = format!(
             "Error reading {} stream in file `{}`: Expected file format version {} but found `{}`",
             stream_tag,
@@ -86,13 +200,112 @@ pub fn verify_file_header(
         return Err(From::from(msg));
     }
 
-    Ok(())
+    // Any minor version can be read, see the module documentation.
+    check_file_format_version(
+        file_format_version & FILE_FORMAT_VERSION_MASK,
+        CURRENT_FILE_FORMAT_VERSION,
+        CURRENT_FILE_FORMAT_VERSION,
+    )
+    .map_err(|msg| {
+        let msg = format!(
+            "Error reading {} stream in file `{}`: {}",
+            stream_tag,
+            diagnostic_file_path.display(),
+            msg
+        );
+        From::from(msg)
+    })
+}
+
+/// Checks that a reader that supports the major file format versions
+/// `oldest_supported` to `newest_supported` can read a file with the major
+/// version `version`. The error tells files that are too new, which need a
+/// newer reader, apart from files that are too old, naming the version of the
+/// file and the versions that are supported.
+pub fn check_file_format_version(
+    version: u32,
+    oldest_supported: u32,
+    newest_supported: u32,
+) -> Result<(), String> {
+    if version > newest_supported {
+        Err(format!(
+            "The file has format version {}, but this version of measureme only supports versions up to {}. Try upgrading your tools to the latest version.",
+            version, newest_supported
+        ))
+    } else if version < oldest_supported {
+        Err(format!(
+            "The file has format version {}, which is older than version {}, the oldest one this version of measureme supports.",
+            version, oldest_supported
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the file with the (verified) top-level file header in `bytes` may
+/// contain instant events with a value, see
+/// `FIRST_MINOR_VERSION_WITH_INSTANT_VALUES`.
+pub fn has_instant_values(bytes: &[u8]) -> bool {
+    bytes[FILE_MINOR_VERSION_BYTE_INDEX] >= FIRST_MINOR_VERSION_WITH_INSTANT_VALUES
+}
+
+/// Whether the timestamp epoch index of the file with the (verified)
+/// top-level file header in `bytes` can be read, see
+/// `FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX`.
+pub fn has_timestamp_epoch_index(bytes: &[u8]) -> bool {
+    bytes[FILE_MINOR_VERSION_BYTE_INDEX] >= FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX
+}
+
+/// Verifies the top-level file header in `bytes`, like `verify_file_header`,
+/// and returns the contents of its codec and flags bytes.
+pub fn verify_top_level_file_header(
+    bytes: &[u8],
+    diagnostic_file_path: Option<&Path>,
+) -> Result<TopLevelFileHeader, Box<dyn Error + Send + Sync>> {
+    if bytes.len() < FILE_HEADER_SIZE {
+        // Let `verify_file_header` produce the error message.
+        verify_file_header(
+            bytes,
+            FILE_MAGIC_TOP_LEVEL,
+            diagnostic_file_path,
+            "top-level",
+        )?;
+    }
+
+    let top_level_header = TopLevelFileHeader {
+        codec: bytes[FILE_CODEC_BYTE_INDEX],
+        flags: bytes[FILE_FLAGS_BYTE_INDEX],
+    };
+
+    let mut header: [u8; FILE_HEADER_SIZE] = bytes[..FILE_HEADER_SIZE].try_into().unwrap();
+    header[FILE_CODEC_BYTE_INDEX] = 0;
+    header[FILE_FLAGS_BYTE_INDEX] = 0;
+    verify_file_header(
+        &header,
+        FILE_MAGIC_TOP_LEVEL,
+        diagnostic_file_path,
+        "top-level",
+    )?;
+
+    Ok(top_level_header)
 }
 
 pub fn strip_file_header(data: &[u8]) -> &[u8] {
     &data[FILE_HEADER_SIZE..]
 }
 
+/// Returns the path of the `segment_index`-th file of a profile that is split
+/// into multiple files because of `ProfilerOptions::max_file_bytes`. The first
+/// segment is at the regular location, `<path_stem>.mm_profdata`, subsequent
+/// ones at `<path_stem>.1.mm_profdata`, `<path_stem>.2.mm_profdata`, etc.
+pub fn segment_file_path(path_stem: &Path, segment_index: u32) -> PathBuf {
+    if segment_index == 0 {
+        path_stem.with_extension(FILE_EXTENSION)
+    } else {
+        path_stem.with_extension(format!("{}.{}", segment_index, FILE_EXTENSION))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,6 +349,102 @@ mod tests {
         assert!(verify_file_header(&data, FILE_MAGIC_STRINGTABLE_INDEX, None, "test").is_err());
     }
 
+    #[test]
+    fn codec_flag_byte() {
+        let header = TopLevelFileHeader {
+            codec: FILE_CODEC_ZSTD,
+            flags: 0,
+        };
+
+        let mut data = Vec::new();
+        write_top_level_file_header(&mut data, header).unwrap();
+
+        // Readers that don't know about codecs must reject the file.
+        assert!(verify_file_header(&data, FILE_MAGIC_TOP_LEVEL, None, "test").is_err());
+
+        assert_eq!(verify_top_level_file_header(&data, None).unwrap(), header);
+    }
+
+    #[test]
+    fn flags_byte() {
+        let header = TopLevelFileHeader {
+            codec: FILE_CODEC_NONE,
+            flags: FILE_FLAG_SAMPLED,
+        };
+
+        let mut data = Vec::new();
+        write_top_level_file_header(&mut data, header).unwrap();
+
+        // Readers that don't know about flags must reject the file.
+        assert!(verify_file_header(&data, FILE_MAGIC_TOP_LEVEL, None, "test").is_err());
+
+        assert_eq!(verify_top_level_file_header(&data, None).unwrap(), header);
+    }
+
+    fn header_with_version(major: u8, minor: u8) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_file_header(&mut data, FILE_MAGIC_EVENT_STREAM).unwrap();
+        data[4] = major;
+        data[FILE_MINOR_VERSION_BYTE_INDEX] = minor;
+        data
+    }
+
+    #[test]
+    fn minor_versions_are_accepted() {
+        let current = CURRENT_FILE_FORMAT_VERSION as u8;
+        let minor = CURRENT_FILE_FORMAT_MINOR_VERSION;
+        for data in [
+            header_with_version(current, minor),
+            header_with_version(current, minor + 1),
+            header_with_version(current, 0xFF),
+        ] {
+            verify_file_header(&data, FILE_MAGIC_EVENT_STREAM, None, "test").unwrap();
+        }
+
+        let mut data = header_with_version(current, minor + 1);
+        data[FILE_FLAGS_BYTE_INDEX] = FILE_FLAG_SAMPLED;
+        assert_eq!(
+            verify_top_level_file_header(&[&FILE_MAGIC_TOP_LEVEL[..], &data[4..]].concat(), None)
+                .unwrap()
+                .flags,
+            FILE_FLAG_SAMPLED
+        );
+    }
+
+    #[test]
+    fn other_major_versions_are_rejected() {
+        let current = CURRENT_FILE_FORMAT_VERSION as u8;
+        let error = |data: &[u8]| {
+            verify_file_header(data, FILE_MAGIC_EVENT_STREAM, None, "test")
+                .unwrap_err()
+                .to_string()
+        };
+
+        let newer = error(&header_with_version(current + 1, 0));
+        assert!(newer.contains(&format!(
+            "The file has format version {}, but this version of measureme only supports versions up to {}.",
+            current + 1,
+            current
+        )));
+        assert!(newer.contains("Try upgrading"));
+
+        let older = error(&header_with_version(current - 1, 0));
+        assert!(older.contains(&format!(
+            "The file has format version {}, which is older than version {}",
+            current - 1,
+            current
+        )));
+
+        assert_eq!(check_file_format_version(7, 7, 8), Ok(()));
+        assert_eq!(check_file_format_version(8, 7, 8), Ok(()));
+        assert!(check_file_format_version(9, 7, 8)
+            .unwrap_err()
+            .contains("up to 8"));
+        assert!(check_file_format_version(6, 7, 8)
+            .unwrap_err()
+            .contains("older than version 7"));
+    }
+
     #[test]
     fn empty_file() {
         let data: [u8; 0] = [];
@@ -0,0 +1,296 @@
+//! A machine-readable description of the format of the files written by
+//! measureme, for tools that read them without this crate, e.g. ones written
+//! in another language: [`SCHEMA`] holds the format version and the sizes,
+//! offsets, magic numbers and tags of the layout, and
+//! [`Schema::to_json`] turns them into JSON.
+//!
+//! The layout itself is described by the modules that implement it:
+//! `file_header` for the file headers, `serialization` for the pages and
+//! streams, `RawEvent` for the events stream and `stringtable` for the string
+//! table. Every value here is one of the constants that the writer uses, so
+//! the schema can't get out of sync with what is actually written.
+
+use crate::file_header::{
+    CURRENT_FILE_FORMAT_MINOR_VERSION, CURRENT_FILE_FORMAT_VERSION, FILE_CODEC_BYTE_INDEX,
+    FILE_CODEC_NONE, FILE_CODEC_ZSTD, FILE_EXTENSION, FILE_FLAGS_BYTE_INDEX, FILE_FLAG_ARGS_BUDGET,
+    FILE_FLAG_EXPLICIT_PARENTS, FILE_FLAG_LABELS_ONLY, FILE_FLAG_NESTING_DEPTH, FILE_FLAG_RESUMED,
+    FILE_FLAG_SAMPLED, FILE_FLAG_TRACE_CONTEXT, FILE_FLAG_WALL_TIME, FILE_FORMAT_VERSION_MASK,
+    FILE_HEADER_SIZE, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_METADATA, FILE_MAGIC_STRINGTABLE_DATA,
+    FILE_MAGIC_STRINGTABLE_INDEX, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX, FILE_MAGIC_TOP_LEVEL,
+    FILE_MAGIC_TRACE_CONTEXT, FILE_MINOR_VERSION_BYTE_INDEX,
+    FIRST_MINOR_VERSION_WITH_INSTANT_VALUES, FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX,
+};
+use crate::raw_event::{
+    RawEvent, INSTANT_MARKER, INSTANT_VALUE_FLAG, INTEGER_MARKER, MAX_SINGLE_VALUE,
+    NESTING_DEPTH_SHIFT, TIMESTAMP_EPOCH_LENGTH, TIMESTAMP_PERIOD,
+};
+use crate::serialization::{
+    PageTag, DEFAULT_PAGE_SIZE, FIRST_OPTIONAL_PAGE_TAG, LAST_OPTIONAL_PAGE_TAG,
+    MAX_CONFIGURABLE_PAGE_SIZE, MIN_CONFIGURABLE_PAGE_SIZE, PAGE_HEADER_SIZE,
+};
+use crate::stringtable::{
+    FIRST_REGULAR_STRING_ID, MAX_USER_VIRTUAL_STRING_ID, METADATA_STRING_ID,
+    STRING_INDEX_ENTRY_SIZE, STRING_REF_ENCODED_SIZE, STRING_REF_TAG, TERMINATOR,
+};
+use crate::trace_context::TRACE_CONTEXT_ENTRY_SIZE;
+use serde::{Serialize, Serializer};
+
+/// The format of the files written by this version of measureme.
+pub const SCHEMA: Schema = Schema {
+    format_version: CURRENT_FILE_FORMAT_VERSION,
+    format_minor_version: CURRENT_FILE_FORMAT_MINOR_VERSION,
+    first_minor_version_with_instant_values: FIRST_MINOR_VERSION_WITH_INSTANT_VALUES,
+    first_minor_version_with_timestamp_epoch_index: FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX,
+    file_extension: FILE_EXTENSION,
+    file_header: FileHeaderSchema {
+        size: FILE_HEADER_SIZE,
+        format_version_mask: FILE_FORMAT_VERSION_MASK,
+        minor_version_byte_index: FILE_MINOR_VERSION_BYTE_INDEX,
+        flags_byte_index: FILE_FLAGS_BYTE_INDEX,
+        codec_byte_index: FILE_CODEC_BYTE_INDEX,
+        magics: &[
+            ("top_level", FILE_MAGIC_TOP_LEVEL),
+            ("event_stream", FILE_MAGIC_EVENT_STREAM),
+            ("string_data", FILE_MAGIC_STRINGTABLE_DATA),
+            ("string_index", FILE_MAGIC_STRINGTABLE_INDEX),
+            ("metadata", FILE_MAGIC_METADATA),
+            ("trace_context", FILE_MAGIC_TRACE_CONTEXT),
+            ("timestamp_epoch_index", FILE_MAGIC_TIMESTAMP_EPOCH_INDEX),
+        ],
+        codecs: &[("none", FILE_CODEC_NONE), ("zstd", FILE_CODEC_ZSTD)],
+        flags: &[
+            ("sampled", FILE_FLAG_SAMPLED),
+            ("nesting_depth", FILE_FLAG_NESTING_DEPTH),
+            ("explicit_parents", FILE_FLAG_EXPLICIT_PARENTS),
+            ("wall_time", FILE_FLAG_WALL_TIME),
+            ("trace_context", FILE_FLAG_TRACE_CONTEXT),
+            ("args_budget", FILE_FLAG_ARGS_BUDGET),
+            ("labels_only", FILE_FLAG_LABELS_ONLY),
+            ("resumed", FILE_FLAG_RESUMED),
+        ],
+    },
+    pages: PageSchema {
+        header_size: PAGE_HEADER_SIZE,
+        default_page_size: DEFAULT_PAGE_SIZE,
+        min_page_size: MIN_CONFIGURABLE_PAGE_SIZE,
+        max_page_size: MAX_CONFIGURABLE_PAGE_SIZE,
+        tags: &[
+            ("events", PageTag::Events as u8),
+            ("string_data", PageTag::StringData as u8),
+            ("string_index", PageTag::StringIndex as u8),
+            ("metadata", PageTag::Metadata as u8),
+            ("trace_context", PageTag::TraceContext as u8),
+            ("timestamp_epoch_index", PageTag::TimestampEpochIndex as u8),
+            ("segment", PageTag::Segment as u8),
+        ],
+        first_optional_tag: FIRST_OPTIONAL_PAGE_TAG,
+        last_optional_tag: LAST_OPTIONAL_PAGE_TAG,
+    },
+    raw_event: RawEventSchema {
+        size: std::mem::size_of::<RawEvent>(),
+        instant_marker: INSTANT_MARKER,
+        integer_marker: INTEGER_MARKER,
+        instant_value_flag: INSTANT_VALUE_FLAG,
+        max_single_value: MAX_SINGLE_VALUE,
+        timestamp_period: TIMESTAMP_PERIOD,
+        timestamp_epoch_length: TIMESTAMP_EPOCH_LENGTH,
+        nesting_depth_shift: NESTING_DEPTH_SHIFT,
+    },
+    string_table: StringTableSchema {
+        index_entry_size: STRING_INDEX_ENTRY_SIZE,
+        terminator: TERMINATOR,
+        string_ref_tag: STRING_REF_TAG,
+        string_ref_encoded_size: STRING_REF_ENCODED_SIZE,
+        max_virtual_string_id: MAX_USER_VIRTUAL_STRING_ID,
+        metadata_string_id: METADATA_STRING_ID,
+        first_regular_string_id: FIRST_REGULAR_STRING_ID,
+    },
+    trace_context_entry_size: TRACE_CONTEXT_ENTRY_SIZE,
+};
+
+/// See [`SCHEMA`]. All sizes and offsets are in bytes, and all integers in
+/// the files are little-endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Schema {
+    /// The major file format version, which readers have to support.
+    pub format_version: u32,
+    /// The minor file format version, see `file_header`.
+    pub format_minor_version: u8,
+    /// Instant events with a value are only decoded as such in files of this
+    /// minor version or a later one.
+    pub first_minor_version_with_instant_values: u8,
+    /// The pages of the timestamp epoch index are only read in files of this
+    /// minor version or a later one.
+    pub first_minor_version_with_timestamp_epoch_index: u8,
+    pub file_extension: &'static str,
+    pub file_header: FileHeaderSchema,
+    pub pages: PageSchema,
+    pub raw_event: RawEventSchema,
+    pub string_table: StringTableSchema,
+    /// The size of an entry in the trace context stream.
+    pub trace_context_entry_size: usize,
+}
+
+/// The header at the start of every file and every stream, see
+/// `file_header`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct FileHeaderSchema {
+    pub size: usize,
+    /// The bits of the `u32` after the magic that hold the major version.
+    pub format_version_mask: u32,
+    pub minor_version_byte_index: usize,
+    /// Only set in the top-level file header.
+    pub flags_byte_index: usize,
+    /// Only set in the top-level file header.
+    pub codec_byte_index: usize,
+    /// The magic at the start of the file and of each stream.
+    #[serde(serialize_with = "magics_to_object")]
+    pub magics: &'static [(&'static str, &'static [u8; 4])],
+    #[serde(serialize_with = "names_to_object")]
+    pub codecs: &'static [(&'static str, u8)],
+    /// The bits of the flags byte.
+    #[serde(serialize_with = "names_to_object")]
+    pub flags: &'static [(&'static str, u8)],
+}
+
+/// The pages that the streams are split into, see `serialization`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct PageSchema {
+    /// The tag byte followed by the size of the page contents as a `u32`.
+    pub header_size: usize,
+    pub default_page_size: usize,
+    pub min_page_size: usize,
+    pub max_page_size: usize,
+    #[serde(serialize_with = "names_to_object")]
+    pub tags: &'static [(&'static str, u8)],
+    /// Pages with a tag from `first_optional_tag` to `last_optional_tag`
+    /// that a reader doesn't know can be skipped.
+    pub first_optional_tag: u8,
+    pub last_optional_tag: u8,
+}
+
+/// The entries of the events stream, see `RawEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct RawEventSchema {
+    pub size: usize,
+    /// The value of payload 2 of instant events without a value.
+    pub instant_marker: u64,
+    /// The value of payload 2 of integer events.
+    pub integer_marker: u64,
+    /// Set in both payloads of instant events with a value.
+    pub instant_value_flag: u64,
+    /// The largest value of a 48 bit payload.
+    pub max_single_value: u64,
+    /// Timestamps are stored modulo this value.
+    pub timestamp_period: u64,
+    pub timestamp_epoch_length: u64,
+    /// How far the nesting depth is shifted into the thread id, if the
+    /// profile has the `nesting_depth` flag.
+    pub nesting_depth_shift: u32,
+}
+
+/// The string data and string index streams, see `stringtable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct StringTableSchema {
+    /// The string id and the address of the string, each as a `u32`.
+    pub index_entry_size: usize,
+    pub terminator: u8,
+    pub string_ref_tag: u8,
+    pub string_ref_encoded_size: usize,
+    pub max_virtual_string_id: u32,
+    pub metadata_string_id: u32,
+    /// The id of the string at address 0 of the string data; the ids of all
+    /// other strings are their address plus this.
+    pub first_regular_string_id: u32,
+}
+
+impl Schema {
+    /// The schema as a JSON object, with the same field names as the structs
+    /// and the lists of names and values as objects.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Serializes a list of names and values as an object.
+fn names_to_object<S: Serializer>(
+    entries: &&[(&str, u8)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(entries.iter().copied())
+}
+
+/// Like `names_to_object`, with the magics as strings.
+fn magics_to_object<S: Serializer>(
+    magics: &&[(&str, &[u8; 4])],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        magics
+            .iter()
+            .map(|&(name, magic)| (name, String::from_utf8_lossy(magic))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_header::{write_top_level_file_header, TopLevelFileHeader};
+    use crate::{EventId, StringId};
+
+    #[test]
+    fn schema_matches_what_is_written() {
+        let mut header = Vec::new();
+        write_top_level_file_header(
+            &mut header,
+            TopLevelFileHeader {
+                codec: FILE_CODEC_ZSTD,
+                flags: FILE_FLAG_WALL_TIME,
+            },
+        )
+        .unwrap();
+        assert_eq!(header.len(), SCHEMA.file_header.size);
+        assert_eq!(&header[..4], SCHEMA.file_header.magics[0].1);
+        assert_eq!(header[SCHEMA.file_header.codec_byte_index], FILE_CODEC_ZSTD);
+        assert_eq!(
+            header[SCHEMA.file_header.flags_byte_index],
+            FILE_FLAG_WALL_TIME
+        );
+        assert_eq!(
+            header[SCHEMA.file_header.minor_version_byte_index],
+            SCHEMA.format_minor_version
+        );
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        assert_eq!(
+            version & SCHEMA.file_header.format_version_mask,
+            SCHEMA.format_version
+        );
+
+        // `serialize` asserts that it is given exactly the size of an event.
+        let mut raw_event = vec![0; SCHEMA.raw_event.size];
+        RawEvent::new_integer(StringId::INVALID, EventId::INVALID, 0, 1).serialize(&mut raw_event);
+
+        // Every flag is a single bit of its own.
+        let flags = SCHEMA.file_header.flags.iter().map(|&(_, flag)| flag);
+        assert!(flags.clone().all(|flag| flag.count_ones() == 1));
+        assert_eq!(flags.fold(0, |all, flag| all | flag).count_ones(), 8);
+    }
+
+    #[test]
+    fn json() {
+        let json: serde_json::Value = serde_json::from_str(&SCHEMA.to_json()).unwrap();
+        assert_eq!(json["format_version"], CURRENT_FILE_FORMAT_VERSION);
+        assert_eq!(json["format_minor_version"], 3);
+        assert_eq!(json["first_minor_version_with_timestamp_epoch_index"], 3);
+        assert_eq!(json["file_extension"], "mm_profdata");
+        assert_eq!(json["file_header"]["magics"]["top_level"], "MMPD");
+        assert_eq!(json["file_header"]["magics"]["event_stream"], "MMES");
+        assert_eq!(
+            json["file_header"]["codecs"],
+            serde_json::json!({ "none": 0, "zstd": 1 })
+        );
+        assert_eq!(json["file_header"]["flags"]["resumed"], 128);
+        assert_eq!(json["raw_event"]["size"], 24);
+        assert_eq!(json["trace_context_entry_size"], 24);
+    }
+}
@@ -12,6 +12,16 @@
 //! the directory and file name for the trace files.
 //! Alternatively, call the [`Profiler::with_counter()`] function, to choose the [`Counter`]
 //! the profiler will use for events (whereas [`Profiler::new()`] defaults to `wall-time`).
+//! [`Profiler::with_clock()`] instead takes timestamps from a custom [`Clock`], which is
+//! mostly useful for tests that need deterministic timestamps.
+//! [`Profiler::with_options()`] additionally takes [`ProfilerOptions`], e.g. for splitting
+//! long-running profiles into multiple files of bounded size, for dropping very short
+//! interval events, for changing the size of the pages data is written in or, with the
+//! `zstd` feature, for compressing the events stream. [`Profiler::with_sink()`] writes to a
+//! [`PageSink`] instead of a file: an [`InMemorySink`], e.g. for tests, a [`WriteSink`]
+//! around e.g. a socket, a [`RingBufferSink`] that only keeps the most recent events, or a
+//! [`SpillingSink`] that only starts writing to a file once the profile has grown beyond a
+//! threshold.
 //!
 //! For more information on available counters, see the [`counters`] module documentation.
 //!
@@ -24,32 +34,110 @@
 //! Alternatively, events can also be recorded via the
 //! [`Profiler::start_recording_interval_event()`] method. This method records a "start" event and
 //! returns a `TimingGuard` object that will automatically record the corresponding "end" event
-//! when it is dropped.
+//! when it is dropped. [`Profiler::start_interval()`] does the same, but determines the
+//! `thread_id` automatically from the calling thread. Such threads can be given a
+//! human-readable name with [`Profiler::set_thread_name()`].
+//! Threads that record many events in a tight loop can collect them in an [`EventBatch`]
+//! created with [`Profiler::batch()`], which writes them in one go instead of one by one.
+//!
+//! Instead of passing `&Profiler` around, a profiler can be turned into a [`ProfilerRef`] with
+//! [`Profiler::into_ref()`], a handle that is cheap to clone and can be stored anywhere.
+//! When profiling is turned off, [`Profiler::disabled()`] creates a profiler that doesn't record
+//! anything and whose methods return right away, so instrumented code doesn't have to branch on
+//! an `Option<Profiler>`.
+//!
+//! [`Profiler::open()`] appends the events of a new session to an existing profile, e.g. after a
+//! long-running process has been restarted, instead of replacing it.
+//!
+//! With [`ProfilerOptions::record_trace_context`], interval events can be tied to a span of a
+//! distributed trace with [`Profiler::start_recording_interval_event_with_trace_context()`], so
+//! that the profiles of several processes can be correlated by the [`TraceContext`]'s trace id.
+//!
+//! None of these methods may be called from a signal handler, since they allocate and take
+//! locks. Threads that record events from signal handlers, e.g. in a sampling profiler, have to
+//! be registered with [`Profiler::register_signal_safe_thread()`] instead; the handler can then
+//! record instant events with [`Profiler::record_instant_raw()`], which only appends to a
+//! buffer allocated at registration, and [`Profiler::flush_signal_safe_events()`] writes them.
 //!
 //! To create a [`StringId`], call one of the string allocation methods:
 //!   - [`Profiler::alloc_string()`]: allocates a string and returns the [`StringId`] that refers
 //!     to it
+//!   - [`Profiler::alloc_string_bytes()`]: like [`Profiler::alloc_string()`], but for strings
+//!     that may not be valid UTF-8, e.g. paths
+//!
+//! # Capturing native stacks
+//!
+//! With the `backtrace` feature enabled, [`Profiler::record_event_with_backtrace()`] records an
+//! instant event together with the innermost frames of the calling thread's stack, to correlate
+//! events with native stacks. [`Profiler::event_id_with_backtrace()`] attaches the stack to an
+//! event id for use with any other recording method. Capturing a stack is expensive, so this is
+//! opt-in per event and the number of frames is chosen by the caller.
+//!
+//! # Integration with `tracing`
+//!
+//! With the `tracing-layer` feature enabled, the [`tracing_layer`] module provides a
+//! `tracing_subscriber::Layer` that records `tracing` spans as interval events.
 //!
 //! [`Counter`]: counters::Counter
+//! [`Clock`]: counters::Clock
 #![deny(warnings)]
 
 #[macro_use]
 extern crate log;
 
+mod append;
+mod buffered_events;
 pub mod counters;
+mod event_batch;
 pub mod event_id;
 pub mod file_header;
+pub mod format;
+mod process_metadata;
 mod profiler;
+mod profiler_ref;
 mod raw_event;
+mod rotating_files;
 mod serialization;
+mod signal_safe;
+#[cfg(feature = "backtrace")]
+mod stack_capture;
 pub mod stringtable;
+pub mod testing_clocks;
+mod timestamp_epoch_index;
+mod trace_context;
+#[cfg(feature = "tracing-layer")]
+pub mod tracing_layer;
 
 pub mod rustc;
 
+pub use crate::event_batch::{EventBatch, DEFAULT_BATCH_CAPACITY};
 pub use crate::event_id::{EventId, EventIdBuilder};
-pub use crate::profiler::{DetachedTiming, Profiler, TimingGuard};
-pub use crate::raw_event::{RawEvent, MAX_INTERVAL_VALUE, MAX_SINGLE_VALUE};
+pub use crate::process_metadata::{decode_process_metadata, ProcessMetadataWriter};
+pub use crate::profiler::{
+    DetachedTiming, IntervalGuard, Profiler, ProfilerOptions, ProfilerStats, SpanToken,
+    TimingGuard, ARGS_DROPPED_EVENT_KIND, CLOCK_WENT_BACKWARDS_EVENT_KIND,
+    COUNTER_UNAVAILABLE_EVENT_KIND, OPEN_INTERVAL_EVENT_KIND, PARENT_EVENT_ID_EVENT_KIND,
+    THREAD_NAME_EVENT_KIND, THREAD_TIMESTAMP_EPOCH_EVENT_KIND, TIMESTAMP_EPOCH_EVENT_KIND,
+    TRACE_CONTEXT_EVENT_KIND, WALL_TIME_EVENT_KIND,
+};
+pub use crate::profiler_ref::{OwnedTimingGuard, ProfilerRef};
+pub use crate::raw_event::{
+    RawEvent, MAX_INSTANT_VALUE, MAX_INTERVAL_VALUE, MAX_NESTING_DEPTH,
+    MAX_NESTING_DEPTH_THREAD_ID, MAX_SINGLE_VALUE, NESTING_DEPTH_SHIFT, TIMESTAMP_EPOCH_LENGTH,
+    TIMESTAMP_PERIOD,
+};
 pub use crate::serialization::{
-    split_streams, Addr, PageTag, SerializationSink, SerializationSinkBuilder,
+    complete_pages_len, decompress_page, decompressed_page_size, is_optional_page_tag, iter_pages,
+    split_streams, write_page_to, Addr, Compression, InMemorySink, PageSink, PageTag,
+    RingBufferSink, SerializationSink, SerializationSinkBuilder, SpillingSink, WriteSink,
+    DEFAULT_PAGE_SIZE, FIRST_OPTIONAL_PAGE_TAG, LAST_OPTIONAL_PAGE_TAG, MAX_CONFIGURABLE_PAGE_SIZE,
+    MIN_CONFIGURABLE_PAGE_SIZE, PAGE_HEADER_SIZE,
 };
 pub use crate::stringtable::{SerializableString, StringComponent, StringId, StringTableBuilder};
+pub use crate::timestamp_epoch_index::{decode_timestamp_epoch_index, TimestampEpochIndexWriter};
+pub use crate::trace_context::{
+    decode_trace_context_entries, decode_trace_contexts, TraceContext, TraceContextWriter,
+    TraceContexts, TRACE_CONTEXT_ENTRY_SIZE, TRACE_CONTEXT_PRELUDE_SIZE,
+};
+#[cfg(feature = "tracing-layer")]
+pub use crate::tracing_layer::MeasuremeLayer;
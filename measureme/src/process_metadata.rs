@@ -0,0 +1,155 @@
+//! Key/value pairs describing the profiled process, e.g. the version of the
+//! compiler that produced a profile.
+//!
+//! These are stored in their own stream (`PageTag::Metadata`), separate from
+//! the events, so that they can be read without decoding any events. The
+//! stream only exists if at least one entry has been recorded, which keeps
+//! such profiles readable by tools that don't know about this stream. It
+//! consists of the usual stream header followed by a sequence of entries,
+//! each of which is encoded as
+//!
+//! ```ignore
+//! [key length: u32 LE][key: UTF-8][value length: u32 LE][value: UTF-8]
+//! ```
+//!
+//! If a key has been recorded more than once, the last value wins.
+
+use crate::file_header::{
+    verify_file_header, write_file_header, FILE_HEADER_SIZE, FILE_MAGIC_METADATA,
+};
+use crate::serialization::SerializationSink;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Once;
+
+/// Writes entries to the metadata stream of a profile.
+pub struct ProcessMetadataWriter {
+    sink: SerializationSink,
+    header_written: Once,
+}
+
+impl ProcessMetadataWriter {
+    /// `sink` must have been created for `PageTag::Metadata`.
+    pub fn new(sink: SerializationSink) -> ProcessMetadataWriter {
+        ProcessMetadataWriter {
+            sink,
+            header_written: Once::new(),
+        }
+    }
+
+    pub fn record(&self, key: &str, value: &str) {
+        // The stream of a profile that is appended to has its header already.
+        self.header_written.call_once(|| {
+            if self.sink.is_empty() {
+                write_file_header(&mut self.sink.as_std_write(), FILE_MAGIC_METADATA).unwrap();
+            }
+        });
+
+        let mut entry = Vec::with_capacity(8 + key.len() + value.len());
+        for s in [key, value] {
+            entry.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            entry.extend_from_slice(s.as_bytes());
+        }
+
+        // The entry is written in one piece, so that entries recorded by
+        // different threads don't get interleaved.
+        self.sink.write_bytes_atomic(&entry);
+    }
+
+    /// Writes the entries recorded so far to the backing storage, see
+    /// `SerializationSink::flush_buffer`.
+    pub fn flush(&self) {
+        self.sink.flush_buffer();
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.sink.into_bytes()
+    }
+}
+
+/// Decodes the contents of a metadata stream. An empty `stream` means that
+/// no metadata has been recorded.
+pub fn decode_process_metadata(
+    stream: &[u8],
+    diagnostic_file_path: Option<&Path>,
+) -> Result<BTreeMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let mut entries = BTreeMap::new();
+
+    if stream.is_empty() {
+        return Ok(entries);
+    }
+
+    verify_file_header(
+        stream,
+        FILE_MAGIC_METADATA,
+        diagnostic_file_path,
+        "metadata",
+    )?;
+
+    let mut pos = FILE_HEADER_SIZE;
+    let read_string = |pos: &mut usize| -> Result<String, Box<dyn Error + Send + Sync>> {
+        let len_bytes = stream
+            .get(*pos..*pos + 4)
+            .ok_or("Invalid metadata stream: truncated entry")?;
+        let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        let bytes = stream
+            .get(*pos + 4..*pos + 4 + len)
+            .ok_or("Invalid metadata stream: truncated entry")?;
+        *pos += 4 + len;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    };
+
+    while pos < stream.len() {
+        let key = read_string(&mut pos)?;
+        let value = read_string(&mut pos)?;
+        entries.insert(key, value);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{PageTag, SerializationSinkBuilder};
+
+    #[test]
+    fn roundtrip() {
+        let writer = ProcessMetadataWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::Metadata),
+        );
+        writer.record("rustc-version", "1.0.0");
+        writer.record("opt-level", "0");
+        writer.record("opt-level", "3");
+
+        let entries = decode_process_metadata(&writer.into_bytes(), None).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["rustc-version"], "1.0.0");
+        assert_eq!(entries["opt-level"], "3");
+    }
+
+    #[test]
+    fn empty_stream() {
+        let writer = ProcessMetadataWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::Metadata),
+        );
+        let bytes = writer.into_bytes();
+
+        assert!(bytes.is_empty());
+        assert!(decode_process_metadata(&bytes, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_entry() {
+        let writer = ProcessMetadataWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::Metadata),
+        );
+        writer.record("key", "value");
+
+        let bytes = writer.into_bytes();
+        assert!(decode_process_metadata(&bytes[..bytes.len() - 1], None).is_err());
+    }
+}
@@ -1,18 +1,511 @@
-use crate::counters::Counter;
-use crate::file_header::{write_file_header, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_TOP_LEVEL};
-use crate::raw_event::RawEvent;
-use crate::serialization::{PageTag, SerializationSink, SerializationSinkBuilder};
+use crate::buffered_events::BufferedEvents;
+use crate::counters::{self, Clock, Counter, WallTime};
+use crate::event_id::EventId;
+use crate::file_header::{
+    segment_file_path, write_file_header, write_top_level_file_header, TopLevelFileHeader,
+    FILE_FLAG_ARGS_BUDGET, FILE_FLAG_EXPLICIT_PARENTS, FILE_FLAG_LABELS_ONLY,
+    FILE_FLAG_NESTING_DEPTH, FILE_FLAG_SAMPLED, FILE_FLAG_TRACE_CONTEXT, FILE_FLAG_WALL_TIME,
+    FILE_MAGIC_EVENT_STREAM,
+};
+use crate::process_metadata::ProcessMetadataWriter;
+use crate::raw_event::{
+    RawEvent, MAX_INSTANT_VALUE, MAX_NESTING_DEPTH_THREAD_ID, MAX_SINGLE_VALUE,
+    TIMESTAMP_EPOCH_LENGTH, TIMESTAMP_PERIOD,
+};
+use crate::serialization::{
+    Addr, Compression, PageSink, PageTag, SerializationSink, SerializationSinkBuilder,
+};
+use crate::signal_safe::{SignalSafeBuffer, SignalSafeBuffers};
 use crate::stringtable::{SerializableString, StringId, StringTableBuilder};
-use crate::{event_id::EventId, file_header::FILE_EXTENSION};
+use crate::timestamp_epoch_index::TimestampEpochIndexWriter;
+use crate::trace_context::{TraceContext, TraceContextWriter};
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::cell::{Cell, RefCell};
 use std::error::Error;
 use std::fs;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// Settings for a [`Profiler`] beyond the choice of [`Counter`].
+#[derive(Clone, Debug, Default)]
+pub struct ProfilerOptions {
+    /// If set, the profiler starts a new file whenever the current one would
+    /// grow beyond this many bytes, so that the profile ends up split across
+    /// `<path_stem>.mm_profdata`, `<path_stem>.1.mm_profdata`,
+    /// `<path_stem>.2.mm_profdata`, etc. Each file gets a copy of the strings
+    /// that its events use and of the metadata, so that each of them can be
+    /// read on its own, and old files can be deleted while the profiler is
+    /// running. The timestamp epoch index isn't written.
+    ///
+    /// The limit is checked at page granularity and each file contains at
+    /// least one page of events, so files may exceed the limit somewhat.
+    pub max_file_bytes: Option<u64>,
+
+    /// The codec used for the events stream. Compressed profiles can only be
+    /// read by tools built with the `zstd` feature.
+    pub compression: Compression,
+
+    /// Interval events that are shorter than this are dropped instead of
+    /// being recorded, which keeps the size of profiles of very hot code
+    /// paths in check. Despite the name, the unit is that of the profiler's
+    /// `Counter`. Instant and integer events are not affected. `0` (the
+    /// default) records all events.
+    ///
+    /// Since the time spent in dropped events is attributed to their parent
+    /// instead, self times computed from such a profile are approximate. The
+    /// file header records that the profile has been filtered, so that tools
+    /// can warn about this.
+    pub min_duration_nanos: u64,
+
+    /// The maximum size of the pages the data is written in, see
+    /// `SerializationSinkBuilder::with_page_size`. The profiler keeps a
+    /// page-sized buffer per stream, and locks the events stream whenever a
+    /// page is full, so smaller pages use less memory and larger pages are
+    /// faster. `None` (the default) uses `DEFAULT_PAGE_SIZE`.
+    pub page_size: Option<usize>,
+
+    /// Makes `alloc_string` return the existing `StringId` when a string with
+    /// the same contents has been allocated before, see
+    /// `StringTableBuilder::with_deduplication`. This is worthwhile if the
+    /// same strings, e.g. the arguments of queries, are allocated many times,
+    /// but costs a hash table lookup per allocation.
+    pub deduplicate_strings: bool,
+
+    /// Stores the nesting depth of each interval event, i.e. the number of
+    /// interval events of the same thread that have started but not yet
+    /// finished when it starts, in the event itself (see
+    /// `RawEvent::with_nesting_depth`), so that readers don't have to
+    /// reconstruct it. Interval events dropped because of
+    /// `min_duration_nanos` still count towards the depth of the events
+    /// nested in them.
+    ///
+    /// The depth shares its bits with the thread id, so the thread ids of
+    /// interval events must not be larger than `MAX_NESTING_DEPTH_THREAD_ID`,
+    /// which is checked when they start. Keeping track of the depth takes an
+    /// atomic update at the start and at the end of each event.
+    pub record_nesting_depth: bool,
+
+    /// Writes the events in a canonical order instead of in the order in
+    /// which they have been recorded, so that two runs of the same workload
+    /// with a deterministic `Counter` (e.g. a mock `Clock`) produce identical
+    /// events streams, regardless of how their threads have been scheduled.
+    /// Events are sorted by the time they have been recorded at (the end of
+    /// interval events), then by thread id, then by the order in which the
+    /// thread has recorded them. The metadata of the profile (e.g. its start
+    /// time) and the string table still differ between runs, unless strings
+    /// are allocated in a deterministic order.
+    ///
+    /// This keeps all events in memory until the profiler is dropped, in a
+    /// buffer per thread, which costs 32 bytes per event and means that
+    /// nothing is written to the events stream before, e.g. for
+    /// `ProfilingData::open_incremental`. Integer events don't have a time,
+    /// they are ordered right after the event their thread has recorded
+    /// before them.
+    pub stable_event_order: bool,
+
+    /// If set, a background thread writes out the partially filled pages of
+    /// the profile this often, like `Profiler::flush`, so that the profile
+    /// of a process that aborts or is killed is only missing the last
+    /// interval's worth of data. `None` (the default) writes pages only once
+    /// they are full and when the profiler is dropped, which keeps the files
+    /// as small as possible.
+    pub flush_interval: Option<Duration>,
+
+    /// Lets interval events name the event they are logically nested in,
+    /// see `Profiler::start_recording_interval_event_with_parent`. This is
+    /// meant for async code, where the fragments of different tasks that are
+    /// polled on the same thread interleave, so that nesting them by their
+    /// timestamps doesn't reflect which task they belong to.
+    ///
+    /// Events don't have a "start" record that could hold the parent, so it
+    /// is written as a separate `PARENT_EVENT_ID_EVENT_KIND` event right
+    /// before the interval event, which costs 24 bytes per event that has a
+    /// parent. Events without a parent are not affected.
+    pub record_explicit_parents: bool,
+
+    /// Records the wall-clock duration of each interval event in addition to
+    /// its duration according to the profiler's `Counter`, so that e.g. the
+    /// instructions per nanosecond of an event can be computed from a profile
+    /// recorded with the `instructions:u` counter.
+    ///
+    /// The duration is written as a separate `WALL_TIME_EVENT_KIND` event
+    /// right after the interval event, which costs 24 bytes per interval
+    /// event and a second clock read at its start and end.
+    pub record_wall_time: bool,
+
+    /// Lets interval events be tied to a span of a distributed trace, see
+    /// `Profiler::start_recording_interval_event_with_trace_context`, e.g. to
+    /// correlate the profiles of the processes taking part in handling the
+    /// same request by their trace id.
+    ///
+    /// The ids are written to a separate stream, and the interval event is
+    /// followed by a `TRACE_CONTEXT_EVENT_KIND` event that refers to them,
+    /// which costs 48 bytes per event that has a trace context. Events
+    /// without one are not affected.
+    pub record_trace_context: bool,
+
+    /// Caps the memory that long sessions with many unique arguments spend
+    /// on strings: once the strings allocated so far take up more than this
+    /// many bytes, `Profiler::records_args` returns `false` and
+    /// `EventIdBuilder` creates event ids without arguments, so that callers
+    /// stop allocating argument strings. Labels and other strings are still
+    /// allocated as usual.
+    ///
+    /// The file header records that the profile has a budget, and an
+    /// `ARGS_DROPPED_EVENT_KIND` marker records when it has been exceeded, so
+    /// that tools can point out that the events after it lack their
+    /// arguments.
+    pub max_string_bytes: Option<u64>,
+
+    /// Records only the labels of events, e.g. for profiling in production
+    /// where it only matters which events have taken how long:
+    /// `Profiler::records_args` always returns `false`, and `EventIdBuilder`
+    /// creates event ids that are just their label, without a category or
+    /// arguments, so that no strings are allocated for them and event ids of
+    /// the same label are all the same string.
+    ///
+    /// Unlike with `max_string_bytes`, no marker is recorded, the file header
+    /// records that the whole profile is without arguments instead.
+    pub labels_only: bool,
+
+    /// If set, only events of these kinds are recorded, e.g. `["Query"]` for
+    /// just the query events, and all other events are skipped before
+    /// reading the counter or encoding anything, so that they cost no more
+    /// than a hash set lookup. Interval events that are skipped don't count
+    /// towards the nesting depth of other events either.
+    ///
+    /// The kinds are given by name since their `StringId`s don't exist before
+    /// the profiler does. They are allocated with `Profiler::alloc_event_kind`
+    /// when the profiler is created, so events have to use the `StringId`s
+    /// that `alloc_event_kind` returns for them: a kind allocated with
+    /// `alloc_string` is a different string and is skipped. Kinds that are
+    /// allocated in other ways can be passed to
+    /// `Profiler::set_event_kind_filter` by their `StringId`s instead.
+    /// Callers can check `Profiler::records_event_kind` to skip creating the
+    /// event ids of skipped events as well. The markers that the profiler
+    /// records on its own, e.g. for thread names and timestamp epochs, are
+    /// always recorded.
+    pub event_kinds: Option<Vec<String>>,
+}
+
+pub(crate) fn top_level_file_header(options: &ProfilerOptions) -> TopLevelFileHeader {
+    let mut flags = 0;
+    if options.min_duration_nanos > 0 {
+        flags |= FILE_FLAG_SAMPLED;
+    }
+    if options.record_nesting_depth {
+        flags |= FILE_FLAG_NESTING_DEPTH;
+    }
+    if options.record_explicit_parents {
+        flags |= FILE_FLAG_EXPLICIT_PARENTS;
+    }
+    if options.record_wall_time {
+        flags |= FILE_FLAG_WALL_TIME;
+    }
+    if options.record_trace_context {
+        flags |= FILE_FLAG_TRACE_CONTEXT;
+    }
+    if options.max_string_bytes.is_some() {
+        flags |= FILE_FLAG_ARGS_BUDGET;
+    }
+    if options.labels_only {
+        flags |= FILE_FLAG_LABELS_ONLY;
+    }
+
+    TopLevelFileHeader {
+        codec: options.compression.codec(),
+        flags,
+    }
+}
+
+/// The event kind of the instant events that `Profiler::set_thread_name`
+/// records. The event's label is the name of the thread.
+pub const THREAD_NAME_EVENT_KIND: &str = "ThreadName";
+
+/// The event kind of the epoch markers, see `TIMESTAMP_EPOCH_LENGTH`.
+/// These are integer events whose value is the number of the epoch that the
+/// timestamps of the events recorded after them belong to. They are recorded
+/// by the thread whose event was the first one in the new epoch.
+pub const TIMESTAMP_EPOCH_EVENT_KIND: &str = "TimestampEpoch";
+
+/// Like `TIMESTAMP_EPOCH_EVENT_KIND`, but for counters that each thread reads
+/// separately (e.g. `thread-time`). Such markers only apply to the events of
+/// the thread that recorded them.
+pub const THREAD_TIMESTAMP_EPOCH_EVENT_KIND: &str = "ThreadTimestampEpoch";
+
+/// The event kind of the markers that name the explicit parent of the interval
+/// event recorded right after them by the same thread, see
+/// `ProfilerOptions::record_explicit_parents`. These are integer events whose
+/// event id is the one of the parent and whose value is always 0.
+pub const PARENT_EVENT_ID_EVENT_KIND: &str = "ParentEventId";
+
+/// The event kind of the markers that hold the wall-clock duration of the
+/// interval event recorded right before them by the same thread, see
+/// `ProfilerOptions::record_wall_time`. These are integer events whose event
+/// id is the one of the interval event and whose value is the duration in
+/// nanoseconds.
+pub const WALL_TIME_EVENT_KIND: &str = "WallTime";
+
+/// The event kind of the markers that flag the interval event recorded right
+/// before them by the same thread (or right before its `WALL_TIME_EVENT_KIND`
+/// marker) as not having a duration, because the counter couldn't be read at
+/// its start or end, see `counters::COUNTER_UNAVAILABLE`. These are integer
+/// events whose event id is the one of the interval event and whose value is
+/// always 0.
+pub const COUNTER_UNAVAILABLE_EVENT_KIND: &str = "CounterUnavailable";
+
+/// The event kind of the markers that tie the interval event recorded right
+/// before them by the same thread (or right before its other markers) to a
+/// `TraceContext`, see `ProfilerOptions::record_trace_context`. These are
+/// integer events whose event id is the one of the interval event and whose
+/// value is the index of the trace context in the trace context stream.
+pub const TRACE_CONTEXT_EVENT_KIND: &str = "TraceContext";
+
+/// The event kind of the instant event that marks when the strings of the
+/// profile have exceeded `ProfilerOptions::max_string_bytes`. Events recorded
+/// after it have been recorded without arguments. It is recorded at most
+/// once, by the thread that first noticed, and its label is empty.
+pub const ARGS_DROPPED_EVENT_KIND: &str = "ArgsDropped";
+
+/// The event kind of the markers that record that the profiler's counter,
+/// one that measures time, has gone backwards on the thread that recorded
+/// them, which happens on some virtualized hosts. The profiler doesn't let
+/// the time of a thread go backwards: until the clock has caught up again,
+/// its events get the latest time read before, so that no interval event
+/// ends before it starts. These are instant events at that time, with an
+/// empty label, whose value is how far the clock has gone back (at most
+/// `MAX_INSTANT_VALUE`).
+pub const CLOCK_WENT_BACKWARDS_EVENT_KIND: &str = "ClockWentBackwards";
+
+/// The event kind of the markers that `Profiler::open_ended_span` records at
+/// the start of a span, since its interval event is only recorded once it is
+/// closed, which may never happen. These are instant events at the start of
+/// the span, whose event id is the one of the span and whose value is the
+/// `StringId` of its event kind. Tools treat a span whose interval event is
+/// missing as lasting until the end of the profile.
+pub const OPEN_INTERVAL_EVENT_KIND: &str = "OpenInterval";
+
+/// Statistics about what a [`Profiler`] has recorded so far, see
+/// [`Profiler::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerStats {
+    /// The number of raw events recorded, including the markers that the
+    /// profiler records on its own, e.g. for timestamp epochs.
+    pub events: u64,
+    /// The number of bytes that these events take up in the events stream,
+    /// before compression and without page headers.
+    pub event_bytes: u64,
+    /// The number of strings written to the string table.
+    pub strings: u64,
+    /// The number of spans opened with `Profiler::open_span` that haven't
+    /// been closed with `Profiler::close_span` yet, including those whose
+    /// `SpanToken` has been dropped. Such spans aren't recorded at all, so
+    /// the profiler logs a warning about the ones that are left when it is
+    /// dropped. Spans that may never be closed should be opened with
+    /// `Profiler::open_ended_span` instead.
+    pub open_spans: u64,
+}
 
 pub struct Profiler {
-    event_sink: Arc<SerializationSink>,
-    string_table: StringTableBuilder,
+    event_sink: EventSink,
+    // These are shared with the thread started for
+    // `ProfilerOptions::flush_interval`.
+    string_table: Arc<StringTableBuilder>,
+    metadata: Arc<ProcessMetadataWriter>,
     counter: Counter,
+    min_duration_nanos: u64,
+    timestamp_epochs: TimestampEpochs,
+    /// Where the positions of the epoch markers are written, `None` for
+    /// disabled profilers.
+    timestamp_epoch_index: Option<TimestampEpochIndexWriter>,
+    /// The event kind of the epoch markers, once the first one is recorded.
+    timestamp_epoch_kind: OnceLock<StringId>,
+    /// The number of unfinished interval events of each thread, if
+    /// `ProfilerOptions::record_nesting_depth` is set.
+    nesting_depths: Option<NestingDepths>,
+    /// The event kind of the `PARENT_EVENT_ID_EVENT_KIND` markers, if
+    /// `ProfilerOptions::record_explicit_parents` is set.
+    parent_event_kind: Option<StringId>,
+    /// The clock for the wall-clock durations of interval events and the
+    /// event kind of the `WALL_TIME_EVENT_KIND` markers, if
+    /// `ProfilerOptions::record_wall_time` is set.
+    wall_time: Option<(WallTime, StringId)>,
+    /// The writer of the trace context stream and the event kind of the
+    /// `TRACE_CONTEXT_EVENT_KIND` markers, if
+    /// `ProfilerOptions::record_trace_context` is set. The writer is shared
+    /// with the thread started for `ProfilerOptions::flush_interval`.
+    trace_contexts: Option<(Arc<TraceContextWriter>, StringId)>,
+    /// The names of the frames captured by `event_id_with_backtrace`.
+    #[cfg(feature = "backtrace")]
+    pub(crate) frame_names: crate::stack_capture::FrameNames,
+    /// The events recorded so far and their timestamps, if
+    /// `ProfilerOptions::stable_event_order` is set. They are sorted and
+    /// written when the profiler is dropped.
+    buffered_events: Option<BufferedEvents>,
+    /// Tells the state that different profilers keep for the same thread
+    /// apart, see `Counter::since_start_clamped`.
+    id: u64,
+    /// The buffers of all threads registered with
+    /// `register_signal_safe_thread`, flushed by `flush_signal_safe_events`.
+    signal_safe_buffers: SignalSafeBuffers,
+    /// Held by `flush_signal_safe_events`, since the buffers must not be
+    /// drained concurrently.
+    signal_safe_flush: Mutex<()>,
+    /// The event kinds allocated by `alloc_event_kind` so far, by name.
+    event_kinds: Mutex<FxHashMap<String, StringId>>,
+    periodic_flush: Option<PeriodicFlush>,
+    /// The number of raw events recorded so far, see `stats`.
+    num_events: AtomicU64,
+    /// See `ProfilerStats::open_spans`.
+    open_spans: AtomicU64,
+    /// Whether the warning about the counter being unavailable has been
+    /// logged already.
+    counter_unavailable_warned: AtomicBool,
+    /// The event kind and id of the `CLOCK_WENT_BACKWARDS_EVENT_KIND`
+    /// markers, allocated when the clock goes backwards for the first time,
+    /// which is also when the warning about it is logged.
+    clock_went_backwards_ids: OnceLock<(StringId, EventId)>,
+    /// `ProfilerOptions::max_string_bytes`.
+    max_string_bytes: Option<u64>,
+    /// Whether the `ARGS_DROPPED_EVENT_KIND` marker has been recorded.
+    args_dropped: AtomicBool,
+    /// `ProfilerOptions::labels_only`.
+    labels_only: bool,
+    /// The event kinds of `ProfilerOptions::event_kinds`, if set.
+    event_kind_filter: Option<FxHashSet<StringId>>,
+    /// The callback registered with `set_event_observer`, if any, which is
+    /// only looked up once `has_event_observer` has been set.
+    event_observer: RwLock<Option<Arc<EventObserver>>>,
+    has_event_observer: AtomicBool,
+}
+
+/// A callback registered with `Profiler::set_event_observer`.
+type EventObserver = dyn Fn(&RawEvent) + Send + Sync;
+
+/// Where a profiler writes its events. Only the profilers created by
+/// `Profiler::disabled` don't have a sink, which all recording methods check
+/// before doing anything else.
+#[derive(Clone)]
+enum EventSink {
+    Null,
+    Serialization(Arc<SerializationSink>),
+}
+
+impl EventSink {
+    /// Returns the address of the first event in the events stream, `None`
+    /// if there is no sink.
+    fn write_raw_events(&self, raw_events: &[RawEvent]) -> Option<Addr> {
+        const RAW_EVENT_SIZE: usize = std::mem::size_of::<RawEvent>();
+
+        match *self {
+            EventSink::Null => None,
+            EventSink::Serialization(ref event_sink) => Some(event_sink.write_atomic(
+                raw_events.len() * RAW_EVENT_SIZE,
+                |bytes| {
+                    for (raw_event, bytes) in
+                        raw_events.iter().zip(bytes.chunks_mut(RAW_EVENT_SIZE))
+                    {
+                        raw_event.serialize(bytes);
+                    }
+                },
+            )),
+        }
+    }
+
+    /// Writes the events buffered so far as a page, and then flushes the
+    /// backing storage that is shared with the other sinks, so that all
+    /// pages that have been written reach it.
+    fn flush_buffer(&self) {
+        if let EventSink::Serialization(ref event_sink) = *self {
+            event_sink.flush_buffer();
+            event_sink.flush_backing_storage();
+        }
+    }
+}
+
+/// The thread started for `ProfilerOptions::flush_interval`, which exits
+/// once `stop` is dropped.
+struct PeriodicFlush {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+thread_local! {
+    /// The id of the calling thread (see `current_thread_id`), once it has
+    /// been registered with a profiler by
+    /// `Profiler::register_signal_safe_thread`. Const-initialized thread
+    /// locals without a destructor are plain memory accesses, which is what
+    /// makes reading this from a signal handler safe.
+    static SIGNAL_SAFE_THREAD_ID: Cell<Option<u32>> = const { Cell::new(None) };
+
+    /// The ids of the profilers whose callbacks registered with
+    /// `Profiler::set_event_observer` the calling thread is running, so that
+    /// the events a callback records on its own profiler, also through the
+    /// callbacks of other profilers, aren't passed to it again.
+    static RUNNING_EVENT_OBSERVERS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The latest epoch for which a marker has been recorded, either for all
+/// threads or, for per-thread counters, for each thread.
+enum TimestampEpochs {
+    Global(AtomicU64),
+    PerThread(Mutex<FxHashMap<u32, u64>>),
+}
+
+/// The number of unfinished interval events of each thread id. The calling
+/// thread remembers the counters it has used last, so that the map only has
+/// to be locked the first time a thread records events for a thread id.
+#[derive(Default)]
+struct NestingDepths(Mutex<FxHashMap<u32, Arc<AtomicU32>>>);
+
+impl NestingDepths {
+    /// Calls `f` with the counter of `thread_id`. `profiler_id` tells the
+    /// counters that different profilers keep for the same thread id apart.
+    #[inline]
+    fn with_depth<R>(
+        &self,
+        profiler_id: u64,
+        thread_id: u32,
+        f: impl FnOnce(&AtomicU32) -> R,
+    ) -> R {
+        /// The number of counters that a thread remembers.
+        const MAX_CACHED: usize = 8;
+
+        thread_local! {
+            static CACHED: RefCell<Vec<(u64, u32, Arc<AtomicU32>)>> = const { RefCell::new(Vec::new()) };
+        }
+
+        let lookup = || self.0.lock().entry(thread_id).or_default().clone();
+        let mut f = Some(f);
+        let result = CACHED.try_with(|cached| {
+            let mut cached = cached.try_borrow_mut().ok()?;
+            let position = cached
+                .iter()
+                .position(|&(p, t, _)| p == profiler_id && t == thread_id);
+            let position = match position {
+                Some(position) => position,
+                None => {
+                    if cached.len() == MAX_CACHED {
+                        cached.remove(0);
+                    }
+                    cached.push((profiler_id, thread_id, lookup()));
+                    cached.len() - 1
+                }
+            };
+            Some(f.take().unwrap()(&cached[position].2))
+        });
+
+        match result {
+            Ok(Some(result)) => result,
+            // The thread is being torn down.
+            _ => f.take().unwrap()(&lookup()),
+        }
+    }
 }
 
 impl Profiler {
@@ -23,55 +516,333 @@ impl Profiler {
         )
     }
 
+    /// Creates a profiler that takes its timestamps from `clock` instead of
+    /// the system's monotonic clock.
+    pub fn with_clock<P: AsRef<Path>>(
+        path_stem: P,
+        clock: Box<dyn Clock>,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        Self::with_counter(path_stem, Counter::Clock(clock))
+    }
+
     pub fn with_counter<P: AsRef<Path>>(
         path_stem: P,
         counter: Counter,
     ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
-        let path = path_stem.as_ref().with_extension(FILE_EXTENSION);
+        Self::with_options(path_stem, counter, ProfilerOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        path_stem: P,
+        counter: Counter,
+        options: ProfilerOptions,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        let path_stem = path_stem.as_ref();
+        let path = segment_file_path(path_stem, 0);
 
         fs::create_dir_all(path.parent().unwrap())?;
         let mut file = fs::File::create(path)?;
 
         // The first thing in the file must be the top-level file header.
-        write_file_header(&mut file, FILE_MAGIC_TOP_LEVEL)?;
+        write_top_level_file_header(&mut file, top_level_file_header(&options))?;
+
+        let sink_builder = match options.max_file_bytes {
+            Some(max_file_bytes) => {
+                SerializationSinkBuilder::new_rotating_files(file, path_stem, max_file_bytes)?
+            }
+            None => SerializationSinkBuilder::new_from_file(file)?,
+        };
+
+        Self::with_sink_builder(sink_builder, counter, options)
+    }
+
+    /// Creates a profiler that writes to `sink` instead of a file, using the
+    /// `wall-time` counter. With an `InMemorySink`, this is mostly useful for
+    /// tests, which can read the recorded events from `sink` after dropping
+    /// the profiler, without touching the filesystem. The bytes written to
+    /// `sink` are exactly those that would have been written to a file. See
+    /// `PageSink` for other destinations, like `RingBufferSink`.
+    pub fn with_sink(sink: impl PageSink) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        Self::with_sink_and_options(
+            sink,
+            Counter::WallTime(crate::counters::WallTime::new()),
+            ProfilerOptions::default(),
+        )
+    }
+
+    /// Like `with_sink`, but with the given counter and options. Fails if
+    /// `options.max_file_bytes` is set, since there are no files to split the
+    /// profile into.
+    pub fn with_sink_and_options(
+        mut sink: impl PageSink,
+        counter: Counter,
+        options: ProfilerOptions,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        if options.max_file_bytes.is_some() {
+            return Err(From::from(
+                "`max_file_bytes` is only supported when writing to files",
+            ));
+        }
+
+        let mut header = Vec::new();
+        write_top_level_file_header(&mut header, top_level_file_header(&options))?;
+        sink.write_file_header(&header)?;
+
+        Self::with_sink_builder(
+            SerializationSinkBuilder::new_from_page_sink(sink),
+            counter,
+            options,
+        )
+    }
+
+    pub(crate) fn with_sink_builder(
+        sink_builder: SerializationSinkBuilder,
+        counter: Counter,
+        options: ProfilerOptions,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        Self::with_sink_builder_since(sink_builder, counter, options, None)
+    }
 
-        let sink_builder = SerializationSinkBuilder::new_from_file(file)?;
-        let event_sink = Arc::new(sink_builder.new_sink(PageTag::Events));
+    /// Like `with_sink_builder`, but the metadata gives `start_time` as the
+    /// start of the profile instead of the current time, for a session that
+    /// is appended to a profile started then, see `Profiler::open`.
+    pub(crate) fn with_sink_builder_since(
+        sink_builder: SerializationSinkBuilder,
+        counter: Counter,
+        options: ProfilerOptions,
+        start_time: Option<SystemTime>,
+    ) -> Result<Profiler, Box<dyn Error + Send + Sync>> {
+        let mut sink_builder = sink_builder
+            .with_compression(options.compression)
+            .with_file_flags(top_level_file_header(&options).flags);
+        if let Some(page_size) = options.page_size {
+            sink_builder = sink_builder.with_page_size(page_size)?;
+        }
+        let (event_sink, timestamp_epoch_index) = if sink_builder.is_null() {
+            (EventSink::Null, None)
+        } else {
+            let event_sink = Arc::new(sink_builder.new_sink(PageTag::Events));
 
-        // The first thing in every stream we generate must be the stream header.
-        write_file_header(&mut event_sink.as_std_write(), FILE_MAGIC_EVENT_STREAM)?;
+            // The first thing in every stream we generate must be the stream
+            // header, unless the stream is continued, see `Profiler::open`.
+            let new_profile = event_sink.is_empty();
+            if new_profile {
+                write_file_header(&mut event_sink.as_std_write(), FILE_MAGIC_EVENT_STREAM)?;
+            }
+            let timestamp_epoch_index = TimestampEpochIndexWriter::new(
+                sink_builder.new_sink(PageTag::TimestampEpochIndex),
+                new_profile,
+            );
+            (
+                EventSink::Serialization(event_sink),
+                Some(timestamp_epoch_index),
+            )
+        };
 
-        let string_table = StringTableBuilder::new(
+        let mut string_table = StringTableBuilder::new(
             Arc::new(sink_builder.new_sink(PageTag::StringData)),
             Arc::new(sink_builder.new_sink(PageTag::StringIndex)),
         )?;
+        if options.deduplicate_strings {
+            string_table = string_table.with_deduplication();
+        }
+
+        let string_table = Arc::new(string_table);
+        let metadata = Arc::new(ProcessMetadataWriter::new(
+            sink_builder.new_sink(PageTag::Metadata),
+        ));
+        let trace_contexts = if options.record_trace_context {
+            let writer = TraceContextWriter::new(sink_builder.new_sink(PageTag::TraceContext));
+            Some((
+                Arc::new(writer),
+                string_table.alloc(TRACE_CONTEXT_EVENT_KIND),
+            ))
+        } else {
+            None
+        };
+
+        let periodic_flush = options.flush_interval.map(|flush_interval| {
+            let (stop, stopped) = mpsc::channel();
+            let event_sink = event_sink.clone();
+            let string_table = string_table.clone();
+            let metadata = metadata.clone();
+            let trace_contexts = trace_contexts.as_ref().map(|(writer, _)| writer.clone());
+
+            let thread = thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(flush_interval) {
+                    flush_streams(
+                        &event_sink,
+                        &string_table,
+                        &metadata,
+                        trace_contexts.as_deref(),
+                    );
+                }
+            });
+
+            PeriodicFlush { stop, thread }
+        });
 
-        let profiler = Profiler {
+        let parent_event_kind = if options.record_explicit_parents {
+            Some(string_table.alloc(PARENT_EVENT_ID_EVENT_KIND))
+        } else {
+            None
+        };
+
+        let wall_time = if options.record_wall_time {
+            Some((WallTime::new(), string_table.alloc(WALL_TIME_EVENT_KIND)))
+        } else {
+            None
+        };
+
+        let id = {
+            static NEXT_PROFILER_ID: AtomicU64 = AtomicU64::new(1);
+            NEXT_PROFILER_ID.fetch_add(1, Ordering::Relaxed)
+        };
+
+        let mut profiler = Profiler {
             event_sink,
             string_table,
+            metadata,
+            timestamp_epochs: if counter.is_per_thread() {
+                TimestampEpochs::PerThread(Mutex::new(FxHashMap::default()))
+            } else {
+                TimestampEpochs::Global(AtomicU64::new(0))
+            },
+            timestamp_epoch_index,
+            timestamp_epoch_kind: OnceLock::new(),
             counter,
+            min_duration_nanos: options.min_duration_nanos,
+            nesting_depths: if options.record_nesting_depth {
+                Some(NestingDepths::default())
+            } else {
+                None
+            },
+            parent_event_kind,
+            wall_time,
+            trace_contexts,
+            #[cfg(feature = "backtrace")]
+            frame_names: Default::default(),
+            buffered_events: if options.stable_event_order {
+                Some(BufferedEvents::new(id))
+            } else {
+                None
+            },
+            id,
+            signal_safe_buffers: SignalSafeBuffers::new(),
+            signal_safe_flush: Mutex::new(()),
+            event_kinds: Mutex::new(FxHashMap::default()),
+            periodic_flush,
+            num_events: AtomicU64::new(0),
+            open_spans: AtomicU64::new(0),
+            counter_unavailable_warned: AtomicBool::new(false),
+            clock_went_backwards_ids: OnceLock::new(),
+            max_string_bytes: options.max_string_bytes,
+            args_dropped: AtomicBool::new(false),
+            labels_only: options.labels_only,
+            event_kind_filter: None,
+            event_observer: RwLock::new(None),
+            has_event_observer: AtomicBool::new(false),
         };
 
+        // Disabled profilers allocate no strings, so they don't have any
+        // kinds to record either, which doesn't matter since they record
+        // nothing anyway.
+        if let Some(ref event_kinds) = options.event_kinds {
+            if profiler.is_enabled() {
+                let event_kinds = event_kinds
+                    .iter()
+                    .map(|name| profiler.alloc_event_kind(name))
+                    .collect();
+                profiler.event_kind_filter = Some(event_kinds);
+            }
+        }
+
+        // A session that is appended to a profile records its own metadata
+        // string as well, which replaces the one of the earlier sessions for
+        // readers, but keeps their start time.
         let mut args = String::new();
         for arg in std::env::args() {
-            args.push_str(&arg.escape_default().to_string());
+            args.push_str(&arg);
             args.push(' ');
         }
 
-        profiler.string_table.alloc_metadata(&*format!(
-            r#"{{ "start_time": {}, "process_id": {}, "cmd": "{}", "counter": {} }}"#,
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
+        let nanos_since_epoch = |time: SystemTime| {
+            time.duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
-                .as_nanos(),
-            std::process::id(),
-            args,
-            profiler.counter.describe_as_json(),
-        ));
+                .as_nanos() as u64
+        };
+
+        let mut metadata = serde_json::json!({
+            "start_time": nanos_since_epoch(start_time.unwrap_or_else(SystemTime::now)),
+            "process_id": std::process::id(),
+            "cmd": args,
+            "counter": profiler.counter.describe_as_json(),
+            "page_size": sink_builder.page_size(),
+        });
+
+        // Lets tools convert timestamps to wall-clock times, see
+        // `Counter::wall_clock_start`.
+        if let Some(wall_clock_start) = profiler.counter.wall_clock_start() {
+            metadata["wall_clock_start"] = nanos_since_epoch(wall_clock_start).into();
+        }
+
+        profiler.string_table.alloc_metadata(&*metadata.to_string());
 
         Ok(profiler)
     }
 
+    /// Creates a profiler that doesn't record anything, so that instrumented
+    /// code can call it unconditionally instead of branching on an
+    /// `Option<Profiler>` everywhere. All recording methods return right
+    /// away, without reading the counter, and the string allocation methods
+    /// only return `StringId::INVALID`. Nothing is written anywhere.
+    pub fn disabled() -> Profiler {
+        Self::with_sink_builder(
+            SerializationSinkBuilder::new_null(),
+            Counter::WallTime(WallTime::new()),
+            ProfilerOptions::default(),
+        )
+        .expect("creating a disabled profiler can't fail")
+    }
+
+    /// Whether the profiler records anything, i.e. whether it hasn't been
+    /// created with `Profiler::disabled`.
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.event_sink, EventSink::Null)
+    }
+
+    /// Registers `observer` to be called with every raw event that is
+    /// recorded from now on, in addition to writing it to the profile, e.g.
+    /// to feed a live dashboard. Replaces the observer registered before.
+    ///
+    /// The observer runs synchronously on the thread that records the event,
+    /// in the middle of recording it, so it has to be fast and must not
+    /// block. Events are passed to it as they are recorded, before they are
+    /// sorted for `ProfilerOptions::stable_event_order`. The events of an
+    /// `EventBatch` are passed to it when the batch is written, and the ones
+    /// buffered by `record_instant_raw` when they are flushed. Markers, e.g.
+    /// for wall times, are passed to it like any other event.
+    ///
+    /// Events that the observer records on this profiler, also through the
+    /// observers of other profilers, are written as usual but not passed to
+    /// it again, so that an observer can't recurse into itself. The events
+    /// it records on other profilers are passed to their observers. Without
+    /// an observer, recording an event only checks that there is none.
+    pub fn set_event_observer(&self, observer: impl Fn(&RawEvent) + Send + Sync + 'static) {
+        *self.event_observer.write() = Some(Arc::new(observer));
+        self.has_event_observer.store(true, Ordering::Release);
+    }
+
+    /// Records only events of the given kinds from now on, like
+    /// `ProfilerOptions::event_kinds`, whose kinds this replaces. The kinds
+    /// are matched by `StringId`, so they can be allocated in any way, e.g.
+    /// with `alloc_string` or `alloc_event_kind`, as long as the events use
+    /// the same `StringId`s.
+    pub fn set_event_kind_filter(&mut self, event_kinds: Vec<StringId>) {
+        self.event_kind_filter = Some(event_kinds.into_iter().collect());
+    }
+
     #[inline(always)]
     pub fn map_virtual_to_concrete_string(&self, virtual_id: StringId, concrete_id: StringId) {
         self.string_table
@@ -92,20 +863,180 @@ impl Profiler {
 
     #[inline(always)]
     pub fn alloc_string<STR: SerializableString + ?Sized>(&self, s: &STR) -> StringId {
+        if !self.is_enabled() {
+            return StringId::INVALID;
+        }
+
         self.string_table.alloc(s)
     }
 
+    /// Allocates `name` as an event kind. Event kinds are ordinary strings, so
+    /// any name can be used: the ones in `measureme::rustc` are only those
+    /// that the tools know how to interpret, and all others are shown by
+    /// their name. Unlike `alloc_string`, this returns the same `StringId`
+    /// every time it is called with the same name, so a kind doesn't need to
+    /// be allocated up front and passed around.
+    pub fn alloc_event_kind(&self, name: &str) -> StringId {
+        if !self.is_enabled() {
+            return StringId::INVALID;
+        }
+
+        let mut event_kinds = self.event_kinds.lock();
+        if let Some(&event_kind) = event_kinds.get(name) {
+            return event_kind;
+        }
+
+        let event_kind = self.string_table.alloc(name);
+        event_kinds.insert(name.to_string(), event_kind);
+        event_kind
+    }
+
+    /// Allocates a string made of arbitrary bytes, e.g. a label created from
+    /// a path that isn't valid UTF-8, see `StringTableBuilder::alloc_bytes`.
+    /// Fails if the string contains an unescaped `event_id::SEPARATOR_BYTE`.
+    pub fn alloc_string_bytes(
+        &self,
+        components: &[&[u8]],
+    ) -> Result<StringId, Box<dyn Error + Send + Sync>> {
+        if !self.is_enabled() {
+            return Ok(StringId::INVALID);
+        }
+
+        self.string_table.alloc_bytes(components)
+    }
+
+    /// Whether event ids should still get arguments, i.e. `false` once the
+    /// strings allocated so far exceed `ProfilerOptions::max_string_bytes`,
+    /// and always with `ProfilerOptions::labels_only`.
+    /// `EventIdBuilder` checks this on its own, but callers that allocate
+    /// the strings of arguments should check it before doing so, since those
+    /// strings would be wasted otherwise. This only compares a running byte
+    /// count against the budget.
+    #[inline]
+    pub fn records_args(&self) -> bool {
+        if self.labels_only {
+            return false;
+        }
+
+        match self.max_string_bytes {
+            Some(max_string_bytes) if self.string_table.num_bytes() > max_string_bytes => {
+                if !self.args_dropped.load(Ordering::Relaxed) {
+                    self.record_args_dropped();
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether event ids are only labels, see `ProfilerOptions::labels_only`.
+    #[inline]
+    pub fn records_labels_only(&self) -> bool {
+        self.labels_only
+    }
+
+    #[cold]
+    fn record_args_dropped(&self) {
+        if self.args_dropped.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let event_kind = self.string_table.alloc(ARGS_DROPPED_EVENT_KIND);
+        let event_id = EventId::from_label(self.string_table.alloc(""));
+        self.record_instant_event_impl(event_kind, event_id, current_thread_id(), None);
+    }
+
+    /// Whether events of `event_kind` are recorded, i.e. `false` if it isn't
+    /// one of the kinds of `ProfilerOptions::event_kinds` or
+    /// `set_event_kind_filter`. All recording methods check this on their
+    /// own, but callers can check it before creating the event id of an
+    /// event, e.g. before allocating the strings of its arguments, since
+    /// those would be wasted otherwise.
+    #[inline(always)]
+    pub fn records_event_kind(&self, event_kind: StringId) -> bool {
+        match self.event_kind_filter {
+            Some(ref event_kinds) => event_kinds.contains(&event_kind),
+            None => true,
+        }
+    }
+
+    /// Associates `name` with the calling thread, i.e. with the thread id that
+    /// `start_interval` uses for events recorded on this thread. This is done
+    /// by recording a `THREAD_NAME_EVENT_KIND` instant event, so a thread can
+    /// be renamed by calling this method again.
+    pub fn set_thread_name(&self, name: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let event_kind = self.string_table.alloc(THREAD_NAME_EVENT_KIND);
+        let event_id = EventId::from_label(self.string_table.alloc(name));
+
+        self.record_instant_event_impl(event_kind, event_id, current_thread_id(), None);
+    }
+
+    /// Records a key/value pair describing the profiled process, e.g. the
+    /// version of the program or the options it has been invoked with. The
+    /// pairs are stored separately from the events and can be read with
+    /// `analyzeme::ProfilingData::process_metadata`. Recording a key again
+    /// replaces its value.
+    ///
+    /// See the `rustc::METADATA_KEY_*` constants for the keys that rustc uses.
+    pub fn record_metadata(&self, key: &str, value: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.metadata.record(key, value);
+    }
+
     /// Records an event with the given parameters. The event time is computed
     /// automatically.
-    pub fn record_instant_event(&self, event_kind: StringId, event_id: EventId, thread_id: u32) {
-        let raw_event =
-            RawEvent::new_instant(event_kind, event_id, thread_id, self.counter.since_start());
+    ///
+    /// The event can carry a numeric `value`, e.g. the number of bytes freed
+    /// by a garbage collection run, which must not exceed `MAX_INSTANT_VALUE`.
+    /// Tools show such events as markers on the timeline and list them
+    /// separately from intervals.
+    #[inline]
+    pub fn record_instant_event(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: Option<u64>,
+    ) {
+        if !self.is_enabled() || !self.records_event_kind(event_kind) {
+            return;
+        }
+
+        self.record_instant_event_impl(event_kind, event_id, thread_id, value);
+    }
+
+    /// `record_instant_event` without the checks, for the markers that the
+    /// profiler records on its own.
+    #[inline]
+    fn record_instant_event_impl(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: Option<u64>,
+    ) {
+        let count = self.read_counter();
+        self.check_timestamp_epoch(count, thread_id);
 
-        self.record_raw_event(&raw_event);
+        let raw_event = match value {
+            None => RawEvent::new_wrapping_instant(event_kind, event_id, thread_id, count),
+            Some(value) => RawEvent::new_wrapping_instant_with_value(
+                event_kind, event_id, thread_id, count, value,
+            ),
+        };
+        self.record_raw_event(&raw_event, Some(count));
     }
 
     /// Records an event with the given parameters. The event time is computed
     /// automatically.
+    #[inline]
     pub fn record_integer_event(
         &self,
         event_kind: StringId,
@@ -113,122 +1044,2437 @@ impl Profiler {
         thread_id: u32,
         value: u64,
     ) {
+        if !self.is_enabled() || !self.records_event_kind(event_kind) {
+            return;
+        }
+
         let raw_event = RawEvent::new_integer(event_kind, event_id, thread_id, value);
-        self.record_raw_event(&raw_event);
+        self.record_raw_event(&raw_event, None);
     }
 
-    /// Creates a "start" event and returns a `TimingGuard` that will create
-    /// the corresponding "end" event when it is dropped.
-    #[inline]
-    pub fn start_recording_interval_event<'a>(
-        &'a self,
+    /// Records an interval event that started at `start_nanos` and ended at
+    /// `end_nanos` without reading the counter, e.g. when replaying a trace
+    /// that has been captured elsewhere. The timestamps are counter values
+    /// like those of `current_timestamp`, i.e. relative to the start of the
+    /// profile.
+    ///
+    /// The event is recorded as it is: `ProfilerOptions::min_duration_nanos`
+    /// doesn't apply, it gets no wall-time marker, and its nesting depth, if
+    /// recorded, is 0. Fails if `end_nanos` is before `start_nanos`, if the
+    /// interval is longer than `TIMESTAMP_PERIOD` allows, or if it ends so
+    /// long before the events that have already been recorded for the same
+    /// thread that its timestamps couldn't be decoded anymore. Events are
+    /// expected to be recorded in roughly chronological order, events of the
+    /// same thread less than half a `TIMESTAMP_EPOCH_LENGTH` apart can be
+    /// recorded in any order.
+    pub fn record_event_with_timestamps(
+        &self,
         event_kind: StringId,
         event_id: EventId,
         thread_id: u32,
-    ) -> TimingGuard<'a> {
-        TimingGuard {
-            profiler: self,
-            event_id,
+        start_nanos: u64,
+        end_nanos: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.is_enabled() || !self.records_event_kind(event_kind) {
+            return Ok(());
+        }
+
+        if end_nanos < start_nanos {
+            return Err(From::from(format!(
+                "the event ends at {}ns, before its start at {}ns",
+                end_nanos, start_nanos
+            )));
+        }
+        if end_nanos - start_nanos >= TIMESTAMP_PERIOD {
+            return Err(From::from(format!(
+                "the event lasts {}ns, the longest events that can be recorded last {}ns",
+                end_nanos - start_nanos,
+                TIMESTAMP_PERIOD - 1
+            )));
+        }
+        self.check_explicit_timestamp(end_nanos, thread_id)?;
+
+        let mut raw_event = RawEvent::new_wrapping_interval(
             event_kind,
+            event_id,
             thread_id,
-            start_count: self.counter.since_start(),
+            start_nanos,
+            end_nanos,
+        );
+        if self.nesting_depths.is_some() {
+            raw_event = raw_event.with_nesting_depth(0);
         }
+        self.record_raw_event(&raw_event, Some(end_nanos));
+
+        Ok(())
     }
 
-    /// Creates a "start" event and returns a `DetachedTiming`.
-    /// To create the corresponding "event" event, you must call
-    /// `finish_recording_internal_event` with the returned
-    /// `DetachedTiming`.
-    /// Since `DetachedTiming` does not capture the lifetime of `&self`,
-    /// this method can sometimes be more convenient than
-    /// `start_recording_interval_event` - e.g. it can be stored
-    /// in a struct without the need to add a lifetime parameter.
-    #[inline]
-    pub fn start_recording_interval_event_detached(
+    /// Like `record_event_with_timestamps`, but records an instant event at
+    /// `timestamp_nanos`.
+    pub fn record_instant_event_with_timestamp(
         &self,
         event_kind: StringId,
         event_id: EventId,
         thread_id: u32,
-    ) -> DetachedTiming {
-        DetachedTiming {
-            event_id,
-            event_kind,
-            thread_id,
-            start_count: self.counter.since_start(),
+        timestamp_nanos: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.is_enabled() || !self.records_event_kind(event_kind) {
+            return Ok(());
         }
+
+        self.check_explicit_timestamp(timestamp_nanos, thread_id)?;
+
+        let raw_event =
+            RawEvent::new_wrapping_instant(event_kind, event_id, thread_id, timestamp_nanos);
+        self.record_raw_event(&raw_event, Some(timestamp_nanos));
+
+        Ok(())
     }
 
-    /// Creates the corresponding "end" event for
-    /// the "start" event represented by `timing`. You
-    /// must have obtained `timing` from the same `Profiler`
-    pub fn finish_recording_interval_event(&self, timing: DetachedTiming) {
-        drop(TimingGuard {
-            profiler: self,
-            event_id: timing.event_id,
-            event_kind: timing.event_kind,
-            thread_id: timing.thread_id,
-            start_count: timing.start_count,
-        });
+    /// Makes sure that an event of `thread_id` that has been given the
+    /// timestamp `count` by the caller can be decoded, like
+    /// `check_timestamp_epoch` does for events with timestamps from the
+    /// counter.
+    fn check_explicit_timestamp(
+        &self,
+        count: u64,
+        thread_id: u32,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let latest_epoch = self.latest_timestamp_epoch(thread_id);
+        let earliest =
+            (latest_epoch * TIMESTAMP_EPOCH_LENGTH).saturating_sub(TIMESTAMP_EPOCH_LENGTH / 2);
+        if count < earliest {
+            return Err(From::from(format!(
+                "the event ends at {}ns, but events ending before {}ns can't be recorded anymore",
+                count, earliest
+            )));
+        }
+
+        // Readers assume that the events before the first marker belong to
+        // the epoch before it, which only holds for the events recorded so
+        // far if that is epoch 0.
+        if latest_epoch == 0 && count >= 2 * TIMESTAMP_EPOCH_LENGTH {
+            self.check_timestamp_epoch(TIMESTAMP_EPOCH_LENGTH, thread_id);
+        }
+        self.check_timestamp_epoch(count, thread_id);
+
+        Ok(())
     }
 
-    fn record_raw_event(&self, raw_event: &RawEvent) {
-        self.event_sink
-            .write_atomic(std::mem::size_of::<RawEvent>(), |bytes| {
-                raw_event.serialize(bytes);
-            });
+    /// Prepares the calling thread for recording events with
+    /// `record_instant_raw`, e.g. from a `SIGPROF` handler of a sampling
+    /// profiler, by allocating a buffer for `capacity` events (24 bytes
+    /// each). Events that don't fit into the buffer because it hasn't been
+    /// flushed in time are dropped, see `flush_signal_safe_events`.
+    ///
+    /// This must be called outside of signal handlers, before the handler
+    /// can first run on this thread. A thread can be registered with several
+    /// profilers. Registering a thread with the same profiler again replaces
+    /// its buffer, the events in the old one are still written.
+    pub fn register_signal_safe_thread(&self, capacity: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let thread_id = current_thread_id();
+        SIGNAL_SAFE_THREAD_ID.with(|current| current.set(Some(thread_id)));
+        self.signal_safe_buffers
+            .push(Box::new(SignalSafeBuffer::new(thread_id, capacity)));
     }
-}
 
-/// Created by `Profiler::start_recording_interval_event_detached`.
-/// Must be passed to `finish_recording_interval_event` to record an
-/// "end" event.
-#[must_use]
-pub struct DetachedTiming {
-    event_id: EventId,
-    event_kind: StringId,
-    thread_id: u32,
-    start_count: u64,
-}
+    /// The current value of the profiler's counter, i.e. the timestamp an
+    /// event recorded now would have, for passing to `record_instant_raw`.
+    ///
+    /// With the `wall-time` counter and the hardware counters, this is safe
+    /// to call from a signal handler. A custom `Clock` is only if its
+    /// `now_nanos` is. For that reason, the value is not corrected if the
+    /// clock has gone backwards (see `CLOCK_WENT_BACKWARDS_EVENT_KIND`),
+    /// which only matters for interval events.
+    #[inline]
+    pub fn current_timestamp(&self) -> u64 {
+        self.counter.since_start()
+    }
 
-/// When dropped, this `TimingGuard` will record an "end" event in the
-/// `Profiler` it was created by.
-#[must_use]
-pub struct TimingGuard<'a> {
-    profiler: &'a Profiler,
-    event_id: EventId,
-    event_kind: StringId,
-    thread_id: u32,
-    start_count: u64,
-}
+    /// Records an instant event for the calling thread, which must have
+    /// been registered with `register_signal_safe_thread`, at `timestamp`
+    /// (see `current_timestamp`). Returns `false` if the event has been
+    /// dropped, because the thread hasn't been registered or because its
+    /// buffer is full. Events that `ProfilerOptions::event_kinds` skips
+    /// aren't dropped in this sense, `true` is returned for them.
+    ///
+    /// Unlike all other methods of the profiler, this one is
+    /// async-signal-safe: it doesn't allocate, take locks or make system
+    /// calls, it only appends the event to the thread's buffer with a few
+    /// atomic operations. In particular, it may be called from signal
+    /// handlers that interrupt each other, or that interrupt the thread
+    /// while it is using the profiler itself. Its arguments must have been
+    /// created ahead of time, outside of the handler: `event_kind` and the
+    /// label of `event_id` with `alloc_string`, and `event_id` e.g. with
+    /// `EventId::from_label`. Besides this method and `current_timestamp`,
+    /// nothing else may be called from a handler, neither the string
+    /// allocation methods nor any of the `record_*` and `start_*` methods.
+    ///
+    /// The events are only written to the profile by
+    /// `flush_signal_safe_events`, which also happens when the profiler is
+    /// dropped.
+    #[inline]
+    pub fn record_instant_raw(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        timestamp: u64,
+    ) -> bool {
+        if !self.records_event_kind(event_kind) {
+            return true;
+        }
 
-impl<'a> Drop for TimingGuard<'a> {
+        let thread_id = match SIGNAL_SAFE_THREAD_ID.try_with(Cell::get) {
+            Ok(Some(thread_id)) => thread_id,
+            _ => return false,
+        };
+
+        match self.signal_safe_buffers.find(thread_id) {
+            Some(buffer) => buffer.push(event_kind, event_id, timestamp),
+            None => false,
+        }
+    }
+
+    /// Writes the events recorded with `record_instant_raw` so far to the
+    /// profile. This has to be called regularly, outside of signal handlers,
+    /// so that the buffers of the registered threads don't fill up. Events
+    /// that a handler is still in the middle of recording are written by the
+    /// next call.
+    pub fn flush_signal_safe_events(&self) {
+        let _flushing = self.signal_safe_flush.lock();
+
+        // In the order in which the buffers have been registered.
+        let buffers: Vec<_> = self.signal_safe_buffers.iter().collect();
+        for buffer in buffers.into_iter().rev() {
+            let thread_id = buffer.thread_id();
+            buffer.drain(|event_kind, event_id, timestamp| {
+                self.check_timestamp_epoch(timestamp, thread_id);
+
+                let raw_event =
+                    RawEvent::new_wrapping_instant(event_kind, event_id, thread_id, timestamp);
+                self.record_raw_event(&raw_event, Some(timestamp));
+            });
+        }
+    }
+
+    /// Returns the number of events and strings recorded so far. The counters
+    /// are updated with relaxed atomic operations, so events that are being
+    /// recorded on other threads at the same time may or may not be included,
+    /// and the numbers are only approximate while other threads record
+    /// events. Events that `record_instant_raw` has buffered are only counted
+    /// once they have been flushed.
+    pub fn stats(&self) -> ProfilerStats {
+        let events = self.num_events.load(Ordering::Relaxed);
+
+        ProfilerStats {
+            events,
+            event_bytes: events * std::mem::size_of::<RawEvent>() as u64,
+            strings: self.string_table.num_strings(),
+            open_spans: self.open_spans.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The number of events that `record_instant_raw` has dropped because
+    /// the buffer of the recording thread was full.
+    pub fn dropped_signal_safe_events(&self) -> u64 {
+        self.signal_safe_buffers
+            .iter()
+            .map(|buffer| buffer.dropped())
+            .sum()
+    }
+
+    /// Writes everything recorded so far to the file, including the partially
+    /// filled pages that are otherwise only written once they are full or
+    /// when the profiler is dropped, and the events recorded with
+    /// `record_instant_raw`. What has been flushed can be read even if the
+    /// process then exits without dropping the profiler, e.g. when it aborts
+    /// or is killed, so embedders can call this as a checkpoint, or from a
+    /// panic hook before a `panic = "abort"` abort (see
+    /// `ProfilerRef::flush_on_panic`). The `PageSink` of a profiler created
+    /// with `with_sink` is flushed as well.
+    ///
+    /// Every flush ends the current pages early, which makes the profile
+    /// somewhat larger. Events that are held back for
+    /// `ProfilerOptions::stable_event_order` are still only written when the
+    /// profiler is dropped.
+    pub fn flush(&self) {
+        self.flush_signal_safe_events();
+        flush_streams(
+            &self.event_sink,
+            &self.string_table,
+            &self.metadata,
+            self.trace_contexts.as_ref().map(|(writer, _)| &**writer),
+        );
+    }
+
+    /// Creates a "start" event and returns a `TimingGuard` that will create
+    /// the corresponding "end" event when it is dropped.
     #[inline]
-    fn drop(&mut self) {
-        let raw_event = RawEvent::new_interval(
-            self.event_kind,
-            self.event_id,
-            self.thread_id,
-            self.start_count,
-            self.profiler.counter.since_start(),
+    pub fn start_recording_interval_event<'a>(
+        &'a self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+    ) -> TimingGuard<'a> {
+        self.start_recording_interval_event_impl(event_kind, event_id, None, None, thread_id)
+    }
+
+    /// Like `start_recording_interval_event`, but the event is recorded as
+    /// nested in the event whose event id is `parent`, wherever it ends up in
+    /// relation to the other events of the thread. Tools look for the parent
+    /// among the interval events of the same thread that contain the event,
+    /// and use the innermost one (see `analyzeme::ProfilingData::call_tree`).
+    ///
+    /// The parent is only recorded if `ProfilerOptions::record_explicit_parents`
+    /// is set, otherwise this is the same as `start_recording_interval_event`.
+    #[inline]
+    pub fn start_recording_interval_event_with_parent<'a>(
+        &'a self,
+        event_kind: StringId,
+        event_id: EventId,
+        parent: EventId,
+        thread_id: u32,
+    ) -> TimingGuard<'a> {
+        self.start_recording_interval_event_impl(
+            event_kind,
+            event_id,
+            Some(parent),
+            None,
+            thread_id,
+        )
+    }
+
+    /// Like `start_recording_interval_event`, but the event is recorded as
+    /// part of the span `trace_context` of a distributed trace, so that it can
+    /// be correlated with the events of other processes that handled the same
+    /// trace (see `analyzeme::ProfilingData::spans_by_trace_id`).
+    ///
+    /// The trace context is only recorded if
+    /// `ProfilerOptions::record_trace_context` is set, otherwise this is the
+    /// same as `start_recording_interval_event`.
+    #[inline]
+    pub fn start_recording_interval_event_with_trace_context<'a>(
+        &'a self,
+        event_kind: StringId,
+        event_id: EventId,
+        trace_context: TraceContext,
+        thread_id: u32,
+    ) -> TimingGuard<'a> {
+        self.start_recording_interval_event_impl(
+            event_kind,
+            event_id,
+            None,
+            Some(trace_context),
+            thread_id,
+        )
+    }
+
+    #[inline]
+    fn start_recording_interval_event_impl<'a>(
+        &'a self,
+        event_kind: StringId,
+        event_id: EventId,
+        parent: Option<EventId>,
+        trace_context: Option<TraceContext>,
+        thread_id: u32,
+    ) -> TimingGuard<'a> {
+        TimingGuard {
+            profiler: self,
+            timing: self.start_recording_interval_event_detached_impl(
+                event_kind,
+                event_id,
+                parent,
+                trace_context,
+                thread_id,
+            ),
+        }
+    }
+
+    /// Creates a "start" event and returns an `IntervalGuard` that will create
+    /// the corresponding "end" event when it is dropped, including when it is
+    /// dropped during unwinding.
+    ///
+    /// Unlike `start_recording_interval_event`, the thread id is not passed in
+    /// but determined automatically from the thread calling this method. The
+    /// "end" event is always recorded for that thread, even if the guard is
+    /// dropped somewhere else (e.g. in a future that was moved to another
+    /// thread while being polled).
+    #[inline]
+    pub fn start_interval<'a>(
+        &'a self,
+        event_kind: StringId,
+        event_id: EventId,
+    ) -> IntervalGuard<'a> {
+        IntervalGuard {
+            timing_guard: self.start_recording_interval_event(
+                event_kind,
+                event_id,
+                current_thread_id(),
+            ),
+        }
+    }
+
+    /// Creates a "start" event for the calling thread and returns a
+    /// `SpanToken` for it, which has to be passed to `close_span` to record
+    /// the "end" event. This is meant for spans that start and end in
+    /// different calls, e.g. in different polls of a future. The token
+    /// doesn't borrow the profiler and can be sent to another thread, the
+    /// event is always recorded for the thread that has opened it, see
+    /// `SpanToken::thread_id`.
+    ///
+    /// Events recorded by that thread in the meantime may only partially
+    /// overlap the span, e.g. those of other futures polled on it, which
+    /// tools report as improperly nested (see
+    /// `analyzeme::ProfilingData::validate`).
+    ///
+    /// Nothing is recorded for a span that is never closed, see
+    /// `ProfilerStats::open_spans`.
+    #[inline]
+    pub fn open_span(&self, event_kind: StringId, event_id: EventId) -> SpanToken {
+        if self.is_enabled() {
+            self.open_spans.fetch_add(1, Ordering::Relaxed);
+        }
+        self.open_span_uncounted(event_kind, event_id)
+    }
+
+    #[inline]
+    fn open_span_uncounted(&self, event_kind: StringId, event_id: EventId) -> SpanToken {
+        SpanToken {
+            timing: self.start_recording_interval_event_detached(
+                event_kind,
+                event_id,
+                current_thread_id(),
+            ),
+        }
+    }
+
+    /// Like `open_span`, but for spans that may never be closed, e.g. one for
+    /// a lock or a connection that is held until the process exits. A marker
+    /// is recorded right away (see `OPEN_INTERVAL_EVENT_KIND`), so that tools
+    /// can tell such a span from one whose "end" event has been lost and can
+    /// show it as lasting until the end of the profile if it is never closed.
+    /// Spans that are closed are recorded like any other, except that they
+    /// are never dropped for being shorter than
+    /// `ProfilerOptions::min_duration_nanos`, since their marker is written
+    /// already.
+    pub fn open_ended_span(&self, event_kind: StringId, event_id: EventId) -> SpanToken {
+        let mut token = self.open_span_uncounted(event_kind, event_id);
+        token.timing.open_ended = true;
+
+        // A span without a start time is recorded at its end, so there is
+        // nothing for the marker to mark.
+        let start_count = token.timing.start_count;
+        if self.is_enabled()
+            && self.records_event_kind(event_kind)
+            && counters::is_available(start_count)
+        {
+            let marker = RawEvent::new_wrapping_instant_with_value(
+                self.alloc_event_kind(OPEN_INTERVAL_EVENT_KIND),
+                event_id,
+                token.thread_id(),
+                start_count,
+                event_kind.as_u32() as u64,
+            );
+            self.check_timestamp_epoch(start_count, token.thread_id());
+            self.record_raw_event(&marker, Some(start_count));
+        }
+
+        token
+    }
+
+    /// Records the "end" event of the span that `token` has been returned
+    /// for by `open_span`, which has to have been called on the same
+    /// `Profiler`. Can be called on any thread.
+    #[inline]
+    pub fn close_span(&self, token: SpanToken) {
+        if self.is_enabled() && !token.timing.open_ended {
+            self.open_spans.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.finish_recording_interval_event(token.timing);
+    }
+
+    /// Creates a "start" event and returns a `DetachedTiming`.
+    /// To create the corresponding "event" event, you must call
+    /// `finish_recording_internal_event` with the returned
+    /// `DetachedTiming`.
+    /// Since `DetachedTiming` does not capture the lifetime of `&self`,
+    /// this method can sometimes be more convenient than
+    /// `start_recording_interval_event` - e.g. it can be stored
+    /// in a struct without the need to add a lifetime parameter.
+    #[inline]
+    pub fn start_recording_interval_event_detached(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+    ) -> DetachedTiming {
+        self.start_recording_interval_event_detached_impl(
+            event_kind, event_id, None, None, thread_id,
+        )
+    }
+
+    /// Like `start_recording_interval_event_detached`, but with an explicit
+    /// parent, see `start_recording_interval_event_with_parent`.
+    #[inline]
+    pub fn start_recording_interval_event_detached_with_parent(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        parent: EventId,
+        thread_id: u32,
+    ) -> DetachedTiming {
+        self.start_recording_interval_event_detached_impl(
+            event_kind,
+            event_id,
+            Some(parent),
+            None,
+            thread_id,
+        )
+    }
+
+    /// Like `start_recording_interval_event_detached`, but with a trace
+    /// context, see `start_recording_interval_event_with_trace_context`.
+    #[inline]
+    pub fn start_recording_interval_event_detached_with_trace_context(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        trace_context: TraceContext,
+        thread_id: u32,
+    ) -> DetachedTiming {
+        self.start_recording_interval_event_detached_impl(
+            event_kind,
+            event_id,
+            None,
+            Some(trace_context),
+            thread_id,
+        )
+    }
+
+    #[inline]
+    fn start_recording_interval_event_detached_impl(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        parent: Option<EventId>,
+        trace_context: Option<TraceContext>,
+        thread_id: u32,
+    ) -> DetachedTiming {
+        let (nesting_depth, wall_start, start_count) = self.interval_start(event_kind, thread_id);
+        DetachedTiming {
+            event_id,
+            event_kind,
+            parent,
+            trace_context,
+            thread_id,
+            nesting_depth,
+            wall_start,
+            start_count,
+            open_ended: false,
+        }
+    }
+
+    /// The nesting depth, wall time and counter value at the start of an
+    /// interval event of `event_kind` on `thread_id`. Disabled profilers
+    /// don't read any of them, and neither do profilers that skip the kind.
+    #[inline(always)]
+    fn interval_start(&self, event_kind: StringId, thread_id: u32) -> (Option<u32>, u64, u64) {
+        if !self.is_enabled() || !self.records_event_kind(event_kind) {
+            return (None, 0, 0);
+        }
+
+        (
+            self.enter_interval(thread_id),
+            self.wall_time_now(),
+            self.read_counter(),
+        )
+    }
+
+    /// Creates the corresponding "end" event for
+    /// the "start" event represented by `timing`. You
+    /// must have obtained `timing` from the same `Profiler`
+    pub fn finish_recording_interval_event(&self, timing: DetachedTiming) {
+        drop(TimingGuard {
+            profiler: self,
+            timing,
+        });
+    }
+
+    /// Reads the counter for the timestamp of an event recorded by the calling
+    /// thread, see `Counter::since_start_clamped`.
+    #[inline]
+    pub(crate) fn read_counter(&self) -> u64 {
+        let (count, went_backwards) = self.counter.since_start_clamped(self.id);
+        if went_backwards > 0 {
+            self.record_clock_went_backwards(count, went_backwards);
+        }
+        count
+    }
+
+    #[cold]
+    fn record_clock_went_backwards(&self, count: u64, went_backwards: u64) {
+        let &(event_kind, event_id) = self.clock_went_backwards_ids.get_or_init(|| {
+            error!(
+                "[WARNING] the clock went backwards by {}ns, events are recorded with the latest \
+                 time read before until it has caught up again",
+                went_backwards
+            );
+            (
+                self.alloc_event_kind(CLOCK_WENT_BACKWARDS_EVENT_KIND),
+                EventId::from_label(self.string_table.alloc("")),
+            )
+        });
+
+        // The marker gets the time that has been read already, since reading
+        // the counter again could find it to have gone backwards again.
+        let thread_id = current_thread_id();
+        self.check_timestamp_epoch(count, thread_id);
+        let raw_event = RawEvent::new_wrapping_instant_with_value(
+            event_kind,
+            event_id,
+            thread_id,
+            count,
+            went_backwards.min(MAX_INSTANT_VALUE),
         );
+        self.record_raw_event(&raw_event, Some(count));
+    }
 
-        self.profiler.record_raw_event(&raw_event);
+    /// The current value of the clock of `ProfilerOptions::record_wall_time`,
+    /// or `0` if wall times aren't recorded.
+    #[inline]
+    fn wall_time_now(&self) -> u64 {
+        match self.wall_time {
+            Some((ref wall_time, _)) => wall_time.now_nanos(),
+            None => 0,
+        }
     }
-}
 
-impl<'a> TimingGuard<'a> {
-    /// This method set a new `event_id` right before actually recording the
-    /// event.
+    /// The event kind of the `COUNTER_UNAVAILABLE_EVENT_KIND` markers. The
+    /// first time this is called, a warning is logged, since all durations
+    /// measured with an unreliable counter are suspicious.
+    #[cold]
+    fn counter_unavailable_event_kind(&self) -> StringId {
+        if !self
+            .counter_unavailable_warned
+            .swap(true, Ordering::Relaxed)
+        {
+            error!(
+                "[WARNING] the counter couldn't be read at the start or end of an interval event, \
+                 such events are recorded without a duration"
+            );
+        }
+
+        self.alloc_event_kind(COUNTER_UNAVAILABLE_EVENT_KIND)
+    }
+
+    /// Counts an interval event starting on `thread_id` and returns its
+    /// nesting depth, if nesting depths are recorded. Panics if `thread_id`
+    /// is too large for storing the depth, so that the end of the event,
+    /// which may be recorded by a destructor, doesn't have to.
     #[inline]
-    pub fn finish_with_override_event_id(mut self, event_id: EventId) {
-        self.event_id = event_id;
-        // Let's be explicit about it: Dropping the guard will record the event.
-        drop(self)
+    fn enter_interval(&self, thread_id: u32) -> Option<u32> {
+        let nesting_depths = self.nesting_depths.as_ref()?;
+        assert!(
+            thread_id <= MAX_NESTING_DEPTH_THREAD_ID,
+            "thread id {} is too large for storing the nesting depth",
+            thread_id
+        );
+
+        Some(nesting_depths.with_depth(self.id, thread_id, |depth| {
+            depth.fetch_add(1, Ordering::Relaxed)
+        }))
+    }
+
+    /// Counts an interval event on `thread_id` as finished again.
+    #[inline]
+    fn exit_interval(&self, thread_id: u32) {
+        if let Some(ref nesting_depths) = self.nesting_depths {
+            nesting_depths.with_depth(self.id, thread_id, |depth| {
+                let _ = depth.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                    depth.checked_sub(1)
+                });
+            });
+        }
+    }
+
+    /// Makes sure that an epoch marker has been recorded for the epoch that
+    /// `count` falls into, before an event with that timestamp is recorded.
+    #[inline]
+    pub(crate) fn check_timestamp_epoch(&self, count: u64, thread_id: u32) {
+        // Markers are only needed once timestamps can't be decoded as they
+        // are anymore, which keeps this cheap for all other profiles.
+        if count >= TIMESTAMP_EPOCH_LENGTH {
+            self.record_timestamp_epoch(count / TIMESTAMP_EPOCH_LENGTH, thread_id);
+        }
+    }
+
+    /// The epoch of the latest epoch marker that applies to the events of
+    /// `thread_id`, or 0 if there is none.
+    pub(crate) fn latest_timestamp_epoch(&self, thread_id: u32) -> u64 {
+        match self.timestamp_epochs {
+            TimestampEpochs::Global(ref latest) => latest.load(Ordering::Relaxed),
+            TimestampEpochs::PerThread(ref latest) => {
+                latest.lock().get(&thread_id).copied().unwrap_or(0)
+            }
+        }
+    }
+
+    #[cold]
+    fn record_timestamp_epoch(&self, epoch: u64, thread_id: u32) {
+        let event_kind = match self.timestamp_epochs {
+            TimestampEpochs::Global(ref latest) => {
+                if latest.load(Ordering::Relaxed) >= epoch
+                    || latest.fetch_max(epoch, Ordering::Relaxed) >= epoch
+                {
+                    return;
+                }
+                TIMESTAMP_EPOCH_EVENT_KIND
+            }
+            TimestampEpochs::PerThread(ref latest) => {
+                let mut latest = latest.lock();
+                let latest = latest.entry(thread_id).or_insert(0);
+                if *latest >= epoch {
+                    return;
+                }
+                *latest = epoch;
+                THREAD_TIMESTAMP_EPOCH_EVENT_KIND
+            }
+        };
+
+        let event_kind = *self
+            .timestamp_epoch_kind
+            .get_or_init(|| self.string_table.alloc(event_kind));
+        let event_id = EventId::from_label(event_kind);
+        // The marker is ordered before all events of its epoch. Buffered
+        // markers are indexed once they are written, see `Drop`.
+        let addr = self.record_raw_event(
+            &RawEvent::new_integer(event_kind, event_id, thread_id, epoch),
+            Some(epoch * TIMESTAMP_EPOCH_LENGTH),
+        );
+        if let (Some(index), Some(addr)) = (&self.timestamp_epoch_index, addr) {
+            index.record(addr);
+        }
+    }
+
+    /// Writes `raw_event`, or buffers it if the events are written in a
+    /// stable order. `timestamp` is the counter value the event is ordered
+    /// by. `None` orders it right after the event that the calling thread has
+    /// recorded before, so that integer events don't have to read the
+    /// counter. Returns the address of the event in the events stream if it
+    /// has been written.
+    fn record_raw_event(&self, raw_event: &RawEvent, timestamp: Option<u64>) -> Option<Addr> {
+        self.num_events.fetch_add(1, Ordering::Relaxed);
+        self.observe_raw_events(std::slice::from_ref(raw_event));
+
+        if let Some(ref buffered_events) = self.buffered_events {
+            buffered_events.push([(timestamp, *raw_event)]);
+            return None;
+        }
+
+        self.event_sink
+            .write_raw_events(std::slice::from_ref(raw_event))
+    }
+
+    /// Like `record_raw_event`, but records an interval event together with
+    /// its `PARENT_EVENT_ID_EVENT_KIND`, `WALL_TIME_EVENT_KIND`,
+    /// `COUNTER_UNAVAILABLE_EVENT_KIND` and `TRACE_CONTEXT_EVENT_KIND` markers.
+    /// They are written as a single unit, so that no other event can end up
+    /// in between.
+    fn record_raw_events(&self, raw_events: &[RawEvent], timestamp: u64) {
+        self.num_events
+            .fetch_add(raw_events.len() as u64, Ordering::Relaxed);
+        self.observe_raw_events(raw_events);
+
+        if let Some(ref buffered_events) = self.buffered_events {
+            // The sort keeps events with the same timestamp and thread in
+            // order, so the markers stay next to the event.
+            buffered_events.push(
+                raw_events
+                    .iter()
+                    .map(|raw_event| (Some(timestamp), *raw_event)),
+            );
+            return;
+        }
+
+        self.event_sink.write_raw_events(raw_events);
+    }
+
+    /// Passes `raw_events` to the observer registered with
+    /// `set_event_observer`, if any. This is called before any lock is
+    /// taken, so that the observer can record events itself.
+    #[inline]
+    fn observe_raw_events(&self, raw_events: &[RawEvent]) {
+        if self.has_event_observer.load(Ordering::Acquire) {
+            // The lock isn't held while the observer runs, so that it can
+            // register another observer.
+            let observer = self.event_observer.read().clone();
+            if let Some(observer) = observer {
+                call_event_observer(self.id, &*observer, raw_events);
+            }
+        }
+    }
+
+    /// Whether events are buffered for `ProfilerOptions::stable_event_order`.
+    #[inline]
+    pub(crate) fn has_stable_event_order(&self) -> bool {
+        self.buffered_events.is_some()
+    }
+
+    /// Writes the events of an `EventBatch` in one go. `timestamps` are the
+    /// counter values the events are ordered by, which are only needed, and
+    /// only given, if `has_stable_event_order`, see `record_raw_event`.
+    pub(crate) fn record_batched_events(
+        &self,
+        raw_events: &[RawEvent],
+        timestamps: &[Option<u64>],
+    ) {
+        self.num_events
+            .fetch_add(raw_events.len() as u64, Ordering::Relaxed);
+        self.observe_raw_events(raw_events);
+
+        if let Some(ref buffered_events) = self.buffered_events {
+            buffered_events.push(timestamps.iter().copied().zip(raw_events.iter().copied()));
+            return;
+        }
+
+        self.event_sink.write_raw_events(raw_events);
     }
 }
 
-// Make sure that `Profiler` can be used in a multithreaded context
-fn _assert_bounds() {
-    assert_bounds_inner(&Profiler::new(""));
-    fn assert_bounds_inner<S: Sized + Send + Sync + 'static>(_: &S) {}
+/// Passes `raw_events` to `observer`, the observer of the profiler with the
+/// id `profiler_id`, unless the calling thread is running it already. Events
+/// recorded while the thread is being torn down, e.g. by the destructors of
+/// other thread locals, aren't observed at all.
+fn call_event_observer(profiler_id: u64, observer: &EventObserver, raw_events: &[RawEvent]) {
+    let entered = RUNNING_EVENT_OBSERVERS.try_with(|running| {
+        let mut running = running.borrow_mut();
+        if running.contains(&profiler_id) {
+            return false;
+        }
+        running.push(profiler_id);
+        true
+    });
+    if !entered.unwrap_or(false) {
+        return;
+    }
+
+    // Removes the id even if the observer panics.
+    struct ExitOnDrop(u64);
+
+    impl Drop for ExitOnDrop {
+        fn drop(&mut self) {
+            let _ = RUNNING_EVENT_OBSERVERS.try_with(|running| {
+                running.borrow_mut().retain(|&id| id != self.0);
+            });
+        }
+    }
+
+    let _exit = ExitOnDrop(profiler_id);
+    for raw_event in raw_events {
+        observer(raw_event);
+    }
+}
+
+// Strings are written before the events and metadata that refer to them, and
+// trace contexts before the events that refer to them.
+fn flush_streams(
+    event_sink: &EventSink,
+    string_table: &StringTableBuilder,
+    metadata: &ProcessMetadataWriter,
+    trace_contexts: Option<&TraceContextWriter>,
+) {
+    string_table.flush();
+    metadata.flush();
+    if let Some(trace_contexts) = trace_contexts {
+        trace_contexts.flush();
+    }
+    // Flushes the backing storage as well, after all other streams.
+    event_sink.flush_buffer();
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let open_spans = self.open_spans.load(Ordering::Relaxed);
+        if open_spans > 0 {
+            error!(
+                "[WARNING] {} spans opened with `open_span` have never been closed and are \
+                 missing from the profile, spans that may outlive the profiler should be opened \
+                 with `open_ended_span`",
+                open_spans
+            );
+        }
+
+        if let Some(PeriodicFlush { stop, thread }) = self.periodic_flush.take() {
+            drop(stop);
+            thread.join().unwrap();
+        }
+
+        self.flush_signal_safe_events();
+
+        if let Some(ref mut buffered_events) = self.buffered_events {
+            let mut buffered_events = buffered_events.take();
+
+            // The sort is stable, so the events of a thread with the same
+            // timestamp stay in the order in which they have been recorded.
+            // The nesting depth stored in the thread id of interval events
+            // must not tell them apart from the other events of their thread.
+            let record_nesting_depth = self.nesting_depths.is_some();
+            buffered_events.sort_by_key(|(timestamp, raw_event)| {
+                let mut raw_event = *raw_event;
+                if record_nesting_depth && raw_event.is_interval() {
+                    raw_event.take_nesting_depth();
+                }
+                (*timestamp, raw_event.thread_id)
+            });
+
+            let timestamp_epoch_kind = self.timestamp_epoch_kind.get().copied();
+            for (_, raw_event) in buffered_events.iter() {
+                let addr = self
+                    .event_sink
+                    .write_raw_events(std::slice::from_ref(raw_event));
+                if raw_event.is_integer() && Some(raw_event.event_kind) == timestamp_epoch_kind {
+                    if let (Some(index), Some(addr)) = (&self.timestamp_epoch_index, addr) {
+                        index.record(addr);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Created by `Profiler::start_recording_interval_event_detached`.
+/// Must be passed to `finish_recording_interval_event` to record an
+/// "end" event.
+#[must_use]
+pub struct DetachedTiming {
+    pub(crate) event_id: EventId,
+    event_kind: StringId,
+    parent: Option<EventId>,
+    trace_context: Option<TraceContext>,
+    pub(crate) thread_id: u32,
+    /// `None` if nesting depths aren't recorded.
+    nesting_depth: Option<u32>,
+    wall_start: u64,
+    start_count: u64,
+    /// Whether an `OPEN_INTERVAL_EVENT_KIND` marker has been recorded for the
+    /// start, see `Profiler::open_ended_span`.
+    open_ended: bool,
+}
+
+/// Created by `Profiler::open_span` and `Profiler::open_ended_span`. Must be
+/// passed to `Profiler::close_span` to record an "end" event, nothing is
+/// recorded if it is dropped instead.
+#[must_use]
+pub struct SpanToken {
+    timing: DetachedTiming,
+}
+
+impl SpanToken {
+    /// The id of the thread that has opened the span, which the event is
+    /// recorded for.
+    #[inline]
+    pub fn thread_id(&self) -> u32 {
+        self.timing.thread_id
+    }
+}
+
+/// When dropped, this `TimingGuard` will record an "end" event in the
+/// `Profiler` it was created by.
+#[must_use]
+pub struct TimingGuard<'a> {
+    profiler: &'a Profiler,
+    timing: DetachedTiming,
+}
+
+impl<'a> Drop for TimingGuard<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        // Only the check for disabled profilers needs to be inlined into the
+        // code that is being profiled.
+        if self.profiler.is_enabled() {
+            self.record_end_event();
+        }
+    }
+}
+
+impl<'a> TimingGuard<'a> {
+    fn record_end_event(&self) {
+        let end = match self.timing.end(self.profiler) {
+            Some(end) => end,
+            None => return,
+        };
+
+        self.profiler
+            .check_timestamp_epoch(end.end_count, self.timing.thread_id);
+
+        match end.raw_events() {
+            [raw_event] => {
+                self.profiler
+                    .record_raw_event(raw_event, Some(end.end_count));
+            }
+            raw_events => self.profiler.record_raw_events(raw_events, end.end_count),
+        }
+    }
+}
+
+/// An interval event that has ended, see `DetachedTiming::end`.
+pub(crate) struct IntervalEnd {
+    raw_events: [RawEvent; 5],
+    len: usize,
+    /// The counter value the event is ordered by.
+    pub(crate) end_count: u64,
+}
+
+impl IntervalEnd {
+    /// The event and its markers, in the order in which they are written.
+    pub(crate) fn raw_events(&self) -> &[RawEvent] {
+        &self.raw_events[..self.len]
+    }
+}
+
+impl DetachedTiming {
+    /// Reads the counter for the end of the interval event and creates the
+    /// raw event for it, together with its `PARENT_EVENT_ID_EVENT_KIND`,
+    /// `WALL_TIME_EVENT_KIND`, `COUNTER_UNAVAILABLE_EVENT_KIND` and
+    /// `TRACE_CONTEXT_EVENT_KIND` markers, which have to be written as a
+    /// single unit. `None` if the event is shorter than
+    /// `ProfilerOptions::min_duration_nanos` or if its kind is skipped. The
+    /// caller has to check the timestamp epoch of `IntervalEnd::end_count`
+    /// before writing them.
+    pub(crate) fn end(&self, profiler: &Profiler) -> Option<IntervalEnd> {
+        if !profiler.records_event_kind(self.event_kind) {
+            return None;
+        }
+
+        let mut end_count = profiler.read_counter();
+        let wall_end = profiler.wall_time_now();
+        if self.nesting_depth.is_some() {
+            profiler.exit_interval(self.thread_id);
+        }
+
+        let mut start_count = self.start_count;
+        let counter_available = counters::is_available(start_count)
+            && counters::is_available(end_count)
+            && start_count <= end_count;
+
+        if counter_available {
+            // Nothing has been written for the "start" of the event, so
+            // sampling it out just means not writing anything at all. Open-
+            // ended spans have their start marker, which would otherwise make
+            // them look like they have never been closed.
+            if end_count.saturating_sub(start_count) < profiler.min_duration_nanos
+                && !self.open_ended
+            {
+                return None;
+            }
+        } else {
+            // The event is flagged instead of getting a bogus duration. It is
+            // placed at whichever of its timestamps is real, so it still ends
+            // up close to where it belongs.
+            end_count = if counters::is_available(start_count) {
+                start_count
+            } else if counters::is_available(end_count) {
+                end_count
+            } else {
+                0
+            };
+            start_count = end_count;
+        }
+
+        let mut raw_event = RawEvent::new_wrapping_interval(
+            self.event_kind,
+            self.event_id,
+            self.thread_id,
+            start_count,
+            end_count,
+        );
+        // The thread id has been checked by `enter_interval`.
+        if let Some(nesting_depth) = self.nesting_depth {
+            raw_event = raw_event.with_nesting_depth(nesting_depth);
+        }
+
+        let parent_marker = match (self.parent, profiler.parent_event_kind) {
+            (Some(parent), Some(parent_event_kind)) => Some(RawEvent::new_integer(
+                parent_event_kind,
+                parent,
+                self.thread_id,
+                0,
+            )),
+            _ => None,
+        };
+        let wall_time_marker = profiler.wall_time.as_ref().map(|&(_, event_kind)| {
+            let wall_nanos = wall_end.saturating_sub(self.wall_start);
+            RawEvent::new_integer(
+                event_kind,
+                self.event_id,
+                self.thread_id,
+                wall_nanos.min(MAX_SINGLE_VALUE),
+            )
+        });
+
+        let counter_unavailable_marker = if counter_available {
+            None
+        } else {
+            Some(RawEvent::new_integer(
+                profiler.counter_unavailable_event_kind(),
+                self.event_id,
+                self.thread_id,
+                0,
+            ))
+        };
+
+        // The trace context is written before the marker that refers to it,
+        // see `flush_streams`.
+        let trace_context_marker = match (self.trace_context, &profiler.trace_contexts) {
+            (Some(trace_context), Some((writer, event_kind))) => Some(RawEvent::new_integer(
+                *event_kind,
+                self.event_id,
+                self.thread_id,
+                writer.record(trace_context),
+            )),
+            _ => None,
+        };
+
+        // The event and its markers, in the order in which they are written.
+        let mut raw_events = [raw_event; 5];
+        let mut len = 0;
+        for event in parent_marker
+            .into_iter()
+            .chain(Some(raw_event))
+            .chain(wall_time_marker)
+            .chain(counter_unavailable_marker)
+            .chain(trace_context_marker)
+        {
+            raw_events[len] = event;
+            len += 1;
+        }
+
+        Some(IntervalEnd {
+            raw_events,
+            len,
+            end_count,
+        })
+    }
+}
+
+impl<'a> TimingGuard<'a> {
+    /// This method set a new `event_id` right before actually recording the
+    /// event.
+    #[inline]
+    pub fn finish_with_override_event_id(mut self, event_id: EventId) {
+        self.timing.event_id = event_id;
+        // Let's be explicit about it: Dropping the guard will record the event.
+        drop(self)
+    }
+}
+
+/// Created by `Profiler::start_interval`. When dropped, this `IntervalGuard`
+/// will record an "end" event for the thread it was created on.
+#[must_use]
+pub struct IntervalGuard<'a> {
+    timing_guard: TimingGuard<'a>,
+}
+
+impl<'a> IntervalGuard<'a> {
+    /// The id of the thread this guard was created on and will record the
+    /// "end" event for.
+    #[inline]
+    pub fn thread_id(&self) -> u32 {
+        self.timing_guard.timing.thread_id
+    }
+}
+
+/// Returns an id for the calling thread that is unique within the process.
+/// Ids are handed out sequentially, starting at 0, the first time a thread
+/// calls this function, so they can collide with thread ids that were chosen
+/// by the caller for other events.
+pub(crate) fn current_thread_id() -> u32 {
+    static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(0);
+
+    thread_local! {
+        static THREAD_ID: u32 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    }
+
+    THREAD_ID.with(|&thread_id| thread_id)
+}
+
+// Make sure that `Profiler` can be used in a multithreaded context
+fn _assert_bounds(span_token: SpanToken) {
+    assert_bounds_inner(&Profiler::new(""));
+    assert_bounds_inner(&span_token);
+    fn assert_bounds_inner<S: Sized + Send + Sync + 'static>(_: &S) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_header::{verify_top_level_file_header, FILE_HEADER_SIZE};
+    use crate::serialization::split_streams;
+    use crate::stringtable::StringComponent;
+    use crate::testing_clocks::{ScriptedClock, SteppingClock};
+
+    fn read_raw_events(path: &Path) -> Vec<RawEvent> {
+        let data = fs::read(path).unwrap();
+        let event_data = split_streams(&data[FILE_HEADER_SIZE..])
+            .remove(&PageTag::Events)
+            .unwrap();
+
+        event_data[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect()
+    }
+
+    #[test]
+    fn alloc_string_bytes_rejects_unescaped_separators() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("unescaped_separators");
+        let profiler = Profiler::new(&path_stem).unwrap();
+
+        assert!(profiler.alloc_string_bytes(&[b"a\x1Eb"]).is_err());
+        assert!(profiler.alloc_string_bytes(&[b"a", b"\x1E\xFF"]).is_err());
+        // An escaped escape byte doesn't escape the separator after it.
+        assert!(profiler.alloc_string_bytes(&[b"a\x1B\x1B\x1Eb"]).is_err());
+    }
+
+    #[test]
+    fn alloc_string_bytes_accepts_escaped_separators() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("escaped_separators");
+        let profiler = Profiler::new(&path_stem).unwrap();
+
+        assert!(profiler.alloc_string_bytes(&[b"a\x1B\x1E\xFF"]).is_ok());
+        // The escape byte may end the component before the separator.
+        assert!(profiler.alloc_string_bytes(&[b"a\x1B", b"\x1Eb"]).is_ok());
+        assert!(profiler
+            .alloc_string_bytes(&[&crate::event_id::escape_bytes(b"\x1E\x1B\x1E")])
+            .is_ok());
+    }
+
+    #[test]
+    fn stats_count_recorded_events_and_strings() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("stats");
+
+        let profiler =
+            Profiler::with_clock(&path_stem, Box::new(SteppingClock::default())).unwrap();
+        let initial = profiler.stats();
+        assert_eq!(initial.events, 0);
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.alloc_string("label");
+
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        profiler.record_integer_event(event_kind, event_id, 1, 42);
+
+        let stats = profiler.stats();
+        assert_eq!(
+            stats,
+            ProfilerStats {
+                events: 3,
+                event_bytes: 3 * std::mem::size_of::<RawEvent>() as u64,
+                strings: initial.strings + 3,
+                open_spans: 0,
+            }
+        );
+
+        drop(profiler);
+        assert_eq!(
+            read_raw_events(&segment_file_path(&path_stem, 0)).len() as u64,
+            stats.events
+        );
+    }
+
+    #[test]
+    fn custom_clock_determines_timestamps() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("custom_clock");
+
+        let profiler =
+            Profiler::with_clock(&path_stem, Box::new(SteppingClock::starting_at(100))).unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 2));
+        profiler.record_instant_event(event_kind, event_id, 3, Some(40 << 20));
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+
+        assert_eq!(raw_events.len(), 3);
+
+        assert!(raw_events[0].is_instant());
+        assert_eq!(raw_events[0].thread_id, 1);
+        assert_eq!(raw_events[0].start_value(), 100);
+
+        assert!(!raw_events[1].is_instant());
+        assert_eq!(raw_events[1].thread_id, 2);
+        assert_eq!(raw_events[1].start_value(), 110);
+        assert_eq!(raw_events[1].end_value(), 120);
+
+        assert!(raw_events[2].is_instant());
+        assert_eq!(raw_events[2].thread_id, 3);
+        assert_eq!(raw_events[2].instant_timestamp(), 130);
+        assert_eq!(raw_events[2].instant_payload(), Some(40 << 20));
+    }
+
+    #[test]
+    fn max_file_bytes_splits_events_across_segments() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("rotating");
+        let _ = fs::remove_file(segment_file_path(&path_stem, 3));
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                max_file_bytes: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        // Enough events to fill three pages, each of which ends up in a
+        // segment of its own because of the tiny size limit.
+        let event_count = 30_000;
+        for _ in 0..event_count {
+            profiler.record_instant_event(event_kind, event_id, 0, None);
+        }
+        drop(profiler);
+
+        assert!(!segment_file_path(&path_stem, 3).exists());
+
+        let mut timestamps = Vec::new();
+        for segment_index in 0..3 {
+            let path = segment_file_path(&path_stem, segment_index);
+
+            // Every segment has its own copy of the strings its events use.
+            let data = fs::read(&path).unwrap();
+            let mut streams = split_streams(&data[FILE_HEADER_SIZE..]);
+            assert_eq!(
+                streams[&PageTag::Segment],
+                segment_index.to_le_bytes()
+            );
+            assert!(streams.contains_key(&PageTag::StringIndex));
+            let string_data = streams.remove(&PageTag::StringData).unwrap();
+            let label_count = string_data
+                .windows(b"label".len())
+                .filter(|window| window == b"label")
+                .count();
+            assert_eq!(label_count, 1);
+
+            let raw_events = read_raw_events(&path);
+            assert!(!raw_events.is_empty());
+            timestamps.extend(raw_events.iter().map(|e| e.start_value()));
+        }
+
+        let expected: Vec<u64> = (0..event_count).map(|i| i * 10).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn min_duration_drops_short_intervals() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("sampled");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                min_duration_nanos: 20,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        // Every read of the clock advances it by 10ns, so the outer interval
+        // is 30ns long and the inner one 10ns.
+        {
+            let _outer = profiler.start_recording_interval_event(event_kind, event_id, 0);
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        }
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        drop(profiler);
+
+        let path = segment_file_path(&path_stem, 0);
+        let header = verify_top_level_file_header(&fs::read(&path).unwrap(), None).unwrap();
+        assert_eq!(header.flags, FILE_FLAG_SAMPLED);
+
+        let raw_events = read_raw_events(&path);
+
+        assert_eq!(raw_events.len(), 2);
+        assert_eq!(raw_events[0].start_value(), 0);
+        assert_eq!(raw_events[0].end_value(), 30);
+        assert!(raw_events[1].is_instant());
+    }
+
+    #[test]
+    fn event_kinds_skip_other_events() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("event_kinds");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                event_kinds: Some(vec!["Query".to_string()]),
+                record_nesting_depth: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let query = profiler.alloc_event_kind("Query");
+        let other = profiler.alloc_event_kind("Other");
+        // The same name, but not the `StringId` that the filter knows.
+        let unknown_query = profiler.alloc_string("Query");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        assert!(profiler.records_event_kind(query));
+        assert!(!profiler.records_event_kind(other));
+        assert!(!profiler.records_event_kind(unknown_query));
+
+        // Skipped events don't read the clock, so the outer interval is 30ns
+        // long and the inner one 10ns.
+        {
+            let _outer = profiler.start_recording_interval_event(query, event_id, 0);
+            let _skipped = profiler.start_recording_interval_event(other, event_id, 0);
+            drop(profiler.start_recording_interval_event(query, event_id, 0));
+        }
+        profiler.record_instant_event(other, event_id, 0, None);
+        profiler.record_instant_event(unknown_query, event_id, 0, Some(1));
+        profiler.record_integer_event(other, event_id, 0, 1);
+        profiler
+            .record_event_with_timestamps(other, event_id, 0, 40, 50)
+            .unwrap();
+        profiler.record_integer_event(query, event_id, 0, 2);
+        profiler.set_thread_name("main");
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+
+        assert_eq!(raw_events.len(), 4);
+        let mut inner = raw_events[0];
+        assert_eq!((inner.start_value(), inner.end_value()), (10, 20));
+        // The skipped interval doesn't count towards the depth either.
+        assert_eq!(inner.take_nesting_depth(), 1);
+        assert_eq!(raw_events[1].end_value(), 30);
+        assert_eq!(raw_events[2].value(), 2);
+        assert!(raw_events[3].is_instant());
+    }
+
+    #[test]
+    fn event_kind_filter_matches_string_ids() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("event_kind_filter");
+
+        let mut profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                event_kinds: Some(vec!["Other".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let query = profiler.alloc_string("Query");
+        let other = profiler.alloc_event_kind("Other");
+        profiler.set_event_kind_filter(vec![query]);
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        assert!(profiler.records_event_kind(query));
+        assert!(!profiler.records_event_kind(other));
+
+        profiler.record_integer_event(other, event_id, 0, 1);
+        profiler.record_integer_event(query, event_id, 0, 2);
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        assert_eq!(raw_events.len(), 1);
+        assert_eq!(raw_events[0].value(), 2);
+    }
+
+    #[test]
+    fn span_closed_on_another_thread() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("span_token");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                record_nesting_depth: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_event_kind("Future");
+        let event_id = EventId::from_label(profiler.alloc_string("poll"));
+
+        let open_thread_id = current_thread_id();
+        let token = profiler.open_span(event_kind, event_id);
+        assert_eq!(token.thread_id(), open_thread_id);
+
+        // The span is closed by another thread, like a future that is
+        // resumed by another worker of an executor.
+        let close_thread_id = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    profiler.close_span(token);
+                    current_thread_id()
+                })
+                .join()
+                .unwrap()
+        });
+        assert_ne!(close_thread_id, open_thread_id);
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        assert_eq!(raw_events.len(), 1);
+        let mut raw_event = raw_events[0];
+        assert!(raw_event.is_interval());
+        assert_eq!(raw_event.event_kind, event_kind);
+        assert_eq!(raw_event.event_id, event_id);
+        assert_eq!((raw_event.start_value(), raw_event.end_value()), (0, 10));
+        assert_eq!(raw_event.take_nesting_depth(), 0);
+        assert_eq!(raw_event.thread_id, open_thread_id);
+    }
+
+    #[test]
+    fn unclosed_spans_are_counted() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("unclosed_spans");
+
+        let profiler = Profiler::with_counter(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_event_kind("Future");
+        let event_id = EventId::from_label(profiler.alloc_string("poll"));
+
+        let closed = profiler.open_span(event_kind, event_id);
+        let forgotten = profiler.open_span(event_kind, event_id);
+        let open_ended = profiler.open_ended_span(event_kind, event_id);
+        assert_eq!(profiler.stats().open_spans, 2);
+
+        profiler.close_span(closed);
+        drop(forgotten);
+        assert_eq!(profiler.stats().open_spans, 1);
+
+        // Spans opened with `open_ended_span` are expected to outlive the
+        // profiler, so they aren't counted either way.
+        profiler.close_span(open_ended);
+        assert_eq!(profiler.stats().open_spans, 1);
+    }
+
+    #[test]
+    fn event_observer_sees_recorded_events() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("event_observer");
+
+        let profiler = Arc::new(
+            Profiler::with_counter(
+                &path_stem,
+                Counter::Clock(Box::new(SteppingClock::default())),
+            )
+            .unwrap(),
+        );
+
+        // The observer records an event on its own profiler for every event
+        // it sees, which must not be passed to it again.
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let observer_kind = profiler.alloc_string("Observer");
+        {
+            let observed = observed.clone();
+            let this = Arc::downgrade(&profiler);
+            profiler.set_event_observer(move |raw_event| {
+                observed.lock().push(*raw_event);
+                if let Some(profiler) = this.upgrade() {
+                    profiler.record_instant_event(observer_kind, raw_event.event_id, 7, None);
+                }
+            });
+        }
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 1));
+
+        // Batched events are observed once the batch is written.
+        let mut batch = profiler.batch();
+        batch.record_integer_event(event_kind, event_id, 2, 42);
+        assert_eq!(observed.lock().len(), 2);
+        drop(batch);
+        drop(profiler);
+
+        let observed = observed.lock().clone();
+        let kinds: Vec<_> = observed
+            .iter()
+            .map(|e| (e.thread_id, e.is_instant(), e.is_interval(), e.is_integer()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, true, false, false),
+                (1, false, true, false),
+                (2, false, false, true),
+            ]
+        );
+        assert!(observed.iter().all(|e| e.event_kind == event_kind));
+
+        // The events recorded by the observer are still written.
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        assert_eq!(raw_events.len(), 6);
+        let observer_events = raw_events
+            .iter()
+            .filter(|e| e.event_kind == observer_kind)
+            .count();
+        assert_eq!(observer_events, 3);
+    }
+
+    #[test]
+    fn observers_of_other_profilers_see_observed_events() {
+        let dir = Path::new("test-tmp").join("profiler");
+        let new_profiler = |name| {
+            Arc::new(
+                Profiler::with_counter(
+                    dir.join(name),
+                    Counter::Clock(Box::new(SteppingClock::default())),
+                )
+                .unwrap(),
+            )
+        };
+        let (a, b) = (new_profiler("observer_a"), new_profiler("observer_b"));
+
+        // Each observer records the events it sees on the other profiler, so
+        // the event recorded by the observer of `b` is one that the observer
+        // of `a` has recorded itself and doesn't see.
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        for (name, this, other) in [("a", &a, &b), ("b", &b, &a)] {
+            let observed = observed.clone();
+            let other = Arc::downgrade(other);
+            this.set_event_observer(move |raw_event| {
+                observed.lock().push((name, raw_event.thread_id));
+                if let Some(other) = other.upgrade() {
+                    other.record_instant_event(
+                        raw_event.event_kind,
+                        raw_event.event_id,
+                        raw_event.thread_id + 1,
+                        None,
+                    );
+                }
+            });
+        }
+
+        let event_kind = a.alloc_string("kind");
+        let event_id = EventId::from_label(a.alloc_string("label"));
+        a.record_instant_event(event_kind, event_id, 0, None);
+        drop((a, b));
+
+        assert_eq!(*observed.lock(), [("a", 0), ("b", 1)]);
+        let thread_ids = |name| {
+            read_raw_events(&segment_file_path(&dir.join(name), 0))
+                .iter()
+                .map(|e| e.thread_id)
+                .collect::<Vec<_>>()
+        };
+        // The events are observed before they are written.
+        assert_eq!(thread_ids("observer_a"), [2, 0]);
+        assert_eq!(thread_ids("observer_b"), [1]);
+    }
+
+    #[test]
+    fn record_wall_time_after_interval_events() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("wall_time");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::Clock(Box::new(SteppingClock::default())),
+            ProfilerOptions {
+                record_explicit_parents: true,
+                record_wall_time: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        let parent = EventId::from_label(profiler.alloc_string("parent"));
+
+        {
+            let _outer = profiler.start_recording_interval_event(event_kind, event_id, 0);
+            std::thread::sleep(Duration::from_millis(1));
+            drop(
+                profiler
+                    .start_recording_interval_event_with_parent(event_kind, event_id, parent, 0),
+            );
+        }
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        drop(profiler);
+
+        let path = segment_file_path(&path_stem, 0);
+        let header = verify_top_level_file_header(&fs::read(&path).unwrap(), None).unwrap();
+        assert_eq!(
+            header.flags,
+            FILE_FLAG_EXPLICIT_PARENTS | FILE_FLAG_WALL_TIME
+        );
+
+        let raw_events = read_raw_events(&path);
+        assert_eq!(raw_events.len(), 6);
+
+        // The inner event: its parent, the event itself and its wall time.
+        assert!(raw_events[0].is_integer());
+        assert_eq!(raw_events[0].event_id, parent);
+        assert!(raw_events[1].is_interval());
+        assert!(raw_events[2].is_integer());
+        assert_eq!(raw_events[2].event_id, event_id);
+
+        // The outer event, whose wall time includes the sleep, unlike its
+        // counter value.
+        assert!(raw_events[3].is_interval());
+        assert_eq!(raw_events[3].end_value() - raw_events[3].start_value(), 30);
+        assert!(raw_events[4].is_integer());
+        assert_ne!(raw_events[4].event_kind, raw_events[0].event_kind);
+        assert!(raw_events[4].value() >= 1_000_000);
+        assert!(raw_events[4].value() >= raw_events[2].value());
+
+        // Instant events don't have a wall time.
+        assert!(raw_events[5].is_instant());
+    }
+
+    #[test]
+    fn record_trace_context_in_separate_stream() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("trace_context");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(WallTime::new()),
+            ProfilerOptions {
+                record_wall_time: true,
+                record_trace_context: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        let trace_context = TraceContext {
+            trace_id: 0xfeed_0000_0000_0000_0000_0000_0000_beef,
+            span_id: 42,
+        };
+
+        drop(profiler.start_recording_interval_event_with_trace_context(
+            event_kind,
+            event_id,
+            trace_context,
+            0,
+        ));
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        drop(profiler);
+
+        let path = segment_file_path(&path_stem, 0);
+        let data = fs::read(&path).unwrap();
+        let header = verify_top_level_file_header(&data, None).unwrap();
+        assert_eq!(header.flags, FILE_FLAG_WALL_TIME | FILE_FLAG_TRACE_CONTEXT);
+
+        // The first event is followed by its wall time and its trace context,
+        // the second one only by its wall time.
+        let raw_events = read_raw_events(&path);
+        assert_eq!(raw_events.len(), 5);
+        assert!(raw_events[0].is_interval());
+        assert!(raw_events[2].is_integer());
+        assert_eq!(raw_events[2].event_id, event_id);
+        assert_eq!(raw_events[2].value(), 0);
+        assert_ne!(raw_events[2].event_kind, raw_events[1].event_kind);
+        assert!(raw_events[3].is_interval());
+        assert_eq!(raw_events[4].event_kind, raw_events[1].event_kind);
+
+        let mut streams = split_streams(&data[FILE_HEADER_SIZE..]);
+        let trace_contexts =
+            crate::decode_trace_contexts(&streams.remove(&PageTag::TraceContext).unwrap(), None)
+                .unwrap();
+        assert_eq!(trace_contexts.entries, vec![trace_context]);
+    }
+
+    #[test]
+    fn trace_context_is_ignored_unless_enabled() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("no_trace_context");
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        drop(profiler.start_recording_interval_event_with_trace_context(
+            event_kind,
+            event_id,
+            TraceContext {
+                trace_id: 1,
+                span_id: 2,
+            },
+            0,
+        ));
+        drop(profiler);
+
+        let path = segment_file_path(&path_stem, 0);
+        assert_eq!(read_raw_events(&path).len(), 1);
+        let data = fs::read(&path).unwrap();
+        assert!(!split_streams(&data[FILE_HEADER_SIZE..]).contains_key(&PageTag::TraceContext));
+    }
+
+    #[test]
+    fn disabled_profiler_records_nothing() {
+        let profiler = Profiler::disabled();
+        assert!(!profiler.is_enabled());
+
+        let event_kind = profiler.alloc_event_kind("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        assert_eq!(event_kind, StringId::INVALID);
+        assert_eq!(event_id, EventId::INVALID);
+        let initial_strings = profiler.stats().strings;
+
+        profiler.set_thread_name("main");
+        profiler.record_metadata("key", "value");
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+        profiler.record_integer_event(event_kind, event_id, 0, 42);
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        drop(profiler.start_interval(event_kind, event_id));
+        profiler.finish_recording_interval_event(
+            profiler.start_recording_interval_event_detached(event_kind, event_id, 0),
+        );
+        profiler.register_signal_safe_thread(16);
+        assert!(!profiler.record_instant_raw(event_kind, event_id, 0));
+        profiler.flush();
+
+        assert_eq!(
+            profiler.stats(),
+            ProfilerStats {
+                events: 0,
+                event_bytes: 0,
+                strings: initial_strings,
+                open_spans: 0,
+            }
+        );
+
+        let profiler = profiler.into_ref();
+        drop(profiler.start_interval(event_kind, event_id));
+        assert_eq!(profiler.profiler().stats().events, 0);
+    }
+
+    #[test]
+    fn flag_interval_events_without_counter_values() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("counter_unavailable");
+
+        let values = vec![10, 20, 30, counters::COUNTER_UNAVAILABLE, 60, 70];
+        let profiler =
+            Profiler::with_clock(&path_stem, Box::new(ScriptedClock::new(values))).unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        for _ in 0..3 {
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        }
+        let counter_unavailable_kind = profiler.alloc_event_kind(COUNTER_UNAVAILABLE_EVENT_KIND);
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        let summary: Vec<_> = raw_events
+            .iter()
+            .map(|raw_event| {
+                if raw_event.is_interval() {
+                    (raw_event.start_value(), raw_event.end_value())
+                } else {
+                    assert!(raw_event.is_integer());
+                    assert_eq!(raw_event.event_kind, counter_unavailable_kind);
+                    assert_eq!(raw_event.event_id, event_id);
+                    (u64::MAX, raw_event.value())
+                }
+            })
+            .collect();
+
+        // The event whose end couldn't be read is placed at its start and
+        // flagged.
+        assert_eq!(summary, vec![(10, 20), (30, 30), (u64::MAX, 0), (60, 70)]);
+    }
+
+    #[test]
+    fn clock_going_backwards_is_clamped() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("clock_went_backwards");
+
+        let values = vec![10, 50, 20, 30, 40, 60, 55, 70];
+        let profiler =
+            Profiler::with_clock(&path_stem, Box::new(ScriptedClock::new(values))).unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        for _ in 0..4 {
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 0));
+        }
+        let went_backwards_kind = profiler.alloc_event_kind(CLOCK_WENT_BACKWARDS_EVENT_KIND);
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        let summary: Vec<_> = raw_events
+            .iter()
+            .map(|raw_event| {
+                if raw_event.is_interval() {
+                    assert!(raw_event.end_value() >= raw_event.start_value());
+                    (true, raw_event.start_value(), raw_event.end_value())
+                } else {
+                    assert!(raw_event.is_instant());
+                    assert_eq!(raw_event.event_kind, went_backwards_kind);
+                    (
+                        false,
+                        raw_event.instant_timestamp(),
+                        raw_event.instant_payload().unwrap(),
+                    )
+                }
+            })
+            .collect();
+
+        // Every read that goes backwards is marked with how far it went, and
+        // the time stands still at 50 until the clock has caught up again.
+        assert_eq!(
+            summary,
+            vec![
+                (true, 10, 50),
+                (false, 50, 30),
+                (true, 50, 50),
+                (true, 50, 60),
+                (false, 60, 5),
+                (true, 60, 70),
+            ]
+        );
+
+        // All markers share the same strings.
+        let markers: Vec<_> = raw_events.iter().filter(|e| e.is_instant()).collect();
+        assert_eq!(markers[0].event_id, markers[1].event_id);
+    }
+
+    #[test]
+    fn clock_is_clamped_per_profiler() {
+        let dir = Path::new("test-tmp").join("profiler");
+        let clamped = Profiler::with_clock(
+            dir.join("clamped_per_profiler"),
+            Box::new(ScriptedClock::new(vec![10, 50, 20, 30])),
+        )
+        .unwrap();
+        let other = Profiler::with_clock(
+            dir.join("clamped_per_profiler_other"),
+            Box::new(ScriptedClock::new(vec![100, 110])),
+        )
+        .unwrap();
+
+        let event_kind = clamped.alloc_string("kind");
+        let event_id = EventId::from_label(clamped.alloc_string("label"));
+        let other_event_kind = other.alloc_string("kind");
+        let other_event_id = EventId::from_label(other.alloc_string("label"));
+
+        // Reading the other profiler's clock in between doesn't make the
+        // thread forget the time it has read from the first one.
+        drop(clamped.start_recording_interval_event(event_kind, event_id, 0));
+        drop(other.start_recording_interval_event(other_event_kind, other_event_id, 0));
+        drop(clamped.start_recording_interval_event(event_kind, event_id, 0));
+        drop(clamped);
+        drop(other);
+
+        let raw_events = read_raw_events(&segment_file_path(&dir.join("clamped_per_profiler"), 0));
+        let intervals: Vec<_> = raw_events
+            .iter()
+            .filter(|raw_event| raw_event.is_interval())
+            .map(|raw_event| (raw_event.start_value(), raw_event.end_value()))
+            .collect();
+        assert_eq!(intervals, vec![(10, 50), (50, 50)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "too large for storing the nesting depth")]
+    fn nesting_depth_thread_id_is_checked_at_start() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("nesting_depth_thread_id");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(crate::counters::WallTime::new()),
+            ProfilerOptions {
+                record_nesting_depth: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        let _detached = profiler.start_recording_interval_event_detached(
+            event_kind,
+            event_id,
+            MAX_NESTING_DEPTH_THREAD_ID + 1,
+        );
+    }
+
+    #[test]
+    fn record_nesting_depth() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("nesting_depth");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(crate::counters::WallTime::new()),
+            ProfilerOptions {
+                record_nesting_depth: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        {
+            let _outer = profiler.start_recording_interval_event(event_kind, event_id, 1);
+            let detached =
+                profiler.start_recording_interval_event_detached(event_kind, event_id, 1);
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 1));
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 2));
+            profiler.finish_recording_interval_event(detached);
+        }
+        drop(profiler.start_recording_interval_event(event_kind, event_id, 1));
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+        drop(profiler);
+
+        let path = segment_file_path(&path_stem, 0);
+        let header = verify_top_level_file_header(&fs::read(&path).unwrap(), None).unwrap();
+        assert_eq!(header.flags, FILE_FLAG_NESTING_DEPTH);
+
+        let depths: Vec<_> = read_raw_events(&path)
+            .into_iter()
+            .map(|mut raw_event| {
+                let depth = if raw_event.is_interval() {
+                    Some(raw_event.take_nesting_depth())
+                } else {
+                    None
+                };
+                (raw_event.thread_id, depth)
+            })
+            .collect();
+
+        assert_eq!(
+            depths,
+            vec![
+                (1, Some(2)),
+                (2, Some(0)),
+                (1, Some(1)),
+                (1, Some(0)),
+                (1, Some(0)),
+                (1, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn stable_event_order() {
+        let record = |run: u32| {
+            let path_stem = Path::new("test-tmp")
+                .join("profiler")
+                .join(format!("stable_event_order_{}", run));
+
+            let profiler = Arc::new(
+                Profiler::with_options(
+                    &path_stem,
+                    Counter::Clock(Box::new(SteppingClock::per_thread())),
+                    ProfilerOptions {
+                        stable_event_order: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap(),
+            );
+
+            let event_kind = profiler.alloc_string("kind");
+            let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+            let threads: Vec<_> = (0..4)
+                .map(|thread_id| {
+                    let profiler = profiler.clone();
+                    std::thread::spawn(move || {
+                        for i in 0..1000 {
+                            let _outer = profiler
+                                .start_recording_interval_event(event_kind, event_id, thread_id);
+                            profiler.record_instant_event(event_kind, event_id, thread_id, None);
+                            profiler.record_integer_event(event_kind, event_id, thread_id, i);
+                        }
+                    })
+                })
+                .collect();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            drop(profiler);
+
+            let data = fs::read(segment_file_path(&path_stem, 0)).unwrap();
+            split_streams(&data[FILE_HEADER_SIZE..])
+                .remove(&PageTag::Events)
+                .unwrap()
+        };
+
+        let events = record(0);
+        assert_eq!(events, record(1));
+
+        let raw_events: Vec<_> = events[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect();
+        assert_eq!(raw_events.len(), 4 * 3000);
+
+        // The first interval of each thread starts at 0ns and ends at 20ns,
+        // the instant within it is recorded at 10ns and the integer event
+        // right after it. The instant in the second interval follows at 40ns.
+        let timestamps: Vec<_> = raw_events[..16]
+            .iter()
+            .map(|e| {
+                if e.is_integer() {
+                    None
+                } else if e.is_instant() {
+                    Some(e.instant_timestamp())
+                } else {
+                    Some(e.end_value())
+                }
+            })
+            .collect();
+        let thread_ids: Vec<_> = raw_events[..16].iter().map(|e| e.thread_id).collect();
+        assert_eq!(
+            thread_ids,
+            vec![0, 0, 1, 1, 2, 2, 3, 3, 0, 1, 2, 3, 0, 0, 1, 1]
+        );
+        assert_eq!(&timestamps[..8], &[Some(10), None].repeat(4)[..]);
+        assert_eq!(&timestamps[8..12], &[Some(20); 4]);
+        assert_eq!(&timestamps[12..], &[Some(40), None].repeat(2)[..]);
+    }
+
+    #[test]
+    fn set_thread_name_records_instant_event() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("thread_name");
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        profiler.set_thread_name("main");
+        let thread_id = profiler.start_interval(event_kind, event_id).thread_id();
+        drop(profiler);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+
+        assert_eq!(raw_events.len(), 2);
+        assert!(raw_events[0].is_instant());
+        assert_eq!(raw_events[0].thread_id, thread_id);
+        assert_ne!(raw_events[0].event_kind, event_kind);
+    }
+
+    #[test]
+    fn record_instant_raw_from_registered_threads() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("signal_safe");
+
+        let profiler = Arc::new(
+            Profiler::with_clock(&path_stem, Box::new(SteppingClock::starting_at(100))).unwrap(),
+        );
+        let event_kind = profiler.alloc_string("Sample");
+        let event_id = EventId::from_label(profiler.alloc_string("sample"));
+
+        // Threads that haven't been registered can't record anything.
+        assert!(!profiler.record_instant_raw(event_kind, event_id, 0));
+
+        profiler.register_signal_safe_thread(2);
+        let thread_id = current_thread_id();
+        assert!(profiler.record_instant_raw(event_kind, event_id, profiler.current_timestamp()));
+        assert!(profiler.record_instant_raw(event_kind, event_id, 500));
+        assert!(!profiler.record_instant_raw(event_kind, event_id, 600));
+        assert_eq!(profiler.dropped_signal_safe_events(), 1);
+
+        // Nothing is written before the buffers are flushed.
+        drop(profiler.start_recording_interval_event(event_kind, event_id, thread_id));
+        profiler.flush_signal_safe_events();
+        assert!(profiler.record_instant_raw(event_kind, event_id, 700));
+
+        let other_thread_id = {
+            let profiler = profiler.clone();
+            std::thread::spawn(move || {
+                profiler.register_signal_safe_thread(1);
+                assert!(profiler.record_instant_raw(event_kind, event_id, 800));
+                current_thread_id()
+            })
+            .join()
+            .unwrap()
+        };
+
+        // The remaining events are written when the profiler is dropped.
+        drop(Arc::try_unwrap(profiler).ok().unwrap());
+
+        let events: Vec<_> = read_raw_events(&segment_file_path(&path_stem, 0))
+            .iter()
+            .map(|raw_event| {
+                if raw_event.is_instant() {
+                    (raw_event.thread_id, raw_event.instant_timestamp())
+                } else {
+                    (raw_event.thread_id, raw_event.end_value())
+                }
+            })
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (thread_id, 120),
+                (thread_id, 100),
+                (thread_id, 500),
+                (thread_id, 700),
+                (other_thread_id, 800),
+            ]
+        );
+    }
+
+    #[test]
+    fn record_instant_raw_with_several_profilers() {
+        let dir = Path::new("test-tmp").join("profiler");
+        let path_stems = [
+            dir.join("signal_safe_first"),
+            dir.join("signal_safe_second"),
+        ];
+
+        let profilers: Vec<_> = path_stems
+            .iter()
+            .map(|path_stem| {
+                Profiler::with_clock(path_stem, Box::new(SteppingClock::default())).unwrap()
+            })
+            .collect();
+        let events: Vec<_> = profilers
+            .iter()
+            .map(|profiler| {
+                let event_kind = profiler.alloc_string("Sample");
+                (event_kind, EventId::from_label(event_kind))
+            })
+            .collect();
+
+        for profiler in &profilers {
+            profiler.register_signal_safe_thread(4);
+        }
+        for (timestamp, (profiler, &(event_kind, event_id))) in
+            profilers.iter().zip(&events).enumerate()
+        {
+            assert!(profiler.record_instant_raw(event_kind, event_id, timestamp as u64));
+        }
+        drop(profilers);
+
+        for (timestamp, path_stem) in path_stems.iter().enumerate() {
+            let timestamps: Vec<_> = read_raw_events(&segment_file_path(path_stem, 0))
+                .iter()
+                .map(|raw_event| raw_event.instant_timestamp())
+                .collect();
+            assert_eq!(timestamps, vec![timestamp as u64]);
+        }
+    }
+
+    #[test]
+    fn deduplicate_strings() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("deduplicate_strings");
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(crate::counters::WallTime::new()),
+            ProfilerOptions {
+                deduplicate_strings: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let id = profiler.alloc_string("some_arg");
+        assert_eq!(profiler.alloc_string("some_arg"), id);
+        assert_eq!(
+            profiler.alloc_string(&[StringComponent::Value("some_arg")][..]),
+            id
+        );
+        assert_ne!(profiler.alloc_string("other_arg"), id);
+        assert_ne!(profiler.alloc_string("some_arg2"), id);
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let id = profiler.alloc_string("some_arg");
+        assert_ne!(profiler.alloc_string("some_arg"), id);
+    }
+
+    #[test]
+    fn record_metadata_writes_separate_stream() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("metadata");
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        profiler.record_metadata(crate::rustc::METADATA_KEY_OPT_LEVEL, "3");
+        drop(profiler);
+
+        let data = fs::read(segment_file_path(&path_stem, 0)).unwrap();
+        let mut streams = split_streams(&data[FILE_HEADER_SIZE..]);
+        let metadata =
+            crate::decode_process_metadata(&streams.remove(&PageTag::Metadata).unwrap(), None)
+                .unwrap();
+
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata["opt-level"], "3");
+        assert!(read_raw_events(&segment_file_path(&path_stem, 0)).is_empty());
+    }
+
+    #[test]
+    fn no_metadata_stream_without_metadata() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("no_metadata");
+
+        drop(Profiler::new(&path_stem).unwrap());
+
+        let data = fs::read(segment_file_path(&path_stem, 0)).unwrap();
+        assert!(!split_streams(&data[FILE_HEADER_SIZE..]).contains_key(&PageTag::Metadata));
+    }
+
+    #[test]
+    fn flush_writes_partial_pages() {
+        let path_stem = Path::new("test-tmp").join("profiler").join("flush");
+        let path = segment_file_path(&path_stem, 0);
+
+        let profiler = Profiler::new(&path_stem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        for thread_id in 0..3 {
+            profiler.record_instant_event(event_kind, event_id, thread_id, None);
+        }
+        profiler.record_metadata("key", "value");
+
+        // Nothing fills a page, so nothing has been written yet.
+        let data = fs::read(&path).unwrap();
+        assert!(split_streams(&data[FILE_HEADER_SIZE..]).is_empty());
+
+        profiler.flush();
+        let data = fs::read(&path).unwrap();
+        let streams = split_streams(&data[FILE_HEADER_SIZE..]);
+        for page_tag in [
+            PageTag::Events,
+            PageTag::StringData,
+            PageTag::StringIndex,
+            PageTag::Metadata,
+        ] {
+            assert!(streams.contains_key(&page_tag), "{:?}", page_tag);
+        }
+        assert_eq!(read_raw_events(&path).len(), 3);
+
+        profiler.record_instant_event(event_kind, event_id, 3, None);
+        drop(profiler);
+
+        let thread_ids: Vec<_> = read_raw_events(&path)
+            .iter()
+            .map(|raw_event| raw_event.thread_id)
+            .collect();
+        assert_eq!(thread_ids, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn flush_flushes_the_page_sink() {
+        /// Counts how often it has been flushed, and how many pages it had
+        /// received by then.
+        struct FlushCountingSink(Arc<Mutex<(usize, Vec<usize>)>>);
+
+        impl PageSink for FlushCountingSink {
+            fn write_file_header(
+                &mut self,
+                _header: &[u8],
+            ) -> Result<(), Box<dyn Error + Send + Sync>> {
+                Ok(())
+            }
+
+            fn write_page(
+                &mut self,
+                _page_tag: PageTag,
+                _contents: &[u8],
+            ) -> Result<(), Box<dyn Error + Send + Sync>> {
+                self.0.lock().0 += 1;
+                Ok(())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                let mut state = self.0.lock();
+                let pages = state.0;
+                state.1.push(pages);
+                Ok(())
+            }
+        }
+
+        let state = Arc::new(Mutex::new((0, Vec::new())));
+        let profiler = Profiler::with_sink(FlushCountingSink(state.clone())).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        profiler.record_instant_event(event_kind, EventId::from_label(event_kind), 0, None);
+
+        profiler.flush();
+        let (pages, ref flushes) = *state.lock();
+        // The sink is flushed once, after the events and strings.
+        assert_eq!(flushes, &[pages]);
+        assert!(pages >= 3);
+    }
+
+    #[test]
+    fn flush_interval_writes_pages_in_the_background() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler")
+            .join("flush_interval");
+        let path = segment_file_path(&path_stem, 0);
+
+        let profiler = Profiler::with_options(
+            &path_stem,
+            Counter::WallTime(crate::counters::WallTime::new()),
+            ProfilerOptions {
+                flush_interval: Some(Duration::from_millis(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.record_instant_event(event_kind, event_id, 0, None);
+
+        let started = std::time::Instant::now();
+        loop {
+            let data = fs::read(&path).unwrap();
+            if split_streams(&data[FILE_HEADER_SIZE..]).contains_key(&PageTag::Events) {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read_raw_events(&path).len(), 1);
+
+        profiler.record_instant_event(event_kind, event_id, 1, None);
+        drop(profiler);
+        assert_eq!(read_raw_events(&path).len(), 2);
+    }
 }
@@ -0,0 +1,300 @@
+use crate::event_id::EventId;
+use crate::profiler::{current_thread_id, DetachedTiming, Profiler};
+use crate::stringtable::{SerializableString, StringId};
+use crate::trace_context::TraceContext;
+use std::sync::Arc;
+
+/// A shared handle to a [`Profiler`], created with [`Profiler::into_ref`].
+/// Cloning it is cheap, so it can be stored in structs and moved to other
+/// threads instead of passing `&Profiler` around. The profiler is dropped,
+/// and thereby finishes writing the profile, once the last handle and the
+/// last guard created from one are gone.
+///
+/// The common recording methods of `Profiler` are mirrored here. The guards
+/// of interval events hold a handle of their own, so they don't borrow the
+/// handle they have been created from. Everything else is available through
+/// [`ProfilerRef::profiler`].
+#[derive(Clone)]
+pub struct ProfilerRef(Arc<Profiler>);
+
+impl Profiler {
+    /// Turns the profiler into a handle that can be cloned, see
+    /// [`ProfilerRef`].
+    pub fn into_ref(self) -> ProfilerRef {
+        ProfilerRef(Arc::new(self))
+    }
+}
+
+impl From<Arc<Profiler>> for ProfilerRef {
+    fn from(profiler: Arc<Profiler>) -> ProfilerRef {
+        ProfilerRef(profiler)
+    }
+}
+
+impl ProfilerRef {
+    #[inline(always)]
+    pub fn profiler(&self) -> &Profiler {
+        &self.0
+    }
+
+    #[inline(always)]
+    pub fn alloc_string<STR: SerializableString + ?Sized>(&self, s: &STR) -> StringId {
+        self.0.alloc_string(s)
+    }
+
+    /// See `Profiler::alloc_event_kind`.
+    pub fn alloc_event_kind(&self, name: &str) -> StringId {
+        self.0.alloc_event_kind(name)
+    }
+
+    /// See `Profiler::set_thread_name`.
+    pub fn set_thread_name(&self, name: &str) {
+        self.0.set_thread_name(name)
+    }
+
+    /// See `Profiler::record_instant_event`.
+    pub fn record_instant_event(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: Option<u64>,
+    ) {
+        self.0
+            .record_instant_event(event_kind, event_id, thread_id, value)
+    }
+
+    /// See `Profiler::record_integer_event`.
+    pub fn record_integer_event(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        value: u64,
+    ) {
+        self.0
+            .record_integer_event(event_kind, event_id, thread_id, value)
+    }
+
+    /// Like `Profiler::start_recording_interval_event`, but the returned
+    /// guard holds its own handle to the profiler.
+    #[inline]
+    pub fn start_recording_interval_event(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+    ) -> OwnedTimingGuard {
+        OwnedTimingGuard {
+            timing: Some(
+                self.0
+                    .start_recording_interval_event_detached(event_kind, event_id, thread_id),
+            ),
+            profiler: self.clone(),
+        }
+    }
+
+    /// Like `Profiler::start_recording_interval_event_with_parent`, but the
+    /// returned guard holds its own handle to the profiler.
+    #[inline]
+    pub fn start_recording_interval_event_with_parent(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        parent: EventId,
+        thread_id: u32,
+    ) -> OwnedTimingGuard {
+        OwnedTimingGuard {
+            timing: Some(self.0.start_recording_interval_event_detached_with_parent(
+                event_kind, event_id, parent, thread_id,
+            )),
+            profiler: self.clone(),
+        }
+    }
+
+    /// Like `Profiler::start_recording_interval_event_with_trace_context`, but
+    /// the returned guard holds its own handle to the profiler.
+    #[inline]
+    pub fn start_recording_interval_event_with_trace_context(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        trace_context: TraceContext,
+        thread_id: u32,
+    ) -> OwnedTimingGuard {
+        OwnedTimingGuard {
+            timing: Some(
+                self.0
+                    .start_recording_interval_event_detached_with_trace_context(
+                        event_kind,
+                        event_id,
+                        trace_context,
+                        thread_id,
+                    ),
+            ),
+            profiler: self.clone(),
+        }
+    }
+
+    /// Like `Profiler::start_interval`, but the returned guard holds its own
+    /// handle to the profiler. The "end" event is recorded for the thread
+    /// this is called on, including when the guard is dropped during
+    /// unwinding or on another thread.
+    #[inline]
+    pub fn start_interval(&self, event_kind: StringId, event_id: EventId) -> OwnedTimingGuard {
+        self.start_recording_interval_event(event_kind, event_id, current_thread_id())
+    }
+
+    /// Installs a panic hook that flushes the profiler (see
+    /// `Profiler::flush`) and then runs the hook that has been installed
+    /// before, so that the events recorded up to a panic make it into the
+    /// profile even if the process then aborts, e.g. with `panic = "abort"`.
+    /// The hook doesn't keep the profiler alive, once it has been dropped the
+    /// hook only runs the previous one.
+    pub fn flush_on_panic(&self) {
+        let profiler = Arc::downgrade(&self.0);
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Some(profiler) = profiler.upgrade() {
+                profiler.flush();
+            }
+            previous_hook(info);
+        }));
+    }
+}
+
+/// Created by `ProfilerRef::start_recording_interval_event` and
+/// `ProfilerRef::start_interval`. Like a `TimingGuard`, it records the "end"
+/// event when it is dropped, but it can outlive the handle it has been
+/// created from.
+#[must_use]
+pub struct OwnedTimingGuard {
+    profiler: ProfilerRef,
+    // Only `None` once the event has been recorded.
+    timing: Option<DetachedTiming>,
+}
+
+impl OwnedTimingGuard {
+    /// The id of the thread the "end" event will be recorded for.
+    #[inline]
+    pub fn thread_id(&self) -> u32 {
+        self.timing.as_ref().unwrap().thread_id
+    }
+
+    /// This method set a new `event_id` right before actually recording the
+    /// event.
+    #[inline]
+    pub fn finish_with_override_event_id(mut self, event_id: EventId) {
+        if let Some(ref mut timing) = self.timing {
+            timing.event_id = event_id;
+        }
+    }
+}
+
+impl Drop for OwnedTimingGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(timing) = self.timing.take() {
+            self.profiler.0.finish_recording_interval_event(timing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_header::{segment_file_path, FILE_HEADER_SIZE};
+    use crate::serialization::{split_streams, PageTag};
+    use crate::RawEvent;
+    use std::fs;
+    use std::path::Path;
+
+    fn read_raw_events(path: &Path) -> Vec<RawEvent> {
+        let data = fs::read(path).unwrap();
+        let event_data = split_streams(&data[FILE_HEADER_SIZE..])
+            .remove(&PageTag::Events)
+            .unwrap();
+
+        event_data[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect()
+    }
+
+    #[test]
+    fn guards_outlive_the_handle_they_are_created_from() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler_ref")
+            .join("owned_guards");
+
+        let profiler = Profiler::new(&path_stem).unwrap().into_ref();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        let other_id = EventId::from_label(profiler.alloc_string("other"));
+
+        let threads: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let profiler = profiler.clone();
+                std::thread::spawn(move || {
+                    profiler.record_instant_event(event_kind, event_id, thread_id, None);
+                    profiler.start_recording_interval_event(event_kind, event_id, thread_id)
+                })
+            })
+            .collect();
+
+        let overridden = profiler.start_interval(event_kind, event_id);
+        let main_thread_id = overridden.thread_id();
+        drop(profiler);
+
+        // The guards keep the profiler alive, it is only dropped together
+        // with the last one.
+        let guards: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        overridden.finish_with_override_event_id(other_id);
+        drop(guards);
+
+        let raw_events = read_raw_events(&segment_file_path(&path_stem, 0));
+        assert_eq!(raw_events.len(), 9);
+
+        let intervals: Vec<_> = raw_events
+            .iter()
+            .filter(|raw_event| raw_event.is_interval())
+            .map(|raw_event| (raw_event.thread_id, raw_event.event_id))
+            .collect();
+        assert_eq!(intervals.len(), 5);
+        assert!(intervals.contains(&(main_thread_id, other_id)));
+        for thread_id in 0..4 {
+            assert!(intervals.contains(&(thread_id, event_id)));
+        }
+    }
+
+    #[test]
+    fn flush_on_panic() {
+        let path_stem = Path::new("test-tmp")
+            .join("profiler_ref")
+            .join("flush_on_panic");
+        let path = segment_file_path(&path_stem, 0);
+
+        let profiler = Profiler::new(&path_stem).unwrap().into_ref();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+        profiler.flush_on_panic();
+
+        let panicking = profiler.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = panicking.start_interval(event_kind, event_id);
+            panicking.record_instant_event(event_kind, event_id, 0, None);
+            panic!("flush_on_panic test");
+        })
+        .join();
+        assert!(result.is_err());
+
+        // The instant event has been flushed by the hook, before the guard
+        // has been dropped during unwinding and recorded the interval event.
+        let raw_events = read_raw_events(&path);
+        assert_eq!(raw_events.len(), 1);
+        assert!(raw_events[0].is_instant());
+
+        drop(profiler);
+        assert_eq!(read_raw_events(&path).len(), 2);
+    }
+}
@@ -5,7 +5,7 @@ use std::convert::TryInto;
 
 /// `RawEvent` is how events are stored on-disk. If you change this struct,
 /// make sure that you increment `file_header::CURRENT_FILE_FORMAT_VERSION`.
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 #[repr(C)]
 pub struct RawEvent {
     pub event_kind: StringId,
@@ -22,6 +22,10 @@ pub struct RawEvent {
     // Payload2 is 0xFFFF_FFFF_FFFF
     // VVVVVVVVVVVVVVVV1111111111111111VVVVVVV11111111
     // [payload1_lower][payload2_lower][payloads_upper]
+    // Instant with a value:
+    // The highest bit (`INSTANT_VALUE_FLAG`) of both payloads is set, the
+    // remaining bits of payload 1 are the timestamp and those of payload 2
+    // are the value
     // Integer:
     // Payload2 is 0xFFFF_FFFF_FFFE
     // VVVVVVVVVVVVVVVV1111111111111111VVVVVVV11111110
@@ -32,9 +36,14 @@ pub struct RawEvent {
 }
 
 /// `RawEvents` that have a payload 2 value with this value are instant events.
-const INSTANT_MARKER: u64 = 0xFFFF_FFFF_FFFF;
+pub(crate) const INSTANT_MARKER: u64 = 0xFFFF_FFFF_FFFF;
 /// `RawEvents` that have a payload 2 value with this value are integer events.
-const INTEGER_MARKER: u64 = INSTANT_MARKER - 1;
+pub(crate) const INTEGER_MARKER: u64 = INSTANT_MARKER - 1;
+
+/// Instant events with a value have this bit set in both payloads. Since
+/// a `Profiler` stores all timestamps modulo `TIMESTAMP_PERIOD`, it is never
+/// set in the payloads of its interval events.
+pub(crate) const INSTANT_VALUE_FLAG: u64 = TIMESTAMP_PERIOD;
 
 /// The max value we can represent with the 48 bits available.
 pub const MAX_SINGLE_VALUE: u64 = 0xFFFF_FFFF_FFFF;
@@ -43,6 +52,39 @@ pub const MAX_SINGLE_VALUE: u64 = 0xFFFF_FFFF_FFFF;
 /// The highest two values are reserved for the `INSTANT_MARKER` and `INTEGER_MARKER`.
 pub const MAX_INTERVAL_VALUE: u64 = INTEGER_MARKER - 1;
 
+/// The timestamps of events recorded by a `Profiler` are stored modulo this
+/// value, so that the 48 bits available suffice for processes running longer
+/// than `MAX_INTERVAL_VALUE` nanoseconds (about 78 hours). It is a lot smaller
+/// than that so that the intervals spanning a wrap-around can be told apart
+/// from intervals with a negative duration. Intervals can't be longer than
+/// this (about 39 hours).
+pub const TIMESTAMP_PERIOD: u64 = 1 << 47;
+
+/// The length of the epochs the profiler divides time into. Whenever a
+/// timestamp falls into a new epoch, the profiler records an epoch marker
+/// event (see `TIMESTAMP_EPOCH_EVENT_KIND`) before it, which tells
+/// readers which period the following timestamps belong to. Epochs are half
+/// as long as `TIMESTAMP_PERIOD`, so events that are written slightly out of
+/// order still decode correctly.
+pub const TIMESTAMP_EPOCH_LENGTH: u64 = TIMESTAMP_PERIOD / 2;
+
+/// The max value of an instant event with a value. The value shares its bits
+/// with `INSTANT_VALUE_FLAG`, and must not collide with the `INSTANT_MARKER`
+/// and `INTEGER_MARKER`.
+pub const MAX_INSTANT_VALUE: u64 = INTEGER_MARKER - 1 - INSTANT_VALUE_FLAG;
+
+/// In profiles with `file_header::FILE_FLAG_NESTING_DEPTH`, interval events
+/// store their nesting depth in the bits of their thread id starting at this
+/// one, see `RawEvent::with_nesting_depth`.
+pub const NESTING_DEPTH_SHIFT: u32 = 16;
+
+/// The largest thread id of interval events that store their nesting depth.
+pub const MAX_NESTING_DEPTH_THREAD_ID: u32 = (1 << NESTING_DEPTH_SHIFT) - 1;
+
+/// The largest nesting depth that can be stored. Deeper events store this
+/// depth instead.
+pub const MAX_NESTING_DEPTH: u32 = u32::MAX >> NESTING_DEPTH_SHIFT;
+
 impl RawEvent {
     #[inline]
     pub fn new_interval(
@@ -69,6 +111,69 @@ impl RawEvent {
         Self::pack_values(event_kind, event_id, thread_id, instant, INSTANT_MARKER)
     }
 
+    /// Like `new_interval`, but stores the timestamps modulo
+    /// `TIMESTAMP_PERIOD`, so any counter values can be recorded. Intervals
+    /// that are longer than `TIMESTAMP_PERIOD` are truncated to start
+    /// `TIMESTAMP_PERIOD - 1` before their end.
+    #[inline]
+    pub fn new_wrapping_interval(
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        start: u64,
+        end: u64,
+    ) -> Self {
+        assert!(start <= end);
+        let start = std::cmp::max(start, end.saturating_sub(TIMESTAMP_PERIOD - 1));
+
+        Self::pack_values(
+            event_kind,
+            event_id,
+            thread_id,
+            start % TIMESTAMP_PERIOD,
+            end % TIMESTAMP_PERIOD,
+        )
+    }
+
+    /// Like `new_instant`, but stores the timestamp modulo `TIMESTAMP_PERIOD`.
+    #[inline]
+    pub fn new_wrapping_instant(
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        instant: u64,
+    ) -> Self {
+        Self::pack_values(
+            event_kind,
+            event_id,
+            thread_id,
+            instant % TIMESTAMP_PERIOD,
+            INSTANT_MARKER,
+        )
+    }
+
+    /// Like `new_wrapping_instant`, but additionally stores `value`, e.g. the
+    /// number of bytes freed by the garbage collection run the instant event
+    /// marks. Unlike an interval with the same start and end, this is still
+    /// decoded as an instant event.
+    #[inline]
+    pub fn new_wrapping_instant_with_value(
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        instant: u64,
+        value: u64,
+    ) -> Self {
+        assert!(value <= MAX_INSTANT_VALUE);
+        Self::pack_values(
+            event_kind,
+            event_id,
+            thread_id,
+            INSTANT_VALUE_FLAG | (instant % TIMESTAMP_PERIOD),
+            INSTANT_VALUE_FLAG | value,
+        )
+    }
+
     #[inline]
     pub fn new_integer(
         event_kind: StringId,
@@ -124,9 +229,45 @@ impl RawEvent {
         self.payload1_lower as u64 | (((self.payloads_upper & 0xFFFF_0000) as u64) << 16)
     }
 
+    /// The timestamp assuming self is an instant.
+    #[inline]
+    pub fn instant_timestamp(&self) -> u64 {
+        if self.end_value() == INSTANT_MARKER {
+            self.start_value()
+        } else {
+            self.start_value() & !INSTANT_VALUE_FLAG
+        }
+    }
+
+    /// The value stored by `new_wrapping_instant_with_value`, if self is an
+    /// instant with a value.
+    #[inline]
+    pub fn instant_payload(&self) -> Option<u64> {
+        let (start, end) = (self.start_value(), self.end_value());
+        if start & end & INSTANT_VALUE_FLAG != 0 && end < INTEGER_MARKER {
+            Some(end & !INSTANT_VALUE_FLAG)
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn is_instant(&self) -> bool {
-        self.end_value() == INSTANT_MARKER
+        self.end_value() == INSTANT_MARKER || self.instant_payload().is_some()
+    }
+
+    /// Like `is_interval`, but if `!instant_values`, for an event of a file
+    /// that predates instant events with a value (see
+    /// `file_header::has_instant_values`), in which the interval events
+    /// whose start and end are both at least `INSTANT_VALUE_FLAG` look like
+    /// an instant with a value.
+    #[inline]
+    pub fn is_interval_in(&self, instant_values: bool) -> bool {
+        if instant_values {
+            self.is_interval()
+        } else {
+            self.end_value() < INTEGER_MARKER
+        }
     }
 
     #[inline]
@@ -134,6 +275,35 @@ impl RawEvent {
         self.end_value() == INTEGER_MARKER
     }
 
+    #[inline]
+    pub fn is_interval(&self) -> bool {
+        !self.is_instant() && !self.is_integer()
+    }
+
+    /// Stores `depth` in the upper bits of the thread id of this interval
+    /// event, see `NESTING_DEPTH_SHIFT`. Depths larger than
+    /// `MAX_NESTING_DEPTH` are clamped to it.
+    #[inline]
+    pub fn with_nesting_depth(mut self, depth: u32) -> Self {
+        assert!(
+            self.thread_id <= MAX_NESTING_DEPTH_THREAD_ID,
+            "thread id {} is too large for storing the nesting depth",
+            self.thread_id
+        );
+
+        self.thread_id |= std::cmp::min(depth, MAX_NESTING_DEPTH) << NESTING_DEPTH_SHIFT;
+        self
+    }
+
+    /// Removes the nesting depth stored by `with_nesting_depth` from the
+    /// thread id and returns it.
+    #[inline]
+    pub fn take_nesting_depth(&mut self) -> u32 {
+        let depth = self.thread_id >> NESTING_DEPTH_SHIFT;
+        self.thread_id &= MAX_NESTING_DEPTH_THREAD_ID;
+        depth
+    }
+
     #[inline]
     pub fn serialize(&self, bytes: &mut [u8]) {
         assert!(bytes.len() == std::mem::size_of::<RawEvent>());
@@ -324,6 +494,92 @@ mod tests {
         let _ = RawEvent::new_interval(StringId::INVALID, EventId::INVALID, 123, 1, 1);
     }
 
+    #[test]
+    fn wrapping_timestamps() {
+        let e = RawEvent::new_wrapping_interval(
+            StringId::INVALID,
+            EventId::INVALID,
+            1234,
+            TIMESTAMP_PERIOD - 5,
+            TIMESTAMP_PERIOD + 7,
+        );
+        assert_eq!(e.start_value(), TIMESTAMP_PERIOD - 5);
+        assert_eq!(e.end_value(), 7);
+
+        let e =
+            RawEvent::new_wrapping_interval(StringId::INVALID, EventId::INVALID, 1234, 3, u64::MAX);
+        assert_eq!(
+            e.start_value(),
+            (u64::MAX - (TIMESTAMP_PERIOD - 1)) % TIMESTAMP_PERIOD
+        );
+        assert_eq!(e.end_value(), u64::MAX % TIMESTAMP_PERIOD);
+        assert!(!e.is_instant() && !e.is_integer());
+
+        let e = RawEvent::new_wrapping_instant(
+            StringId::INVALID,
+            EventId::INVALID,
+            1234,
+            5 * TIMESTAMP_PERIOD + 42,
+        );
+        assert!(e.is_instant());
+        assert_eq!(e.start_value(), 42);
+        assert_eq!(e.instant_payload(), None);
+    }
+
+    #[test]
+    fn instant_with_value() {
+        for &value in &[0, 40 << 20, MAX_INSTANT_VALUE] {
+            let e = RawEvent::new_wrapping_instant_with_value(
+                StringId::INVALID,
+                EventId::INVALID,
+                1234,
+                3 * TIMESTAMP_PERIOD + 42,
+                value,
+            );
+            assert!(e.is_instant() && !e.is_integer());
+            assert_eq!(e.instant_timestamp(), 42);
+            assert_eq!(e.instant_payload(), Some(value));
+        }
+
+        // Degenerate intervals are not instants.
+        let e = RawEvent::new_wrapping_interval(StringId::INVALID, EventId::INVALID, 1234, 7, 7);
+        assert!(!e.is_instant());
+        assert_eq!(e.instant_payload(), None);
+
+        let e = RawEvent::new_instant(StringId::INVALID, EventId::INVALID, 987, MAX_SINGLE_VALUE);
+        assert_eq!(e.instant_timestamp(), MAX_SINGLE_VALUE);
+        assert_eq!(e.instant_payload(), None);
+    }
+
+    #[test]
+    fn intervals_of_files_without_instant_values() {
+        let start = INSTANT_VALUE_FLAG + 10;
+        let e = RawEvent::new_interval(StringId::INVALID, EventId::INVALID, 1234, start, start + 5);
+        assert_eq!(e.instant_payload(), Some(5 + 10));
+        assert!(!e.is_interval_in(true));
+        assert!(e.is_interval_in(false));
+
+        let e = RawEvent::new_interval(StringId::INVALID, EventId::INVALID, 1234, 10, start);
+        assert!(e.is_interval_in(true) && e.is_interval_in(false));
+
+        let e = RawEvent::new_instant(StringId::INVALID, EventId::INVALID, 1234, start);
+        assert!(!e.is_interval_in(false));
+        let e = RawEvent::new_integer(StringId::INVALID, EventId::INVALID, 1234, start);
+        assert!(!e.is_interval_in(false));
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_instant_value() {
+        let _ = RawEvent::new_wrapping_instant_with_value(
+            StringId::INVALID,
+            EventId::INVALID,
+            123,
+            0,
+            MAX_INSTANT_VALUE + 1,
+        );
+    }
+
     #[test]
     fn interval_count_decoding() {
         // Check the upper limits
@@ -406,4 +662,41 @@ mod tests {
             MAX_SINGLE_VALUE
         );
     }
+
+    #[test]
+    fn nesting_depth() {
+        let interval = |thread_id| {
+            RawEvent::new_wrapping_interval(StringId::INVALID, EventId::INVALID, thread_id, 10, 20)
+        };
+
+        let mut e = interval(MAX_NESTING_DEPTH_THREAD_ID).with_nesting_depth(3);
+        assert!(e.is_interval());
+        assert_eq!((e.start_value(), e.end_value()), (10, 20));
+        assert_eq!(e.take_nesting_depth(), 3);
+        assert_eq!(e.thread_id, MAX_NESTING_DEPTH_THREAD_ID);
+
+        let mut e = interval(7).with_nesting_depth(u32::MAX);
+        assert_eq!(e.take_nesting_depth(), MAX_NESTING_DEPTH);
+        assert_eq!(e.thread_id, 7);
+
+        let mut e = interval(7);
+        assert_eq!(e.take_nesting_depth(), 0);
+        assert_eq!(e.thread_id, 7);
+
+        assert!(!RawEvent::new_instant(StringId::INVALID, EventId::INVALID, 0, 1).is_interval());
+        assert!(!RawEvent::new_integer(StringId::INVALID, EventId::INVALID, 0, 1).is_interval());
+    }
+
+    #[test]
+    #[should_panic]
+    fn nesting_depth_thread_id_too_large() {
+        let e = RawEvent::new_wrapping_interval(
+            StringId::INVALID,
+            EventId::INVALID,
+            MAX_NESTING_DEPTH_THREAD_ID + 1,
+            10,
+            20,
+        );
+        let _ = e.with_nesting_depth(0);
+    }
 }
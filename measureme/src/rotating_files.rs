@@ -0,0 +1,617 @@
+//! Splitting a profile into multiple files ("segments"), each of which holds
+//! a limited amount of events, see `ProfilerOptions::max_file_bytes`.
+//!
+//! Every segment is a complete profile that can be read on its own, even once
+//! the segments before it have been deleted. Since the strings that its
+//! events use may have been allocated long before the segment was started,
+//! each segment has a string table of its own, which the strings are
+//! re-emitted to the first time one of the segment's events uses them: the
+//! encoded string is copied as it is, and so are the strings that it refers
+//! to, and the segment's string index maps the id of each copy to where it
+//! is in the segment (see `PageTag::Segment`). The events and the string ids
+//! they contain are thus the same in all segments.
+//!
+//! For this, the strings of the whole profile are kept in memory, and the
+//! sinks of the string table pass every string on as soon as it has been
+//! allocated, instead of a page at a time, so that a string is known before
+//! any event that uses it is written. Virtual ids are often mapped only after
+//! the events that use them have been written, e.g. by rustc at the end of
+//! the session, so the mappings are appended to all segments that have used
+//! the virtual id by then.
+//!
+//! The metadata is written to all segments, no matter when it has been
+//! recorded. The trace contexts are written to the segment that is being
+//! written when they are recorded, which is usually the one with the events
+//! that they belong to, and which continues the trace context stream of
+//! the segment before it.
+
+use crate::file_header::{
+    segment_file_path, write_file_header, write_top_level_file_header, TopLevelFileHeader,
+    FILE_EXTENSION, FILE_HEADER_SIZE, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_STRINGTABLE_DATA,
+    FILE_MAGIC_STRINGTABLE_INDEX, FILE_MAGIC_TRACE_CONTEXT,
+};
+use crate::profiler::OPEN_INTERVAL_EVENT_KIND;
+use crate::raw_event::RawEvent;
+use crate::serialization::{
+    decompress_page, write_page_to, Addr, Compression, PageTag, PAGE_HEADER_SIZE,
+};
+use crate::stringtable::{
+    StringId, ESCAPED_BYTE_ENCODED_SIZE, METADATA_STRING_ID, STRING_INDEX_ENTRY_SIZE,
+    STRING_REF_ENCODED_SIZE, STRING_REF_TAG, TERMINATOR,
+};
+use crate::trace_context::{TRACE_CONTEXT_ENTRY_SIZE, TRACE_CONTEXT_PRELUDE_SIZE};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const RAW_EVENT_SIZE: usize = std::mem::size_of::<RawEvent>();
+
+/// A set of files where a new segment is started whenever writing the next
+/// events page would make the current one exceed `max_file_bytes`. Segment
+/// `n` is located at `segment_file_path(path_stem, n)`.
+#[derive(Debug)]
+pub(crate) struct RotatingFiles {
+    path_stem: PathBuf,
+    max_file_bytes: u64,
+    pub(crate) compression: Compression,
+    pub(crate) file_flags: u8,
+    segment_index: u32,
+    file: fs::File,
+    file_bytes: u64,
+    file_has_events: bool,
+    strings: Strings,
+    /// The string table of the current segment.
+    segment: SegmentStrings,
+    /// The earlier segments that use virtual ids that haven't been mapped
+    /// yet, with their index.
+    unmapped_segments: Vec<(u32, SegmentStrings)>,
+    /// Whether the event kind with the given id is `OPEN_INTERVAL_EVENT_KIND`,
+    /// whose markers have the id of an event kind as their value.
+    open_interval_kinds: FxHashMap<StringId, bool>,
+    /// The size of the events stream so far, and the bytes of its last event
+    /// if that hasn't been written completely yet.
+    events_len: usize,
+    partial_event: Vec<u8>,
+    /// The metadata stream, which all segments get.
+    metadata: Vec<u8>,
+    /// The size of the trace context stream so far, and whether the current
+    /// segment has a trace context stream of its own yet.
+    trace_context_len: usize,
+    segment_has_trace_contexts: bool,
+}
+
+impl RotatingFiles {
+    /// `file` must be the file at `segment_file_path(path_stem, 0)` with the
+    /// top-level file header already written to it.
+    pub(crate) fn new(
+        file: fs::File,
+        path_stem: &Path,
+        max_file_bytes: u64,
+    ) -> Result<RotatingFiles, Box<dyn Error + Send + Sync>> {
+        let mut files = RotatingFiles {
+            path_stem: path_stem.to_path_buf(),
+            max_file_bytes,
+            compression: Compression::None,
+            file_flags: 0,
+            segment_index: 0,
+            file,
+            file_bytes: 0,
+            file_has_events: false,
+            strings: Strings::default(),
+            segment: SegmentStrings::new(),
+            unmapped_segments: Vec::new(),
+            open_interval_kinds: FxHashMap::default(),
+            events_len: 0,
+            partial_event: Vec::new(),
+            metadata: Vec::new(),
+            trace_context_len: 0,
+            segment_has_trace_contexts: false,
+        };
+
+        let mut prelude = Vec::new();
+        files.write_segment_prelude(&mut prelude, 0)?;
+        files.file.write_all(&prelude)?;
+        files.file_bytes = files.file.metadata()?.len();
+
+        Ok(files)
+    }
+
+    pub(crate) fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match page_tag {
+            PageTag::Events => self.write_events_page(bytes),
+            PageTag::StringData => {
+                self.strings.data.extend_from_slice(bytes);
+                Ok(())
+            }
+            PageTag::StringIndex => self.add_index_entries(bytes),
+            PageTag::Metadata => self.write_metadata_page(bytes),
+            PageTag::TraceContext => self.write_trace_context_page(bytes),
+            // The index refers to events by their position in the whole
+            // stream, not in the segment, so readers scan the events of each
+            // segment for the markers instead.
+            PageTag::TimestampEpochIndex => Ok(()),
+            // These pages are only written by `write_segment_prelude`.
+            PageTag::Segment => Err(From::from(
+                "Segment pages can't be written to rotating files",
+            )),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn write_events_page(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let events = if self.compression == Compression::None {
+            Cow::Borrowed(bytes)
+        } else {
+            Cow::Owned(decompress_page(self.compression.codec(), bytes)?)
+        };
+
+        // The first page starts with the header of the events stream. Pages
+        // usually hold complete events, but a batch of events that is larger
+        // than a page is split across pages wherever the page is full.
+        let header_len = FILE_HEADER_SIZE
+            .saturating_sub(self.events_len)
+            .min(events.len());
+        self.events_len += events.len();
+        let starts_with_event = self.partial_event.is_empty();
+        self.partial_event.extend_from_slice(&events[header_len..]);
+
+        let mut unread = std::mem::take(&mut self.partial_event);
+        let complete_len = unread.len() / RAW_EVENT_SIZE * RAW_EVENT_SIZE;
+        let mut ids = Vec::new();
+        for event in unread[..complete_len].chunks_exact(RAW_EVENT_SIZE) {
+            let raw_event = RawEvent::deserialize(event);
+            ids.push(raw_event.event_kind);
+            ids.push(raw_event.event_id.to_string_id());
+            if let Some(value) = raw_event.instant_payload() {
+                if self.is_open_interval_kind(raw_event.event_kind) {
+                    ids.push(StringId::new(value as u32));
+                }
+            }
+        }
+        unread.drain(..complete_len);
+        self.partial_event = unread;
+
+        // Every segment gets at least one events page, even if the strings
+        // alone exceed the limit, and segments only end between events.
+        let page_bytes = (PAGE_HEADER_SIZE + bytes.len()) as u64;
+        let mut new = self.segment.add_all(&self.strings, &ids);
+        if self.file_has_events
+            && starts_with_event
+            && self.file_bytes + new.page_bytes() + page_bytes > self.max_file_bytes
+        {
+            self.roll_over()?;
+            new = self.segment.add_all(&self.strings, &ids);
+        }
+
+        // The strings go first, so that readers that read the segment while
+        // it is being written know them by the time they get to the events.
+        new.write_to(&mut self.file)?;
+        self.file_bytes += new.page_bytes();
+        self.segment.commit(new);
+
+        write_page_to(&mut self.file, PageTag::Events, bytes)?;
+        self.file_bytes += page_bytes;
+        self.file_has_events = true;
+
+        Ok(())
+    }
+
+    fn is_open_interval_kind(&mut self, event_kind: StringId) -> bool {
+        if let Some(&is_open_interval) = self.open_interval_kinds.get(&event_kind) {
+            return is_open_interval;
+        }
+
+        let mut expected = OPEN_INTERVAL_EVENT_KIND.as_bytes().to_vec();
+        expected.push(TERMINATOR);
+        let is_open_interval = self
+            .strings
+            .addr(event_kind)
+            .and_then(|addr| self.strings.string_at(addr))
+            .is_some_and(|(string, _)| string == &expected[..]);
+
+        self.open_interval_kinds
+            .insert(event_kind, is_open_interval);
+        is_open_interval
+    }
+
+    /// Decodes the entries of a page of the string index, and maps the
+    /// virtual ids in the segments that have used them already.
+    fn add_index_entries(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let ids = self.strings.add_index_entries(bytes);
+
+        let new = self.segment.add_unmapped(&self.strings, &ids);
+        new.write_to(&mut self.file)?;
+        self.file_bytes += new.page_bytes();
+        self.segment.commit(new);
+
+        for (segment_index, segment) in &mut self.unmapped_segments {
+            let new = segment.add_unmapped(&self.strings, &ids);
+            if !new.is_empty() {
+                append_to_segment(&self.path_stem, *segment_index, |file| new.write_to(file))?;
+                segment.commit(new);
+            }
+        }
+        self.unmapped_segments
+            .retain(|(_, segment)| !segment.unmapped.is_empty());
+
+        Ok(())
+    }
+
+    /// Writes a page of the metadata stream to all segments, so that every
+    /// segment describes the process, no matter when the metadata was
+    /// recorded.
+    fn write_metadata_page(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.metadata.extend_from_slice(bytes);
+
+        for segment_index in 0..self.segment_index {
+            append_to_segment(&self.path_stem, segment_index, |file| {
+                write_page_to(file, PageTag::Metadata, bytes)
+            })?;
+        }
+
+        write_page_to(&mut self.file, PageTag::Metadata, bytes)?;
+        self.file_bytes += (PAGE_HEADER_SIZE + bytes.len()) as u64;
+
+        Ok(())
+    }
+
+    /// Writes the entries of a page of the trace context stream to the
+    /// current segment, whose stream starts with the index of the first one.
+    fn write_trace_context_page(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The prelude of the profile's stream is replaced with the one of the
+        // segment's stream.
+        let prelude_len = TRACE_CONTEXT_PRELUDE_SIZE
+            .saturating_sub(self.trace_context_len)
+            .min(bytes.len());
+        self.trace_context_len += bytes.len();
+        let entries = &bytes[prelude_len..];
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        if !self.segment_has_trace_contexts {
+            let entries_before =
+                self.trace_context_len - entries.len() - TRACE_CONTEXT_PRELUDE_SIZE;
+            let first_index = (entries_before / TRACE_CONTEXT_ENTRY_SIZE) as u64;
+
+            let mut prelude = Vec::with_capacity(TRACE_CONTEXT_PRELUDE_SIZE);
+            write_file_header(&mut prelude, FILE_MAGIC_TRACE_CONTEXT)?;
+            prelude.extend_from_slice(&first_index.to_le_bytes());
+            write_page_to(&mut self.file, PageTag::TraceContext, &prelude)?;
+            self.file_bytes += (PAGE_HEADER_SIZE + prelude.len()) as u64;
+            self.segment_has_trace_contexts = true;
+        }
+
+        write_page_to(&mut self.file, PageTag::TraceContext, entries)?;
+        self.file_bytes += (PAGE_HEADER_SIZE + entries.len()) as u64;
+
+        Ok(())
+    }
+
+    /// The pages that every segment starts with, after the top-level file
+    /// header: the `PageTag::Segment` page, the headers of the string table
+    /// streams and the metadata recorded so far.
+    fn write_segment_prelude(
+        &self,
+        dest: &mut Vec<u8>,
+        segment_index: u32,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        write_page_to(dest, PageTag::Segment, &segment_index.to_le_bytes())?;
+
+        for &(page_tag, magic) in &[
+            (PageTag::StringData, FILE_MAGIC_STRINGTABLE_DATA),
+            (PageTag::StringIndex, FILE_MAGIC_STRINGTABLE_INDEX),
+        ] {
+            let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+            write_file_header(&mut header, magic)?;
+            write_page_to(dest, page_tag, &header)?;
+        }
+
+        if !self.metadata.is_empty() {
+            write_page_to(dest, PageTag::Metadata, &self.metadata)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the next segment. The new file is written to a temporary
+    /// location first and only renamed into place once it is a valid
+    /// profiling data file, so readers never observe a half-initialized
+    /// segment.
+    fn roll_over(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.file.flush()?;
+
+        let segment_index = self.segment_index + 1;
+        let path = segment_file_path(&self.path_stem, segment_index);
+        let tmp_path = path.with_extension(format!("{}.tmp", FILE_EXTENSION));
+
+        let mut prelude = Vec::new();
+        write_top_level_file_header(
+            &mut prelude,
+            TopLevelFileHeader {
+                codec: self.compression.codec(),
+                flags: self.file_flags,
+            },
+        )?;
+        self.write_segment_prelude(&mut prelude, segment_index)?;
+
+        // Readers need the metadata string, which no event uses.
+        let mut segment = SegmentStrings::new();
+        let new = segment.add_unmapped(&self.strings, &[StringId::new(METADATA_STRING_ID)]);
+        new.write_to(&mut prelude)?;
+        segment.commit(new);
+
+        let mut stream_header = Vec::with_capacity(FILE_HEADER_SIZE);
+        write_file_header(&mut stream_header, FILE_MAGIC_EVENT_STREAM)?;
+        let stream_header = self.compression.encode_page(&stream_header);
+        write_page_to(&mut prelude, PageTag::Events, &stream_header)?;
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&prelude)?;
+        fs::rename(&tmp_path, &path)?;
+
+        let previous = std::mem::replace(&mut self.segment, segment);
+        if !previous.unmapped.is_empty() {
+            self.unmapped_segments.push((self.segment_index, previous));
+        }
+
+        self.segment_index = segment_index;
+        self.file = file;
+        self.file_bytes = prelude.len() as u64;
+        self.file_has_events = false;
+        self.segment_has_trace_contexts = false;
+
+        Ok(())
+    }
+}
+
+/// Appends to the file of an earlier segment, unless it has been deleted.
+fn append_to_segment(
+    path_stem: &Path,
+    segment_index: u32,
+    write: impl FnOnce(&mut fs::File) -> io::Result<()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let path = segment_file_path(path_stem, segment_index);
+    let mut file = match fs::OpenOptions::new().append(true).open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from(e)),
+    };
+
+    write(&mut file)?;
+    Ok(file.flush()?)
+}
+
+/// The string table of the whole profile, as the sinks have written it.
+#[derive(Debug, Default)]
+struct Strings {
+    /// The string data stream, which concrete `StringId`s are addresses into.
+    data: Vec<u8>,
+    virtual_ids: FxHashMap<StringId, Addr>,
+    /// The size of the string index stream so far, and the bytes of its last
+    /// entry if that hasn't been written completely yet.
+    index_len: usize,
+    partial_index_entry: Vec<u8>,
+}
+
+impl Strings {
+    /// Decodes the entries of a page of the string index. Returns the virtual
+    /// ids that have been mapped.
+    fn add_index_entries(&mut self, bytes: &[u8]) -> Vec<StringId> {
+        let header_len = FILE_HEADER_SIZE
+            .saturating_sub(self.index_len)
+            .min(bytes.len());
+        self.index_len += bytes.len();
+        self.partial_index_entry
+            .extend_from_slice(&bytes[header_len..]);
+
+        let complete_len =
+            self.partial_index_entry.len() / STRING_INDEX_ENTRY_SIZE * STRING_INDEX_ENTRY_SIZE;
+        let mut ids = Vec::new();
+        for entry in self.partial_index_entry[..complete_len].chunks_exact(STRING_INDEX_ENTRY_SIZE)
+        {
+            let id = StringId::new(u32::from_le_bytes(entry[0..4].try_into().unwrap()));
+            let addr = Addr(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+            self.virtual_ids.insert(id, addr);
+            ids.push(id);
+        }
+        self.partial_index_entry.drain(..complete_len);
+
+        ids
+    }
+
+    /// The address of the string with id `id`, `None` for virtual ids that
+    /// haven't been mapped yet.
+    fn addr(&self, id: StringId) -> Option<Addr> {
+        if id.is_virtual() {
+            self.virtual_ids.get(&id).copied()
+        } else {
+            Some(id.to_addr())
+        }
+    }
+
+    /// The encoded string at `addr`, up to and including its terminator, and
+    /// the ids of the strings that it refers to. `None` if it is incomplete.
+    fn string_at(&self, addr: Addr) -> Option<(&[u8], Vec<StringId>)> {
+        let data = self.data.get(addr.as_usize()..)?;
+        let mut refs = Vec::new();
+        let mut pos = 0;
+
+        loop {
+            match *data.get(pos)? {
+                TERMINATOR => return Some((&data[..=pos], refs)),
+                STRING_REF_TAG => {
+                    let id = data.get(pos + 1..pos + STRING_REF_ENCODED_SIZE)?;
+                    let id = StringId::new(u32::from_le_bytes(id.try_into().unwrap()));
+                    if id == StringId::INVALID {
+                        // An escaped byte, see `stringtable`.
+                        pos += ESCAPED_BYTE_ENCODED_SIZE;
+                    } else {
+                        refs.push(id);
+                        pos += STRING_REF_ENCODED_SIZE;
+                    }
+                }
+                _ => pos += 1,
+            }
+        }
+    }
+}
+
+/// The string table of a segment, see the module documentation.
+#[derive(Debug)]
+struct SegmentStrings {
+    /// Where the strings that have been copied to the segment are in its
+    /// string data, by their id. For virtual ids, this is where the string
+    /// that they have been mapped to is.
+    addrs: FxHashMap<StringId, Addr>,
+    /// The virtual ids that the segment uses, but that haven't been mapped
+    /// yet.
+    unmapped: FxHashSet<StringId>,
+    /// The size of the segment's string data stream.
+    data_len: usize,
+}
+
+impl SegmentStrings {
+    fn new() -> SegmentStrings {
+        // Readers need the metadata string, which is mapped like a virtual id.
+        let mut unmapped = FxHashSet::default();
+        unmapped.insert(StringId::new(METADATA_STRING_ID));
+
+        SegmentStrings {
+            addrs: FxHashMap::default(),
+            unmapped,
+            data_len: FILE_HEADER_SIZE,
+        }
+    }
+
+    /// The strings with the given ids, and the strings they refer to, that
+    /// aren't in the segment yet.
+    fn add_all(&self, strings: &Strings, ids: &[StringId]) -> NewStrings {
+        let mut new = NewStrings::default();
+        for &id in ids {
+            self.add(strings, id, &mut new);
+        }
+        new
+    }
+
+    /// Like `add_all`, but only for the ids that the segment has used before
+    /// they have been mapped.
+    fn add_unmapped(&self, strings: &Strings, ids: &[StringId]) -> NewStrings {
+        let mut new = NewStrings::default();
+        for &id in ids {
+            if self.unmapped.contains(&id) {
+                self.add(strings, id, &mut new);
+            }
+        }
+        new
+    }
+
+    fn add(&self, strings: &Strings, id: StringId, new: &mut NewStrings) {
+        if id == StringId::INVALID || self.addrs.contains_key(&id) || new.addrs.contains_key(&id) {
+            return;
+        }
+
+        let addr = match strings.addr(id) {
+            Some(addr) => addr,
+            None => {
+                new.unmapped.push(id);
+                return;
+            }
+        };
+
+        let concrete_id = StringId::from_addr(addr);
+        let local_addr = match self
+            .addrs
+            .get(&concrete_id)
+            .or_else(|| new.addrs.get(&concrete_id))
+        {
+            Some(&local_addr) => local_addr,
+            None => {
+                // Strings are complete when they are passed on, so this only
+                // happens for ids that don't refer to a string at all.
+                let (string, refs) = match strings.string_at(addr) {
+                    Some(string) => string,
+                    None => return,
+                };
+
+                let local_addr = Addr((self.data_len + new.data.len()) as u32);
+                new.data.extend_from_slice(string);
+                new.push_index_entry(concrete_id, local_addr);
+
+                for string_ref in refs {
+                    self.add(strings, string_ref, new);
+                }
+
+                local_addr
+            }
+        };
+
+        if id.is_virtual() {
+            new.push_index_entry(id, local_addr);
+        }
+    }
+
+    fn commit(&mut self, new: NewStrings) {
+        self.data_len += new.data.len();
+        for id in new.addrs.keys() {
+            self.unmapped.remove(id);
+        }
+        self.addrs.extend(new.addrs);
+        self.unmapped.extend(new.unmapped);
+    }
+}
+
+/// The strings that are about to be added to a segment, see
+/// `SegmentStrings::add_all`.
+#[derive(Default)]
+struct NewStrings {
+    data: Vec<u8>,
+    index: Vec<u8>,
+    addrs: FxHashMap<StringId, Addr>,
+    unmapped: Vec<StringId>,
+}
+
+impl NewStrings {
+    fn push_index_entry(&mut self, id: StringId, addr: Addr) {
+        self.index.extend_from_slice(&id.as_u32().to_le_bytes());
+        self.index.extend_from_slice(&addr.0.to_le_bytes());
+        self.addrs.insert(id, addr);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.index.is_empty()
+    }
+
+    /// The number of bytes that `write_to` writes.
+    fn page_bytes(&self) -> u64 {
+        [&self.data, &self.index]
+            .iter()
+            .filter(|contents| !contents.is_empty())
+            .map(|contents| (PAGE_HEADER_SIZE + contents.len()) as u64)
+            .sum()
+    }
+
+    fn write_to(&self, dest: &mut dyn Write) -> io::Result<()> {
+        if !self.data.is_empty() {
+            write_page_to(dest, PageTag::StringData, &self.data)?;
+        }
+        if !self.index.is_empty() {
+            write_page_to(dest, PageTag::StringIndex, &self.index)?;
+        }
+        Ok(())
+    }
+}
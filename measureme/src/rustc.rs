@@ -13,3 +13,17 @@ pub const QUERY_BLOCKED_EVENT_KIND: &str = "QueryBlocked";
 pub const QUERY_CACHE_HIT_EVENT_KIND: &str = "QueryCacheHit";
 
 pub const ARTIFACT_SIZE_EVENT_KIND: &str = "ArtifactSize";
+
+/// Integer events with this category are artifact sizes in bytes, whatever
+/// their event kind. Like for `ARTIFACT_SIZE_EVENT_KIND` events, the label is
+/// the kind of the artifact, and the first argument, if any, its name.
+pub const ARTIFACT_SIZE_CATEGORY: &str = "ArtifactSize";
+
+/// The `Profiler::record_metadata` key for the output of `rustc --version`.
+pub const METADATA_KEY_RUSTC_VERSION: &str = "rustc-version";
+
+/// The `Profiler::record_metadata` key for the target triple.
+pub const METADATA_KEY_TARGET: &str = "target";
+
+/// The `Profiler::record_metadata` key for the value of `-C opt-level`.
+pub const METADATA_KEY_OPT_LEVEL: &str = "opt-level";
@@ -24,22 +24,45 @@
 /// | &[5 .. (5 + page_size)] | page contents (exactly page_size bytes) |
 ///
 /// A page is immediately followed by the next page, without any padding.
+///
+/// If the events stream is compressed (see `Compression`), the contents of
+/// each of its pages are compressed individually, so that a reader can
+/// decompress pages on demand. A compressed page's contents are the size of
+/// the uncompressed data as little endian u32, followed by the compressed
+/// data.
+use crate::file_header::{
+    write_file_header, FILE_CODEC_NONE, FILE_CODEC_ZSTD, FILE_MAGIC_TRACE_CONTEXT,
+};
+use crate::raw_event::RawEvent;
+use crate::rotating_files::RotatingFiles;
+use crate::trace_context::{TRACE_CONTEXT_ENTRY_SIZE, TRACE_CONTEXT_PRELUDE_SIZE};
 use parking_lot::Mutex;
 use rustc_hash::FxHashMap;
+use std::borrow::Cow;
 use std::cmp::min;
-use std::convert::TryInto;
+use std::collections::VecDeque;
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fmt::Debug;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::Arc;
 
-const MAX_PAGE_SIZE: usize = 256 * 1024;
+/// The size of the pages written by sinks, unless configured otherwise with
+/// `SerializationSinkBuilder::with_page_size`. This is tuned for rustc.
+pub const DEFAULT_PAGE_SIZE: usize = 256 * 1024;
+
+/// The smallest page size that can be configured. A page must be able to
+/// hold at least one event.
+pub const MIN_CONFIGURABLE_PAGE_SIZE: usize = std::mem::size_of::<RawEvent>();
+
+/// The largest page size that can be configured.
+pub const MAX_CONFIGURABLE_PAGE_SIZE: usize = 64 * 1024 * 1024;
 
-/// The number of bytes we consider enough to warrant their own page when
-/// deciding whether to flush a partially full buffer. Actual pages may need
-/// to be smaller, e.g. when writing the tail of the data stream.
-const MIN_PAGE_SIZE: usize = MAX_PAGE_SIZE / 2;
+/// The number of bytes in a page header: one byte for the page tag and four
+/// bytes for the page size.
+pub const PAGE_HEADER_SIZE: usize = 5;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -47,8 +70,45 @@ pub enum PageTag {
     Events = 0,
     StringData = 1,
     StringIndex = 2,
+    /// Key/value pairs describing the profiled process, see
+    /// `Profiler::record_metadata`.
+    Metadata = 3,
+    /// Marks a file as a segment of a profile that has been split into
+    /// multiple files, see `ProfilerOptions::max_file_bytes`. Its contents are
+    /// the index of the segment as a little endian u32. The string table of a
+    /// segment only holds the strings that its events use, and its string
+    /// index maps all of their ids, not just the virtual ones, to where they
+    /// are in the segment's string data, which is why this tag isn't an
+    /// optional one: readers that don't know it can't decode the strings.
+    Segment = 4,
+    /// The trace and span ids of interval events, see
+    /// `ProfilerOptions::record_trace_context`. Added in minor file format
+    /// version 1. Older readers skip these pages, and thus only miss the
+    /// trace contexts, not the interval events they belong to.
+    TraceContext = FIRST_OPTIONAL_PAGE_TAG + 1,
+    /// The positions of the timestamp epoch markers in the events stream, see
+    /// `timestamp_epoch_index`. Added in minor file format version 3, see
+    /// `FIRST_MINOR_VERSION_WITH_TIMESTAMP_EPOCH_INDEX`. Older readers skip
+    /// these pages and scan the events for the markers instead.
+    TimestampEpochIndex = FIRST_OPTIONAL_PAGE_TAG + 2,
+}
+
+/// The tags from `FIRST_OPTIONAL_PAGE_TAG` to `LAST_OPTIONAL_PAGE_TAG` are
+/// reserved for the pages of optional sections that a new minor file format
+/// version may add (see `file_header`). Readers skip the pages with such tags
+/// that they don't know, instead of treating them as corrupt.
+pub const FIRST_OPTIONAL_PAGE_TAG: u8 = 64;
+pub const LAST_OPTIONAL_PAGE_TAG: u8 = 127;
+
+/// Whether pages with the tag `tag` can be skipped by readers that don't
+/// know it, see `FIRST_OPTIONAL_PAGE_TAG`.
+pub fn is_optional_page_tag(tag: u8) -> bool {
+    (FIRST_OPTIONAL_PAGE_TAG..=LAST_OPTIONAL_PAGE_TAG).contains(&tag)
 }
 
+const TRACE_CONTEXT_PAGE_TAG: u8 = PageTag::TraceContext as u8;
+const TIMESTAMP_EPOCH_INDEX_PAGE_TAG: u8 = PageTag::TimestampEpochIndex as u8;
+
 impl std::convert::TryFrom<u8> for PageTag {
     type Error = String;
 
@@ -57,11 +117,93 @@ impl std::convert::TryFrom<u8> for PageTag {
             0 => Ok(PageTag::Events),
             1 => Ok(PageTag::StringData),
             2 => Ok(PageTag::StringIndex),
+            3 => Ok(PageTag::Metadata),
+            4 => Ok(PageTag::Segment),
+            TRACE_CONTEXT_PAGE_TAG => Ok(PageTag::TraceContext),
+            TIMESTAMP_EPOCH_INDEX_PAGE_TAG => Ok(PageTag::TimestampEpochIndex),
             _ => Err(format!("Could not convert byte `{}` to PageTag.", value)),
         }
     }
 }
 
+/// The codec used for the pages of the events stream. Compression is only
+/// available if the `zstd` feature is enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    /// The value of the codec flag byte in the top-level file header.
+    pub fn codec(self) -> u8 {
+        match self {
+            Compression::None => FILE_CODEC_NONE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => FILE_CODEC_ZSTD,
+        }
+    }
+
+    pub(crate) fn encode_page(self, bytes: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Compression::None => Cow::Borrowed(bytes),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => {
+                let compressed = zstd::bulk::compress(bytes, level).unwrap();
+
+                let mut page = Vec::with_capacity(4 + compressed.len());
+                page.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                page.extend_from_slice(&compressed);
+                Cow::Owned(page)
+            }
+        }
+    }
+}
+
+/// Returns the number of bytes that the compressed page `page_contents`
+/// decompresses to, without actually decompressing it.
+pub fn decompressed_page_size(page_contents: &[u8]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    match page_contents.get(..4) {
+        Some(size) => Ok(u32::from_le_bytes(size.try_into().unwrap()) as usize),
+        None => Err(From::from("Invalid file: Truncated compressed page")),
+    }
+}
+
+/// Decompresses the contents of a page that has been compressed with the
+/// given codec.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub fn decompress_page(
+    codec: u8,
+    page_contents: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    match codec {
+        #[cfg(feature = "zstd")]
+        FILE_CODEC_ZSTD => {
+            let size = decompressed_page_size(page_contents)?;
+            let data = zstd::bulk::decompress(&page_contents[4..], size)?;
+
+            if data.len() != size {
+                let msg = format!(
+                    "Invalid file: Compressed page decompressed to {} bytes instead of {}",
+                    data.len(),
+                    size
+                );
+                return Err(From::from(msg));
+            }
+
+            Ok(data)
+        }
+        #[cfg(not(feature = "zstd"))]
+        FILE_CODEC_ZSTD => Err(From::from(
+            "The file is compressed with zstd but measureme has been built \
+             without the `zstd` feature.",
+        )),
+        unknown => Err(From::from(format!("Unknown codec `{}`", unknown))),
+    }
+}
+
 /// An address within a data stream. Each data stream has its own address space,
 /// i.e. the first piece of data written to the events stream will have
 /// `Addr(0)` and the first piece of data written to the string data stream
@@ -83,31 +225,188 @@ pub struct SerializationSink {
     shared_state: SharedState,
     data: Mutex<SerializationSinkInner>,
     page_tag: PageTag,
+    compression: Compression,
+    // The maximum number of bytes per page. Partially full buffers are only
+    // written as a page of their own if they contain at least half of that.
+    page_size: usize,
+    // Whether all data is written to the backing storage right away, instead
+    // of being buffered until a page is full.
+    write_through: bool,
 }
 
-pub struct SerializationSinkBuilder(SharedState);
+/// The shared backing storage, the compression of the events stream, the page
+/// size and the lengths of the streams that the sinks continue, see
+/// `continuing_streams`.
+pub struct SerializationSinkBuilder(SharedState, Compression, usize, FxHashMap<PageTag, u32>);
 
 impl SerializationSinkBuilder {
     pub fn new_from_file(file: fs::File) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        Ok(Self(SharedState(Arc::new(Mutex::new(
-            BackingStorage::File(file),
-        )))))
+        Ok(Self(
+            SharedState(Arc::new(Mutex::new(BackingStorage::File(file)))),
+            Compression::None,
+            DEFAULT_PAGE_SIZE,
+            FxHashMap::default(),
+        ))
+    }
+
+    /// Creates a builder whose sinks write to `file` until it would grow
+    /// beyond `max_file_bytes`, and then continue in a new file. `file` must
+    /// be the file at `segment_file_path(path_stem, 0)` with the top-level
+    /// file header already written to it.
+    pub fn new_rotating_files(
+        file: fs::File,
+        path_stem: &Path,
+        max_file_bytes: u64,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let files = RotatingFiles::new(file, path_stem, max_file_bytes)?;
+
+        Ok(Self(
+            SharedState(Arc::new(Mutex::new(BackingStorage::RotatingFiles(
+                Box::new(files),
+            )))),
+            Compression::None,
+            DEFAULT_PAGE_SIZE,
+            FxHashMap::default(),
+        ))
+    }
+
+    /// Creates a builder whose sinks write to `sink`. The top-level file
+    /// header must already have been written to it, see
+    /// `PageSink::write_file_header`.
+    pub fn new_from_page_sink(sink: impl PageSink) -> SerializationSinkBuilder {
+        Self(
+            SharedState(Arc::new(Mutex::new(BackingStorage::Custom(CustomSink(
+                Box::new(sink),
+            ))))),
+            Compression::None,
+            DEFAULT_PAGE_SIZE,
+            FxHashMap::default(),
+        )
+    }
+
+    /// Creates a builder whose sinks discard everything written to them, for
+    /// `Profiler::disabled`.
+    pub(crate) fn new_null() -> SerializationSinkBuilder {
+        Self(
+            SharedState(Arc::new(Mutex::new(BackingStorage::Null))),
+            Compression::None,
+            DEFAULT_PAGE_SIZE,
+            FxHashMap::default(),
+        )
+    }
+
+    /// Whether the sinks created by this builder discard everything, see
+    /// `new_null`.
+    pub(crate) fn is_null(&self) -> bool {
+        matches!(*(self.0).0.lock(), BackingStorage::Null)
     }
 
     pub fn new_in_memory() -> SerializationSinkBuilder {
-        Self(SharedState(Arc::new(Mutex::new(BackingStorage::Memory(
-            Vec::new(),
-        )))))
+        Self::new_from_in_memory_sink(&InMemorySink::new())
+    }
+
+    /// Creates a builder whose sinks write to `sink`. The top-level file
+    /// header must already have been written to it, like for files.
+    pub fn new_from_in_memory_sink(sink: &InMemorySink) -> SerializationSinkBuilder {
+        Self(
+            sink.0.clone(),
+            Compression::None,
+            DEFAULT_PAGE_SIZE,
+            FxHashMap::default(),
+        )
+    }
+
+    /// Makes the events sinks created by this builder compress their pages
+    /// with the given codec. The top-level file header must specify the same
+    /// codec, see `file_header::write_top_level_file_header`.
+    pub fn with_compression(self, compression: Compression) -> Self {
+        if let BackingStorage::RotatingFiles(ref mut files) = *(self.0).0.lock() {
+            files.compression = compression;
+        }
+
+        Self(self.0, compression, self.2, self.3)
+    }
+
+    /// Sets the maximum size of the pages written by the sinks created by
+    /// this builder. Small pages keep the memory usage of tiny profiles down,
+    /// large pages reduce the locking overhead when events are recorded at a
+    /// high rate. `page_size` is rounded up to the next power of two and must
+    /// be between `MIN_CONFIGURABLE_PAGE_SIZE` (the size of a single event)
+    /// and `MAX_CONFIGURABLE_PAGE_SIZE`.
+    ///
+    /// Readers don't need to know the page size, since it is stored in the
+    /// header of each page.
+    pub fn with_page_size(self, page_size: usize) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !(MIN_CONFIGURABLE_PAGE_SIZE..=MAX_CONFIGURABLE_PAGE_SIZE).contains(&page_size) {
+            let msg = format!(
+                "Invalid page size {}: must be between {} and {} bytes",
+                page_size, MIN_CONFIGURABLE_PAGE_SIZE, MAX_CONFIGURABLE_PAGE_SIZE
+            );
+            return Err(From::from(msg));
+        }
+
+        Ok(Self(self.0, self.1, page_size.next_power_of_two(), self.3))
+    }
+
+    /// The page size of the sinks created by this builder.
+    pub fn page_size(&self) -> usize {
+        self.2
+    }
+
+    /// Sets the `FILE_FLAG_*` bits of the top-level file headers that this
+    /// builder writes. This only has an effect for rotating files, where the
+    /// header of every segment after the first one is written by the builder.
+    pub fn with_file_flags(self, file_flags: u8) -> Self {
+        if let BackingStorage::RotatingFiles(ref mut files) = *(self.0).0.lock() {
+            files.file_flags = file_flags;
+        }
+
+        self
+    }
+
+    /// Makes the sinks created by this builder continue the streams of an
+    /// existing profile, which are `stream_lengths` bytes long, so that their
+    /// addresses, and with them the ids of new strings, continue where the
+    /// profile has left off. The headers of these streams have been written
+    /// already, see `SerializationSink::is_empty`. Used by `Profiler::open`.
+    pub fn continuing_streams(self, stream_lengths: FxHashMap<PageTag, u32>) -> Self {
+        Self(self.0, self.1, self.2, stream_lengths)
     }
 
     pub fn new_sink(&self, page_tag: PageTag) -> SerializationSink {
+        let compression = match page_tag {
+            PageTag::Events => self.1,
+            PageTag::StringData
+            | PageTag::StringIndex
+            | PageTag::Metadata
+            | PageTag::TraceContext
+            | PageTag::TimestampEpochIndex
+            | PageTag::Segment => Compression::None,
+        };
+
+        // Rotating files re-emit the strings that the events of each segment
+        // use, so they need to know about a string before any event that uses
+        // it, see `RotatingFiles`.
+        let write_through = match page_tag {
+            PageTag::StringData
+            | PageTag::StringIndex
+            | PageTag::Metadata
+            | PageTag::TraceContext => {
+                matches!(*(self.0).0.lock(), BackingStorage::RotatingFiles(_))
+            }
+            PageTag::Events | PageTag::TimestampEpochIndex | PageTag::Segment => false,
+        };
+
         SerializationSink {
             data: Mutex::new(SerializationSinkInner {
-                buffer: Vec::with_capacity(MAX_PAGE_SIZE),
-                addr: 0,
+                buffer: Vec::with_capacity(self.2),
+                addr: self.3.get(&page_tag).copied().unwrap_or(0),
             }),
             shared_state: self.0.clone(),
             page_tag,
+            compression,
+            page_size: self.2,
+            write_through,
         }
     }
 }
@@ -118,28 +417,65 @@ impl SerializationSinkBuilder {
 enum BackingStorage {
     File(fs::File),
     Memory(Vec<u8>),
+    RotatingFiles(Box<RotatingFiles>),
+    Custom(CustomSink),
+    /// Discards all pages.
+    Null,
 }
 
-impl Write for BackingStorage {
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+struct CustomSink(Box<dyn PageSink>);
+
+impl Debug for CustomSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomSink")
+    }
+}
+
+impl BackingStorage {
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         match *self {
-            BackingStorage::File(ref mut file) => file.write(buf),
-            BackingStorage::Memory(ref mut vec) => vec.write(buf),
+            BackingStorage::File(ref mut file) => Ok(write_page_to(file, page_tag, bytes)?),
+            BackingStorage::Memory(ref mut vec) => Ok(write_page_to(vec, page_tag, bytes)?),
+            BackingStorage::RotatingFiles(ref mut files) => files.write_page(page_tag, bytes),
+            BackingStorage::Custom(ref mut sink) => sink.0.write_page(page_tag, bytes),
+            BackingStorage::Null => Ok(()),
         }
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
+    fn flush(&mut self) -> io::Result<()> {
         match *self {
             BackingStorage::File(ref mut file) => file.flush(),
             BackingStorage::Memory(_) => {
                 // Nothing to do
                 Ok(())
             }
+            BackingStorage::RotatingFiles(ref mut files) => files.flush(),
+            BackingStorage::Custom(ref mut sink) => sink.0.flush(),
+            BackingStorage::Null => Ok(()),
         }
     }
 }
 
+/// Writes a page with the given tag and contents, including its page header,
+/// to `dest`. This is the encoding expected by readers like `iter_pages`.
+///
+/// Pages are only ever appended, one after the other while holding the lock
+/// of the backing storage, and never modified once they have been written.
+/// This is what allows `ProfilingData::open_incremental` to read a profile
+/// while it is still being written: everything before the end of the last
+/// complete page (see `complete_pages_len`) is final.
+pub fn write_page_to(dest: &mut dyn Write, page_tag: PageTag, bytes: &[u8]) -> io::Result<()> {
+    dest.write_all(&[page_tag as u8])?;
+
+    let page_size: [u8; 4] = (bytes.len() as u32).to_le_bytes();
+    dest.write_all(&page_size)?;
+    dest.write_all(bytes)
+}
+
 /// This struct allows to treat `SerializationSink` as `std::io::Write`.
 pub struct StdWriteAdapter<'a>(&'a SerializationSink);
 
@@ -185,7 +521,12 @@ impl SharedState {
     fn copy_bytes_with_page_tag(&self, page_tag: PageTag) -> Vec<u8> {
         let data = self.0.lock();
         let data = match *data {
-            BackingStorage::File(_) => panic!(),
+            BackingStorage::File(_)
+            | BackingStorage::RotatingFiles(_)
+            | BackingStorage::Custom(_)
+            | BackingStorage::Null => {
+                panic!()
+            }
             BackingStorage::Memory(ref data) => data,
         };
 
@@ -193,6 +534,410 @@ impl SharedState {
     }
 }
 
+/// A growable byte buffer that profiling data can be written to instead of a
+/// file, e.g. in unit tests or when embedding the profiler in a process that
+/// wants to handle the data itself (see `Profiler::with_sink`). The buffer is
+/// shared between all clones of an `InMemorySink`.
+#[derive(Clone, Debug)]
+pub struct InMemorySink(SharedState);
+
+impl InMemorySink {
+    pub fn new() -> InMemorySink {
+        InMemorySink(SharedState(Arc::new(Mutex::new(BackingStorage::Memory(
+            Vec::new(),
+        )))))
+    }
+
+    /// Appends `bytes` to the buffer as they are, without a page header. This
+    /// is meant for writing the top-level file header.
+    pub fn write_raw(&self, bytes: &[u8]) {
+        match *(self.0).0.lock() {
+            BackingStorage::Memory(ref mut data) => data.extend_from_slice(bytes),
+            BackingStorage::File(_)
+            | BackingStorage::RotatingFiles(_)
+            | BackingStorage::Custom(_)
+            | BackingStorage::Null => {
+                unreachable!()
+            }
+        }
+    }
+
+    /// Returns a copy of all data written so far. The bytes are exactly what
+    /// would have been written to a file, so they can be read with e.g.
+    /// analyzeme's `ProfilingData::from_paged_buffer`. The sinks writing to
+    /// this buffer have to be dropped first (e.g. by dropping the `Profiler`),
+    /// since they only write their last page then.
+    pub fn bytes(&self) -> Vec<u8> {
+        match *(self.0).0.lock() {
+            BackingStorage::Memory(ref data) => data.clone(),
+            BackingStorage::File(_)
+            | BackingStorage::RotatingFiles(_)
+            | BackingStorage::Custom(_)
+            | BackingStorage::Null => {
+                unreachable!()
+            }
+        }
+    }
+}
+
+impl Default for InMemorySink {
+    fn default() -> InMemorySink {
+        InMemorySink::new()
+    }
+}
+
+/// The destination of the pages written by the sinks of a `Profiler`, see
+/// `Profiler::with_sink`. Implementations can e.g. send the data over the
+/// network or keep only the most recent events, like `RingBufferSink`.
+///
+/// The profiler first calls `write_file_header` with the top-level file
+/// header, and then `write_page` for every page of every stream (events,
+/// string data, string index and metadata), one page at a time and never
+/// concurrently. The data that the methods receive, in the order in which
+/// they receive it, is exactly what `Profiler::new` would write to a file.
+///
+/// Pages are the unit of atomicity: a page is only ever passed to the sink
+/// once it is complete, and a page that has been passed on must not be
+/// modified afterwards. Readers that process a profile while it is being
+/// written, like `ProfilingData::open_incremental`, rely on everything up
+/// to the end of the last complete page being final, so implementations
+/// that make the data visible to such readers must only ever append whole
+/// pages (see `write_page_to`). Pages of the string table must not be
+/// dropped, since events refer to strings in earlier pages.
+pub trait PageSink: Send + 'static {
+    /// Receives the top-level file header, before any page.
+    fn write_file_header(&mut self, header: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Receives the contents of a page of the stream identified by
+    /// `page_tag`, without the page header.
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Called when the profiler is flushed, e.g. by `Profiler::flush`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PageSink for InMemorySink {
+    fn write_file_header(&mut self, header: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.write_raw(header);
+        Ok(())
+    }
+
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (self.0).0.lock().write_page(page_tag, contents)
+    }
+}
+
+/// A `PageSink` that writes the profile to `W`, e.g. a `std::fs::File` or a
+/// `std::net::TcpStream` to a process collecting profiles. The bytes are
+/// exactly those of a `.mm_profdata` file.
+#[derive(Debug)]
+pub struct WriteSink<W>(pub W);
+
+impl<W: Write + Send + 'static> PageSink for WriteSink<W> {
+    fn write_file_header(&mut self, header: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(self.0.write_all(header)?)
+    }
+
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(write_page_to(&mut self.0, page_tag, contents)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A `PageSink` that keeps the profile in memory and only starts writing it
+/// to `W`, e.g. a `std::fs::File`, once it has grown beyond a threshold.
+/// Short-lived processes whose profile stays below the threshold then write
+/// it in a single `write_all` when the sink is dropped, while the profiles of
+/// long-running ones are written page by page from the moment they've spilled
+/// over, with the memory of the buffer freed. The bytes are exactly those of
+/// a `.mm_profdata` file either way, like with a `WriteSink`.
+///
+/// `flush` only flushes `W` once the profile has spilled over, before that
+/// the data stays in memory, so `ProfilerOptions::flush_interval` doesn't
+/// help with processes that are killed before then.
+#[derive(Debug)]
+pub struct SpillingSink<W: Write> {
+    writer: W,
+    max_buffered_bytes: usize,
+    // The header and the pages written so far, with their page headers, or
+    // `None` once they have been spilled to `writer`.
+    buffer: Option<Vec<u8>>,
+}
+
+impl<W: Write> SpillingSink<W> {
+    /// Creates a sink that keeps up to `max_buffered_bytes` bytes in memory,
+    /// and writes them and all pages after them to `writer` once the next
+    /// page would exceed that.
+    pub fn new(writer: W, max_buffered_bytes: usize) -> SpillingSink<W> {
+        SpillingSink {
+            writer,
+            max_buffered_bytes,
+            buffer: Some(Vec::new()),
+        }
+    }
+
+    /// Whether the profile has grown beyond `max_buffered_bytes`, and is
+    /// being written to `W` page by page.
+    pub fn has_spilled(&self) -> bool {
+        self.buffer.is_none()
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        match self.buffer.take() {
+            Some(buffer) => self.writer.write_all(&buffer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> PageSink for SpillingSink<W> {
+    fn write_file_header(&mut self, header: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.buffer {
+            Some(ref mut buffer) => buffer.extend_from_slice(header),
+            None => self.writer.write_all(header)?,
+        }
+        Ok(())
+    }
+
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.buffer {
+            Some(ref mut buffer) => {
+                write_page_to(buffer, page_tag, contents)?;
+                if buffer.len() > self.max_buffered_bytes {
+                    self.spill()?;
+                }
+            }
+            None => write_page_to(&mut self.writer, page_tag, contents)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.has_spilled() {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for SpillingSink<W> {
+    fn drop(&mut self) {
+        // Errors can't be returned from here, but like when `SerializationSink`
+        // fails to write a page, the profile mustn't be lost silently.
+        self.spill()
+            .and_then(|()| self.writer.flush())
+            .expect("failed to write the buffered profile");
+    }
+}
+
+/// A `PageSink` that keeps only the most recent events pages in memory, for
+/// capturing what happened right before some point of interest without
+/// writing a complete profile ("flight recorder" style). Once the events
+/// pages exceed the capacity, the oldest ones are overwritten.
+///
+/// The string table and metadata pages are always kept, since events refer
+/// to strings allocated before them, and so is the first events page, which
+/// starts with the header of the events stream. The trace context pages
+/// are overwritten like the events pages, once they exceed the same
+/// capacity. Since every entry belongs to an interval event and a marker,
+/// which together take twice its size, this keeps the entries of the events
+/// that are kept. `snapshot` combines them with the events pages that
+/// haven't been overwritten into a complete profile.
+///
+/// This means that the capacity only bounds the events: the string table
+/// grows with every string the profiler allocates, and the metadata with
+/// every entry that is recorded, for as long as the profiler runs. Events
+/// with unique arguments allocate a string each, so long sessions should
+/// also set `ProfilerOptions::max_string_bytes`, which stops the profiler
+/// from allocating arguments once the string table has reached that size.
+/// `string_bytes` tells how much memory the string table and metadata
+/// pages take up.
+///
+/// Note that the events of a thread in the snapshot don't have to be
+/// properly nested, since the events that contained them may have been
+/// overwritten, and that timestamps may be decoded incorrectly if the
+/// snapshot doesn't go back to the latest `TIMESTAMP_EPOCH_EVENT_KIND`
+/// marker, i.e. if more than `TIMESTAMP_EPOCH_LENGTH` nanoseconds have
+/// passed since the profiler's oldest remaining event.
+///
+/// The buffer is shared between all clones of a `RingBufferSink`, so a
+/// clone can be kept for taking snapshots while the profiler is running.
+#[derive(Clone, Debug)]
+pub struct RingBufferSink(Arc<Mutex<RingBuffer>>);
+
+#[derive(Debug)]
+struct RingBuffer {
+    max_event_bytes: usize,
+    header: Vec<u8>,
+    // All string table and metadata pages, with their page headers.
+    string_pages: Vec<u8>,
+    // The trace context pages that haven't been overwritten, with their page
+    // headers, their total size, and the size of the contents of the
+    // overwritten ones.
+    trace_context_pages: VecDeque<Vec<u8>>,
+    trace_context_bytes: usize,
+    overwritten_trace_context_bytes: usize,
+    first_events_page: Option<Vec<u8>>,
+    // The other events pages that haven't been overwritten, with their page
+    // headers, and their total size.
+    events_pages: VecDeque<Vec<u8>>,
+    events_bytes: usize,
+    overwritten_pages: u64,
+}
+
+impl RingBufferSink {
+    /// Creates a sink that keeps up to `max_event_bytes` bytes of events
+    /// pages, in addition to the first one. At least the most recent events
+    /// page is kept even if it is larger than that, so the capacity should be
+    /// a multiple of the page size (see `ProfilerOptions::page_size`).
+    pub fn new(max_event_bytes: usize) -> RingBufferSink {
+        RingBufferSink(Arc::new(Mutex::new(RingBuffer {
+            max_event_bytes,
+            header: Vec::new(),
+            string_pages: Vec::new(),
+            trace_context_pages: VecDeque::new(),
+            trace_context_bytes: 0,
+            overwritten_trace_context_bytes: 0,
+            first_events_page: None,
+            events_pages: VecDeque::new(),
+            events_bytes: 0,
+            overwritten_pages: 0,
+        })))
+    }
+
+    /// Returns a complete profile made of the pages that are currently in the
+    /// buffer, which can be read with e.g. analyzeme's
+    /// `ProfilingData::from_paged_buffer`. Events that are still buffered by
+    /// the profiler are only included once they have been written, e.g. by
+    /// `Profiler::flush` or by dropping the profiler.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let ring = self.0.lock();
+
+        let mut data = Vec::with_capacity(
+            ring.header.len()
+                + ring.string_pages.len()
+                + PAGE_HEADER_SIZE
+                + TRACE_CONTEXT_PRELUDE_SIZE
+                + ring.trace_context_bytes
+                + ring.first_events_page.as_ref().map_or(0, Vec::len)
+                + ring.events_bytes,
+        );
+        data.extend_from_slice(&ring.header);
+        data.extend_from_slice(&ring.string_pages);
+
+        // The first page of the stream, which starts with its header, may
+        // have been overwritten as well, so the snapshot gets a header of its
+        // own that says which entry comes first.
+        if ring.overwritten_trace_context_bytes > 0 {
+            let first_index = (ring.overwritten_trace_context_bytes - TRACE_CONTEXT_PRELUDE_SIZE)
+                / TRACE_CONTEXT_ENTRY_SIZE;
+            let mut prelude = Vec::with_capacity(TRACE_CONTEXT_PRELUDE_SIZE);
+            write_file_header(&mut prelude, FILE_MAGIC_TRACE_CONTEXT).unwrap();
+            prelude.extend_from_slice(&(first_index as u64).to_le_bytes());
+            write_page_to(&mut data, PageTag::TraceContext, &prelude).unwrap();
+        }
+        for page in &ring.trace_context_pages {
+            data.extend_from_slice(page);
+        }
+
+        if let Some(ref page) = ring.first_events_page {
+            data.extend_from_slice(page);
+        }
+        for page in &ring.events_pages {
+            data.extend_from_slice(page);
+        }
+
+        data
+    }
+
+    /// The number of events pages that have been overwritten so far.
+    pub fn overwritten_pages(&self) -> u64 {
+        self.0.lock().overwritten_pages
+    }
+
+    /// The size of the string table and metadata pages, with their page
+    /// headers. These are never overwritten, see the type's documentation.
+    pub fn string_bytes(&self) -> usize {
+        self.0.lock().string_pages.len()
+    }
+}
+
+impl PageSink for RingBufferSink {
+    fn write_file_header(&mut self, header: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.0.lock().header = header.to_vec();
+        Ok(())
+    }
+
+    fn write_page(
+        &mut self,
+        page_tag: PageTag,
+        contents: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut ring = self.0.lock();
+
+        let mut page = Vec::with_capacity(PAGE_HEADER_SIZE + contents.len());
+        write_page_to(&mut page, page_tag, contents)?;
+
+        match page_tag {
+            PageTag::Events if ring.first_events_page.is_none() => {
+                ring.first_events_page = Some(page);
+            }
+            PageTag::Events => {
+                ring.events_bytes += page.len();
+                ring.events_pages.push_back(page);
+
+                while ring.events_bytes > ring.max_event_bytes && ring.events_pages.len() > 1 {
+                    let oldest = ring.events_pages.pop_front().unwrap();
+                    ring.events_bytes -= oldest.len();
+                    ring.overwritten_pages += 1;
+                }
+            }
+            PageTag::TraceContext => {
+                ring.trace_context_bytes += page.len();
+                ring.trace_context_pages.push_back(page);
+
+                while ring.trace_context_bytes > ring.max_event_bytes
+                    && ring.trace_context_pages.len() > 1
+                {
+                    let oldest = ring.trace_context_pages.pop_front().unwrap();
+                    ring.trace_context_bytes -= oldest.len();
+                    ring.overwritten_trace_context_bytes += oldest.len() - PAGE_HEADER_SIZE;
+                }
+            }
+            PageTag::StringData | PageTag::StringIndex | PageTag::Metadata | PageTag::Segment => {
+                ring.string_pages.extend_from_slice(&page);
+            }
+            // The events of a snapshot don't start at the first event, which
+            // the positions in the index count from, so readers scan them for
+            // the markers instead.
+            PageTag::TimestampEpochIndex => {}
+        }
+
+        Ok(())
+    }
+}
+
 /// This function reconstructs the individual data streams from their paged
 /// version.
 ///
@@ -215,23 +960,65 @@ impl SharedState {
 pub fn split_streams(paged_data: &[u8]) -> FxHashMap<PageTag, Vec<u8>> {
     let mut result: FxHashMap<PageTag, Vec<u8>> = FxHashMap::default();
 
+    for (tag, page_contents) in iter_pages(paged_data) {
+        result
+            .entry(tag)
+            .or_default()
+            .extend_from_slice(page_contents);
+    }
+
+    result
+}
+
+/// Iterates over the pages in `paged_data`, yielding each page's tag and
+/// contents. Pages with optional tags that aren't known are skipped, see
+/// `FIRST_OPTIONAL_PAGE_TAG`.
+pub fn iter_pages(paged_data: &[u8]) -> impl Iterator<Item = (PageTag, &[u8])> {
     let mut pos = 0;
-    while pos < paged_data.len() {
-        let tag = TryInto::try_into(paged_data[pos]).unwrap();
+
+    std::iter::from_fn(move || loop {
+        if pos >= paged_data.len() {
+            return None;
+        }
+
+        let tag = paged_data[pos];
         let page_size =
             u32::from_le_bytes(paged_data[pos + 1..pos + 5].try_into().unwrap()) as usize;
 
         assert!(page_size > 0);
 
-        result
-            .entry(tag)
-            .or_default()
-            .extend_from_slice(&paged_data[pos + 5..pos + 5 + page_size]);
-
+        let page_contents = &paged_data[pos + 5..pos + 5 + page_size];
         pos += page_size + 5;
+
+        match PageTag::try_from(tag) {
+            Ok(tag) => return Some((tag, page_contents)),
+            Err(_) if is_optional_page_tag(tag) => continue,
+            Err(msg) => panic!("{}", msg),
+        }
+    })
+}
+
+/// Returns the length of the longest prefix of `paged_data` that consists of
+/// complete pages. For data that is still being written, the bytes after that
+/// belong to a page whose header or contents haven't been written completely
+/// yet. For files written by a process that crashed, they belong to a page
+/// that is incomplete or, if the header has been written only partially,
+/// corrupt.
+pub fn complete_pages_len(paged_data: &[u8]) -> usize {
+    let mut pos = 0;
+
+    while let Some(header) = paged_data.get(pos..pos + PAGE_HEADER_SIZE) {
+        let page_size = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+        if paged_data.len() < pos + PAGE_HEADER_SIZE + page_size
+            || page_size == 0
+            || (PageTag::try_from(header[0]).is_err() && !is_optional_page_tag(header[0]))
+        {
+            break;
+        }
+        pos += PAGE_HEADER_SIZE + page_size;
     }
 
-    result
+    pos
 }
 
 impl SerializationSink {
@@ -241,18 +1028,19 @@ impl SerializationSink {
     /// (i.e. `bytes`).
     fn write_page(&self, bytes: &[u8]) {
         if bytes.len() > 0 {
-            // We explicitly don't assert `bytes.len() >= MIN_PAGE_SIZE` because
-            // `MIN_PAGE_SIZE` is just a recommendation and the last page will
+            // We explicitly don't assert `bytes.len() >= self.page_size / 2`
+            // because that is just a recommendation and the last page will
             // often be smaller than that.
-            assert!(bytes.len() <= MAX_PAGE_SIZE);
-
-            let mut file = self.shared_state.0.lock();
+            assert!(bytes.len() <= self.page_size);
 
-            file.write_all(&[self.page_tag as u8]).unwrap();
+            // Compress before taking the lock, so that sinks don't have to
+            // wait for each other.
+            let page = self.compression.encode_page(bytes);
 
-            let page_size: [u8; 4] = (bytes.len() as u32).to_le_bytes();
-            file.write_all(&page_size).unwrap();
-            file.write_all(&bytes[..]).unwrap();
+            // The lock is released before panicking, so that a panic hook
+            // can still flush the profiler, see `ProfilerRef::flush_on_panic`.
+            let result = self.shared_state.0.lock().write_page(self.page_tag, &page);
+            result.unwrap();
         }
     }
 
@@ -263,6 +1051,32 @@ impl SerializationSink {
         buffer.clear();
     }
 
+    /// Writes the data buffered so far as a page of its own, even if that
+    /// page isn't full, so that it ends up in the backing storage without
+    /// dropping the sink. The pages written like this are smaller than usual,
+    /// which makes the data somewhat larger.
+    pub fn flush_buffer(&self) {
+        let mut data = self.data.lock();
+        self.flush(&mut data.buffer);
+    }
+
+    /// Flushes the backing storage that this sink shares with the other sinks
+    /// of its `SerializationSinkBuilder`, e.g. the `PageSink` of a profiler
+    /// created with `Profiler::with_sink`, so that it passes on what has been
+    /// written so far. `flush_buffer` only writes to the backing storage.
+    pub fn flush_backing_storage(&self) {
+        let result = self.shared_state.0.lock().flush();
+        result.unwrap();
+    }
+
+    /// Whether nothing has been written to the stream of this sink yet,
+    /// neither by this sink nor, for a sink that continues the stream of an
+    /// existing profile (see `SerializationSinkBuilder::continuing_streams`),
+    /// before. Only empty streams still need their stream header.
+    pub fn is_empty(&self) -> bool {
+        self.data.lock().addr == 0
+    }
+
     /// Creates a copy of all data written so far. This method is meant to be
     /// used for writing unit tests. It will panic if the underlying
     /// `BackingStorage` is a file.
@@ -302,7 +1116,7 @@ impl SerializationSink {
     where
         W: FnOnce(&mut [u8]),
     {
-        if num_bytes > MAX_PAGE_SIZE {
+        if num_bytes > self.page_size || self.write_through {
             let mut bytes = vec![0u8; num_bytes];
             write(&mut bytes[..]);
             return self.write_bytes_atomic(&bytes[..]);
@@ -314,7 +1128,7 @@ impl SerializationSink {
             ref mut addr,
         } = *data;
 
-        if buffer.len() + num_bytes > MAX_PAGE_SIZE {
+        if buffer.len() + num_bytes > self.page_size {
             self.flush(buffer);
             assert!(buffer.is_empty());
         }
@@ -343,7 +1157,7 @@ impl SerializationSink {
     /// refer to the data later on.
     pub fn write_bytes_atomic(&self, bytes: &[u8]) -> Addr {
         // For "small" data we go to the buffered version immediately.
-        if bytes.len() <= 128 {
+        if bytes.len() <= 128 && !self.write_through {
             return self.write_atomic(bytes.len(), |sink| {
                 sink.copy_from_slice(bytes);
             });
@@ -358,12 +1172,23 @@ impl SerializationSink {
         let curr_addr = Addr(*addr);
         *addr += bytes.len() as u32;
 
+        if self.write_through {
+            for chunk in bytes.chunks(self.page_size) {
+                self.write_page(chunk);
+            }
+            return curr_addr;
+        }
+
         let mut bytes_left = bytes;
 
+        // The number of bytes we consider enough to warrant their own page
+        // when deciding whether to flush a partially full buffer.
+        let min_page_size = self.page_size / 2;
+
         // Do we have too little data in the buffer? If so, fill up the buffer
         // to the minimum page size.
-        if buffer.len() < MIN_PAGE_SIZE {
-            let num_bytes_to_take = min(MIN_PAGE_SIZE - buffer.len(), bytes_left.len());
+        if buffer.len() < min_page_size {
+            let num_bytes_to_take = min(min_page_size - buffer.len(), bytes_left.len());
             buffer.extend_from_slice(&bytes_left[..num_bytes_to_take]);
             bytes_left = &bytes_left[num_bytes_to_take..];
         }
@@ -375,8 +1200,8 @@ impl SerializationSink {
         // Make sure we flush the buffer before writing out any other pages.
         self.flush(buffer);
 
-        for chunk in bytes_left.chunks(MAX_PAGE_SIZE) {
-            if chunk.len() == MAX_PAGE_SIZE {
+        for chunk in bytes_left.chunks(self.page_size) {
+            if chunk.len() == self.page_size {
                 // This chunk has the maximum size. It might or might not be the
                 // last one. In either case we want to write it to disk
                 // immediately because there is no reason to copy it to the
@@ -387,7 +1212,7 @@ impl SerializationSink {
                 // it must be the last one. If it is big enough to warrant its
                 // own page, we write it to disk immediately. Otherwise, we copy
                 // it to the buffer.
-                if chunk.len() >= MIN_PAGE_SIZE {
+                if chunk.len() >= min_page_size {
                     self.write_page(chunk);
                 } else {
                     debug_assert!(buffer.is_empty());
@@ -420,6 +1245,9 @@ impl Drop for SerializationSink {
 mod tests {
     use super::*;
 
+    const MAX_PAGE_SIZE: usize = DEFAULT_PAGE_SIZE;
+    const MIN_PAGE_SIZE: usize = DEFAULT_PAGE_SIZE / 2;
+
     // This function writes `chunk_count` byte-slices of size `chunk_size` to
     // three `SerializationSinks` that all map to the same underlying stream,
     // so we get interleaved pages with different tags.
@@ -492,6 +1320,284 @@ mod tests {
     mk_roundtrip_test!(max_page_size_plus_one, MAX_PAGE_SIZE + 1, 10);
     mk_roundtrip_test!(max_page_size_minus_one, MAX_PAGE_SIZE - 1, 10);
 
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_pages_roundtrip() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory()
+            .with_compression(Compression::Zstd { level: 3 });
+        let expected: Vec<u8> = (0..MAX_PAGE_SIZE * 3).map(|x| (x / 1000) as u8).collect();
+
+        let paged_data = {
+            let events_sink = sink_builder.new_sink(PageTag::Events);
+            let string_sink = sink_builder.new_sink(PageTag::StringData);
+            events_sink.write_bytes_atomic(&expected);
+            string_sink.write_bytes_atomic(b"not compressed");
+            drop(events_sink);
+            drop(string_sink);
+
+            match *(sink_builder.0).0.lock() {
+                BackingStorage::Memory(ref data) => data.clone(),
+                _ => unreachable!(),
+            }
+        };
+
+        let mut events = Vec::new();
+        for (tag, page_contents) in iter_pages(&paged_data) {
+            match tag {
+                PageTag::Events => {
+                    assert!(page_contents.len() < MAX_PAGE_SIZE / 2);
+                    events.extend(decompress_page(FILE_CODEC_ZSTD, page_contents).unwrap());
+                }
+                PageTag::StringData => assert_eq!(page_contents, b"not compressed"),
+                PageTag::StringIndex
+                | PageTag::Metadata
+                | PageTag::TraceContext
+                | PageTag::TimestampEpochIndex
+                | PageTag::Segment => {
+                    unreachable!()
+                }
+            }
+        }
+
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn complete_pages_len_of_partial_data() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory();
+        let events_sink = sink_builder.new_sink(PageTag::Events);
+        let string_sink = sink_builder.new_sink(PageTag::StringData);
+
+        events_sink.write_bytes_atomic(&[1; 10]);
+        drop(events_sink);
+        string_sink.write_bytes_atomic(&[2; 20]);
+        drop(string_sink);
+
+        let paged_data = match *(sink_builder.0).0.lock() {
+            BackingStorage::Memory(ref data) => data.clone(),
+            _ => unreachable!(),
+        };
+        let first_page_len = PAGE_HEADER_SIZE + 10;
+        assert_eq!(paged_data.len(), first_page_len + PAGE_HEADER_SIZE + 20);
+
+        assert_eq!(complete_pages_len(&paged_data), paged_data.len());
+        assert_eq!(
+            complete_pages_len(&paged_data[..paged_data.len() - 1]),
+            first_page_len
+        );
+        assert_eq!(
+            complete_pages_len(&paged_data[..first_page_len + 2]),
+            first_page_len
+        );
+        assert_eq!(
+            complete_pages_len(&paged_data[..first_page_len]),
+            first_page_len
+        );
+        assert_eq!(complete_pages_len(&paged_data[..3]), 0);
+
+        // Garbage after the last complete page isn't counted either.
+        let mut corrupt_data = paged_data[..first_page_len].to_vec();
+        corrupt_data.extend_from_slice(&[0xAB, 1, 0, 0, 0, 0]);
+        assert_eq!(complete_pages_len(&corrupt_data), first_page_len);
+
+        let mut empty_page = paged_data[..first_page_len].to_vec();
+        empty_page.extend_from_slice(&[PageTag::Events as u8, 0, 0, 0, 0]);
+        assert_eq!(complete_pages_len(&empty_page), first_page_len);
+
+        // Pages of optional sections are complete pages, and skipped.
+        let mut optional_page = paged_data[..first_page_len].to_vec();
+        optional_page.extend_from_slice(&[LAST_OPTIONAL_PAGE_TAG, 2, 0, 0, 0, 3, 3]);
+        optional_page.extend_from_slice(&paged_data[first_page_len..]);
+        assert_eq!(complete_pages_len(&optional_page), optional_page.len());
+        let tags: Vec<_> = iter_pages(&optional_page).map(|(tag, _)| tag).collect();
+        assert_eq!(tags, vec![PageTag::Events, PageTag::StringData]);
+    }
+
+    #[test]
+    fn in_memory_sink_matches_file() {
+        fn write_data(sink_builder: SerializationSinkBuilder) {
+            let events_sink = sink_builder.new_sink(PageTag::Events);
+            let string_sink = sink_builder.new_sink(PageTag::StringData);
+            for i in 0..MAX_PAGE_SIZE / 10 {
+                events_sink.write_bytes_atomic(&[i as u8; 7]);
+                string_sink.write_bytes_atomic(&[i as u8; 13]);
+            }
+        }
+
+        let header = b"top-level header";
+
+        let path = std::env::temp_dir().join(format!(
+            "measureme-in-memory-sink-test-{}",
+            std::process::id()
+        ));
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(header).unwrap();
+        write_data(SerializationSinkBuilder::new_from_file(file).unwrap());
+        let file_data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let sink = InMemorySink::new();
+        sink.write_raw(header);
+        write_data(SerializationSinkBuilder::new_from_in_memory_sink(&sink));
+
+        assert!(file_data.len() > MAX_PAGE_SIZE * 2);
+        assert!(sink.bytes() == file_data);
+
+        // The same bytes end up in page sinks.
+        let mut write_sink = WriteSink(fs::File::create(&path).unwrap());
+        write_sink.write_file_header(header).unwrap();
+        write_data(SerializationSinkBuilder::new_from_page_sink(write_sink));
+        let write_sink_data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(write_sink_data == file_data);
+
+        let mut in_memory_sink = InMemorySink::new();
+        in_memory_sink.write_file_header(header).unwrap();
+        write_data(SerializationSinkBuilder::new_from_page_sink(
+            in_memory_sink.clone(),
+        ));
+        assert!(in_memory_sink.bytes() == file_data);
+    }
+
+    #[test]
+    fn spilling_sink_matches_write_sink() {
+        let header = b"top-level header";
+        let write_pages = |sink: &mut dyn PageSink| {
+            sink.write_file_header(header).unwrap();
+            for i in 0..10 {
+                sink.write_page(PageTag::Events, &[i; 100]).unwrap();
+                sink.write_page(PageTag::StringData, &[i; 30]).unwrap();
+            }
+            sink.flush().unwrap();
+        };
+
+        let mut expected = WriteSink(Vec::new());
+        write_pages(&mut expected);
+        let expected = expected.0;
+
+        let path = std::env::temp_dir().join(format!(
+            "measureme-spilling-sink-test-{}",
+            std::process::id()
+        ));
+        // The number of bytes in the file before the sink is dropped, and
+        // all bytes in the file afterwards.
+        let spill = |max_buffered_bytes| {
+            let mut sink = SpillingSink::new(fs::File::create(&path).unwrap(), max_buffered_bytes);
+            write_pages(&mut sink);
+            let before_drop = fs::read(&path).unwrap().len();
+            assert_eq!(sink.has_spilled(), before_drop > 0);
+            drop(sink);
+            (before_drop, fs::read(&path).unwrap())
+        };
+
+        // Everything fits, so it is only written once the sink is dropped.
+        assert!(spill(expected.len()) == (0, expected.clone()));
+        assert!(spill(usize::MAX) == (0, expected.clone()));
+
+        // Spilled early, or with the very last page.
+        assert!(spill(500) == (expected.len(), expected.clone()));
+        assert!(spill(expected.len() - 1) == (expected.len(), expected.clone()));
+        assert!(spill(0) == (expected.len(), expected));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ring_buffer_sink_overwrites_oldest_events_pages() {
+        let page = |page_tag: PageTag, contents: &[u8]| {
+            let mut page = Vec::new();
+            write_page_to(&mut page, page_tag, contents).unwrap();
+            page
+        };
+
+        let ring = RingBufferSink::new(2 * (PAGE_HEADER_SIZE + 10));
+        let mut sink = ring.clone();
+        sink.write_file_header(b"header").unwrap();
+
+        sink.write_page(PageTag::Events, &[0; 10]).unwrap();
+        sink.write_page(PageTag::StringData, b"strings1").unwrap();
+        for i in 1..5 {
+            sink.write_page(PageTag::Events, &[i; 10]).unwrap();
+        }
+        sink.write_page(PageTag::StringIndex, b"index").unwrap();
+
+        // The first events page and the string table are always kept, of the
+        // other events pages only the two most recent ones.
+        let expected = [
+            &b"header"[..],
+            &page(PageTag::StringData, b"strings1"),
+            &page(PageTag::StringIndex, b"index"),
+            &page(PageTag::Events, &[0; 10]),
+            &page(PageTag::Events, &[3; 10]),
+            &page(PageTag::Events, &[4; 10]),
+        ]
+        .concat();
+        assert!(ring.snapshot() == expected);
+        assert_eq!(ring.overwritten_pages(), 2);
+
+        // The most recent page is kept even if it exceeds the capacity.
+        sink.write_page(PageTag::Events, &[5; 100]).unwrap();
+        let snapshot = ring.snapshot();
+        assert!(snapshot.ends_with(&page(PageTag::Events, &[5; 100])));
+        assert_eq!(split_streams(&snapshot[6..])[&PageTag::Events].len(), 110);
+        assert_eq!(ring.overwritten_pages(), 4);
+    }
+
+    #[test]
+    fn configured_page_size() {
+        let sink_builder = SerializationSinkBuilder::new_in_memory()
+            .with_page_size(100)
+            .unwrap();
+        assert_eq!(sink_builder.page_size(), 128);
+
+        let expected: Vec<u8> = (0..40 * MIN_CONFIGURABLE_PAGE_SIZE)
+            .map(|x| x as u8)
+            .collect();
+
+        let paged_data = {
+            let sink = sink_builder.new_sink(PageTag::Events);
+            for chunk in expected.chunks(MIN_CONFIGURABLE_PAGE_SIZE) {
+                sink.write_bytes_atomic(chunk);
+            }
+            drop(sink);
+
+            match *(sink_builder.0).0.lock() {
+                BackingStorage::Memory(ref data) => data.clone(),
+                _ => unreachable!(),
+            }
+        };
+
+        let mut events = Vec::new();
+        for (_, page_contents) in iter_pages(&paged_data) {
+            assert!(page_contents.len() <= 128);
+            // Each page contains whole chunks only.
+            assert_eq!(page_contents.len() % MIN_CONFIGURABLE_PAGE_SIZE, 0);
+            events.extend_from_slice(page_contents);
+        }
+
+        // 5 chunks fit into a page.
+        assert_eq!(iter_pages(&paged_data).count(), 8);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn invalid_page_size() {
+        let page_size = |size| {
+            SerializationSinkBuilder::new_in_memory()
+                .with_page_size(size)
+                .map(|builder| builder.page_size())
+        };
+
+        assert!(page_size(MIN_CONFIGURABLE_PAGE_SIZE - 1).is_err());
+        assert!(page_size(MAX_CONFIGURABLE_PAGE_SIZE + 1).is_err());
+        assert_eq!(page_size(MIN_CONFIGURABLE_PAGE_SIZE).unwrap(), 32);
+        assert_eq!(page_size(4096).unwrap(), 4096);
+        assert_eq!(
+            page_size(MAX_CONFIGURABLE_PAGE_SIZE).unwrap(),
+            MAX_CONFIGURABLE_PAGE_SIZE
+        );
+    }
+
     mk_roundtrip_test!(exactly_min_page_size, MIN_PAGE_SIZE, 10);
     mk_roundtrip_test!(min_page_size_plus_one, MIN_PAGE_SIZE + 1, 10);
     mk_roundtrip_test!(min_page_size_minus_one, MIN_PAGE_SIZE - 1, 10);
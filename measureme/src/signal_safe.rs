@@ -0,0 +1,315 @@
+//! The buffers that `Profiler::record_instant_raw` appends to, which must
+//! work from within signal handlers: appending an event only uses atomic
+//! operations on memory allocated ahead of time, and never blocks.
+
+use crate::event_id::EventId;
+use crate::stringtable::StringId;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// The number of `drain`s that have to find the same slot incomplete before
+/// its event is given up on, see `SignalSafeBuffer::drain`.
+const STALLED_DRAINS: u32 = 3;
+
+struct Slot {
+    event_kind: AtomicU32,
+    event_id: AtomicU32,
+    timestamp: AtomicU64,
+    // The number of the reservation whose event the fields above hold, plus
+    // one, once they have been written. 0 before the first event.
+    written: AtomicUsize,
+}
+
+/// A bounded ring buffer of instant events recorded by a single thread.
+///
+/// Events are appended by the thread the buffer has been registered for,
+/// including from signal handlers that interrupt each other, and taken out
+/// by `drain`, which may run on any thread but not concurrently with itself.
+/// An event is only taken out once it has been completely written, so a
+/// handler that is interrupted in the middle of `push` just delays the
+/// events after it until the next `drain`. A handler that never finishes
+/// writing its event, e.g. because it has jumped out of `push` with
+/// `siglongjmp`, only delays them for a few `drain`s.
+pub(crate) struct SignalSafeBuffer {
+    thread_id: u32,
+    slots: Box<[Slot]>,
+    // The number of slots that have been handed out to `push` and taken out
+    // by `drain` so far. Slot `n` is at `slots[n % slots.len()]`.
+    reserved: AtomicUsize,
+    drained: AtomicUsize,
+    dropped: AtomicU64,
+    // The number of consecutive `drain`s that have stopped at slot `drained`
+    // because it hasn't been written yet.
+    stalled_drains: AtomicU32,
+    // The buffer that has been registered before this one with the same
+    // profiler, see `SignalSafeBuffers`.
+    next: AtomicPtr<SignalSafeBuffer>,
+}
+
+impl SignalSafeBuffer {
+    pub(crate) fn new(thread_id: u32, capacity: usize) -> SignalSafeBuffer {
+        assert!(capacity > 0, "a signal-safe buffer needs at least one slot");
+
+        SignalSafeBuffer {
+            thread_id,
+            slots: (0..capacity)
+                .map(|_| Slot {
+                    event_kind: AtomicU32::new(0),
+                    event_id: AtomicU32::new(0),
+                    timestamp: AtomicU64::new(0),
+                    written: AtomicUsize::new(0),
+                })
+                .collect(),
+            reserved: AtomicUsize::new(0),
+            drained: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            stalled_drains: AtomicU32::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub(crate) fn thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    /// The number of events that have been dropped because the buffer was
+    /// full, or because they haven't been completely written in time.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Appends an event, or returns `false` if the buffer is full. This is
+    /// async-signal-safe.
+    #[inline]
+    pub(crate) fn push(&self, event_kind: StringId, event_id: EventId, timestamp: u64) -> bool {
+        let mut index = self.reserved.load(Ordering::Relaxed);
+        loop {
+            // `index` may be behind `drained` if a handler that has
+            // interrupted this one has pushed an event that has been drained
+            // in the meantime, which `compare_exchange_weak` catches.
+            let used = index.wrapping_sub(self.drained.load(Ordering::Acquire));
+            if used == self.slots.len() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+
+            match self.reserved.compare_exchange_weak(
+                index,
+                index + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => index = current,
+            }
+        }
+
+        let slot = &self.slots[index % self.slots.len()];
+        slot.event_kind
+            .store(event_kind.as_u32(), Ordering::Relaxed);
+        slot.event_id.store(event_id.as_u32(), Ordering::Relaxed);
+        slot.timestamp.store(timestamp, Ordering::Relaxed);
+        slot.written.store(index.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Takes out the events appended so far, in the order in which their
+    /// slots have been reserved, and stops at the first one that hasn't been
+    /// completely written yet. Must not be called concurrently with itself.
+    ///
+    /// If `STALLED_DRAINS` drains in a row stop at the same event, it is
+    /// dropped, so that the events after it aren't held back forever. Should
+    /// its handler still finish writing it, after its slot has been reused,
+    /// the event in the slot may end up with fields of both events.
+    pub(crate) fn drain(&self, mut f: impl FnMut(StringId, EventId, u64)) {
+        let reserved = self.reserved.load(Ordering::Acquire);
+        let mut drained = self.drained.load(Ordering::Relaxed);
+
+        while drained != reserved {
+            let slot = &self.slots[drained % self.slots.len()];
+            if slot.written.load(Ordering::Acquire) == drained.wrapping_add(1) {
+                f(
+                    StringId::new(slot.event_kind.load(Ordering::Relaxed)),
+                    EventId::from_u32(slot.event_id.load(Ordering::Relaxed)),
+                    slot.timestamp.load(Ordering::Relaxed),
+                );
+            } else if self.stalled_drains.fetch_add(1, Ordering::Relaxed) + 1 == STALLED_DRAINS {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                break;
+            }
+
+            self.stalled_drains.store(0, Ordering::Relaxed);
+            drained = drained.wrapping_add(1);
+            // Hands the slot back to `push`.
+            self.drained.store(drained, Ordering::Release);
+        }
+    }
+}
+
+/// The buffers registered with a profiler, as a list that
+/// `Profiler::record_instant_raw` can search from a signal handler. Buffers
+/// are only ever added, and they are owned by the list until it is dropped.
+pub(crate) struct SignalSafeBuffers {
+    // The buffer registered last, which links to those before it.
+    head: AtomicPtr<SignalSafeBuffer>,
+}
+
+impl SignalSafeBuffers {
+    pub(crate) fn new() -> SignalSafeBuffers {
+        SignalSafeBuffers {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Adds `buffer` in front of the buffers added so far.
+    pub(crate) fn push(&self, buffer: Box<SignalSafeBuffer>) {
+        // From here on, the buffer is owned by the list.
+        let buffer = Box::into_raw(buffer);
+        // SAFETY: `buffer` is only freed when `self` is dropped.
+        let next = unsafe { &(*buffer).next };
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            next.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                buffer,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// The buffers, the one added last first. This is async-signal-safe.
+    #[inline]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &SignalSafeBuffer> {
+        let mut next = self.head.load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            // SAFETY: the buffers are only freed when `self` is dropped, and
+            // `next` of a buffer has been set before it has been added.
+            let buffer = unsafe { next.as_ref()? };
+            next = buffer.next.load(Ordering::Relaxed);
+            Some(buffer)
+        })
+    }
+
+    /// The buffer that has been added last for the thread with id
+    /// `thread_id`. This is async-signal-safe.
+    #[inline]
+    pub(crate) fn find(&self, thread_id: u32) -> Option<&SignalSafeBuffer> {
+        self.iter().find(|buffer| buffer.thread_id == thread_id)
+    }
+}
+
+impl Drop for SignalSafeBuffers {
+    fn drop(&mut self) {
+        let mut next = *self.head.get_mut();
+        while !next.is_null() {
+            // SAFETY: every buffer in the list has been created by
+            // `Box::into_raw` in `push`, and is freed only once.
+            let buffer = unsafe { Box::from_raw(next) };
+            next = buffer.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(buffer: &SignalSafeBuffer) -> Vec<u64> {
+        let mut timestamps = Vec::new();
+        buffer.drain(|_, _, timestamp| timestamps.push(timestamp));
+        timestamps
+    }
+
+    #[test]
+    fn full_buffer_drops_events_until_drained() {
+        let buffer = SignalSafeBuffer::new(7, 3);
+        let kind = StringId::new(1);
+        let id = EventId::from_u32(2);
+
+        assert!(buffer.push(kind, id, 10));
+        assert!(buffer.push(kind, id, 20));
+        assert!(buffer.push(kind, id, 30));
+        assert!(!buffer.push(kind, id, 40));
+        assert_eq!(buffer.dropped(), 1);
+
+        assert_eq!(drain(&buffer), vec![10, 20, 30]);
+        assert_eq!(drain(&buffer), Vec::<u64>::new());
+
+        // The slots are reused after wrapping around.
+        for timestamp in 50..55 {
+            assert!(buffer.push(kind, id, timestamp));
+            let mut events = Vec::new();
+            buffer.drain(|kind, id, timestamp| events.push((kind, id, timestamp)));
+            assert_eq!(events, vec![(kind, id, timestamp)]);
+        }
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(buffer.thread_id(), 7);
+    }
+
+    #[test]
+    fn drain_stops_at_incomplete_events() {
+        let buffer = SignalSafeBuffer::new(0, 4);
+        let kind = StringId::new(1);
+        let id = EventId::from_u32(2);
+
+        assert!(buffer.push(kind, id, 10));
+        // Simulate a handler that has reserved a slot but has been
+        // interrupted by another one before writing to it.
+        let interrupted = buffer.reserved.fetch_add(1, Ordering::Relaxed);
+        assert!(buffer.push(kind, id, 30));
+
+        assert_eq!(drain(&buffer), vec![10]);
+
+        let slot = &buffer.slots[interrupted % buffer.slots.len()];
+        slot.timestamp.store(20, Ordering::Relaxed);
+        slot.written.store(interrupted + 1, Ordering::Release);
+
+        assert_eq!(drain(&buffer), vec![20, 30]);
+    }
+
+    #[test]
+    fn drain_drops_events_that_are_never_completed() {
+        let buffer = SignalSafeBuffer::new(0, 2);
+        let kind = StringId::new(1);
+        let id = EventId::from_u32(2);
+
+        // A handler that has jumped out of `push` after reserving its slot.
+        buffer.reserved.fetch_add(1, Ordering::Relaxed);
+        assert!(buffer.push(kind, id, 20));
+        assert!(!buffer.push(kind, id, 30));
+        assert_eq!(buffer.dropped(), 1);
+
+        for _ in 1..STALLED_DRAINS {
+            assert_eq!(drain(&buffer), Vec::<u64>::new());
+        }
+        assert_eq!(drain(&buffer), vec![20]);
+        assert_eq!(buffer.dropped(), 2);
+
+        // Both slots can be used again.
+        assert!(buffer.push(kind, id, 40));
+        assert!(buffer.push(kind, id, 50));
+        assert_eq!(drain(&buffer), vec![40, 50]);
+    }
+
+    #[test]
+    fn buffers_are_found_by_thread() {
+        let buffers = SignalSafeBuffers::new();
+        assert!(buffers.find(0).is_none());
+
+        buffers.push(Box::new(SignalSafeBuffer::new(0, 1)));
+        buffers.push(Box::new(SignalSafeBuffer::new(1, 2)));
+        buffers.push(Box::new(SignalSafeBuffer::new(0, 3)));
+
+        assert_eq!(buffers.find(0).unwrap().slots.len(), 3);
+        assert_eq!(buffers.find(1).unwrap().slots.len(), 2);
+        assert!(buffers.find(2).is_none());
+        assert_eq!(buffers.iter().count(), 3);
+    }
+}
@@ -0,0 +1,177 @@
+//! Attaching native stacks to events, see
+//! `Profiler::record_event_with_backtrace`.
+
+use crate::event_id::{escape_text, EventId, BACKTRACE_FRAME_TAG_BYTE, SEPARATOR_BYTE};
+use crate::profiler::Profiler;
+use crate::stringtable::{StringComponent, StringId};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use smallvec::SmallVec;
+use std::ffi::c_void;
+
+/// The number of frames that are captured without allocating.
+const INLINE_FRAMES: usize = 16;
+
+/// How many frames of the unwinder itself may precede the frames of the
+/// profiler's own capturing methods, which aren't part of the captured stack.
+const MAX_UNWINDER_FRAMES: usize = 8;
+
+/// The `StringId`s of the frames symbolized so far, by instruction pointer.
+#[derive(Default)]
+pub(crate) struct FrameNames(Mutex<FxHashMap<usize, StringId>>);
+
+impl Profiler {
+    /// Records an instant event whose event id is `event_id` followed by up
+    /// to `max_frames` frames of the calling thread's stack, innermost first,
+    /// see `event_id_with_backtrace`.
+    #[inline(never)]
+    pub fn record_event_with_backtrace(
+        &self,
+        event_kind: StringId,
+        event_id: EventId,
+        thread_id: u32,
+        max_frames: usize,
+    ) {
+        let event_id = self.event_id_with_backtrace(event_id, max_frames);
+        self.record_instant_event(event_kind, event_id, thread_id, None);
+    }
+
+    /// Returns an event id that consists of `event_id` followed by up to
+    /// `max_frames` frames of the calling thread's stack, innermost first, as
+    /// `<backtrace_frame>` arguments (see `event_id`). The result can be
+    /// passed to any of the recording methods, e.g. to attach the stack to
+    /// an interval event.
+    ///
+    /// This is expensive and only meant for the occasional event: the stack
+    /// is unwound on every call, and each call allocates a new event id in
+    /// the string table. Each frame is symbolized only the first time its
+    /// address is seen and the names are cached for the lifetime of the
+    /// profiler, so that repeated stacks mostly cost the unwinding. The
+    /// debug info needed for symbolizing is only loaded on the first call,
+    /// which can therefore take much longer than later ones. Frames that
+    /// can't be symbolized are recorded as their address.
+    #[inline(never)]
+    pub fn event_id_with_backtrace(&self, event_id: EventId, max_frames: usize) -> EventId {
+        if max_frames == 0 || !self.is_enabled() {
+            return event_id;
+        }
+
+        let frame_ips = capture_frame_ips(max_frames);
+
+        // The lock isn't held while frames are symbolized, which is slow, so
+        // that other threads whose frames are cached don't wait for it.
+        let mut names: SmallVec<[Option<StringId>; INLINE_FRAMES]> = {
+            let frame_names = self.frame_names.0.lock();
+            frame_ips
+                .iter()
+                .map(|ip| frame_names.get(ip).copied())
+                .collect()
+        };
+
+        if names.contains(&None) {
+            for (name, &ip) in names.iter_mut().zip(&frame_ips) {
+                if name.is_none() {
+                    *name = Some(self.alloc_string(&escape_text(&symbolize(ip))[..]));
+                }
+            }
+
+            // Another thread may have symbolized the same frames meanwhile,
+            // the names it has cached are kept.
+            let mut frame_names = self.frame_names.0.lock();
+            for (name, &ip) in names.iter_mut().zip(&frame_ips) {
+                *name = Some(*frame_names.entry(ip).or_insert(name.unwrap()));
+            }
+        }
+
+        let mut components = SmallVec::<[StringComponent<'_>; 1 + 3 * INLINE_FRAMES]>::new();
+        components.push(StringComponent::Ref(event_id.to_string_id()));
+        for name in names {
+            components.push(StringComponent::Value(SEPARATOR_BYTE));
+            components.push(StringComponent::Value(BACKTRACE_FRAME_TAG_BYTE));
+            components.push(StringComponent::Ref(name.unwrap()));
+        }
+
+        EventId::from_label(self.alloc_string(&components[..]))
+    }
+}
+
+/// The instruction pointers of the innermost `max_frames` frames of the
+/// caller of the profiler's capturing methods.
+#[inline(never)]
+fn capture_frame_ips(max_frames: usize) -> SmallVec<[usize; INLINE_FRAMES]> {
+    let own_frames = [
+        capture_frame_ips as fn(usize) -> SmallVec<[usize; INLINE_FRAMES]> as usize,
+        Profiler::event_id_with_backtrace as fn(&Profiler, EventId, usize) -> EventId as usize,
+        Profiler::record_event_with_backtrace as fn(&Profiler, StringId, EventId, u32, usize)
+            as usize,
+    ];
+
+    let mut ips = SmallVec::new();
+    let mut found_own_frames = false;
+    backtrace::trace(|frame| {
+        // Everything up to the last of the profiler's own frames belongs to
+        // the unwinder or the profiler. If they can't be recognized, e.g.
+        // because the platform doesn't report the addresses of symbols, the
+        // stack is recorded as it is.
+        if own_frames.contains(&(frame.symbol_address() as usize)) {
+            ips.clear();
+            found_own_frames = true;
+            return true;
+        }
+
+        ips.push(frame.ip() as usize);
+        if found_own_frames {
+            ips.len() < max_frames
+        } else {
+            ips.len() < max_frames + MAX_UNWINDER_FRAMES
+        }
+    });
+
+    ips.truncate(max_frames);
+    ips
+}
+
+/// The demangled name of the function that `ip` belongs to, without the
+/// hash, or the address itself if it can't be symbolized.
+fn symbolize(ip: usize) -> String {
+    let mut name = None;
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        if name.is_none() {
+            name = symbol.name().map(|name| format!("{:#}", name));
+        }
+    });
+    name.unwrap_or_else(|| format!("{:#x}", ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::InMemorySink;
+
+    #[test]
+    fn frames_are_symbolized_once() {
+        let profiler = Profiler::with_sink(InMemorySink::default()).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let event_id = EventId::from_label(profiler.alloc_string("label"));
+
+        assert_eq!(profiler.event_id_with_backtrace(event_id, 0), event_id);
+
+        // Both events are recorded from the same call site, so they have the
+        // same stack.
+        let before = profiler.stats();
+        let mut stats = Vec::new();
+        for _ in 0..2 {
+            profiler.record_event_with_backtrace(event_kind, event_id, 0, 4);
+            stats.push(profiler.stats());
+        }
+        let (first, second) = (stats[0], stats[1]);
+
+        assert_eq!(first.events, before.events + 1);
+        assert!(first.strings > before.strings + 1);
+        assert!(first.strings <= before.strings + 1 + 4);
+
+        // The same stack only needs a new event id.
+        assert_eq!(second.events, first.events + 1);
+        assert_eq!(second.strings, first.strings + 1);
+    }
+}
@@ -3,14 +3,14 @@
 //! Each entry in the table represents a string and is encoded as a list of
 //! components where each component can either be
 //!
-//! 1. a string _value_ that contains actual UTF-8 string content,
+//! 1. a string _value_ that contains actual string content,
 //! 2. a string _ID_ that contains a reference to another entry, or
 //! 3. a terminator tag which marks the end of a component list.
 //!
 //! The string _content_ of an entry is defined as the concatenation of the
 //! content of its components. The content of a string value is its actual
-//! UTF-8 bytes. The content of a string ID is the contents of the entry
-//! it references.
+//! bytes. The content of a string ID is the contents of the entry it
+//! references.
 //!
 //! The byte-level encoding of component lists uses the structure of UTF-8 in
 //! order to save space:
@@ -34,6 +34,15 @@
 //!
 //! As you can see string IDs are encoded in little endian format.
 //!
+//! The content of a string is an opaque sequence of bytes, which is usually,
+//! but not necessarily, valid UTF-8, e.g. for labels created from paths on
+//! Unix systems (see `StringTableBuilder::alloc_bytes`). The two bytes that
+//! can't be part of a value are escaped: an escaped byte is encoded as a
+//! string ID component for `StringId::INVALID`, which never refers to an
+//! actual entry, followed by the byte itself, e.g. `[254, 2, 225, 245, 5,
+//! 255]` for the byte `0xFF`. Readers that don't know about escaping decode
+//! such strings as `<unknown>` followed by the rest of the string.
+//!
 //! ----------------------------------------------------------------------------
 //!
 //! Each string in the table is referred to via a `StringId`. `StringId`s may
@@ -60,11 +69,15 @@
 //! the profiling session. After `METADATA_STRING_ID` are all other `StringId`
 //! values.
 
+use crate::event_id::{ESCAPE_BYTE, SEPARATOR_BYTE};
 use crate::file_header::{
     write_file_header, FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
 };
 use crate::serialization::Addr;
 use crate::serialization::SerializationSink;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{error::Error, sync::Arc};
 
 /// A `StringId` is used to identify a string in the `StringTable`. It is
@@ -116,9 +129,16 @@ impl StringId {
 pub const TERMINATOR: u8 = 0xFF;
 pub const STRING_REF_TAG: u8 = 0xFE;
 pub const STRING_REF_ENCODED_SIZE: usize = 5;
+/// The size of an escaped byte, see the module-level documentation.
+pub const ESCAPED_BYTE_ENCODED_SIZE: usize = STRING_REF_ENCODED_SIZE + 1;
+
+/// The number of bytes of an entry in the string index: the string id and
+/// the address of the string in the string data, each as a little-endian
+/// `u32`.
+pub const STRING_INDEX_ENTRY_SIZE: usize = 8;
 
 /// The maximum id value a virtual string may be.
-const MAX_USER_VIRTUAL_STRING_ID: u32 = 100_000_000;
+pub(crate) const MAX_USER_VIRTUAL_STRING_ID: u32 = 100_000_000;
 
 /// The id of the profile metadata string entry.
 pub const METADATA_STRING_ID: u32 = MAX_USER_VIRTUAL_STRING_ID + 1;
@@ -132,6 +152,11 @@ pub const FIRST_REGULAR_STRING_ID: u32 = INVALID_STRING_ID + 1;
 pub struct StringTableBuilder {
     data_sink: Arc<SerializationSink>,
     index_sink: Arc<SerializationSink>,
+    // The ids of the strings allocated so far, keyed by their serialized
+    // contents, see `with_deduplication`.
+    deduplication_cache: Option<Mutex<FxHashMap<Box<[u8]>, StringId>>>,
+    num_strings: AtomicU64,
+    num_bytes: AtomicU64,
 }
 
 /// Anything that implements `SerializableString` can be written to a
@@ -165,7 +190,7 @@ pub enum StringComponent<'s> {
 
 impl<'s> StringComponent<'s> {
     #[inline]
-    fn serialized_size(&self) -> usize {
+    pub(crate) fn serialized_size(&self) -> usize {
         match *self {
             StringComponent::Value(s) => s.len(),
             StringComponent::Ref(_) => STRING_REF_ENCODED_SIZE,
@@ -173,7 +198,7 @@ impl<'s> StringComponent<'s> {
     }
 
     #[inline]
-    fn serialize<'b>(&self, bytes: &'b mut [u8]) -> &'b mut [u8] {
+    pub(crate) fn serialize<'b>(&self, bytes: &'b mut [u8]) -> &'b mut [u8] {
         match *self {
             StringComponent::Value(s) => {
                 bytes[..s.len()].copy_from_slice(s.as_bytes());
@@ -213,6 +238,49 @@ impl<'a> SerializableString for [StringComponent<'a>] {
     }
 }
 
+/// The concatenation of a list of byte strings, with `TERMINATOR` and
+/// `STRING_REF_TAG` escaped, see `StringTableBuilder::alloc_bytes`.
+struct EscapedBytes<'a>(&'a [&'a [u8]]);
+
+fn needs_escaping(byte: u8) -> bool {
+    byte == TERMINATOR || byte == STRING_REF_TAG
+}
+
+impl<'a> SerializableString for EscapedBytes<'a> {
+    fn serialized_size(&self) -> usize {
+        let escape_overhead = ESCAPED_BYTE_ENCODED_SIZE - 1;
+
+        self.0
+            .iter()
+            .flat_map(|component| component.iter())
+            .map(|&byte| {
+                if needs_escaping(byte) {
+                    1 + escape_overhead
+                } else {
+                    1
+                }
+            })
+            .sum::<usize>()
+            + 1 // terminator
+    }
+
+    fn serialize(&self, bytes: &mut [u8]) {
+        let mut pos = 0;
+        for &byte in self.0.iter().flat_map(|component| component.iter()) {
+            if needs_escaping(byte) {
+                bytes[pos] = STRING_REF_TAG;
+                bytes[pos + 1..pos + 5].copy_from_slice(&StringId::INVALID.0.to_le_bytes());
+                pos += STRING_REF_ENCODED_SIZE;
+            }
+            bytes[pos] = byte;
+            pos += 1;
+        }
+
+        assert!(bytes.len() == pos + 1);
+        bytes[pos] = TERMINATOR;
+    }
+}
+
 macro_rules! impl_serializable_string_for_fixed_size {
     ($n:expr) => {
         impl<'a> SerializableString for [StringComponent<'a>; $n] {
@@ -248,7 +316,7 @@ impl_serializable_string_for_fixed_size!(15);
 impl_serializable_string_for_fixed_size!(16);
 
 fn serialize_index_entry(sink: &SerializationSink, id: StringId, addr: Addr) {
-    sink.write_atomic(8, |bytes| {
+    sink.write_atomic(STRING_INDEX_ENTRY_SIZE, |bytes| {
         bytes[0..4].copy_from_slice(&id.0.to_le_bytes());
         bytes[4..8].copy_from_slice(&addr.0.to_le_bytes());
     });
@@ -259,16 +327,59 @@ impl StringTableBuilder {
         data_sink: Arc<SerializationSink>,
         index_sink: Arc<SerializationSink>,
     ) -> Result<StringTableBuilder, Box<dyn Error + Send + Sync>> {
-        // The first thing in every stream we generate must be the stream header.
-        write_file_header(&mut data_sink.as_std_write(), FILE_MAGIC_STRINGTABLE_DATA)?;
-        write_file_header(&mut index_sink.as_std_write(), FILE_MAGIC_STRINGTABLE_INDEX)?;
+        // The first thing in every stream we generate must be the stream
+        // header. The streams of a profile that is appended to have theirs
+        // already, see `Profiler::open`.
+        if data_sink.is_empty() {
+            write_file_header(&mut data_sink.as_std_write(), FILE_MAGIC_STRINGTABLE_DATA)?;
+        }
+        if index_sink.is_empty() {
+            write_file_header(&mut index_sink.as_std_write(), FILE_MAGIC_STRINGTABLE_INDEX)?;
+        }
 
         Ok(StringTableBuilder {
             data_sink,
             index_sink,
+            deduplication_cache: None,
+            num_strings: AtomicU64::new(0),
+            num_bytes: AtomicU64::new(0),
         })
     }
 
+    /// The number of strings written to the string table so far. Strings
+    /// that have been found in the deduplication cache (see
+    /// `with_deduplication`) aren't counted again.
+    pub fn num_strings(&self) -> u64 {
+        self.num_strings.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of the strings counted by `num_strings`, without
+    /// their index entries. This is a running total, so reading it is cheap.
+    pub fn num_bytes(&self) -> u64 {
+        self.num_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Writes the strings allocated so far to the backing storage, see
+    /// `SerializationSink::flush_buffer`. The data is written before the
+    /// index, so that the index entries that are written refer to strings
+    /// that have been written, too, unless they have been allocated while
+    /// flushing.
+    pub fn flush(&self) {
+        self.data_sink.flush_buffer();
+        self.index_sink.flush_buffer();
+    }
+
+    /// Makes `alloc` return the id of an earlier string with the same
+    /// contents instead of writing the string again. This keeps the string
+    /// table small if the same strings are allocated over and over, at the
+    /// cost of a hash table lookup per allocation and of keeping a copy of
+    /// every string in memory. The ids remain valid for the lifetime of the
+    /// string table.
+    pub fn with_deduplication(mut self) -> StringTableBuilder {
+        self.deduplication_cache = Some(Mutex::new(FxHashMap::default()));
+        self
+    }
+
     /// Creates a mapping so that `virtual_id` will resolve to the contents of
     /// `concrete_id` when reading the string table.
     pub fn map_virtual_to_concrete_string(&self, virtual_id: StringId, concrete_id: StringId) {
@@ -317,11 +428,71 @@ impl StringTableBuilder {
         serialize_index_entry(&*self.index_sink, virtual_id, concrete_id.to_addr());
     }
 
+    /// Allocates the concatenation of `components`, which may contain
+    /// arbitrary bytes, e.g. the bytes of a path that isn't valid UTF-8.
+    /// Bytes that can't be stored as they are in the string table are
+    /// escaped, so that readers get back exactly these bytes.
+    ///
+    /// Returns an error if the string contains `event_id::SEPARATOR_BYTE`
+    /// without `event_id::ESCAPE_BYTE` before it, since readers would take it
+    /// apart when it is used in an event id. `event_id::escape_bytes` escapes
+    /// the separators of a string.
+    pub fn alloc_bytes(
+        &self,
+        components: &[&[u8]],
+    ) -> Result<StringId, Box<dyn Error + Send + Sync>> {
+        let separator = SEPARATOR_BYTE.as_bytes()[0];
+        let escape = ESCAPE_BYTE.as_bytes()[0];
+        let mut escaped = false;
+        for &byte in components.iter().flat_map(|component| component.iter()) {
+            if byte == separator && !escaped {
+                return Err(From::from(
+                    "Strings must not contain the separator byte `\\x1E` of event ids unless it is escaped",
+                ));
+            }
+            escaped = byte == escape && !escaped;
+        }
+
+        Ok(self.alloc(&EscapedBytes(components)))
+    }
+
+    /// Allocates `bytes`, the whole event id of an event of another profile
+    /// that is being copied, separators included.
+    pub fn alloc_event_id_bytes(&self, bytes: &[u8]) -> StringId {
+        self.alloc(&EscapedBytes(&[bytes]))
+    }
+
     pub fn alloc<STR: SerializableString + ?Sized>(&self, s: &STR) -> StringId {
         let size_in_bytes = s.serialized_size();
+
+        if let Some(cache) = &self.deduplication_cache {
+            let mut bytes = vec![0; size_in_bytes];
+            s.serialize(&mut bytes);
+
+            // The lock is held while writing, so that no two threads can
+            // allocate the same string concurrently.
+            let mut cache = cache.lock();
+            if let Some(&id) = cache.get(&bytes[..]) {
+                return id;
+            }
+
+            let addr = self
+                .data_sink
+                .write_atomic(size_in_bytes, |mem| mem.copy_from_slice(&bytes));
+            let id = StringId::from_addr(addr);
+            cache.insert(bytes.into_boxed_slice(), id);
+            self.num_strings.fetch_add(1, Ordering::Relaxed);
+            self.num_bytes
+                .fetch_add(size_in_bytes as u64, Ordering::Relaxed);
+            return id;
+        }
+
         let addr = self.data_sink.write_atomic(size_in_bytes, |mem| {
             s.serialize(mem);
         });
+        self.num_strings.fetch_add(1, Ordering::Relaxed);
+        self.num_bytes
+            .fetch_add(size_in_bytes as u64, Ordering::Relaxed);
 
         StringId::from_addr(addr)
     }
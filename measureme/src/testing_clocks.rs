@@ -0,0 +1,116 @@
+//! Deterministic [`Clock`]s for the tests of the profiler and of the tools
+//! that read its profiles, so that they can check the exact timestamps of the
+//! events they record.
+
+use crate::counters::{Clock, COUNTER_UNAVAILABLE};
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A clock that advances by 10ns every time it is read, starting at 0.
+#[derive(Debug, Default)]
+pub struct SteppingClock {
+    next: AtomicU64,
+    per_thread: bool,
+}
+
+impl SteppingClock {
+    /// A clock whose first read returns `nanos`.
+    pub fn starting_at(nanos: u64) -> SteppingClock {
+        SteppingClock {
+            next: AtomicU64::new(nanos),
+            per_thread: false,
+        }
+    }
+
+    /// A clock that advances every time it is read on the same thread,
+    /// starting at 0 on every thread, so that the timestamps of the events
+    /// don't depend on the scheduling of the threads. All such clocks share
+    /// the time of a thread.
+    pub fn per_thread() -> SteppingClock {
+        SteppingClock {
+            next: AtomicU64::new(0),
+            per_thread: true,
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now_nanos(&self) -> u64 {
+        thread_local! {
+            static NEXT: Cell<u64> = const { Cell::new(0) };
+        }
+
+        if self.per_thread {
+            NEXT.with(|next| next.replace(next.get() + 10))
+        } else {
+            self.next.fetch_add(10, Ordering::SeqCst)
+        }
+    }
+}
+
+/// Like [`SteppingClock`], but one of its reads fails, i.e. returns
+/// [`COUNTER_UNAVAILABLE`].
+#[derive(Debug)]
+pub struct FailingClock {
+    reads: AtomicU64,
+    failing_read: u64,
+}
+
+impl FailingClock {
+    /// A clock whose read with the (zero-based) index `failing_read` fails.
+    pub fn failing_at_read(failing_read: u64) -> FailingClock {
+        FailingClock {
+            reads: AtomicU64::new(0),
+            failing_read,
+        }
+    }
+}
+
+impl Clock for FailingClock {
+    fn now_nanos(&self) -> u64 {
+        match self.reads.fetch_add(1, Ordering::SeqCst) {
+            read if read == self.failing_read => COUNTER_UNAVAILABLE,
+            read => read * 10,
+        }
+    }
+}
+
+/// A clock that returns whatever value the test has set it to. Clones share
+/// the same time, so a test can keep one to move the time of the clock it has
+/// passed to the profiler.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn set(&self, nanos: u64) {
+        self.0.store(nanos, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_nanos(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the given values in turn, like a counter that fails or goes
+/// backwards while the profiler is running. Panics once they are used up.
+#[derive(Debug)]
+pub struct ScriptedClock(Mutex<std::vec::IntoIter<u64>>);
+
+impl ScriptedClock {
+    pub fn new(values: Vec<u64>) -> ScriptedClock {
+        ScriptedClock(Mutex::new(values.into_iter()))
+    }
+}
+
+impl Clock for ScriptedClock {
+    fn now_nanos(&self) -> u64 {
+        self.0
+            .lock()
+            .next()
+            .expect("the scripted clock has run out of values")
+    }
+}
@@ -0,0 +1,145 @@
+//! The positions of the timestamp epoch markers (see
+//! `TIMESTAMP_EPOCH_EVENT_KIND`) in the events stream, so that readers can
+//! find the markers without scanning all events.
+//!
+//! The positions are stored in their own stream
+//! (`PageTag::TimestampEpochIndex`). It consists of the usual stream header
+//! and the event index of every marker as a `u64` LE, in the order in which
+//! the markers have been written. The header is written when the profile is
+//! created, so a stream without entries means that the profile has no
+//! markers. Readers have to scan the events for the markers if the stream is
+//! missing, e.g. in profiles that have been written by older versions, in the
+//! segments of a profile split with `ProfilerOptions::max_file_bytes` and in
+//! the snapshots of a `RingBufferSink`.
+
+use crate::file_header::{
+    verify_file_header, write_file_header, FILE_HEADER_SIZE, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX,
+};
+use crate::serialization::{Addr, SerializationSink};
+use std::convert::TryInto;
+use std::error::Error;
+use std::path::Path;
+
+const RAW_EVENT_SIZE: usize = std::mem::size_of::<crate::RawEvent>();
+
+/// Writes the positions of the epoch markers to the timestamp epoch index of
+/// a profile.
+pub struct TimestampEpochIndexWriter {
+    sink: SerializationSink,
+    /// Whether the stream has its header. A profile that has been created
+    /// without the index doesn't get one when it is appended to, as the
+    /// markers that have been recorded before would be missing from it.
+    enabled: bool,
+}
+
+impl TimestampEpochIndexWriter {
+    /// `sink` must have been created for `PageTag::TimestampEpochIndex`.
+    /// `new_profile` tells whether the events stream is empty, i.e. whether
+    /// the profile isn't appended to.
+    pub fn new(sink: SerializationSink, new_profile: bool) -> TimestampEpochIndexWriter {
+        let enabled = if sink.is_empty() {
+            if new_profile {
+                let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+                write_file_header(&mut header, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX).unwrap();
+                sink.write_bytes_atomic(&header);
+            }
+            new_profile
+        } else {
+            true
+        };
+
+        TimestampEpochIndexWriter { sink, enabled }
+    }
+
+    /// Records the marker that has been written at `addr` of the events
+    /// stream. The entry is written right away, so that it reaches the
+    /// backing storage before the page with the marker does.
+    pub fn record(&self, addr: Addr) {
+        if !self.enabled {
+            return;
+        }
+
+        let event_index = ((addr.as_usize() - FILE_HEADER_SIZE) / RAW_EVENT_SIZE) as u64;
+        self.sink.write_bytes_atomic(&event_index.to_le_bytes());
+        self.sink.flush_buffer();
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.sink.into_bytes()
+    }
+}
+
+/// Decodes the contents of a timestamp epoch index stream into the event
+/// indices of the markers. `None` if the profile doesn't have an index, i.e.
+/// if `stream` is empty.
+pub fn decode_timestamp_epoch_index(
+    stream: &[u8],
+    diagnostic_file_path: Option<&Path>,
+) -> Result<Option<Vec<u64>>, Box<dyn Error + Send + Sync>> {
+    if stream.is_empty() {
+        return Ok(None);
+    }
+
+    verify_file_header(
+        stream,
+        FILE_MAGIC_TIMESTAMP_EPOCH_INDEX,
+        diagnostic_file_path,
+        "timestamp epoch index",
+    )?;
+
+    let entries = stream[FILE_HEADER_SIZE..].chunks_exact(8);
+    if !entries.remainder().is_empty() {
+        return Err(From::from(
+            "Invalid timestamp epoch index stream: truncated entry",
+        ));
+    }
+
+    Ok(Some(
+        entries
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{PageTag, SerializationSinkBuilder};
+
+    #[test]
+    fn roundtrip() {
+        let writer = TimestampEpochIndexWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::TimestampEpochIndex),
+            true,
+        );
+        writer.record(Addr(FILE_HEADER_SIZE as u32));
+        writer.record(Addr((FILE_HEADER_SIZE + 7 * RAW_EVENT_SIZE) as u32));
+
+        let stream = writer.into_bytes();
+        assert_eq!(
+            decode_timestamp_epoch_index(&stream, None).unwrap(),
+            Some(vec![0, 7])
+        );
+    }
+
+    #[test]
+    fn appended_profile_without_index() {
+        let writer = TimestampEpochIndexWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::TimestampEpochIndex),
+            false,
+        );
+        writer.record(Addr((FILE_HEADER_SIZE + 7 * RAW_EVENT_SIZE) as u32));
+
+        let stream = writer.into_bytes();
+        assert_eq!(decode_timestamp_epoch_index(&stream, None).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_entry() {
+        let mut stream = Vec::new();
+        write_file_header(&mut stream, FILE_MAGIC_TIMESTAMP_EPOCH_INDEX).unwrap();
+        stream.extend_from_slice(&[0; 4]);
+
+        assert!(decode_timestamp_epoch_index(&stream, None).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Trace and span ids that tie interval events to a distributed trace, e.g.
+//! to stitch the profiles of the processes that handled the same request
+//! together, see `ProfilerOptions::record_trace_context`.
+//!
+//! The ids are stored in their own stream (`PageTag::TraceContext`), since
+//! they don't fit into an event. The stream only exists if at least one
+//! interval event has been recorded with a trace context. It consists of the
+//! usual stream header, the index of its first entry as a `u64` LE, and a
+//! sequence of fixed-size entries, each of which is encoded as
+//!
+//! ```ignore
+//! [trace id: u128 LE][span id: u64 LE]
+//! ```
+//!
+//! The interval event is followed by a `TRACE_CONTEXT_EVENT_KIND` marker
+//! whose value is the index of its entry. The first index is `0`, unless the
+//! oldest entries have been dropped, like `RingBufferSink` does.
+
+use crate::file_header::{
+    verify_file_header, write_file_header, FILE_HEADER_SIZE, FILE_MAGIC_TRACE_CONTEXT,
+};
+use crate::serialization::SerializationSink;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Once;
+
+/// The number of bytes of an entry in the trace context stream.
+pub const TRACE_CONTEXT_ENTRY_SIZE: usize = 24;
+
+/// The number of bytes at the start of the trace context stream that come
+/// before its first entry: the stream header and the index of that entry.
+pub const TRACE_CONTEXT_PRELUDE_SIZE: usize = FILE_HEADER_SIZE + 8;
+
+/// Identifies the span of a distributed trace that an interval event
+/// belongs to, like the trace context of OpenTelemetry or W3C Trace Context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TraceContext {
+    /// Shared by all spans of the same trace, e.g. of the same request, no
+    /// matter which process they have been recorded by.
+    pub trace_id: u128,
+    /// Identifies the span within the trace.
+    pub span_id: u64,
+}
+
+/// Writes entries to the trace context stream of a profile.
+pub struct TraceContextWriter {
+    sink: SerializationSink,
+    header_written: Once,
+}
+
+impl TraceContextWriter {
+    /// `sink` must have been created for `PageTag::TraceContext`.
+    pub fn new(sink: SerializationSink) -> TraceContextWriter {
+        TraceContextWriter {
+            sink,
+            header_written: Once::new(),
+        }
+    }
+
+    /// Appends `trace_context` to the stream and returns the index of its
+    /// entry.
+    pub fn record(&self, trace_context: TraceContext) -> u64 {
+        // The stream of a profile that is appended to has its header already.
+        self.header_written.call_once(|| {
+            if self.sink.is_empty() {
+                let mut prelude = Vec::with_capacity(TRACE_CONTEXT_PRELUDE_SIZE);
+                write_file_header(&mut prelude, FILE_MAGIC_TRACE_CONTEXT).unwrap();
+                prelude.extend_from_slice(&0u64.to_le_bytes());
+                self.sink.write_bytes_atomic(&prelude);
+            }
+        });
+
+        let mut entry = [0; TRACE_CONTEXT_ENTRY_SIZE];
+        entry[..16].copy_from_slice(&trace_context.trace_id.to_le_bytes());
+        entry[16..].copy_from_slice(&trace_context.span_id.to_le_bytes());
+
+        let addr = self.sink.write_bytes_atomic(&entry);
+        ((addr.as_usize() - TRACE_CONTEXT_PRELUDE_SIZE) / TRACE_CONTEXT_ENTRY_SIZE) as u64
+    }
+
+    /// Writes the entries recorded so far to the backing storage, see
+    /// `SerializationSink::flush_buffer`.
+    pub fn flush(&self) {
+        self.sink.flush_buffer();
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.sink.into_bytes()
+    }
+}
+
+/// The decoded entries of a trace context stream, see `decode_trace_contexts`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceContexts {
+    /// The index of the first entry of `entries`.
+    pub first_index: u64,
+    pub entries: Vec<TraceContext>,
+}
+
+impl TraceContexts {
+    /// The entry with the given index, i.e. the value of a
+    /// `TRACE_CONTEXT_EVENT_KIND` marker. `None` if it isn't in the stream.
+    pub fn get(&self, index: u64) -> Option<TraceContext> {
+        let offset = index.checked_sub(self.first_index)?;
+        self.entries.get(usize::try_from(offset).ok()?).copied()
+    }
+}
+
+/// Decodes the contents of a trace context stream. An empty `stream` means
+/// that no trace contexts have been recorded.
+pub fn decode_trace_contexts(
+    stream: &[u8],
+    diagnostic_file_path: Option<&Path>,
+) -> Result<TraceContexts, Box<dyn Error + Send + Sync>> {
+    if stream.is_empty() {
+        return Ok(TraceContexts::default());
+    }
+
+    verify_file_header(
+        stream,
+        FILE_MAGIC_TRACE_CONTEXT,
+        diagnostic_file_path,
+        "trace context",
+    )?;
+
+    if stream.len() < TRACE_CONTEXT_PRELUDE_SIZE {
+        return Err(From::from("Invalid trace context stream: truncated header"));
+    }
+    let first_index = u64::from_le_bytes(
+        stream[FILE_HEADER_SIZE..TRACE_CONTEXT_PRELUDE_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+
+    Ok(TraceContexts {
+        first_index,
+        entries: decode_trace_context_entries(&stream[TRACE_CONTEXT_PRELUDE_SIZE..])?,
+    })
+}
+
+/// Decodes the entries of a trace context stream that follow its prelude,
+/// e.g. the ones of the pages that have been appended to a profile.
+pub fn decode_trace_context_entries(
+    entries: &[u8],
+) -> Result<Vec<TraceContext>, Box<dyn Error + Send + Sync>> {
+    let entries = entries.chunks_exact(TRACE_CONTEXT_ENTRY_SIZE);
+    if !entries.remainder().is_empty() {
+        return Err(From::from("Invalid trace context stream: truncated entry"));
+    }
+
+    Ok(entries
+        .map(|entry| TraceContext {
+            trace_id: u128::from_le_bytes(entry[..16].try_into().unwrap()),
+            span_id: u64::from_le_bytes(entry[16..].try_into().unwrap()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{PageTag, SerializationSinkBuilder};
+
+    fn new_writer() -> TraceContextWriter {
+        TraceContextWriter::new(
+            SerializationSinkBuilder::new_in_memory().new_sink(PageTag::TraceContext),
+        )
+    }
+
+    #[test]
+    fn roundtrip() {
+        let writer = new_writer();
+        let first = TraceContext {
+            trace_id: 0x0123_4567_89ab_cdef_0011_2233_4455_6677,
+            span_id: 1,
+        };
+        let second = TraceContext {
+            trace_id: u128::MAX,
+            span_id: u64::MAX,
+        };
+        assert_eq!(writer.record(first), 0);
+        assert_eq!(writer.record(second), 1);
+        assert_eq!(writer.record(first), 2);
+
+        let trace_contexts = decode_trace_contexts(&writer.into_bytes(), None).unwrap();
+
+        assert_eq!(trace_contexts.first_index, 0);
+        assert_eq!(trace_contexts.entries, vec![first, second, first]);
+        assert_eq!(trace_contexts.get(1), Some(second));
+        assert_eq!(trace_contexts.get(3), None);
+    }
+
+    #[test]
+    fn dropped_entries_keep_their_indices() {
+        let writer = new_writer();
+        for span_id in 0..3 {
+            writer.record(TraceContext {
+                trace_id: 1,
+                span_id,
+            });
+        }
+
+        // Drop the first entry, like `RingBufferSink` does.
+        let mut bytes = writer.into_bytes();
+        bytes.drain(
+            TRACE_CONTEXT_PRELUDE_SIZE..TRACE_CONTEXT_PRELUDE_SIZE + TRACE_CONTEXT_ENTRY_SIZE,
+        );
+        bytes[FILE_HEADER_SIZE..TRACE_CONTEXT_PRELUDE_SIZE].copy_from_slice(&1u64.to_le_bytes());
+
+        let trace_contexts = decode_trace_contexts(&bytes, None).unwrap();
+        assert_eq!(trace_contexts.get(0), None);
+        assert_eq!(trace_contexts.get(2).unwrap().span_id, 2);
+    }
+
+    #[test]
+    fn empty_stream() {
+        let bytes = new_writer().into_bytes();
+
+        assert!(bytes.is_empty());
+        assert!(decode_trace_contexts(&bytes, None)
+            .unwrap()
+            .entries
+            .is_empty());
+    }
+
+    #[test]
+    fn truncated_entry() {
+        let writer = new_writer();
+        writer.record(TraceContext {
+            trace_id: 1,
+            span_id: 2,
+        });
+
+        let bytes = writer.into_bytes();
+        assert!(decode_trace_contexts(&bytes[..bytes.len() - 1], None).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+//! A [`tracing_subscriber::Layer`] that records `tracing` spans as interval
+//! events, so that code instrumented with `tracing` can be profiled with
+//! measureme without any changes to the instrumentation.
+//!
+//! Each time a span is entered and exited, an interval event is recorded
+//! whose label is the span's name, whose category is the span's target and
+//! whose arguments are the span's fields, formatted as `name=value`. All
+//! events have the event kind [`TRACING_SPAN_EVENT_KIND`].
+//!
+//! ```ignore
+//! use std::sync::Arc;
+//! use measureme::{MeasuremeLayer, Profiler};
+//! use tracing_subscriber::prelude::*;
+//!
+//! let profiler = Arc::new(Profiler::new("my_profile")?);
+//! let subscriber = tracing_subscriber::registry().with(MeasuremeLayer::new(profiler));
+//! tracing::subscriber::set_global_default(subscriber)?;
+//! ```
+//!
+//! This module is only available if the `tracing-layer` feature is enabled.
+
+use crate::event_id::{EventId, EventIdBuilder};
+use crate::profiler::{current_thread_id, DetachedTiming, Profiler};
+use crate::stringtable::StringId;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use std::fmt;
+use std::sync::Arc;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The event kind of the interval events recorded by [`MeasuremeLayer`].
+pub const TRACING_SPAN_EVENT_KIND: &str = "TracingSpan";
+
+/// Records `tracing` spans in a [`Profiler`]. See the module documentation
+/// for how spans are mapped to events.
+pub struct MeasuremeLayer {
+    profiler: Arc<Profiler>,
+    event_kind: StringId,
+    // Span names and targets are `'static` and usually shared by many spans,
+    // so they are only allocated in the string table once.
+    static_strings: Mutex<FxHashMap<&'static str, StringId>>,
+}
+
+impl MeasuremeLayer {
+    pub fn new(profiler: Arc<Profiler>) -> MeasuremeLayer {
+        let event_kind = profiler.alloc_string(TRACING_SPAN_EVENT_KIND);
+
+        MeasuremeLayer {
+            profiler,
+            event_kind,
+            static_strings: Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    fn alloc_static_string(&self, s: &'static str) -> StringId {
+        *self
+            .static_strings
+            .lock()
+            .entry(s)
+            .or_insert_with(|| self.profiler.alloc_string(s))
+    }
+}
+
+/// The per-span state, stored in the span's extensions.
+struct SpanData {
+    label: StringId,
+    category: StringId,
+    args: Vec<StringId>,
+    // The event id made of the above, which is only allocated once the span
+    // is exited for the first time, and again after more fields have been
+    // recorded, rather than on every exit.
+    event_id: Option<EventId>,
+    // A span can be entered on several threads at the same time, and
+    // repeatedly on the same thread. Each `DetachedTiming` remembers the
+    // thread it has been started on, which is used to find the matching one
+    // when the span is exited.
+    timings: Vec<DetachedTiming>,
+}
+
+/// Allocates a `name=value` string for each field that is visited.
+struct ArgsVisitor<'a> {
+    profiler: &'a Profiler,
+    args: &'a mut Vec<StringId>,
+}
+
+impl<'a> Visit for ArgsVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let arg = format!("{}={}", field.name(), value);
+        self.args.push(self.profiler.alloc_string(&arg[..]));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let arg = format!("{}={:?}", field.name(), value);
+        self.args.push(self.profiler.alloc_string(&arg[..]));
+    }
+}
+
+impl<S> Layer<S> for MeasuremeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span must exist in the registry");
+        let metadata = attrs.metadata();
+
+        let mut span_data = SpanData {
+            label: self.alloc_static_string(metadata.name()),
+            category: self.alloc_static_string(metadata.target()),
+            args: Vec::new(),
+            event_id: None,
+            timings: Vec::new(),
+        };
+
+        if self.profiler.records_args() {
+            attrs.record(&mut ArgsVisitor {
+                profiler: &self.profiler,
+                args: &mut span_data.args,
+            });
+        }
+
+        span.extensions_mut().insert(span_data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if !self.profiler.records_args() {
+            return;
+        }
+
+        let span = ctx.span(id).expect("span must exist in the registry");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(span_data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut ArgsVisitor {
+                profiler: &self.profiler,
+                args: &mut span_data.args,
+            });
+            span_data.event_id = None;
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span must exist in the registry");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(span_data) = extensions.get_mut::<SpanData>() {
+            // The event id is only known once the span is exited, since more
+            // fields may be recorded in the meantime.
+            let timing = self.profiler.start_recording_interval_event_detached(
+                self.event_kind,
+                EventIdBuilder::new(&self.profiler).from_label(span_data.label),
+                current_thread_id(),
+            );
+            span_data.timings.push(timing);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span must exist in the registry");
+        let mut extensions = span.extensions_mut();
+
+        let span_data = match extensions.get_mut::<SpanData>() {
+            Some(span_data) => span_data,
+            None => return,
+        };
+
+        let thread_id = current_thread_id();
+        let timing_index = match span_data
+            .timings
+            .iter()
+            .rposition(|timing| timing.thread_id == thread_id)
+        {
+            Some(timing_index) => timing_index,
+            // The span has been entered before this layer was installed.
+            None => return,
+        };
+
+        let mut timing = span_data.timings.remove(timing_index);
+        let (profiler, label, category, args) = (
+            &self.profiler,
+            span_data.label,
+            span_data.category,
+            &span_data.args,
+        );
+        timing.event_id = *span_data.event_id.get_or_insert_with(|| {
+            EventIdBuilder::new(profiler).from_label_category_and_args(label, category, args)
+        });
+
+        self.profiler.finish_recording_interval_event(timing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counters::Counter;
+    use crate::file_header::{segment_file_path, FILE_HEADER_SIZE};
+    use crate::serialization::{split_streams, PageTag};
+    use crate::testing_clocks::SteppingClock;
+    use crate::RawEvent;
+    use std::path::Path;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn read_raw_events(path_stem: &Path) -> Vec<RawEvent> {
+        let data = std::fs::read(segment_file_path(path_stem, 0)).unwrap();
+        let event_data = split_streams(&data[FILE_HEADER_SIZE..])
+            .remove(&PageTag::Events)
+            .unwrap();
+        event_data[FILE_HEADER_SIZE..]
+            .chunks(std::mem::size_of::<RawEvent>())
+            .map(RawEvent::deserialize)
+            .collect()
+    }
+
+    #[test]
+    fn spans_are_recorded_as_intervals() {
+        let path_stem = Path::new("test-tmp").join("tracing_layer").join("spans");
+
+        let profiler = Arc::new(
+            Profiler::with_counter(
+                &path_stem,
+                Counter::Clock(Box::new(SteppingClock::default())),
+            )
+            .unwrap(),
+        );
+
+        let subscriber = tracing_subscriber::registry().with(MeasuremeLayer::new(profiler.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("outer", answer = 42);
+            let _outer = outer.enter();
+
+            tracing::info_span!("inner").in_scope(|| {});
+        });
+
+        // The subscriber has been dropped, so this is the last reference.
+        drop(Arc::try_unwrap(profiler).ok().unwrap());
+
+        let raw_events = read_raw_events(&path_stem);
+
+        // Events are recorded when they end, so the inner span comes first.
+        assert_eq!(raw_events.len(), 2);
+        assert_eq!(raw_events[0].thread_id, raw_events[1].thread_id);
+        assert!(raw_events[1].start_value() < raw_events[0].start_value());
+        assert!(raw_events[0].end_value() < raw_events[1].end_value());
+        assert_ne!(raw_events[0].event_id, raw_events[1].event_id);
+    }
+
+    #[test]
+    fn reentered_span_reuses_its_event_id() {
+        let path_stem = Path::new("test-tmp")
+            .join("tracing_layer")
+            .join("reentered");
+
+        let profiler = Arc::new(
+            Profiler::with_counter(
+                &path_stem,
+                Counter::Clock(Box::new(SteppingClock::default())),
+            )
+            .unwrap(),
+        );
+
+        let subscriber = tracing_subscriber::registry().with(MeasuremeLayer::new(profiler.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("repeated", answer = 42);
+            span.in_scope(|| {});
+            span.in_scope(|| {});
+        });
+
+        drop(Arc::try_unwrap(profiler).ok().unwrap());
+
+        // Allocating the event id again would have given it another string id.
+        let raw_events = read_raw_events(&path_stem);
+        assert_eq!(raw_events.len(), 2);
+        assert_eq!(raw_events[0].event_id, raw_events[1].event_id);
+    }
+}
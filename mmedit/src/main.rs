@@ -1,7 +1,10 @@
 use std::{convert::TryInto, error::Error, path::PathBuf};
 
-use decodeme::{read_file_header, PageTag, FILE_HEADER_SIZE, FILE_MAGIC_TOP_LEVEL};
+use decodeme::{
+    read_file_header, verify::verify_file, PageTag, FILE_HEADER_SIZE, FILE_MAGIC_TOP_LEVEL,
+};
 
+use analyzeme::ProfilingData;
 use clap::Parser;
 
 #[derive(Parser, Debug)]
@@ -9,11 +12,27 @@ struct TruncateOpt {
     file: PathBuf,
 }
 
+#[derive(Parser, Debug)]
+struct VerifyOpt {
+    file: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct StripArgsOpt {
+    file: PathBuf,
+}
+
 #[derive(Parser, Debug)]
 enum Opt {
     /// Truncate to a single page per tag
     #[clap(name = "truncate")]
     Truncate(TruncateOpt),
+    /// Check that the file is well-formed, reporting the first problem
+    #[clap(name = "verify")]
+    Verify(VerifyOpt),
+    /// Remove the arguments of all events, keeping their labels
+    #[clap(name = "strip-args")]
+    StripArgs(StripArgsOpt),
 }
 
 fn truncate(file_contents: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
@@ -48,8 +67,15 @@ fn truncate(file_contents: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync
                     event_page_emitted = true;
                 }
             }
-            PageTag::StringData | PageTag::StringIndex => {
-                // Copy all string table pages
+            PageTag::StringData
+            | PageTag::StringIndex
+            | PageTag::Metadata
+            | PageTag::TraceContext
+            | PageTag::TimestampEpochIndex
+            | PageTag::Segment => {
+                // Copy all string table, metadata, trace context and timestamp
+                // epoch index pages. The index entries of the markers in the
+                // events pages that are left out are ignored by readers.
                 truncated.extend_from_slice(page_bytes);
             }
         }
@@ -70,6 +96,26 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             let output_file_name = opt.file.with_extension("truncated.mm_profdata");
             std::fs::write(output_file_name, truncated)?;
         }
+        Opt::Verify(opt) => {
+            let file_contents = std::fs::read(&opt.file)?;
+            match verify_file(&file_contents) {
+                Ok(verified) => println!(
+                    "{}: OK, {} pages, {} events",
+                    opt.file.display(),
+                    verified.num_pages,
+                    verified.num_events
+                ),
+                Err(e) => {
+                    eprintln!("{}: {}", opt.file.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Opt::StripArgs(opt) => {
+            let file_contents = std::fs::read(&opt.file)?;
+            let data = ProfilingData::from_paged_buffer(file_contents, Some(&opt.file))?;
+            data.write_with_stripped_args(&opt.file.with_extension("stripped"))?;
+        }
     }
 
     Ok(())
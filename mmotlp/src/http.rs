@@ -0,0 +1,193 @@
+//! Pushing spans to a collector with OTLP/HTTP (see
+//! <https://opentelemetry.io/docs/specs/otlp/#otlphttp>). This only speaks
+//! plain HTTP/1.1, which is enough for a collector running next to the
+//! profiled processes. Collectors that are only reachable via HTTPS need a
+//! local collector or proxy in front of them.
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+
+/// The path that collectors receive traces at, used if the endpoint doesn't
+/// specify one.
+const DEFAULT_TRACES_PATH: &str = "/v1/traces";
+
+/// An `http://host[:port][/path]` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endpoint, String> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("`{}` is not an `http://` URL", s))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let path = match path {
+            "" | "/" => DEFAULT_TRACES_PATH,
+            path => path,
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(index) => {
+                let port = &authority[index + 1..];
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port `{}` in `{}`", port, s))?;
+                (&authority[..index], port)
+            }
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in `{}`", s));
+        }
+
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Sends `body`, an `ExportTraceServiceRequest` in JSON, to `endpoint` and
+/// fails unless the collector has accepted it.
+pub fn post_json(endpoint: &Endpoint, body: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = TcpStream::connect((&endpoint.host[..], endpoint.port))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        endpoint.host,
+        endpoint.port,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => {
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+            Err(From::from(format!(
+                "the collector at {}:{}{} rejected the spans: `{}` {}",
+                endpoint.host,
+                endpoint.port,
+                endpoint.path,
+                status_line,
+                body.trim()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn endpoint(host: &str, port: u16, path: &str) -> Endpoint {
+        Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_endpoints() {
+        assert_eq!(
+            "http://localhost:4318".parse(),
+            Ok(endpoint("localhost", 4318, "/v1/traces"))
+        );
+        assert_eq!(
+            "http://collector/".parse(),
+            Ok(endpoint("collector", 80, "/v1/traces"))
+        );
+        assert_eq!(
+            "http://10.0.0.1:8080/otlp/v1/traces".parse(),
+            Ok(endpoint("10.0.0.1", 8080, "/otlp/v1/traces"))
+        );
+
+        assert!("https://localhost:4318".parse::<Endpoint>().is_err());
+        assert!("http://localhost:port".parse::<Endpoint>().is_err());
+        assert!("http://:4318".parse::<Endpoint>().is_err());
+    }
+
+    /// Accepts a single request and answers it with `status_line`. Returns
+    /// the request line and body that have been received.
+    fn serve_once(listener: TcpListener, status_line: &'static str) -> (String, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header == "\r\n" {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        write!(
+            reader.get_mut(),
+            "{}\r\nContent-Length: 4\r\n\r\nnope",
+            status_line
+        )
+        .unwrap();
+
+        (request_line, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn post_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || serve_once(listener, "HTTP/1.1 200 OK"));
+
+        post_json(&endpoint("127.0.0.1", port, "/v1/traces"), b"{}").unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1\r\n");
+        assert_eq!(body, "{}");
+    }
+
+    #[test]
+    fn rejected_by_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || serve_once(listener, "HTTP/1.1 400 Bad Request"));
+
+        let error = post_json(&endpoint("127.0.0.1", port, "/v1/traces"), b"{}").unwrap_err();
+        server.join().unwrap();
+
+        assert!(error.to_string().contains("400 Bad Request"));
+        assert!(error.to_string().contains("nope"));
+    }
+}
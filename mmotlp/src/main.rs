@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use analyzeme::{warn_about_profile, ProfilingData, TimeRange};
+use clap::Parser;
+
+mod http;
+mod otlp;
+
+use http::Endpoint;
+use otlp::ExportTraceServiceRequest;
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(required = true)]
+    file_prefix: Vec<PathBuf>,
+    /// the file to write the spans to, one OTLP JSON request per profile and
+    /// line
+    #[clap(short = 'o', long = "output", default_value = "traces.otlp.jsonl")]
+    output: PathBuf,
+    /// push the spans to the OTLP/HTTP collector at the given URL instead of
+    /// writing them to a file, e.g. `http://localhost:4318`
+    #[clap(long = "endpoint")]
+    endpoint: Option<Endpoint>,
+    /// only export the events within the given window of time, in seconds since
+    /// the start of each profile, e.g. `120:122.5`
+    #[clap(long = "time-range")]
+    time_range: Option<TimeRange>,
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let opt = Opt::parse();
+
+    let mut output = match opt.endpoint {
+        Some(_) => None,
+        None => Some(BufWriter::new(fs::File::create(&opt.output)?)),
+    };
+
+    for file_prefix in &opt.file_prefix {
+        let data = ProfilingData::new(file_prefix)?;
+        warn_about_profile(&data, file_prefix);
+
+        let request = ExportTraceServiceRequest::from_profile(&data, opt.time_range);
+        match &opt.endpoint {
+            Some(endpoint) => {
+                http::post_json(endpoint, &serde_json::to_vec(&request)?)?;
+                eprintln!(
+                    "Exported {} spans of `{}`",
+                    request.span_count(),
+                    file_prefix.display()
+                );
+            }
+            None => {
+                let output = output.as_mut().unwrap();
+                serde_json::to_writer(&mut *output, &request)?;
+                writeln!(output)?;
+            }
+        }
+    }
+
+    if let Some(mut output) = output {
+        output.flush()?;
+    }
+
+    Ok(())
+}
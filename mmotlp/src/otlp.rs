@@ -0,0 +1,459 @@
+//! Conversion of `ProfilingData` into OpenTelemetry spans, in the JSON
+//! encoding of OTLP's `ExportTraceServiceRequest` (see
+//! <https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding>).
+//!
+//! Every profile becomes a resource, described by the process that recorded
+//! it, and every interval event a span. Spans are grouped into instrumentation
+//! scopes by the event's category, or by its event kind if it has none. The
+//! parent of a span is the event it is nested in, according to the thread's
+//! call tree.
+//!
+//! Events that have been recorded with a trace context (see
+//! `Profiler::start_recording_interval_event_with_trace_context`) keep its
+//! trace and span ids, so that they line up with the spans that other services
+//! have exported for the same trace. All other events get span ids derived
+//! from the profile and their position in it, and belong to the trace of the
+//! event they are nested in. The outermost events without a trace context each
+//! start a trace of their own.
+//!
+//! With a `TimeRange`, only the spans that overlap it are exported, clipped to
+//! it. Their ids are derived from the whole profile, so that a span keeps the
+//! trace and parent of the event it is nested in, also if that event started
+//! before the window.
+
+use analyzeme::{nanos_since_epoch, process_name, CallTreeNode, ProfilingData, TimeRange};
+use serde::Serialize;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// `SPAN_KIND_INTERNAL`, all events are operations within the process.
+const SPAN_KIND_INTERNAL: u32 = 1;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportTraceServiceRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopeSpans {
+    scope: InstrumentationScope,
+    spans: Vec<Span>,
+}
+
+#[derive(Serialize)]
+struct InstrumentationScope {
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    /// 32 hex digits.
+    trace_id: String,
+    /// 16 hex digits.
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u32,
+    // 64-bit integers are encoded as decimal strings.
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AnyValue {
+    StringValue(String),
+    IntValue(String),
+}
+
+impl KeyValue {
+    fn string(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: AnyValue::StringValue(value.to_string()),
+        }
+    }
+
+    fn int(key: &str, value: u64) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: AnyValue::IntValue(value.to_string()),
+        }
+    }
+}
+
+/// The ids of the span that nested spans are children of.
+#[derive(Clone, Copy)]
+struct ParentSpan {
+    trace_id: u128,
+    span_id: u64,
+}
+
+/// The window of time of the spans that are exported, in the time of the
+/// profile's events. `end` is `None` if it extends to the end of the profile.
+#[derive(Clone, Copy)]
+struct Window {
+    start: SystemTime,
+    end: Option<SystemTime>,
+}
+
+impl Window {
+    fn new(data: &ProfilingData, range: Option<TimeRange>) -> Window {
+        let start_time = data.metadata().start_time;
+        let add = |nanos| start_time.checked_add(Duration::from_nanos(nanos));
+        match range {
+            Some(range) => Window {
+                start: add(range.start_nanos).unwrap_or(start_time),
+                end: add(range.end_nanos),
+            },
+            None => Window {
+                start: start_time,
+                end: None,
+            },
+        }
+    }
+
+    /// The part of the interval from `start` to `end` that lies within the
+    /// window, `None` if there is none. Empty intervals are within the window
+    /// if their start is.
+    fn clip(&self, start: SystemTime, end: SystemTime) -> Option<(SystemTime, SystemTime)> {
+        let end = match self.end {
+            Some(window_end) if start >= window_end => return None,
+            Some(window_end) => cmp::min(end, window_end),
+            None => end,
+        };
+        if start < self.start && end <= self.start {
+            return None;
+        }
+
+        Some((cmp::max(start, self.start), end))
+    }
+}
+
+impl ExportTraceServiceRequest {
+    /// Converts the interval events of `data`, only those within `range` if
+    /// given. Instant and integer events are not exported.
+    pub fn from_profile(
+        data: &ProfilingData,
+        range: Option<TimeRange>,
+    ) -> ExportTraceServiceRequest {
+        let metadata = data.metadata();
+        let resource = Resource {
+            attributes: vec![
+                KeyValue::string("service.name", process_name(&metadata.cmd)),
+                KeyValue::int("process.pid", metadata.process_id as u64),
+                KeyValue::string("process.command_line", metadata.cmd.trim()),
+            ],
+        };
+
+        // Derived ids must not depend on anything but the profile, so that
+        // exporting it twice yields the same spans, but they must differ
+        // between the profiles of different processes.
+        let seed = mix(metadata.process_id as u64 ^ mix(nanos_since_epoch(metadata.start_time)));
+
+        let window = Window::new(data, range);
+        let mut scopes = BTreeMap::<String, Vec<Span>>::new();
+        for tree in data.call_trees().values() {
+            add_spans(data, &tree.children, window, seed, &mut scopes);
+        }
+
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource,
+                scope_spans: scopes
+                    .into_iter()
+                    .map(|(name, spans)| ScopeSpans {
+                        scope: InstrumentationScope { name },
+                        spans,
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    /// The number of spans in the request.
+    pub fn span_count(&self) -> usize {
+        self.resource_spans
+            .iter()
+            .flat_map(|resource_spans| &resource_spans.scope_spans)
+            .map(|scope_spans| scope_spans.spans.len())
+            .sum()
+    }
+}
+
+/// Adds the spans of the interval events of the outermost `nodes` of a thread
+/// and of the events nested in them to the scopes they belong to, each span
+/// before the spans of its children. Events outside of `window` are skipped,
+/// along with the events nested in them.
+fn add_spans(
+    data: &ProfilingData,
+    nodes: &[CallTreeNode],
+    window: Window,
+    seed: u64,
+    scopes: &mut BTreeMap<String, Vec<Span>>,
+) {
+    // Parents are visited before their children, with an explicit stack
+    // rather than recursively, see `CallTreeNode::critical_path`.
+    let mut stack: Vec<(&CallTreeNode, Option<ParentSpan>)> =
+        nodes.iter().rev().map(|node| (node, None)).collect();
+    while let Some((node, parent)) = stack.pop() {
+        if let Some(ids) = add_span(data, node, parent, window, seed, scopes) {
+            stack.extend(node.children.iter().rev().map(|child| (child, Some(ids))));
+        }
+    }
+}
+
+/// Adds the span of the interval event of `node` to the scope it belongs to,
+/// and returns its ids, `None` if the event lies outside of `window`.
+fn add_span(
+    data: &ProfilingData,
+    node: &CallTreeNode,
+    parent: Option<ParentSpan>,
+    window: Window,
+    seed: u64,
+    scopes: &mut BTreeMap<String, Vec<Span>>,
+) -> Option<ParentSpan> {
+    let event_index = node.event_index.unwrap();
+    let event = data.decode_lightweight_event(event_index);
+    let event_start = event.start().unwrap();
+    let (start, end) = window.clip(event_start, event_start + node.duration)?;
+    let full_event = data.to_full_event(&event);
+
+    let derived_span_id = mix(seed ^ event_index as u64) | 1;
+    let ids = match data.trace_context(&event) {
+        Some(trace_context) => ParentSpan {
+            trace_id: trace_context.trace_id,
+            span_id: trace_context.span_id,
+        },
+        None => ParentSpan {
+            trace_id: match parent {
+                Some(parent) => parent.trace_id,
+                None => (mix(derived_span_id) as u128) << 64 | derived_span_id as u128,
+            },
+            span_id: derived_span_id,
+        },
+    };
+
+    // A span that continues a trace recorded elsewhere has its parent there.
+    let parent_span_id = match parent {
+        Some(parent) if parent.trace_id == ids.trace_id => Some(format!("{:016x}", parent.span_id)),
+        _ => None,
+    };
+
+    let duration = end.duration_since(start).unwrap();
+    let start = data.to_wall_clock(start).unwrap_or(start);
+
+    let mut attributes = vec![
+        KeyValue::string("measureme.event_kind", &full_event.event_kind),
+        KeyValue::int("thread.id", event.thread_id as u64),
+    ];
+    if let Some(name) = data.thread_name(event.thread_id) {
+        attributes.push(KeyValue::string("thread.name", name));
+    }
+    for (index, arg) in full_event.additional_data.iter().enumerate() {
+        let key = format!("arg{}", index);
+        attributes.push(match full_event.integer_arg(index) {
+            Some(value) => KeyValue::int(&key, value),
+            None => KeyValue::string(&key, arg),
+        });
+    }
+
+    let scope = match &full_event.category {
+        Some(category) => category.to_string(),
+        None => full_event.event_kind.to_string(),
+    };
+    scopes.entry(scope).or_default().push(Span {
+        trace_id: format!("{:032x}", ids.trace_id),
+        span_id: format!("{:016x}", ids.span_id),
+        parent_span_id,
+        name: node.label.clone(),
+        kind: SPAN_KIND_INTERNAL,
+        start_time_unix_nano: nanos_since_epoch(start).to_string(),
+        end_time_unix_nano: nanos_since_epoch(start + duration).to_string(),
+        attributes,
+    });
+
+    Some(ids)
+}
+
+/// The finalizer of SplitMix64, which spreads similar inputs, e.g. adjacent
+/// event indices, over the whole range of ids.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::{ProfilingDataBuilder, TraceContext};
+    use serde_json::Value;
+
+    fn spans(request: &ExportTraceServiceRequest) -> Vec<(String, Value)> {
+        let json = serde_json::to_value(request).unwrap();
+        let resource_spans = json["resourceSpans"].as_array().unwrap();
+        assert_eq!(resource_spans.len(), 1);
+
+        let mut spans = Vec::new();
+        for scope_spans in resource_spans[0]["scopeSpans"].as_array().unwrap() {
+            let scope = scope_spans["scope"]["name"].as_str().unwrap().to_string();
+            for span in scope_spans["spans"].as_array().unwrap() {
+                spans.push((scope.clone(), span.clone()));
+            }
+        }
+        spans
+    }
+
+    fn find<'a>(spans: &'a [(String, Value)], name: &str) -> &'a (String, Value) {
+        spans.iter().find(|(_, span)| span["name"] == name).unwrap()
+    }
+
+    fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+        let attributes = span["attributes"].as_array().unwrap();
+        let attribute = attributes.iter().find(|a| a["key"] == key).unwrap();
+        &attribute["value"]
+    }
+
+    #[test]
+    fn nested_spans() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 10, 100, |b| {
+            b.interval("Query", "inner\u{1e}main", 0, 20, 30, |_| {});
+            b.interval("Generic", "other", 0, 40, 50, |_| {});
+        });
+        b.interval("Query", "second", 1, 10, 20, |_| {});
+        b.instant("Query", "cache_hit", 0, 60);
+
+        let request = ExportTraceServiceRequest::from_profile(&b.into_profiling_data(), None);
+        assert_eq!(request.span_count(), 4);
+        let spans = spans(&request);
+
+        let (scope, outer) = find(&spans, "outer");
+        assert_eq!(scope, "Query");
+        assert!(outer.get("parentSpanId").is_none());
+        assert_eq!(outer["kind"], SPAN_KIND_INTERNAL as u64);
+        assert_eq!(outer["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(outer["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(attribute(outer, "thread.id")["intValue"], "0");
+
+        let start: u64 = outer["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let end: u64 = outer["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert_eq!(end - start, 90);
+
+        let (_, inner) = find(&spans, "inner");
+        assert_eq!(inner["traceId"], outer["traceId"].as_str().unwrap());
+        assert_eq!(inner["parentSpanId"], outer["spanId"].as_str().unwrap());
+        assert_eq!(attribute(inner, "arg0")["stringValue"], "main");
+
+        let (scope, other) = find(&spans, "other");
+        assert_eq!(scope, "Generic");
+        assert_eq!(other["parentSpanId"], outer["spanId"].as_str().unwrap());
+
+        // Outermost events start traces of their own.
+        let (_, second) = find(&spans, "second");
+        assert!(second.get("parentSpanId").is_none());
+        assert_ne!(second["traceId"], outer["traceId"].as_str().unwrap());
+        assert_ne!(second["spanId"], inner["spanId"].as_str().unwrap());
+    }
+
+    #[test]
+    fn trace_contexts_are_kept() {
+        let trace_context = TraceContext {
+            trace_id: 0x0123_4567_89ab_cdef_0011_2233_4455_6677,
+            span_id: 0xfeed,
+        };
+
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 10, 100, |b| {
+            b.interval_with_trace_context("Query", "request", 0, 20, 90, trace_context, |b| {
+                b.interval("Query", "inner", 0, 30, 40, |_| {});
+            });
+        });
+
+        let request = ExportTraceServiceRequest::from_profile(&b.into_profiling_data(), None);
+        let spans = spans(&request);
+
+        // The span belongs to a trace that has been started elsewhere, so its
+        // parent isn't the enclosing event.
+        let (_, outer) = find(&spans, "outer");
+        let (_, span) = find(&spans, "request");
+        assert_eq!(span["traceId"], "0123456789abcdef0011223344556677");
+        assert_eq!(span["spanId"], "000000000000feed");
+        assert!(span.get("parentSpanId").is_none());
+        assert_ne!(outer["traceId"], "0123456789abcdef0011223344556677");
+
+        let (_, inner) = find(&spans, "inner");
+        assert_eq!(inner["traceId"], "0123456789abcdef0011223344556677");
+        assert_eq!(inner["parentSpanId"], "000000000000feed");
+    }
+
+    #[test]
+    fn time_range_keeps_the_trace_of_enclosing_events() {
+        let trace_context = TraceContext {
+            trace_id: 0x0123_4567_89ab_cdef_0011_2233_4455_6677,
+            span_id: 0xfeed,
+        };
+
+        let mut b = ProfilingDataBuilder::new();
+        b.interval_with_trace_context("Query", "request", 0, 10, 100, trace_context, |b| {
+            b.interval("Query", "before", 0, 20, 30, |_| {});
+            b.interval("Query", "inner", 0, 40, 90, |_| {});
+        });
+        let data = b.into_profiling_data();
+
+        let range = TimeRange {
+            start_nanos: 50,
+            end_nanos: 80,
+        };
+        let request = ExportTraceServiceRequest::from_profile(&data, Some(range));
+        assert_eq!(request.span_count(), 2);
+        let spans = spans(&request);
+
+        let (_, span) = find(&spans, "request");
+        assert_eq!(span["traceId"], "0123456789abcdef0011223344556677");
+        assert_eq!(span["spanId"], "000000000000feed");
+
+        // The span started before the window and inherits the trace id and
+        // parent it has in the whole profile.
+        let (_, inner) = find(&spans, "inner");
+        assert_eq!(inner["traceId"], "0123456789abcdef0011223344556677");
+        assert_eq!(inner["parentSpanId"], "000000000000feed");
+        let start: u64 = inner["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let end: u64 = inner["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert_eq!(end - start, 30);
+    }
+}
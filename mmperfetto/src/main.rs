@@ -0,0 +1,46 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use analyzeme::{warn_about_profile, ProfilingData, TimeRange};
+use clap::Parser;
+
+mod proto;
+mod trace;
+
+use trace::TraceWriter;
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(required = true)]
+    file_prefix: Vec<PathBuf>,
+    /// the file to write the trace to
+    #[clap(short = 'o', long = "output", default_value = "trace.perfetto-trace")]
+    output: PathBuf,
+    /// only export the events within the given window of time, in seconds since
+    /// the start of each profile, e.g. `120:122.5`
+    #[clap(long = "time-range")]
+    time_range: Option<TimeRange>,
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let opt = Opt::parse();
+
+    let mut writer = TraceWriter::new(BufWriter::new(fs::File::create(&opt.output)?));
+
+    for file_prefix in &opt.file_prefix {
+        let mut data = ProfilingData::new(file_prefix)?;
+        warn_about_profile(&data, file_prefix);
+
+        if let Some(range) = opt.time_range {
+            data = data.slice_time_range(range.start_nanos, range.end_nanos);
+        }
+
+        writer.write_profile(&data)?;
+    }
+
+    writer.into_inner().flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! The subset of the protobuf wire format needed for writing Perfetto traces.
+//! Messages are encoded directly into a buffer, fields in the order in which
+//! they are added, which is all that the format requires.
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+
+/// An encoded protobuf message.
+#[derive(Default)]
+pub struct Message {
+    bytes: Vec<u8>,
+}
+
+impl Message {
+    pub fn new() -> Message {
+        Message::default()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Adds a field of any of the varint types, e.g. `uint64`, `uint32` or
+    /// an enum. Negative `int64` values are passed as their two's complement.
+    pub fn varint(&mut self, field: u32, value: u64) -> &mut Message {
+        self.tag(field, WIRE_TYPE_VARINT);
+        write_varint(&mut self.bytes, value);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Message {
+        self.length_delimited(field, value.as_bytes())
+    }
+
+    pub fn message(&mut self, field: u32, message: &Message) -> &mut Message {
+        self.length_delimited(field, &message.bytes)
+    }
+
+    fn length_delimited(&mut self, field: u32, bytes: &[u8]) -> &mut Message {
+        self.tag(field, WIRE_TYPE_LENGTH_DELIMITED);
+        write_varint(&mut self.bytes, bytes.len() as u64);
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u64) {
+        write_varint(&mut self.bytes, (field as u64) << 3 | wire_type);
+    }
+}
+
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        let encode = |value| {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            bytes
+        };
+
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(1), [0x01]);
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(128), [0x80, 0x01]);
+        assert_eq!(encode(300), [0xac, 0x02]);
+        assert_eq!(encode(u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn nested_messages() {
+        let mut inner = Message::new();
+        inner.varint(1, 150);
+
+        let mut outer = Message::new();
+        outer.string(2, "testing").message(3, &inner);
+
+        assert_eq!(
+            outer.bytes(),
+            &[
+                0x12, 0x07, b't', b'e', b's', b't', b'i', b'n', b'g', // field 2
+                0x1a, 0x03, 0x08, 0x96, 0x01, // field 3, containing field 1
+            ][..]
+        );
+    }
+}
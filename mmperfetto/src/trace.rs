@@ -0,0 +1,461 @@
+//! Conversion of `ProfilingData` into Perfetto's protobuf `Trace` format (see
+//! <https://perfetto.dev/docs/reference/trace-packet-proto>).
+//!
+//! Every profile becomes a process track with one child track per thread and
+//! one counter track per label of the instant events that have been recorded
+//! with a value. Interval events are written as pairs of slice begin and end
+//! events in the order of the thread's call tree, so that each track is
+//! properly nested. Event names, categories and argument names are interned:
+//! each distinct string is written once, along with the first event that
+//! uses it, and referred to by its id afterwards.
+
+use crate::proto::{write_varint, Message};
+use analyzeme::{nanos_since_epoch, process_name, CallTreeNode, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+// The numbers of the fields used below, from Perfetto's `.proto` files.
+
+const TRACE_PACKET: u32 = 1;
+
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_TRUSTED_PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_INTERNED_DATA: u32 = 12;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_PROCESS: u32 = 3;
+const TRACK_THREAD: u32 = 4;
+const TRACK_PARENT_UUID: u32 = 5;
+const TRACK_COUNTER: u32 = 8;
+
+const PROCESS_PID: u32 = 1;
+const PROCESS_NAME: u32 = 6;
+
+const THREAD_PID: u32 = 1;
+const THREAD_TID: u32 = 2;
+const THREAD_NAME: u32 = 5;
+
+const EVENT_CATEGORY_IIDS: u32 = 3;
+const EVENT_DEBUG_ANNOTATIONS: u32 = 4;
+const EVENT_TYPE: u32 = 9;
+const EVENT_NAME_IID: u32 = 10;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_COUNTER_VALUE: u32 = 30;
+
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_COUNTER: u64 = 4;
+
+const ANNOTATION_NAME_IID: u32 = 1;
+const ANNOTATION_UINT_VALUE: u32 = 3;
+const ANNOTATION_STRING_VALUE: u32 = 6;
+
+const INTERNED_EVENT_CATEGORIES: u32 = 1;
+const INTERNED_EVENT_NAMES: u32 = 2;
+const INTERNED_DEBUG_ANNOTATION_NAMES: u32 = 3;
+
+const INTERNED_IID: u32 = 1;
+const INTERNED_NAME: u32 = 2;
+
+/// All packets are written on a single sequence, which the interned strings
+/// are scoped to.
+const SEQUENCE_ID: u64 = 1;
+
+/// The interning ids of the strings of one kind, e.g. of event names.
+#[derive(Default)]
+struct Interner {
+    ids: FxHashMap<String, u64>,
+}
+
+impl Interner {
+    /// Returns the interning id of `s`. If `s` hasn't been interned before,
+    /// its definition is added to `interned_data` as field `field`.
+    fn intern(&mut self, s: &str, field: u32, interned_data: &mut Option<Message>) -> u64 {
+        if let Some(&iid) = self.ids.get(s) {
+            return iid;
+        }
+
+        // Interning ids must not be 0.
+        let iid = self.ids.len() as u64 + 1;
+        self.ids.insert(s.to_string(), iid);
+
+        let mut entry = Message::new();
+        entry.varint(INTERNED_IID, iid).string(INTERNED_NAME, s);
+        interned_data
+            .get_or_insert_with(Message::new)
+            .message(field, &entry);
+
+        iid
+    }
+}
+
+/// Writes the profiles passed to `write_profile` as a single trace.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    next_uuid: u64,
+    wrote_first_packet: bool,
+    event_names: Interner,
+    categories: Interner,
+    annotation_names: Interner,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W) -> TraceWriter<W> {
+        TraceWriter {
+            out,
+            next_uuid: 1,
+            wrote_first_packet: false,
+            event_names: Interner::default(),
+            categories: Interner::default(),
+            annotation_names: Interner::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    pub fn write_profile(&mut self, data: &ProfilingData) -> io::Result<()> {
+        let pid = data.metadata().process_id as u64;
+
+        let process_uuid = self.next_uuid();
+        let mut process = Message::new();
+        process
+            .varint(PROCESS_PID, pid)
+            .string(PROCESS_NAME, process_name(&data.metadata().cmd));
+        let mut track = Message::new();
+        track
+            .varint(TRACK_UUID, process_uuid)
+            .message(TRACK_PROCESS, &process);
+        self.write_track_descriptor(&track)?;
+
+        for (thread_id, tree) in data.call_trees() {
+            let thread_uuid = self.next_uuid();
+            let thread_name = match data.thread_name(thread_id) {
+                Some(name) => name.to_string(),
+                None => format!("Thread {}", thread_id),
+            };
+
+            let mut thread = Message::new();
+            thread
+                .varint(THREAD_PID, pid)
+                .varint(THREAD_TID, thread_id as u64)
+                .string(THREAD_NAME, &thread_name);
+            let mut track = Message::new();
+            track
+                .varint(TRACK_UUID, thread_uuid)
+                .varint(TRACK_PARENT_UUID, process_uuid)
+                .message(TRACK_THREAD, &thread);
+            self.write_track_descriptor(&track)?;
+
+            for node in &tree.children {
+                self.write_slice(data, node, thread_uuid)?;
+            }
+        }
+
+        let mut counter_tracks = FxHashMap::<String, u64>::default();
+        for event in data.iter() {
+            let value = match event.payload.instant_value() {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let label = data.to_full_event(&event).label;
+            let track_uuid = match counter_tracks.get(&label[..]) {
+                Some(&uuid) => uuid,
+                None => {
+                    let uuid = self.next_uuid();
+                    let mut track = Message::new();
+                    track
+                        .varint(TRACK_UUID, uuid)
+                        .varint(TRACK_PARENT_UUID, process_uuid)
+                        .string(TRACK_NAME, &label)
+                        .message(TRACK_COUNTER, &Message::new());
+                    self.write_track_descriptor(&track)?;
+
+                    counter_tracks.insert(label.into_owned(), uuid);
+                    uuid
+                }
+            };
+
+            let mut track_event = Message::new();
+            track_event
+                .varint(EVENT_TYPE, TYPE_COUNTER)
+                .varint(EVENT_TRACK_UUID, track_uuid)
+                .varint(EVENT_COUNTER_VALUE, value);
+            self.write_track_event(event.start().unwrap(), &track_event, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the slice of the interval event of `node`, and within it the
+    /// slices of its children.
+    fn write_slice(
+        &mut self,
+        data: &ProfilingData,
+        node: &CallTreeNode,
+        track_uuid: u64,
+    ) -> io::Result<()> {
+        let event = data.decode_lightweight_event(node.event_index.unwrap());
+        let full_event = data.to_full_event(&event);
+        let start = event.start().unwrap();
+
+        let mut interned_data = None;
+        let mut track_event = Message::new();
+        track_event
+            .varint(EVENT_TYPE, TYPE_SLICE_BEGIN)
+            .varint(EVENT_TRACK_UUID, track_uuid)
+            .varint(
+                EVENT_NAME_IID,
+                self.event_names
+                    .intern(&node.label, INTERNED_EVENT_NAMES, &mut interned_data),
+            )
+            .varint(
+                EVENT_CATEGORY_IIDS,
+                self.categories.intern(
+                    &full_event.event_kind,
+                    INTERNED_EVENT_CATEGORIES,
+                    &mut interned_data,
+                ),
+            );
+
+        for (index, arg) in full_event.additional_data.iter().enumerate() {
+            let name_iid = self.annotation_names.intern(
+                &format!("arg{}", index),
+                INTERNED_DEBUG_ANNOTATION_NAMES,
+                &mut interned_data,
+            );
+
+            let mut annotation = Message::new();
+            annotation.varint(ANNOTATION_NAME_IID, name_iid);
+            match full_event.integer_arg(index) {
+                Some(value) => annotation.varint(ANNOTATION_UINT_VALUE, value),
+                None => annotation.string(ANNOTATION_STRING_VALUE, arg),
+            };
+            track_event.message(EVENT_DEBUG_ANNOTATIONS, &annotation);
+        }
+
+        self.write_track_event(start, &track_event, interned_data.as_ref())?;
+
+        for child in &node.children {
+            self.write_slice(data, child, track_uuid)?;
+        }
+
+        let mut track_event = Message::new();
+        track_event
+            .varint(EVENT_TYPE, TYPE_SLICE_END)
+            .varint(EVENT_TRACK_UUID, track_uuid);
+        self.write_track_event(start + node.duration, &track_event, None)
+    }
+
+    fn write_track_descriptor(&mut self, track: &Message) -> io::Result<()> {
+        let mut packet = Message::new();
+        packet.message(PACKET_TRACK_DESCRIPTOR, track);
+        self.write_packet(packet)
+    }
+
+    fn write_track_event(
+        &mut self,
+        timestamp: SystemTime,
+        track_event: &Message,
+        interned_data: Option<&Message>,
+    ) -> io::Result<()> {
+        let mut packet = Message::new();
+        packet
+            .varint(PACKET_TIMESTAMP, nanos_since_epoch(timestamp))
+            .message(PACKET_TRACK_EVENT, track_event);
+        if let Some(interned_data) = interned_data {
+            packet.message(PACKET_INTERNED_DATA, interned_data);
+        }
+        self.write_packet(packet)
+    }
+
+    fn write_packet(&mut self, mut packet: Message) -> io::Result<()> {
+        // The first packet of the sequence makes the interned strings valid,
+        // all later ones may refer to them.
+        let sequence_flags = if self.wrote_first_packet {
+            SEQ_NEEDS_INCREMENTAL_STATE
+        } else {
+            SEQ_INCREMENTAL_STATE_CLEARED
+        };
+        self.wrote_first_packet = true;
+
+        packet
+            .varint(PACKET_TRUSTED_PACKET_SEQUENCE_ID, SEQUENCE_ID)
+            .varint(PACKET_SEQUENCE_FLAGS, sequence_flags);
+
+        // A `Trace` is nothing but a sequence of `TracePacket`s, so they can
+        // be written one at a time.
+        let mut header = Vec::new();
+        write_varint(&mut header, (TRACE_PACKET as u64) << 3 | 2);
+        write_varint(&mut header, packet.bytes().len() as u64);
+        self.out.write_all(&header)?;
+        self.out.write_all(packet.bytes())
+    }
+
+    fn next_uuid(&mut self) -> u64 {
+        let uuid = self.next_uuid;
+        self.next_uuid += 1;
+        uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+
+    enum Value<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    impl<'a> Value<'a> {
+        fn varint(&self) -> u64 {
+            match *self {
+                Value::Varint(value) => value,
+                Value::Bytes(_) => panic!("expected a varint"),
+            }
+        }
+
+        fn bytes(&self) -> &'a [u8] {
+            match *self {
+                Value::Bytes(bytes) => bytes,
+                Value::Varint(_) => panic!("expected a length-delimited field"),
+            }
+        }
+    }
+
+    fn read_varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn parse(mut bytes: &[u8]) -> Vec<(u32, Value<'_>)> {
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let tag = read_varint(&mut bytes);
+            let value = match tag & 7 {
+                0 => Value::Varint(read_varint(&mut bytes)),
+                2 => {
+                    let len = read_varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Value::Bytes(value)
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push(((tag >> 3) as u32, value));
+        }
+        fields
+    }
+
+    fn field<'a>(fields: &[(u32, Value<'a>)], number: u32) -> Option<&'a [u8]> {
+        fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value.bytes())
+    }
+
+    fn varint_field(fields: &[(u32, Value<'_>)], number: u32) -> Option<u64> {
+        fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value.varint())
+    }
+
+    #[test]
+    fn slices_and_interned_names() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "outer", 0, 10, 100, |b| {
+            b.interval("Query", "inner", 0, 20, 30, |_| {});
+            b.interval("Query", "inner", 0, 40, 50, |_| {});
+        });
+        b.instant_with_value("Memory", "rss", 0, 60, 1234);
+
+        let mut writer = TraceWriter::new(Vec::new());
+        writer.write_profile(&b.into_profiling_data()).unwrap();
+        let bytes = writer.into_inner();
+
+        let packets: Vec<_> = parse(&bytes)
+            .iter()
+            .map(|(number, value)| {
+                assert_eq!(*number, TRACE_PACKET);
+                parse(value.bytes())
+            })
+            .collect();
+
+        // The process, thread and counter tracks, and 3 slices.
+        assert_eq!(packets.len(), 3 + 3 * 2 + 1);
+        assert_eq!(
+            varint_field(&packets[0], PACKET_SEQUENCE_FLAGS),
+            Some(SEQ_INCREMENTAL_STATE_CLEARED)
+        );
+        assert!(packets[1..]
+            .iter()
+            .all(|p| varint_field(p, PACKET_SEQUENCE_FLAGS) == Some(SEQ_NEEDS_INCREMENTAL_STATE)));
+
+        let mut slices = Vec::new();
+        let mut interned_names = Vec::new();
+        for packet in &packets {
+            let track_event = match field(packet, PACKET_TRACK_EVENT) {
+                Some(track_event) => parse(track_event),
+                None => continue,
+            };
+
+            if let Some(interned_data) = field(packet, PACKET_INTERNED_DATA) {
+                for (number, entry) in parse(interned_data) {
+                    if number == INTERNED_EVENT_NAMES {
+                        let entry = parse(entry.bytes());
+                        let name = field(&entry, INTERNED_NAME).unwrap();
+                        interned_names.push((
+                            varint_field(&entry, INTERNED_IID).unwrap(),
+                            String::from_utf8(name.to_vec()).unwrap(),
+                        ));
+                    }
+                }
+            }
+
+            slices.push((
+                varint_field(packet, PACKET_TIMESTAMP).unwrap(),
+                varint_field(&track_event, EVENT_TYPE).unwrap(),
+                varint_field(&track_event, EVENT_NAME_IID),
+                varint_field(&track_event, EVENT_COUNTER_VALUE),
+            ));
+        }
+
+        assert_eq!(
+            interned_names,
+            vec![(1, "outer".to_string()), (2, "inner".to_string())]
+        );
+        assert_eq!(
+            slices,
+            vec![
+                (10, TYPE_SLICE_BEGIN, Some(1), None),
+                (20, TYPE_SLICE_BEGIN, Some(2), None),
+                (30, TYPE_SLICE_END, None, None),
+                (40, TYPE_SLICE_BEGIN, Some(2), None),
+                (50, TYPE_SLICE_END, None, None),
+                (100, TYPE_SLICE_END, None, None),
+                (60, TYPE_COUNTER, None, Some(1234)),
+            ]
+        );
+    }
+}
@@ -53,6 +53,11 @@ fn print_event(event: &Event<'_>, global_start_time: SystemTime) {
             system_time_to_micros_since(end, global_start_time)
         ),
         EventPayload::Integer(i) => format!("{}", i),
+        EventPayload::InstantWithValue { time, value } => format!(
+            "{} μs, value {}",
+            system_time_to_micros_since(time, global_start_time),
+            value
+        ),
     };
 
     println!(
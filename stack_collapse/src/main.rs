@@ -1,29 +1,88 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::path::PathBuf;
 
-use analyzeme::{collapse_stacks, ProfilingData};
-use clap::Parser;
+use analyzeme::{collapse_stacks_folded, warn_about_profile, ProfilingData};
+use clap::{Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CountBy {
+    /// Microseconds of self time, for profiles recorded with a time counter
+    Time,
+    /// Self instruction counts, for profiles recorded with `instructions:u`
+    Instructions,
+}
 
 #[derive(Parser, Debug)]
 struct Opt {
     file_prefix: PathBuf,
+
+    /// What the count of each stack measures
+    #[clap(long = "count-by", value_enum, default_value = "time")]
+    count_by: CountBy,
+
+    /// The file to write the folded stacks to, `-` for stdout
+    #[clap(short = 'o', long = "output", default_value = "out.stacks_folded")]
+    output: PathBuf,
+
+    /// Show demangled names instead of mangled Rust symbol names, e.g.
+    /// `core::ptr::drop_in_place` instead of `_ZN4core3ptr13drop_in_place..`
+    #[clap(long = "demangle")]
+    demangle: bool,
 }
 
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let opt = Opt::from_args();
 
-    let profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    let mut profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    if opt.demangle {
+        profiling_data.demangle_symbols();
+    }
 
-    let recorded_stacks = collapse_stacks(&profiling_data);
+    warn_about_profile(&profiling_data, &opt.file_prefix);
 
-    let mut file = BufWriter::new(File::create("out.stacks_folded")?);
+    // Profiles written by older versions of measureme don't name their
+    // counter, but they always measured wall time.
+    let counter = profiling_data.metadata().counter.as_ref();
+    let (counter_name, measures_time) = match counter {
+        Some(counter) => (&counter.name[..], counter.measures_time()),
+        None => ("wall-time", true),
+    };
+
+    let units_per_count = match opt.count_by {
+        CountBy::Time if measures_time => 1_000,
+        CountBy::Instructions if counter_name == "instructions:u" => 1,
+        count_by => {
+            return Err(format!(
+                "Cannot count by {:?} for `{}`, which has been recorded with the `{}` counter",
+                count_by,
+                opt.file_prefix.display(),
+                counter_name
+            )
+            .into())
+        }
+    };
+
+    let recorded_stacks = collapse_stacks_folded(&profiling_data);
+
+    let mut file: BufWriter<Box<dyn Write>> = if opt.output.as_os_str() == "-" {
+        BufWriter::new(Box::new(io::stdout()))
+    } else {
+        BufWriter::new(Box::new(File::create(&opt.output)?))
+    };
 
     //now that we've got all of the recorded data, print the results to the output file
     for (unique_stack, count) in recorded_stacks {
-        writeln!(file, "{} {}", unique_stack, count)?;
+        let count = (count + units_per_count / 2) / units_per_count;
+
+        // Stacks that round to zero would be dropped by inferno anyway.
+        if count > 0 {
+            writeln!(file, "{} {}", unique_stack, count)?;
+        }
     }
 
+    file.flush()?;
+
     Ok(())
 }
@@ -115,9 +115,9 @@ impl<E> SamplePoint<E> {
 
 impl SamplePoint<WithParent<Event<'_>>> {
     fn timestamp(&self) -> SystemTime {
-        let timestamp = match self.event().this.payload {
-            EventPayload::Timestamp(t) => t,
-            _ => unreachable!(),
+        let timestamp = match self.event().this.payload.timestamp() {
+            Some(t) => t,
+            None => unreachable!(),
         };
 
         match (self, timestamp) {
@@ -191,9 +191,8 @@ impl<'a> BackwardsIterator for SamplePoints<'a> {
                                 SamplePoint::End(event)
                             }
 
-                            EventPayload::Timestamp(Timestamp::Instant(_)) => {
-                                SamplePoint::Instant(event)
-                            }
+                            EventPayload::Timestamp(Timestamp::Instant(_))
+                            | EventPayload::InstantWithValue { .. } => SamplePoint::Instant(event),
                             EventPayload::Integer(_) => {
                                 unreachable!()
                             }
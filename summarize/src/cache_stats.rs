@@ -0,0 +1,90 @@
+//! The query cache hit rates shown by `summarize summarize --cache-stats`.
+
+use analyzeme::ProfilingData;
+use measureme::rustc::{QUERY_CACHE_HIT_EVENT_KIND, QUERY_EVENT_KIND};
+use rustc_hash::FxHashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of `QUERY_CACHE_HIT_EVENT_KIND` events, i.e. of times the
+    /// result of the query has been found in the cache.
+    pub hits: usize,
+    /// The number of `QUERY_EVENT_KIND` events, i.e. of times the query has
+    /// actually been executed.
+    pub misses: usize,
+}
+
+impl CacheStats {
+    /// The percentage of lookups that have been cache hits.
+    pub fn hit_percent(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses) as f64 * 100.0
+    }
+}
+
+/// Counts the cache hits and misses of each query, by label. Other labels,
+/// e.g. of `GenericActivity` events, are not in the result, which is empty
+/// if the profile hasn't been recorded by rustc.
+pub fn cache_stats(data: &ProfilingData) -> FxHashMap<String, CacheStats> {
+    let mut stats = FxHashMap::<String, CacheStats>::default();
+
+    for event in data.iter_full() {
+        let is_hit = if event.event_kind == QUERY_CACHE_HIT_EVENT_KIND {
+            event.payload.is_instant()
+        } else if event.event_kind == QUERY_EVENT_KIND {
+            if !event.payload.is_interval() {
+                continue;
+            }
+            false
+        } else {
+            continue;
+        };
+
+        let stats = stats.entry(event.label.into_owned()).or_default();
+        if is_hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+    use measureme::rustc::GENERIC_ACTIVITY_EVENT_KIND;
+
+    #[test]
+    fn hits_and_misses_per_query() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval(QUERY_EVENT_KIND, "typeck", 0, 0, 100, |b| {
+            b.instant(QUERY_CACHE_HIT_EVENT_KIND, "type_of", 0, 10);
+            b.instant(QUERY_CACHE_HIT_EVENT_KIND, "type_of", 0, 20);
+            b.interval(QUERY_EVENT_KIND, "type_of", 0, 30, 40, |_| {});
+            b.instant(QUERY_CACHE_HIT_EVENT_KIND, "typeck", 0, 50);
+        });
+        b.interval(QUERY_EVENT_KIND, "type_of", 1, 0, 10, |_| {});
+        b.interval(GENERIC_ACTIVITY_EVENT_KIND, "codegen", 1, 20, 80, |_| {});
+
+        let stats = cache_stats(&b.into_profiling_data());
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["typeck"], CacheStats { hits: 1, misses: 1 });
+        assert_eq!(stats["type_of"], CacheStats { hits: 2, misses: 2 });
+        assert_eq!(stats["type_of"].hit_percent(), 50.0);
+    }
+
+    #[test]
+    fn no_stats_without_query_events() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Custom", "work", 0, 0, 100, |b| {
+            b.instant("Custom", "marker", 0, 50);
+        });
+
+        assert!(cache_stats(&b.into_profiling_data()).is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+//! The self time of the events rolled up by their category, for
+//! `summarize summarize --group-by category` and `--assert`.
+
+use analyzeme::{CallTreeNode, EventSelfTime, ProfilingData};
+use rustc_hash::FxHashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Separates the levels of a hierarchical category, e.g. `codegen::llvm`.
+/// Events are rolled up by the first level of their category.
+pub const CATEGORY_SEPARATOR: &str = "::";
+
+/// The name given to the events that don't have a category.
+pub const NO_CATEGORY: &str = "(none)";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CategoryData {
+    pub category: String,
+    pub self_time: Duration,
+    pub invocation_count: usize,
+    /// The labels of the events within this category, ordered like the
+    /// categories.
+    pub labels: Vec<(String, Duration)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CategoryResults {
+    /// Ordered by descending self time, ties are broken by name.
+    pub categories: Vec<CategoryData>,
+    /// The self time of all interval events.
+    pub total_time: Duration,
+}
+
+/// Returns the top-level category of `category`, e.g. `codegen` for
+/// `codegen::llvm`.
+pub fn top_level_category(category: &str) -> &str {
+    match category.find(CATEGORY_SEPARATOR) {
+        Some(index) => &category[..index],
+        None => category,
+    }
+}
+
+/// Sums up the self time of the interval events per top-level category, given
+/// the `self_times` of `data` (see `ProfilingData::self_times`). Only events
+/// for which `include_label` returns true are attributed to a category, but
+/// all events count towards the total time.
+pub fn group_by_category(
+    data: &ProfilingData,
+    self_times: &[EventSelfTime],
+    include_label: impl Fn(&str) -> bool,
+) -> CategoryResults {
+    let mut categories = FxHashMap::<String, (usize, FxHashMap<String, Duration>)>::default();
+    let mut total_time = Duration::from_secs(0);
+
+    for self_time in self_times {
+        total_time += self_time.self_duration;
+
+        let event = data.to_full_event(&data.decode_lightweight_event(self_time.event_index));
+        if !include_label(&event.label) {
+            continue;
+        }
+
+        let category = match event.category {
+            Some(ref category) => top_level_category(category),
+            None => NO_CATEGORY,
+        };
+
+        let (invocation_count, labels) = categories.entry(category.to_string()).or_default();
+        *invocation_count += 1;
+        *labels.entry(event.label.into_owned()).or_default() += self_time.self_duration;
+    }
+
+    let mut categories: Vec<_> = categories
+        .into_iter()
+        .map(|(category, (invocation_count, labels))| {
+            let mut labels: Vec<_> = labels.into_iter().collect();
+            labels.sort_by(|l, r| r.1.cmp(&l.1).then_with(|| l.0.cmp(&r.0)));
+
+            CategoryData {
+                category,
+                self_time: labels.iter().map(|&(_, self_time)| self_time).sum(),
+                invocation_count,
+                labels,
+            }
+        })
+        .collect();
+
+    categories.sort_by(|l, r| {
+        r.self_time
+            .cmp(&l.self_time)
+            .then_with(|| l.category.cmp(&r.category))
+    });
+
+    CategoryResults {
+        categories,
+        total_time,
+    }
+}
+
+/// Sums up the durations of the interval events per top-level category, for
+/// each event only the part that isn't nested within another event of the
+/// same category, so that recursion isn't counted twice.
+pub fn total_time_by_category(data: &ProfilingData) -> FxHashMap<String, Duration> {
+    let mut total_times = FxHashMap::default();
+
+    for tree in data.call_trees().values() {
+        // The tree is walked with an explicit stack, like
+        // `CallTreeNode::critical_path` does, in which `None` closes the node
+        // that was entered last, so that `outer_categories` holds the
+        // categories of the nodes that enclose the next one. The root of the
+        // call tree isn't an event.
+        let mut outer_categories: Vec<&str> = Vec::new();
+        let mut stack: Vec<Option<&CallTreeNode>> = tree.children.iter().rev().map(Some).collect();
+        while let Some(entry) = stack.pop() {
+            let node = match entry {
+                Some(node) => node,
+                None => {
+                    outer_categories.pop();
+                    continue;
+                }
+            };
+
+            let category = match node.category {
+                Some(ref category) => top_level_category(category),
+                None => NO_CATEGORY,
+            };
+
+            let is_outermost = !outer_categories.contains(&category);
+            if is_outermost {
+                *total_times.entry(category.to_string()).or_default() += node.duration;
+            }
+
+            outer_categories.push(category);
+            stack.push(None);
+            stack.extend(node.children.iter().rev().map(Some));
+        }
+    }
+
+    total_times
+}
+
+/// The largest acceptable share of a top-level category in the total time,
+/// parsed from `<category>:<percent>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CategoryBudget {
+    pub category: String,
+    pub max_percent: f64,
+}
+
+impl FromStr for CategoryBudget {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<CategoryBudget, Self::Err> {
+        let invalid = || -> Self::Err {
+            From::from(format!(
+                "Invalid category budget `{}`: expected `<category>:<percent>`",
+                s
+            ))
+        };
+
+        let index = s.rfind(':').ok_or_else(invalid)?;
+        let max_percent = s[index + 1..].parse::<f64>().map_err(|_| invalid())?;
+
+        if index == 0 || !max_percent.is_finite() || max_percent < 0.0 {
+            return Err(invalid());
+        }
+
+        Ok(CategoryBudget {
+            category: s[..index].to_string(),
+            max_percent,
+        })
+    }
+}
+
+/// Which time of its events counts towards the share of a category, see
+/// `budget_violations`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetTime {
+    /// The self time, as with `--group-by category`.
+    SelfTime,
+    /// The durations, see `total_time_by_category`.
+    TotalTime,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BudgetViolation {
+    pub category: String,
+    pub percent: f64,
+    pub max_percent: f64,
+}
+
+/// Returns the budgets whose category takes up a larger share of the total
+/// time than they allow, i.e. of the self time of all interval events, along
+/// with that share. Categories without events have a share of zero.
+pub fn budget_violations(
+    data: &ProfilingData,
+    self_times: &[EventSelfTime],
+    budgets: &[CategoryBudget],
+    budget_time: BudgetTime,
+) -> Vec<BudgetViolation> {
+    let results = group_by_category(data, self_times, |_| true);
+    let total_nanos = results.total_time.as_nanos();
+    let times: FxHashMap<String, Duration> = match budget_time {
+        BudgetTime::SelfTime => results
+            .categories
+            .into_iter()
+            .map(|category| (category.category, category.self_time))
+            .collect(),
+        BudgetTime::TotalTime => total_time_by_category(data),
+    };
+
+    budgets
+        .iter()
+        .filter_map(|budget| {
+            let time = times.get(&budget.category).copied().unwrap_or_default();
+            let percent = if total_nanos == 0 {
+                0.0
+            } else {
+                time.as_nanos() as f64 * 100.0 / total_nanos as f64
+            };
+
+            if percent > budget.max_percent {
+                Some(BudgetViolation {
+                    category: budget.category.clone(),
+                    percent,
+                    max_percent: budget.max_percent,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::ProfilingDataBuilder;
+
+    fn nanos(nanos: u64) -> Duration {
+        Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn self_time_is_rolled_up_by_top_level_category() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "typeck_fn\x1E\x12typeck", 0, 0, 100, |b| {
+            b.interval(
+                "Query",
+                "llvm_module\x1E\x12codegen::llvm",
+                0,
+                10,
+                40,
+                |_| {},
+            );
+            b.interval("Query", "collect\x1E\x12codegen", 0, 50, 60, |_| {});
+        });
+        b.interval("Query", "typeck_item\x1E\x12typeck", 1, 0, 20, |_| {});
+        b.interval("Query", "encode\x1E\x12metadata", 1, 30, 50, |_| {});
+        b.interval("Query", "untagged", 1, 60, 70, |_| {});
+        b.instant("Query", "marker\x1E\x12instant", 1, 80);
+
+        let data = b.into_profiling_data();
+        let results = group_by_category(&data, &data.self_times(), |_| true);
+
+        assert_eq!(results.total_time, nanos(150));
+        assert_eq!(
+            results.categories,
+            vec![
+                CategoryData {
+                    category: "typeck".to_string(),
+                    self_time: nanos(80),
+                    invocation_count: 2,
+                    labels: vec![
+                        ("typeck_fn".to_string(), nanos(60)),
+                        ("typeck_item".to_string(), nanos(20)),
+                    ],
+                },
+                CategoryData {
+                    category: "codegen".to_string(),
+                    self_time: nanos(40),
+                    invocation_count: 2,
+                    labels: vec![
+                        ("llvm_module".to_string(), nanos(30)),
+                        ("collect".to_string(), nanos(10)),
+                    ],
+                },
+                CategoryData {
+                    category: "metadata".to_string(),
+                    self_time: nanos(20),
+                    invocation_count: 1,
+                    labels: vec![("encode".to_string(), nanos(20))],
+                },
+                CategoryData {
+                    category: NO_CATEGORY.to_string(),
+                    self_time: nanos(10),
+                    invocation_count: 1,
+                    labels: vec![("untagged".to_string(), nanos(10))],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_are_broken_by_name() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "b1\x1E\x12b", 0, 0, 10, |_| {});
+        b.interval("Query", "a2\x1E\x12a", 0, 10, 20, |_| {});
+        b.interval("Query", "c1\x1E\x12c", 0, 20, 30, |_| {});
+        b.interval("Query", "a1\x1E\x12a", 0, 30, 35, |_| {});
+        b.interval("Query", "a0\x1E\x12a", 0, 35, 40, |_| {});
+
+        let data = b.into_profiling_data();
+        let results = group_by_category(&data, &data.self_times(), |label| label != "c1");
+
+        assert_eq!(results.total_time, nanos(40));
+
+        let categories: Vec<_> = results
+            .categories
+            .iter()
+            .map(|c| (&c.category[..], c.self_time))
+            .collect();
+        assert_eq!(categories, vec![("a", nanos(20)), ("b", nanos(10))]);
+
+        let labels: Vec<_> = results.categories[0]
+            .labels
+            .iter()
+            .map(|(label, _)| &label[..])
+            .collect();
+        assert_eq!(labels, vec!["a2", "a0", "a1"]);
+    }
+
+    #[test]
+    fn budgets() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "typeck_fn\x1E\x12typeck", 0, 0, 100, |b| {
+            b.interval("Query", "nested\x1E\x12typeck::inner", 0, 10, 30, |b| {
+                b.interval("Query", "llvm\x1E\x12codegen", 0, 15, 25, |_| {});
+            });
+        });
+        b.interval("Query", "collect\x1E\x12codegen", 1, 0, 100, |_| {});
+        let data = b.into_profiling_data();
+
+        let budget = |s: &str| s.parse::<CategoryBudget>().unwrap();
+        let budgets = vec![
+            budget("typeck:45"),
+            budget("codegen:50"),
+            budget("metadata:0"),
+        ];
+
+        // Of the 200ns, typeck takes up 90ns of self time, which is within
+        // its budget, and 100ns in total since the nested typeck event isn't
+        // counted twice.
+        assert_eq!(
+            budget_violations(&data, &data.self_times(), &budgets, BudgetTime::SelfTime),
+            vec![BudgetViolation {
+                category: "codegen".to_string(),
+                percent: 55.0,
+                max_percent: 50.0,
+            }]
+        );
+        assert_eq!(
+            budget_violations(&data, &data.self_times(), &budgets, BudgetTime::TotalTime),
+            vec![
+                BudgetViolation {
+                    category: "typeck".to_string(),
+                    percent: 50.0,
+                    max_percent: 45.0,
+                },
+                BudgetViolation {
+                    category: "codegen".to_string(),
+                    percent: 55.0,
+                    max_percent: 50.0,
+                },
+            ]
+        );
+
+        let total_times = total_time_by_category(&data);
+        assert_eq!(total_times["typeck"], nanos(100));
+        assert_eq!(total_times["codegen"], nanos(110));
+
+        assert_eq!(
+            budget("codegen::llvm:2.5"),
+            CategoryBudget {
+                category: "codegen::llvm".to_string(),
+                max_percent: 2.5,
+            }
+        );
+        assert!("typeck".parse::<CategoryBudget>().is_err());
+        assert!(":5".parse::<CategoryBudget>().is_err());
+        assert!("typeck:x".parse::<CategoryBudget>().is_err());
+        assert!("typeck:-1".parse::<CategoryBudget>().is_err());
+    }
+
+    #[test]
+    fn top_level_categories() {
+        assert_eq!(top_level_category("codegen::llvm::opt"), "codegen");
+        assert_eq!(top_level_category("typeck"), "typeck");
+        assert_eq!(top_level_category(""), "");
+    }
+}
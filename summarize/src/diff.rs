@@ -3,7 +3,9 @@ use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashSet;
+use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
 
 #[derive(Serialize, Deserialize)]
@@ -31,6 +33,75 @@ fn build_artifact_lookup(artifact_sizes: &[ArtifactSize]) -> FxHashMap<&str, usi
     lookup
 }
 
+impl DiffResults {
+    /// Orders the items by their change in self time, largest regression
+    /// first, instead of by the absolute value of the change.
+    pub fn sort_by_regression(&mut self) {
+        self.query_data.sort_by(|l, r| {
+            r.self_time
+                .cmp(&l.self_time)
+                .then_with(|| l.label.cmp(&r.label))
+        });
+    }
+
+    /// Returns the items whose self time has grown by more than their
+    /// threshold, along with the threshold. Items that only exist in the
+    /// changed profile count as an infinite regression.
+    pub fn regressions<'a>(
+        &'a self,
+        thresholds: &'a [RegressionThreshold],
+    ) -> Vec<(&'a QueryDataDiff, &'a RegressionThreshold)> {
+        thresholds
+            .iter()
+            .filter_map(|threshold| {
+                let query_data = self
+                    .query_data
+                    .iter()
+                    .find(|query_data| query_data.label == threshold.label)?;
+
+                if query_data.self_time_change > threshold.max_percent {
+                    Some((query_data, threshold))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The largest acceptable growth of the self time of an item, parsed from
+/// `<label>=<percent>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegressionThreshold {
+    pub label: String,
+    pub max_percent: f64,
+}
+
+impl FromStr for RegressionThreshold {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<RegressionThreshold, Self::Err> {
+        let invalid = || -> Self::Err {
+            From::from(format!(
+                "Invalid regression threshold `{}`: expected `<label>=<percent>`",
+                s
+            ))
+        };
+
+        let index = s.rfind('=').ok_or_else(invalid)?;
+        let max_percent = s[index + 1..].parse::<f64>().map_err(|_| invalid())?;
+
+        if index == 0 || !max_percent.is_finite() {
+            return Err(invalid());
+        }
+
+        Ok(RegressionThreshold {
+            label: s[..index].to_string(),
+            max_percent,
+        })
+    }
+}
+
 pub fn calculate_diff(base: AnalysisResults, change: AnalysisResults) -> DiffResults {
     #[inline]
     fn sd(d: Duration) -> SignedDuration {
@@ -63,7 +134,13 @@ pub fn calculate_diff(base: AnalysisResults, change: AnalysisResults) -> DiffRes
         })
         .collect();
 
-    query_data.sort_by(|l, r| r.self_time.duration.cmp(&l.self_time.duration));
+    // Break ties by label, so that the output is deterministic.
+    query_data.sort_by(|l, r| {
+        r.self_time
+            .duration
+            .cmp(&l.self_time.duration)
+            .then_with(|| l.label.cmp(&r.label))
+    });
 
     let base_data = build_artifact_lookup(&base.artifact_sizes);
     let change_data = build_artifact_lookup(&change.artifact_sizes);
@@ -279,8 +356,7 @@ impl fmt::Debug for SignedDuration {
 
 #[cfg(test)]
 mod test {
-    use super::SignedDuration;
-    use std::time::Duration;
+    use super::*;
 
     #[test]
     fn op_subtract() {
@@ -325,4 +401,82 @@ mod test {
 
         assert_eq!(zero_sd, neg_one_sd - neg_one_sd);
     }
+
+    fn results(queries: &[(&str, u64, usize)]) -> AnalysisResults {
+        AnalysisResults {
+            query_data: queries
+                .iter()
+                .map(|&(label, self_nanos, invocation_count)| QueryData {
+                    self_time: Duration::from_nanos(self_nanos),
+                    time: Duration::from_nanos(self_nanos),
+                    invocation_count,
+                    ..QueryData::new(label.to_string())
+                })
+                .collect(),
+            artifact_sizes: Vec::new(),
+            instant_values: Vec::new(),
+            total_time: Duration::from_nanos(queries.iter().map(|q| q.1).sum()),
+        }
+    }
+
+    #[test]
+    fn regressions() {
+        let base = results(&[
+            ("a", 100, 1),
+            ("b", 100, 2),
+            ("c", 100, 3),
+            ("removed", 50, 1),
+        ]);
+        let change = results(&[("a", 104, 1), ("b", 150, 4), ("c", 10, 3), ("added", 20, 1)]);
+
+        let mut diff = calculate_diff(base, change);
+
+        // By default, the largest changes come first.
+        let labels = |diff: &DiffResults| -> Vec<String> {
+            diff.query_data.iter().map(|q| q.label.clone()).collect()
+        };
+        assert_eq!(labels(&diff), vec!["c", "b", "removed", "added", "a"]);
+
+        diff.sort_by_regression();
+        assert_eq!(labels(&diff), vec!["b", "added", "a", "removed", "c"]);
+
+        let b = &diff.query_data[0];
+        assert_eq!(b.self_time, SignedDuration::from_nanos(50));
+        assert_eq!(b.self_time_change, 50.0);
+        assert_eq!(b.invocation_count, 2);
+        assert_eq!(
+            diff.query_data[3].self_time,
+            SignedDuration::from_nanos(-50)
+        );
+        assert_eq!(diff.query_data[3].invocation_count, -1);
+
+        let thresholds: Vec<RegressionThreshold> =
+            ["a=5", "b=10", "c=0", "added=1000", "missing=0"]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+        let regressed: Vec<_> = diff
+            .regressions(&thresholds)
+            .iter()
+            .map(|(query_data, threshold)| (&query_data.label[..], threshold.max_percent))
+            .collect();
+        assert_eq!(regressed, vec![("b", 10.0), ("added", 1000.0)]);
+    }
+
+    #[test]
+    fn parse_regression_threshold() {
+        let threshold = "typeck=2.5".parse::<RegressionThreshold>().unwrap();
+        assert_eq!(threshold.label, "typeck");
+        assert_eq!(threshold.max_percent, 2.5);
+
+        // Only the last `=` separates the label from the percentage.
+        let threshold = "a=b=-1".parse::<RegressionThreshold>().unwrap();
+        assert_eq!(threshold.label, "a=b");
+        assert_eq!(threshold.max_percent, -1.0);
+
+        assert!("typeck".parse::<RegressionThreshold>().is_err());
+        assert!("=5".parse::<RegressionThreshold>().is_err());
+        assert!("typeck=x".parse::<RegressionThreshold>().is_err());
+        assert!("typeck=inf".parse::<RegressionThreshold>().is_err());
+    }
 }
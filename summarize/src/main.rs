@@ -1,19 +1,35 @@
 #[macro_use]
 extern crate prettytable;
 
-use analyzeme::AnalysisResults;
-use analyzeme::ProfilingData;
+use analyzeme::summary_file::SUMMARY_FILE_EXTENSION;
+use analyzeme::{warn_about_profile, CounterDescription, ProfilingData, TimeRange};
+use analyzeme::{AnalysisResults, BucketAssignment, LatencyPercentiles};
+use regex::Regex;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::cell::OnceCell;
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::{path::PathBuf, time::Duration};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use prettytable::{Cell, Row, Table};
 use serde::Serialize;
 
 mod aggregate;
+mod cache_stats;
+mod categories;
 mod diff;
+mod report;
+mod wall_time;
+
+use categories::{BudgetTime, CategoryBudget, CategoryResults};
+use diff::{DiffResults, RegressionThreshold};
+use report::{ArtifactSizeReport, CriticalPathReport, DiffReport, DrillDownReport};
+use report::{HistogramReport, Report};
+use report::{IdleTimeReport, ReportMetadata, ThreadCriticalPath, ThreadIdleTime};
+use report::{TimelineReport, TimelineReportBucket, TopEventReport, TopEventsReport};
 
 #[derive(Parser, Debug)]
 struct AggregateOpt {
@@ -30,19 +46,171 @@ struct DiffOpt {
 
     #[clap(long = "json")]
     json: bool,
+
+    /// How to print the differences. The json format is versioned and its
+    /// field names are stable, see the README
+    #[clap(long = "output-format", value_enum, default_value = "table")]
+    output_format: OutputFormat,
+
+    /// Order the items by their change in self time, largest regression
+    /// first, instead of by the absolute value of the change
+    #[clap(long = "sort-by-regression")]
+    sort_by_regression: bool,
+
+    /// Exit with an error if the self time of an item has grown by more than
+    /// the given percentage, e.g. `typeck=5`. Can be given multiple times
+    #[clap(long = "max-regression")]
+    max_regressions: Vec<RegressionThreshold>,
+
+    /// Show demangled names instead of mangled Rust symbol names, e.g.
+    /// `core::ptr::drop_in_place` instead of `_ZN4core3ptr13drop_in_place..`
+    #[clap(long = "demangle")]
+    demangle: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Table,
+    Json,
 }
 
 #[derive(Parser, Debug)]
 struct SummarizeOpt {
+    /// The profile to summarize, or `-` to read it from stdin
     file_prefix: PathBuf,
 
     /// Writes the analysis to a json file next to <file_prefix> instead of stdout
     #[clap(long = "json")]
     json: bool,
 
+    /// Writes the analysis to a compact binary `.mm_summary` file next to
+    /// <file_prefix> instead of stdout, which `diff` can compare without the
+    /// profile
+    #[clap(long = "summary")]
+    summary: bool,
+
     /// Filter the output to items whose self-time is greater than this value
     #[clap(short = 'p', long = "percent-above", default_value = "0.0")]
     percent_above: f64,
+
+    /// Only include items whose label, or label and category formatted as
+    /// "label (category)", match this regular expression
+    #[clap(long = "filter")]
+    filter: Option<String>,
+
+    /// How to print the results. The json format is stable and can be
+    /// processed by other tools
+    #[clap(long = "output-format", value_enum, default_value = "table")]
+    output_format: OutputFormat,
+
+    /// Also estimate the p50, p90 and p99 percentiles and the maximum of the
+    /// durations of the individual invocations of each item
+    #[clap(long = "percentiles")]
+    percentiles: bool,
+
+    /// Check that the interval events of each thread are properly nested
+    /// before summarizing, and exit with an error if they aren't
+    #[clap(long = "validate")]
+    validate: bool,
+
+    /// Only summarize the events within the given window of time, in seconds
+    /// since the start of the profile, e.g. `120:122.5`. Events partially
+    /// within the window are cut off at its edges
+    #[clap(long = "time-range")]
+    time_range: Option<TimeRange>,
+
+    /// Only list the sizes of the artifacts recorded in the profile, e.g. of
+    /// the object files, largest first and with their total
+    #[clap(long = "artifact-sizes")]
+    artifact_sizes: bool,
+
+    /// What the table shows for each item. `instructions` requires a profile
+    /// recorded with an instruction counter, e.g. `instructions:u`
+    #[clap(long = "count-by", value_enum, default_value = "time")]
+    count_by: CountBy,
+
+    /// What the table lists. `category` sums up the self time of the items
+    /// per top-level category, e.g. `codegen` for `codegen::llvm`
+    #[clap(long = "group-by", value_enum, default_value = "label")]
+    group_by: GroupBy,
+
+    /// With `--group-by category`, also list this many of the items with the
+    /// most self time within each category
+    #[clap(long = "top-labels", default_value = "0")]
+    top_labels: usize,
+
+    /// Also show how often each query has been executed instead of found in
+    /// the cache, and the percentage of cache hits. Only available for
+    /// profiles recorded by rustc
+    #[clap(long = "cache-stats")]
+    cache_stats: bool,
+
+    /// Show demangled names instead of mangled Rust symbol names, e.g.
+    /// `core::ptr::drop_in_place` instead of `_ZN4core3ptr13drop_in_place..`
+    #[clap(long = "demangle")]
+    demangle: bool,
+
+    /// Only show how the durations of the individual invocations of the item
+    /// with this label are distributed, in buckets whose boundaries are
+    /// powers of two
+    #[clap(long = "histogram")]
+    histogram: Option<String>,
+
+    /// Only show which items the time of the item with this label has been
+    /// spent in, summed up over all of its invocations
+    #[clap(long = "drill-down")]
+    drill_down: Option<String>,
+
+    /// Only show how many events start in each window of time of this many
+    /// milliseconds, and their self time, to spot where the profiled process
+    /// stalls
+    #[clap(long = "timeline")]
+    timeline: Option<f64>,
+
+    /// With `--timeline`, distribute the events that span several windows
+    /// over them in proportion to their overlap, instead of counting them in
+    /// the window they start in
+    #[clap(long = "timeline-proportional")]
+    timeline_proportional: bool,
+
+    /// Only show the chain of nested events of each thread whose self times
+    /// add up to the most, from the outermost event to the innermost one
+    #[clap(long = "critical-path")]
+    critical_path: bool,
+
+    /// Only show this many of the individual interval events that have taken
+    /// the longest, longest first, instead of totals per item
+    #[clap(long = "top-n")]
+    top_n: Option<usize>,
+
+    /// Only show how much time each thread has spent without any active
+    /// event, between the start of its first event and the end of its last
+    /// one, e.g. while it has been blocked on something that isn't recorded
+    #[clap(long = "idle")]
+    idle: bool,
+
+    /// After summarizing, exit with an error if the self time of a top-level
+    /// category takes up more than the given percentage of the total time,
+    /// e.g. `typeck:30`. Can be given multiple times
+    #[clap(long = "assert")]
+    budgets: Vec<CategoryBudget>,
+
+    /// Make `--assert` compare the total time of the events of a category
+    /// instead of their self time
+    #[clap(long = "assert-total-time")]
+    assert_total_time: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CountBy {
+    Time,
+    Instructions,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GroupBy {
+    Label,
+    Category,
 }
 
 #[derive(Parser, Debug)]
@@ -59,19 +227,47 @@ enum Opt {
     Summarize(SummarizeOpt),
 }
 
-fn process_results(file: &PathBuf) -> Result<AnalysisResults, Box<dyn Error + Send + Sync>> {
+fn process_results(
+    file: &PathBuf,
+    demangle: bool,
+) -> Result<AnalysisResults, Box<dyn Error + Send + Sync>> {
     if file.ends_with("json") {
         let reader = BufReader::new(File::open(&file)?);
 
         let results: AnalysisResults = serde_json::from_reader(reader)?;
         Ok(results)
+    } else if file.extension() == Some(SUMMARY_FILE_EXTENSION.as_ref()) {
+        AnalysisResults::read_summary(&std::fs::read(file)?)
+            .map_err(|e| From::from(format!("couldn't read `{}`: {}", file.display(), e)))
     } else {
-        let data = ProfilingData::new(&file)?;
+        let mut data = ProfilingData::new(&file)?;
+        if demangle {
+            data.demangle_symbols();
+        }
+
+        warn_about_profile(&data, file);
+
+        warn_if_counter_unavailable(&data, file);
 
         Ok(data.perform_analysis())
     }
 }
 
+fn warn_if_counter_unavailable(data: &ProfilingData, file: &Path) {
+    let count = data
+        .iter()
+        .filter(|event| data.counter_unavailable(event))
+        .count();
+
+    if count > 0 {
+        eprintln!(
+            "Warning: the counter of `{}` couldn't be read for {} interval events. Their durations are unknown and have been counted as zero.",
+            file.display(),
+            count
+        );
+    }
+}
+
 fn write_results_json(
     file: &PathBuf,
     results: impl Serialize,
@@ -95,16 +291,50 @@ fn aggregate(opt: AggregateOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
 }
 
 fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let base = process_results(&opt.base)?;
-    let change = process_results(&opt.change)?;
+    let base = process_results(&opt.base, opt.demangle)?;
+    let change = process_results(&opt.change, opt.demangle)?;
+
+    let mut results = diff::calculate_diff(base, change);
+    if opt.sort_by_regression {
+        results.sort_by_regression();
+    }
 
-    let results = diff::calculate_diff(base, change);
+    let regressions: Vec<String> = results
+        .regressions(&opt.max_regressions)
+        .iter()
+        .map(|(query_data, threshold)| {
+            format!(
+                "Error: the self time of `{}` has changed by {:+.2}%, more than the allowed {:+.2}%",
+                query_data.label, query_data.self_time_change, threshold.max_percent
+            )
+        })
+        .collect();
 
     if opt.json {
         write_results_json(&opt.change, results)?;
-        return Ok(());
+    } else {
+        match opt.output_format {
+            OutputFormat::Table => print_diff_table(results, &opt.exclude),
+            OutputFormat::Json => {
+                let report = DiffReport::new(&results, |label| {
+                    !opt.exclude.iter().any(|e| label.contains(e))
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+        }
+    }
+
+    if !regressions.is_empty() {
+        for regression in regressions {
+            eprintln!("{}", regression);
+        }
+        std::process::exit(1);
     }
 
+    Ok(())
+}
+
+fn print_diff_table(results: DiffResults, exclude: &[String]) {
     let mut table = Table::new();
 
     table.add_row(row!(
@@ -121,7 +351,7 @@ fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
     ));
 
     for query_data in results.query_data {
-        let exclude = opt.exclude.iter().any(|e| query_data.label.contains(e));
+        let exclude = exclude.iter().any(|e| query_data.label.contains(e));
         if exclude {
             continue;
         }
@@ -149,7 +379,7 @@ fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
     table.add_row(row!("Item", "Artifact Size Change",));
 
     for artifact_size in results.artifact_sizes {
-        let exclude = opt.exclude.iter().any(|e| artifact_size.label.contains(e));
+        let exclude = exclude.iter().any(|e| artifact_size.label.contains(e));
         if exclude {
             continue;
         }
@@ -161,14 +391,312 @@ fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
     }
 
     table.printstd();
+}
+
+fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut budget_violations = Vec::new();
+    summarize_profile(opt, &mut budget_violations)?;
+
+    // One line per violation, in a format that is easy to pick out of the
+    // logs of a CI job.
+    if !budget_violations.is_empty() {
+        for violation in budget_violations {
+            eprintln!("{}", violation);
+        }
+        std::process::exit(1);
+    }
 
     Ok(())
 }
 
-fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let data = ProfilingData::new(&opt.file_prefix)?;
+/// The option that selects one of the reports that are printed instead of the
+/// summary table, if any. `--json` only writes the results of the analysis
+/// behind the table, so it can't be combined with these.
+fn report_option(opt: &SummarizeOpt) -> Option<&'static str> {
+    if opt.histogram.is_some() {
+        Some("--histogram")
+    } else if opt.drill_down.is_some() {
+        Some("--drill-down")
+    } else if opt.timeline.is_some() {
+        Some("--timeline")
+    } else if opt.critical_path {
+        Some("--critical-path")
+    } else if opt.top_n.is_some() {
+        Some("--top-n")
+    } else if opt.idle {
+        Some("--idle")
+    } else {
+        None
+    }
+}
+
+/// Prints what `opt` asks for, and collects the messages for the violated
+/// `--assert` budgets in `budget_violations`.
+fn summarize_profile(
+    opt: SummarizeOpt,
+    budget_violations: &mut Vec<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let read_from_stdin = opt.file_prefix == Path::new("-");
+    if read_from_stdin && opt.json {
+        return Err(From::from(
+            "`--json` writes next to the profile and doesn't support reading it from stdin, use `--output-format json` instead",
+        ));
+    }
+    if read_from_stdin && opt.summary {
+        return Err(From::from(
+            "`--summary` writes next to the profile and doesn't support reading it from stdin",
+        ));
+    }
+    if let (true, Some(report_option)) = (opt.json, report_option(&opt)) {
+        let msg = format!(
+            "`{}` doesn't support `--json`, use `--output-format json` instead",
+            report_option
+        );
+        return Err(From::from(msg));
+    }
+
+    let mut data = if read_from_stdin {
+        ProfilingData::from_reader(std::io::stdin().lock())?
+    } else {
+        ProfilingData::new(&opt.file_prefix)?
+    };
+    if opt.demangle {
+        data.demangle_symbols();
+    }
+
+    warn_about_profile(&data, &opt.file_prefix);
+
+    warn_if_counter_unavailable(&data, &opt.file_prefix);
+
+    if let Some(range) = opt.time_range {
+        data = data.slice_time_range(range.start_nanos, range.end_nanos);
+    }
+
+    if opt.validate {
+        let errors = data.validate();
+        if !errors.is_empty() {
+            let start_time = data.metadata().start_time;
+            for error in &errors {
+                eprintln!(
+                    "Error: `{}` on thread {} at {:?} {}",
+                    error.label,
+                    error.thread_id,
+                    error
+                        .timestamp
+                        .duration_since(start_time)
+                        .unwrap_or_default(),
+                    error.kind
+                );
+            }
+            eprintln!(
+                "Found {} problem(s) in `{}`, its summary would be meaningless.",
+                errors.len(),
+                opt.file_prefix.display()
+            );
+            std::process::exit(1);
+        }
+    }
 
-    let mut results = data.perform_analysis();
+    // The self times of the events are only computed if one of the analyses
+    // below needs them, and then only once.
+    let all_self_times = OnceCell::new();
+    let self_times = || &all_self_times.get_or_init(|| data.self_times())[..];
+
+    // Computing the shares of the categories takes a pass over all events, so
+    // it is skipped unless there are budgets to check.
+    if !opt.budgets.is_empty() {
+        let budget_time = if opt.assert_total_time {
+            BudgetTime::TotalTime
+        } else {
+            BudgetTime::SelfTime
+        };
+        budget_violations.extend(
+            categories::budget_violations(&data, self_times(), &opt.budgets, budget_time)
+                .into_iter()
+                .map(|violation| {
+                    format!(
+                        "budget-exceeded: category={} time={} percent={:.2} max-percent={:.2}",
+                        violation.category,
+                        match budget_time {
+                            BudgetTime::SelfTime => "self",
+                            BudgetTime::TotalTime => "total",
+                        },
+                        violation.percent,
+                        violation.max_percent
+                    )
+                }),
+        );
+    }
+
+    // The labels have to be collected before `perform_analysis` consumes the
+    // profiling data.
+    let filter_labels = match opt.filter {
+        Some(ref filter) => Some(matching_labels(&data, &Regex::new(filter)?)),
+        None => None,
+    };
+
+    let report_metadata = ReportMetadata::new(data.metadata());
+    let counter = data.metadata().counter.clone();
+
+    // In profiles recorded with an instruction counter, the durations of the
+    // events are their counter values (see `CounterDescription::counter_value`),
+    // so the analysis below sums up instructions.
+    let counts_instructions = counter
+        .as_ref()
+        .is_some_and(CounterDescription::counts_instructions);
+    let count_instructions = opt.count_by == CountBy::Instructions;
+    if count_instructions && !counts_instructions {
+        let msg = format!(
+            "Cannot count instructions: `{}` has been recorded with the `{}` counter instead of an instruction counter like `instructions:u`.",
+            opt.file_prefix.display(),
+            counter
+                .as_ref()
+                .map(|counter| &counter.name[..])
+                .unwrap_or("wall-time")
+        );
+        return Err(From::from(msg));
+    }
+
+    let format_time = |time: Duration| {
+        let instructions = counter
+            .as_ref()
+            .and_then(|counter| counter.counter_value(time));
+        match instructions {
+            Some(instructions) if count_instructions => instructions.to_string(),
+            _ => format!("{:.2?}", time),
+        }
+    };
+
+    if opt.artifact_sizes {
+        let report = ArtifactSizeReport::new(report_metadata, &data, |kind| {
+            filter_labels
+                .as_ref()
+                .map(|labels| labels.contains(kind))
+                .unwrap_or(true)
+        });
+        return print_artifact_sizes(&report, opt.output_format);
+    }
+
+    if let Some(ref label) = opt.histogram {
+        let report = HistogramReport::new(report_metadata, &data, label);
+        if report.invocation_count == 0 {
+            let msg = format!(
+                "`{}` doesn't contain any interval events labeled `{}`.",
+                opt.file_prefix.display(),
+                label
+            );
+            return Err(From::from(msg));
+        }
+        return print_histogram(&report, opt.output_format, format_time);
+    }
+
+    if let Some(ref label) = opt.drill_down {
+        let report = DrillDownReport::new(report_metadata, &data, label);
+        if report.invocation_count == 0 {
+            let msg = format!(
+                "`{}` doesn't contain any interval events labeled `{}`.",
+                opt.file_prefix.display(),
+                label
+            );
+            return Err(From::from(msg));
+        }
+        return print_drill_down(&report, opt.output_format, format_time);
+    }
+
+    if let Some(bucket_ms) = opt.timeline {
+        let bucket_nanos = (bucket_ms * 1e6).round();
+        if bucket_nanos.is_nan() || bucket_nanos < 1.0 {
+            return Err(From::from(
+                "`--timeline` expects a window of at least one nanosecond",
+            ));
+        }
+
+        let assignment = if opt.timeline_proportional {
+            BucketAssignment::Proportional
+        } else {
+            BucketAssignment::Start
+        };
+        let report = TimelineReport::new(
+            report_metadata,
+            &data,
+            self_times(),
+            bucket_nanos as u64,
+            assignment,
+        )?;
+        return print_timeline(&report, opt.output_format, format_time);
+    }
+
+    if opt.critical_path {
+        let report = CriticalPathReport::new(report_metadata, &data);
+        return print_critical_path(&report, opt.output_format, format_time);
+    }
+
+    if let Some(n) = opt.top_n {
+        let report = TopEventsReport::new(report_metadata, &data, n);
+        return print_top_events(&report, opt.output_format, format_time);
+    }
+
+    if opt.idle {
+        let report = IdleTimeReport::new(report_metadata, &data);
+        return print_idle_time(&report, opt.output_format, format_time);
+    }
+
+    if opt.group_by == GroupBy::Category {
+        if opt.json || opt.output_format == OutputFormat::Json {
+            return Err(From::from(
+                "`--group-by category` only supports the table output format",
+            ));
+        }
+
+        let results =
+            categories::group_by_category(&data, self_times(), |label| match filter_labels {
+                Some(ref labels) => labels.contains(label),
+                None => true,
+            });
+        print_category_table(&results, opt.top_labels, count_instructions, format_time);
+        return Ok(());
+    }
+
+    let cache_stats = if opt.cache_stats {
+        let cache_stats = cache_stats::cache_stats(&data);
+        if cache_stats.is_empty() {
+            eprintln!(
+                "Note: this profile doesn't contain any query events, ignoring `--cache-stats`."
+            );
+        }
+        cache_stats
+    } else {
+        FxHashMap::default()
+    };
+    let show_cache_stats = !cache_stats.is_empty();
+
+    // Profiles recorded with `ProfilerOptions::record_wall_time` also have
+    // the wall-clock durations of the events, from which the instructions
+    // per nanosecond can be computed if the counter counts instructions.
+    let show_wall_time = data.has_wall_times();
+    let show_instructions_per_nano = show_wall_time && counts_instructions;
+
+    // The self times of queries that have run concurrently on different
+    // threads add up to more than the profile has taken, so the header, and
+    // the JSON report, also show the wall time and how busy the threads have
+    // been in it. `--json` and `--summary` write the results only.
+    let activity = if count_instructions || opt.json || opt.summary {
+        None
+    } else {
+        Some(data.activity())
+    };
+
+    let mut results = if opt.percentiles {
+        data.perform_analysis_with_percentiles()
+    } else {
+        data.perform_analysis()
+    };
+
+    if let Some(labels) = filter_labels {
+        results
+            .query_data
+            .retain(|query_data| labels.contains(&query_data.label));
+    }
 
     //just output the results into a json file
     if opt.json {
@@ -176,6 +704,15 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
         return Ok(());
     }
 
+    if opt.summary {
+        let path = opt.file_prefix.with_extension(SUMMARY_FILE_EXTENSION);
+        let mut file = BufWriter::new(File::create(&path)?);
+        results.write_summary(&mut file)?;
+        file.flush()?;
+        eprintln!("Wrote the summary to `{}`", path.display());
+        return Ok(());
+    }
+
     let percent_above = opt.percent_above;
     //cannot be greater than 100% or less than 0%
     if percent_above > 100.0 {
@@ -186,6 +723,13 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
         std::process::exit(1);
     }
 
+    if opt.output_format == OutputFormat::Json {
+        let report = Report::new(report_metadata, &results, activity);
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &report)?;
+        println!();
+        return Ok(());
+    }
+
     //order the results by descending self time
     results
         .query_data
@@ -221,13 +765,27 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     // Don't show the cache hits, blocked time or incremental load time unless there are values
     // to display.
+    let (self_time_column, time_column) = if count_instructions {
+        ("Self instructions", "Instructions")
+    } else {
+        ("Self time", "Time")
+    };
     let columns = &[
         ("Item", true),
-        ("Self time", true),
+        (self_time_column, true),
         ("% of total time", true),
-        ("Time", true),
+        ("% of matching time", opt.filter.is_some()),
+        (time_column, true),
+        ("Wall time", show_wall_time),
+        ("Instructions per ns", show_instructions_per_nano),
+        ("p50", opt.percentiles),
+        ("p90", opt.percentiles),
+        ("p99", opt.percentiles),
+        ("Max", opt.percentiles),
         ("Item count", true),
-        ("Cache hits", has_cache_hits),
+        ("Cache hits", has_cache_hits || show_cache_stats),
+        ("Cache misses", show_cache_stats),
+        ("% cache hits", show_cache_stats),
         ("Blocked time", has_blocked_time),
         ("Incremental load time", has_incremental_load_time),
         (
@@ -246,39 +804,95 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     table.add_row(Row::new(filter_cells(columns)));
 
-    let total_time = results.total_time.as_nanos() as f64;
+    if let Some(activity) = activity {
+        println!("Wall time: {}", format_time(activity.wall_time));
+        match activity.parallelism() {
+            Some(parallelism) => println!(
+                "Total active cpu time: {} (parallelism {:.2})",
+                format_time(activity.active_time),
+                parallelism
+            ),
+            None => println!(
+                "Total active cpu time: {}",
+                format_time(activity.active_time)
+            ),
+        }
+    }
+
+    let total_time = results.total_time;
     let mut percent_total_time: f64 = 0.0;
 
+    let filtered_time: Duration = results.query_data.iter().map(|q| q.self_time).sum();
+
     for query_data in results.query_data {
-        let curr_percent = (query_data.self_time.as_nanos() as f64) / total_time * 100.0;
+        let curr_percent = percent_of(query_data.self_time, total_time);
+        let curr_percent_filtered = percent_of(query_data.self_time, filtered_time);
         if curr_percent < percent_above {
             break;
         } //no need to run entire loop if filtering by % time
 
         percent_total_time = percent_total_time + curr_percent;
 
+        // Items that only have cache hits have no durations.
+        let latency = |f: fn(&LatencyPercentiles) -> Duration| match query_data.latency {
+            Some(ref latency) => format_time(f(latency)),
+            None => "-".to_string(),
+        };
+
+        // Only queries have cache statistics.
+        let (cache_misses, percent_cache_hits) = match cache_stats.get(&query_data.label) {
+            Some(stats) => (
+                stats.misses.to_string(),
+                format!("{:.3}", stats.hit_percent()),
+            ),
+            None => ("-".to_string(), "-".to_string()),
+        };
+
+        let wall_time = query_data.wall_time;
+        let instructions_per_nano = wall_time
+            .and_then(|wall_time| wall_time::instructions_per_nano(query_data.time, wall_time));
+
         // Don't show the cache hits, blocked time or incremental load time columns unless there is
         // data to show.
         table.add_row(Row::new(filter_cells(&[
             (&query_data.label, true),
-            (&format!("{:.2?}", query_data.self_time), true),
+            (&format_time(query_data.self_time), true),
             (&format!("{:.3}", curr_percent), true),
-            (&format!("{:.2?}", query_data.time), true),
-            (&format!("{}", query_data.invocation_count), true),
             (
-                &format!("{}", query_data.number_of_cache_hits),
-                has_cache_hits,
+                &format!("{:.3}", curr_percent_filtered),
+                opt.filter.is_some(),
+            ),
+            (&format_time(query_data.time), true),
+            (
+                &wall_time
+                    .map(|wall_time| format!("{:.2?}", wall_time))
+                    .unwrap_or_else(|| "-".to_string()),
+                show_wall_time,
             ),
             (
-                &format!("{:.2?}", query_data.blocked_time),
-                has_blocked_time,
+                &instructions_per_nano
+                    .map(|ipn| format!("{:.3}", ipn))
+                    .unwrap_or_else(|| "-".to_string()),
+                show_instructions_per_nano,
             ),
+            (&latency(|l| l.p50), opt.percentiles),
+            (&latency(|l| l.p90), opt.percentiles),
+            (&latency(|l| l.p99), opt.percentiles),
+            (&latency(|l| l.max), opt.percentiles),
+            (&format!("{}", query_data.invocation_count), true),
             (
-                &format!("{:.2?}", query_data.incremental_load_time),
+                &format!("{}", query_data.number_of_cache_hits),
+                has_cache_hits || show_cache_stats,
+            ),
+            (&cache_misses, show_cache_stats),
+            (&percent_cache_hits, show_cache_stats),
+            (&format_time(query_data.blocked_time), has_blocked_time),
+            (
+                &format_time(query_data.incremental_load_time),
                 has_incremental_load_time,
             ),
             (
-                &format!("{:.2?}", query_data.incremental_hashing_time),
+                &format_time(query_data.incremental_hashing_time),
                 has_incremental_hashing_time,
             ),
         ])));
@@ -286,7 +900,27 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     table.printstd();
 
-    println!("Total cpu time: {:?}", results.total_time);
+    if count_instructions {
+        println!("Total instructions: {}", format_time(results.total_time));
+    } else {
+        if let Some(counter) = counter.as_ref().filter(|counter| !counter.measures_time()) {
+            println!(
+                "Note: this profile has been recorded with the `{}` counter, all times are counts of {} shown as nanoseconds.",
+                counter.name,
+                counter.base_unit()
+            );
+        }
+
+        println!("Total cpu time: {:?}", results.total_time);
+    }
+
+    if opt.filter.is_some() {
+        println!(
+            "Items matching the filter account for {} ({:.3}% of total time).",
+            format_time(filtered_time),
+            percent_of(filtered_time, total_time)
+        );
+    }
 
     if percent_above != 0.0 {
         println!(
@@ -308,9 +942,385 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     table.printstd();
 
+    // Instant events with values mark points in time, so they are listed on
+    // their own instead of being aggregated with the intervals above.
+    if !results.instant_values.is_empty() {
+        let mut table = Table::new();
+
+        table.add_row(row!("Marker", "Count", "Total value", "Max value"));
+
+        for instant_values in results.instant_values {
+            table.add_row(row![
+                instant_values.label,
+                instant_values.count,
+                instant_values.total,
+                instant_values.max,
+            ]);
+        }
+
+        table.printstd();
+    }
+
+    Ok(())
+}
+
+fn print_category_table(
+    results: &CategoryResults,
+    top_labels: usize,
+    count_instructions: bool,
+    format_time: impl Fn(Duration) -> String,
+) {
+    let mut table = Table::new();
+
+    let self_time_column = if count_instructions {
+        "Self instructions"
+    } else {
+        "Self time"
+    };
+    table.add_row(row!(
+        "Category",
+        self_time_column,
+        "% of total time",
+        "Item count"
+    ));
+
+    let total_time = results.total_time.as_nanos() as f64;
+    let percent = |time: Duration| format!("{:.3}", time.as_nanos() as f64 / total_time * 100.0);
+
+    for category in &results.categories {
+        table.add_row(row![
+            category.category,
+            format_time(category.self_time),
+            percent(category.self_time),
+            category.invocation_count,
+        ]);
+
+        for (label, self_time) in category.labels.iter().take(top_labels) {
+            table.add_row(row![
+                format!("  {}", label),
+                format_time(*self_time),
+                percent(*self_time),
+                "",
+            ]);
+        }
+    }
+
+    table.printstd();
+
+    if count_instructions {
+        println!("Total instructions: {}", format_time(results.total_time));
+    } else {
+        println!("Total cpu time: {:?}", results.total_time);
+    }
+}
+
+fn print_artifact_sizes(
+    report: &ArtifactSizeReport,
+    output_format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.add_row(row!("Artifact", "Kind", "Size"));
+
+    for artifact in &report.artifacts {
+        table.add_row(row![
+            artifact.name.as_deref().unwrap_or("-"),
+            artifact.kind,
+            format!("{} bytes", artifact.bytes),
+        ]);
+    }
+
+    table.add_row(row!["Total", "", format!("{} bytes", report.total_bytes)]);
+
+    table.printstd();
+
+    Ok(())
+}
+
+fn print_histogram(
+    report: &HistogramReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    // The longest bar, for the bucket with the most invocations.
+    const MAX_BAR_WIDTH: usize = 40;
+    let max_count = report.buckets.iter().map(|b| b.count).max().unwrap_or(0);
+
+    let mut table = Table::new();
+
+    table.add_row(row!("From", "To", "Count", "% of invocations", ""));
+
+    for bucket in &report.buckets {
+        // Every bucket that isn't empty gets at least a sliver of a bar.
+        let bar_width = (bucket.count * MAX_BAR_WIDTH).div_ceil(max_count);
+
+        table.add_row(row![
+            format_time(Duration::from_nanos(bucket.min_nanos)),
+            format_time(Duration::from_nanos(bucket.max_nanos)),
+            bucket.count,
+            format!(
+                "{:.2}",
+                bucket.count as f64 / report.invocation_count as f64 * 100.0
+            ),
+            "#".repeat(bar_width),
+        ]);
+    }
+
+    table.printstd();
+
+    println!(
+        "Invocations of `{}`: {}",
+        report.label, report.invocation_count
+    );
+
+    Ok(())
+}
+
+fn print_timeline(
+    report: &TimelineReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.add_row(row!("From", "To", "Events", "Self time"));
+
+    for &TimelineReportBucket {
+        start_nanos,
+        events,
+        self_time_nanos,
+    } in &report.buckets
+    {
+        table.add_row(row![
+            format_time(Duration::from_nanos(start_nanos)),
+            format_time(Duration::from_nanos(start_nanos + report.bucket_nanos)),
+            if report.proportional {
+                format!("{:.2}", events)
+            } else {
+                events.to_string()
+            },
+            format_time(Duration::from_nanos(self_time_nanos)),
+        ]);
+    }
+
+    table.printstd();
+
+    Ok(())
+}
+
+fn print_drill_down(
+    report: &DrillDownReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    // The instances may all have taken no time, e.g. with a clock that is
+    // too coarse for them.
+    let percent = |nanos: u64| match report.duration_nanos {
+        0 => format!("{:.3}", 0.0),
+        total_nanos => format!("{:.3}", nanos as f64 / total_nanos as f64 * 100.0),
+    };
+
+    let mut table = Table::new();
+
+    table.add_row(row!("Item", "Time", "% of total", "Item count"));
+
+    table.add_row(row![
+        report.label,
+        format_time(Duration::from_nanos(report.duration_nanos)),
+        percent(report.duration_nanos),
+        report.invocation_count,
+    ]);
+
+    // The children are indented below their parent.
+    for child in &report.children {
+        table.add_row(row![
+            format!("  {}", child.label),
+            format_time(Duration::from_nanos(child.duration_nanos)),
+            percent(child.duration_nanos),
+            child.invocation_count,
+        ]);
+    }
+
+    table.add_row(row![
+        "  (self time)",
+        format_time(Duration::from_nanos(report.self_time_nanos)),
+        percent(report.self_time_nanos),
+        "",
+    ]);
+
+    table.printstd();
+
+    Ok(())
+}
+
+fn print_critical_path(
+    report: &CriticalPathReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.add_row(row!("Thread", "Item", "Time", "Self time"));
+
+    for ThreadCriticalPath {
+        thread_id, frames, ..
+    } in &report.threads
+    {
+        // The frames are indented by their depth.
+        for (depth, frame) in frames.iter().enumerate() {
+            table.add_row(row![
+                if depth == 0 {
+                    thread_id.to_string()
+                } else {
+                    String::new()
+                },
+                format!("{}{}", "  ".repeat(depth), frame.label),
+                format_time(Duration::from_nanos(frame.duration_nanos)),
+                format_time(Duration::from_nanos(frame.self_time_nanos)),
+            ]);
+        }
+    }
+
+    table.printstd();
+
     Ok(())
 }
 
+fn print_top_events(
+    report: &TopEventsReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.add_row(row!("Item", "Thread", "Start", "Time"));
+
+    for TopEventReport {
+        label,
+        category: _,
+        thread_id,
+        start_nanos,
+        duration_nanos,
+    } in &report.events
+    {
+        table.add_row(row![
+            label,
+            thread_id,
+            format!("{:.2?}", Duration::from_nanos(*start_nanos)),
+            format_time(Duration::from_nanos(*duration_nanos)),
+        ]);
+    }
+
+    table.printstd();
+
+    Ok(())
+}
+
+fn print_idle_time(
+    report: &IdleTimeReport,
+    output_format: OutputFormat,
+    format_time: impl Fn(Duration) -> String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), report)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.add_row(row!("Thread", "Idle time", "Gaps", "Longest gap"));
+
+    for ThreadIdleTime {
+        thread_id,
+        idle_nanos,
+        gaps,
+        longest_gap_nanos,
+    } in &report.threads
+    {
+        table.add_row(row![
+            thread_id,
+            format_time(Duration::from_nanos(*idle_nanos)),
+            gaps,
+            format_time(Duration::from_nanos(*longest_gap_nanos)),
+        ]);
+    }
+
+    table.printstd();
+
+    Ok(())
+}
+
+/// How large a percentage `part` is of `whole`, `0` if `whole` is empty, e.g.
+/// when no item matches the filter.
+fn percent_of(part: Duration, whole: Duration) -> f64 {
+    if whole.is_zero() {
+        0.0
+    } else {
+        part.as_nanos() as f64 / whole.as_nanos() as f64 * 100.0
+    }
+}
+
+/// Returns the labels of all events whose label, or label and category
+/// formatted as "label (category)", match `filter`.
+fn matching_labels(data: &ProfilingData, filter: &Regex) -> FxHashSet<String> {
+    let mut labels = FxHashSet::default();
+
+    for event in data.iter_full() {
+        if labels.contains(&event.label[..]) {
+            continue;
+        }
+
+        let is_match = filter.is_match(&event.label)
+            || match event.category {
+                Some(ref category) => filter.is_match(&format!("{} ({})", event.label, category)),
+                None => false,
+            };
+
+        if is_match {
+            labels.insert(event.label.into_owned());
+        }
+    }
+
+    labels
+}
+
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let opt = Opt::from_args();
 
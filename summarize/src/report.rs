@@ -0,0 +1,1048 @@
+//! The machine-readable output of `summarize summarize --output-format json`
+//! and `summarize diff --output-format json`.
+//!
+//! The field names of these types are part of the output format and must not
+//! change. New fields may be added; if the meaning of an existing field has to
+//! change, bump `REPORT_FORMAT_VERSION` instead.
+
+use crate::diff::DiffResults;
+use analyzeme::{
+    is_artifact_size, Activity, AnalysisResults, BucketAssignment, EventSelfTime,
+    LatencyPercentiles, Metadata, ProfilingData,
+};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::error::Error;
+use std::time::UNIX_EPOCH;
+
+pub const REPORT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    /// The total time of the profiled process, in nanoseconds.
+    pub total_time_nanos: u64,
+    /// The time from the start of the first interval event to the end of the
+    /// last one, and the sum of the time in which each thread has had an
+    /// active one, see `ProfilingData::activity`. Missing with
+    /// `--count-by instructions`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wall_time_nanos: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_time_nanos: Option<u64>,
+    /// Ordered by descending self time.
+    pub queries: Vec<QueryReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReportMetadata {
+    /// The start of the profiled process, in nanoseconds since the Unix epoch.
+    pub start_time_nanos: u64,
+    pub process_id: u32,
+    pub cmd: String,
+    /// The name of the counter used for all times, e.g. `wall-time` or
+    /// `instructions:u`. Missing for profiles of older versions of measureme.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<String>,
+}
+
+impl ReportMetadata {
+    pub fn new(metadata: &Metadata) -> ReportMetadata {
+        ReportMetadata {
+            start_time_nanos: metadata
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            process_id: metadata.process_id,
+            cmd: metadata.cmd.clone(),
+            counter: metadata
+                .counter
+                .as_ref()
+                .map(|counter| counter.name.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryReport {
+    pub label: String,
+    pub self_time_nanos: u64,
+    pub time_nanos: u64,
+    pub invocation_count: usize,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// Only present with `--percentiles`, and only for items that have been
+    /// executed at least once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyReport>,
+}
+
+/// Percentiles of the durations of the individual invocations of an item.
+#[derive(Serialize, Debug)]
+pub struct LatencyReport {
+    pub p50_nanos: u64,
+    pub p90_nanos: u64,
+    pub p99_nanos: u64,
+    pub max_nanos: u64,
+}
+
+impl LatencyReport {
+    fn new(latency: &LatencyPercentiles) -> LatencyReport {
+        LatencyReport {
+            p50_nanos: latency.p50.as_nanos() as u64,
+            p90_nanos: latency.p90.as_nanos() as u64,
+            p99_nanos: latency.p99.as_nanos() as u64,
+            max_nanos: latency.max.as_nanos() as u64,
+        }
+    }
+}
+
+impl Report {
+    pub fn new(
+        metadata: ReportMetadata,
+        results: &AnalysisResults,
+        activity: Option<Activity>,
+    ) -> Report {
+        let mut queries: Vec<_> = results
+            .query_data
+            .iter()
+            .map(|query_data| QueryReport {
+                label: query_data.label.clone(),
+                self_time_nanos: query_data.self_time.as_nanos() as u64,
+                time_nanos: query_data.time.as_nanos() as u64,
+                invocation_count: query_data.invocation_count,
+                cache_hits: query_data.number_of_cache_hits,
+                cache_misses: query_data.number_of_cache_misses,
+                latency: query_data.latency.as_ref().map(LatencyReport::new),
+            })
+            .collect();
+
+        // Break ties by label, so that the output is deterministic.
+        queries.sort_by(|l, r| {
+            r.self_time_nanos
+                .cmp(&l.self_time_nanos)
+                .then_with(|| l.label.cmp(&r.label))
+        });
+
+        Report {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            total_time_nanos: results.total_time.as_nanos() as u64,
+            wall_time_nanos: activity.map(|activity| activity.wall_time.as_nanos() as u64),
+            active_time_nanos: activity.map(|activity| activity.active_time.as_nanos() as u64),
+            queries,
+        }
+    }
+}
+
+/// The output of `summarize summarize --artifact-sizes --output-format json`.
+#[derive(Serialize, Debug)]
+pub struct ArtifactSizeReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    pub total_bytes: u64,
+    /// Ordered by descending size.
+    pub artifacts: Vec<ArtifactReport>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ArtifactReport {
+    /// The label of the artifact size events, e.g. `object_file`.
+    pub kind: String,
+    /// The first argument of the artifact size events, e.g. the file name.
+    /// Missing if they don't have any arguments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub bytes: u64,
+}
+
+impl ArtifactSizeReport {
+    /// Collects the sizes of all artifacts of `data` (see `is_artifact_size`)
+    /// whose kind is accepted by `include`. Sizes recorded more than once
+    /// for the same artifact are added up.
+    pub fn new(
+        metadata: ReportMetadata,
+        data: &ProfilingData,
+        include: impl Fn(&str) -> bool,
+    ) -> ArtifactSizeReport {
+        let mut sizes = FxHashMap::<(String, Option<String>), u64>::default();
+
+        for event in data.iter_full() {
+            if !is_artifact_size(&event) || !include(&event.label) {
+                continue;
+            }
+
+            let name = event.additional_data.first().map(|name| name.to_string());
+            *sizes.entry((event.label.into_owned(), name)).or_default() +=
+                event.payload.integer().unwrap();
+        }
+
+        let mut artifacts: Vec<_> = sizes
+            .into_iter()
+            .map(|((kind, name), bytes)| ArtifactReport { kind, name, bytes })
+            .collect();
+
+        // Break ties by kind and name, so that the output is deterministic.
+        artifacts.sort_by(|l, r| {
+            r.bytes
+                .cmp(&l.bytes)
+                .then_with(|| (&l.kind, &l.name).cmp(&(&r.kind, &r.name)))
+        });
+
+        ArtifactSizeReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            total_bytes: artifacts.iter().map(|artifact| artifact.bytes).sum(),
+            artifacts,
+        }
+    }
+}
+
+/// The output of `summarize summarize --histogram <label> --output-format
+/// json`.
+#[derive(Serialize, Debug)]
+pub struct HistogramReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    pub label: String,
+    pub invocation_count: usize,
+    /// Ordered by duration, from the first to the last bucket that isn't
+    /// empty, including the empty buckets in between.
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// The invocations whose duration is at least `min_nanos` and less than
+/// `max_nanos`. The first bucket holds the invocations of zero nanoseconds,
+/// each of the others is twice as wide as the one before it.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct HistogramBucket {
+    pub min_nanos: u64,
+    pub max_nanos: u64,
+    pub count: usize,
+}
+
+impl HistogramReport {
+    /// Sorts the durations of all interval events labeled `label` into
+    /// buckets whose boundaries are powers of two, which is enough to tell
+    /// apart e.g. the fast and the slow paths of a query.
+    pub fn new(metadata: ReportMetadata, data: &ProfilingData, label: &str) -> HistogramReport {
+        let mut counts = Vec::<usize>::new();
+        let mut invocation_count = 0;
+
+        for event in data.iter() {
+            let duration = match event.duration() {
+                Some(duration) => duration.as_nanos() as u64,
+                None => continue,
+            };
+            if &data.label_bytes(&event)[..] != label.as_bytes() {
+                continue;
+            }
+
+            // Bucket `n` holds the durations with `n` significant bits.
+            let bucket = (u64::BITS - duration.leading_zeros()) as usize;
+            if counts.len() <= bucket {
+                counts.resize(bucket + 1, 0);
+            }
+            counts[bucket] += 1;
+            invocation_count += 1;
+        }
+
+        let first = counts.iter().position(|&count| count > 0).unwrap_or(0);
+        let buckets = counts
+            .iter()
+            .enumerate()
+            .skip(first)
+            .map(|(bucket, &count)| HistogramBucket {
+                min_nanos: if bucket == 0 { 0 } else { 1 << (bucket - 1) },
+                max_nanos: 1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX),
+                count,
+            })
+            .collect();
+
+        HistogramReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            label: label.to_string(),
+            invocation_count,
+            buckets,
+        }
+    }
+}
+
+/// The output of `summarize summarize --drill-down <label> --output-format
+/// json`, see `ProfilingData::children_breakdown`.
+#[derive(Serialize, Debug)]
+pub struct DrillDownReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    pub label: String,
+    pub invocation_count: usize,
+    pub duration_nanos: u64,
+    pub self_time_nanos: u64,
+    /// Ordered by descending duration, and by label for equal durations.
+    pub children: Vec<DrillDownChild>,
+}
+
+/// The events with a label that are directly nested within the invocations
+/// of the label of the report.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DrillDownChild {
+    pub label: String,
+    pub invocation_count: usize,
+    pub duration_nanos: u64,
+}
+
+impl DrillDownReport {
+    pub fn new(metadata: ReportMetadata, data: &ProfilingData, label: &str) -> DrillDownReport {
+        let breakdown = data.children_breakdown(label);
+
+        DrillDownReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            label: label.to_string(),
+            invocation_count: breakdown.invocation_count,
+            duration_nanos: breakdown.duration.as_nanos() as u64,
+            self_time_nanos: breakdown.self_duration.as_nanos() as u64,
+            children: breakdown
+                .children
+                .into_iter()
+                .map(|child| DrillDownChild {
+                    label: child.label,
+                    invocation_count: child.invocation_count,
+                    duration_nanos: child.duration.as_nanos() as u64,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The output of `summarize summarize --timeline <bucket_ms> --output-format
+/// json`.
+#[derive(Serialize, Debug)]
+pub struct TimelineReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    pub bucket_nanos: u64,
+    /// Whether events spanning several buckets are distributed over them.
+    pub proportional: bool,
+    /// Ordered by time, from the start of the profile to the last bucket that
+    /// isn't empty, including the empty buckets in between.
+    pub buckets: Vec<TimelineReportBucket>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TimelineReportBucket {
+    /// In nanoseconds since the start of the profile.
+    pub start_nanos: u64,
+    /// Only fractional if `proportional` is set.
+    pub events: f64,
+    pub self_time_nanos: u64,
+}
+
+impl TimelineReport {
+    pub fn new(
+        metadata: ReportMetadata,
+        data: &ProfilingData,
+        self_times: &[EventSelfTime],
+        bucket_nanos: u64,
+        assignment: BucketAssignment,
+    ) -> Result<TimelineReport, Box<dyn Error + Send + Sync>> {
+        Ok(TimelineReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            bucket_nanos,
+            proportional: assignment == BucketAssignment::Proportional,
+            buckets: data
+                .timeline(bucket_nanos, assignment, Some(self_times))?
+                .into_iter()
+                .map(|bucket| TimelineReportBucket {
+                    start_nanos: bucket.start_nanos,
+                    events: bucket.events,
+                    self_time_nanos: bucket.self_time.unwrap().as_nanos() as u64,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The output of `summarize summarize --critical-path --output-format json`.
+#[derive(Serialize, Debug)]
+pub struct CriticalPathReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    /// Ordered by thread id, for every thread with interval events.
+    pub threads: Vec<ThreadCriticalPath>,
+}
+
+/// The chain of nested events of a thread with the largest sum of self
+/// times, see `CallTreeNode::critical_path`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ThreadCriticalPath {
+    pub thread_id: u32,
+    /// The sum of the self times of the frames.
+    pub total_nanos: u64,
+    /// From the outermost event to the innermost one.
+    pub frames: Vec<CriticalPathFrame>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CriticalPathFrame {
+    /// The position of the event in the profile, see
+    /// `LightweightEvent::index`.
+    pub event_index: usize,
+    pub label: String,
+    pub category: Option<String>,
+    pub duration_nanos: u64,
+    pub self_time_nanos: u64,
+}
+
+impl CriticalPathReport {
+    pub fn new(metadata: ReportMetadata, data: &ProfilingData) -> CriticalPathReport {
+        let threads = data
+            .call_trees()
+            .into_iter()
+            .map(|(thread_id, tree)| {
+                let frames: Vec<_> = tree
+                    .critical_path()
+                    .into_iter()
+                    .map(|node| CriticalPathFrame {
+                        // The root of the tree isn't part of the path.
+                        event_index: node.event_index.unwrap(),
+                        label: node.label.clone(),
+                        category: node.category.clone(),
+                        duration_nanos: node.duration.as_nanos() as u64,
+                        self_time_nanos: node.self_duration.as_nanos() as u64,
+                    })
+                    .collect();
+
+                ThreadCriticalPath {
+                    thread_id,
+                    total_nanos: frames.iter().fold(0, |total: u64, frame| {
+                        total.saturating_add(frame.self_time_nanos)
+                    }),
+                    frames,
+                }
+            })
+            .collect();
+
+        CriticalPathReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            threads,
+        }
+    }
+}
+
+/// The output of `summarize summarize --top-n <n> --output-format json`.
+#[derive(Serialize, Debug)]
+pub struct TopEventsReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    /// Longest first, see `ProfilingData::top_events_by_duration`.
+    pub events: Vec<TopEventReport>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TopEventReport {
+    pub label: String,
+    pub category: Option<String>,
+    pub thread_id: u32,
+    /// In nanoseconds since the start of the profile.
+    pub start_nanos: u64,
+    pub duration_nanos: u64,
+}
+
+impl TopEventsReport {
+    pub fn new(metadata: ReportMetadata, data: &ProfilingData, n: usize) -> TopEventsReport {
+        let start_time = data.metadata().start_time;
+        let events = data
+            .top_events_by_duration(n)
+            .into_iter()
+            .map(|event| TopEventReport {
+                start_nanos: event
+                    .payload
+                    .timestamp()
+                    .and_then(|timestamp| timestamp.start().duration_since(start_time).ok())
+                    .unwrap_or_default()
+                    .as_nanos() as u64,
+                duration_nanos: event.duration().unwrap_or_default().as_nanos() as u64,
+                label: event.label.into_owned(),
+                category: event.category.map(|category| category.into_owned()),
+                thread_id: event.thread_id,
+            })
+            .collect();
+
+        TopEventsReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            events,
+        }
+    }
+}
+
+/// The output of `summarize summarize --idle --output-format json`.
+#[derive(Serialize, Debug)]
+pub struct IdleTimeReport {
+    pub format_version: u32,
+    pub metadata: ReportMetadata,
+    /// Ordered by thread id, for every thread with interval events.
+    pub threads: Vec<ThreadIdleTime>,
+}
+
+/// The time in which none of the interval events of a thread has been
+/// active, see `ProfilingData::idle_intervals_by_thread`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ThreadIdleTime {
+    pub thread_id: u32,
+    /// The sum of the durations of the gaps.
+    pub idle_nanos: u64,
+    pub gaps: usize,
+    pub longest_gap_nanos: u64,
+}
+
+impl IdleTimeReport {
+    pub fn new(metadata: ReportMetadata, data: &ProfilingData) -> IdleTimeReport {
+        let threads = data
+            .idle_intervals_by_thread()
+            .into_iter()
+            .map(|(thread_id, gaps)| {
+                let gap_nanos: Vec<u64> = gaps
+                    .into_iter()
+                    .map(|(start, end)| end.duration_since(start).unwrap().as_nanos() as u64)
+                    .collect();
+
+                ThreadIdleTime {
+                    thread_id,
+                    idle_nanos: gap_nanos.iter().sum(),
+                    gaps: gap_nanos.len(),
+                    longest_gap_nanos: gap_nanos.iter().copied().max().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        IdleTimeReport {
+            format_version: REPORT_FORMAT_VERSION,
+            metadata,
+            threads,
+        }
+    }
+}
+
+/// The output of `summarize diff --output-format json`. All changes are
+/// those from the base profile to the changed profile.
+#[derive(Serialize, Debug)]
+pub struct DiffReport {
+    pub format_version: u32,
+    pub total_time_change_nanos: i64,
+    /// In the same order as the table, i.e. by descending absolute self time
+    /// change, or by descending self time change with `--sort-by-regression`.
+    pub queries: Vec<QueryDiffReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryDiffReport {
+    pub label: String,
+    pub self_time_change_nanos: i64,
+    /// Relative to the self time in the base profile. `null` if the item
+    /// doesn't take any time in the base profile.
+    pub self_time_change_percent: Option<f64>,
+    pub time_change_nanos: i64,
+    /// Relative to the time in the base profile. `null` if the item doesn't
+    /// take any time in the base profile.
+    pub time_change_percent: Option<f64>,
+    pub invocation_count_change: i64,
+    pub cache_hits_change: i64,
+    pub cache_misses_change: i64,
+}
+
+impl DiffReport {
+    pub fn new(results: &DiffResults, include: impl Fn(&str) -> bool) -> DiffReport {
+        let percent = |change: f64| {
+            if change.is_finite() {
+                Some(change)
+            } else {
+                None
+            }
+        };
+
+        DiffReport {
+            format_version: REPORT_FORMAT_VERSION,
+            total_time_change_nanos: results.total_time.as_nanos() as i64,
+            queries: results
+                .query_data
+                .iter()
+                .filter(|query_data| include(&query_data.label))
+                .map(|query_data| QueryDiffReport {
+                    label: query_data.label.clone(),
+                    self_time_change_nanos: query_data.self_time.as_nanos() as i64,
+                    self_time_change_percent: percent(query_data.self_time_change),
+                    time_change_nanos: query_data.time.as_nanos() as i64,
+                    time_change_percent: percent(query_data.time_change),
+                    invocation_count_change: query_data.invocation_count,
+                    cache_hits_change: query_data.number_of_cache_hits,
+                    cache_misses_change: query_data.number_of_cache_misses,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzeme::{ProfilingDataBuilder, QueryData};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn field_names_are_stable() {
+        let results = AnalysisResults {
+            query_data: vec![
+                QueryData {
+                    self_time: Duration::from_nanos(10),
+                    time: Duration::from_nanos(10),
+                    invocation_count: 1,
+                    number_of_cache_misses: 1,
+                    ..QueryData::new("b".to_string())
+                },
+                QueryData {
+                    self_time: Duration::from_nanos(20),
+                    time: Duration::from_nanos(30),
+                    invocation_count: 3,
+                    number_of_cache_hits: 2,
+                    number_of_cache_misses: 1,
+                    latency: Some(LatencyPercentiles {
+                        p50: Duration::from_nanos(5),
+                        p90: Duration::from_nanos(9),
+                        p99: Duration::from_nanos(10),
+                        max: Duration::from_nanos(11),
+                    }),
+                    ..QueryData::new("a".to_string())
+                },
+            ],
+            artifact_sizes: Vec::new(),
+            instant_values: Vec::new(),
+            total_time: Duration::from_nanos(40),
+        };
+
+        let metadata = ReportMetadata {
+            start_time_nanos: 5,
+            process_id: 42,
+            cmd: "rustc".to_string(),
+            counter: Some("wall-time".to_string()),
+        };
+
+        let activity = Activity {
+            wall_time: Duration::from_nanos(35),
+            active_time: Duration::from_nanos(40),
+        };
+        let report =
+            serde_json::to_value(&Report::new(metadata, &results, Some(activity))).unwrap();
+
+        assert_eq!(
+            report,
+            json!({
+                "format_version": 1,
+                "metadata": {
+                    "start_time_nanos": 5,
+                    "process_id": 42,
+                    "cmd": "rustc",
+                    "counter": "wall-time"
+                },
+                "total_time_nanos": 40,
+                "wall_time_nanos": 35,
+                "active_time_nanos": 40,
+                "queries": [
+                    {
+                        "label": "a",
+                        "self_time_nanos": 20,
+                        "time_nanos": 30,
+                        "invocation_count": 3,
+                        "cache_hits": 2,
+                        "cache_misses": 1,
+                        "latency": {
+                            "p50_nanos": 5,
+                            "p90_nanos": 9,
+                            "p99_nanos": 10,
+                            "max_nanos": 11
+                        }
+                    },
+                    {
+                        "label": "b",
+                        "self_time_nanos": 10,
+                        "time_nanos": 10,
+                        "invocation_count": 1,
+                        "cache_hits": 0,
+                        "cache_misses": 1
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn artifact_sizes() {
+        let mut b = ProfilingDataBuilder::new();
+        b.integer("ArtifactSize", "object_file\x1Ea.o", 0, 100)
+            .integer("ArtifactSize", "object_file\x1Eb.o", 0, 300)
+            .integer("ArtifactSize", "object_file\x1Ea.o", 1, 250)
+            .integer("Other", "crate_metadata\x1E\x12ArtifactSize", 0, 50)
+            .integer("Other", "not_a_size", 0, 1000)
+            .interval("ArtifactSize", "timing", 0, 0, 10, |_| {});
+        let data = b.into_profiling_data();
+
+        let report = ArtifactSizeReport::new(ReportMetadata::new(data.metadata()), &data, |_| true);
+        let artifact = |kind: &str, name: Option<&str>, bytes| ArtifactReport {
+            kind: kind.to_string(),
+            name: name.map(str::to_string),
+            bytes,
+        };
+
+        assert_eq!(report.total_bytes, 700);
+        assert_eq!(
+            report.artifacts,
+            vec![
+                artifact("object_file", Some("a.o"), 350),
+                artifact("object_file", Some("b.o"), 300),
+                artifact("crate_metadata", None, 50),
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            report["artifacts"][2],
+            json!({ "kind": "crate_metadata", "bytes": 50 })
+        );
+
+        let filtered =
+            ArtifactSizeReport::new(ReportMetadata::new(data.metadata()), &data, |kind| {
+                kind != "object_file"
+            });
+        assert_eq!(filtered.total_bytes, 50);
+    }
+
+    #[test]
+    fn histogram() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 5, |b| {
+            b.interval("Query", "type_of", 0, 1, 2, |_| {});
+            b.interval("Query", "typeck", 0, 2, 3, |_| {});
+        })
+        .interval("Query", "typeck", 1, 0, 6, |_| {})
+        .interval("Query", "typeck", 1, 10, 30, |_| {})
+        .interval("Query", "typeck", 1, 40, 40, |_| {})
+        .instant("Query", "typeck", 1, 50);
+        let data = b.into_profiling_data();
+
+        let report = HistogramReport::new(ReportMetadata::new(data.metadata()), &data, "typeck");
+        let bucket = |min_nanos, max_nanos, count| HistogramBucket {
+            min_nanos,
+            max_nanos,
+            count,
+        };
+
+        assert_eq!(report.invocation_count, 5);
+        assert_eq!(
+            report.buckets,
+            vec![
+                bucket(0, 1, 1),
+                bucket(1, 2, 1),
+                bucket(2, 4, 0),
+                bucket(4, 8, 2),
+                bucket(8, 16, 0),
+                bucket(16, 32, 1),
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(report["label"], json!("typeck"));
+        assert_eq!(
+            report["buckets"][5],
+            json!({ "min_nanos": 16, "max_nanos": 32, "count": 1 })
+        );
+
+        let report = HistogramReport::new(ReportMetadata::new(data.metadata()), &data, "type_of");
+        assert_eq!(report.buckets, vec![bucket(1, 2, 1)]);
+
+        let report = HistogramReport::new(ReportMetadata::new(data.metadata()), &data, "missing");
+        assert_eq!(report.invocation_count, 0);
+        assert!(report.buckets.is_empty());
+    }
+
+    #[test]
+    fn drill_down() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 20, |b| {
+            b.interval("Query", "type_of", 0, 5, 15, |_| {});
+        })
+        .interval("Query", "typeck", 1, 0, 30, |b| {
+            b.interval("Query", "typeck", 1, 5, 25, |b| {
+                b.interval("Query", "type_of", 1, 10, 15, |_| {});
+                b.interval("Query", "layout_of", 1, 15, 20, |_| {});
+            });
+        });
+        let data = b.into_profiling_data();
+
+        // The recursive invocation is part of the outer one.
+        let report = DrillDownReport::new(ReportMetadata::new(data.metadata()), &data, "typeck");
+        assert_eq!(report.invocation_count, 2);
+        assert_eq!(report.duration_nanos, 50);
+        assert_eq!(report.self_time_nanos, 30);
+        assert_eq!(
+            report.children,
+            vec![
+                DrillDownChild {
+                    label: "type_of".to_string(),
+                    invocation_count: 2,
+                    duration_nanos: 15,
+                },
+                DrillDownChild {
+                    label: "layout_of".to_string(),
+                    invocation_count: 1,
+                    duration_nanos: 5,
+                },
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(report["label"], json!("typeck"));
+        assert_eq!(
+            report["children"][1],
+            json!({ "label": "layout_of", "invocation_count": 1, "duration_nanos": 5 })
+        );
+    }
+
+    #[test]
+    fn critical_path() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 20, |b| {
+            b.interval("Query", "type_of", 0, 5, 15, |_| {});
+        })
+        .interval("Query", "codegen", 0, 20, 45, |_| {})
+        .instant("Query", "typeck", 1, 35)
+        .interval("Query", "resolve", 2, 0, 5, |_| {});
+        let data = b.into_profiling_data();
+
+        let report = CriticalPathReport::new(ReportMetadata::new(data.metadata()), &data);
+        let frame = |label: &str, duration_nanos, self_time_nanos| CriticalPathFrame {
+            event_index: data
+                .iter()
+                .find(|event| data.to_full_event(event).label == label)
+                .unwrap()
+                .index(),
+            label: label.to_string(),
+            category: None,
+            duration_nanos,
+            self_time_nanos,
+        };
+
+        // `codegen` takes longer than `typeck`, which includes `type_of`.
+        // Thread 1 doesn't have any interval events.
+        assert_eq!(
+            report.threads,
+            vec![
+                ThreadCriticalPath {
+                    thread_id: 0,
+                    total_nanos: 25,
+                    frames: vec![frame("codegen", 25, 25)],
+                },
+                ThreadCriticalPath {
+                    thread_id: 2,
+                    total_nanos: 5,
+                    frames: vec![frame("resolve", 5, 5)],
+                },
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(report["threads"][1]["thread_id"], json!(2));
+        assert_eq!(
+            report["threads"][1]["frames"][0],
+            json!({
+                "event_index": 4,
+                "label": "resolve",
+                "category": null,
+                "duration_nanos": 5,
+                "self_time_nanos": 5,
+            })
+        );
+    }
+
+    #[test]
+    fn top_events() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 30, |b| {
+            b.interval("Query", "type_of", 0, 15, 25, |_| {});
+        })
+        .interval("Query", "codegen", 1, 0, 40, |_| {})
+        .instant("Query", "typeck", 1, 50);
+        let data = b.into_profiling_data();
+
+        let report = TopEventsReport::new(ReportMetadata::new(data.metadata()), &data, 2);
+        let event = |label: &str, thread_id, start_nanos, duration_nanos| TopEventReport {
+            label: label.to_string(),
+            category: None,
+            thread_id,
+            start_nanos,
+            duration_nanos,
+        };
+        assert_eq!(
+            report.events,
+            vec![event("codegen", 1, 0, 40), event("typeck", 0, 10, 20)]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            report["events"][1],
+            json!({
+                "label": "typeck",
+                "category": null,
+                "thread_id": 0,
+                "start_nanos": 10,
+                "duration_nanos": 20,
+            })
+        );
+    }
+
+    #[test]
+    fn idle_time() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 20, |b| {
+            b.interval("Query", "type_of", 0, 5, 15, |_| {});
+        })
+        .interval("Query", "codegen", 0, 30, 40, |_| {})
+        .interval("Query", "codegen", 0, 100, 110, |_| {})
+        .instant("Query", "typeck", 1, 35)
+        .interval("Query", "resolve", 2, 0, 5, |_| {});
+        let data = b.into_profiling_data();
+
+        // Thread 1 doesn't have any interval events.
+        let report = IdleTimeReport::new(ReportMetadata::new(data.metadata()), &data);
+        assert_eq!(
+            report.threads,
+            vec![
+                ThreadIdleTime {
+                    thread_id: 0,
+                    idle_nanos: 70,
+                    gaps: 2,
+                    longest_gap_nanos: 60,
+                },
+                ThreadIdleTime {
+                    thread_id: 2,
+                    idle_nanos: 0,
+                    gaps: 0,
+                    longest_gap_nanos: 0,
+                },
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            report["threads"][0],
+            json!({
+                "thread_id": 0,
+                "idle_nanos": 70,
+                "gaps": 2,
+                "longest_gap_nanos": 60,
+            })
+        );
+    }
+
+    #[test]
+    fn timeline() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 20, |b| {
+            b.interval("Query", "type_of", 0, 5, 15, |_| {});
+        })
+        .instant("Query", "typeck", 1, 35);
+        let data = b.into_profiling_data();
+
+        let report = TimelineReport::new(
+            ReportMetadata::new(data.metadata()),
+            &data,
+            &data.self_times(),
+            10,
+            BucketAssignment::Start,
+        )
+        .unwrap();
+        let bucket = |start_nanos, events, self_time_nanos| TimelineReportBucket {
+            start_nanos,
+            events,
+            self_time_nanos,
+        };
+
+        assert_eq!(
+            report.buckets,
+            vec![
+                bucket(0, 2.0, 20),
+                bucket(10, 0.0, 0),
+                bucket(20, 0.0, 0),
+                bucket(30, 1.0, 0),
+            ]
+        );
+
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(report["bucket_nanos"], json!(10));
+        assert_eq!(report["proportional"], json!(false));
+        assert_eq!(
+            report["buckets"][0],
+            json!({ "start_nanos": 0, "events": 2.0, "self_time_nanos": 20 })
+        );
+    }
+
+    #[test]
+    fn diff_field_names_are_stable() {
+        let results = |self_nanos: &[(&str, u64)]| AnalysisResults {
+            query_data: self_nanos
+                .iter()
+                .map(|&(label, nanos)| QueryData {
+                    self_time: Duration::from_nanos(nanos),
+                    time: Duration::from_nanos(nanos),
+                    invocation_count: 2,
+                    ..QueryData::new(label.to_string())
+                })
+                .collect(),
+            artifact_sizes: Vec::new(),
+            instant_values: Vec::new(),
+            total_time: Duration::from_nanos(self_nanos.iter().map(|q| q.1).sum()),
+        };
+
+        let diff = crate::diff::calculate_diff(
+            results(&[("a", 100), ("excluded", 10)]),
+            results(&[("a", 150), ("excluded", 10), ("new", 20)]),
+        );
+        let report = DiffReport::new(&diff, |label| label != "excluded");
+
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "format_version": REPORT_FORMAT_VERSION,
+                "total_time_change_nanos": 70,
+                "queries": [
+                    {
+                        "label": "a",
+                        "self_time_change_nanos": 50,
+                        "self_time_change_percent": 50.0,
+                        "time_change_nanos": 50,
+                        "time_change_percent": 50.0,
+                        "invocation_count_change": 0,
+                        "cache_hits_change": 0,
+                        "cache_misses_change": 0,
+                    },
+                    {
+                        "label": "new",
+                        "self_time_change_nanos": 20,
+                        "self_time_change_percent": null,
+                        "time_change_nanos": 20,
+                        "time_change_percent": null,
+                        "invocation_count_change": 2,
+                        "cache_hits_change": 0,
+                        "cache_misses_change": 0,
+                    },
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! The wall-clock durations shown by `summarize summarize` for profiles
+//! recorded with `ProfilerOptions::record_wall_time`, see
+//! `analyzeme::QueryData::wall_time`.
+
+use std::time::Duration;
+
+/// The number of instructions per nanosecond of wall-clock time, for profiles
+/// recorded with an instruction counter, whose durations are instruction
+/// counts.
+pub fn instructions_per_nano(instructions: Duration, wall_time: Duration) -> Option<f64> {
+    if wall_time == Duration::from_secs(0) {
+        return None;
+    }
+
+    Some(instructions.as_nanos() as f64 / wall_time.as_nanos() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_per_wall_time_nano() {
+        assert_eq!(
+            instructions_per_nano(Duration::from_nanos(800), Duration::from_nanos(200)),
+            Some(4.0)
+        );
+        assert_eq!(
+            instructions_per_nano(Duration::from_nanos(100), Duration::from_secs(0)),
+            None
+        );
+    }
+}